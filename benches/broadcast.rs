@@ -0,0 +1,63 @@
+//! Benchmarks for fanning a single message out to every member of a channel. Members are
+//! simulated by actors that drop anything they're sent, so this measures the cost of cloning
+//! the message and going through each member's mailbox.
+
+use actix::{Actor, Addr, Context, Handler};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use futures::future::join_all;
+use irc_proto::{Command, Message, Prefix};
+use titanircd::messages::Broadcast;
+use tracing::Span;
+
+struct Member;
+
+impl Actor for Member {
+    type Context = Context<Self>;
+}
+
+impl Handler<Broadcast> for Member {
+    type Result = ();
+
+    fn handle(&mut self, msg: Broadcast, _ctx: &mut Self::Context) -> Self::Result {
+        drop(msg);
+    }
+}
+
+fn fan_out(c: &mut Criterion) {
+    let system = actix_rt::System::new();
+    let mut group = c.benchmark_group("channel_fan_out");
+
+    let message = Message {
+        tags: None,
+        prefix: Some(Prefix::new_from_str("nick!user@host")),
+        command: Command::PRIVMSG(
+            "#channel".to_string(),
+            "hello world, how is everyone doing?".to_string(),
+        ),
+    };
+
+    for members in [10, 100, 1_000] {
+        let members: Vec<Addr<Member>> =
+            system.block_on(async { (0..members).map(|_| Member.start()).collect() });
+
+        group.bench_with_input(
+            BenchmarkId::from_parameter(members.len()),
+            &members,
+            |b, members| {
+                b.iter(|| {
+                    system.block_on(join_all(members.iter().map(|member| {
+                        member.send(Broadcast {
+                            message: message.clone(),
+                            span: Span::none(),
+                        })
+                    })))
+                });
+            },
+        );
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, fan_out);
+criterion_main!(benches);
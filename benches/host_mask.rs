@@ -0,0 +1,54 @@
+//! Benchmarks for the host mask trie, which is hit on every join and for every connection
+//! checked against the ban list.
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use titanircd::host_mask::{HostMask, HostMaskMap};
+
+fn build_map(size: usize) -> HostMaskMap<usize> {
+    let mut map = HostMaskMap::new();
+
+    for i in 0..size {
+        let nick = format!("nick{i}");
+        let user = format!("user{i}");
+        let host = format!("host{i}.example.com");
+
+        map.insert(&HostMask::new(&nick, &user, &host), i);
+        map.insert(&HostMask::new("*", &user, "*"), i);
+        map.insert(&HostMask::new("*", "*", &format!("host{i}.*")), i);
+    }
+
+    map
+}
+
+fn host_mask_map(c: &mut Criterion) {
+    let mut group = c.benchmark_group("host_mask_map");
+
+    for size in [10, 1_000, 10_000] {
+        let map = build_map(size);
+        let hit = HostMask::new("nick5", "user5", "host5.example.com");
+        let miss = HostMask::new("someone", "else", "elsewhere.example.com");
+
+        group.bench_with_input(BenchmarkId::new("get_hit", size), &map, |b, map| {
+            b.iter(|| map.get(black_box(&hit)).len());
+        });
+
+        group.bench_with_input(BenchmarkId::new("get_miss", size), &map, |b, map| {
+            b.iter(|| map.get(black_box(&miss)).len());
+        });
+
+        group.bench_function(BenchmarkId::new("insert", size), |b| {
+            let mask = HostMask::new("*", "new", "new.example.com");
+
+            b.iter_batched(
+                || build_map(size),
+                |mut map| map.insert(black_box(&mask), 0),
+                BatchSize::LargeInput,
+            );
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, host_mask_map);
+criterion_main!(benches);
@@ -0,0 +1,54 @@
+//! Benchmarks for parsing incoming lines from clients, both through `irc_proto` and our own
+//! extension commands.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use irc_proto::Message;
+use titanircd::proto::LocalCommand;
+
+fn irc_proto(c: &mut Criterion) {
+    let mut group = c.benchmark_group("irc_proto");
+
+    for (name, line) in [
+        ("privmsg", "PRIVMSG #channel :hello world, how is everyone doing?\r\n"),
+        (
+            "privmsg_with_tags_and_prefix",
+            "@time=2024-01-01T00:00:00.000Z;msgid=abc :nick!user@host PRIVMSG #channel :hello world\r\n",
+        ),
+        ("join", "JOIN #a,#b,#c,#d,#e\r\n"),
+        ("ping", "PING :my.cool.server\r\n"),
+    ] {
+        group.bench_function(name, |b| {
+            b.iter(|| black_box(line).parse::<Message>().unwrap());
+        });
+    }
+
+    group.finish();
+}
+
+fn local_command(c: &mut Criterion) {
+    let mut group = c.benchmark_group("local_command");
+
+    for (name, command, args) in [
+        ("gline", "GLINE", vec!["*!*@127.0.0.1", "1d", "go away"]),
+        (
+            "cprivmsg",
+            "CPRIVMSG",
+            vec!["nick", "#channel", "hello world"],
+        ),
+        ("accept", "ACCEPT", vec!["aaa,-bbb,ccc"]),
+    ] {
+        let args: Vec<String> = args.into_iter().map(ToString::to_string).collect();
+
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                LocalCommand::try_from((black_box(command.to_string()), black_box(args.clone())))
+                    .unwrap()
+            });
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, irc_proto, local_command);
+criterion_main!(benches);
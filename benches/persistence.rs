@@ -0,0 +1,114 @@
+//! Benchmarks for the queries hit on every join and connection, run against an in-memory
+//! SQLite database seeded with a busy channel.
+
+use std::{str::FromStr, time::Duration};
+
+use actix::{Actor, Addr};
+use criterion::{criterion_group, criterion_main, Criterion};
+use sqlx::any::AnyPoolOptions;
+use titanircd::{
+    channel::ChannelId,
+    connection::UserId,
+    database,
+    persistence::{
+        events::{FetchUnseenChannelMessages, FetchUserChannels},
+        Persistence,
+    },
+};
+use tracing::Span;
+
+const CHANNELS: i64 = 100;
+const USERS: i64 = 100;
+const MESSAGES_PER_CHANNEL: i64 = 500;
+
+async fn setup() -> Addr<Persistence> {
+    sqlx::any::install_default_drivers();
+
+    // in-memory databases are per-connection, so everything has to go over the one connection
+    let database = AnyPoolOptions::new()
+        .max_connections(1)
+        .connect_with(sqlx::any::AnyConnectOptions::from_str("sqlite::memory:").unwrap())
+        .await
+        .unwrap();
+
+    database::migrate(&database).await.unwrap();
+
+    for user in 1..=USERS {
+        sqlx::query("INSERT INTO users (id, username, password) VALUES ($1, $2, '')")
+            .bind(user)
+            .bind(format!("user{user}"))
+            .execute(&database)
+            .await
+            .unwrap();
+    }
+
+    for channel in 1..=CHANNELS {
+        sqlx::query("INSERT INTO channels (id, name) VALUES ($1, $2)")
+            .bind(channel)
+            .bind(format!("#channel{channel}"))
+            .execute(&database)
+            .await
+            .unwrap();
+
+        for user in 1..=USERS {
+            sqlx::query(
+                "INSERT INTO channel_users (channel, user_id, in_channel, last_seen_message_timestamp)
+                 VALUES ($1, $2, $3, $4)",
+            )
+            .bind(channel)
+            .bind(user)
+            .bind(user % 2 == 0)
+            .bind(user)
+            .execute(&database)
+            .await
+            .unwrap();
+        }
+
+        for timestamp in 1..=MESSAGES_PER_CHANNEL {
+            sqlx::query(
+                "INSERT INTO channel_messages (channel, timestamp, sender, message, kind)
+                 VALUES ($1, $2, 'someone!someone@host', 'hello world', 0)",
+            )
+            .bind(channel)
+            .bind(timestamp)
+            .execute(&database)
+            .await
+            .unwrap();
+        }
+    }
+
+    Persistence::new(
+        database,
+        Duration::from_secs(u64::from(u32::MAX)),
+        500,
+        Duration::from_secs(0),
+    )
+    .start()
+}
+
+fn persistence(c: &mut Criterion) {
+    let system = actix_rt::System::new();
+    let persistence = system.block_on(setup());
+
+    c.bench_function("fetch_unseen_channel_messages", |b| {
+        b.iter(|| {
+            system.block_on(persistence.send(FetchUnseenChannelMessages {
+                channel_id: ChannelId(CHANNELS / 2),
+                user_id: UserId(USERS / 2),
+                span: Span::none(),
+            }))
+        });
+    });
+
+    c.bench_function("fetch_user_channels", |b| {
+        b.iter(|| {
+            system.block_on(persistence.send(FetchUserChannels {
+                user_id: UserId(USERS / 2),
+                span: Span::none(),
+            }))
+        });
+    });
+}
+
+criterion_group!(benches, persistence);
+criterion_main!(benches);
@@ -0,0 +1,127 @@
+"""irctest controller for titanircd.
+
+Copy this file into irctest's `irctest/controllers` directory, and make sure a
+`titanircd` binary built with the `irctest` feature is on your `PATH` (or point
+`TITANIRCD_BIN` at one). See `README.md` alongside this file for details.
+"""
+
+import base64
+import os
+import shutil
+import socket
+import subprocess
+from typing import Optional, Type
+
+from irctest.basecontrollers import (
+    BaseServerController,
+    DirectoryBasedController,
+    NotImplementedByController,
+)
+
+# `implicit-accounts` lets irctest's clients register without SASL, which every
+# test that doesn't explicitly log in relies on.
+TEMPLATE_CONFIG = """
+network-name = "titanircd"
+
+[database]
+uri = "sqlite://{directory}/titanircd.db?mode=rwc"
+
+[[listeners]]
+address = "{hostname}:{port}"
+
+[compat]
+implicit-accounts = true
+"""
+
+
+class TitanircdController(BaseServerController, DirectoryBasedController):
+    software_name = "titanircd"
+    supported_sasl_mechanisms = {"PLAIN"}
+    supports_sts = False
+
+    def create_config(self) -> None:
+        super().create_config()
+        with self.open_file("server.toml"):
+            pass
+
+    def run(
+        self,
+        hostname: str,
+        port: int,
+        *,
+        password: Optional[str],
+        ssl: bool,
+        run_services: bool,
+        faketime: Optional[str],
+        websocket_hostname: Optional[str] = None,
+        websocket_port: Optional[int] = None,
+    ) -> None:
+        if password is not None:
+            raise NotImplementedByController("PASS command")
+        if ssl:
+            raise NotImplementedByController("TLS")
+        if run_services:
+            raise NotImplementedByController("Registration services")
+        if websocket_hostname is not None:
+            raise NotImplementedByController("Websockets")
+
+        assert self.proc is None
+        self.hostname = hostname
+        self.port = port
+        self.create_config()
+        assert self.directory
+
+        # the database lives in the controller's temporary directory, so each test
+        # starts from a clean slate
+        with self.open_file("server.toml") as fd:
+            fd.write(
+                TEMPLATE_CONFIG.format(
+                    directory=self.directory, hostname=hostname, port=port
+                )
+            )
+
+        if faketime and shutil.which("faketime"):
+            faketime_cmd = ["faketime", "-f", faketime]
+            self.faketime_enabled = True
+        else:
+            faketime_cmd = []
+
+        self.proc = subprocess.Popen(
+            [
+                *faketime_cmd,
+                os.environ.get("TITANIRCD_BIN", "titanircd"),
+                "--config",
+                str(self.directory / "server.toml"),
+            ]
+        )
+
+    def registerUser(
+        self,
+        case,  # type: ignore
+        username: str,
+        password: Optional[str] = None,
+    ) -> None:
+        # accounts are created the first time they're logged into, so log in once
+        # and disconnect straight after
+        blob = f"{username}\0{username}\0{password or ''}".encode()
+        lines = [
+            "CAP REQ :sasl",
+            f"NICK {username}",
+            f"USER {username} 0 * :{username}",
+            "AUTHENTICATE PLAIN",
+            f"AUTHENTICATE {base64.b64encode(blob).decode()}",
+        ]
+
+        with socket.create_connection((self.hostname, self.port), timeout=5) as sock:
+            sock.sendall("".join(f"{line}\r\n" for line in lines).encode())
+
+            received = b""
+            while b" 903 " not in received:
+                chunk = sock.recv(4096)
+                received += chunk
+                if not chunk or b" 904 " in received:
+                    raise AssertionError(f"Failed to register {username}: {received!r}")
+
+
+def get_irctest_controller_class() -> Type[TitanircdController]:
+    return TitanircdController
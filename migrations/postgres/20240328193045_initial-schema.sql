@@ -0,0 +1,175 @@
+-- the schema built up by the SQLite migrations up to this point. Timestamps are stored as
+-- nanoseconds since the epoch, so need to be BIGINTs here where SQLite's INTs are already
+-- 64-bit, and strings are stored as TEXT as Postgres enforces the lengths SQLite ignores
+CREATE TABLE keys (
+    name TEXT PRIMARY KEY,
+    enckey BYTEA NOT NULL
+);
+
+CREATE TABLE users (
+    id BIGSERIAL PRIMARY KEY,
+    username TEXT NOT NULL,
+    password TEXT NOT NULL,
+    created_timestamp BIGINT
+);
+
+CREATE UNIQUE INDEX users_username ON users(username);
+
+CREATE TABLE user_nicks (
+    nick TEXT NOT NULL PRIMARY KEY,
+    user_id BIGINT NOT NULL REFERENCES users(id)
+);
+
+CREATE INDEX user_nicks_user ON user_nicks(user_id);
+CREATE INDEX user_nicks_nick_lower ON user_nicks(LOWER(nick));
+
+CREATE TABLE channels (
+    id BIGSERIAL PRIMARY KEY,
+    name TEXT NOT NULL,
+    created_timestamp BIGINT,
+    invite_only BOOLEAN NOT NULL DEFAULT false,
+    channel_key TEXT,
+    client_limit BIGINT,
+    moderated BOOLEAN NOT NULL DEFAULT false,
+    private BOOLEAN NOT NULL DEFAULT false,
+    secret BOOLEAN NOT NULL DEFAULT false,
+    topic_lock BOOLEAN NOT NULL DEFAULT true,
+    permissions_version BIGINT NOT NULL DEFAULT 0,
+    successor BIGINT REFERENCES users(id),
+    language TEXT,
+    category TEXT,
+    website TEXT
+);
+
+CREATE UNIQUE INDEX channel_name ON channels(name);
+
+CREATE TABLE channel_messages (
+    channel BIGINT NOT NULL REFERENCES channels(id),
+    timestamp BIGINT NOT NULL,
+    msgid TEXT,
+    sender TEXT,
+    message TEXT,
+    kind SMALLINT NOT NULL,
+    redacted BOOLEAN NOT NULL DEFAULT false,
+    PRIMARY KEY(channel, timestamp)
+);
+
+CREATE INDEX channel_messages_msgid ON channel_messages(channel, msgid);
+
+CREATE TABLE channel_users (
+    channel BIGINT NOT NULL REFERENCES channels(id),
+    user_id BIGINT NOT NULL REFERENCES users(id),
+    in_channel BOOLEAN DEFAULT false,
+    last_seen_message_timestamp BIGINT,
+    PRIMARY KEY(channel, user_id)
+);
+
+CREATE INDEX channel_users_user_in_channel ON channel_users(user_id, in_channel);
+
+CREATE TABLE channel_permissions (
+    channel BIGINT NOT NULL REFERENCES channels(id),
+    mask TEXT,
+    permissions SMALLINT NOT NULL DEFAULT 0,
+    set_by TEXT,
+    set_at BIGINT,
+    PRIMARY KEY(channel, mask)
+);
+
+CREATE TABLE channel_invites (
+    channel BIGINT NOT NULL REFERENCES channels(id),
+    user_id BIGINT NOT NULL REFERENCES users(id),
+    PRIMARY KEY(channel, user_id)
+);
+
+CREATE TABLE channel_message_reactions (
+    channel BIGINT NOT NULL REFERENCES channels(id),
+    msgid TEXT NOT NULL,
+    user_id BIGINT NOT NULL REFERENCES users(id),
+    sender TEXT NOT NULL,
+    reaction TEXT NOT NULL,
+    timestamp BIGINT NOT NULL,
+    PRIMARY KEY(channel, msgid, user_id, reaction)
+);
+
+CREATE TABLE private_messages (
+    timestamp BIGINT NOT NULL PRIMARY KEY,
+    sender TEXT NOT NULL,
+    sender_user BIGINT REFERENCES users(id),
+    receiver BIGINT NOT NULL REFERENCES users(id),
+    message TEXT NOT NULL,
+    kind SMALLINT NOT NULL,
+    delivered BOOLEAN NOT NULL DEFAULT false
+);
+
+CREATE INDEX private_messages_receiver ON private_messages(receiver);
+CREATE INDEX private_messages_sender_user ON private_messages(sender_user);
+
+CREATE TABLE server_bans (
+    mask TEXT NOT NULL PRIMARY KEY,
+    requester BIGINT NOT NULL REFERENCES users(id),
+    reason TEXT NOT NULL,
+    created_timestamp BIGINT NOT NULL,
+    expires_timestamp BIGINT
+);
+
+CREATE TABLE server_shuns (
+    mask TEXT NOT NULL PRIMARY KEY,
+    requester BIGINT NOT NULL REFERENCES users(id),
+    reason TEXT NOT NULL,
+    created_timestamp BIGINT NOT NULL,
+    expires_timestamp BIGINT
+);
+
+CREATE TABLE nick_history (
+    user_id BIGINT NOT NULL REFERENCES users(id),
+    nick TEXT NOT NULL,
+    first_used_timestamp BIGINT NOT NULL,
+    last_used_timestamp BIGINT NOT NULL,
+    PRIMARY KEY(user_id, nick)
+);
+
+CREATE TABLE server_runs (
+    id BIGSERIAL PRIMARY KEY,
+    started_timestamp BIGINT NOT NULL,
+    stopped_timestamp BIGINT
+);
+
+CREATE TABLE user_certificates (
+    fingerprint TEXT NOT NULL PRIMARY KEY,
+    user_id BIGINT NOT NULL REFERENCES users(id)
+);
+
+CREATE INDEX user_certificates_user ON user_certificates(user_id);
+
+CREATE TABLE stats_daily (
+    day TEXT NOT NULL PRIMARY KEY,
+    peak_clients BIGINT NOT NULL DEFAULT 0,
+    new_accounts BIGINT NOT NULL DEFAULT 0
+);
+
+CREATE TABLE stats_daily_channels (
+    day TEXT NOT NULL,
+    channel BIGINT NOT NULL REFERENCES channels(id),
+    messages BIGINT NOT NULL DEFAULT 0,
+    PRIMARY KEY(day, channel)
+);
+
+CREATE TABLE whowas (
+    nick TEXT NOT NULL,
+    username TEXT NOT NULL,
+    host TEXT NOT NULL,
+    realname TEXT NOT NULL,
+    seen_timestamp BIGINT NOT NULL
+);
+
+CREATE INDEX whowas_nick ON whowas(LOWER(nick), seen_timestamp);
+
+CREATE TABLE login_history (
+    user_id BIGINT NOT NULL REFERENCES users(id),
+    session TEXT NOT NULL,
+    ip TEXT NOT NULL,
+    host TEXT,
+    timestamp BIGINT NOT NULL
+);
+
+CREATE INDEX login_history_user ON login_history(user_id, timestamp);
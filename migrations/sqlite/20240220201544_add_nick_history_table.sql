@@ -0,0 +1,8 @@
+CREATE TABLE nick_history (
+    user INT NOT NULL,
+    nick VARCHAR(255) NOT NULL,
+    first_used_timestamp INT NOT NULL,
+    last_used_timestamp INT NOT NULL,
+    FOREIGN KEY(user) REFERENCES users(id),
+    PRIMARY KEY(user, nick)
+);
@@ -0,0 +1,8 @@
+-- channel_messages is already keyed on (channel, timestamp) which covers both history replay
+-- and truncation, so it doesn't need an index of its own.
+
+-- fetching the channels a user is currently in on connection
+CREATE INDEX channel_users_user_in_channel ON channel_users(user, in_channel);
+
+-- looking up the nicks owned by a user
+CREATE INDEX user_nicks_user ON user_nicks(user);
@@ -0,0 +1,5 @@
+CREATE TABLE server_runs (
+    id INTEGER PRIMARY KEY,
+    started_timestamp INT NOT NULL,
+    stopped_timestamp INT
+);
@@ -0,0 +1,3 @@
+-- channels created before this migration have no known creation time, these are filled in the
+-- next time the channel is loaded
+ALTER TABLE channels ADD COLUMN created_timestamp INT;
@@ -0,0 +1,2 @@
+-- nicks are looked up case-insensitively, which can't make use of the primary key
+CREATE INDEX user_nicks_nick_nocase ON user_nicks(nick COLLATE NOCASE);
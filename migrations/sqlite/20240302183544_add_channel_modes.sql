@@ -0,0 +1,7 @@
+-- channel-wide modes, new channels (and channels created before this migration) default to +t
+ALTER TABLE channels ADD COLUMN invite_only BOOLEAN NOT NULL DEFAULT false;
+ALTER TABLE channels ADD COLUMN channel_key TEXT;
+ALTER TABLE channels ADD COLUMN client_limit INT;
+ALTER TABLE channels ADD COLUMN moderated BOOLEAN NOT NULL DEFAULT false;
+ALTER TABLE channels ADD COLUMN secret BOOLEAN NOT NULL DEFAULT false;
+ALTER TABLE channels ADD COLUMN topic_lock BOOLEAN NOT NULL DEFAULT true;
@@ -0,0 +1,9 @@
+-- TLS client certificate fingerprints users can authenticate with via SASL EXTERNAL, each
+-- certificate can only belong to a single account
+CREATE TABLE user_certificates (
+    fingerprint VARCHAR(64) NOT NULL PRIMARY KEY,
+    user INTEGER NOT NULL,
+    FOREIGN KEY(user) REFERENCES users(id)
+);
+
+CREATE INDEX user_certificates_user ON user_certificates(user);
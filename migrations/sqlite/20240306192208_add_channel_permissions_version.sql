@@ -0,0 +1,3 @@
+-- incremented on every change to a channel's permissions, so live channels can tell whether
+-- they've missed a change to their cached permissions
+ALTER TABLE channels ADD COLUMN permissions_version INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,6 @@
+-- private messages are now kept after they've been delivered so they can be fetched with
+-- CHATHISTORY, messages stored before this migration are all still waiting to be delivered
+ALTER TABLE private_messages ADD COLUMN sender_user INT REFERENCES users(id);
+ALTER TABLE private_messages ADD COLUMN delivered BOOLEAN NOT NULL DEFAULT false;
+
+CREATE INDEX private_messages_sender_user ON private_messages(sender_user);
@@ -0,0 +1,8 @@
+-- outstanding invites to a channel, each is used up once the invited user joins
+CREATE TABLE channel_invites (
+    channel INT NOT NULL,
+    user INT NOT NULL,
+    FOREIGN KEY(channel) REFERENCES channels(id),
+    FOREIGN KEY(user) REFERENCES users(id),
+    PRIMARY KEY(channel, user)
+);
@@ -0,0 +1,2 @@
+-- the account that takes over as founder of the channel once the founder's account is dropped
+ALTER TABLE channels ADD COLUMN successor INT REFERENCES users(id);
@@ -0,0 +1,17 @@
+-- daily aggregates for operators to follow the growth of the network, days are formatted as
+-- YYYY-MM-DD in UTC
+ALTER TABLE users ADD COLUMN created_timestamp INT;
+
+CREATE TABLE stats_daily (
+    day VARCHAR(10) NOT NULL PRIMARY KEY,
+    peak_clients INT NOT NULL DEFAULT 0,
+    new_accounts INT NOT NULL DEFAULT 0
+);
+
+CREATE TABLE stats_daily_channels (
+    day VARCHAR(10) NOT NULL,
+    channel INT NOT NULL,
+    messages INT NOT NULL DEFAULT 0,
+    FOREIGN KEY(channel) REFERENCES channels(id),
+    PRIMARY KEY(day, channel)
+);
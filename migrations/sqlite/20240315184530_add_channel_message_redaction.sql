@@ -0,0 +1,6 @@
+-- messages are given an id clients can refer to them by, redacted messages are kept as a
+-- tombstone with their content removed and are no longer replayed
+ALTER TABLE channel_messages ADD COLUMN msgid VARCHAR(32);
+ALTER TABLE channel_messages ADD COLUMN redacted BOOLEAN NOT NULL DEFAULT false;
+
+CREATE INDEX channel_messages_msgid ON channel_messages(channel, msgid);
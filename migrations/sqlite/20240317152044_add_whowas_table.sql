@@ -0,0 +1,10 @@
+-- nicks users have stopped using, either by changing nick or disconnecting, kept for WHOWAS
+CREATE TABLE whowas (
+    nick VARCHAR(255) NOT NULL,
+    username VARCHAR(255) NOT NULL,
+    host VARCHAR(255) NOT NULL,
+    realname VARCHAR(255) NOT NULL,
+    seen_timestamp INT NOT NULL
+);
+
+CREATE INDEX whowas_nick ON whowas(nick COLLATE NOCASE, seen_timestamp);
@@ -0,0 +1,13 @@
+-- `+draft/react` reactions to channel messages, keyed by the msgid of the message they're
+-- reacting to so they can be replayed alongside it
+CREATE TABLE channel_message_reactions (
+    channel INT NOT NULL,
+    msgid VARCHAR(32) NOT NULL,
+    user INT NOT NULL,
+    sender VARCHAR(255) NOT NULL,
+    reaction VARCHAR(255) NOT NULL,
+    timestamp INT NOT NULL,
+    FOREIGN KEY(channel) REFERENCES channels(id),
+    FOREIGN KEY(user) REFERENCES users(id),
+    PRIMARY KEY(channel, msgid, user, reaction)
+);
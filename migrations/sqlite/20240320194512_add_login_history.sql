@@ -0,0 +1,11 @@
+-- connections made to each account, shown to the account's owner by `NS SESSIONS`
+CREATE TABLE login_history (
+    user INT NOT NULL,
+    session VARCHAR(8) NOT NULL,
+    ip VARCHAR(255) NOT NULL,
+    host VARCHAR(255),
+    timestamp INT NOT NULL,
+    FOREIGN KEY(user) REFERENCES users(id)
+);
+
+CREATE INDEX login_history_user ON login_history(user, timestamp);
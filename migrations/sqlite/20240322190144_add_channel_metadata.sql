@@ -0,0 +1,4 @@
+-- descriptive metadata set by channel operators through CS SET
+ALTER TABLE channels ADD COLUMN language TEXT;
+ALTER TABLE channels ADD COLUMN category TEXT;
+ALTER TABLE channels ADD COLUMN website TEXT;
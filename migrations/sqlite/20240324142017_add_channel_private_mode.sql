@@ -0,0 +1,2 @@
+-- `+p`, hides the channel's members from users not in it
+ALTER TABLE channels ADD COLUMN private BOOLEAN NOT NULL DEFAULT false;
@@ -0,0 +1,4 @@
+-- who last changed each permission and when, shown in the ban list. NULL for permissions set
+-- before these were recorded
+ALTER TABLE channel_permissions ADD COLUMN set_by VARCHAR(255);
+ALTER TABLE channel_permissions ADD COLUMN set_at INT;
@@ -0,0 +1,17 @@
+-- `user` is a reserved word in Postgres, so columns referencing an account are renamed to
+-- `user_id` to let the same queries run against both databases
+ALTER TABLE user_nicks RENAME COLUMN user TO user_id;
+ALTER TABLE channel_users RENAME COLUMN user TO user_id;
+ALTER TABLE nick_history RENAME COLUMN user TO user_id;
+ALTER TABLE user_certificates RENAME COLUMN user TO user_id;
+ALTER TABLE channel_invites RENAME COLUMN user TO user_id;
+ALTER TABLE channel_message_reactions RENAME COLUMN user TO user_id;
+ALTER TABLE login_history RENAME COLUMN user TO user_id;
+
+-- nicks are compared using `LOWER`, which is available in both databases unlike SQLite's
+-- `NOCASE` collation
+DROP INDEX user_nicks_nick_nocase;
+CREATE INDEX user_nicks_nick_lower ON user_nicks(LOWER(nick));
+
+DROP INDEX whowas_nick;
+CREATE INDEX whowas_nick ON whowas(LOWER(nick), seen_timestamp);
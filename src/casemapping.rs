@@ -0,0 +1,42 @@
+//! Nick comparisons, following the casemapping advertised to clients via `ISUPPORT`.
+//!
+//! Nicks are stored in the case the user chose, but any lookup of a user by nick should go
+//! through here so `Bob` and `bob` are treated as the same user.
+
+/// The casemapping advertised to clients, only ASCII letters are folded which matches the
+/// `LOWER` used for lookups against SQLite. Postgres' `LOWER` also folds non-ASCII letters
+/// depending on the database's locale.
+pub const CASEMAPPING: &str = "ascii";
+
+/// Returns true if both nicks refer to the same user.
+#[must_use]
+pub fn nick_eq(a: &str, b: &str) -> bool {
+    a.eq_ignore_ascii_case(b)
+}
+
+/// Folds a nick or channel name into the form used as a key when storing it in a map.
+#[must_use]
+pub fn fold(name: &str) -> String {
+    name.to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{fold, nick_eq};
+
+    #[test]
+    fn nick_eq_ignores_ascii_case() {
+        assert!(nick_eq("bob", "bob"));
+        assert!(nick_eq("Bob", "bOB"));
+        assert!(nick_eq("[Bob]", "[bob]"));
+        assert!(!nick_eq("bob", "bob_"));
+        assert!(!nick_eq("[bob]", "{bob}"));
+    }
+
+    #[test]
+    fn fold_matches_nick_eq() {
+        assert_eq!(fold("Bob"), fold("bOB"));
+        assert_eq!(fold("#Chan"), "#chan");
+        assert_ne!(fold("[bob]"), fold("{bob}"));
+    }
+}
@@ -1,7 +1,15 @@
+pub mod list;
+pub mod metadata;
+pub mod modes;
 pub mod permissions;
 pub mod response;
 
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    iter::once,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use actix::{
     Actor, ActorContext, ActorFutureExt, Addr, AsyncContext, Context, Handler, MessageResult,
@@ -9,52 +17,100 @@ use actix::{
 };
 use chrono::{DateTime, Utc};
 use futures::future::Either;
-use irc_proto::{Command, Message, Mode, Response};
+use irc_proto::{message::Tag, Command, Message, Mode, Response};
 use tracing::{debug, error, info, instrument, warn, Span};
 
 use crate::{
+    casemapping::nick_eq,
     channel::{
-        permissions::Permission,
+        metadata::ChannelMetadata,
+        modes::ChannelModeState,
+        permissions::{Permission, PermissionDetails},
         response::{
-            BanList, ChannelInviteResult, ChannelJoinRejectionReason, ChannelNamesList,
-            ChannelTopic, ChannelWhoList, MissingPrivileges, ModeList,
+            BanList, ChannelCreationTime, ChannelInviteResult, ChannelJoinBurst,
+            ChannelJoinRejectionReason, ChannelModes, ChannelNamesList, ChannelTopic, ChannelUrl,
+            ChannelWhoList, MissingPrivileges, ModeList, RedactFailed, TooManyReactions,
+            UserNotInChannel,
         },
     },
-    client::Client,
-    connection::{Capability, InitiatedConnection},
+    client::{msgid_tag, new_msgid, server_time_tag, server_time_tags, Client, TagBuilder},
+    connection::{Capability, InitiatedConnection, UserId, UserMode},
     host_mask::{HostMask, HostMaskMap},
     messages::{
-        Broadcast, ChannelFetchTopic, ChannelFetchWhoList, ChannelInvite, ChannelJoin,
-        ChannelKickUser, ChannelMemberList, ChannelMessage, ChannelPart, ChannelSetMode,
-        ChannelUpdateTopic, ClientAway, FetchClientByNick, FetchUserPermission, MessageKind,
-        ServerDisconnect, UserKickedFromChannel, UserNickChange,
+        Broadcast, ChannelDirectMessage, ChannelEmptied, ChannelFetchCreationTime,
+        ChannelFetchMetadata, ChannelFetchTopic, ChannelFetchWhoList, ChannelInvite, ChannelJoin,
+        ChannelKickUser, ChannelMemberList, ChannelMembershipChanged, ChannelMessage, ChannelPart,
+        ChannelRedact, ChannelSetMetadata, ChannelSetMode, ChannelUpdateTopic, ClientAway,
+        CloseChannel, FetchUserPermission, MessageKind, PermissionsChanged, ResolveTarget,
+        ServerDisconnect, SpyChannel, UserKickedFromChannel,
     },
     persistence::{
-        events::{FetchAllUserChannelPermissions, SetUserChannelPermissions},
+        events::{
+            ChannelReaction, ChannelReactionResult, FetchAllUserChannelPermissions,
+            FetchChannelInvites, FetchChannelMetadata, FetchChannelModes, RedactChannelMessage,
+            RedactChannelMessageResult, SetChannelInvite, SetChannelMetadata, SetChannelModes,
+            SetUserChannelPermissions, SubscribeChannelPermissions,
+        },
         Persistence,
     },
-    server::{response::IntoProtocol, Server},
+    proto::builder::MessageBuilder,
+    server::{
+        response::{IntoProtocol, SpyStatus, Target},
+        Server,
+    },
 };
 
 #[derive(Copy, Clone)]
 pub struct ChannelId(pub i64);
 
+/// How long the channel's `WHO` list is reused for, it's rebuilt sooner if anyone joins or
+/// leaves the channel or the channel's permissions change.
+const WHO_CACHE_TTL: Duration = Duration::from_secs(10);
+
+/// The members of the channel along with the permissions they hold that come with a prefix.
+pub type WhoMembers = Vec<(Vec<Permission>, Arc<InitiatedConnection>)>;
+
 /// A channel is an IRC channel (ie. #abc) that multiple users can connect to in order
 /// to chat together.
 pub struct Channel {
     pub name: String,
     pub server: Addr<Server>,
     pub permissions: HostMaskMap<Permission>,
-    pub clients: HashMap<Addr<Client>, InitiatedConnection>,
+    /// The version of the permissions currently cached, see [`PermissionsChanged`].
+    pub permissions_version: i64,
+    /// Who set each of the `permissions` and when, keyed by mask.
+    pub permission_details: HashMap<String, PermissionDetails>,
+    pub clients: HashMap<Addr<Client>, Arc<InitiatedConnection>>,
+    /// Operators observing the channel's traffic through `SPY`, who are sent everything the
+    /// members are without showing up in `NAMES` or `WHO`.
+    pub spies: HashMap<Addr<Client>, Arc<InitiatedConnection>>,
     pub topic: Option<CurrentChannelTopic>,
+    pub modes: ChannelModeState,
+    /// Users that have been invited to the channel and haven't joined since, allowing them to
+    /// join whilst the channel is invite-only.
+    pub invites: HashSet<UserId>,
+    /// Descriptive information set by the channel's operators through `CS SET`.
+    pub metadata: ChannelMetadata,
+    /// The members last sent in reply to `WHO` and when they were looked up, as some clients
+    /// query the channel on every join. Cleared whenever membership or permissions change.
+    pub who_cache: Option<(Instant, WhoMembers)>,
     pub persistence: Addr<Persistence>,
     pub channel_id: ChannelId,
+    pub created_at: DateTime<Utc>,
+    /// Whether the channel is temporary, in which case none of its state is persisted and it's
+    /// destroyed once the last member leaves.
+    pub temporary: bool,
 }
 
 impl Actor for Channel {
     type Context = Context<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
+        // temporary channels start out fresh every time
+        if self.temporary {
+            return;
+        }
+
         ctx.wait(
             self.persistence
                 .send(crate::persistence::events::ChannelCreated {
@@ -63,8 +119,9 @@ impl Actor for Channel {
                 .into_actor(self)
                 .then(|res, this, ctx| {
                     match res {
-                        Ok(channel_id) => {
+                        Ok((channel_id, created_at)) => {
                             this.channel_id.0 = channel_id;
+                            this.created_at = created_at;
                         }
                         Err(error) => {
                             error!(%error, "Failed to create channel in database");
@@ -72,18 +129,78 @@ impl Actor for Channel {
                         }
                     }
 
+                    // subscribe before fetching, so no change can be made between the two
+                    // without us hearing about it
+                    this.persistence.do_send(SubscribeChannelPermissions {
+                        channel_id: this.channel_id,
+                        channel: ctx.address().recipient(),
+                    });
+
                     this.persistence
                         .send(FetchAllUserChannelPermissions {
                             channel_id: this.channel_id,
                         })
                         .into_actor(this)
                 })
+                .then(|res, this, ctx| {
+                    match res {
+                        Ok((permissions, details, version)) => {
+                            this.permissions = permissions;
+                            this.permission_details = details;
+                            this.permissions_version = version;
+                        }
+                        Err(error) => {
+                            error!(%error, "Failed to fetch channel permissions");
+                            ctx.terminate();
+                        }
+                    }
+
+                    this.persistence
+                        .send(FetchChannelModes {
+                            channel_id: this.channel_id,
+                        })
+                        .into_actor(this)
+                })
+                .then(|res, this, ctx| {
+                    match res {
+                        Ok(modes) => {
+                            this.modes = modes;
+                        }
+                        Err(error) => {
+                            error!(%error, "Failed to fetch channel modes");
+                            ctx.terminate();
+                        }
+                    }
+
+                    this.persistence
+                        .send(FetchChannelInvites {
+                            channel_id: this.channel_id,
+                        })
+                        .into_actor(this)
+                })
+                .then(|res, this, ctx| {
+                    match res {
+                        Ok(invites) => {
+                            this.invites = invites;
+                        }
+                        Err(error) => {
+                            error!(%error, "Failed to fetch channel invites");
+                            ctx.terminate();
+                        }
+                    }
+
+                    this.persistence
+                        .send(FetchChannelMetadata {
+                            channel_id: this.channel_id,
+                        })
+                        .into_actor(this)
+                })
                 .map(|res, this, ctx| match res {
-                    Ok(permissions) => {
-                        this.permissions = permissions;
+                    Ok(metadata) => {
+                        this.metadata = metadata;
                     }
                     Err(error) => {
-                        error!(%error, "Failed to fetch channel permissions");
+                        error!(%error, "Failed to fetch channel metadata");
                         ctx.terminate();
                     }
                 }),
@@ -94,6 +211,421 @@ impl Actor for Channel {
 impl Supervised for Channel {}
 
 impl Channel {
+    /// Sends an event to the persistence actor, unless the channel is temporary.
+    fn persist<M>(&self, msg: M)
+    where
+        M: actix::Message + Send + 'static,
+        M::Result: Send,
+        Persistence: Handler<M>,
+    {
+        if !self.temporary {
+            self.persistence.do_send(msg);
+        }
+    }
+
+    /// Lets the server know `client` joined or left the channel.
+    fn membership_changed(&self, ctx: &Context<Self>, client: Addr<Client>, joined: bool) {
+        self.server.do_send(ChannelMembershipChanged {
+            channel: self.name.to_string(),
+            handle: ctx.address(),
+            client,
+            joined,
+            span: Span::current(),
+        });
+    }
+
+    /// Asks the server to destroy the channel if it's temporary and the last member just left.
+    fn close_if_empty(&self, ctx: &mut Context<Self>) {
+        if self.temporary && self.clients.is_empty() {
+            self.server.do_send(ChannelEmptied {
+                name: self.name.to_string(),
+                handle: ctx.address(),
+                span: Span::current(),
+            });
+        }
+    }
+
+    /// Sends a message to every member of the channel with at least the `status` permission,
+    /// or every member if no status is given, skipping `except`.
+    pub fn broadcast_to(
+        &self,
+        status: Option<Permission>,
+        except: Option<&Addr<Client>>,
+        message: &Message,
+    ) {
+        for (client, connection) in &self.clients {
+            if Some(client) == except {
+                continue;
+            }
+
+            if status.is_some_and(|status| {
+                self.get_user_permissions(&connection.to_host_mask()) < status
+            }) {
+                continue;
+            }
+
+            client.do_send(Broadcast {
+                span: Span::current(),
+                message: message.clone(),
+            });
+        }
+
+        self.broadcast_to_spies(message);
+    }
+
+    /// Sends a message to every operator observing the channel who isn't also a member, and so
+    /// wouldn't have received it already.
+    fn broadcast_to_spies(&self, message: &Message) {
+        for (client, _) in self.spies_outside_channel() {
+            client.do_send(Broadcast {
+                span: Span::current(),
+                message: message.clone(),
+            });
+        }
+    }
+
+    /// Operators observing the channel that aren't members of it, skipping any that have since
+    /// disconnected.
+    fn spies_outside_channel(
+        &self,
+    ) -> impl Iterator<Item = (&Addr<Client>, &Arc<InitiatedConnection>)> {
+        self.spies
+            .iter()
+            .filter(|(client, _)| client.connected() && !self.clients.contains_key(*client))
+    }
+
+    /// Delivers a message sent by `client` to the rest of the channel, echoing it back to them
+    /// if they negotiated `echo-message`.
+    fn relay_message(
+        &self,
+        status: Option<Permission>,
+        client: &Addr<Client>,
+        echo: bool,
+        message: Message,
+    ) {
+        self.broadcast_to(status, Some(client), &message);
+
+        // only echo the message back to the sender if they asked for it, regardless of whether
+        // they'd have received a status message themselves
+        if echo {
+            client.do_send(Broadcast {
+                message,
+                span: Span::current(),
+            });
+        }
+    }
+
+    /// Sends an `AWAY` message to every member of the channel that has negotiated `away-notify`,
+    /// skipping `except`.
+    pub fn broadcast_away_notify(&self, except: Option<&Addr<Client>>, message: &Message) {
+        for (client, connection) in &self.clients {
+            if Some(client) == except || !connection.capabilities.contains(Capability::AWAY_NOTIFY)
+            {
+                continue;
+            }
+
+            client.do_send(Broadcast {
+                span: Span::current(),
+                message: message.clone(),
+            });
+        }
+    }
+
+    /// `NAMES` has no way of conveying away state, so members joining with `away-notify` are
+    /// sent an `AWAY` for each member that's already away.
+    fn away_members_for(
+        &self,
+        client: &Addr<Client>,
+        connection: &InitiatedConnection,
+    ) -> Vec<Message> {
+        if !connection.capabilities.contains(Capability::AWAY_NOTIFY) {
+            return Vec::new();
+        }
+
+        self.clients
+            .iter()
+            .filter(|(handle, _)| *handle != client)
+            .filter_map(|(_, member)| {
+                Some(
+                    MessageBuilder::user(member.to_nick())
+                        .command(Command::AWAY(Some(member.away()?))),
+                )
+            })
+            .collect()
+    }
+
+    /// Resolves the bare nicks given as `MODE` arguments to the accounts owning them, so their
+    /// modes apply to `*!account@*`, before applying the whole line.
+    fn resolve_mode_targets(
+        &self,
+        ctx: &mut Context<Self>,
+        client: Addr<Client>,
+        requester: Arc<InitiatedConnection>,
+        changes: Vec<PendingMode>,
+    ) {
+        let server = self.server.clone();
+        let span = Span::current();
+
+        let fut = async move {
+            let mut resolved = Vec::with_capacity(changes.len());
+
+            for change in changes {
+                let PendingMode::UserByNick {
+                    add,
+                    permission,
+                    nick,
+                } = change
+                else {
+                    resolved.push(change);
+                    continue;
+                };
+
+                let target = server
+                    .send(ResolveTarget {
+                        target: nick,
+                        server: None,
+                        span: span.clone(),
+                    })
+                    .await
+                    .unwrap();
+
+                let account = match target {
+                    Target::OnlineUser { connection, .. } => connection.user.clone(),
+                    Target::OfflineAccount { account, .. } => account,
+                    Target::Channel(_) | Target::Services(_) | Target::Unknown => {
+                        // TODO: return error to caller
+                        error!("Unknown user");
+                        continue;
+                    }
+                };
+
+                resolved.push(PendingMode::User {
+                    add,
+                    permission,
+                    mask: HostMask::new("*", &account, "*").into_owned(),
+                });
+            }
+
+            resolved
+        }
+        .into_actor(self)
+        .map(move |changes, this, ctx| {
+            this.apply_mode_changes(ctx, &client, &requester, changes);
+        });
+
+        ctx.spawn(fut);
+    }
+
+    /// Applies every change from a `MODE` line at once, broadcasting a single `MODE` with the
+    /// changes that had an effect.
+    ///
+    /// Every change is validated before any are applied, so if the requester isn't allowed to
+    /// make one of them the whole line is rejected, rather than it being applied up to that
+    /// point. Changes are validated in order against the state left by the changes before them,
+    /// so lines like `+o-o` behave as they would if sent separately.
+    fn apply_mode_changes(
+        &mut self,
+        ctx: &mut Context<Self>,
+        client: &Addr<Client>,
+        requester: &InitiatedConnection,
+        changes: Vec<PendingMode>,
+    ) {
+        let permissions = self.get_user_permissions(&requester.to_host_mask());
+
+        let mut modes = self.modes.clone();
+        let mut permission_changes: Vec<(HostMask<'static>, Permission)> = Vec::new();
+        let mut applied = Vec::new();
+
+        for change in changes {
+            match change {
+                PendingMode::Channel { add, mode, arg } => {
+                    if !permissions.can_set_channel_modes() {
+                        error!("User attempted to set channel modes without privileges");
+                        client.do_send(Broadcast {
+                            message: MissingPrivileges(requester.to_nick(), self.name.to_string())
+                                .into_message(),
+                            span: Span::current(),
+                        });
+                        return;
+                    }
+
+                    if let Some(mode) = modes.apply(add, &mode, arg) {
+                        applied.push(mode);
+                    } else {
+                        debug!(?mode, "Channel mode change had no effect");
+                    }
+                }
+                PendingMode::User {
+                    add,
+                    permission,
+                    mask,
+                } => {
+                    // grab the permissions of the user we're trying to affect, taking into
+                    // account any changes to them earlier in the line
+                    let affected_user_perms = permission_changes
+                        .iter()
+                        .rev()
+                        .find(|(changed, _)| *changed == mask)
+                        .map_or_else(|| self.get_user_permissions(&mask), |(_, v)| *v);
+
+                    // calculate the new permissions that should be set on the user
+                    let new_affected_user_perms = if add {
+                        permission
+                    } else if affected_user_perms == permission {
+                        Permission::Normal
+                    } else {
+                        debug!("Removing the given permission would do nothing");
+                        continue;
+                    };
+
+                    // check if the caller can set these permissions on the user
+                    if !permissions.can_set_permission(new_affected_user_perms, affected_user_perms)
+                    {
+                        error!(
+                            ?permissions,
+                            ?new_affected_user_perms,
+                            ?affected_user_perms,
+                            "User is not allowed to set permissions for this user"
+                        );
+                        client.do_send(Broadcast {
+                            message: MissingPrivileges(requester.to_nick(), self.name.to_string())
+                                .into_message(),
+                            span: Span::current(),
+                        });
+                        return;
+                    }
+
+                    if let Some(mode) = permission.into_mode(add, mask.to_string()) {
+                        applied.push(mode);
+                    }
+
+                    permission_changes.push((mask, new_affected_user_perms));
+                }
+                PendingMode::UserByNick { .. } => {
+                    unreachable!("nicks are resolved before changes are applied")
+                }
+            }
+        }
+
+        // everything in the line has been validated, so persist the changes both locally and
+        // to the database
+        if modes != self.modes {
+            self.modes = modes;
+            self.persist(SetChannelModes {
+                channel_id: self.channel_id,
+                modes: self.modes.clone(),
+            });
+        }
+
+        for (mask, permissions) in permission_changes {
+            self.set_permission(
+                mask,
+                permissions,
+                Some(requester.to_host_mask().to_string()),
+            );
+        }
+
+        if applied.is_empty() {
+            return;
+        }
+
+        ctx.notify(Broadcast {
+            message: MessageBuilder::user(requester.to_nick())
+                .tags(server_time_tags())
+                .command(Command::ChannelMODE(self.name.to_string(), applied)),
+            span: Span::current(),
+        });
+    }
+
+    /// Sets the permissions held by `mask`, both locally and in the database, recording who set
+    /// them for the ban list.
+    fn set_permission(
+        &mut self,
+        mask: HostMask<'static>,
+        permissions: Permission,
+        set_by: Option<String>,
+    ) {
+        self.permissions.insert(&mask, permissions);
+        self.permission_details.insert(
+            mask.to_string(),
+            PermissionDetails {
+                set_by: set_by.clone(),
+                set_at: Some(Utc::now()),
+            },
+        );
+        self.who_cache = None;
+        self.persist(SetUserChannelPermissions {
+            channel_id: self.channel_id,
+            mask,
+            permissions,
+            set_by,
+        });
+    }
+
+    /// Replaces the permission cache with the permissions currently in the database, unless
+    /// the cache has since moved on to a newer version.
+    fn refetch_permissions(&self, ctx: &mut Context<Self>) {
+        let fut = self
+            .persistence
+            .send(FetchAllUserChannelPermissions {
+                channel_id: self.channel_id,
+            })
+            .into_actor(self)
+            .map(|res, this, _ctx| match res {
+                Ok((permissions, details, version)) if version > this.permissions_version => {
+                    this.permissions = permissions;
+                    this.permission_details = details;
+                    this.permissions_version = version;
+                    this.who_cache = None;
+                }
+                Ok(_) => {}
+                Err(error) => error!(%error, "Failed to refetch channel permissions"),
+            });
+
+        ctx.spawn(fut);
+    }
+
+    /// Returns the channel's members for `WHO`, reusing the last lookup if it's recent enough.
+    fn who_members(&mut self) -> WhoMembers {
+        let now = Instant::now();
+
+        if let Some((built_at, members)) = &self.who_cache {
+            if now.saturating_duration_since(*built_at) < WHO_CACHE_TTL {
+                return members.clone();
+            }
+        }
+
+        let members: WhoMembers = self
+            .clients
+            .values()
+            .map(|v| {
+                (
+                    self.get_user_prefix_permissions(&v.to_host_mask()),
+                    v.clone(),
+                )
+            })
+            .collect();
+        self.who_cache = Some((now, members.clone()));
+
+        members
+    }
+
+    /// Returns true if the channel is secret or private and the requester isn't in it. Requests
+    /// without a requester, such as those made by operators, can see every channel.
+    fn hidden_from(&self, requester: Option<&Addr<Client>>) -> bool {
+        self.modes.visibility().is_hidden()
+            && requester.is_some_and(|v| !self.clients.contains_key(v))
+    }
+
+    /// Leaves out invisible members, unless the requester is in the channel with them.
+    fn retain_visible(&self, requester: Option<&Addr<Client>>, members: &mut WhoMembers) {
+        if requester.is_none_or(|v| self.clients.contains_key(v)) {
+            return;
+        }
+
+        members.retain(|(_, conn)| !conn.mode().contains(UserMode::INVISIBLE));
+    }
+
     /// Grabs the user's permissions from the permission cache, defaulting to `Normal`.
     #[must_use]
     pub fn get_user_permissions(&self, host_mask: &HostMask<'_>) -> Permission {
@@ -104,6 +636,50 @@ impl Channel {
             .max()
             .unwrap_or(Permission::Normal)
     }
+
+    /// Grabs every permission the user holds that comes with a prefix, from any mask matching
+    /// them, ordered highest first.
+    #[must_use]
+    pub fn get_user_prefix_permissions(&self, host_mask: &HostMask<'_>) -> Vec<Permission> {
+        let mut permissions: Vec<_> = self
+            .permissions
+            .get(host_mask)
+            .into_iter()
+            .copied()
+            .filter(|v| *v >= Permission::Voice)
+            .collect();
+        permissions.sort_unstable_by(|a, b| b.cmp(a));
+        permissions.dedup();
+        permissions
+    }
+}
+
+/// Keeps the permission cache in sync with changes made to the channel's permissions, whether
+/// by this channel or from elsewhere. If any changes were missed, the whole cache is refetched.
+impl Handler<PermissionsChanged> for Channel {
+    type Result = ();
+
+    fn handle(&mut self, msg: PermissionsChanged, ctx: &mut Self::Context) -> Self::Result {
+        if msg.version <= self.permissions_version {
+            // already reflected by a refetch
+            return;
+        }
+
+        if msg.version == self.permissions_version + 1 {
+            self.permissions.insert(&msg.mask, msg.permissions);
+            self.permission_details
+                .insert(msg.mask.to_string(), msg.details);
+            self.permissions_version = msg.version;
+            self.who_cache = None;
+        } else {
+            debug!(
+                current = self.permissions_version,
+                received = msg.version,
+                "Missed channel permission changes, refetching"
+            );
+            self.refetch_permissions(ctx);
+        }
+    }
 }
 
 /// Broadcast a raw IRC message to all clients connected to this channel.
@@ -115,6 +691,26 @@ impl Handler<Broadcast> for Channel {
         for client in self.clients.keys() {
             client.do_send(msg.clone());
         }
+
+        self.broadcast_to_spies(&msg.message);
+    }
+}
+
+/// Adds or removes an operator observing the channel through `SPY`.
+impl Handler<SpyChannel> for Channel {
+    type Result = MessageResult<SpyChannel>;
+
+    #[instrument(parent = &msg.span, skip_all)]
+    fn handle(&mut self, msg: SpyChannel, _ctx: &mut Self::Context) -> Self::Result {
+        self.spies.retain(|client, _| client.connected());
+
+        MessageResult(Ok(if msg.enabled {
+            self.spies.insert(msg.client, msg.connection);
+            SpyStatus::Enabled(self.name.to_string())
+        } else {
+            self.spies.remove(&msg.client);
+            SpyStatus::Disabled(self.name.to_string())
+        }))
     }
 }
 
@@ -122,18 +718,17 @@ impl Handler<ClientAway> for Channel {
     type Result = ();
 
     #[instrument(parent = &msg.span, skip_all)]
-    fn handle(&mut self, msg: ClientAway, ctx: &mut Self::Context) -> Self::Result {
-        if let Some(c) = self.clients.get_mut(&msg.handle) {
-            c.away = msg.message;
-            ctx.notify(Broadcast {
-                message: Message {
-                    tags: None,
-                    prefix: Some(c.to_nick()),
-                    command: Command::AWAY(c.away.clone()),
-                },
-                span: msg.span,
-            });
-        }
+    fn handle(&mut self, msg: ClientAway, _ctx: &mut Self::Context) -> Self::Result {
+        let Some(c) = self.clients.get(&msg.handle) else {
+            return;
+        };
+
+        self.broadcast_away_notify(
+            Some(&msg.handle),
+            &MessageBuilder::user(c.to_nick())
+                .tags(server_time_tags())
+                .command(Command::AWAY(msg.message)),
+        );
     }
 }
 
@@ -142,7 +737,11 @@ impl Handler<FetchUserPermission> for Channel {
     type Result = MessageResult<FetchUserPermission>;
 
     fn handle(&mut self, msg: FetchUserPermission, _ctx: &mut Self::Context) -> Self::Result {
-        MessageResult(self.get_user_permissions(&msg.host_mask))
+        if self.hidden_from(msg.requester.as_ref()) {
+            return MessageResult(None);
+        }
+
+        MessageResult(Some(self.get_user_permissions(&msg.host_mask)))
     }
 }
 
@@ -152,7 +751,14 @@ impl Handler<ChannelMemberList> for Channel {
 
     #[instrument(parent = &msg.span, skip_all)]
     fn handle(&mut self, msg: ChannelMemberList, _ctx: &mut Self::Context) -> Self::Result {
-        MessageResult(ChannelNamesList::new(self))
+        // hidden channels are answered as if they didn't exist
+        if self.hidden_from(msg.requester.as_ref()) {
+            return MessageResult(ChannelNamesList::empty(self.name.to_string()));
+        }
+
+        let mut list = ChannelNamesList::new(self);
+        self.retain_visible(msg.requester.as_ref(), &mut list.nick_list);
+        MessageResult(list)
     }
 }
 
@@ -161,7 +767,7 @@ impl Handler<ChannelMessage> for Channel {
     type Result = ();
 
     #[instrument(parent = &msg.span, skip_all)]
-    fn handle(&mut self, msg: ChannelMessage, _ctx: &mut Self::Context) -> Self::Result {
+    fn handle(&mut self, msg: ChannelMessage, ctx: &mut Self::Context) -> Self::Result {
         // ensure the user is actually in the channel by their handle, and grab their
         // nick & host if they are
         let Some(sender) = self.clients.get(&msg.client) else {
@@ -169,23 +775,20 @@ impl Handler<ChannelMessage> for Channel {
             return;
         };
 
-        if !self
-            .get_user_permissions(&sender.to_host_mask())
-            .can_chatter()
+        let permissions = self.get_user_permissions(&sender.to_host_mask());
+
+        if !permissions.can_chatter()
+            || (self.modes.moderated && !permissions.can_chatter_moderated())
         {
             msg.client.do_send(Broadcast {
-                message: Message {
-                    tags: None,
-                    prefix: None,
-                    command: Command::Response(
-                        Response::ERR_CANNOTSENDTOCHAN,
-                        vec![
-                            sender.to_nick().to_string(),
-                            self.name.to_string(),
-                            "Cannot send to channel".to_string(),
-                        ],
-                    ),
-                },
+                message: MessageBuilder::server().response(
+                    Response::ERR_CANNOTSENDTOCHAN,
+                    vec![
+                        sender.to_nick().to_string(),
+                        self.name.to_string(),
+                        "Cannot send to channel".to_string(),
+                    ],
+                ),
                 span: Span::current(),
             });
 
@@ -194,40 +797,216 @@ impl Handler<ChannelMessage> for Channel {
 
         // build the nick prefix for the message we're about to broadcast
         let nick = sender.to_nick();
-
-        // TODO: implement client msg recv acks
-        self.persistence
-            .do_send(crate::persistence::events::ChannelMessage {
+        let echo = sender.capabilities.contains(Capability::ECHO_MESSAGE);
+        let msgid = new_msgid();
+
+        // messages addressed to a subset of the channel aren't persisted, since history is
+        // replayed to every member of the channel
+        if msg.status.is_none() && msg.kind.is_persisted() {
+            // TODO: implement client msg recv acks
+            self.persist(crate::persistence::events::ChannelMessage {
                 channel_id: self.channel_id,
+                msgid: msgid.clone(),
                 sender: nick.to_string(),
                 message: msg.message.to_string(),
                 receivers: self.clients.values().map(|v| v.user_id).collect(),
                 kind: msg.kind,
             });
+        }
 
-        for client in self.clients.keys() {
-            if client == &msg.client {
-                // don't echo the message back to the sender
-                continue;
-            }
+        // reactions are kept alongside the message they're reacting to, so they're only
+        // relayed once we know they haven't hit the cap on reactions to the message
+        let reaction = (msg.kind == MessageKind::Tag && msg.status.is_none() && !self.temporary)
+            .then(|| parse_reaction(&msg.tags))
+            .flatten()
+            .map(|(reply, reaction)| ChannelReaction {
+                channel_id: self.channel_id,
+                msgid: reply,
+                user_id: sender.user_id,
+                sender: nick.to_string(),
+                reaction,
+            });
 
-            // broadcast the message to `client`
-            client.do_send(Broadcast {
+        let target = format!(
+            "{}{}",
+            msg.status.map_or("", Permission::into_prefix),
+            self.name
+        );
+
+        let message = MessageBuilder::user(nick)
+            .tags(
+                TagBuilder::default()
+                    .insert(server_time_tag(Utc::now()))
+                    .insert(msgid_tag(msgid))
+                    .extend(msg.tags),
+            )
+            .command(msg.kind.into_command(target, msg.message));
+
+        let Some(reaction) = reaction else {
+            self.relay_message(msg.status, &msg.client, echo, message);
+            return;
+        };
+
+        let reply = reaction.msgid.clone();
+        let fut =
+            self.persistence
+                .send(reaction)
+                .into_actor(self)
+                .map(move |result, this, _ctx| {
+                    if result.unwrap() == ChannelReactionResult::TooManyReactions {
+                        msg.client.do_send(Broadcast {
+                            message: TooManyReactions(this.name.to_string(), reply).into_message(),
+                            span: Span::current(),
+                        });
+                    } else {
+                        this.relay_message(msg.status, &msg.client, echo, message);
+                    }
+                });
+
+        ctx.spawn(fut);
+    }
+}
+
+/// Pulls the msgid being reacted to and the reaction out of a `TAGMSG`'s client tags, if the
+/// message is a `+draft/react` reaction.
+fn parse_reaction(tags: &[Tag]) -> Option<(String, String)> {
+    let tag = |name: &str| {
+        tags.iter()
+            .find(|Tag(key, _)| key == name)
+            .and_then(|Tag(_, value)| value.clone())
+            .filter(|v| !v.is_empty())
+    };
+
+    Some((tag("+draft/reply")?, tag("+draft/react")?))
+}
+
+/// Tombstones a message sent to the channel, letting members that negotiated
+/// `draft/message-redaction` know it's been redacted. Users can redact their own messages,
+/// channel operators can redact anyone's.
+impl Handler<ChannelRedact> for Channel {
+    type Result = ();
+
+    #[instrument(parent = &msg.span, skip_all)]
+    fn handle(&mut self, msg: ChannelRedact, ctx: &mut Self::Context) -> Self::Result {
+        let Some(sender) = self.clients.get(&msg.client).cloned() else {
+            error!("Received redaction from user not in channel");
+            return;
+        };
+
+        // messages sent to temporary channels are never persisted, so there's nothing to redact
+        if self.temporary {
+            msg.client.do_send(Broadcast {
+                message: RedactFailed::UnknownMessage(self.name.to_string(), msg.msgid)
+                    .into_message(),
+                span: Span::current(),
+            });
+            return;
+        }
+
+        let account = (!self
+            .get_user_permissions(&sender.to_host_mask())
+            .can_redact_others())
+        .then(|| sender.user.clone());
+
+        let fut = self
+            .persistence
+            .send(RedactChannelMessage {
+                channel_id: self.channel_id,
+                msgid: msg.msgid.clone(),
+                account,
+            })
+            .into_actor(self)
+            .map(move |result, this, _ctx| {
+                let failure = match result.unwrap() {
+                    RedactChannelMessageResult::Redacted => None,
+                    RedactChannelMessageResult::Forbidden => Some(RedactFailed::Forbidden(
+                        this.name.to_string(),
+                        msg.msgid.clone(),
+                    )),
+                    RedactChannelMessageResult::UnknownMessage => Some(
+                        RedactFailed::UnknownMessage(this.name.to_string(), msg.msgid.clone()),
+                    ),
+                };
+
+                if let Some(failure) = failure {
+                    msg.client.do_send(Broadcast {
+                        message: failure.into_message(),
+                        span: Span::current(),
+                    });
+                    return;
+                }
+
+                let message = MessageBuilder::user(sender.to_nick())
+                    .tags(server_time_tags())
+                    .command(Command::Raw(
+                        "REDACT".to_string(),
+                        [this.name.to_string(), msg.msgid]
+                            .into_iter()
+                            .chain(msg.reason)
+                            .collect(),
+                    ));
+
+                for (client, connection) in &this.clients {
+                    if connection
+                        .capabilities
+                        .contains(Capability::MESSAGE_REDACTION)
+                    {
+                        client.do_send(Broadcast {
+                            message: message.clone(),
+                            span: Span::current(),
+                        });
+                    }
+                }
+            });
+
+        ctx.spawn(fut);
+    }
+}
+
+/// Sends a message from a voiced user or channel operator to a single member of the channel, as
+/// requested by `CPRIVMSG`/`CNOTICE`.
+impl Handler<ChannelDirectMessage> for Channel {
+    type Result = bool;
+
+    #[instrument(parent = &msg.span, skip_all)]
+    fn handle(&mut self, msg: ChannelDirectMessage, _ctx: &mut Self::Context) -> Self::Result {
+        let Some(sender) = self.clients.get(&msg.client) else {
+            error!("Received message from user not in channel");
+            return false;
+        };
+
+        if !self
+            .get_user_permissions(&sender.to_host_mask())
+            .can_direct_message()
+        {
+            msg.client.do_send(Broadcast {
+                message: MissingPrivileges(sender.to_nick(), self.name.to_string()).into_message(),
                 span: Span::current(),
-                message: Message {
-                    tags: None,
-                    prefix: Some(nick.clone()),
-                    command: match msg.kind {
-                        MessageKind::Normal => {
-                            Command::PRIVMSG(self.name.to_string(), msg.message.clone())
-                        }
-                        MessageKind::Notice => {
-                            Command::NOTICE(self.name.to_string(), msg.message.clone())
-                        }
-                    },
-                },
             });
+            return false;
         }
+
+        let Some((target, target_conn)) = self
+            .clients
+            .iter()
+            .find(|(_handle, conn)| nick_eq(&conn.nick(), &msg.nick))
+        else {
+            msg.client.do_send(Broadcast {
+                message: UserNotInChannel(sender.to_nick(), msg.nick, self.name.to_string())
+                    .into_message(),
+                span: Span::current(),
+            });
+            return true;
+        };
+
+        target.do_send(Broadcast {
+            message: MessageBuilder::user(sender.to_nick())
+                .tags(server_time_tags())
+                .command(msg.kind.into_command(target_conn.nick(), msg.message)),
+            span: Span::current(),
+        });
+
+        true
     }
 }
 
@@ -236,7 +1015,14 @@ impl Handler<ChannelFetchWhoList> for Channel {
 
     #[instrument(parent = &msg.span, skip_all)]
     fn handle(&mut self, msg: ChannelFetchWhoList, _ctx: &mut Self::Context) -> Self::Result {
-        MessageResult(ChannelWhoList::new(self))
+        let mut nick_list = self.who_members();
+        self.retain_visible(msg.requester.as_ref(), &mut nick_list);
+
+        MessageResult(ChannelWhoList {
+            channel_name: self.name.to_string(),
+            nick_list,
+            multi_prefix: msg.multi_prefix,
+        })
     }
 }
 
@@ -249,134 +1035,83 @@ impl Handler<ChannelSetMode> for Channel {
             return MessageResult(None);
         };
 
+        if msg.modes.is_empty() {
+            return MessageResult(Some(ModeList::Channel(ChannelModes {
+                channel: self.name.to_string(),
+                modes: self.modes.to_arguments(),
+                created_at: ChannelCreationTime::new(self),
+            })));
+        }
+
+        let mut changes = Vec::with_capacity(msg.modes.len());
+
         for mode in msg.modes {
-            // TODO
-            let (add, channel_mode, arg) = match mode.clone() {
+            let (add, channel_mode, arg) = match mode {
                 Mode::Plus(mode, arg) => (true, mode, arg),
                 Mode::Minus(mode, arg) => (false, mode, arg),
             };
 
-            if let Ok(user_mode) = Permission::try_from(channel_mode) {
-                let Some(affected_mask) = arg else {
-                    if add && matches!(user_mode, Permission::Ban) {
-                        // list is readable and the user didn't supply a mask, so
-                        // return the list
-                        let bans = BanList {
-                            channel: self.name.to_string(),
-                            list: self
-                                .permissions
-                                .iter()
-                                .filter(|(_, v)| matches!(v, Permission::Ban))
-                                .map(|(k, _)| k)
-                                .collect(),
-                        };
-
-                        return MessageResult(Some(ModeList::Ban(bans)));
-                    }
+            let Ok(permission) = Permission::try_from(channel_mode.clone()) else {
+                changes.push(PendingMode::Channel {
+                    add,
+                    mode: channel_mode,
+                    arg,
+                });
+                continue;
+            };
 
-                    error!("No user given");
-                    break;
-                };
+            let Some(affected_mask) = arg else {
+                if add && matches!(permission, Permission::Ban) {
+                    // list is readable and the user didn't supply a mask, so
+                    // return the list
+                    return MessageResult(Some(ModeList::Ban(BanList::new(self, 1))));
+                }
 
-                let Ok(affected_mask) = HostMask::try_from(affected_mask.as_str()) else {
-                    // TODO: return error to caller
-                    error!("Invalid mask");
-                    continue;
-                };
+                error!("No user given");
+                continue;
+            };
+
+            // nicks can't start with a digit, so a number is a request for a later page of the
+            // list rather than a nick to ban
+            if add && matches!(permission, Permission::Ban) {
+                if let Ok(page) = affected_mask.parse::<usize>() {
+                    return MessageResult(Some(ModeList::Ban(BanList::new(self, page))));
+                }
+            }
 
-                ctx.notify(SetUserMode {
-                    requester: client.clone(),
+            // a bare nick refers to the account owning it, rather than anyone using the nick
+            if !affected_mask.contains(['!', '@']) {
+                changes.push(PendingMode::UserByNick {
                     add,
-                    affected_mask: affected_mask.into_owned(),
-                    user_mode,
-                    span: Span::current(),
+                    permission,
+                    nick: affected_mask,
                 });
-            } else {
-                // TODO
+                continue;
             }
-        }
-
-        MessageResult(None)
-    }
-}
 
-/// Called by other users to set a permission on a user.
-///
-/// Users are currently only able to set permissions of users that are currently at a lower
-/// permission level to themselves, and can only set permissions to levels lower than their
-/// own.
-impl Handler<SetUserMode> for Channel {
-    type Result = ();
-
-    #[instrument(parent = &msg.span, skip_all)]
-    fn handle(&mut self, msg: SetUserMode, ctx: &mut Self::Context) -> Self::Result {
-        let permissions = self.get_user_permissions(&msg.requester.to_host_mask());
+            let Ok(affected_mask) = HostMask::try_from(affected_mask.as_str()) else {
+                // TODO: return error to caller
+                error!("Invalid mask");
+                continue;
+            };
 
-        // grab the permissions of the user we're trying to affect
-        let affected_user_perms = self.get_user_permissions(&msg.affected_mask);
+            changes.push(PendingMode::User {
+                add,
+                permission,
+                mask: affected_mask.into_owned(),
+            });
+        }
 
-        // calculate the new permissions that should be set on the user
-        let new_affected_user_perms = if msg.add {
-            msg.user_mode
-        } else if affected_user_perms == msg.user_mode {
-            Permission::Normal
+        if changes
+            .iter()
+            .any(|change| matches!(change, PendingMode::UserByNick { .. }))
+        {
+            self.resolve_mode_targets(ctx, msg.client, client, changes);
         } else {
-            error!("Removing the given permission would do nothing");
-            return;
-        };
-
-        // check if the caller can set these permissions on the user
-        if !permissions.can_set_permission(new_affected_user_perms, affected_user_perms) {
-            error!(
-                ?permissions,
-                ?new_affected_user_perms,
-                ?affected_user_perms,
-                "User is not allowed to set permissions for this user"
-            );
-
-            return;
+            self.apply_mode_changes(ctx, &msg.client, &client, changes);
         }
 
-        // persist the permissions change both locally and to the database
-        self.permissions
-            .insert(&msg.affected_mask, new_affected_user_perms);
-        self.persistence.do_send(SetUserChannelPermissions {
-            channel_id: self.channel_id,
-            mask: msg.affected_mask.clone().into_owned(),
-            permissions: new_affected_user_perms,
-        });
-
-        let Some(mode) = msg
-            .user_mode
-            .into_mode(msg.add, msg.affected_mask.to_string())
-        else {
-            return;
-        };
-
-        ctx.notify(Broadcast {
-            message: Message {
-                tags: None,
-                prefix: Some(msg.requester.to_nick()),
-                command: Command::ChannelMODE(self.name.to_string(), vec![mode]),
-            },
-            span: Span::current(),
-        });
-    }
-}
-
-/// Received when a user changes their nick.
-impl Handler<UserNickChange> for Channel {
-    type Result = ();
-
-    fn handle(&mut self, msg: UserNickChange, _ctx: &mut Self::Context) -> Self::Result {
-        // grab the user's current info
-        let Some(sender) = self.clients.get_mut(&msg.client) else {
-            return;
-        };
-
-        // update the user's info with the latest `connection` details and new nick
-        *sender = msg.connection;
-        sender.nick = msg.new_nick;
+        MessageResult(None)
     }
 }
 
@@ -392,9 +1127,19 @@ impl Handler<ChannelJoin> for Channel {
 
     #[instrument(parent = &msg.span, skip_all)]
     fn handle(&mut self, msg: ChannelJoin, ctx: &mut Self::Context) -> Self::Result {
-        info!(self.name, msg.connection.nick, "User is joining channel");
+        let nick = msg.connection.nick();
+        info!(self.name, nick, "User is joining channel");
+
+        // the user is already in the channel, so there's nothing left to do
+        if self.clients.contains_key(&msg.client) {
+            debug!(self.name, "User is already in channel, ignoring join");
+            return MessageResult(Ok(Ok(ChannelJoinBurst {
+                handle: ctx.address(),
+                channel_id: self.channel_id,
+            })));
+        }
 
-        let mut permissions = self
+        let permissions = self
             .permissions
             .get(&msg.connection.to_host_mask())
             .into_iter()
@@ -406,79 +1151,131 @@ impl Handler<ChannelJoin> for Channel {
             return MessageResult(Ok(Err(ChannelJoinRejectionReason::Banned)));
         }
 
-        // persist the user's join to the database
-        self.persistence
-            .do_send(crate::persistence::events::ChannelJoined {
+        if !permissions.can_bypass_join_restrictions() {
+            let rejection =
+                if self.modes.invite_only && !self.invites.contains(&msg.connection.user_id) {
+                    Some(ChannelJoinRejectionReason::InviteOnly(
+                        self.name.to_string(),
+                    ))
+                } else if self
+                    .modes
+                    .key
+                    .as_deref()
+                    .is_some_and(|key| msg.key.as_deref() != Some(key))
+                {
+                    Some(ChannelJoinRejectionReason::BadKey(self.name.to_string()))
+                } else if self
+                    .modes
+                    .limit
+                    .is_some_and(|limit| self.clients.len() >= limit)
+                {
+                    Some(ChannelJoinRejectionReason::Full(self.name.to_string()))
+                } else {
+                    None
+                };
+
+            if let Some(rejection) = rejection {
+                return MessageResult(Ok(Err(rejection)));
+            }
+        }
+
+        // invites can only be used once
+        if self.invites.remove(&msg.connection.user_id) {
+            self.persist(SetChannelInvite {
                 channel_id: self.channel_id,
                 user_id: msg.connection.user_id,
-                span: msg.span.clone(),
+                invited: false,
             });
+        }
+
+        // persist the user's join to the database
+        self.persist(crate::persistence::events::ChannelJoined {
+            channel_id: self.channel_id,
+            user_id: msg.connection.user_id,
+            span: msg.span.clone(),
+        });
 
         // we need to send out the set user channel permissions after the channel joined persistence
         // event has been sent so the user's row exists
         if self.permissions.is_empty() {
             // the first person to ever join the channel should get founder permissions
-            permissions = Permission::Founder;
+            let username_mask = HostMask::new("*", &msg.connection.user, "*").into_owned();
 
-            let username_mask = HostMask::new("*", &msg.connection.user, "*");
-
-            self.permissions.insert(&username_mask, permissions);
-
-            self.persistence.do_send(SetUserChannelPermissions {
-                channel_id: self.channel_id,
-                mask: username_mask.into_owned(),
-                permissions,
-            });
+            self.set_permission(username_mask, Permission::Founder, None);
         }
 
         self.clients
             .insert(msg.client.clone(), msg.connection.clone());
+        self.who_cache = None;
 
-        // broadcast the user's join to everyone in the channel, including the joining user
-        for client in self.clients.keys() {
-            client.do_send(Broadcast {
-                span: Span::current(),
-                message: Message {
-                    tags: None,
-                    prefix: Some(msg.connection.to_nick()),
-                    command: Command::JOIN(self.name.to_string(), None, None),
-                },
-            });
+        let join = MessageBuilder::user(msg.connection.to_nick())
+            .tags(server_time_tags())
+            .command(Command::JOIN(self.name.to_string(), None, None));
 
-            if let Some(mode) = permissions.into_mode(true, msg.connection.nick.to_string()) {
-                client.do_send(Broadcast {
-                    span: Span::current(),
-                    message: Message {
-                        tags: None,
-                        prefix: Some(msg.connection.to_nick()),
-                        command: Command::ChannelMODE(self.name.to_string(), vec![mode]),
-                    },
-                });
+        // the user learns their own permissions through the prefixes in the member list, but
+        // the channel's modes are only worth sending if they differ from the defaults
+        let modes = if self.modes == ChannelModeState::default() {
+            ChannelCreationTime::new(self).into_messages(&nick)
+        } else {
+            ChannelModes {
+                channel: self.name.to_string(),
+                modes: self.modes.to_arguments(),
+                created_at: ChannelCreationTime::new(self),
             }
-        }
+            .into_messages(&nick)
+        };
 
-        // send the channel's topic to the joining user
-        for message in ChannelTopic::new(self, true).into_messages(&self.name) {
+        // send the joining user their burst before anything else the channel sends them can be
+        // queued, in order of the join itself, followed by the channel's topic, its modes and
+        // creation time and then its member list
+        let burst = once(join.clone())
+            .chain(ChannelTopic::new(self, true).into_messages(&nick))
+            .chain(modes)
+            .chain(
+                ChannelUrl::new(self)
+                    .into_iter()
+                    .flat_map(|v| v.into_messages(&nick)),
+            )
+            .chain(
+                ChannelNamesList::new(self)
+                    .into_messages(nick.clone(), msg.connection.capabilities),
+            )
+            .chain(self.away_members_for(&msg.client, &msg.connection));
+
+        for message in burst {
             msg.client.do_send(Broadcast {
-                message,
                 span: Span::current(),
+                message,
             });
         }
 
-        // send the user list to the user
-        for message in ChannelNamesList::new(self).into_messages(
-            msg.connection.nick.to_string(),
-            msg.connection
-                .capabilities
-                .contains(Capability::USERHOST_IN_NAMES),
-        ) {
-            msg.client.do_send(Broadcast {
-                message,
+        self.membership_changed(ctx, msg.client.clone(), true);
+
+        // broadcast the user's join to everyone else in the channel
+        for client in self.clients.keys().filter(|v| **v != msg.client) {
+            client.do_send(Broadcast {
                 span: Span::current(),
+                message: join.clone(),
             });
         }
 
-        MessageResult(Ok(Ok(ctx.address())))
+        self.broadcast_to_spies(&join);
+
+        // let members know the user is already away, since they won't have seen the user's
+        // original `AWAY`
+        if let Some(away) = msg.connection.away() {
+            self.broadcast_away_notify(
+                Some(&msg.client),
+                &MessageBuilder::user(msg.connection.to_nick())
+                    .tags(server_time_tags())
+                    .command(Command::AWAY(Some(away))),
+            );
+        }
+
+        MessageResult(Ok(Ok(ChannelJoinBurst {
+            handle: ctx.address(),
+            channel_id: self.channel_id,
+        })))
     }
 }
 
@@ -494,10 +1291,9 @@ impl Handler<ChannelUpdateTopic> for Channel {
 
         debug!(msg.topic, "User is attempting to update channel topic");
 
-        if !self
-            .get_user_permissions(&client_info.to_host_mask())
-            .can_set_topic()
-        {
+        let permissions = self.get_user_permissions(&client_info.to_host_mask());
+
+        if !permissions.can_chatter() || (self.modes.topic_lock && !permissions.can_set_topic()) {
             error!("User attempted to set channel topic without privileges");
             msg.client.do_send(Broadcast {
                 message: MissingPrivileges(client_info.to_nick(), self.name.to_string())
@@ -509,12 +1305,12 @@ impl Handler<ChannelUpdateTopic> for Channel {
 
         self.topic = Some(CurrentChannelTopic {
             topic: msg.topic,
-            set_by: client_info.nick.to_string(),
+            set_by: client_info.nick(),
             set_time: Utc::now(),
         });
 
-        for (client, connection) in &self.clients {
-            for message in ChannelTopic::new(self, false).into_messages(&connection.nick) {
+        for (client, connection) in self.clients.iter().chain(self.spies_outside_channel()) {
+            for message in ChannelTopic::new(self, false).into_messages(&connection.nick()) {
                 client.do_send(Broadcast {
                     message,
                     span: Span::current(),
@@ -525,16 +1321,21 @@ impl Handler<ChannelUpdateTopic> for Channel {
 }
 
 /// Sent from an oper client to remove a user from the channel.
+///
+/// If `ban` is set, the user's host is banned before they're kicked. Both happen within this
+/// handler so the user can't rejoin in between the two.
 impl Handler<ChannelKickUser> for Channel {
     type Result = ();
 
-    fn handle(&mut self, msg: ChannelKickUser, _ctx: &mut Self::Context) -> Self::Result {
+    fn handle(&mut self, msg: ChannelKickUser, ctx: &mut Self::Context) -> Self::Result {
         let Some(kicker) = self.clients.get(&msg.client) else {
             error!("Kicker is unknown");
             return;
         };
 
-        if !self.get_user_permissions(&kicker.to_host_mask()).can_kick() {
+        let kicker_permissions = self.get_user_permissions(&kicker.to_host_mask());
+
+        if !kicker_permissions.can_kick() {
             error!("Kicker can not kick people from the channel");
             msg.client.do_send(Broadcast {
                 message: MissingPrivileges(kicker.to_nick(), self.name.to_string()).into_message(),
@@ -543,39 +1344,68 @@ impl Handler<ChannelKickUser> for Channel {
             return;
         }
 
+        let kicker_mask = kicker.to_host_mask().to_string();
         let kicker = kicker.to_nick();
 
         let kicked_user = self
             .clients
             .iter()
-            .find(|(_handle, client)| client.nick == msg.user)
+            .find(|(_handle, client)| nick_eq(&client.nick(), &msg.user))
             .map(|(k, v)| (k.clone(), v));
         let Some((kicked_user_handle, kicked_user_info)) = kicked_user else {
             error!(msg.user, "Attempted to kick unknown user");
             return;
         };
 
-        for client in self.clients.keys() {
-            client.do_send(Broadcast {
-                message: Message {
-                    tags: None,
-                    prefix: Some(kicker.clone()),
-                    command: Command::KICK(
-                        self.name.to_string(),
-                        kicked_user_info.nick.to_string(),
-                        msg.reason.clone(),
-                    ),
-                },
-                span: Span::current(),
-            });
+        if msg.ban {
+            let kicked_user_permissions =
+                self.get_user_permissions(&kicked_user_info.to_host_mask());
+
+            if !kicker_permissions.can_set_permission(Permission::Ban, kicked_user_permissions) {
+                error!("Kicker can not ban this user from the channel");
+                msg.client.do_send(Broadcast {
+                    message: MissingPrivileges(kicker, self.name.to_string()).into_message(),
+                    span: Span::current(),
+                });
+                return;
+            }
+
+            let mask = HostMask::new("*", "*", &kicked_user_info.cloak()).into_owned();
+
+            self.set_permission(mask.clone(), Permission::Ban, Some(kicker_mask));
+
+            if let Some(mode) = Permission::Ban.into_mode(true, mask.to_string()) {
+                self.broadcast_to(
+                    None,
+                    None,
+                    &MessageBuilder::user(kicker.clone())
+                        .tags(server_time_tags())
+                        .command(Command::ChannelMODE(self.name.to_string(), vec![mode])),
+                );
+            }
         }
 
+        self.broadcast_to(
+            None,
+            None,
+            &MessageBuilder::user(kicker)
+                .tags(server_time_tags())
+                .command(Command::KICK(
+                    self.name.to_string(),
+                    kicked_user_info.nick(),
+                    msg.reason.clone(),
+                )),
+        );
+
         kicked_user_handle.do_send(UserKickedFromChannel {
             channel: self.name.to_string(),
             span: Span::current(),
         });
 
         self.clients.remove(&kicked_user_handle);
+        self.who_cache = None;
+        self.membership_changed(ctx, kicked_user_handle, false);
+        self.close_if_empty(ctx);
     }
 }
 
@@ -589,6 +1419,56 @@ impl Handler<ChannelFetchTopic> for Channel {
     }
 }
 
+/// Returns the time the channel was created, for filtering `LIST`.
+impl Handler<ChannelFetchCreationTime> for Channel {
+    type Result = MessageResult<ChannelFetchCreationTime>;
+
+    #[instrument(parent = &msg.span, skip_all)]
+    fn handle(&mut self, msg: ChannelFetchCreationTime, _ctx: &mut Self::Context) -> Self::Result {
+        MessageResult(ChannelCreationTime::new(self))
+    }
+}
+
+impl Handler<ChannelFetchMetadata> for Channel {
+    type Result = MessageResult<ChannelFetchMetadata>;
+
+    #[instrument(parent = &msg.span, skip_all)]
+    fn handle(&mut self, msg: ChannelFetchMetadata, _ctx: &mut Self::Context) -> Self::Result {
+        MessageResult(self.metadata.clone())
+    }
+}
+
+/// Sets or clears a piece of the channel's metadata, as requested by one of its operators via
+/// `CS SET`.
+impl Handler<ChannelSetMetadata> for Channel {
+    type Result = bool;
+
+    #[instrument(parent = &msg.span, skip_all)]
+    fn handle(&mut self, msg: ChannelSetMetadata, _ctx: &mut Self::Context) -> Self::Result {
+        let Some(client_info) = self.clients.get(&msg.client) else {
+            error!("User attempted to set channel metadata without being in the channel");
+            return false;
+        };
+
+        if !self
+            .get_user_permissions(&client_info.to_host_mask())
+            .can_set_metadata()
+        {
+            error!("User attempted to set channel metadata without privileges");
+            return false;
+        }
+
+        if self.metadata.set(msg.key, msg.value) {
+            self.persist(SetChannelMetadata {
+                channel_id: self.channel_id,
+                metadata: self.metadata.clone(),
+            });
+        }
+
+        true
+    }
+}
+
 /// Received when a client is parting the channel and broadcasts it to all connected users.
 impl Handler<ChannelPart> for Channel {
     type Result = ();
@@ -598,27 +1478,29 @@ impl Handler<ChannelPart> for Channel {
         let Some(client_info) = self.clients.remove(&msg.client) else {
             return;
         };
+        self.who_cache = None;
 
         // update the client's state in the database
-        self.persistence
-            .do_send(crate::persistence::events::ChannelParted {
-                channel_id: self.channel_id,
-                user_id: client_info.user_id,
-                span: msg.span.clone(),
-            });
+        self.persist(crate::persistence::events::ChannelParted {
+            channel_id: self.channel_id,
+            user_id: client_info.user_id,
+            span: msg.span.clone(),
+        });
 
         let message = Broadcast {
-            message: Message {
-                tags: None,
-                prefix: Some(client_info.to_nick()),
-                command: Command::PART(self.name.to_string(), msg.message),
-            },
+            message: MessageBuilder::user(client_info.to_nick())
+                .tags(server_time_tags())
+                .command(Command::PART(self.name.to_string(), msg.message)),
             span: Span::current(),
         };
 
         // send the part message to both the parting user and other clients
         msg.client.do_send(message.clone());
         ctx.notify(message);
+
+        self.membership_changed(ctx, msg.client, false);
+
+        self.close_if_empty(ctx);
     }
 }
 
@@ -631,24 +1513,50 @@ impl Handler<ChannelInvite> for Channel {
             return Box::pin(futures::future::ready(ChannelInviteResult::NotOnChannel));
         };
 
+        // only those able to lift invite-only can let users past it
+        if self.modes.invite_only
+            && !self
+                .get_user_permissions(&source.to_host_mask())
+                .can_set_channel_modes()
+        {
+            return Box::pin(futures::future::ready(
+                ChannelInviteResult::MissingPrivileges,
+            ));
+        }
+
         let source = source.to_nick();
 
         let fut = self
             .server
-            .send(FetchClientByNick {
-                nick: msg.nick.clone(),
+            .send(ResolveTarget {
+                target: msg.nick.clone(),
+                server: None,
+                span: msg.span.clone(),
             })
             .into_actor(self)
-            .then(|client, this, _ctx| {
-                let client = match client.unwrap() {
-                    Some(v) if this.clients.contains_key(&v) => {
+            .then(|target, this, _ctx| {
+                let client = match target.unwrap() {
+                    Target::OnlineUser { handle, .. } if this.clients.contains_key(&handle) => {
                         return Either::Left(futures::future::ready(
                             ChannelInviteResult::UserAlreadyOnChannel,
                         ))
                         .into_actor(this);
                     }
-                    Some(v) => v,
-                    None => {
+                    Target::OnlineUser { handle, connection } => {
+                        if this.invites.insert(connection.user_id) {
+                            this.persist(SetChannelInvite {
+                                channel_id: this.channel_id,
+                                user_id: connection.user_id,
+                                invited: true,
+                            });
+                        }
+
+                        handle
+                    }
+                    Target::OfflineAccount { .. }
+                    | Target::Channel(_)
+                    | Target::Services(_)
+                    | Target::Unknown => {
                         return Either::Left(futures::future::ready(
                             ChannelInviteResult::NoSuchUser,
                         ))
@@ -661,11 +1569,9 @@ impl Handler<ChannelInvite> for Channel {
                 Either::Right(async move {
                     client
                         .send(Broadcast {
-                            message: Message {
-                                tags: None,
-                                prefix: Some(source),
-                                command: Command::INVITE(msg.nick, channel_name),
-                            },
+                            message: MessageBuilder::user(source)
+                                .tags(server_time_tags())
+                                .command(Command::INVITE(msg.nick, channel_name)),
                             span: msg.span,
                         })
                         .await
@@ -690,18 +1596,37 @@ impl Handler<ServerDisconnect> for Channel {
         let Some(client_info) = self.clients.remove(&msg.client) else {
             return;
         };
+        self.who_cache = None;
 
         let message = Broadcast {
             span: Span::current(),
-            message: Message {
-                tags: None,
-                prefix: Some(client_info.to_nick()),
-                command: Command::QUIT(msg.message),
-            },
+            message: MessageBuilder::user(client_info.to_nick())
+                .tags(server_time_tags())
+                .command(Command::QUIT(msg.message)),
         };
 
         // send the part message to all other clients
         ctx.notify(message);
+
+        self.close_if_empty(ctx);
+    }
+}
+
+/// Sent by the server once it's stopped routing users to the channel, the channel is destroyed
+/// unless someone joined in the meantime. Returns true if the channel was destroyed.
+impl Handler<CloseChannel> for Channel {
+    type Result = bool;
+
+    #[instrument(parent = &msg.span, skip_all)]
+    fn handle(&mut self, msg: CloseChannel, ctx: &mut Self::Context) -> Self::Result {
+        if !self.clients.is_empty() {
+            return false;
+        }
+
+        info!(self.name, "Destroying empty temporary channel");
+        ctx.stop();
+
+        true
     }
 }
 
@@ -712,12 +1637,26 @@ pub struct CurrentChannelTopic {
     pub set_time: DateTime<Utc>,
 }
 
-#[derive(actix::Message)]
-#[rtype(result = "()")]
-pub struct SetUserMode {
-    requester: InitiatedConnection,
-    add: bool,
-    affected_mask: HostMask<'static>,
-    user_mode: Permission,
-    span: Span,
+/// A single change from a `MODE` line, waiting to be validated and applied alongside the rest of
+/// the line.
+enum PendingMode {
+    /// Sets or unsets a channel-wide mode.
+    Channel {
+        add: bool,
+        mode: irc_proto::ChannelMode,
+        arg: Option<String>,
+    },
+    /// Sets or unsets a permission on a mask.
+    User {
+        add: bool,
+        permission: Permission,
+        mask: HostMask<'static>,
+    },
+    /// Sets or unsets a permission on the account owning a nick, resolved into a
+    /// [`PendingMode::User`] before the line is applied.
+    UserByNick {
+        add: bool,
+        permission: Permission,
+        nick: String,
+    },
 }
@@ -0,0 +1,200 @@
+//! Filters given to `LIST`, supporting the `ELIST` extensions for matching channels by their
+//! name, user count, creation time and the time their topic was set.
+
+use chrono::{DateTime, Utc};
+
+use crate::casemapping;
+
+/// The `ELIST` extensions we support, advertised via `ISUPPORT`.
+pub const SUPPORTED_EXTENSIONS: &str = "CMNTU";
+
+/// The conditions a channel has to meet to be included in `LIST`, every channel is listed if
+/// none are given.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ListQuery {
+    /// Masks the channel's name has to match one of, any name matches if empty
+    masks: Vec<String>,
+    /// `!mask`, masks the channel's name mustn't match
+    excluded_masks: Vec<String>,
+    /// `>n`, the channel has to have more than this many users
+    more_users_than: Option<usize>,
+    /// `<n`, the channel has to have fewer than this many users
+    fewer_users_than: Option<usize>,
+    /// `C>n`, the channel has to have been created before this time
+    created_before: Option<DateTime<Utc>>,
+    /// `C<n`, the channel has to have been created after this time
+    created_after: Option<DateTime<Utc>>,
+    /// `T>n`, the channel's topic has to have been set before this time
+    topic_set_before: Option<DateTime<Utc>>,
+    /// `T<n`, the channel's topic has to have been set after this time
+    topic_set_after: Option<DateTime<Utc>>,
+}
+
+impl ListQuery {
+    /// Parses the comma-separated conditions given to `LIST`, with the creation and topic times
+    /// given in minutes before `now`. Conditions that can't be parsed are ignored.
+    #[must_use]
+    pub fn parse(query: &str, now: DateTime<Utc>) -> Self {
+        let mut out = Self::default();
+        let minutes_ago = |value: &str| {
+            let minutes = value.parse::<u32>().ok()?;
+            now.checked_sub_signed(chrono::Duration::minutes(i64::from(minutes)))
+        };
+
+        for condition in query.split(',').filter(|v| !v.is_empty()) {
+            if let Some(value) = condition.strip_prefix("C>") {
+                out.created_before = minutes_ago(value);
+            } else if let Some(value) = condition.strip_prefix("C<") {
+                out.created_after = minutes_ago(value);
+            } else if let Some(value) = condition.strip_prefix("T>") {
+                out.topic_set_before = minutes_ago(value);
+            } else if let Some(value) = condition.strip_prefix("T<") {
+                out.topic_set_after = minutes_ago(value);
+            } else if let Some(value) = condition.strip_prefix('>') {
+                out.more_users_than = value.parse().ok();
+            } else if let Some(value) = condition.strip_prefix('<') {
+                out.fewer_users_than = value.parse().ok();
+            } else if let Some(mask) = condition.strip_prefix('!') {
+                out.excluded_masks.push(casemapping::fold(mask));
+            } else {
+                out.masks.push(casemapping::fold(condition));
+            }
+        }
+
+        out
+    }
+
+    /// Checks the channel's name against the masks, which can be done before asking the
+    /// channel for anything else.
+    #[must_use]
+    pub fn matches_name(&self, name: &str) -> bool {
+        let name = casemapping::fold(name);
+
+        (self.masks.is_empty() || self.masks.iter().any(|mask| glob_matches(mask, &name)))
+            && !self
+                .excluded_masks
+                .iter()
+                .any(|mask| glob_matches(mask, &name))
+    }
+
+    /// Checks the rest of the channel's state against the conditions. Channels without a topic
+    /// never match a topic condition.
+    #[must_use]
+    pub fn matches_channel(
+        &self,
+        users: usize,
+        created_at: DateTime<Utc>,
+        topic_set_at: Option<DateTime<Utc>>,
+    ) -> bool {
+        self.more_users_than.is_none_or(|n| users > n)
+            && self.fewer_users_than.is_none_or(|n| users < n)
+            && self.created_before.is_none_or(|v| created_at < v)
+            && self.created_after.is_none_or(|v| created_at > v)
+            && self
+                .topic_set_before
+                .is_none_or(|v| topic_set_at.is_some_and(|set_at| set_at < v))
+            && self
+                .topic_set_after
+                .is_none_or(|v| topic_set_at.is_some_and(|set_at| set_at > v))
+    }
+}
+
+/// Matches `name` against `mask`, where `*` matches any number of characters and `?` matches
+/// exactly one.
+fn glob_matches(mask: &str, name: &str) -> bool {
+    let (mask, name) = (mask.as_bytes(), name.as_bytes());
+    let (mut m, mut n) = (0, 0);
+    // the position of the last `*` in the mask, and the position in the name it was tried from
+    let mut backtrack = None;
+
+    while n < name.len() {
+        match mask.get(m) {
+            Some(b'*') => {
+                backtrack = Some((m, n));
+                m += 1;
+            }
+            Some(&c) if c == b'?' || c == name[n] => {
+                m += 1;
+                n += 1;
+            }
+            _ => {
+                // let the last `*` swallow one more character, if there's been one
+                let Some((star, from)) = backtrack else {
+                    return false;
+                };
+
+                backtrack = Some((star, from + 1));
+                m = star + 1;
+                n = from + 1;
+            }
+        }
+    }
+
+    mask[m..].iter().all(|c| *c == b'*')
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::{Duration, TimeZone, Utc};
+
+    use super::{glob_matches, ListQuery};
+
+    #[test]
+    fn globs() {
+        assert!(glob_matches("#chan", "#chan"));
+        assert!(glob_matches("#c*", "#chan"));
+        assert!(glob_matches("#*n", "#chan"));
+        assert!(glob_matches("#c?an", "#chan"));
+        assert!(glob_matches("*", "#chan"));
+        assert!(glob_matches("#*a*n*", "#chan"));
+        assert!(!glob_matches("#c", "#chan"));
+        assert!(!glob_matches("#c?", "#chan"));
+        assert!(!glob_matches("#*x*", "#chan"));
+    }
+
+    #[test]
+    fn names() {
+        let now = Utc::now();
+
+        assert!(ListQuery::parse("", now).matches_name("#chan"));
+        assert!(ListQuery::parse("#CH*", now).matches_name("#chan"));
+        assert!(ListQuery::parse("#other,#chan", now).matches_name("#Chan"));
+        assert!(!ListQuery::parse("#other", now).matches_name("#chan"));
+        assert!(!ListQuery::parse("!#c*", now).matches_name("#chan"));
+        assert!(!ListQuery::parse("#*,!#chan", now).matches_name("#chan"));
+        assert!(ListQuery::parse("#*,!#chan", now).matches_name("#other"));
+    }
+
+    #[test]
+    fn conditions() {
+        let now = Utc.with_ymd_and_hms(2024, 1, 1, 12, 0, 0).unwrap();
+        let hour_ago = now - Duration::hours(1);
+
+        let query = ListQuery::parse(">2,<5", now);
+        assert!(query.matches_name("#chan"));
+        assert!(query.matches_channel(3, now, None));
+        assert!(!query.matches_channel(2, now, None));
+        assert!(!query.matches_channel(5, now, None));
+
+        let query = ListQuery::parse("C>30", now);
+        assert!(query.matches_channel(1, hour_ago, None));
+        assert!(!query.matches_channel(1, now, None));
+
+        let query = ListQuery::parse("C<30", now);
+        assert!(!query.matches_channel(1, hour_ago, None));
+        assert!(query.matches_channel(1, now, None));
+
+        let query = ListQuery::parse("T>30", now);
+        assert!(query.matches_channel(1, now, Some(hour_ago)));
+        assert!(!query.matches_channel(1, now, Some(now)));
+        assert!(!query.matches_channel(1, now, None));
+
+        let query = ListQuery::parse("T<30", now);
+        assert!(!query.matches_channel(1, now, Some(hour_ago)));
+        assert!(query.matches_channel(1, now, Some(now)));
+
+        // malformed conditions are ignored
+        let query = ListQuery::parse(">lots,C<soon", now);
+        assert!(query.matches_channel(0, hour_ago, None));
+    }
+}
@@ -0,0 +1,130 @@
+//! Descriptive information about a channel set by its operators through `CS SET`, shown to users
+//! joining the channel and optionally in `LIST`.
+
+use std::str::FromStr;
+
+/// The longest value that can be stored against a piece of metadata.
+const MAX_VALUE_LENGTH: usize = 100;
+
+/// The metadata currently set on a channel.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ChannelMetadata {
+    /// The language spoken in the channel, such as `en`
+    pub language: Option<String>,
+    /// What the channel is about, such as `gaming`
+    pub category: Option<String>,
+    /// The channel's website, sent to joining users as `RPL_CHANNEL_URL`
+    pub website: Option<String>,
+}
+
+impl ChannelMetadata {
+    /// Sets or clears a single piece of metadata, returning true if anything changed.
+    pub fn set(&mut self, key: ChannelMetadataKey, value: Option<String>) -> bool {
+        let field = match key {
+            ChannelMetadataKey::Language => &mut self.language,
+            ChannelMetadataKey::Category => &mut self.category,
+            ChannelMetadataKey::Website => &mut self.website,
+        };
+
+        if *field == value {
+            return false;
+        }
+
+        *field = value;
+        true
+    }
+
+    /// A short summary of the metadata to prefix the topic with in `LIST`, or `None` if none is
+    /// set. The website is left out, as it's sent to users when they join the channel.
+    #[must_use]
+    pub fn list_summary(&self) -> Option<String> {
+        let parts = [
+            self.language.as_deref().map(|v| format!("lang: {v}")),
+            self.category.as_deref().map(|v| format!("category: {v}")),
+        ];
+
+        let parts = parts.into_iter().flatten().collect::<Vec<_>>();
+        (!parts.is_empty()).then(|| format!("[{}]", parts.join(", ")))
+    }
+}
+
+/// A piece of metadata that can be set with `CS SET <key> <channel> [value]`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ChannelMetadataKey {
+    Language,
+    Category,
+    Website,
+}
+
+impl ChannelMetadataKey {
+    #[must_use]
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Language => "LANGUAGE",
+            Self::Category => "CATEGORY",
+            Self::Website => "WEBSITE",
+        }
+    }
+
+    /// Checks whether `value` can be stored against this key, websites are required to be
+    /// `http` or `https` URLs so clients can safely link to them.
+    #[must_use]
+    pub fn is_valid(self, value: &str) -> bool {
+        if value.is_empty() || value.len() > MAX_VALUE_LENGTH || value.contains(char::is_control) {
+            return false;
+        }
+
+        match self {
+            Self::Language => value.chars().all(|c| c.is_ascii_alphabetic() || c == '-'),
+            Self::Category => true,
+            Self::Website => {
+                (value.starts_with("https://") || value.starts_with("http://"))
+                    && !value.contains(char::is_whitespace)
+            }
+        }
+    }
+}
+
+impl FromStr for ChannelMetadataKey {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [Self::Language, Self::Category, Self::Website]
+            .into_iter()
+            .find(|v| v.name().eq_ignore_ascii_case(s))
+            .ok_or(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ChannelMetadata, ChannelMetadataKey};
+
+    #[test]
+    fn validates_values() {
+        assert!(ChannelMetadataKey::Language.is_valid("en-GB"));
+        assert!(!ChannelMetadataKey::Language.is_valid("english!"));
+        assert!(ChannelMetadataKey::Category.is_valid("video games"));
+        assert!(!ChannelMetadataKey::Category.is_valid(""));
+        assert!(ChannelMetadataKey::Website.is_valid("https://example.com"));
+        assert!(!ChannelMetadataKey::Website.is_valid("javascript:alert(1)"));
+        assert!(!ChannelMetadataKey::Website.is_valid("https://example.com/a b"));
+    }
+
+    #[test]
+    fn summarises_for_list() {
+        let mut metadata = ChannelMetadata::default();
+        assert_eq!(metadata.list_summary(), None);
+
+        assert!(metadata.set(ChannelMetadataKey::Website, Some("https://a.b".to_string())));
+        assert_eq!(metadata.list_summary(), None);
+
+        assert!(metadata.set(ChannelMetadataKey::Language, Some("en".to_string())));
+        assert!(metadata.set(ChannelMetadataKey::Category, Some("gaming".to_string())));
+        assert!(!metadata.set(ChannelMetadataKey::Category, Some("gaming".to_string())));
+        assert_eq!(
+            metadata.list_summary().as_deref(),
+            Some("[lang: en, category: gaming]")
+        );
+    }
+}
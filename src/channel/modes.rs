@@ -0,0 +1,256 @@
+//! Modes that apply to the channel as a whole, rather than to individual members.
+
+use irc_proto::{ChannelMode, Mode};
+
+/// The channel-wide modes currently set on a channel.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChannelModeState {
+    /// `+i`, only users that have been invited may join
+    pub invite_only: bool,
+    /// `+k`, users must give the key to join
+    pub key: Option<String>,
+    /// `+l`, the maximum amount of users that may be in the channel at once
+    pub limit: Option<usize>,
+    /// `+m`, only voiced users and above may speak
+    pub moderated: bool,
+    /// `+p`, the channel's members are hidden from users not in it
+    pub private: bool,
+    /// `+s`, the channel is hidden from users not in it
+    pub secret: bool,
+    /// `+t`, only half-operators and above may change the topic
+    pub topic_lock: bool,
+}
+
+impl Default for ChannelModeState {
+    fn default() -> Self {
+        Self {
+            invite_only: false,
+            key: None,
+            limit: None,
+            moderated: false,
+            private: false,
+            secret: false,
+            topic_lock: true,
+        }
+    }
+}
+
+impl ChannelModeState {
+    /// The channel modes we support, grouped in the format of the `CHANMODES` `ISUPPORT` token.
+    pub const SUPPORTED_MODES: &'static str = "b,k,l,impst";
+
+    /// Applies a single mode change to the channel, returning the mode to broadcast to the
+    /// channel if anything changed. Returns `None` for modes that aren't channel-wide, or if
+    /// the mode is missing a valid argument.
+    pub fn apply(
+        &mut self,
+        add: bool,
+        mode: &ChannelMode,
+        arg: Option<String>,
+    ) -> Option<Mode<ChannelMode>> {
+        let changed = match mode {
+            ChannelMode::InviteOnly => replace(&mut self.invite_only, add),
+            ChannelMode::Moderated => replace(&mut self.moderated, add),
+            ChannelMode::Unknown('p') => replace(&mut self.private, add),
+            ChannelMode::Secret => replace(&mut self.secret, add),
+            ChannelMode::ProtectedTopic => replace(&mut self.topic_lock, add),
+            ChannelMode::Key if add => {
+                let key = arg.filter(|v| !v.is_empty() && !v.contains([' ', ',']))?;
+                self.key = Some(key.clone());
+                return Some(Mode::Plus(ChannelMode::Key, Some(key)));
+            }
+            ChannelMode::Key => {
+                // the key being removed is echoed back, so clients that expect an argument for
+                // `-k` can still parse the mode
+                let key = self.key.take()?;
+                return Some(Mode::Minus(ChannelMode::Key, Some(key)));
+            }
+            ChannelMode::Limit if add => {
+                let limit = arg.and_then(|v| v.parse().ok()).filter(|v| *v > 0)?;
+                if self.limit.replace(limit) == Some(limit) {
+                    return None;
+                }
+
+                return Some(Mode::Plus(ChannelMode::Limit, Some(limit.to_string())));
+            }
+            ChannelMode::Limit => self.limit.take().is_some(),
+            _ => return None,
+        };
+
+        changed.then(|| {
+            if add {
+                Mode::Plus(mode.clone(), None)
+            } else {
+                Mode::Minus(mode.clone(), None)
+            }
+        })
+    }
+
+    /// Builds the arguments of `RPL_CHANNELMODEIS`, the mode string followed by the arguments of
+    /// any modes that take one.
+    #[must_use]
+    pub fn to_arguments(&self) -> Vec<String> {
+        let mut modes = String::from("+");
+        let mut arguments = Vec::new();
+
+        if self.invite_only {
+            modes.push('i');
+        }
+
+        if let Some(key) = &self.key {
+            modes.push('k');
+            arguments.push(key.to_string());
+        }
+
+        if let Some(limit) = self.limit {
+            modes.push('l');
+            arguments.push(limit.to_string());
+        }
+
+        if self.moderated {
+            modes.push('m');
+        }
+
+        if self.private {
+            modes.push('p');
+        }
+
+        if self.secret {
+            modes.push('s');
+        }
+
+        if self.topic_lock {
+            modes.push('t');
+        }
+
+        std::iter::once(modes).chain(arguments).collect()
+    }
+
+    #[must_use]
+    pub const fn visibility(&self) -> ChannelVisibility {
+        if self.secret {
+            ChannelVisibility::Secret
+        } else if self.private {
+            ChannelVisibility::Private
+        } else {
+            ChannelVisibility::Public
+        }
+    }
+}
+
+/// How visible a channel is to users that aren't in it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChannelVisibility {
+    Public,
+    /// `+p`, the channel's members aren't shown to users not in it
+    Private,
+    /// `+s`, neither the channel nor its members are shown to users not in it
+    Secret,
+}
+
+impl ChannelVisibility {
+    /// Whether the channel is left out of `LIST`, `WHOIS` and `NAMES` for users not in it.
+    #[must_use]
+    pub const fn is_hidden(self) -> bool {
+        !matches!(self, Self::Public)
+    }
+
+    /// The channel type shown in `RPL_NAMREPLY`.
+    #[must_use]
+    pub const fn symbol(self) -> &'static str {
+        match self {
+            Self::Public => "=",
+            Self::Private => "*",
+            Self::Secret => "@",
+        }
+    }
+}
+
+/// Sets `flag` to `value`, returning whether the flag changed.
+fn replace(flag: &mut bool, value: bool) -> bool {
+    std::mem::replace(flag, value) != value
+}
+
+#[cfg(test)]
+mod test {
+    use irc_proto::{ChannelMode, Mode};
+
+    use super::{ChannelModeState, ChannelVisibility};
+
+    #[test]
+    fn defaults_to_topic_lock() {
+        assert_eq!(ChannelModeState::default().to_arguments(), vec!["+t"]);
+    }
+
+    #[test]
+    fn applies_flags() {
+        let mut modes = ChannelModeState::default();
+
+        assert_eq!(
+            modes.apply(true, &ChannelMode::Moderated, None),
+            Some(Mode::Plus(ChannelMode::Moderated, None))
+        );
+        assert_eq!(modes.apply(true, &ChannelMode::Moderated, None), None);
+        assert_eq!(
+            modes.apply(false, &ChannelMode::ProtectedTopic, None),
+            Some(Mode::Minus(ChannelMode::ProtectedTopic, None))
+        );
+        assert_eq!(modes.apply(true, &ChannelMode::Voice, None), None);
+
+        assert!(modes.moderated);
+        assert!(!modes.topic_lock);
+    }
+
+    #[test]
+    fn visibility() {
+        let mut modes = ChannelModeState::default();
+        assert_eq!(modes.visibility(), ChannelVisibility::Public);
+        assert!(!modes.visibility().is_hidden());
+
+        assert_eq!(
+            modes.apply(true, &ChannelMode::Unknown('p'), None),
+            Some(Mode::Plus(ChannelMode::Unknown('p'), None))
+        );
+        assert_eq!(modes.visibility(), ChannelVisibility::Private);
+        assert!(modes.visibility().is_hidden());
+
+        modes.apply(true, &ChannelMode::Secret, None);
+        assert_eq!(modes.visibility(), ChannelVisibility::Secret);
+        assert_eq!(modes.to_arguments(), vec!["+pst"]);
+    }
+
+    #[test]
+    fn applies_key_and_limit() {
+        let mut modes = ChannelModeState::default();
+
+        assert_eq!(modes.apply(true, &ChannelMode::Key, None), None);
+        assert_eq!(
+            modes.apply(true, &ChannelMode::Key, Some("bad key".to_string())),
+            None
+        );
+        assert_eq!(
+            modes.apply(true, &ChannelMode::Key, Some("hunter2".to_string())),
+            Some(Mode::Plus(ChannelMode::Key, Some("hunter2".to_string())))
+        );
+        assert_eq!(
+            modes.apply(true, &ChannelMode::Limit, Some("abc".to_string())),
+            None
+        );
+        assert_eq!(
+            modes.apply(true, &ChannelMode::Limit, Some("10".to_string())),
+            Some(Mode::Plus(ChannelMode::Limit, Some("10".to_string())))
+        );
+        assert_eq!(modes.to_arguments(), vec!["+klt", "hunter2", "10"]);
+
+        assert_eq!(
+            modes.apply(false, &ChannelMode::Key, Some("*".to_string())),
+            Some(Mode::Minus(ChannelMode::Key, Some("hunter2".to_string())))
+        );
+        assert_eq!(
+            modes.apply(false, &ChannelMode::Limit, None),
+            Some(Mode::Minus(ChannelMode::Limit, None))
+        );
+        assert_eq!(modes.apply(false, &ChannelMode::Limit, None), None);
+        assert_eq!(modes, ChannelModeState::default());
+    }
+}
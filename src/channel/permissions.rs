@@ -1,6 +1,7 @@
 use std::cmp::Ordering;
 
 use anyhow::anyhow;
+use chrono::{DateTime, Utc};
 use irc_proto::{ChannelMode, Mode};
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq, sqlx::Type)]
@@ -14,6 +15,17 @@ pub enum Permission {
     Founder = i16::MAX,
 }
 
+/// Who last changed a mask's permissions in a channel and when, shown alongside bans in
+/// `RPL_BANLIST`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PermissionDetails {
+    /// The hostmask of the user that made the change, `None` if it wasn't made from within the
+    /// channel (ie. through `CS ACCESS`)
+    pub set_by: Option<String>,
+    /// `None` for permissions set before changes were timestamped
+    pub set_at: Option<DateTime<Utc>>,
+}
+
 impl PartialOrd for Permission {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         Some(self.cmp(other))
@@ -50,6 +62,10 @@ impl Permission {
     /// A list of (mode)prefix used to inform clients of which modes set which prefixes.
     pub const SUPPORTED_PREFIXES: &'static str = "(qohv)~@%+";
 
+    /// Prefixes that can be used to address a message to members of a channel with at least the
+    /// given permission (ie. `@#channel`).
+    pub const STATUSMSG_PREFIXES: &'static str = "~@%+";
+
     /// Builds the mode message that's used to set (or unset) this permission.
     #[must_use]
     pub fn into_mode(self, add: bool, mask: String) -> Option<Mode<ChannelMode>> {
@@ -62,6 +78,32 @@ impl Permission {
         })
     }
 
+    /// Maps a prefix back to the permission it represents.
+    #[must_use]
+    pub const fn from_prefix(prefix: char) -> Option<Self> {
+        match prefix {
+            '+' => Some(Self::Voice),
+            '%' => Some(Self::HalfOperator),
+            '@' => Some(Self::Operator),
+            '~' => Some(Self::Founder),
+            _ => None,
+        }
+    }
+
+    /// Builds the prefixes shown before a member's nick from all the permissions they hold,
+    /// ordered highest first. Only the highest prefix is shown unless `multi_prefix` is set.
+    #[must_use]
+    pub fn into_prefixes(permissions: &[Self], multi_prefix: bool) -> String {
+        let take = if multi_prefix { permissions.len() } else { 1 };
+
+        permissions
+            .iter()
+            .take(take)
+            .copied()
+            .map(Self::into_prefix)
+            .collect()
+    }
+
     /// Grabs the prefix that is used to represent a permission.
     #[must_use]
     pub const fn into_prefix(self) -> &'static str {
@@ -95,6 +137,12 @@ impl Permission {
         self != Self::Ban
     }
 
+    /// Returns true, if the user is allowed to chat in the channel whilst it's moderated.
+    #[must_use]
+    pub const fn can_chatter_moderated(self) -> bool {
+        (self as i16) >= (Self::Voice as i16)
+    }
+
     /// Returns true, if the user is allowed to join the channel.
     #[must_use]
     pub fn can_join(self) -> bool {
@@ -107,12 +155,45 @@ impl Permission {
         (self as i16) >= (Self::HalfOperator as i16)
     }
 
+    /// Returns true, if the user is allowed to join the channel regardless of it being
+    /// invite-only, keyed or full.
+    #[must_use]
+    pub const fn can_bypass_join_restrictions(self) -> bool {
+        (self as i16) >= (Self::Voice as i16)
+    }
+
+    /// Returns true, if the user is allowed to change the channel's modes.
+    #[must_use]
+    pub const fn can_set_channel_modes(self) -> bool {
+        (self as i16) >= (Self::HalfOperator as i16)
+    }
+
     /// Returns true, if the user is allowed to kick people from the channel.
     #[must_use]
     pub const fn can_kick(self) -> bool {
         (self as i16) >= (Self::HalfOperator as i16)
     }
 
+    /// Returns true, if the user is allowed to redact messages sent to the channel by other
+    /// users.
+    #[must_use]
+    pub const fn can_redact_others(self) -> bool {
+        (self as i16) >= (Self::HalfOperator as i16)
+    }
+
+    /// Returns true, if the user is allowed to message other channel members via
+    /// `CPRIVMSG`/`CNOTICE`.
+    #[must_use]
+    pub const fn can_direct_message(self) -> bool {
+        (self as i16) >= (Self::Voice as i16)
+    }
+
+    /// Returns true, if the user is allowed to change the channel's metadata via `CS SET`.
+    #[must_use]
+    pub const fn can_set_metadata(self) -> bool {
+        (self as i16) >= (Self::Operator as i16)
+    }
+
     /// Returns true, if the user is allowed to set the given permission on another
     /// user.
     #[must_use]
@@ -122,3 +203,17 @@ impl Permission {
             && (self as i16) > (old as i16)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::Permission;
+
+    #[test]
+    fn into_prefixes() {
+        let permissions = [Permission::Operator, Permission::Voice];
+
+        assert_eq!(Permission::into_prefixes(&permissions, false), "@");
+        assert_eq!(Permission::into_prefixes(&permissions, true), "@+");
+        assert_eq!(Permission::into_prefixes(&[], true), "");
+    }
+}
@@ -1,12 +1,20 @@
-use std::iter::once;
+use std::{iter::once, sync::Arc};
 
+use actix::Addr;
+use chrono::{DateTime, Utc};
 use irc_proto::{Command, Message, Prefix, Response};
 use itertools::Itertools;
 
 use crate::{
-    channel::{permissions::Permission, Channel, CurrentChannelTopic},
-    connection::InitiatedConnection,
-    server::response::IntoProtocol,
+    channel::{
+        modes::ChannelVisibility,
+        permissions::{Permission, PermissionDetails},
+        Channel, ChannelId, CurrentChannelTopic, WhoMembers,
+    },
+    config::NamespaceRestriction,
+    connection::{Capability, InitiatedConnection},
+    proto::builder::MessageBuilder,
+    server::response::{IntoProtocol, ResourceUnavailable},
     SERVER_NAME,
 };
 
@@ -31,45 +39,33 @@ impl IntoProtocol for ChannelTopic {
     fn into_messages(self, for_user: &str) -> Vec<Message> {
         if let Some(topic) = self.topic {
             vec![
-                Message {
-                    tags: None,
-                    prefix: Some(Prefix::ServerName(SERVER_NAME.to_string())),
-                    command: Command::Response(
-                        Response::RPL_TOPIC,
-                        vec![
-                            for_user.to_string(),
-                            self.channel_name.to_string(),
-                            topic.topic,
-                        ],
-                    ),
-                },
-                Message {
-                    tags: None,
-                    prefix: Some(Prefix::ServerName(SERVER_NAME.to_string())),
-                    command: Command::Response(
-                        Response::RPL_TOPICWHOTIME,
-                        vec![
-                            for_user.to_string(),
-                            self.channel_name.to_string(),
-                            topic.set_by,
-                            topic.set_time.timestamp().to_string(),
-                        ],
-                    ),
-                },
-            ]
-        } else if !self.skip_on_none {
-            vec![Message {
-                tags: None,
-                prefix: Some(Prefix::ServerName(SERVER_NAME.to_string())),
-                command: Command::Response(
-                    Response::RPL_NOTOPIC,
+                MessageBuilder::server().response(
+                    Response::RPL_TOPIC,
+                    vec![
+                        for_user.to_string(),
+                        self.channel_name.to_string(),
+                        topic.topic,
+                    ],
+                ),
+                MessageBuilder::server().response(
+                    Response::RPL_TOPICWHOTIME,
                     vec![
                         for_user.to_string(),
-                        self.channel_name,
-                        "No topic is set".to_string(),
+                        self.channel_name.to_string(),
+                        topic.set_by,
+                        topic.set_time.timestamp().to_string(),
                     ],
                 ),
-            }]
+            ]
+        } else if !self.skip_on_none {
+            vec![MessageBuilder::server().response(
+                Response::RPL_NOTOPIC,
+                vec![
+                    for_user.to_string(),
+                    self.channel_name,
+                    "No topic is set".to_string(),
+                ],
+            )]
         } else {
             vec![]
         }
@@ -78,48 +74,34 @@ impl IntoProtocol for ChannelTopic {
 
 pub struct ChannelWhoList {
     pub channel_name: String,
-    pub nick_list: Vec<(Permission, InitiatedConnection)>,
-}
-
-impl ChannelWhoList {
-    #[must_use]
-    pub fn new(channel: &Channel) -> Self {
-        Self {
-            channel_name: channel.name.to_string(),
-            nick_list: channel
-                .clients
-                .values()
-                .map(|v| (channel.get_user_permissions(&v.to_host_mask()), v.clone()))
-                .collect(),
-        }
-    }
+    /// Each member along with the permissions they hold that come with a prefix, highest first
+    pub nick_list: WhoMembers,
+    /// Whether to show every prefix a member holds, rather than only the highest
+    pub multi_prefix: bool,
 }
 
 impl IntoProtocol for ChannelWhoList {
     fn into_messages(self, for_user: &str) -> Vec<Message> {
         let mut out = Vec::with_capacity(self.nick_list.len());
 
-        for (perm, conn) in self.nick_list {
-            let presence = if conn.away.is_some() { "G" } else { "H" };
+        for (permissions, conn) in self.nick_list {
+            let presence = if conn.is_away() { "G" } else { "H" };
+            let prefixes = Permission::into_prefixes(&permissions, self.multi_prefix);
 
-            out.push(Message {
-                tags: None,
-                prefix: Some(Prefix::ServerName(SERVER_NAME.to_string())),
-                command: Command::Response(
-                    Response::RPL_WHOREPLY,
-                    vec![
-                        for_user.to_string(),
-                        self.channel_name.to_string(),
-                        conn.user,
-                        conn.cloak.to_string(),
-                        SERVER_NAME.to_string(),
-                        conn.nick,
-                        format!("{presence}{}", perm.into_prefix()), // TODO: user modes & server operator
-                        "0".to_string(),
-                        conn.real_name,
-                    ],
-                ),
-            });
+            out.push(MessageBuilder::server().response(
+                Response::RPL_WHOREPLY,
+                vec![
+                    for_user.to_string(),
+                    self.channel_name.to_string(),
+                    conn.user.to_string(),
+                    conn.cloak(),
+                    SERVER_NAME.to_string(),
+                    conn.nick(),
+                    format!("{presence}{prefixes}"), // TODO: user modes & server operator
+                    "0".to_string(),
+                    conn.real_name.to_string(),
+                ],
+            ));
         }
 
         out
@@ -128,52 +110,191 @@ impl IntoProtocol for ChannelWhoList {
 
 pub enum ModeList {
     Ban(BanList),
+    Channel(ChannelModes),
 }
 
 impl IntoProtocol for ModeList {
     fn into_messages(self, for_user: &str) -> Vec<Message> {
         match self {
             Self::Ban(l) => l.into_messages(for_user),
+            Self::Channel(l) => l.into_messages(for_user),
         }
     }
 }
 
+/// Returned when a user queries the channel's modes without changing any.
+pub struct ChannelModes {
+    pub channel: String,
+    /// The mode string, followed by the arguments of any modes that take one
+    pub modes: Vec<String>,
+    pub created_at: ChannelCreationTime,
+}
+
+impl IntoProtocol for ChannelModes {
+    fn into_messages(self, for_user: &str) -> Vec<Message> {
+        once(
+            MessageBuilder::server().response(
+                Response::RPL_CHANNELMODEIS,
+                once(for_user.to_string())
+                    .chain(once(self.channel))
+                    .chain(self.modes)
+                    .collect(),
+            ),
+        )
+        .chain(self.created_at.into_messages(for_user))
+        .collect()
+    }
+}
+
+/// The time the channel was first created, which clients use for ordering channels.
+pub struct ChannelCreationTime {
+    pub channel: String,
+    pub created_at: DateTime<Utc>,
+}
+
+impl ChannelCreationTime {
+    #[must_use]
+    pub fn new(channel: &Channel) -> Self {
+        Self {
+            channel: channel.name.to_string(),
+            created_at: channel.created_at,
+        }
+    }
+}
+
+impl IntoProtocol for ChannelCreationTime {
+    fn into_messages(self, for_user: &str) -> Vec<Message> {
+        vec![MessageBuilder::server().response(
+            Response::RPL_CREATIONTIME,
+            vec![
+                for_user.to_string(),
+                self.channel,
+                self.created_at.timestamp().to_string(),
+            ],
+        )]
+    }
+}
+
+/// The channel's website, as set through `CS SET WEBSITE`.
+pub struct ChannelUrl {
+    pub channel: String,
+    pub url: String,
+}
+
+impl ChannelUrl {
+    /// Returns `None` if the channel doesn't have a website set.
+    #[must_use]
+    pub fn new(channel: &Channel) -> Option<Self> {
+        Some(Self {
+            channel: channel.name.to_string(),
+            url: channel.metadata.website.clone()?,
+        })
+    }
+}
+
+impl IntoProtocol for ChannelUrl {
+    fn into_messages(self, for_user: &str) -> Vec<Message> {
+        vec![MessageBuilder::server()
+            .numeric(328, vec![for_user.to_string(), self.channel, self.url])] // RPL_CHANNEL_URL
+    }
+}
+
 pub struct BanList {
     pub channel: String,
-    pub list: Vec<String>,
+    /// The bans on the requested page, along with who set them and when if it's known
+    pub list: Vec<(String, Option<PermissionDetails>)>,
+    /// The page being sent, starting from 1
+    pub page: usize,
+    pub pages: usize,
+}
+
+impl BanList {
+    /// The most bans sent in reply to a single request, the rest of a longer list can be
+    /// requested a page at a time with `MODE <channel> +b <page>`.
+    pub const PAGE_SIZE: usize = 100;
+
+    #[must_use]
+    pub fn new(channel: &Channel, page: usize) -> Self {
+        let bans = channel
+            .permissions
+            .iter()
+            .filter(|(_, v)| matches!(v, Permission::Ban))
+            .map(|(mask, _)| {
+                let details = channel.permission_details.get(&mask).cloned();
+                (mask, details)
+            })
+            .collect();
+
+        Self::paginate(channel.name.to_string(), bans, page)
+    }
+
+    /// Picks the requested page out of `bans`, which are sorted first so pages stay stable
+    /// between requests. Pages past either end of the list are clamped to the first or last.
+    fn paginate(
+        channel: String,
+        mut bans: Vec<(String, Option<PermissionDetails>)>,
+        page: usize,
+    ) -> Self {
+        bans.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        let pages = bans.len().div_ceil(Self::PAGE_SIZE).max(1);
+        let page = page.clamp(1, pages);
+        let list = bans
+            .into_iter()
+            .skip((page - 1) * Self::PAGE_SIZE)
+            .take(Self::PAGE_SIZE)
+            .collect();
+
+        Self {
+            channel,
+            list,
+            page,
+            pages,
+        }
+    }
 }
 
 impl IntoProtocol for BanList {
     fn into_messages(self, for_user: &str) -> Vec<Message> {
+        let end = if self.pages > 1 {
+            format!(
+                "End of channel ban list (page {} of {}, use MODE {} +b <page> for the rest)",
+                self.page, self.pages, self.channel
+            )
+        } else {
+            "End of channel ban list".to_string()
+        };
+
         self.list
             .into_iter()
-            .map(|mask| Message {
-                tags: None,
-                prefix: Some(Prefix::ServerName(SERVER_NAME.to_string())),
-                command: Command::Response(
-                    Response::RPL_BANLIST,
-                    vec![for_user.to_string(), self.channel.to_string(), mask],
-                ),
+            .map(|(mask, details)| {
+                let mut args = vec![for_user.to_string(), self.channel.to_string(), mask];
+
+                // bans set from outside the channel are attributed to the server
+                if let Some(PermissionDetails {
+                    set_by,
+                    set_at: Some(set_at),
+                }) = details
+                {
+                    args.push(set_by.unwrap_or_else(|| SERVER_NAME.to_string()));
+                    args.push(set_at.timestamp().to_string());
+                }
+
+                MessageBuilder::server().response(Response::RPL_BANLIST, args)
             })
-            .chain(once(Message {
-                tags: None,
-                prefix: Some(Prefix::ServerName(SERVER_NAME.to_string())),
-                command: Command::Response(
-                    Response::RPL_ENDOFBANLIST,
-                    vec![
-                        for_user.to_string(),
-                        self.channel.to_string(),
-                        "End of channel ban list".to_string(),
-                    ],
-                ),
-            }))
+            .chain(once(MessageBuilder::server().response(
+                Response::RPL_ENDOFBANLIST,
+                vec![for_user.to_string(), self.channel.to_string(), end],
+            )))
             .collect()
     }
 }
 
 pub struct ChannelNamesList {
     pub channel_name: String,
-    pub nick_list: Vec<(Permission, InitiatedConnection)>,
+    pub visibility: ChannelVisibility,
+    /// Each member along with the permissions they hold that come with a prefix, highest first
+    pub nick_list: Vec<(Vec<Permission>, Arc<InitiatedConnection>)>,
 }
 
 impl ChannelNamesList {
@@ -181,10 +302,16 @@ impl ChannelNamesList {
     pub fn new(channel: &Channel) -> Self {
         Self {
             channel_name: channel.name.to_string(),
+            visibility: channel.modes.visibility(),
             nick_list: channel
                 .clients
                 .values()
-                .map(|v| (channel.get_user_permissions(&v.to_host_mask()), v.clone()))
+                .map(|v| {
+                    (
+                        channel.get_user_prefix_permissions(&v.to_host_mask()),
+                        v.clone(),
+                    )
+                })
                 .collect(),
         }
     }
@@ -193,48 +320,46 @@ impl ChannelNamesList {
     pub const fn empty(channel_name: String) -> Self {
         Self {
             channel_name,
+            visibility: ChannelVisibility::Public,
             nick_list: vec![],
         }
     }
 
+    /// Builds the `NAMES` reply, including each member's full hostmask if the client negotiated
+    /// `userhost-in-names` and all of their prefixes if they negotiated `multi-prefix`.
     #[must_use]
-    pub fn into_messages(self, for_user: String, with_hostnames: bool) -> Vec<Message> {
+    pub fn into_messages(self, for_user: String, capabilities: Capability) -> Vec<Message> {
+        let with_hostnames = capabilities.contains(Capability::USERHOST_IN_NAMES);
+        let multi_prefix = capabilities.contains(Capability::MULTI_PREFIX);
+
         let nick_list = self
             .nick_list
             .into_iter()
-            .map(|(permission, connection)| {
-                let permission = permission.into_prefix();
+            .map(|(permissions, connection)| {
+                let prefixes = Permission::into_prefixes(&permissions, multi_prefix);
 
                 if with_hostnames {
-                    format!("{permission}{}", connection.to_nick())
+                    format!("{prefixes}{}", connection.to_nick())
                 } else {
-                    format!("{permission}{}", connection.nick)
+                    format!("{prefixes}{}", connection.nick())
                 }
             })
             .join(" ");
 
         vec![
-            Message {
-                tags: None,
-                prefix: Some(Prefix::ServerName(SERVER_NAME.to_string())),
-                command: Command::Response(
-                    Response::RPL_NAMREPLY,
-                    vec![
-                        for_user.to_string(),
-                        "=".to_string(),
-                        self.channel_name,
-                        nick_list,
-                    ],
-                ),
-            },
-            Message {
-                tags: None,
-                prefix: Some(Prefix::ServerName(SERVER_NAME.to_string())),
-                command: Command::Response(
-                    Response::RPL_ENDOFNAMES,
-                    vec![for_user, "End of /NAMES list".to_string()],
-                ),
-            },
+            MessageBuilder::server().response(
+                Response::RPL_NAMREPLY,
+                vec![
+                    for_user.to_string(),
+                    self.visibility.symbol().to_string(),
+                    self.channel_name,
+                    nick_list,
+                ],
+            ),
+            MessageBuilder::server().response(
+                Response::RPL_ENDOFNAMES,
+                vec![for_user, "End of /NAMES list".to_string()],
+            ),
         ]
     }
 }
@@ -245,6 +370,8 @@ pub enum ChannelInviteResult {
     NoSuchUser,
     UserAlreadyOnChannel,
     NotOnChannel,
+    /// The channel is invite-only and the user isn't able to lift it themselves.
+    MissingPrivileges,
 }
 
 impl ChannelInviteResult {
@@ -274,52 +401,286 @@ impl ChannelInviteResult {
                 Response::ERR_NOTONCHANNEL,
                 vec![for_user, channel, "You're not on that channel".to_string()],
             ),
+            Self::MissingPrivileges => Command::Response(
+                Response::ERR_CHANOPRIVSNEEDED,
+                vec![for_user, channel, "You're not channel operator".to_string()],
+            ),
         };
 
-        Some(Message {
-            tags: None,
-            prefix: Some(Prefix::ServerName(SERVER_NAME.to_string())),
-            command,
-        })
+        Some(MessageBuilder::server().command(command))
     }
 }
 
-#[derive(Copy, Clone, Debug)]
+/// Returned to a user once they've joined a channel. The channel has already queued the join
+/// burst to the user by then, ahead of anything else it sends them.
+pub struct ChannelJoinBurst {
+    pub handle: Addr<Channel>,
+    pub channel_id: ChannelId,
+}
+
+#[derive(Clone, Debug)]
 pub enum ChannelJoinRejectionReason {
     Banned,
+    /// The channel is invite-only (`+i`) and the user hasn't been invited.
+    InviteOnly(String),
+    /// The channel has a key set (`+k`) which the user didn't give.
+    BadKey(String),
+    /// The channel has reached its client limit (`+l`).
+    Full(String),
+    /// The channel is being held by the server and can't be joined until the hold expires.
+    Unavailable(ResourceUnavailable),
+    /// The channel doesn't exist yet, and the user isn't allowed to create channels within its
+    /// namespace.
+    Restricted(CreationRestricted),
 }
 
 impl IntoProtocol for ChannelJoinRejectionReason {
     fn into_messages(self, for_user: &str) -> Vec<Message> {
         match self {
-            Self::Banned => vec![Message {
-                tags: None,
-                prefix: Some(Prefix::ServerName(SERVER_NAME.to_string())),
-                command: Command::Response(
-                    Response::ERR_BANNEDFROMCHAN,
-                    vec![for_user.to_string(), "Cannot join channel (+b)".to_string()],
-                ),
-            }],
+            Self::Banned => vec![MessageBuilder::server().response(
+                Response::ERR_BANNEDFROMCHAN,
+                vec![for_user.to_string(), "Cannot join channel (+b)".to_string()],
+            )],
+            Self::InviteOnly(channel) => vec![MessageBuilder::server().response(
+                Response::ERR_INVITEONLYCHAN,
+                vec![
+                    for_user.to_string(),
+                    channel,
+                    "Cannot join channel (+i)".to_string(),
+                ],
+            )],
+            Self::BadKey(channel) => vec![MessageBuilder::server().response(
+                Response::ERR_BADCHANNELKEY,
+                vec![
+                    for_user.to_string(),
+                    channel,
+                    "Cannot join channel (+k)".to_string(),
+                ],
+            )],
+            Self::Full(channel) => vec![MessageBuilder::server().response(
+                Response::ERR_CHANNELISFULL,
+                vec![
+                    for_user.to_string(),
+                    channel,
+                    "Cannot join channel (+l)".to_string(),
+                ],
+            )],
+            Self::Unavailable(unavailable) => unavailable.into_messages(for_user),
+            Self::Restricted(restricted) => restricted.into_messages(for_user),
         }
     }
 }
 
+pub struct CreationRestricted {
+    pub channel: String,
+    /// The prefix of the namespace restricting the channel, `None` if the restriction applies
+    /// to every channel.
+    pub prefix: Option<String>,
+    pub restriction: NamespaceRestriction,
+}
+
+impl IntoProtocol for CreationRestricted {
+    fn into_messages(self, for_user: &str) -> Vec<Message> {
+        let channels = self.prefix.map_or_else(
+            || "channels".to_string(),
+            |prefix| format!("channels starting with {prefix}"),
+        );
+
+        let (numeric, reason) = match self.restriction {
+            // ERR_OPERONLY
+            NamespaceRestriction::OperOnly => {
+                (520, format!("Only operators can create {channels}"))
+            }
+            // ERR_NEEDREGGEDNICK
+            NamespaceRestriction::RegisteredOnly => (
+                477,
+                format!("You need to register your account to create {channels}"),
+            ),
+            NamespaceRestriction::AccountTooNew(age) => (
+                477,
+                format!(
+                    "Your account needs to be at least {} old to create {channels}",
+                    humantime::format_duration(age)
+                ),
+            ),
+        };
+
+        vec![MessageBuilder::server()
+            .numeric(numeric, vec![for_user.to_string(), self.channel, reason])]
+    }
+}
+
 pub struct MissingPrivileges(pub Prefix, pub String);
 
 impl MissingPrivileges {
     #[must_use]
     pub fn into_message(self) -> Message {
-        Message {
-            tags: None,
-            prefix: None,
-            command: Command::Response(
-                Response::ERR_CHANOPRIVSNEEDED,
-                vec![
-                    self.0.to_string(),
-                    self.1,
-                    "You're not channel operator".to_string(),
-                ],
-            ),
-        }
+        MessageBuilder::server().response(
+            Response::ERR_CHANOPRIVSNEEDED,
+            vec![
+                self.0.to_string(),
+                self.1,
+                "You're not channel operator".to_string(),
+            ],
+        )
+    }
+}
+
+pub struct UserNotInChannel(pub Prefix, pub String, pub String);
+
+impl UserNotInChannel {
+    #[must_use]
+    pub fn into_message(self) -> Message {
+        MessageBuilder::server().response(
+            Response::ERR_USERNOTINCHANNEL,
+            vec![
+                self.0.to_string(),
+                self.1,
+                self.2,
+                "They aren't on that channel".to_string(),
+            ],
+        )
+    }
+}
+
+pub struct NotOnChannel(pub String, pub String);
+
+impl NotOnChannel {
+    #[must_use]
+    pub fn into_message(self) -> Message {
+        MessageBuilder::server().response(
+            Response::ERR_NOTONCHANNEL,
+            vec![self.0, self.1, "You're not on that channel".to_string()],
+        )
+    }
+}
+
+/// Sent to a user whose `REDACT` was refused, as a standard reply.
+pub enum RedactFailed {
+    /// Only channel messages can be redacted.
+    InvalidTarget(String),
+    /// The message was sent by someone else, and the user isn't a channel operator.
+    Forbidden(String, String),
+    /// The message doesn't exist, or has already been redacted.
+    UnknownMessage(String, String),
+}
+
+impl RedactFailed {
+    #[must_use]
+    pub fn into_message(self) -> Message {
+        let arguments = match self {
+            Self::InvalidTarget(target) => vec![
+                "INVALID_TARGET".to_string(),
+                target,
+                "You cannot delete messages from that target".to_string(),
+            ],
+            Self::Forbidden(target, msgid) => vec![
+                "REDACT_FORBIDDEN".to_string(),
+                target,
+                msgid,
+                "You are not authorised to delete this message".to_string(),
+            ],
+            Self::UnknownMessage(target, msgid) => vec![
+                "UNKNOWN_MSGID".to_string(),
+                target,
+                msgid,
+                "This message does not exist or is too old".to_string(),
+            ],
+        };
+
+        MessageBuilder::server().command(Command::Raw(
+            "FAIL".to_string(),
+            once("REDACT".to_string()).chain(arguments).collect(),
+        ))
+    }
+}
+
+/// A `+draft/react` reaction was refused because the message, or the user, already has as many
+/// reactions as are kept for it.
+pub struct TooManyReactions(pub String, pub String);
+
+impl TooManyReactions {
+    #[must_use]
+    pub fn into_message(self) -> Message {
+        let Self(target, msgid) = self;
+
+        MessageBuilder::server().command(Command::Raw(
+            "FAIL".to_string(),
+            vec![
+                "TAGMSG".to_string(),
+                "REACTION_LIMIT".to_string(),
+                target,
+                msgid,
+                "There are too many reactions to this message".to_string(),
+            ],
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::{TimeZone, Utc};
+    use irc_proto::{Command, Response};
+
+    use super::BanList;
+    use crate::{channel::permissions::PermissionDetails, server::response::IntoProtocol};
+
+    fn bans(count: usize) -> Vec<(String, Option<PermissionDetails>)> {
+        (0..count).map(|i| (format!("*!*@{i:03}"), None)).collect()
+    }
+
+    #[test]
+    fn ban_list_pages() {
+        let list = BanList::paginate("#chan".to_string(), bans(250), 2);
+        assert_eq!((list.page, list.pages), (2, 3));
+        assert_eq!(list.list.len(), BanList::PAGE_SIZE);
+        assert_eq!(list.list[0].0, "*!*@100");
+
+        let list = BanList::paginate("#chan".to_string(), bans(250), 9);
+        assert_eq!(list.page, 3);
+        assert_eq!(list.list.len(), 50);
+
+        let list = BanList::paginate("#chan".to_string(), Vec::new(), 0);
+        assert_eq!((list.page, list.pages), (1, 1));
+        assert!(list.list.is_empty());
+    }
+
+    #[test]
+    fn ban_list_details() {
+        let set_at = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+        let list = BanList::paginate(
+            "#chan".to_string(),
+            vec![
+                (
+                    "*!*@a".to_string(),
+                    Some(PermissionDetails {
+                        set_by: Some("op!op@host".to_string()),
+                        set_at: Some(set_at),
+                    }),
+                ),
+                ("*!*@b".to_string(), None),
+            ],
+            1,
+        );
+
+        let messages = list.into_messages("nick");
+        let args: Vec<_> = messages
+            .iter()
+            .map(|message| match &message.command {
+                Command::Response(Response::RPL_BANLIST | Response::RPL_ENDOFBANLIST, args) => {
+                    args.clone()
+                }
+                other => panic!("unexpected {other:?}"),
+            })
+            .collect();
+
+        assert_eq!(
+            args,
+            vec![
+                vec!["nick", "#chan", "*!*@a", "op!op@host", "1704067200"],
+                vec!["nick", "#chan", "*!*@b"],
+                vec!["nick", "#chan", "End of channel ban list"],
+            ]
+        );
     }
 }
@@ -1,4 +1,17 @@
-use std::{collections::HashMap, time::Duration};
+mod commands;
+pub mod flood;
+pub mod tap;
+pub mod traffic;
+pub mod usage;
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 use actix::{
     dev::ToEnvelope, fut::wrap_future, io::WriteHandler, Actor, ActorContext, ActorFuture,
@@ -6,101 +19,324 @@ use actix::{
     ResponseFuture, Running, StreamHandler, WrapFuture,
 };
 use chrono::{DateTime, SecondsFormat, Utc};
-use clap::{crate_name, crate_version};
 use futures::{future, stream::FuturesUnordered, FutureExt, StreamExt};
-use irc_proto::{
-    error::ProtocolError, message::Tag, ChannelExt, Command, Message, Prefix, Response,
-};
+use irc_proto::{error::ProtocolError, message::Tag, ChannelExt, Command, Message, Mode, Response};
 use tokio::time::Instant;
 use tracing::{debug, error, info, instrument, warn, Instrument, Span};
 
 use crate::{
+    casemapping::nick_eq,
     channel::Channel,
-    connection::{
-        sasl::SaslAlreadyAuthenticated, Capability, InitiatedConnection, MessageSink,
-        NickNotOwnedByUser, UserMode,
+    client::{
+        flood::{FloodDecision, FloodLimiter},
+        tap::{Tap, TapLog},
+        traffic::{CountingSink, Traffic, WriteErrors},
     },
+    config::{CommandsConfig, OperSessionConfig},
+    connection::{Capability, InitiatedConnection, NickNotOwnedByUser, UserMode},
+    listener::governor::ConnectionPermit,
     messages::{
-        Broadcast, ChannelFetchTopic, ChannelFetchWhoList, ChannelInvite, ChannelJoin,
-        ChannelKickUser, ChannelList, ChannelMemberList, ChannelMessage, ChannelPart,
-        ChannelSetMode, ChannelUpdateTopic, ClientAway, ConnectedChannels, FetchClientDetails,
-        FetchUserPermission, FetchWhoList, FetchWhois, ForceDisconnect, Gline, KillUser, ListGline,
-        MessageKind, PrivateMessage, RemoveGline, ServerAdminInfo, ServerDisconnect,
-        ServerFetchMotd, ServerListUsers, UserKickedFromChannel, UserNickChange,
-        UserNickChangeInternal, Wallops,
+        Broadcast, ChangeHost, ChannelFetchWhoList, ChannelJoin, ChannelMemberList,
+        CheckNickAvailability, ClientAway, ClientShunned, FetchChannelMemberList,
+        FetchClientDetails, FetchClientTraffic, FetchWhoList, ForceDisconnect, KillUser,
+        MessageKind, PrivateMessage, ResolveTarget, ServerDisconnect, ServicesPrivateMessage,
+        Shutdown, TapClient, UpdateCommandsConfig, UserKickedFromChannel, UserNickChange,
+        UserNickChangeInternal,
     },
     persistence::{
         events::{
-            FetchUnseenChannelMessages, FetchUnseenPrivateMessages, FetchUserChannels,
-            FetchUserIdByNick, ReserveNick,
+            ChannelMessageReplay, FetchUnseenChannelMessages, FetchUnseenPrivateMessages,
+            FetchUserChannels, RecordWhowas, ReserveNick,
         },
         Persistence,
     },
-    proto::LocalCommand,
+    proto::{builder::MessageBuilder, NoSuchServer},
+    replay::{new_batch_id, Replayer},
     server::{
-        response::{IntoProtocol, WhoList},
+        response::{IntoProtocol, NoSuchNick, TapStatus, Target, WhoList},
         Server,
     },
     SERVER_NAME,
 };
 
+/// Total amount of failed writes to clients since the server started.
+pub static WRITE_ERRORS: AtomicU64 = AtomicU64::new(0);
+
 /// A client refers to a single connection to the server.
 ///
 /// This client has a handle to the server to inform it of leaves, and to request handles to
 /// channels.
 pub struct Client {
     /// The TcpStream writer half for sending responses to the client
-    pub writer: MessageSink,
+    pub writer: CountingSink,
+    /// Counters for the messages and bytes sent to and received from the client
+    pub traffic: Arc<Traffic>,
+    /// The log connections are tapped to, `None` if tapping isn't configured
+    pub tap_log: Option<Arc<TapLog>>,
+    /// Records the client's raw traffic whilst it's being tapped
+    pub tap: Option<Tap>,
     /// Details about the user's connection, including their nick
-    pub connection: InitiatedConnection,
+    pub connection: Arc<InitiatedConnection>,
     /// A handle to the root actor for arbitration between clients and channels
     pub server: Addr<Server>,
     /// A list of channels the user is currently connected to
     pub channels: HashMap<String, Addr<Channel>>,
-    /// The time of the last ping we received from the client
+    /// Channels the user has requested to join, but haven't been joined to yet
+    pub joining: HashSet<String>,
+    /// The time we last received a well-formed command from the client, pings are only sent
+    /// once the client has been quiet for a while
     pub last_active: Instant,
+    /// The time of the last command, other than a `PING` or `PONG`, received from the client
+    pub last_command: Instant,
+    /// Tracks how long the user has been an operator for, to expire their privileges
+    pub oper_session: OperSession,
+    /// The commands disabled on the server, kept up to date as the config is reloaded
+    pub commands: Arc<CommandsConfig>,
+    /// The time of the last `WHO` query the user sent, to enforce the configured interval
+    pub last_who: Option<Instant>,
+    /// Amount of writes to the client that have failed in a row, used to tear down connections
+    /// with broken sockets
+    pub write_errors: WriteErrors,
     /// Whether the client is shutting down due to the client calling QUIT, or whether the server
     /// terminated the connection
     pub graceful_shutdown: bool,
+    /// Whether the client is being disconnected because the server is shutting down, in which
+    /// case the user is kept in their channels so they're rejoined to them when reconnecting
+    pub server_shutdown: bool,
     /// The reason the client is leaving the server, whether this is set by the server or the user
     /// is decided by graceful_shutdown
     pub server_leave_reason: Option<String>,
+    /// Whether the user has been shunned, all commands except for `PING` and `PONG` are dropped
+    /// from shunned users
+    pub shunned: bool,
+    /// Throttles the commands the user sends, `None` if their connection class has no limits
+    pub flood: Option<FloodLimiter>,
+    /// Holds the connection's place in the per-IP and per-account connection limits until the
+    /// client is dropped
+    pub connection_permit: ConnectionPermit,
     /// Actor for persisting state to the datastore.
     pub persistence: Addr<Persistence>,
+    /// The datastore itself, for managing the user's account through `NS`.
+    pub database: sqlx::Pool<sqlx::Any>,
     /// The connection span to group all logs for the same connection
     pub span: Span,
 }
 
+/// Tracks an operator's session, so their privileges can be revoked once the session expires.
+pub struct OperSession {
+    pub config: OperSessionConfig,
+    /// The time the user became an operator
+    pub started: Option<Instant>,
+    /// Whether the user has been warned of their session expiring
+    pub warned: bool,
+}
+
+impl OperSession {
+    #[must_use]
+    pub const fn new(config: OperSessionConfig) -> Self {
+        Self {
+            config,
+            started: None,
+            warned: false,
+        }
+    }
+}
+
 impl Client {
+    /// Starts or stops recording the client's raw traffic to the tap log, returning `false` if
+    /// tapping isn't configured.
+    pub fn set_tap(&mut self, enabled: bool) -> bool {
+        let Some(log) = &self.tap_log else {
+            return false;
+        };
+
+        match (enabled, self.tap.take()) {
+            (true, None) => {
+                self.tap = Some(Tap::start(
+                    log.clone(),
+                    self.connection.host,
+                    &self.connection.to_host_mask(),
+                ));
+            }
+            (true, Some(tap)) => self.tap = Some(tap),
+            (false, Some(tap)) => tap.stop(),
+            (false, None) => {}
+        }
+
+        self.writer.set_tap(self.tap.clone());
+
+        true
+    }
+
+    /// Records the user's current nick for `WHOWAS`, called whenever the user stops using it.
+    fn record_whowas(&self) {
+        self.persistence.do_send(RecordWhowas {
+            nick: self.connection.nick(),
+            username: self.connection.user.clone(),
+            host: self.connection.cloak(),
+            realname: self.connection.real_name.clone(),
+        });
+    }
+
+    /// Strips any tags from the message that the client hasn't negotiated the capability for,
+    /// all messages written to the client should go through this. Returns `None` if the message
+    /// is a `TAGMSG` and the client hasn't negotiated `message-tags`, since there'd be nothing
+    /// left to send.
     #[must_use]
-    pub fn maybe_build_time_tag(&self, time: DateTime<Utc>) -> Option<Tag> {
-        if !self
-            .connection
-            .capabilities
-            .contains(Capability::SERVER_TIME)
-        {
+    pub fn filter_tags(&self, mut message: Message) -> Option<Message> {
+        let capabilities = self.connection.capabilities;
+        let server_time = capabilities.contains(Capability::SERVER_TIME);
+        let message_tags = capabilities.contains(Capability::MESSAGE_TAGS);
+
+        if !message_tags && matches!(&message.command, Command::Raw(c, _) if c == "TAGMSG") {
             return None;
         }
 
-        Some(Tag(
-            "time".to_string(),
-            Some(time.to_rfc3339_opts(SecondsFormat::Millis, true)),
-        ))
+        if let Some(tags) = &mut message.tags {
+            tags.retain(|Tag(key, _)| {
+                (key == "time" && server_time)
+                    || ((key == "msgid" || key.starts_with('+')) && message_tags)
+            });
+        }
+
+        if message.tags.as_ref().is_some_and(Vec::is_empty) {
+            message.tags = None;
+        }
+
+        Some(message)
     }
 
-    /// Send scheduled pings to the client
+    /// Pings the client if nothing has been received from it for the class' ping frequency,
+    /// disconnecting it once it's been silent for the ping timeout. Clients that are actively
+    /// sending us traffic are never pinged, the next check is scheduled for when the client
+    /// would next become idle.
     #[instrument(parent = &self.span, skip_all)]
     fn handle_ping_interval(&mut self, ctx: &mut Context<Self>) {
-        if Instant::now().duration_since(self.last_active) >= Duration::from_secs(120) {
-            self.server_leave_reason = Some("Ping timeout: 120 seconds".to_string());
+        let frequency = self.connection.class.ping_frequency;
+        let timeout = self.connection.class.ping_timeout();
+        let idle = Instant::now().saturating_duration_since(self.last_active);
+
+        if idle >= timeout {
+            self.server_leave_reason = Some(format!("Ping timeout: {} seconds", timeout.as_secs()));
             ctx.stop();
+            return;
         }
 
-        self.writer.write(Message {
-            tags: None,
-            prefix: None,
-            command: Command::PING(SERVER_NAME.to_string(), None),
-        });
+        if idle >= frequency {
+            self.writer.write(
+                MessageBuilder::bare().command(Command::PING(SERVER_NAME.to_string(), None)),
+            );
+            ctx.run_later(frequency, Self::handle_ping_interval);
+        } else {
+            ctx.run_later(frequency - idle, Self::handle_ping_interval);
+        }
+    }
+
+    /// Handles any commands that were queued up whilst the user was being throttled, as they
+    /// regain the tokens to do so.
+    fn handle_flood_interval(&mut self, ctx: &mut Context<Self>) {
+        while let Some(item) = self
+            .flood
+            .as_mut()
+            .and_then(|flood| flood.next_queued(Instant::now()))
+        {
+            self.handle_command(ctx, item);
+        }
+    }
+
+    /// Takes a token from the flood limiter for a command that was let past it, disconnecting the
+    /// client if they've gone too far into debt.
+    pub fn charge_flood(&mut self, ctx: &mut Context<Self>) {
+        if self
+            .flood
+            .as_mut()
+            .is_some_and(|flood| !flood.charge(Instant::now()))
+        {
+            warn!("Disconnecting client for flooding");
+            self.server_leave_reason = Some("Excess flood".to_string());
+            ctx.stop();
+        }
+    }
+
+    /// Dispatches a command received from the client, once it's made it past any throttling.
+    fn handle_command(&mut self, ctx: &mut Context<Self>, item: irc_proto::Message) {
+        let is_keepalive = matches!(item.command, Command::PING(..) | Command::PONG(..));
+
+        if !is_keepalive {
+            self.last_command = Instant::now();
+        }
+
+        // ensure that the message from the client is either a global message (ie. a ping) or
+        // has the correct nick (ie. it isn't spoofed or desynced)
+        if item
+            .source_nickname()
+            .map_or(false, |v| !nick_eq(v, &self.connection.nick()))
+        {
+            warn!("Rejecting message from client due to incorrect nick");
+            return;
+        }
+
+        commands::dispatch(self, ctx, item);
+    }
+
+    /// Revokes operator privileges from the user once their operator session expires, warning
+    /// them ahead of time.
+    fn handle_oper_session_interval(&mut self, _ctx: &mut Context<Self>) {
+        if !self.connection.mode().contains(UserMode::OPER) {
+            self.oper_session.started = None;
+            self.oper_session.warned = false;
+            return;
+        }
+
+        let now = Instant::now();
+        let started = *self.oper_session.started.get_or_insert(now);
+        let config = self.oper_session.config;
+
+        let Some(expires_at) = [
+            config.max_duration.map(|v| started + v),
+            config.idle_timeout.map(|v| self.last_command + v),
+        ]
+        .into_iter()
+        .flatten()
+        .min() else {
+            return;
+        };
+
+        if now >= expires_at {
+            info!("Operator session expired, revoking privileges");
+
+            self.connection
+                .set_mode(self.connection.mode().difference(UserMode::OPER));
+            self.oper_session.started = None;
+            self.oper_session.warned = false;
+
+            self.writer
+                .write(MessageBuilder::server().command(Command::UserMODE(
+                    self.connection.nick(),
+                    vec![Mode::Minus(irc_proto::UserMode::Oper, None)],
+                )));
+            self.write_server_notice(
+                "Your operator session has expired, use OPER to regain privileges".to_string(),
+            );
+        } else if expires_at - now <= config.warning {
+            if !self.oper_session.warned {
+                self.oper_session.warned = true;
+                self.write_server_notice(format!(
+                    "Your operator session will expire in {}",
+                    humantime::format_duration(Duration::from_secs((expires_at - now).as_secs()))
+                ));
+            }
+        } else {
+            // the user became active again, so warn them again next time their session is
+            // about to expire
+            self.oper_session.warned = false;
+        }
+    }
+
+    fn write_server_notice(&mut self, message: String) {
+        self.writer.write(
+            MessageBuilder::server().command(Command::NOTICE(self.connection.nick(), message)),
+        );
     }
 
     //// Join the user to all the channels they were previously in before disconnecting from
@@ -114,31 +350,12 @@ impl Client {
             .into_actor(self)
             .map(move |res, this, ctx| {
                 ctx.notify(JoinChannelRequest {
-                    channels: res.unwrap(),
+                    channels: res.unwrap().into_iter().map(|v| (v, None)).collect(),
                     span: this.span.clone(),
                 });
             })
     }
 
-    fn build_unseen_message(
-        &self,
-        sent: DateTime<Utc>,
-        sender: &str,
-        message: String,
-        kind: MessageKind,
-    ) -> Message {
-        Message {
-            tags: TagBuilder::default()
-                .insert(self.maybe_build_time_tag(sent))
-                .into(),
-            prefix: Some(Prefix::new_from_str(sender)),
-            command: match kind {
-                MessageKind::Normal => Command::PRIVMSG(self.connection.nick.clone(), message),
-                MessageKind::Notice => Command::NOTICE(self.connection.nick.clone(), message),
-            },
-        }
-    }
-
     fn send_unseen_private_messages(&self) -> impl ActorFuture<Self, Output = ()> + 'static {
         self.persistence
             .send(FetchUnseenPrivateMessages {
@@ -147,9 +364,17 @@ impl Client {
             })
             .into_actor(self)
             .map(move |res, this, ctx| {
-                for (sent, sender, message, kind) in res.unwrap() {
+                let replayer = Replayer::new(this.connection.capabilities);
+
+                for message in replayer.replay(
+                    &new_batch_id(),
+                    &this.connection.nick(),
+                    res.unwrap(),
+                    Vec::new(),
+                    0,
+                ) {
                     ctx.notify(Broadcast {
-                        message: this.build_unseen_message(sent, &sender, message, kind),
+                        message,
                         span: this.span.clone(),
                     });
                 }
@@ -171,7 +396,7 @@ impl Client {
             .send(message)
             .into_actor(self)
             .map(move |result, ref mut this, _ctx| {
-                for message in result.unwrap().into_messages(&this.connection.nick) {
+                for message in result.unwrap().into_messages(&this.connection.nick()) {
                     this.writer.write(message);
                 }
             });
@@ -190,7 +415,7 @@ impl Client {
                 .send(message)
                 .into_actor(self)
                 .map(move |result, ref mut this, _ctx| {
-                    for message in result.unwrap().into_messages(&this.connection.nick) {
+                    for message in result.unwrap().into_messages(&this.connection.nick()) {
                         this.writer.write(message);
                     }
                 });
@@ -208,7 +433,16 @@ impl Actor for Client {
     fn started(&mut self, ctx: &mut Self::Context) {
         info!(?self.connection, "Client has successfully joined to server");
 
-        ctx.run_interval(Duration::from_secs(30), Self::handle_ping_interval);
+        ctx.run_later(
+            self.connection.class.ping_frequency,
+            Self::handle_ping_interval,
+        );
+        ctx.run_interval(Duration::from_secs(60), Self::handle_oper_session_interval);
+
+        if let Some(flood) = &self.flood {
+            ctx.run_interval(flood.drain_interval(), Self::handle_flood_interval);
+        }
+
         ctx.spawn(self.rejoin_channels());
         ctx.spawn(self.send_unseen_private_messages());
     }
@@ -217,8 +451,15 @@ impl Actor for Client {
     /// by the server.
     #[instrument(parent = &self.span, skip_all)]
     fn stopped(&mut self, ctx: &mut Self::Context) {
+        if self.server_shutdown {
+            self.set_tap(false);
+            return;
+        }
+
         let message = self.server_leave_reason.take();
 
+        self.record_whowas();
+
         // inform the server that the user is leaving the server
         self.server.do_send(ServerDisconnect {
             client: ctx.address(),
@@ -240,18 +481,19 @@ impl Actor for Client {
         }
 
         // acknowledge the client's quit message by sending an ERROR
-        self.writer.write(Message {
-            tags: None,
-            prefix: None,
-            command: Command::ERROR(if self.graceful_shutdown {
-                String::new()
-            } else {
-                format!(
-                    "Closing Link: {}",
-                    message.as_deref().unwrap_or("Ungraceful shutdown")
-                )
-            }),
-        });
+        self.writer
+            .write(
+                MessageBuilder::bare().command(Command::ERROR(if self.graceful_shutdown {
+                    String::new()
+                } else {
+                    format!(
+                        "Closing Link: {}",
+                        message.as_deref().unwrap_or("Ungraceful shutdown")
+                    )
+                })),
+            );
+
+        self.set_tap(false);
     }
 }
 
@@ -261,45 +503,58 @@ impl Handler<Broadcast> for Client {
 
     #[instrument(parent = &msg.span, skip_all)]
     fn handle(&mut self, msg: Broadcast, _ctx: &mut Self::Context) -> Self::Result {
-        self.writer.write(msg.message);
+        if let Some(message) = self.filter_tags(msg.message) {
+            self.writer.write(message);
+        }
     }
 }
 
-/// Retrieves all the channels the user is connected to.
-impl Handler<ConnectedChannels> for Client {
-    type Result = ResponseFuture<<ConnectedChannels as actix::Message>::Result>;
+impl Handler<ForceDisconnect> for Client {
+    type Result = MessageResult<ForceDisconnect>;
+
+    fn handle(&mut self, msg: ForceDisconnect, ctx: &mut Self::Context) -> Self::Result {
+        self.server_leave_reason = Some(msg.comment);
+        ctx.stop();
+        MessageResult(Ok(()))
+    }
+}
+
+impl Handler<Shutdown> for Client {
+    type Result = ();
 
     #[instrument(parent = &msg.span, skip_all)]
-    fn handle(&mut self, msg: ConnectedChannels, _ctx: &mut Self::Context) -> Self::Result {
-        let span = Span::current();
-        let host_mask = self.connection.to_host_mask().into_owned();
-
-        let fut = self.channels.iter().map(move |(channel_name, handle)| {
-            let span = span.clone();
-            let channel_name = channel_name.to_string();
-            let handle = handle.clone();
-            let host_mask = host_mask.clone();
-
-            async move {
-                let permission = handle
-                    .send(FetchUserPermission { span, host_mask })
-                    .await
-                    .unwrap();
-
-                (permission, channel_name)
-            }
-        });
+    fn handle(&mut self, msg: Shutdown, ctx: &mut Self::Context) -> Self::Result {
+        self.writer.write(
+            MessageBuilder::bare().command(Command::ERROR("Server shutting down".to_string())),
+        );
 
-        Box::pin(future::join_all(fut))
+        self.server_shutdown = true;
+        ctx.stop();
     }
 }
 
-impl Handler<ForceDisconnect> for Client {
-    type Result = MessageResult<ForceDisconnect>;
+impl Handler<UpdateCommandsConfig> for Client {
+    type Result = ();
 
-    fn handle(&mut self, _msg: ForceDisconnect, ctx: &mut Self::Context) -> Self::Result {
-        ctx.stop();
-        MessageResult(Ok(()))
+    fn handle(&mut self, msg: UpdateCommandsConfig, _ctx: &mut Self::Context) -> Self::Result {
+        self.commands = msg.commands;
+    }
+}
+
+impl Handler<TapClient> for Client {
+    type Result = MessageResult<TapClient>;
+
+    #[instrument(parent = &msg.span, skip_all)]
+    fn handle(&mut self, msg: TapClient, _ctx: &mut Self::Context) -> Self::Result {
+        let status = if !self.set_tap(msg.enabled) {
+            TapStatus::NotConfigured
+        } else if msg.enabled {
+            TapStatus::Enabled(self.connection.nick())
+        } else {
+            TapStatus::Disabled(self.connection.nick())
+        };
+
+        MessageResult(Ok(status))
     }
 }
 
@@ -317,6 +572,8 @@ impl Handler<FetchWhoList> for Client {
             .map(|v| {
                 v.send(ChannelFetchWhoList {
                     span: msg.span.clone(),
+                    requester: None,
+                    multi_prefix: msg.multi_prefix,
                 })
             })
             .collect::<FuturesUnordered<_>>();
@@ -339,47 +596,125 @@ impl Handler<FetchClientDetails> for Client {
     }
 }
 
+/// Returns the client's traffic counters.
+impl Handler<FetchClientTraffic> for Client {
+    type Result = MessageResult<FetchClientTraffic>;
+
+    #[instrument(parent = &msg.span, skip_all)]
+    fn handle(&mut self, msg: FetchClientTraffic, _ctx: &mut Self::Context) -> Self::Result {
+        MessageResult(self.traffic.snapshot())
+    }
+}
+
 impl Handler<SetAway> for Client {
     type Result = ();
 
     #[instrument(parent = &msg.span, skip_all)]
     fn handle(&mut self, msg: SetAway, ctx: &mut Self::Context) -> Self::Result {
-        self.connection.away = msg.msg.filter(|msg| !msg.is_empty());
+        let message = msg.msg.filter(|msg| !msg.is_empty());
+        self.connection.set_away(message.clone());
 
+        // the away state itself is shared with the server and channels, so they only need to be
+        // told in order to notify other users
         let broadcast = ClientAway {
             span: msg.span,
             handle: ctx.address(),
-            message: self.connection.away.clone(),
+            message: message.clone(),
         };
 
-        self.server.do_send(broadcast.clone());
         for channel in self.channels.values() {
             channel.do_send(broadcast.clone());
         }
 
-        let resp = if self.connection.away.is_some() {
-            Command::Response(
-                Response::RPL_NOWAWAY,
-                vec![
-                    self.connection.nick.to_string(),
-                    "You have been marked as being away".to_string(),
-                ],
-            )
+        let (response, text) = if message.is_some() {
+            (Response::RPL_NOWAWAY, "You have been marked as being away")
         } else {
-            Command::Response(
+            (
                 Response::RPL_UNAWAY,
-                vec![
-                    self.connection.nick.to_string(),
-                    "You are no longer marked as being away".to_string(),
-                ],
+                "You are no longer marked as being away",
             )
         };
 
-        self.writer.write(Message {
-            tags: None,
-            prefix: None,
-            command: resp,
-        });
+        self.writer.write(
+            MessageBuilder::server()
+                .response(response, vec![self.connection.nick(), text.to_string()]),
+        );
+    }
+}
+
+/// A self-message from the Client's [`StreamHandler`] implementation when the user attempts
+/// to update their own modes.
+impl Handler<SetUserModes> for Client {
+    type Result = ();
+
+    #[instrument(parent = &msg.span, skip_all)]
+    fn handle(&mut self, msg: SetUserModes, _ctx: &mut Self::Context) -> Self::Result {
+        if !nick_eq(&msg.nick, &self.connection.nick()) {
+            self.writer.write(MessageBuilder::server().response(
+                Response::ERR_USERSDONTMATCH,
+                vec![
+                    self.connection.nick(),
+                    "Cant change mode for other users".to_string(),
+                ],
+            ));
+            return;
+        }
+
+        let mut new_mode = self.connection.mode();
+
+        for mode in msg.modes {
+            let (add, mode) = match mode {
+                Mode::Plus(mode, _) => (true, mode),
+                Mode::Minus(mode, _) => (false, mode),
+            };
+
+            // operators can give up their status, but it can only be granted through `OPER`
+            if matches!(
+                mode,
+                irc_proto::UserMode::Oper | irc_proto::UserMode::LocalOper
+            ) {
+                if !add {
+                    new_mode.remove(UserMode::OPER);
+                }
+
+                continue;
+            }
+
+            let Some(mode) = UserMode::from_user_settable(&mode) else {
+                self.writer.write(MessageBuilder::server().response(
+                    Response::ERR_UMODEUNKNOWNFLAG,
+                    vec![self.connection.nick(), "Unknown MODE flag".to_string()],
+                ));
+                continue;
+            };
+
+            new_mode.set(mode, add);
+        }
+
+        if self.connection.mode().contains(UserMode::OPER) && !new_mode.contains(UserMode::OPER) {
+            info!("User gave up their operator privileges");
+            self.oper_session.started = None;
+            self.oper_session.warned = false;
+        }
+
+        self.connection.set_mode(new_mode);
+
+        self.writer.write(MessageBuilder::server().response(
+            Response::RPL_UMODEIS,
+            vec![self.connection.nick(), new_mode.to_string()],
+        ));
+    }
+}
+
+/// Sent by the server when a shun is placed or lifted on the user.
+impl Handler<ClientShunned> for Client {
+    type Result = ();
+
+    fn handle(&mut self, msg: ClientShunned, _ctx: &mut Self::Context) -> Self::Result {
+        if self.shunned != msg.0 {
+            info!(shunned = msg.0, "Client shun status changed");
+            self.shunned = msg.0;
+        }
     }
 }
 
@@ -394,6 +729,27 @@ impl Handler<KillUser> for Client {
     }
 }
 
+/// Sent by services to change the host the user is shown with.
+impl Handler<ChangeHost> for Client {
+    type Result = ();
+
+    #[instrument(parent = &msg.span, skip_all)]
+    fn handle(&mut self, msg: ChangeHost, _ctx: &mut Self::Context) -> Self::Result {
+        info!(host = %msg.host, "User's host changed by services");
+        self.connection.set_cloak(msg.host.clone());
+
+        // RPL_VISIBLEHOST
+        self.writer.write(MessageBuilder::server().numeric(
+            396,
+            vec![
+                self.connection.nick(),
+                msg.host,
+                "is now your displayed host".to_string(),
+            ],
+        ));
+    }
+}
+
 /// A self-message from the Client's [`StreamHandler`] implementation when the user
 /// sends a join command out.
 ///
@@ -408,63 +764,87 @@ impl Handler<JoinChannelRequest> for Client {
 
         // loop over all the channels and send a channel join notification to the root
         // server actor to get a handle back
-        for channel_name in msg.channels {
+        for (channel_name, key) in msg.channels {
             if !channel_name.is_channel_name() || self.channels.contains_key(&channel_name) {
                 // todo: send message to client informing them of the invalid channel name
                 continue;
             }
 
+            // coalesce duplicate joins to the same channel whilst one is already in-flight
+            if !self.joining.insert(channel_name.clone()) {
+                continue;
+            }
+
             let channel_handle_fut = self.server.clone().send(ChannelJoin {
                 channel_name: channel_name.to_string(),
+                key,
                 client: ctx.address(),
                 connection: self.connection.clone(),
                 span: Span::current(),
             });
 
-            let channel_messages_fut = self.persistence.send(FetchUnseenChannelMessages {
-                channel_name: channel_name.to_string(),
-                user_id: self.connection.user_id,
-                span: Span::current(),
-            });
+            let persistence = self.persistence.clone();
+            let user_id = self.connection.user_id;
+            let span = Span::current();
 
-            futures.push(future::join(channel_handle_fut, channel_messages_fut).map(
-                move |(handle, messages)| {
-                    (channel_name, handle.unwrap().unwrap(), messages.unwrap())
-                },
-            ));
+            // once we've joined the channel, we know its id and can look up the history by it
+            futures.push(channel_handle_fut.then(move |handle| async move {
+                let burst = handle.unwrap().unwrap();
+
+                let replay = match &burst {
+                    Ok(burst) => persistence
+                        .send(FetchUnseenChannelMessages {
+                            channel_id: burst.channel_id,
+                            user_id,
+                            span,
+                        })
+                        .await
+                        .unwrap(),
+                    Err(_) => ChannelMessageReplay {
+                        messages: Vec::new(),
+                        reactions: Vec::new(),
+                        omitted: 0,
+                    },
+                };
+
+                (channel_name, burst, replay)
+            }));
         }
 
         // await on all the `ChannelJoin` events to the server, and once we get the channel
-        // handles back write them to the server
+        // handles back write each channel's history to the client
         let fut = wrap_future::<_, Self>(
             future::join_all(futures.into_iter()).instrument(Span::current()),
         )
         .map(|result, this, _ctx| {
-            for (channel_name, handle, messages) in result {
-                let handle = match handle {
+            for (channel_name, burst, replay) in result {
+                this.joining.remove(&channel_name);
+
+                let burst = match burst {
                     Ok(v) => v,
                     Err(error) => {
                         error!(?error, "User failed to join channel");
-                        for m in error.into_messages(&this.connection.nick) {
+                        for m in error.into_messages(&this.connection.nick()) {
                             this.writer.write(m);
                         }
                         continue;
                     }
                 };
 
-                this.channels.insert(channel_name.clone(), handle);
-
-                for (sent, source, message, kind) in messages {
-                    this.writer.write(Message {
-                        tags: TagBuilder::default()
-                            .insert(this.maybe_build_time_tag(sent))
-                            .into(),
-                        prefix: Some(Prefix::new_from_str(&source)),
-                        command: match kind {
-                            MessageKind::Normal => Command::PRIVMSG(channel_name.clone(), message),
-                            MessageKind::Notice => Command::NOTICE(channel_name.clone(), message),
-                        },
-                    });
+                // the channel sent its join burst straight to us before we got its handle back,
+                // so the user already knows about the channel before the history is replayed
+                this.channels.insert(channel_name.clone(), burst.handle);
+
+                let replayer = Replayer::new(this.connection.capabilities);
+
+                for message in replayer.replay(
+                    &new_batch_id(),
+                    &channel_name,
+                    replay.messages,
+                    replay.reactions,
+                    replay.omitted,
+                ) {
+                    this.writer.write(message);
                 }
             }
         });
@@ -479,39 +859,38 @@ impl Handler<ListChannelMemberRequest> for Client {
     type Result = ResponseActFuture<Self, ()>;
 
     #[instrument(parent = &msg.span, skip_all)]
-    fn handle(&mut self, msg: ListChannelMemberRequest, _ctx: &mut Self::Context) -> Self::Result {
-        let mut futures = Vec::with_capacity(msg.channels.len());
-
-        // loop over all channels the user is connected to and fetch their members
-        for (channel_name, handle) in &self.channels {
-            if !msg.channels.contains(channel_name) {
-                continue;
+    fn handle(&mut self, msg: ListChannelMemberRequest, ctx: &mut Self::Context) -> Self::Result {
+        // members of a channel can see everyone in it, other channels are fetched through the
+        // server so invisible members can be hidden
+        let futures = msg.channels.into_iter().map(|channel| {
+            if let Some(handle) = self.channels.get(&channel) {
+                future::Either::Left(handle.send(ChannelMemberList {
+                    span: Span::current(),
+                    requester: None,
+                }))
+            } else {
+                future::Either::Right(self.server.send(FetchChannelMemberList {
+                    span: Span::current(),
+                    client: ctx.address(),
+                    channel,
+                }))
             }
-
-            futures.push(handle.send(ChannelMemberList {
-                span: Span::current(),
-            }));
-        }
+        });
 
         // await on all the `ChannelMemberList` events to the channels, and once we get the lists back
         // write them to the client
-        let fut = wrap_future::<_, Self>(
-            future::join_all(futures.into_iter()).instrument(Span::current()),
-        )
-        .map(|result, this, _ctx| {
-            for list in result {
-                let list = list.unwrap();
-
-                for message in list.into_messages(
-                    this.connection.nick.clone(),
-                    this.connection
-                        .capabilities
-                        .contains(Capability::USERHOST_IN_NAMES),
-                ) {
-                    this.writer.write(message);
+        let fut = wrap_future::<_, Self>(future::join_all(futures).instrument(Span::current()))
+            .map(|result, this, _ctx| {
+                for list in result {
+                    let list = list.unwrap();
+
+                    for message in
+                        list.into_messages(this.connection.nick(), this.connection.capabilities)
+                    {
+                        this.writer.write(message);
+                    }
                 }
-            }
-        });
+            });
 
         Box::pin(fut)
     }
@@ -521,44 +900,72 @@ impl Handler<UserNickChangeInternal> for Client {
     type Result = ResponseActFuture<Self, ()>;
 
     #[instrument(parent = &msg.span, skip_all)]
-    fn handle(&mut self, msg: UserNickChangeInternal, _ctx: &mut Self::Context) -> Self::Result {
-        self.persistence
-            .send(ReserveNick {
-                user_id: self.connection.user_id,
-                nick: msg.new_nick.clone(),
-            })
-            .into_actor(self)
-            .map(|res, this, ctx| {
-                if !res.unwrap() {
-                    ctx.notify(Broadcast {
-                        message: NickNotOwnedByUser(msg.new_nick).into_message(),
-                        span: Span::current(),
-                    });
+    fn handle(&mut self, msg: UserNickChangeInternal, ctx: &mut Self::Context) -> Self::Result {
+        let server = self.server.clone();
+        let persistence = self.persistence.clone();
+        let client = ctx.address();
+        let user_id = self.connection.user_id;
+        let new_nick = msg.new_nick.clone();
+
+        // check the nick isn't in use by any other online session before reserving it for the
+        // user's account
+        async move {
+            let availability = server
+                .send(CheckNickAvailability {
+                    nick: new_nick.clone(),
+                    client: Some(client),
+                })
+                .await
+                .unwrap();
+
+            if !availability.is_available() {
+                return Err(availability);
+            }
+
+            Ok(persistence
+                .send(ReserveNick {
+                    user_id,
+                    nick: new_nick,
+                })
+                .await
+                .unwrap())
+        }
+        .into_actor(self)
+        .map(|res, this, ctx| {
+            let reserved = match res {
+                Ok(reserved) => reserved,
+                Err(in_use) => {
+                    for message in in_use.into_messages(&this.connection.nick()) {
+                        this.writer.write(message);
+                    }
                     return;
                 }
+            };
 
-                // alert the server to the nick change (we'll receive this event back so the user
-                // gets the notification too)
-                this.server.do_send(UserNickChange {
-                    client: ctx.address(),
-                    connection: this.connection.clone(),
-                    new_nick: msg.new_nick.clone(),
+            if !reserved {
+                ctx.notify(Broadcast {
+                    message: NickNotOwnedByUser(msg.new_nick).into_message(),
                     span: Span::current(),
                 });
+                return;
+            }
 
-                for channel in this.channels.values() {
-                    channel.do_send(UserNickChange {
-                        client: ctx.address(),
-                        connection: this.connection.clone(),
-                        new_nick: msg.new_nick.clone(),
-                        span: Span::current(),
-                    });
-                }
+            // the connection is shared with the server and our channels, so updating it here
+            // updates it everywhere
+            let old_prefix = this.connection.to_nick();
+            this.record_whowas();
+            this.connection.set_nick(msg.new_nick.clone());
 
-                // updates our nick locally
-                this.connection.nick = msg.new_nick;
-            })
-            .boxed_local()
+            // alert the server to the nick change so other users can be notified (we'll receive
+            // this event back so the user gets the notification too)
+            this.server.do_send(UserNickChange {
+                client: ctx.address(),
+                old_prefix,
+                new_nick: msg.new_nick,
+                span: Span::current(),
+            });
+        })
+        .boxed_local()
     }
 }
 
@@ -569,11 +976,13 @@ impl Handler<UserNickChange> for Client {
 
     #[instrument(parent = &msg.span, skip_all)]
     fn handle(&mut self, msg: UserNickChange, _ctx: &mut Self::Context) -> Self::Result {
-        self.writer.write(Message {
-            tags: None,
-            prefix: Some(msg.connection.to_nick()),
-            command: Command::NICK(msg.new_nick),
-        });
+        let message = MessageBuilder::user(msg.old_prefix)
+            .tags(server_time_tags())
+            .command(Command::NICK(msg.new_nick));
+
+        if let Some(message) = self.filter_tags(message) {
+            self.writer.write(message);
+        }
     }
 }
 
@@ -593,22 +1002,51 @@ impl Handler<SendPrivateMessage> for Client {
 
     #[instrument(parent = &msg.span, skip_all)]
     fn handle(&mut self, msg: SendPrivateMessage, _ctx: &mut Self::Context) -> Self::Result {
-        self.persistence
-            .send(FetchUserIdByNick {
-                nick: msg.destination,
+        self.server
+            .send(ResolveTarget {
+                target: msg.destination.clone(),
+                server: msg.server.clone(),
+                span: msg.span.clone(),
             })
             .into_actor(self)
             .map(move |res, this, ctx| {
-                let Some(destination) = res.unwrap() else {
-                    // TODO
-                    eprintln!("User attempted to send a message to non-existent user");
-                    return;
+                let destination = match res.unwrap() {
+                    Target::OnlineUser { connection, .. } => connection.user_id,
+                    Target::OfflineAccount { user_id, .. } => user_id,
+                    Target::Services(link) => {
+                        link.do_send(ServicesPrivateMessage {
+                            from: ctx.address(),
+                            target: msg.destination,
+                            message: msg.message,
+                            kind: msg.kind,
+                        });
+                        return;
+                    }
+                    Target::Channel(_) | Target::Unknown => {
+                        let nick = this.connection.nick();
+                        let messages = if let Some(server) = msg.server {
+                            NoSuchServer(server).into_messages(&nick)
+                        } else {
+                            NoSuchNick {
+                                nick: msg.destination,
+                            }
+                            .into_messages(&nick)
+                        };
+
+                        for message in messages {
+                            this.writer.write(message);
+                        }
+
+                        return;
+                    }
                 };
 
                 this.server.do_send(PrivateMessage {
                     destination,
+                    destination_nick: msg.destination,
                     message: msg.message,
                     kind: msg.kind,
+                    tags: msg.tags,
                     from: ctx.address(),
                     span: msg.span,
                 });
@@ -626,6 +1064,16 @@ impl StreamHandler<Result<irc_proto::Message, ProtocolError>> for Client {
         let item = match item {
             Ok(item) => {
                 debug!(?item, "Received message from client");
+
+                // any command received from the client shows it's still there, so it doesn't
+                // need pinging
+                self.last_active = Instant::now();
+                self.traffic.record_received(&item);
+
+                if let Some(tap) = &self.tap {
+                    tap.inbound(&item);
+                }
+
                 item
             }
             Err(error) => {
@@ -634,366 +1082,61 @@ impl StreamHandler<Result<irc_proto::Message, ProtocolError>> for Client {
             }
         };
 
-        // ensure that the message from the client is either a global message (ie. a ping) or
-        // has the correct nick (ie. it isn't spoofed or desynced)
-        if item
-            .source_nickname()
-            .map_or(false, |v| v != self.connection.nick)
-        {
-            warn!("Rejecting message from client due to incorrect nick");
+        let is_keepalive = matches!(item.command, Command::PING(..) | Command::PONG(..));
+
+        // silently drop everything but keepalives from shunned users
+        if self.shunned && !is_keepalive {
             return;
         }
 
-        // https://modern.ircdocs.horse/
-        #[allow(clippy::match_same_arms)]
-        match item.command {
-            Command::NICK(new_nick) => {
-                ctx.notify(UserNickChangeInternal {
-                    old_nick: self.connection.nick.to_string(),
-                    new_nick,
-                    span: Span::current(),
-                });
-            }
-            Command::UserMODE(_, _) => {
-                // TODO
-            }
-            Command::QUIT(message) => {
-                // set the user's leave reason and request a shutdown of the actor to close the
-                // connection
-                self.graceful_shutdown = true;
-                self.server_leave_reason = message;
-                ctx.stop();
-            }
-            Command::JOIN(channel_names, _passwords, _real_name) => {
-                // split the list of channel names...
-                let channels = parse_channel_name_list(&channel_names);
-
-                // ...and send a self-notification to schedule those joins
-                ctx.notify(JoinChannelRequest {
-                    channels,
-                    span: Span::current(),
-                });
-            }
-            Command::PART(channel, message) => {
-                // remove the handle from the users locally connected channels
-                let Some(channel) = self.channels.remove(&channel) else {
-                    return;
-                };
-
-                // alert the channel to our leave
-                channel.do_send(ChannelPart {
-                    client: ctx.address(),
-                    message,
-                    span: Span::current(),
-                });
-            }
-            Command::ChannelMODE(channel, modes) => {
-                let Some(channel) = self.channels.get(&channel) else {
-                    return;
-                };
-
-                self.channel_send_map_write(
-                    ctx,
-                    channel,
-                    ChannelSetMode {
-                        span: Span::current(),
-                        client: ctx.address(),
-                        modes,
-                    },
-                );
-            }
-            Command::TOPIC(channel, topic) => {
-                let Some(channel) = self.channels.get(&channel) else {
-                    return;
-                };
-
-                #[allow(clippy::option_if_let_else)]
-                if let Some(topic) = topic {
-                    channel.do_send(ChannelUpdateTopic {
-                        topic,
-                        client: ctx.address(),
-                        span: Span::current(),
-                    });
-                } else {
-                    let span = Span::current();
-                    self.channel_send_map_write(
-                        ctx,
-                        channel,
-                        ChannelFetchTopic {
-                            span,
-                            skip_on_none: false,
-                        },
-                    );
-                }
-            }
-            Command::NAMES(channel_names, _) => {
-                // split the list of channel names...
-                let channels = parse_channel_name_list(channel_names.as_deref().unwrap_or(""));
-
-                if channels.is_empty() {
-                    warn!("Client didn't request names for a particular channel");
-                    return;
+        // keepalives and channel direct messages are handled before the class limiter is
+        // consulted, the latter are charged once the channel has checked the sender's privileges
+        let item = match self.flood.as_mut() {
+            Some(flood) if !FloodLimiter::is_exempt(&item) => {
+                match flood.receive(item, Instant::now()) {
+                    FloodDecision::Allow(item) => item,
+                    FloodDecision::Queued => return,
+                    FloodDecision::Excess => {
+                        warn!("Disconnecting client for flooding");
+                        self.server_leave_reason = Some("Excess flood".to_string());
+                        ctx.stop();
+                        return;
+                    }
                 }
-
-                // ...and send a self-notification to request each channel for their list
-                ctx.notify(ListChannelMemberRequest {
-                    channels,
-                    span: Span::current(),
-                });
             }
-            Command::LIST(_, _) => {
-                let span = Span::current();
-                self.server_send_map_write(ctx, ChannelList { span });
-            }
-            Command::INVITE(nick, channel) => {
-                let Some(channel) = self.channels.get(&channel) else {
-                    error!(%channel, "User not connected to channel");
-                    return;
-                };
+            _ => item,
+        };
 
-                channel.do_send(ChannelInvite {
-                    nick,
-                    client: ctx.address(),
-                    span: Span::current(),
-                });
-            }
-            Command::KICK(channel, users, reason) => {
-                let Some(channel) = self.channels.get(&channel) else {
-                    error!(%channel, "User not connected to channel");
-                    return;
-                };
+        self.handle_command(ctx, item);
+    }
+}
 
-                for user in parse_channel_name_list(&users) {
-                    channel.do_send(ChannelKickUser {
-                        span: Span::current(),
-                        client: ctx.address(),
-                        user,
-                        reason: reason.clone(),
-                    });
-                }
-            }
-            command @ (Command::NOTICE(_, _) | Command::PRIVMSG(_, _)) => {
-                let (target, message, kind) = match command {
-                    Command::PRIVMSG(target, message) => (target, message, MessageKind::Normal),
-                    Command::NOTICE(target, message) => (target, message, MessageKind::Notice),
-                    _ => unreachable!(),
-                };
+/// Builds a `time` tag for the given time.
+#[must_use]
+pub fn server_time_tag(time: DateTime<Utc>) -> Tag {
+    Tag(
+        "time".to_string(),
+        Some(time.to_rfc3339_opts(SecondsFormat::Millis, true)),
+    )
+}
 
-                if !target.is_channel_name() {
-                    // private message to another user
-                    ctx.notify(SendPrivateMessage {
-                        destination: target,
-                        message,
-                        kind,
-                        span: Span::current(),
-                    });
-                } else if let Some(channel) = self.channels.get(&target) {
-                    channel.do_send(ChannelMessage {
-                        client: ctx.address(),
-                        message,
-                        kind,
-                        span: Span::current(),
-                    });
-                } else {
-                    // user not connected to channel
-                    error!("User not connected to channel");
-                }
-            }
-            Command::MOTD(_) => {
-                let span = Span::current();
-                self.server_send_map_write(ctx, ServerFetchMotd { span });
-            }
-            Command::LUSERS(_, _) => {
-                let span = Span::current();
-                self.server_send_map_write(ctx, ServerListUsers { span });
-            }
-            Command::VERSION(_) => {
-                self.writer.write(Message {
-                    tags: None,
-                    prefix: Some(Prefix::ServerName(SERVER_NAME.to_string())),
-                    command: Command::Response(
-                        Response::RPL_VERSION,
-                        vec![
-                            self.connection.nick.to_string(),
-                            format!("{}-{}", crate_name!(), crate_version!()),
-                            SERVER_NAME.to_string(),
-                        ],
-                    ),
-                });
-            }
-            Command::TIME(_) => {
-                let time = chrono::Utc::now();
-
-                self.writer.write(Message {
-                    tags: None,
-                    prefix: None,
-                    command: Command::Response(
-                        Response::RPL_TIME,
-                        vec![
-                            self.connection.nick.to_string(),
-                            SERVER_NAME.to_string(),
-                            time.timestamp().to_string(),
-                            time.format("%a %b %d %Y %T").to_string(),
-                        ],
-                    ),
-                });
-            }
-            Command::ADMIN(_) => {
-                let span = Span::current();
-                self.server_send_map_write(ctx, ServerAdminInfo { span });
-            }
-            Command::INFO(_) => {
-                static INFO_STR: &str = include_str!("../text/info.txt");
-                for line in INFO_STR.trim().split('\n') {
-                    self.writer.write(Message {
-                        tags: None,
-                        prefix: None,
-                        command: Command::Response(
-                            Response::RPL_INFO,
-                            vec![self.connection.nick.to_string(), line.to_string()],
-                        ),
-                    });
-                }
+/// Generates a new id for a message, unique enough for clients to refer back to the message by.
+#[must_use]
+pub fn new_msgid() -> String {
+    format!("{:032x}", rand::random::<u128>())
+}
 
-                self.writer.write(Message {
-                    tags: None,
-                    prefix: None,
-                    command: Command::Response(
-                        Response::RPL_ENDOFINFO,
-                        vec![
-                            self.connection.nick.to_string(),
-                            "End of INFO list".to_string(),
-                        ],
-                    ),
-                });
-            }
-            Command::WHO(Some(query), _) => {
-                let span = Span::current();
-                self.server_send_map_write(ctx, FetchWhoList { span, query });
-            }
-            Command::WHOIS(Some(query), _) => {
-                let span = Span::current();
-                self.server_send_map_write(ctx, FetchWhois { span, query });
-            }
-            Command::WHOWAS(_, _, _) => {}
-            Command::KILL(nick, comment) => {
-                self.server.do_send(KillUser {
-                    span: Span::current(),
-                    killer: self.connection.nick.to_string(),
-                    comment,
-                    killed: nick,
-                });
-            }
-            Command::PING(v, _) => {
-                self.writer.write(Message {
-                    tags: None,
-                    prefix: None,
-                    command: Command::PONG(v, None),
-                });
-            }
-            Command::PONG(_, _) => {
-                self.last_active = Instant::now();
-            }
-            Command::AWAY(msg) => {
-                ctx.notify(SetAway {
-                    span: Span::current(),
-                    msg,
-                });
-            }
-            Command::REHASH => {}
-            Command::DIE => {}
-            Command::RESTART => {}
-            Command::WALLOPS(message) if self.connection.mode.contains(UserMode::OPER) => {
-                self.server.do_send(Wallops {
-                    span: Span::current(),
-                    message,
-                });
-            }
-            Command::USERHOST(_) => {}
-            Command::SAJOIN(_, _) => {}
-            Command::SAMODE(_, _, _) => {}
-            Command::SANICK(old_nick, new_nick) => {
-                // TODO: permission checks
-                self.server.do_send(UserNickChangeInternal {
-                    old_nick,
-                    new_nick,
-                    span: Span::current(),
-                });
-            }
-            Command::SAPART(_, _) => {}
-            Command::SAQUIT(user, comment) if self.connection.mode.contains(UserMode::OPER) => {
-                let span = Span::current();
-                self.server_send_map_write(
-                    ctx,
-                    ForceDisconnect {
-                        span,
-                        user,
-                        comment,
-                    },
-                );
-            }
-            Command::AUTHENTICATE(_) => {
-                self.writer.write(
-                    SaslAlreadyAuthenticated(self.connection.nick.to_string()).into_message(),
-                );
-            }
-            Command::ACCOUNT(_) => {}
-            Command::METADATA(_, _, _) => {}
-            Command::MONITOR(_, _) => {}
-            Command::BATCH(_, _, _) => {}
-            Command::CHGHOST(_, _) => {}
-            Command::Response(_, _) => {}
-            Command::Raw(command, args) => self.handle_custom_command(ctx, command, args),
-            _ => {
-                for m in crate::proto::Error::UnknownCommand.into_messages(&self.connection.nick) {
-                    self.writer.write(m);
-                }
-            }
-        }
-    }
+/// Builds the `msgid` tag clients can refer back to a message by, such as to redact it.
+#[must_use]
+pub fn msgid_tag(msgid: String) -> Tag {
+    Tag("msgid".to_string(), Some(msgid))
 }
 
-impl Client {
-    fn handle_custom_command(
-        &mut self,
-        ctx: &mut Context<Self>,
-        command: String,
-        args: Vec<String>,
-    ) {
-        match LocalCommand::try_from((command, args)) {
-            Ok(LocalCommand::Gline(mask, duration, reason))
-                if self.connection.mode.contains(UserMode::OPER) =>
-            {
-                self.server_send_map_write(
-                    ctx,
-                    Gline {
-                        requester: self.connection.clone(),
-                        mask,
-                        duration,
-                        reason,
-                    },
-                );
-            }
-            Ok(LocalCommand::RemoveGline(mask))
-                if self.connection.mode.contains(UserMode::OPER) =>
-            {
-                self.server_send_map_write(ctx, RemoveGline { mask });
-            }
-            Ok(LocalCommand::ListGline) if self.connection.mode.contains(UserMode::OPER) => {
-                self.server_send_map_write(ctx, ListGline);
-            }
-            Err(e) => {
-                for m in e.into_messages(&self.connection.nick) {
-                    self.writer.write(m);
-                }
-            }
-            _ => {
-                for m in crate::proto::Error::UnknownCommand.into_messages(&self.connection.nick) {
-                    self.writer.write(m);
-                }
-            }
-        }
-    }
+/// Builds the tags attached to live events as they're broadcast, these are filtered down to the
+/// tags each recipient supports by [`Client::filter_tags`] before being written.
+#[must_use]
+pub fn server_time_tags() -> Option<Vec<Tag>> {
+    Some(vec![server_time_tag(Utc::now())])
 }
 
 #[derive(Default)]
@@ -1010,6 +1153,12 @@ impl TagBuilder {
 
         self
     }
+
+    #[must_use]
+    pub fn extend(mut self, tags: impl IntoIterator<Item = Tag>) -> Self {
+        self.inner.extend(tags);
+        self
+    }
 }
 
 impl From<TagBuilder> for Option<Vec<Tag>> {
@@ -1018,6 +1167,29 @@ impl From<TagBuilder> for Option<Vec<Tag>> {
     }
 }
 
+/// Pairs each of the channels given to `JOIN` with the key given at the same position in the
+/// comma-separated list of keys, if there is one.
+#[must_use]
+pub fn parse_channel_join_list(
+    channels: &str,
+    keys: Option<&str>,
+) -> Vec<(String, Option<String>)> {
+    let mut keys = keys.into_iter().flat_map(|v| v.split(','));
+
+    channels
+        .split(',')
+        .map(|channel| {
+            let key = keys
+                .next()
+                .filter(|v| !v.is_empty())
+                .map(ToString::to_string);
+            (channel, key)
+        })
+        .filter(|(channel, _)| !channel.is_empty())
+        .map(|(channel, key)| (channel.to_string(), key))
+        .collect()
+}
+
 #[must_use]
 pub fn parse_channel_name_list(s: &str) -> Vec<String> {
     s.split(',')
@@ -1031,7 +1203,19 @@ impl WriteHandler<ProtocolError> for Client {
     #[instrument(parent = &self.span, skip_all)]
     fn error(&mut self, error: ProtocolError, _ctx: &mut Self::Context) -> Running {
         error!(%error, "Failed to write message to client");
-        Running::Continue
+
+        WRITE_ERRORS.fetch_add(1, Ordering::Relaxed);
+
+        if self.write_errors.record_failure() {
+            warn!(
+                write_errors = self.write_errors.count(),
+                "Too many consecutive write errors, disconnecting client"
+            );
+            self.server_leave_reason = Some(format!("Write error: {error}"));
+            Running::Stop
+        } else {
+            Running::Continue
+        }
     }
 }
 
@@ -1040,8 +1224,11 @@ impl WriteHandler<ProtocolError> for Client {
 #[rtype(result = "()")]
 struct SendPrivateMessage {
     destination: String,
+    /// The server the destination was addressed on, if it wasn't this one.
+    server: Option<String>,
     message: String,
     kind: MessageKind,
+    tags: Vec<Tag>,
     span: Span,
 }
 
@@ -1057,10 +1244,21 @@ struct ListChannelMemberRequest {
 #[derive(actix::Message, Debug)]
 #[rtype(result = "()")]
 struct JoinChannelRequest {
-    channels: Vec<String>,
+    /// The channels to join, along with the key given for each of them
+    channels: Vec<(String, Option<String>)>,
     span: Span,
 }
 
+/// Updates the user's own modes, sent by the [`Client`] to itself when the user sends `MODE`
+/// and by the services link when services change the user's modes.
+#[derive(actix::Message, Debug)]
+#[rtype(result = "()")]
+pub struct SetUserModes {
+    pub nick: String,
+    pub modes: Vec<Mode<irc_proto::UserMode>>,
+    pub span: Span,
+}
+
 /// A [`Client`] internal self-notification to set away status
 #[derive(actix::Message, Debug)]
 #[rtype(result = "()")]
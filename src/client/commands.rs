@@ -0,0 +1,553 @@
+//! Handlers for each of the commands a registered client can send, passed on from the client's
+//! [`StreamHandler`](actix::StreamHandler) once the message has been validated.
+
+mod channel;
+mod info;
+mod messaging;
+mod oper;
+mod user;
+
+use actix::Context;
+use irc_proto::{message::Tag, Command, Message};
+
+use crate::{
+    client::{usage::COMMAND_USAGE, Client},
+    connection::UserMode,
+    messages::MessageKind,
+    proto::{self, LocalCommand},
+    server::response::{CommandDisabled, IntoProtocol},
+};
+
+/// A single command sent by a client, along with the arguments it was sent with.
+pub trait CommandHandler {
+    /// Processes the command on behalf of the client that sent it.
+    fn handle(self, client: &mut Client, ctx: &mut Context<Client>);
+}
+
+/// Passes the command onto its handler.
+pub fn dispatch(client: &mut Client, ctx: &mut Context<Client>, message: Message) {
+    let name = String::from(&message.command);
+    let name = name.split(' ').next().unwrap_or_default();
+
+    if client.commands.is_disabled(name, is_oper(client)) {
+        for m in CommandDisabled(name.to_string()).into_messages(&client.connection.nick()) {
+            client.writer.write(m);
+        }
+
+        return;
+    }
+
+    // commands we don't know of by name are only counted once they've been parsed, so made up
+    // commands don't end up in the counts
+    if !matches!(
+        message.command,
+        Command::Raw(..) | Command::NICKSERV(_) | Command::CHANSERV(_)
+    ) {
+        COMMAND_USAGE.record(name);
+    }
+
+    let tags = client_only_tags(message.tags);
+
+    // https://modern.ircdocs.horse/
+    #[allow(clippy::match_same_arms)]
+    match message.command {
+        Command::NICK(new_nick) => user::Nick { new_nick }.handle(client, ctx),
+        Command::UserMODE(nick, modes) => user::Mode { nick, modes }.handle(client, ctx),
+        Command::QUIT(message) => user::Quit { message }.handle(client, ctx),
+        Command::JOIN(channels, keys, _real_name) => {
+            channel::Join { channels, keys }.handle(client, ctx);
+        }
+        Command::PART(channel, message) => channel::Part { channel, message }.handle(client, ctx),
+        Command::ChannelMODE(channel, modes) => {
+            channel::Mode { channel, modes }.handle(client, ctx);
+        }
+        Command::TOPIC(channel, topic) => channel::Topic { channel, topic }.handle(client, ctx),
+        Command::NAMES(channels, _) => channel::Names { channels }.handle(client, ctx),
+        Command::LIST(query, _) => channel::List { query }.handle(client, ctx),
+        Command::INVITE(nick, channel) => channel::Invite { nick, channel }.handle(client, ctx),
+        Command::KICK(channel, users, reason) => channel::Kick {
+            channel,
+            users,
+            reason,
+        }
+        .handle(client, ctx),
+        Command::PRIVMSG(target, message) => messaging::Message {
+            target,
+            message,
+            kind: MessageKind::Normal,
+            tags,
+        }
+        .handle(client, ctx),
+        Command::NOTICE(target, message) => messaging::Message {
+            target,
+            message,
+            kind: MessageKind::Notice,
+            tags,
+        }
+        .handle(client, ctx),
+        Command::MOTD(_) => info::Motd.handle(client, ctx),
+        Command::LUSERS(_, _) => info::Lusers.handle(client, ctx),
+        Command::STATS(query, _) => info::Stats { query }.handle(client, ctx),
+        Command::VERSION(_) => info::Version.handle(client, ctx),
+        Command::TIME(_) => info::Time.handle(client, ctx),
+        Command::ADMIN(_) => info::Admin.handle(client, ctx),
+        Command::INFO(_) => info::Info.handle(client, ctx),
+        Command::WHO(Some(query), _) => info::Who { query }.handle(client, ctx),
+        Command::WHOIS(Some(query), _) => info::Whois { query }.handle(client, ctx),
+        Command::WHOWAS(nick, count, _) => info::Whowas { nick, count }.handle(client, ctx),
+        Command::OPER(name, password) => user::Oper { name, password }.handle(client, ctx),
+        Command::KILL(nick, comment) => oper::Kill { nick, comment }.handle(client, ctx),
+        Command::PING(token, _) => user::Ping { token }.handle(client, ctx),
+        Command::PONG(_, _) => user::Pong.handle(client, ctx),
+        Command::AWAY(message) => user::Away { message }.handle(client, ctx),
+        Command::REHASH => oper::Rehash.handle(client, ctx),
+        Command::DIE => {}
+        Command::RESTART => {}
+        Command::WALLOPS(message) => oper::Wallops { message }.handle(client, ctx),
+        Command::USERHOST(_) => {}
+        Command::SAJOIN(_, _) => {}
+        Command::SAMODE(_, _, _) => {}
+        Command::SANICK(old_nick, new_nick) => {
+            oper::SaNick { old_nick, new_nick }.handle(client, ctx);
+        }
+        Command::SAPART(_, _) => {}
+        Command::SAQUIT(user, comment) => oper::SaQuit { user, comment }.handle(client, ctx),
+        Command::AUTHENTICATE(_) => user::Authenticate.handle(client, ctx),
+        Command::ACCOUNT(_) => {}
+        Command::METADATA(_, _, _) => {}
+        Command::MONITOR(_, _) => {}
+        Command::BATCH(_, _, _) => {}
+        Command::CHGHOST(_, _) => {}
+        Command::Response(_, _) => {}
+        Command::NICKSERV(args) => dispatch_raw(client, ctx, "NS".to_string(), args, tags),
+        Command::CHANSERV(args) => {
+            let args = args.split_whitespace().map(ToString::to_string).collect();
+            dispatch_raw(client, ctx, "CS".to_string(), args, tags);
+        }
+        Command::Raw(command, args) if command.eq_ignore_ascii_case("CHANSERV") => {
+            dispatch_raw(client, ctx, "CS".to_string(), args, tags);
+        }
+        Command::Raw(command, args) => dispatch_raw(client, ctx, command, args, tags),
+        _ => unknown_command(client),
+    }
+}
+
+/// Parses a command that isn't a part of the IRC spec, passing it onto its handler.
+fn dispatch_raw(
+    client: &mut Client,
+    ctx: &mut Context<Client>,
+    command: String,
+    args: Vec<String>,
+    tags: Vec<Tag>,
+) {
+    let name = command.clone();
+
+    match LocalCommand::try_from((command, args)) {
+        Ok(command) => {
+            COMMAND_USAGE.record(&name);
+            dispatch_local(client, ctx, command, tags);
+        }
+        Err(e) => {
+            for m in e.into_messages(&client.connection.nick()) {
+                client.writer.write(m);
+            }
+        }
+    }
+}
+
+/// Passes a command that isn't a part of the IRC spec onto its handler.
+fn dispatch_local(
+    client: &mut Client,
+    ctx: &mut Context<Client>,
+    command: LocalCommand,
+    tags: Vec<Tag>,
+) {
+    match command {
+        LocalCommand::ListGline => oper::ListGline.handle(client, ctx),
+        LocalCommand::RemoveGline(mask) => oper::RemoveGline { mask }.handle(client, ctx),
+        LocalCommand::Gline(mask, duration, reason) => oper::Gline {
+            mask,
+            duration,
+            reason,
+        }
+        .handle(client, ctx),
+        LocalCommand::ListShun => oper::ListShun.handle(client, ctx),
+        LocalCommand::RemoveShun(mask) => oper::RemoveShun { mask }.handle(client, ctx),
+        LocalCommand::Shun(mask, duration, reason) => oper::Shun {
+            mask,
+            duration,
+            reason,
+        }
+        .handle(client, ctx),
+        LocalCommand::ChannelDirectMessage(kind, nick, channel, message) => {
+            messaging::ChannelDirectMessage {
+                kind,
+                nick,
+                channel,
+                message,
+            }
+            .handle(client, ctx);
+        }
+        LocalCommand::Accept(changes) => user::Accept { changes }.handle(client, ctx),
+        LocalCommand::NickHistory(query) => oper::NickHistory { query }.handle(client, ctx),
+        LocalCommand::DailyStats(days) => oper::DailyStats { days }.handle(client, ctx),
+        LocalCommand::DebugTap(nick, enabled) => {
+            oper::DebugTap { nick, enabled }.handle(client, ctx)
+        }
+        LocalCommand::Spy(channel, enabled) => oper::Spy { channel, enabled }.handle(client, ctx),
+        LocalCommand::Cert(command) => user::Cert { command }.handle(client, ctx),
+        LocalCommand::NickServ(command) => user::NickServ { command }.handle(client, ctx),
+        LocalCommand::KickBan(channel, user, reason) => channel::KickBan {
+            channel,
+            user,
+            reason,
+        }
+        .handle(client, ctx),
+        LocalCommand::ChanServ(command) => channel::ChanServ { command }.handle(client, ctx),
+        LocalCommand::ChatHistory(target, range, limit) => messaging::ChatHistory {
+            target,
+            range,
+            limit,
+        }
+        .handle(client, ctx),
+        LocalCommand::Redact(target, msgid, reason) => messaging::Redact {
+            target,
+            msgid,
+            reason,
+        }
+        .handle(client, ctx),
+        LocalCommand::TagMsg(target) => messaging::Message {
+            target,
+            message: String::new(),
+            kind: MessageKind::Tag,
+            tags,
+        }
+        .handle(client, ctx),
+    }
+}
+
+/// Picks out the client-only tags (ie. `+typing`) from a message sent by a client, these are
+/// the only tags a client is allowed to pass on to other users.
+fn client_only_tags(tags: Option<Vec<Tag>>) -> Vec<Tag> {
+    tags.into_iter()
+        .flatten()
+        .filter(|Tag(key, _)| key.starts_with('+'))
+        .collect()
+}
+
+/// Returns true if the client has operator privileges.
+fn is_oper(client: &Client) -> bool {
+    client.connection.mode().contains(UserMode::OPER)
+}
+
+/// Informs the client that we don't know of the command they sent. This is also sent in response
+/// to operator commands from users without operator privileges.
+fn unknown_command(client: &mut Client) {
+    for m in proto::Error::UnknownCommand.into_messages(&client.connection.nick()) {
+        client.writer.write(m);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::{HashMap, HashSet},
+        sync::Arc,
+        time::Duration,
+    };
+
+    use actix::{Actor, Addr, AsyncContext, Context, Handler, MessageResult};
+    use futures::StreamExt;
+    use irc_proto::{Command, Message, Prefix, Response};
+    use tokio::{
+        net::{TcpListener, TcpStream},
+        time::Instant,
+    };
+    use tokio_util::codec::FramedRead;
+    use tracing::Span;
+
+    use super::{messaging, oper, user, CommandHandler};
+    use crate::{
+        client::{
+            flood::{FloodDecision, FloodLimiter},
+            server_time_tags,
+            traffic::{CountingSink, SocketWriter, Traffic, WriteErrors},
+            Client, OperSession,
+        },
+        config::{CommandsConfig, ConnectionClass, OperSessionConfig},
+        connection::{negotiation::Negotiation, stream::ClientStream, UserId},
+        database::in_memory,
+        keys::Keys,
+        listener::{governor::ConnectionGovernor, irc_codec},
+        messages::{Broadcast, MessageKind},
+        persistence::Persistence,
+        proto::builder::MessageBuilder,
+        server::Server,
+    };
+
+    /// Runs a closure against the client from within its actor, as the client does when
+    /// dispatching a command, returning whatever the closure returns.
+    struct Run<F>(F);
+
+    impl<F, R> actix::Message for Run<F>
+    where
+        F: FnOnce(&mut Client, &mut Context<Client>) -> R,
+        R: 'static,
+    {
+        type Result = R;
+    }
+
+    impl<F, R> Handler<Run<F>> for Client
+    where
+        F: FnOnce(&mut Client, &mut Context<Client>) -> R,
+        R: 'static,
+    {
+        type Result = MessageResult<Run<F>>;
+
+        fn handle(&mut self, msg: Run<F>, ctx: &mut Self::Context) -> Self::Result {
+            MessageResult((msg.0)(self, ctx))
+        }
+    }
+
+    /// Starts a registered client in `class` over a loopback socket, returning the client along
+    /// with the other end of its socket to read what it sends.
+    async fn client(
+        class: ConnectionClass,
+    ) -> (Addr<Client>, FramedRead<TcpStream, irc_proto::IrcCodec>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (peer, accepted) = tokio::join!(TcpStream::connect(address), listener.accept());
+        let (stream, host) = accepted.unwrap();
+
+        let database = in_memory().await;
+
+        let persistence = Persistence::new(
+            database.clone(),
+            Duration::from_secs(0),
+            0,
+            Duration::from_secs(0),
+        )
+        .start();
+
+        let class = Arc::new(class);
+        let mut negotiation = Negotiation::new(host, class.clone());
+        let mut send = |line: &str| negotiation.handle(line.parse::<Message>().unwrap().command);
+        send("CAP REQ :sasl");
+        send("NICK test");
+        send("USER test 0 * :Test");
+        negotiation.authenticated("test".to_string(), UserId(1));
+        negotiation.handle("CAP END".parse::<Message>().unwrap().command);
+        let connection = negotiation
+            .complete(&Keys { ip_salt: [0; 32] })
+            .unwrap_or_else(|_| panic!("registration should have completed"));
+
+        let permit = ConnectionGovernor::default()
+            .admit(host.ip(), None)
+            .unwrap();
+
+        let client = Client::create(move |ctx| {
+            let (_read, write) = tokio::io::split(ClientStream::Plain(stream));
+            let write_errors = WriteErrors::default();
+            let writer = actix::io::FramedWrite::new(
+                SocketWriter::new(write, write_errors.clone()),
+                irc_codec(),
+                ctx,
+            );
+            let traffic = Arc::new(Traffic::new());
+
+            Client {
+                writer: CountingSink::new(writer, traffic.clone()),
+                traffic,
+                tap_log: None,
+                tap: None,
+                connection: Arc::new(connection),
+                // nothing is listening on the server's mailbox, so anything sent to it is dropped
+                server: Context::<Server>::new().address(),
+                channels: HashMap::new(),
+                joining: HashSet::new(),
+                last_active: Instant::now(),
+                last_command: Instant::now(),
+                last_who: None,
+                oper_session: OperSession::new(OperSessionConfig::default()),
+                commands: Arc::new(CommandsConfig::default()),
+                write_errors,
+                graceful_shutdown: false,
+                server_shutdown: false,
+                server_leave_reason: None,
+                shunned: false,
+                flood: FloodLimiter::for_class(&class),
+                connection_permit: permit,
+                persistence,
+                database,
+                span: Span::current(),
+            }
+        });
+
+        (client, FramedRead::new(peer.unwrap(), irc_codec()))
+    }
+
+    /// Reads the next message the client sent.
+    async fn next(reader: &mut FramedRead<TcpStream, irc_proto::IrcCodec>) -> Message {
+        tokio::time::timeout(Duration::from_secs(5), reader.next())
+            .await
+            .expect("client didn't send anything")
+            .unwrap()
+            .unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn pong_resets_write_errors() {
+        let (client, _reader) = client(ConnectionClass::default()).await;
+
+        let count = client
+            .send(Run(|client: &mut Client, ctx: &mut Context<Client>| {
+                client.write_errors.record_failure();
+                client.write_errors.record_failure();
+
+                user::Pong.handle(client, ctx);
+                client.write_errors.count()
+            }))
+            .await;
+
+        assert_eq!(count.unwrap(), 0);
+    }
+
+    #[actix_rt::test]
+    async fn oper_commands_are_unknown_to_users() {
+        let (client, mut reader) = client(ConnectionClass::default()).await;
+
+        client
+            .send(Run(|client: &mut Client, ctx: &mut Context<Client>| {
+                oper::NickHistory {
+                    query: "someone".to_string(),
+                }
+                .handle(client, ctx);
+            }))
+            .await
+            .unwrap();
+
+        let message = next(&mut reader).await;
+        assert!(
+            matches!(
+                message.command,
+                Command::Response(Response::ERR_UNKNOWNCOMMAND, _)
+            ),
+            "{message:?}"
+        );
+    }
+
+    #[actix_rt::test]
+    async fn kill_is_unknown_to_users() {
+        let (client, mut reader) = client(ConnectionClass::default()).await;
+
+        client
+            .send(Run(|client: &mut Client, ctx: &mut Context<Client>| {
+                oper::Kill {
+                    nick: "someone".to_string(),
+                    comment: "bye".to_string(),
+                }
+                .handle(client, ctx);
+            }))
+            .await
+            .unwrap();
+
+        let message = next(&mut reader).await;
+        assert!(
+            matches!(
+                message.command,
+                Command::Response(Response::ERR_UNKNOWNCOMMAND, _)
+            ),
+            "{message:?}"
+        );
+    }
+
+    #[actix_rt::test]
+    async fn sanick_is_unknown_to_users() {
+        let (client, mut reader) = client(ConnectionClass::default()).await;
+
+        client
+            .send(Run(|client: &mut Client, ctx: &mut Context<Client>| {
+                oper::SaNick {
+                    old_nick: "someone".to_string(),
+                    new_nick: "someone_else".to_string(),
+                }
+                .handle(client, ctx);
+            }))
+            .await
+            .unwrap();
+
+        let message = next(&mut reader).await;
+        assert!(
+            matches!(
+                message.command,
+                Command::Response(Response::ERR_UNKNOWNCOMMAND, _)
+            ),
+            "{message:?}"
+        );
+    }
+
+    #[actix_rt::test]
+    async fn join_burst_drops_tags_without_capability() {
+        let (client, mut reader) = client(ConnectionClass::default()).await;
+
+        // the channel sends its join burst through the client's broadcasts
+        client
+            .send(Broadcast {
+                message: MessageBuilder::user(Prefix::Nickname(
+                    "test".to_string(),
+                    "test".to_string(),
+                    "localhost".to_string(),
+                ))
+                .tags(server_time_tags())
+                .command(Command::JOIN("#test".to_string(), None, None)),
+                span: Span::current(),
+            })
+            .await
+            .unwrap();
+
+        let message = next(&mut reader).await;
+        assert!(matches!(message.command, Command::JOIN(..)), "{message:?}");
+        assert_eq!(message.tags, None);
+    }
+
+    #[actix_rt::test]
+    async fn direct_message_outside_channel_is_charged() {
+        let class = ConnectionClass {
+            flood_rate: Some(1),
+            flood_burst: Some(1),
+            ..ConnectionClass::default()
+        };
+        let (client, mut reader) = client(class).await;
+
+        let decision = client
+            .send(Run(|client: &mut Client, ctx: &mut Context<Client>| {
+                messaging::ChannelDirectMessage {
+                    kind: MessageKind::Normal,
+                    nick: "other".to_string(),
+                    channel: "#channel".to_string(),
+                    message: "hello".to_string(),
+                }
+                .handle(client, ctx);
+
+                // the token taken for the message leaves nothing for the next command
+                let message = Message::from(Command::PING("token".to_string(), None));
+                client
+                    .flood
+                    .as_mut()
+                    .unwrap()
+                    .receive(message, Instant::now())
+            }))
+            .await;
+
+        assert_eq!(decision.unwrap(), FloodDecision::Queued);
+
+        let message = next(&mut reader).await;
+        assert!(
+            matches!(
+                &message.command,
+                Command::Response(Response::ERR_NOTONCHANNEL, args) if args[1] == "#channel"
+            ),
+            "{message:?}"
+        );
+    }
+}
@@ -0,0 +1,461 @@
+//! Commands targeting a channel.
+
+use actix::{ActorFutureExt, AsyncContext, Context, WrapFuture};
+use chrono::Utc;
+use futures::FutureExt;
+use irc_proto::ChannelMode;
+use tracing::{error, warn, Span};
+
+use crate::{
+    channel::{list::ListQuery, permissions::Permission, response::NotOnChannel},
+    client::{
+        commands::CommandHandler, parse_channel_join_list, parse_channel_name_list, Client,
+        JoinChannelRequest, ListChannelMemberRequest,
+    },
+    host_mask::HostMask,
+    messages::{
+        ChannelFetchTopic, ChannelInvite, ChannelKickUser, ChannelList, ChannelPart,
+        ChannelSetMetadata, ChannelSetMode, ChannelUpdateTopic,
+    },
+    persistence::events::{
+        FetchChannelAccess, ImportChannelAccess, ImportChannelAccessResult, RegisterChannel,
+        RegisterChannelResult, SetChannelAccess, SetChannelAccessResult, SetChannelSuccessor,
+        SetChannelSuccessorResult, TransferChannel,
+    },
+    proto::ChanServCommand,
+    server::response::{ChannelSuccessor, IntoProtocol},
+    services::{export_access, ChanServResponse},
+};
+
+/// `JOIN`, joins each of the given comma-separated channels, using the comma-separated keys
+/// for any channels with a key set.
+pub struct Join {
+    pub channels: String,
+    pub keys: Option<String>,
+}
+
+impl CommandHandler for Join {
+    fn handle(self, _client: &mut Client, ctx: &mut Context<Client>) {
+        // split the list of channel names and their keys...
+        let channels = parse_channel_join_list(&self.channels, self.keys.as_deref());
+
+        // ...and send a self-notification to schedule those joins
+        ctx.notify(JoinChannelRequest {
+            channels,
+            span: Span::current(),
+        });
+    }
+}
+
+/// `PART`, leaves a channel.
+pub struct Part {
+    pub channel: String,
+    pub message: Option<String>,
+}
+
+impl CommandHandler for Part {
+    fn handle(self, client: &mut Client, ctx: &mut Context<Client>) {
+        // remove the handle from the users locally connected channels
+        let Some(channel) = client.channels.remove(&self.channel) else {
+            return;
+        };
+
+        // alert the channel to our leave
+        channel.do_send(ChannelPart {
+            client: ctx.address(),
+            message: self.message,
+            span: Span::current(),
+        });
+    }
+}
+
+/// `MODE` targeting a channel, queries or updates the channel's modes.
+pub struct Mode {
+    pub channel: String,
+    pub modes: Vec<irc_proto::Mode<ChannelMode>>,
+}
+
+impl CommandHandler for Mode {
+    fn handle(self, client: &mut Client, ctx: &mut Context<Client>) {
+        let Some(channel) = client.channels.get(&self.channel) else {
+            return;
+        };
+
+        client.channel_send_map_write(
+            ctx,
+            channel,
+            ChannelSetMode {
+                span: Span::current(),
+                client: ctx.address(),
+                modes: self.modes,
+            },
+        );
+    }
+}
+
+/// `TOPIC`, queries or updates the channel's topic.
+pub struct Topic {
+    pub channel: String,
+    pub topic: Option<String>,
+}
+
+impl CommandHandler for Topic {
+    fn handle(self, client: &mut Client, ctx: &mut Context<Client>) {
+        let Some(channel) = client.channels.get(&self.channel) else {
+            return;
+        };
+
+        #[allow(clippy::option_if_let_else)]
+        if let Some(topic) = self.topic {
+            channel.do_send(ChannelUpdateTopic {
+                topic,
+                client: ctx.address(),
+                span: Span::current(),
+            });
+        } else {
+            let span = Span::current();
+            client.channel_send_map_write(
+                ctx,
+                channel,
+                ChannelFetchTopic {
+                    span,
+                    skip_on_none: false,
+                },
+            );
+        }
+    }
+}
+
+/// `NAMES`, lists the members of each of the given comma-separated channels.
+pub struct Names {
+    pub channels: Option<String>,
+}
+
+impl CommandHandler for Names {
+    fn handle(self, _client: &mut Client, ctx: &mut Context<Client>) {
+        // split the list of channel names...
+        let channels = parse_channel_name_list(self.channels.as_deref().unwrap_or(""));
+
+        if channels.is_empty() {
+            warn!("Client didn't request names for a particular channel");
+            return;
+        }
+
+        // ...and send a self-notification to request each channel for their list
+        ctx.notify(ListChannelMemberRequest {
+            channels,
+            span: Span::current(),
+        });
+    }
+}
+
+/// `LIST`, lists the channels on the server matching the query.
+pub struct List {
+    pub query: Option<String>,
+}
+
+impl CommandHandler for List {
+    fn handle(self, client: &mut Client, ctx: &mut Context<Client>) {
+        let span = Span::current();
+        let query = ListQuery::parse(self.query.as_deref().unwrap_or_default(), Utc::now());
+        client.server_send_map_write(
+            ctx,
+            ChannelList {
+                span,
+                client: ctx.address(),
+                query,
+            },
+        );
+    }
+}
+
+/// `INVITE`, invites a user to a channel.
+pub struct Invite {
+    pub nick: String,
+    pub channel: String,
+}
+
+impl CommandHandler for Invite {
+    fn handle(self, client: &mut Client, ctx: &mut Context<Client>) {
+        let Some(channel) = client.channels.get(&self.channel) else {
+            error!(channel = %self.channel, "User not connected to channel");
+            return;
+        };
+
+        channel.do_send(ChannelInvite {
+            nick: self.nick,
+            client: ctx.address(),
+            span: Span::current(),
+        });
+    }
+}
+
+/// `KICK`, removes each of the given comma-separated users from a channel.
+pub struct Kick {
+    pub channel: String,
+    pub users: String,
+    pub reason: Option<String>,
+}
+
+impl CommandHandler for Kick {
+    fn handle(self, client: &mut Client, ctx: &mut Context<Client>) {
+        let Some(channel) = client.channels.get(&self.channel) else {
+            error!(channel = %self.channel, "User not connected to channel");
+            return;
+        };
+
+        for user in parse_channel_name_list(&self.users) {
+            channel.do_send(ChannelKickUser {
+                span: Span::current(),
+                client: ctx.address(),
+                user,
+                reason: self.reason.clone(),
+                ban: false,
+            });
+        }
+    }
+}
+
+/// `KICKBAN`/`REMOVE`, bans a user's host from a channel and kicks them.
+pub struct KickBan {
+    pub channel: String,
+    pub user: String,
+    pub reason: Option<String>,
+}
+
+impl CommandHandler for KickBan {
+    fn handle(self, client: &mut Client, ctx: &mut Context<Client>) {
+        let Some(channel) = client.channels.get(&self.channel) else {
+            client
+                .writer
+                .write(NotOnChannel(client.connection.nick(), self.channel).into_message());
+            return;
+        };
+
+        channel.do_send(ChannelKickUser {
+            span: Span::current(),
+            client: ctx.address(),
+            user: self.user,
+            reason: self.reason,
+            ban: true,
+        });
+    }
+}
+
+/// `CS`, manages the registration, access list and metadata of a channel.
+pub struct ChanServ {
+    pub command: ChanServCommand,
+}
+
+impl CommandHandler for ChanServ {
+    fn handle(self, client: &mut Client, ctx: &mut Context<Client>) {
+        let requester = client.connection.user_id;
+        let nick = client.connection.nick();
+        let persistence = client.persistence.clone();
+
+        let fut = match self.command {
+            ChanServCommand::Register(channel) => async move {
+                let result = persistence
+                    .send(RegisterChannel {
+                        channel: channel.clone(),
+                        requester,
+                    })
+                    .await
+                    .unwrap();
+
+                let response = match result {
+                    RegisterChannelResult::Registered => ChanServResponse::Registered(channel),
+                    RegisterChannelResult::AlreadyRegistered => {
+                        ChanServResponse::AlreadyRegistered(channel)
+                    }
+                    RegisterChannelResult::NotInChannel => ChanServResponse::NotInChannel(channel),
+                };
+
+                response.into_messages(&nick)
+            }
+            .boxed_local(),
+            ChanServCommand::Transfer(channel, account) => async move {
+                let result = persistence
+                    .send(TransferChannel {
+                        channel: channel.clone(),
+                        requester,
+                        account: account.clone(),
+                    })
+                    .await
+                    .unwrap();
+
+                let response = match result {
+                    SetChannelSuccessorResult::Updated => {
+                        ChanServResponse::Transferred(channel, account)
+                    }
+                    SetChannelSuccessorResult::NotFounder => ChanServResponse::NotFounder(channel),
+                    SetChannelSuccessorResult::NoSuchAccount => {
+                        ChanServResponse::NoSuchAccount(account)
+                    }
+                };
+
+                response.into_messages(&nick)
+            }
+            .boxed_local(),
+            ChanServCommand::SetSuccessor(channel, account) => async move {
+                let result = persistence
+                    .send(SetChannelSuccessor {
+                        channel: channel.clone(),
+                        requester,
+                        successor: account.clone(),
+                    })
+                    .await
+                    .unwrap();
+
+                let response = match (result, account) {
+                    (SetChannelSuccessorResult::Updated, Some(account)) => {
+                        ChannelSuccessor::Set(channel, account)
+                    }
+                    (SetChannelSuccessorResult::Updated, None) => {
+                        ChannelSuccessor::Cleared(channel)
+                    }
+                    (SetChannelSuccessorResult::NotFounder, _) => {
+                        ChannelSuccessor::NotFounder(channel)
+                    }
+                    (SetChannelSuccessorResult::NoSuchAccount, account) => {
+                        ChannelSuccessor::NoSuchAccount(account.unwrap_or_default())
+                    }
+                };
+
+                response.into_messages(&nick)
+            }
+            .boxed_local(),
+            ChanServCommand::SetMetadata(channel, key, value) => {
+                let Some(handle) = client.channels.get(&channel) else {
+                    client
+                        .writer
+                        .write(NotOnChannel(nick, channel).into_message());
+                    return;
+                };
+
+                let request = handle.send(ChannelSetMetadata {
+                    key,
+                    value: value.clone(),
+                    client: ctx.address(),
+                    span: Span::current(),
+                });
+
+                async move {
+                    let response = match (request.await.unwrap(), value) {
+                        (true, Some(value)) => ChanServResponse::MetadataSet(channel, key, value),
+                        (true, None) => ChanServResponse::MetadataCleared(channel, key),
+                        (false, _) => ChanServResponse::NotOperator(channel),
+                    };
+
+                    response.into_messages(&nick)
+                }
+                .boxed_local()
+            }
+            ChanServCommand::AccessAdd(channel, mask, permissions) => async move {
+                let result = persistence
+                    .send(SetChannelAccess {
+                        channel: channel.clone(),
+                        requester,
+                        mask: mask.clone(),
+                        permissions: Some(permissions),
+                    })
+                    .await
+                    .unwrap();
+
+                access_response(result, channel, mask, Some(permissions)).into_messages(&nick)
+            }
+            .boxed_local(),
+            ChanServCommand::AccessDel(channel, mask) => async move {
+                let result = persistence
+                    .send(SetChannelAccess {
+                        channel: channel.clone(),
+                        requester,
+                        mask: mask.clone(),
+                        permissions: None,
+                    })
+                    .await
+                    .unwrap();
+
+                access_response(result, channel, mask, None).into_messages(&nick)
+            }
+            .boxed_local(),
+            ChanServCommand::AccessList(channel) => async move {
+                let access = persistence
+                    .send(FetchChannelAccess {
+                        channel: channel.clone(),
+                    })
+                    .await
+                    .unwrap();
+
+                let response = match access {
+                    Some(access) => ChanServResponse::AccessList(channel, access),
+                    None => ChanServResponse::NoSuchChannel(channel),
+                };
+
+                response.into_messages(&nick)
+            }
+            .boxed_local(),
+            ChanServCommand::AccessExport(channel) => async move {
+                let access = persistence
+                    .send(FetchChannelAccess {
+                        channel: channel.clone(),
+                    })
+                    .await
+                    .unwrap();
+
+                let response = match access {
+                    Some(access) => ChanServResponse::AccessExport(channel, export_access(&access)),
+                    None => ChanServResponse::NoSuchChannel(channel),
+                };
+
+                response.into_messages(&nick)
+            }
+            .boxed_local(),
+            ChanServCommand::AccessImport(channel, entries) => async move {
+                let result = persistence
+                    .send(ImportChannelAccess {
+                        channel: channel.clone(),
+                        requester,
+                        entries,
+                    })
+                    .await
+                    .unwrap();
+
+                let response = match result {
+                    ImportChannelAccessResult::Imported(imported) => {
+                        ChanServResponse::AccessImported(channel, imported)
+                    }
+                    ImportChannelAccessResult::NotFounder => ChanServResponse::NotFounder(channel),
+                };
+
+                response.into_messages(&nick)
+            }
+            .boxed_local(),
+        };
+
+        ctx.spawn(fut.into_actor(client).map(|messages, this, _ctx| {
+            for message in messages {
+                this.writer.write(message);
+            }
+        }));
+    }
+}
+
+/// Maps the outcome of changing an entry on a channel's access list to the response sent back
+/// to the user, `permissions` being `None` if the entry was being removed.
+fn access_response(
+    result: SetChannelAccessResult,
+    channel: String,
+    mask: HostMask<'static>,
+    permissions: Option<Permission>,
+) -> ChanServResponse {
+    match (result, permissions) {
+        (SetChannelAccessResult::Updated, Some(permissions)) => {
+            ChanServResponse::AccessUpdated(channel, mask, permissions)
+        }
+        (SetChannelAccessResult::Updated, None) => ChanServResponse::AccessRemoved(channel, mask),
+        (SetChannelAccessResult::NotFounder, _) => ChanServResponse::NotFounder(channel),
+        (SetChannelAccessResult::FounderEntry, _) => ChanServResponse::FounderEntry(channel),
+        (SetChannelAccessResult::NoSuchEntry, _) => ChanServResponse::NoSuchEntry(channel, mask),
+    }
+}
@@ -0,0 +1,241 @@
+//! Commands querying information about the server and its users.
+
+use actix::{ActorFutureExt, AsyncContext, Context, WrapFuture};
+use clap::{crate_name, crate_version};
+use irc_proto::Response;
+use tokio::time::Instant;
+use tracing::Span;
+
+use crate::{
+    client::{
+        commands::{is_oper, CommandHandler},
+        Client,
+    },
+    connection::Capability,
+    messages::{
+        FetchWhoList, FetchWhois, ServerAdminInfo, ServerFetchMotd, ServerListUsers, ServerStats,
+    },
+    persistence::events::FetchWhowas,
+    proto::builder::MessageBuilder,
+    server::response::{self, IntoProtocol},
+    SERVER_NAME,
+};
+
+/// `MOTD`, sends the message of the day.
+pub struct Motd;
+
+impl CommandHandler for Motd {
+    fn handle(self, client: &mut Client, ctx: &mut Context<Client>) {
+        let span = Span::current();
+        let class = client.connection.class.clone();
+        client.server_send_map_write(ctx, ServerFetchMotd { class, span });
+    }
+}
+
+/// `LUSERS`, sends statistics about the size of the network.
+pub struct Lusers;
+
+impl CommandHandler for Lusers {
+    fn handle(self, client: &mut Client, ctx: &mut Context<Client>) {
+        let span = Span::current();
+        client.server_send_map_write(ctx, ServerListUsers { span });
+    }
+}
+
+/// `STATS`, sends the requested statistics report.
+pub struct Stats {
+    pub query: Option<String>,
+}
+
+impl CommandHandler for Stats {
+    fn handle(self, client: &mut Client, ctx: &mut Context<Client>) {
+        let query = self.query.unwrap_or_default();
+
+        // per-client traffic, the configured operators and bans are only exposed to operators
+        if matches!(query.as_str(), "l" | "o" | "k" | "g") && !is_oper(client) {
+            client.writer.write(MessageBuilder::server().response(
+                Response::ERR_NOPRIVILEGES,
+                vec![
+                    client.connection.nick(),
+                    "Permission Denied- You're not an IRC operator".to_string(),
+                ],
+            ));
+            return;
+        }
+
+        let span = Span::current();
+        client.server_send_map_write(ctx, ServerStats { span, query });
+    }
+}
+
+/// `VERSION`, sends the version of the server.
+pub struct Version;
+
+impl CommandHandler for Version {
+    fn handle(self, client: &mut Client, _ctx: &mut Context<Client>) {
+        client.writer.write(MessageBuilder::server().response(
+            Response::RPL_VERSION,
+            vec![
+                client.connection.nick(),
+                format!("{}-{}", crate_name!(), crate_version!()),
+                SERVER_NAME.to_string(),
+            ],
+        ));
+    }
+}
+
+/// `TIME`, sends the server's local time.
+pub struct Time;
+
+impl CommandHandler for Time {
+    fn handle(self, client: &mut Client, _ctx: &mut Context<Client>) {
+        let time = chrono::Utc::now();
+
+        client.writer.write(MessageBuilder::server().response(
+            Response::RPL_TIME,
+            vec![
+                client.connection.nick(),
+                SERVER_NAME.to_string(),
+                time.timestamp().to_string(),
+                time.format("%a %b %d %Y %T").to_string(),
+            ],
+        ));
+    }
+}
+
+/// `ADMIN`, sends the administrative contact details for the server.
+pub struct Admin;
+
+impl CommandHandler for Admin {
+    fn handle(self, client: &mut Client, ctx: &mut Context<Client>) {
+        let span = Span::current();
+        client.server_send_map_write(ctx, ServerAdminInfo { span });
+    }
+}
+
+/// `INFO`, sends information about the server software.
+pub struct Info;
+
+impl CommandHandler for Info {
+    fn handle(self, client: &mut Client, _ctx: &mut Context<Client>) {
+        static INFO_STR: &str = include_str!("../../../text/info.txt");
+
+        for line in INFO_STR.trim().split('\n') {
+            client.writer.write(MessageBuilder::server().response(
+                Response::RPL_INFO,
+                vec![client.connection.nick(), line.to_string()],
+            ));
+        }
+
+        client.writer.write(MessageBuilder::server().response(
+            Response::RPL_ENDOFINFO,
+            vec![client.connection.nick(), "End of INFO list".to_string()],
+        ));
+    }
+}
+
+/// `WHO`, lists the users matching the query.
+pub struct Who {
+    pub query: String,
+}
+
+impl CommandHandler for Who {
+    fn handle(self, client: &mut Client, ctx: &mut Context<Client>) {
+        let now = Instant::now();
+        let interval = client.commands.who_interval;
+
+        if !is_oper(client)
+            && client
+                .last_who
+                .is_some_and(|last| now.saturating_duration_since(last) < interval)
+        {
+            client.writer.write(MessageBuilder::server().response(
+                Response::RPL_TRYAGAIN,
+                vec![
+                    client.connection.nick(),
+                    "WHO".to_string(),
+                    "Please wait a while and try again.".to_string(),
+                ],
+            ));
+            return;
+        }
+
+        client.last_who = Some(now);
+
+        let span = Span::current();
+        client.server_send_map_write(
+            ctx,
+            FetchWhoList {
+                span,
+                client: ctx.address(),
+                query: self.query,
+                multi_prefix: client
+                    .connection
+                    .capabilities
+                    .contains(Capability::MULTI_PREFIX),
+            },
+        );
+    }
+}
+
+/// `WHOIS`, sends information about a user.
+pub struct Whois {
+    pub query: String,
+}
+
+impl CommandHandler for Whois {
+    fn handle(self, client: &mut Client, ctx: &mut Context<Client>) {
+        let span = Span::current();
+        client.server_send_map_write(
+            ctx,
+            FetchWhois {
+                span,
+                client: ctx.address(),
+                query: self.query,
+            },
+        );
+    }
+}
+
+/// `WHOWAS`, sends information about the users that previously used a nick.
+pub struct Whowas {
+    pub nick: String,
+    pub count: Option<String>,
+}
+
+impl Whowas {
+    /// The most entries returned, regardless of the count the client asked for.
+    const MAX_ENTRIES: usize = 10;
+}
+
+impl CommandHandler for Whowas {
+    fn handle(self, client: &mut Client, ctx: &mut Context<Client>) {
+        // a missing or non-positive count asks for every entry
+        let count = self
+            .count
+            .and_then(|v| v.parse::<usize>().ok())
+            .filter(|v| *v > 0)
+            .map_or(Self::MAX_ENTRIES, |v| v.min(Self::MAX_ENTRIES));
+
+        let query = self.nick;
+        let fut = client
+            .persistence
+            .send(FetchWhowas {
+                nick: query.clone(),
+                count,
+            })
+            .into_actor(client)
+            .map(move |entries, this, _ctx| {
+                let whowas = response::Whowas {
+                    query,
+                    entries: entries.unwrap(),
+                };
+
+                for message in whowas.into_messages(&this.connection.nick()) {
+                    this.writer.write(message);
+                }
+            });
+
+        ctx.spawn(fut);
+    }
+}
@@ -0,0 +1,234 @@
+//! Commands sending messages to users and channels.
+
+use actix::{ActorFutureExt, AsyncContext, Context, WrapFuture};
+use futures::FutureExt;
+use irc_proto::{message::Tag, ChannelExt};
+use tracing::{error, Span};
+
+use crate::{
+    channel::response::{NotOnChannel, RedactFailed},
+    client::{commands::CommandHandler, Client, SendPrivateMessage},
+    messages::{self, ChannelMessage, MessageKind},
+    persistence::events::{
+        FetchAccountByNick, FetchChannelHistory, FetchChannelReactions, FetchPrivateHistory,
+        HistoryRange,
+    },
+    proto::MessageTarget,
+    replay::{new_batch_id, Replayer},
+    server::response::{IntoProtocol, NoSuchNick},
+};
+
+/// `PRIVMSG`/`NOTICE`/`TAGMSG`, sends a message to a user or channel.
+pub struct Message {
+    pub target: String,
+    pub message: String,
+    pub kind: MessageKind,
+    /// Client-only tags to forward to recipients that negotiated `message-tags`
+    pub tags: Vec<Tag>,
+}
+
+impl CommandHandler for Message {
+    fn handle(self, client: &mut Client, ctx: &mut Context<Client>) {
+        let (destination, server) = match MessageTarget::parse(&self.target) {
+            MessageTarget::User(nick) => (nick, None),
+            MessageTarget::Remote { nick, server } => (nick, Some(server)),
+            MessageTarget::Channel { name, status } => {
+                if let Some(channel) = client.channels.get(&name) {
+                    channel.do_send(ChannelMessage {
+                        client: ctx.address(),
+                        message: self.message,
+                        kind: self.kind,
+                        tags: self.tags,
+                        status,
+                        span: Span::current(),
+                    });
+                } else {
+                    // user not connected to channel
+                    error!("User not connected to channel");
+                }
+
+                return;
+            }
+        };
+
+        // private message to another user
+        ctx.notify(SendPrivateMessage {
+            destination,
+            server,
+            message: self.message,
+            kind: self.kind,
+            tags: self.tags,
+            span: Span::current(),
+        });
+    }
+}
+
+/// `CPRIVMSG`/`CNOTICE`, sends a message to a user via a channel the sender has voice or operator
+/// privileges in. These are let past the flood limiter, so the limiter is charged for the
+/// command if the sender turns out not to hold the privilege.
+pub struct ChannelDirectMessage {
+    pub kind: MessageKind,
+    pub nick: String,
+    pub channel: String,
+    pub message: String,
+}
+
+impl CommandHandler for ChannelDirectMessage {
+    fn handle(self, client: &mut Client, ctx: &mut Context<Client>) {
+        let Some(channel) = client.channels.get(&self.channel) else {
+            client
+                .writer
+                .write(NotOnChannel(client.connection.nick(), self.channel).into_message());
+            client.charge_flood(ctx);
+            return;
+        };
+
+        let request = channel.send(messages::ChannelDirectMessage {
+            client: ctx.address(),
+            nick: self.nick,
+            kind: self.kind,
+            message: self.message,
+            span: Span::current(),
+        });
+
+        ctx.spawn(request.into_actor(client).map(|privileged, this, ctx| {
+            if !privileged.unwrap_or_default() {
+                this.charge_flood(ctx);
+            }
+        }));
+    }
+}
+
+/// `REDACT`, deletes a message previously sent to a channel the user is in.
+pub struct Redact {
+    pub target: String,
+    pub msgid: String,
+    pub reason: Option<String>,
+}
+
+impl CommandHandler for Redact {
+    fn handle(self, client: &mut Client, ctx: &mut Context<Client>) {
+        if !self.target.is_channel_name() {
+            client
+                .writer
+                .write(RedactFailed::InvalidTarget(self.target).into_message());
+            return;
+        }
+
+        let Some(channel) = client.channels.get(&self.target) else {
+            client
+                .writer
+                .write(NotOnChannel(client.connection.nick(), self.target).into_message());
+            return;
+        };
+
+        channel.do_send(messages::ChannelRedact {
+            client: ctx.address(),
+            msgid: self.msgid,
+            reason: self.reason,
+            span: Span::current(),
+        });
+    }
+}
+
+/// `CHATHISTORY`, fetches the history of a channel the user is in, or of the user's private
+/// conversation with another user.
+pub struct ChatHistory {
+    pub target: String,
+    pub range: HistoryRange,
+    pub limit: usize,
+}
+
+impl CommandHandler for ChatHistory {
+    fn handle(self, client: &mut Client, ctx: &mut Context<Client>) {
+        let persistence = client.persistence.clone();
+        let Self {
+            target,
+            range,
+            limit,
+        } = self;
+
+        let fut = if target.is_channel_name() {
+            if !client.channels.contains_key(&target) {
+                client
+                    .writer
+                    .write(NotOnChannel(client.connection.nick(), target).into_message());
+                return;
+            }
+
+            async move {
+                let messages = persistence
+                    .send(FetchChannelHistory {
+                        channel: target.clone(),
+                        range,
+                        limit,
+                    })
+                    .await
+                    .unwrap();
+
+                let reactions = persistence
+                    .send(FetchChannelReactions {
+                        channel: target.clone(),
+                        msgids: messages.iter().filter_map(|v| v.4.clone()).collect(),
+                    })
+                    .await
+                    .unwrap();
+
+                let messages = messages
+                    .into_iter()
+                    .map(|message| (target.clone(), message))
+                    .collect();
+
+                Ok((target, messages, reactions))
+            }
+            .boxed_local()
+        } else {
+            let user_id = client.connection.user_id;
+            let own_nick = client.connection.nick();
+
+            async move {
+                let Some((other_user_id, _)) = persistence
+                    .send(FetchAccountByNick {
+                        nick: target.clone(),
+                    })
+                    .await
+                    .unwrap()
+                else {
+                    return Err(NoSuchNick { nick: target });
+                };
+
+                // messages are addressed to whoever received them, as they were when first sent
+                let messages = persistence
+                    .send(FetchPrivateHistory {
+                        user_id,
+                        other_user_id,
+                        range,
+                        limit,
+                    })
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .map(|(message, sent)| {
+                        let to = if sent { &target } else { &own_nick };
+                        (to.clone(), message)
+                    })
+                    .collect();
+
+                Ok((target, messages, Vec::new()))
+            }
+            .boxed_local()
+        };
+
+        ctx.spawn(fut.into_actor(client).map(|result, this, _ctx| {
+            let messages = match result {
+                Ok((target, messages, reactions)) => Replayer::new(this.connection.capabilities)
+                    .history(&new_batch_id(), &target, messages, reactions),
+                Err(error) => error.into_messages(&this.connection.nick()),
+            };
+
+            for message in messages {
+                this.writer.write(message);
+            }
+        }));
+    }
+}
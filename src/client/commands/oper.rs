@@ -0,0 +1,339 @@
+//! Commands that may only be used by operators. Users without operator privileges are told the
+//! command doesn't exist.
+
+use std::time::Duration;
+
+use actix::{ActorFutureExt, AsyncContext, Context, WrapFuture};
+use tracing::Span;
+
+use crate::{
+    client::{
+        commands::{is_oper, unknown_command, CommandHandler},
+        Client,
+    },
+    host_mask::{BanMask, HostMask},
+    messages::{self, ForceDisconnect, KillUser, UserNickChangeInternal},
+    persistence::events::{FetchDailyStats, FetchNickHistory},
+    server::response::{self, IntoProtocol},
+};
+
+/// `KILL`, disconnects a user from the server.
+pub struct Kill {
+    pub nick: String,
+    pub comment: String,
+}
+
+impl CommandHandler for Kill {
+    fn handle(self, client: &mut Client, _ctx: &mut Context<Client>) {
+        if !is_oper(client) {
+            return unknown_command(client);
+        }
+
+        client.server.do_send(KillUser {
+            span: Span::current(),
+            killer: client.connection.nick(),
+            comment: self.comment,
+            killed: self.nick,
+        });
+    }
+}
+
+/// `WALLOPS`, sends a message to every user with `+w` set.
+pub struct Wallops {
+    pub message: String,
+}
+
+impl CommandHandler for Wallops {
+    fn handle(self, client: &mut Client, _ctx: &mut Context<Client>) {
+        if !is_oper(client) {
+            return unknown_command(client);
+        }
+
+        client.server.do_send(messages::Wallops {
+            span: Span::current(),
+            message: self.message,
+        });
+    }
+}
+
+/// `REHASH`, reloads the server's config.
+pub struct Rehash;
+
+impl CommandHandler for Rehash {
+    fn handle(self, client: &mut Client, ctx: &mut Context<Client>) {
+        if !is_oper(client) {
+            return unknown_command(client);
+        }
+
+        client.server_send_map_write(
+            ctx,
+            messages::ReloadConfig {
+                span: Span::current(),
+            },
+        );
+    }
+}
+
+/// `SANICK`, forcefully changes another user's nick.
+pub struct SaNick {
+    pub old_nick: String,
+    pub new_nick: String,
+}
+
+impl CommandHandler for SaNick {
+    fn handle(self, client: &mut Client, _ctx: &mut Context<Client>) {
+        if !is_oper(client) {
+            return unknown_command(client);
+        }
+
+        client.server.do_send(UserNickChangeInternal {
+            old_nick: self.old_nick,
+            new_nick: self.new_nick,
+            span: Span::current(),
+        });
+    }
+}
+
+/// `SAQUIT`, forcefully disconnects another user.
+pub struct SaQuit {
+    pub user: String,
+    pub comment: String,
+}
+
+impl CommandHandler for SaQuit {
+    fn handle(self, client: &mut Client, ctx: &mut Context<Client>) {
+        if !is_oper(client) {
+            return unknown_command(client);
+        }
+
+        let span = Span::current();
+        client.server_send_map_write(
+            ctx,
+            ForceDisconnect {
+                span,
+                user: self.user,
+                comment: self.comment,
+            },
+        );
+    }
+}
+
+/// `GLINE` with no arguments, lists the network bans.
+pub struct ListGline;
+
+impl CommandHandler for ListGline {
+    fn handle(self, client: &mut Client, ctx: &mut Context<Client>) {
+        if !is_oper(client) {
+            return unknown_command(client);
+        }
+
+        client.server_send_map_write(ctx, messages::ListGline);
+    }
+}
+
+/// `GLINE -<mask>`, lifts a network ban.
+pub struct RemoveGline {
+    pub mask: BanMask,
+}
+
+impl CommandHandler for RemoveGline {
+    fn handle(self, client: &mut Client, ctx: &mut Context<Client>) {
+        if !is_oper(client) {
+            return unknown_command(client);
+        }
+
+        client.server_send_map_write(ctx, messages::RemoveGline { mask: self.mask });
+    }
+}
+
+/// `GLINE <mask>`, bans a hostmask or account from the network.
+pub struct Gline {
+    pub mask: BanMask,
+    pub duration: Option<Duration>,
+    pub reason: Option<String>,
+}
+
+impl CommandHandler for Gline {
+    fn handle(self, client: &mut Client, ctx: &mut Context<Client>) {
+        if !is_oper(client) {
+            return unknown_command(client);
+        }
+
+        client.server_send_map_write(
+            ctx,
+            messages::Gline {
+                requester: client.connection.clone(),
+                mask: self.mask,
+                duration: self.duration,
+                reason: self.reason,
+            },
+        );
+    }
+}
+
+/// `SHUN` with no arguments, lists the active shuns.
+pub struct ListShun;
+
+impl CommandHandler for ListShun {
+    fn handle(self, client: &mut Client, ctx: &mut Context<Client>) {
+        if !is_oper(client) {
+            return unknown_command(client);
+        }
+
+        client.server_send_map_write(ctx, messages::ListShun);
+    }
+}
+
+/// `SHUN -<mask>`, lifts a shun.
+pub struct RemoveShun {
+    pub mask: HostMask<'static>,
+}
+
+impl CommandHandler for RemoveShun {
+    fn handle(self, client: &mut Client, ctx: &mut Context<Client>) {
+        if !is_oper(client) {
+            return unknown_command(client);
+        }
+
+        client.server_send_map_write(ctx, messages::RemoveShun { mask: self.mask });
+    }
+}
+
+/// `SHUN <mask>`, ignores every command from users matching the hostmask.
+pub struct Shun {
+    pub mask: HostMask<'static>,
+    pub duration: Option<Duration>,
+    pub reason: Option<String>,
+}
+
+impl CommandHandler for Shun {
+    fn handle(self, client: &mut Client, ctx: &mut Context<Client>) {
+        if !is_oper(client) {
+            return unknown_command(client);
+        }
+
+        client.server_send_map_write(
+            ctx,
+            messages::Shun {
+                requester: client.connection.clone(),
+                mask: self.mask,
+                duration: self.duration,
+                reason: self.reason,
+            },
+        );
+    }
+}
+
+/// `NICKHISTORY`, lists every nick used by the account owning the given nick.
+pub struct NickHistory {
+    pub query: String,
+}
+
+impl CommandHandler for NickHistory {
+    fn handle(self, client: &mut Client, ctx: &mut Context<Client>) {
+        if !is_oper(client) {
+            return unknown_command(client);
+        }
+
+        let query = self.query;
+        let fut = client
+            .persistence
+            .send(FetchNickHistory {
+                nick: query.clone(),
+            })
+            .into_actor(client)
+            .map(move |entries, this, _ctx| {
+                let history = response::NickHistory {
+                    query,
+                    entries: entries.unwrap(),
+                };
+
+                for message in history.into_messages(&this.connection.nick()) {
+                    this.writer.write(message);
+                }
+            });
+
+        ctx.spawn(fut);
+    }
+}
+
+/// `DAILYSTATS`, lists the statistics recorded for each of the last few days.
+pub struct DailyStats {
+    pub days: Option<usize>,
+}
+
+impl DailyStats {
+    const DEFAULT_DAYS: usize = 7;
+}
+
+impl CommandHandler for DailyStats {
+    fn handle(self, client: &mut Client, ctx: &mut Context<Client>) {
+        if !is_oper(client) {
+            return unknown_command(client);
+        }
+
+        let fut = client
+            .persistence
+            .send(FetchDailyStats {
+                days: self.days.unwrap_or(Self::DEFAULT_DAYS),
+            })
+            .into_actor(client)
+            .map(|entries, this, _ctx| {
+                for message in
+                    response::DailyStats(entries.unwrap()).into_messages(&this.connection.nick())
+                {
+                    this.writer.write(message);
+                }
+            });
+
+        ctx.spawn(fut);
+    }
+}
+
+/// `DEBUG TAP`, starts or stops recording a user's raw traffic to the tap log.
+pub struct DebugTap {
+    pub nick: String,
+    pub enabled: bool,
+}
+
+impl CommandHandler for DebugTap {
+    fn handle(self, client: &mut Client, ctx: &mut Context<Client>) {
+        if !is_oper(client) {
+            return unknown_command(client);
+        }
+
+        client.server_send_map_write(
+            ctx,
+            messages::TapClient {
+                span: Span::current(),
+                nick: self.nick,
+                enabled: self.enabled,
+            },
+        );
+    }
+}
+
+/// `SPY`, starts or stops observing a channel's traffic without joining it.
+pub struct Spy {
+    pub channel: String,
+    pub enabled: bool,
+}
+
+impl CommandHandler for Spy {
+    fn handle(self, client: &mut Client, ctx: &mut Context<Client>) {
+        if !is_oper(client) {
+            return unknown_command(client);
+        }
+
+        client.server_send_map_write(
+            ctx,
+            messages::SpyChannel {
+                span: Span::current(),
+                client: ctx.address(),
+                connection: client.connection.clone(),
+                channel: self.channel,
+                enabled: self.enabled,
+            },
+        );
+    }
+}
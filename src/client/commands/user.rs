@@ -0,0 +1,363 @@
+//! Commands affecting the user's own connection.
+
+use actix::{ActorContext, ActorFutureExt, AsyncContext, Context, WrapFuture};
+use futures::FutureExt;
+use irc_proto::{Command, Response};
+use tokio::time::Instant;
+use tracing::{info, Span};
+
+use crate::{
+    client::{commands::CommandHandler, Client, SetAway, SetUserModes},
+    connection::{sasl::SaslAlreadyAuthenticated, UserMode},
+    messages::{
+        CheckOperCredentials, DisconnectAccount, FetchSessions, LogoutSession, UpdateAcceptList,
+        UserNickChangeInternal,
+    },
+    persistence::events::{
+        AddUserCertificate, FetchLoginHistory, ListUserCertificates, RemoveUserCertificate,
+    },
+    proto::{builder::MessageBuilder, CertCommand, NickServCommand},
+    server::response::{CertificateResponse, IntoProtocol, SessionsResponse},
+    services::{self, NickServResponse},
+};
+
+/// `NICK`, changes the user's nick.
+pub struct Nick {
+    pub new_nick: String,
+}
+
+impl CommandHandler for Nick {
+    fn handle(self, client: &mut Client, ctx: &mut Context<Client>) {
+        ctx.notify(UserNickChangeInternal {
+            old_nick: client.connection.nick(),
+            new_nick: self.new_nick,
+            span: Span::current(),
+        });
+    }
+}
+
+/// `MODE` targeting a user, updates the user's own modes.
+pub struct Mode {
+    pub nick: String,
+    pub modes: Vec<irc_proto::Mode<irc_proto::UserMode>>,
+}
+
+impl CommandHandler for Mode {
+    fn handle(self, _client: &mut Client, ctx: &mut Context<Client>) {
+        ctx.notify(SetUserModes {
+            nick: self.nick,
+            modes: self.modes,
+            span: Span::current(),
+        });
+    }
+}
+
+/// `QUIT`, disconnects the user from the server.
+pub struct Quit {
+    pub message: Option<String>,
+}
+
+impl CommandHandler for Quit {
+    fn handle(self, client: &mut Client, ctx: &mut Context<Client>) {
+        // set the user's leave reason and request a shutdown of the actor to close the
+        // connection
+        client.graceful_shutdown = true;
+        client.server_leave_reason = self.message;
+        ctx.stop();
+    }
+}
+
+/// `AWAY`, sets or clears the user's away message.
+pub struct Away {
+    pub message: Option<String>,
+}
+
+impl CommandHandler for Away {
+    fn handle(self, _client: &mut Client, ctx: &mut Context<Client>) {
+        ctx.notify(SetAway {
+            span: Span::current(),
+            msg: self.message,
+        });
+    }
+}
+
+/// `PING`, sent by the client to check the connection is still alive.
+pub struct Ping {
+    pub token: String,
+}
+
+impl CommandHandler for Ping {
+    fn handle(self, client: &mut Client, _ctx: &mut Context<Client>) {
+        client
+            .writer
+            .write(MessageBuilder::bare().command(Command::PONG(self.token, None)));
+    }
+}
+
+/// `PONG`, the client's response to one of our pings.
+pub struct Pong;
+
+impl CommandHandler for Pong {
+    fn handle(self, client: &mut Client, _ctx: &mut Context<Client>) {
+        // the client received our ping, so the socket can't be broken
+        client.write_errors.record_success();
+    }
+}
+
+/// `AUTHENTICATE`, which is only valid during registration.
+pub struct Authenticate;
+
+impl CommandHandler for Authenticate {
+    fn handle(self, client: &mut Client, _ctx: &mut Context<Client>) {
+        client
+            .writer
+            .write(SaslAlreadyAuthenticated(client.connection.nick()).into_message());
+    }
+}
+
+/// `OPER`, grants the user operator privileges if they give the credentials of one of the
+/// configured operator accounts.
+pub struct Oper {
+    pub name: String,
+    pub password: String,
+}
+
+impl CommandHandler for Oper {
+    fn handle(self, client: &mut Client, ctx: &mut Context<Client>) {
+        let name = self.name.clone();
+        let fut = client.server.send(CheckOperCredentials {
+            name: self.name,
+            password: self.password,
+            span: Span::current(),
+        });
+
+        ctx.spawn(fut.into_actor(client).map(move |result, this, _ctx| {
+            let nick = this.connection.nick();
+
+            if !result.unwrap() {
+                info!(%name, "User gave invalid operator credentials");
+                this.writer.write(MessageBuilder::server().response(
+                    Response::ERR_PASSWDMISMATCH,
+                    vec![nick, "Password incorrect".to_string()],
+                ));
+                return;
+            }
+
+            info!(%name, "User became an operator");
+
+            this.connection
+                .set_mode(this.connection.mode().union(UserMode::OPER));
+            this.oper_session.started = Some(Instant::now());
+            this.oper_session.warned = false;
+
+            this.writer.write(MessageBuilder::server().response(
+                Response::RPL_YOUREOPER,
+                vec![nick.clone(), "You are now an IRC operator".to_string()],
+            ));
+            this.writer
+                .write(MessageBuilder::server().command(Command::UserMODE(
+                    nick,
+                    vec![irc_proto::Mode::Plus(irc_proto::UserMode::Oper, None)],
+                )));
+        }));
+    }
+}
+
+/// `ACCEPT`, updates or lists the user's caller-id accept list.
+pub struct Accept {
+    pub changes: Vec<String>,
+}
+
+impl CommandHandler for Accept {
+    fn handle(self, client: &mut Client, ctx: &mut Context<Client>) {
+        client.server_send_map_write(
+            ctx,
+            UpdateAcceptList {
+                span: Span::current(),
+                client: ctx.address(),
+                changes: self.changes,
+            },
+        );
+    }
+}
+
+/// `CERT`, manages the TLS client certificates the user can authenticate with.
+pub struct Cert {
+    pub command: CertCommand,
+}
+
+impl CommandHandler for Cert {
+    fn handle(self, client: &mut Client, ctx: &mut Context<Client>) {
+        let user_id = client.connection.user_id;
+        let persistence = client.persistence.clone();
+
+        let fut = match self.command {
+            CertCommand::Add(fingerprint) => {
+                let Some(fingerprint) =
+                    fingerprint.or_else(|| client.connection.certificate_fingerprint.clone())
+                else {
+                    for message in
+                        CertificateResponse::NoCertificate.into_messages(&client.connection.nick())
+                    {
+                        client.writer.write(message);
+                    }
+
+                    return;
+                };
+
+                async move {
+                    let added = persistence
+                        .send(AddUserCertificate {
+                            user_id,
+                            fingerprint: fingerprint.clone(),
+                        })
+                        .await
+                        .unwrap();
+
+                    if added {
+                        CertificateResponse::Added(fingerprint)
+                    } else {
+                        CertificateResponse::AlreadyInUse(fingerprint)
+                    }
+                }
+                .boxed_local()
+            }
+            CertCommand::Del(fingerprint) => async move {
+                let removed = persistence
+                    .send(RemoveUserCertificate {
+                        user_id,
+                        fingerprint: fingerprint.clone(),
+                    })
+                    .await
+                    .unwrap();
+
+                if removed {
+                    CertificateResponse::Removed(fingerprint)
+                } else {
+                    CertificateResponse::NotFound(fingerprint)
+                }
+            }
+            .boxed_local(),
+            CertCommand::List => async move {
+                CertificateResponse::List(
+                    persistence
+                        .send(ListUserCertificates { user_id })
+                        .await
+                        .unwrap(),
+                )
+            }
+            .boxed_local(),
+        };
+
+        ctx.spawn(fut.into_actor(client).map(|response, this, _ctx| {
+            for message in response.into_messages(&this.connection.nick()) {
+                this.writer.write(message);
+            }
+        }));
+    }
+}
+
+/// `NS`, manages the user's account and the sessions logged into it.
+pub struct NickServ {
+    pub command: NickServCommand,
+}
+
+impl CommandHandler for NickServ {
+    fn handle(self, client: &mut Client, ctx: &mut Context<Client>) {
+        let user_id = client.connection.user_id;
+        let account = client.connection.user.clone();
+        let nick = client.connection.nick();
+        let server = client.server.clone();
+        let persistence = client.persistence.clone();
+        let database = client.database.clone();
+        let span = Span::current();
+
+        let fut = match self.command {
+            NickServCommand::Register(password) => {
+                let connection = client.connection.clone();
+
+                async move {
+                    let response =
+                        services::register(&database, &account, user_id, &password).await;
+
+                    if matches!(response, NickServResponse::Registered(_)) {
+                        connection.set_registered(true);
+                    }
+
+                    response.into_messages(&nick)
+                }
+                .boxed_local()
+            }
+            // users are always logged in by the time they've registered
+            NickServCommand::Identify(..) => {
+                for message in NickServResponse::AlreadyIdentified(account).into_messages(&nick) {
+                    client.writer.write(message);
+                }
+
+                return;
+            }
+            NickServCommand::SetPassword(password) => async move {
+                services::set_password(&database, user_id, &password)
+                    .await
+                    .into_messages(&nick)
+            }
+            .boxed_local(),
+            NickServCommand::Drop(password) => async move {
+                let response =
+                    services::drop_account(&database, &account, user_id, &password).await;
+
+                if matches!(response, NickServResponse::Dropped(_)) {
+                    server.do_send(DisconnectAccount {
+                        span,
+                        user_id,
+                        reason: "Account dropped".to_string(),
+                    });
+                }
+
+                response.into_messages(&nick)
+            }
+            .boxed_local(),
+            NickServCommand::Sessions => {
+                let current = client.connection.session_id.clone();
+
+                async move {
+                    let sessions = server.send(FetchSessions { span, user_id });
+                    let history = persistence.send(FetchLoginHistory { user_id });
+
+                    SessionsResponse::List {
+                        current,
+                        sessions: sessions.await.unwrap(),
+                        history: history.await.unwrap(),
+                    }
+                    .into_messages(&nick)
+                }
+                .boxed_local()
+            }
+            NickServCommand::Logout(session_id) => async move {
+                let logged_out = server
+                    .send(LogoutSession {
+                        span,
+                        user_id,
+                        session_id: session_id.clone(),
+                    })
+                    .await
+                    .unwrap();
+
+                let response = if logged_out {
+                    SessionsResponse::LoggedOut(session_id)
+                } else {
+                    SessionsResponse::NotFound(session_id)
+                };
+
+                response.into_messages(&nick)
+            }
+            .boxed_local(),
+        };
+
+        ctx.spawn(fut.into_actor(client).map(|messages, this, _ctx| {
+            for message in messages {
+                this.writer.write(message);
+            }
+        }));
+    }
+}
@@ -0,0 +1,237 @@
+//! Throttles the commands a client can send, as configured by their connection class.
+
+use std::{collections::VecDeque, time::Duration};
+
+use irc_proto::{Command, Message};
+use tokio::time::Instant;
+
+use crate::config::ConnectionClass;
+
+/// A token bucket refilling at `flood-rate` tokens a second up to `flood-burst` tokens, each
+/// command received from the client takes a token. Commands received whilst the bucket is empty
+/// are queued up until tokens become available again.
+#[derive(Debug)]
+pub struct FloodLimiter {
+    rate: u32,
+    burst: u32,
+    tokens: f64,
+    last_refill: Instant,
+    queue: VecDeque<Message>,
+}
+
+/// What to do with a command that was just received from the client.
+#[derive(Debug, PartialEq)]
+pub enum FloodDecision {
+    /// The client is within its limits, the command should be handled straight away.
+    Allow(Message),
+    /// The command was queued up to be handled once the client has tokens again.
+    Queued,
+    /// The client has sent more commands than we're willing to queue up.
+    Excess,
+}
+
+impl FloodLimiter {
+    /// Builds the limiter for a client in `class`, returns `None` if the class doesn't limit
+    /// clients. The burst defaults to the rate if it isn't set.
+    #[must_use]
+    pub fn for_class(class: &ConnectionClass) -> Option<Self> {
+        let rate = class.flood_rate.filter(|v| *v > 0)?;
+        let burst = class.flood_burst.unwrap_or(rate).max(1);
+
+        Some(Self {
+            rate,
+            burst,
+            tokens: f64::from(burst),
+            last_refill: Instant::now(),
+            queue: VecDeque::new(),
+        })
+    }
+
+    /// Whether `message` skips the limiter and is handled straight away. Keepalives are never
+    /// throttled so a flooding client can't time itself out, and `CPRIVMSG`/`CNOTICE` let users
+    /// with voice or op in a channel reach its members whilst throttled. The limiter is charged
+    /// for these after the fact if the sender turns out not to hold the privilege, see
+    /// [`Self::charge`].
+    #[must_use]
+    pub fn is_exempt(message: &Message) -> bool {
+        match &message.command {
+            Command::PING(..) | Command::PONG(..) => true,
+            Command::Raw(command, _) => command == "CPRIVMSG" || command == "CNOTICE",
+            _ => false,
+        }
+    }
+
+    /// How often the queue should be drained, this is the time it takes to gain a single token.
+    #[must_use]
+    pub fn drain_interval(&self) -> Duration {
+        Duration::from_secs(1) / self.rate
+    }
+
+    /// Takes a token for `message`, queueing it if there aren't any left. Once as many commands
+    /// as the burst allows are queued up, the client is considered to be flooding.
+    pub fn receive(&mut self, message: Message, now: Instant) -> FloodDecision {
+        self.refill(now);
+
+        if self.queue.is_empty() && self.take_token() {
+            return FloodDecision::Allow(message);
+        }
+
+        if self.queue.len() >= usize::try_from(self.burst).unwrap_or(usize::MAX) {
+            return FloodDecision::Excess;
+        }
+
+        self.queue.push_back(message);
+        FloodDecision::Queued
+    }
+
+    /// Takes a token for a command that was let past [`Self::receive`], such as a `CPRIVMSG`
+    /// from a user who turned out not to have voice or op in the channel. The client can go into
+    /// debt by as many tokens as the burst allows, delaying their next commands, returns `false`
+    /// once they've gone beyond that and are considered to be flooding.
+    pub fn charge(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens -= 1.0;
+        self.tokens > -f64::from(self.burst)
+    }
+
+    /// Pops the next queued command if the client has gained a token since it was queued.
+    pub fn next_queued(&mut self, now: Instant) -> Option<Message> {
+        self.refill(now);
+
+        if self.queue.is_empty() || !self.take_token() {
+            return None;
+        }
+
+        self.queue.pop_front()
+    }
+
+    fn take_token(&mut self) -> bool {
+        if self.tokens < 1.0 {
+            return false;
+        }
+
+        self.tokens -= 1.0;
+        true
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.last_refill = now;
+
+        self.tokens = elapsed
+            .as_secs_f64()
+            .mul_add(f64::from(self.rate), self.tokens)
+            .min(f64::from(self.burst));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use irc_proto::{Command, Message};
+    use tokio::time::Instant;
+
+    use super::{FloodDecision, FloodLimiter};
+    use crate::config::ConnectionClass;
+
+    fn message(n: usize) -> Message {
+        Message::from(Command::PRIVMSG("#chan".to_string(), n.to_string()))
+    }
+
+    #[test]
+    fn unlimited_without_rate() {
+        assert!(FloodLimiter::for_class(&ConnectionClass::default()).is_none());
+    }
+
+    #[test]
+    fn queues_then_floods() {
+        let class = ConnectionClass {
+            flood_rate: Some(1),
+            flood_burst: Some(2),
+            ..ConnectionClass::default()
+        };
+        let mut limiter = FloodLimiter::for_class(&class).unwrap();
+        let now = Instant::now();
+
+        assert_eq!(
+            limiter.receive(message(0), now),
+            FloodDecision::Allow(message(0))
+        );
+        assert_eq!(
+            limiter.receive(message(1), now),
+            FloodDecision::Allow(message(1))
+        );
+        assert_eq!(limiter.receive(message(2), now), FloodDecision::Queued);
+        assert_eq!(limiter.receive(message(3), now), FloodDecision::Queued);
+        assert_eq!(limiter.next_queued(now), None);
+
+        let later = now + Duration::from_secs(1);
+        assert_eq!(limiter.next_queued(later), Some(message(2)));
+        assert_eq!(limiter.next_queued(later), None);
+
+        assert_eq!(limiter.receive(message(4), later), FloodDecision::Queued);
+        assert_eq!(limiter.receive(message(5), later), FloodDecision::Excess);
+    }
+
+    #[test]
+    fn throttled_operators_can_direct_message() {
+        let class = ConnectionClass {
+            flood_rate: Some(1),
+            flood_burst: Some(1),
+            ..ConnectionClass::default()
+        };
+        let mut limiter = FloodLimiter::for_class(&class).unwrap();
+        let now = Instant::now();
+
+        assert_eq!(
+            limiter.receive(message(0), now),
+            FloodDecision::Allow(message(0))
+        );
+        assert_eq!(limiter.receive(message(1), now), FloodDecision::Queued);
+
+        // whilst throttled, CPRIVMSG/CNOTICE still get through to be handled straight away
+        let direct_message = |command: &str| {
+            Message::from(Command::Raw(
+                command.to_string(),
+                vec!["nick".to_string(), "#chan".to_string(), "hi".to_string()],
+            ))
+        };
+        assert!(FloodLimiter::is_exempt(&direct_message("CPRIVMSG")));
+        assert!(FloodLimiter::is_exempt(&direct_message("CNOTICE")));
+        assert!(FloodLimiter::is_exempt(&Message::from(Command::PING(
+            "token".to_string(),
+            None
+        ))));
+        assert!(!FloodLimiter::is_exempt(&message(2)));
+        assert!(!FloodLimiter::is_exempt(&direct_message("PRIVMSG")));
+    }
+
+    #[test]
+    fn charges_go_into_debt_then_flood() {
+        let class = ConnectionClass {
+            flood_rate: Some(1),
+            flood_burst: Some(2),
+            ..ConnectionClass::default()
+        };
+        let mut limiter = FloodLimiter::for_class(&class).unwrap();
+        let now = Instant::now();
+
+        assert!(limiter.charge(now));
+        assert!(limiter.charge(now));
+        assert!(limiter.charge(now));
+        assert_eq!(limiter.receive(message(0), now), FloodDecision::Queued);
+
+        // the debt has to be paid off before anything queued is handled
+        let later = now + Duration::from_secs(1);
+        assert_eq!(limiter.next_queued(later), None);
+        assert_eq!(
+            limiter.next_queued(later + Duration::from_secs(1)),
+            Some(message(0))
+        );
+
+        let later = later + Duration::from_secs(1);
+        assert!(limiter.charge(later));
+        assert!(!limiter.charge(later));
+    }
+}
@@ -0,0 +1,258 @@
+//! Records the raw traffic of individual connections to a separate log, for diagnosing
+//! interoperability issues with clients. Connections are tapped either by an operator using
+//! `DEBUG TAP <nick>`, or upon registration if they match one of the configured masks.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use chrono::{SecondsFormat, Utc};
+use irc_proto::{Command, Message};
+use tracing::error;
+
+use crate::{
+    config::TapConfig,
+    host_mask::{HostMask, HostMaskMap},
+};
+
+/// Replaces anything sensitive in a tapped line.
+const REDACTED: &str = "<redacted>";
+
+/// The log shared by every tapped connection.
+pub struct TapLog {
+    file: Mutex<RotatingFile>,
+    masks: HostMaskMap<()>,
+}
+
+impl TapLog {
+    pub fn open(config: &TapConfig) -> std::io::Result<Self> {
+        let masks = config
+            .masks
+            .iter()
+            .filter_map(|mask| HostMask::try_from(mask.as_str()).ok())
+            .map(|mask| (mask, ()))
+            .collect();
+
+        Ok(Self {
+            file: Mutex::new(RotatingFile::open(
+                config.path.clone(),
+                config.max_size,
+                config.max_files,
+            )?),
+            masks,
+        })
+    }
+
+    /// Whether a connection should be tapped as soon as it registers.
+    #[must_use]
+    pub fn matches(&self, mask: &HostMask<'_>) -> bool {
+        !self.masks.get(mask).is_empty()
+    }
+
+    fn write(&self, line: &str) {
+        let mut file = self.file.lock().unwrap();
+
+        if let Err(error) = file.write_line(line) {
+            error!(%error, "Failed to write to tap log");
+        }
+    }
+}
+
+/// A tap on a single connection.
+#[derive(Clone)]
+pub struct Tap {
+    log: Arc<TapLog>,
+    host: SocketAddr,
+}
+
+impl Tap {
+    /// Starts tapping the connection from `host`, noting who it belongs to in the log.
+    #[must_use]
+    pub fn start(log: Arc<TapLog>, host: SocketAddr, mask: &HostMask<'_>) -> Self {
+        let tap = Self { log, host };
+        tap.write("--", &format!("tap started for {mask}"));
+        tap
+    }
+
+    pub fn stop(&self) {
+        self.write("--", "tap stopped");
+    }
+
+    /// Records a message received from the client.
+    pub fn inbound(&self, message: &Message) {
+        self.write(">>", &format_line(message));
+    }
+
+    /// Records a message written to the client.
+    pub fn outbound(&self, message: &Message) {
+        self.write("<<", &format_line(message));
+    }
+
+    fn write(&self, direction: &str, line: &str) {
+        let time = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+        self.log
+            .write(&format!("{time} {} {direction} {line}\n", self.host));
+    }
+}
+
+/// Serialises the message as it'd appear on the wire, without the trailing CRLF and with any
+/// credentials removed.
+fn format_line(message: &Message) -> String {
+    let redacted = match &message.command {
+        Command::AUTHENTICATE(_) => Some(Command::AUTHENTICATE(REDACTED.to_string())),
+        Command::OPER(name, _) => Some(Command::OPER(name.clone(), REDACTED.to_string())),
+        Command::PASS(_) => Some(Command::PASS(REDACTED.to_string())),
+        Command::NICKSERV(args) => Some(Command::NICKSERV(redact_nickserv(args))),
+        Command::Raw(command, args) if command == "NS" => {
+            Some(Command::Raw(command.clone(), redact_nickserv(args)))
+        }
+        _ => None,
+    };
+
+    let line = match redacted {
+        Some(command) => Message {
+            tags: message.tags.clone(),
+            prefix: message.prefix.clone(),
+            command,
+        }
+        .to_string(),
+        None => message.to_string(),
+    };
+
+    line.trim_end_matches(['\r', '\n']).to_string()
+}
+
+/// Keeps the subcommand of an `NS` command, dropping its arguments since most of them carry a
+/// password.
+fn redact_nickserv(args: &[String]) -> Vec<String> {
+    args.iter()
+        .take(1)
+        .cloned()
+        .chain((args.len() > 1).then(|| REDACTED.to_string()))
+        .collect()
+}
+
+/// A file that's moved aside once it reaches `max_size`, keeping up to `max_files` of the
+/// previous files as `<path>.1` (the most recent) through `<path>.<max_files>`.
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    written: u64,
+    max_size: u64,
+    max_files: usize,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_size: u64, max_files: usize) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let written = file.metadata()?.len();
+
+        Ok(Self {
+            path,
+            file,
+            written,
+            max_size,
+            max_files,
+        })
+    }
+
+    fn write_line(&mut self, line: &str) -> std::io::Result<()> {
+        if self.written > 0 && self.written + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+
+        self.file.write_all(line.as_bytes())?;
+        self.written += line.len() as u64;
+
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        if self.max_files > 0 {
+            for i in (1..self.max_files).rev() {
+                rename_if_exists(
+                    &rotated_path(&self.path, i),
+                    &rotated_path(&self.path, i + 1),
+                )?;
+            }
+
+            rename_if_exists(&self.path, &rotated_path(&self.path, 1))?;
+        }
+
+        self.file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.path)?;
+        self.written = 0;
+
+        Ok(())
+    }
+}
+
+fn rotated_path(path: &Path, i: usize) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(format!(".{i}"));
+    path.into()
+}
+
+fn rename_if_exists(from: &Path, to: &Path) -> std::io::Result<()> {
+    match std::fs::rename(from, to) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        v => v,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use irc_proto::{Command, Message};
+
+    use super::{format_line, RotatingFile};
+
+    #[test]
+    fn redacts_credentials() {
+        let message = Message::from(Command::AUTHENTICATE(
+            "aGVsbG8AaGVsbG8Ad29ybGQ=".to_string(),
+        ));
+        let line = format_line(&message);
+        assert!(line.starts_with("AUTHENTICATE"), "{line}");
+        assert!(line.ends_with("<redacted>"), "{line}");
+
+        let message = Message::from(Command::OPER("admin".to_string(), "hunter2".to_string()));
+        let line = format_line(&message);
+        assert!(line.contains("admin"), "{line}");
+        assert!(!line.contains("hunter2"), "{line}");
+
+        let message: Message = "NS IDENTIFY alice hunter2".parse().unwrap();
+        let line = format_line(&message);
+        assert!(line.starts_with("NS IDENTIFY"), "{line}");
+        assert!(!line.contains("hunter2"), "{line}");
+
+        let message = Message::from(Command::PRIVMSG("#chan".to_string(), "hi".to_string()));
+        assert_eq!(format_line(&message), "PRIVMSG #chan :hi");
+    }
+
+    #[test]
+    fn rotates_once_full() {
+        let dir = std::env::temp_dir().join(format!("titanircd-tap-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("tap.log");
+
+        let mut file = RotatingFile::open(path, 10, 2).unwrap();
+        for line in ["aaaaaaaa\n", "bbbbbbbb\n", "cccccccc\n", "dddddddd\n"] {
+            file.write_line(line).unwrap();
+        }
+
+        let read = |p: &str| std::fs::read_to_string(dir.join(p)).unwrap();
+        assert_eq!(read("tap.log"), "dddddddd\n");
+        assert_eq!(read("tap.log.1"), "cccccccc\n");
+        assert_eq!(read("tap.log.2"), "bbbbbbbb\n");
+        assert!(!dir.join("tap.log.3").exists());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,242 @@
+//! Counters for the messages and bytes flowing between the server and its clients.
+
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+use irc_proto::Message;
+use tokio::io::AsyncWrite;
+
+use crate::{client::tap::Tap, connection::MessageSink};
+
+/// Traffic totals across every client that has connected since the server started.
+pub static TOTAL_TRAFFIC: Traffic = Traffic::new();
+
+/// Counts the messages and bytes sent to, and received from, a single client.
+#[derive(Debug, Default)]
+pub struct Traffic {
+    messages_sent: AtomicU64,
+    bytes_sent: AtomicU64,
+    messages_received: AtomicU64,
+    bytes_received: AtomicU64,
+}
+
+impl Traffic {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            messages_sent: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+            messages_received: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+        }
+    }
+
+    /// Records a message being written to the client, also adding it to the server's totals.
+    pub fn record_sent(&self, message: &Message) {
+        let bytes = wire_length(message);
+
+        for traffic in [self, &TOTAL_TRAFFIC] {
+            traffic.messages_sent.fetch_add(1, Ordering::Relaxed);
+            traffic.bytes_sent.fetch_add(bytes, Ordering::Relaxed);
+        }
+    }
+
+    /// Records a message being received from the client, also adding it to the server's totals.
+    pub fn record_received(&self, message: &Message) {
+        let bytes = wire_length(message);
+
+        for traffic in [self, &TOTAL_TRAFFIC] {
+            traffic.messages_received.fetch_add(1, Ordering::Relaxed);
+            traffic.bytes_received.fetch_add(bytes, Ordering::Relaxed);
+        }
+    }
+
+    #[must_use]
+    pub fn snapshot(&self) -> TrafficSnapshot {
+        TrafficSnapshot {
+            messages_sent: self.messages_sent.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            messages_received: self.messages_received.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A point-in-time copy of a [`Traffic`]'s counters.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct TrafficSnapshot {
+    pub messages_sent: u64,
+    pub bytes_sent: u64,
+    pub messages_received: u64,
+    pub bytes_received: u64,
+}
+
+/// The client's outgoing message sink, counting every message written through it and
+/// recording it to the connection's tap, if it has one.
+pub struct CountingSink {
+    inner: MessageSink,
+    traffic: Arc<Traffic>,
+    tap: Option<Tap>,
+}
+
+impl CountingSink {
+    #[must_use]
+    pub fn new(inner: MessageSink, traffic: Arc<Traffic>) -> Self {
+        Self {
+            inner,
+            traffic,
+            tap: None,
+        }
+    }
+
+    pub fn set_tap(&mut self, tap: Option<Tap>) {
+        self.tap = tap;
+    }
+
+    pub fn write(&mut self, message: Message) {
+        self.traffic.record_sent(&message);
+
+        if let Some(tap) = &self.tap {
+            tap.outbound(&message);
+        }
+
+        self.inner.write(message);
+    }
+}
+
+/// The amount of writes to a client that have failed in a row, shared between the `Client`,
+/// which is told about failures by actix, and the [`SocketWriter`] under it, which resets the
+/// count whenever a write to the socket succeeds.
+#[derive(Debug, Default, Clone)]
+pub struct WriteErrors(Arc<AtomicUsize>);
+
+impl WriteErrors {
+    /// Maximum amount of writes that can fail in a row before the client is disconnected.
+    pub const MAX_CONSECUTIVE: usize = 5;
+
+    /// Records a failed write, returning `true` once enough writes have failed in a row for the
+    /// connection to be considered broken.
+    pub fn record_failure(&self) -> bool {
+        self.0.fetch_add(1, Ordering::Relaxed) + 1 >= Self::MAX_CONSECUTIVE
+    }
+
+    pub fn record_success(&self) {
+        self.0.store(0, Ordering::Relaxed);
+    }
+
+    #[must_use]
+    pub fn count(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Wraps the client's socket, resetting the client's [`WriteErrors`] whenever a write to it
+/// succeeds.
+pub struct SocketWriter<W> {
+    inner: W,
+    errors: WriteErrors,
+}
+
+impl<W> SocketWriter<W> {
+    #[must_use]
+    pub const fn new(inner: W, errors: WriteErrors) -> Self {
+        Self { inner, errors }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for SocketWriter<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.inner).poll_write(cx, buf);
+
+        if matches!(result, Poll::Ready(Ok(written)) if written > 0) {
+            this.errors.record_success();
+        }
+
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// The length of the message once it's been serialised to be written to the wire, including the
+/// trailing CRLF.
+fn wire_length(message: &Message) -> u64 {
+    message.to_string().len() as u64
+}
+
+#[cfg(test)]
+mod test {
+    use irc_proto::{Command, Message};
+    use tokio::io::AsyncWriteExt;
+
+    use super::{SocketWriter, Traffic, TrafficSnapshot, WriteErrors};
+
+    #[test]
+    fn counts_messages_and_bytes() {
+        let traffic = Traffic::new();
+        let message = Message::from(Command::PING("hello".to_string(), None));
+        let length = message.to_string().len() as u64;
+
+        traffic.record_sent(&message);
+        traffic.record_sent(&message);
+        traffic.record_received(&message);
+
+        assert_eq!(
+            traffic.snapshot(),
+            TrafficSnapshot {
+                messages_sent: 2,
+                bytes_sent: length * 2,
+                messages_received: 1,
+                bytes_received: length,
+            }
+        );
+    }
+
+    #[test]
+    fn disconnects_after_consecutive_write_errors() {
+        let errors = WriteErrors::default();
+
+        for _ in 1..WriteErrors::MAX_CONSECUTIVE {
+            assert!(!errors.record_failure());
+        }
+
+        // a successful write in between means the failures aren't consecutive
+        errors.record_success();
+
+        for _ in 1..WriteErrors::MAX_CONSECUTIVE {
+            assert!(!errors.record_failure());
+        }
+
+        assert!(errors.record_failure());
+    }
+
+    #[actix_rt::test]
+    async fn successful_writes_reset_errors() {
+        let errors = WriteErrors::default();
+        let mut writer = SocketWriter::new(tokio::io::sink(), errors.clone());
+
+        assert!(!errors.record_failure());
+        assert!(!errors.record_failure());
+        assert_eq!(errors.count(), 2);
+
+        writer.write_all(b"PING :hello\r\n").await.unwrap();
+        assert_eq!(errors.count(), 0);
+    }
+}
@@ -0,0 +1,62 @@
+//! Counts how many times each command has been used since the server started, for `STATS m`.
+
+use std::{collections::BTreeMap, sync::Mutex};
+
+/// Command usage across every client that has connected since the server started.
+pub static COMMAND_USAGE: CommandUsage = CommandUsage::new();
+
+/// The amount of times each command has been used, keyed by the command's upper-cased name.
+#[derive(Debug, Default)]
+pub struct CommandUsage {
+    counts: Mutex<BTreeMap<String, u64>>,
+}
+
+impl CommandUsage {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            counts: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Records a use of the command. Only commands we recognise should be recorded, so clients
+    /// can't grow the counts without bound by sending made up commands.
+    pub fn record(&self, command: &str) {
+        *self
+            .counts
+            .lock()
+            .unwrap()
+            .entry(command.to_ascii_uppercase())
+            .or_default() += 1;
+    }
+
+    /// Returns the count of every command that's been used, ordered by the command's name.
+    #[must_use]
+    pub fn snapshot(&self) -> Vec<(String, u64)> {
+        self.counts
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(command, count)| (command.clone(), *count))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::CommandUsage;
+
+    #[test]
+    fn counts_commands() {
+        let usage = CommandUsage::new();
+
+        usage.record("PRIVMSG");
+        usage.record("privmsg");
+        usage.record("JOIN");
+
+        assert_eq!(
+            usage.snapshot(),
+            vec![("JOIN".to_string(), 1), ("PRIVMSG".to_string(), 2)]
+        );
+    }
+}
@@ -1,64 +1,1356 @@
-use std::{net::SocketAddr, str::FromStr, time::Duration};
+use std::{
+    collections::HashSet,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
 
 use clap::Parser;
-use serde::Deserialize;
+use irc_proto::ChannelExt;
+use serde::{Deserialize, Deserializer};
+use thiserror::Error;
+
+use crate::{
+    casemapping,
+    conformance::ConformanceArgs,
+    host_mask::{HostMask, HostMaskMap},
+};
 
 #[derive(Parser)]
 #[clap(version = clap::crate_version!(), author = clap::crate_authors!())]
+#[clap(subcommand_negates_reqs = true)]
 pub struct Args {
     /// Turn debugging information on
     #[clap(short, long, action = clap::ArgAction::Count)]
     pub verbose: u8,
-    #[clap(short, long)]
-    pub config: Config,
+    /// Path to the server's configuration file, required unless running a subcommand
+    #[clap(short, long, required = true)]
+    pub config: Option<PathBuf>,
+    #[clap(subcommand)]
+    pub command: Option<Subcommand>,
+}
+
+#[derive(clap::Subcommand)]
+pub enum Subcommand {
+    /// Connect to a running server and check its conformance to the IRC specs
+    Conformance(ConformanceArgs),
 }
 
 #[derive(Deserialize, Debug, Clone)]
-#[serde(rename_all = "kebab-case")]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
 pub struct Config {
-    pub listen_address: SocketAddr,
-    pub database_uri: String,
+    /// The name of the network this server is a part of, used in the MOTD and welcome messages.
+    /// Defaults to `titanircd`.
+    #[serde(default = "Config::default_network_name")]
+    pub network_name: String,
+    /// The message of the day to send to clients upon connection. Supports the placeholders
+    /// `{server_name}`, `{network}`, `{clients}`, `{uptime}` and `{version}`, which are
+    /// expanded whenever the MOTD is sent. Connection classes may override this.
     pub motd: Option<String>,
-    /// Maximum amount of messages to replay upon rejoin to a channel, if set to 0 an unlimited
+    /// Whether to tell connected clients when their MOTD changes upon reloading the config.
+    /// Clients that negotiated `draft/motd-changed` are sent the new MOTD, everyone else is
+    /// sent a notice asking them to run `MOTD`.
+    #[serde(default)]
+    pub notify_motd_change: bool,
+    pub database: DatabaseConfig,
+    /// Addresses to accept connections on, at least one is required.
+    pub listeners: Vec<ListenerConfig>,
+    /// The certificate and key to present to clients connecting to a TLS listener.
+    pub tls: Option<TlsConfig>,
+    #[serde(default)]
+    pub threads: ThreadsConfig,
+    #[serde(default)]
+    pub nicks: NickConfig,
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    #[serde(default)]
+    pub channels: ChannelConfig,
+    /// Commands that are turned off on this server.
+    #[serde(default)]
+    pub commands: CommandsConfig,
+    /// Connection classes that connecting clients are sorted into, the first class with a
+    /// matching CIDR and host mask is picked. Clients that don't match any class are placed into
+    /// the default class.
+    #[serde(default)]
+    pub classes: Vec<ConnectionClass>,
+    /// Accounts that users can use to become an operator.
+    #[serde(default)]
+    pub opers: Vec<OperConfig>,
+    /// Limits on how long users can hold operator privileges for before having to re-OPER.
+    #[serde(default)]
+    pub oper_session: OperSessionConfig,
+    /// The message shown to users affected by a network ban.
+    #[serde(default)]
+    pub bans: BanConfig,
+    /// Where to record the raw traffic of tapped connections, tapping is unavailable if unset.
+    pub tap: Option<TapConfig>,
+    /// An HTTP endpoint users can upload files to and share links to, disabled if unset.
+    pub filehost: Option<FilehostConfig>,
+    /// An external services package allowed to link to us, disabled if unset.
+    pub services: Option<ServicesConfig>,
+    /// Relaxes behaviour that intentionally differs from other servers, for running external
+    /// test suites against us.
+    #[serde(default)]
+    pub compat: CompatConfig,
+}
+
+impl Config {
+    /// Returns the MOTD template for clients in the named connection class, falling back to the
+    /// server's MOTD if the class doesn't override it.
+    #[must_use]
+    pub fn motd_for_class(&self, class: &str) -> Option<&str> {
+        self.classes
+            .iter()
+            .find(|v| v.name == class)
+            .and_then(|v| v.motd.as_deref())
+            .or(self.motd.as_deref())
+    }
+
+    /// Reads and validates the config file at `path`.
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        std::fs::read_to_string(path)?.parse()
+    }
+
+    /// Checks the parts of the config that can't be expressed through its types alone, such as
+    /// references between sections.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.listeners.is_empty() {
+            return Err(ConfigError::Invalid(
+                "at least one listener must be configured".to_string(),
+            ));
+        }
+
+        let mut classes = HashSet::new();
+        for class in &self.classes {
+            if !classes.insert(class.name.as_str()) {
+                return Err(ConfigError::Invalid(format!(
+                    "connection class {} is defined more than once",
+                    class.name
+                )));
+            }
+
+            for mask in &class.hostmasks {
+                if let Err(e) = HostMask::try_from(mask.as_str()) {
+                    return Err(ConfigError::Invalid(format!(
+                        "invalid host mask {mask} for connection class {}: {e}",
+                        class.name
+                    )));
+                }
+            }
+        }
+
+        for listener in &self.listeners {
+            if listener.tls && self.tls.is_none() {
+                return Err(ConfigError::Invalid(format!(
+                    "listener {} requires tls to be configured",
+                    listener.address
+                )));
+            }
+
+            if let Some(class) = &listener.class {
+                if !classes.contains(class.as_str()) {
+                    return Err(ConfigError::Invalid(format!(
+                        "listener {} refers to unknown connection class {class}",
+                        listener.address
+                    )));
+                }
+            }
+        }
+
+        if let Some(prefix) = &self.channels.temporary_prefix {
+            if !prefix.is_channel_name() {
+                return Err(ConfigError::Invalid(format!(
+                    "temporary channel prefix {prefix} isn't a valid channel name"
+                )));
+            }
+        }
+
+        let mut namespaces = HashSet::new();
+        for namespace in &self.channels.namespaces {
+            if !namespace.prefix.is_channel_name() {
+                return Err(ConfigError::Invalid(format!(
+                    "channel namespace {} isn't a valid channel name prefix",
+                    namespace.prefix
+                )));
+            }
+
+            if !namespaces.insert(casemapping::fold(&namespace.prefix)) {
+                return Err(ConfigError::Invalid(format!(
+                    "channel namespace {} is defined more than once",
+                    namespace.prefix
+                )));
+            }
+        }
+
+        let mut opers = HashSet::new();
+        for oper in &self.opers {
+            if !opers.insert(oper.name.as_str()) {
+                return Err(ConfigError::Invalid(format!(
+                    "oper {} is defined more than once",
+                    oper.name
+                )));
+            }
+
+            if let Err(e) = argon2::PasswordHash::new(&oper.password) {
+                return Err(ConfigError::Invalid(format!(
+                    "password for oper {} isn't a valid argon2 hash: {e}",
+                    oper.name
+                )));
+            }
+        }
+
+        for command in &self.commands.disabled {
+            if CommandsConfig::REQUIRED.contains(&command.to_ascii_uppercase().as_str()) {
+                return Err(ConfigError::Invalid(format!(
+                    "command {command} is required by clients and can't be disabled"
+                )));
+            }
+        }
+
+        if let Some(services) = &self.services {
+            if !ServicesConfig::is_valid_sid(&services.sid) {
+                return Err(ConfigError::Invalid(format!(
+                    "services sid {} must be a digit followed by two letters or digits",
+                    services.sid
+                )));
+            }
+        }
+
+        if self.compat != CompatConfig::default() && !cfg!(feature = "irctest") {
+            return Err(ConfigError::Invalid(
+                "compat options require titanircd to be built with the irctest feature".to_string(),
+            ));
+        }
+
+        if let Some(tap) = &self.tap {
+            for mask in &tap.masks {
+                if let Err(e) = HostMask::try_from(mask.as_str()) {
+                    return Err(ConfigError::Invalid(format!(
+                        "invalid tap mask {mask}: {e}"
+                    )));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Finds the connection class that a client connecting from `ip` belongs to. Until the
+    /// client has registered and `mask` is known, classes matching on host masks are passed
+    /// over.
+    #[must_use]
+    pub fn find_class(
+        classes: &[Arc<ConnectionClass>],
+        ip: IpAddr,
+        mask: Option<&HostMask<'_>>,
+    ) -> Arc<ConnectionClass> {
+        classes
+            .iter()
+            .find(|class| class.matches(ip, mask))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    #[must_use]
+    fn default_network_name() -> String {
+        "titanircd".to_string()
+    }
+}
+
+impl FromStr for Config {
+    type Err = ConfigError;
+
+    fn from_str(contents: &str) -> Result<Self, Self::Err> {
+        let config: Self = toml::from_str(contents)?;
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("failed to read config: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("failed to parse config: {0}")]
+    Parse(#[from] toml::de::Error),
+    #[error("invalid config: {0}")]
+    Invalid(String),
+}
+
+/// Where the server's state is kept, and how much of it is replayed to users.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub uri: String,
+    /// Maximum age of messages to replay upon rejoin to a channel, if set to 0 an unlimited
     /// amount of messages will be retained. Defaults to 1 day.
     #[serde(
-        default = "Config::default_max_message_replay_since",
+        default = "DatabaseConfig::default_max_message_replay_since",
         with = "serde_humantime"
     )]
     pub max_message_replay_since: Duration,
-    /// Amount of threads to spawn for processing client commands, set to 0 to spawn clients on the
-    /// main server thread. Defaults to 1 thread.
-    #[serde(default = "Config::default_client_threads")]
-    pub client_threads: usize,
-    /// Amount of threads to spawn for processing channel commands, set to 0 to spawn channels on
-    /// the main server thread. Defaults to 1 thread.
-    #[serde(default = "Config::default_channel_threads")]
-    pub channel_threads: usize,
+    /// Maximum amount of messages to replay upon rejoin to a channel, older messages are omitted
+    /// and the user is told how many were skipped. Defaults to 500 messages.
+    #[serde(default = "DatabaseConfig::default_max_message_replay_count")]
+    pub max_message_replay_count: u32,
+    /// How long nicks are remembered for `WHOWAS` after a user stops using them. Defaults to
+    /// 7 days.
+    #[serde(
+        default = "DatabaseConfig::default_whowas_retention",
+        with = "serde_humantime"
+    )]
+    pub whowas_retention: Duration,
 }
 
-impl Config {
+impl DatabaseConfig {
     #[must_use]
-    const fn default_client_threads() -> usize {
-        1
+    const fn default_max_message_replay_count() -> u32 {
+        500
     }
 
     #[must_use]
-    const fn default_channel_threads() -> usize {
+    const fn default_max_message_replay_since() -> Duration {
+        Duration::from_secs(24 * 60 * 60)
+    }
+
+    #[must_use]
+    const fn default_whowas_retention() -> Duration {
+        Duration::from_secs(7 * 24 * 60 * 60)
+    }
+}
+
+/// Amount of threads to spread actors over, set to 0 to spawn them on the main server thread.
+#[derive(Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ThreadsConfig {
+    /// Threads for processing client commands. Defaults to 1 thread.
+    #[serde(default = "ThreadsConfig::default_threads")]
+    pub client: usize,
+    /// Threads for processing channel commands. Defaults to 1 thread.
+    #[serde(default = "ThreadsConfig::default_threads")]
+    pub channel: usize,
+}
+
+impl ThreadsConfig {
+    #[must_use]
+    const fn default_threads() -> usize {
         1
     }
+}
+
+impl Default for ThreadsConfig {
+    fn default() -> Self {
+        Self {
+            client: Self::default_threads(),
+            channel: Self::default_threads(),
+        }
+    }
+}
+
+/// How nick collisions are handled.
+#[derive(Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct NickConfig {
+    /// Whether users attempting to use a nick that's already in use by another online session
+    /// are given an alternative nick to try (ie. `nick_`). Defaults to false.
+    #[serde(default)]
+    pub suggest_alternative: bool,
+    /// Nick to assign to users connecting with a nick that's already taken, rather than
+    /// rejecting their connection. Defaults to disabled.
+    #[serde(default)]
+    pub fallback: FallbackNick,
+    /// How long a user's nick is held after they're killed, during which nobody else can take
+    /// it. Set to 0 to disable. Defaults to 1 minute.
+    #[serde(default = "NickConfig::default_kill_hold", with = "serde_humantime")]
+    pub kill_hold: Duration,
+}
 
+impl NickConfig {
     #[must_use]
-    const fn default_max_message_replay_since() -> Duration {
-        Duration::from_secs(24 * 60 * 60)
+    const fn default_kill_hold() -> Duration {
+        Duration::from_secs(60)
     }
 }
 
-impl FromStr for Config {
-    type Err = std::io::Error;
+impl Default for NickConfig {
+    fn default() -> Self {
+        Self {
+            suggest_alternative: false,
+            fallback: FallbackNick::default(),
+            kill_hold: Self::default_kill_hold(),
+        }
+    }
+}
+
+/// Restrictions applied to every user, regardless of their connection class.
+#[derive(Deserialize, Debug, Copy, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// Whether users are required to share a channel with another user before they're able to
+    /// send them private messages. Operators are exempt. Defaults to false.
+    #[serde(default)]
+    pub require_shared_channel_for_private_messages: bool,
+    /// How long ago a user's account must have been created before they're able to create
+    /// channels, regardless of the channel's namespace. Operators are exempt. Defaults to 0.
+    #[serde(default, with = "serde_humantime")]
+    pub channel_creation_min_account_age: Duration,
+    /// How long ago a user's account must have been created before they're able to send
+    /// private messages to other users. Operators are exempt. Defaults to 0.
+    #[serde(default, with = "serde_humantime")]
+    pub private_message_min_account_age: Duration,
+}
+
+/// Options applying to channels.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ChannelConfig {
+    /// Channels with names starting with this prefix are temporary, none of their state is
+    /// persisted and they're destroyed once the last member leaves. Disabled if unset.
+    pub temporary_prefix: Option<String>,
+    /// Rules restricting who can create channels within a namespace, existing channels can
+    /// still be joined by anyone.
+    #[serde(default)]
+    pub namespaces: Vec<ChannelNamespace>,
+    /// Whether the language and category set on channels through `CS SET` are shown before
+    /// their topic in `LIST`. Defaults to false.
+    #[serde(default)]
+    pub list_metadata: bool,
+}
+
+impl ChannelConfig {
+    /// Returns true if the channel is in the temporary namespace.
+    #[must_use]
+    pub fn is_temporary(&self, name: &str) -> bool {
+        self.temporary_prefix
+            .as_deref()
+            .is_some_and(|prefix| casemapping::fold(name).starts_with(&casemapping::fold(prefix)))
+    }
+
+    /// Returns the namespace rules applying to the channel, when the channel falls within
+    /// several namespaces the one with the longest prefix applies.
+    #[must_use]
+    pub fn namespace_for(&self, name: &str) -> Option<&ChannelNamespace> {
+        let name = casemapping::fold(name);
+
+        self.namespaces
+            .iter()
+            .filter(|namespace| name.starts_with(&casemapping::fold(&namespace.prefix)))
+            .max_by_key(|namespace| namespace.prefix.len())
+    }
+}
+
+/// Restricts who can create channels with names starting with a prefix, such as only letting
+/// operators create `#help-` channels. A namespace without any restrictions can be used to exempt
+/// a longer prefix from a shorter one, such as `##` from `#`.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ChannelNamespace {
+    pub prefix: String,
+    /// Whether only operators can create channels in the namespace. Defaults to false.
+    #[serde(default)]
+    pub oper_only: bool,
+    /// Whether only users that have set a password on their account can create channels in the
+    /// namespace. Defaults to false.
+    #[serde(default)]
+    pub registered_only: bool,
+    /// How long ago the user's account must have been created for them to create channels in
+    /// the namespace, implies `registered-only`. Defaults to 0.
+    #[serde(default, with = "serde_humantime")]
+    pub min_account_age: Duration,
+}
+
+impl ChannelNamespace {
+    /// Returns the restriction preventing a user from creating a channel in the namespace,
+    /// `account_age` being `None` for users that haven't registered their account. Operators
+    /// are exempt from every restriction.
+    #[must_use]
+    pub fn restriction(
+        &self,
+        is_oper: bool,
+        account_age: Option<Duration>,
+    ) -> Option<NamespaceRestriction> {
+        if is_oper {
+            None
+        } else if self.oper_only {
+            Some(NamespaceRestriction::OperOnly)
+        } else if account_age.is_none() && (self.registered_only || !self.min_account_age.is_zero())
+        {
+            Some(NamespaceRestriction::RegisteredOnly)
+        } else if account_age.is_some_and(|age| age < self.min_account_age) {
+            Some(NamespaceRestriction::AccountTooNew(self.min_account_age))
+        } else {
+            None
+        }
+    }
+}
+
+/// The reason a user isn't allowed to create a channel within a namespace.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum NamespaceRestriction {
+    OperOnly,
+    RegisteredOnly,
+    /// The user's account is younger than the namespace's minimum account age.
+    AccountTooNew(Duration),
+}
+
+/// Commands that are turned off, for features a network doesn't want to offer such as `LIST`
+/// on privacy-focused networks or `WHO` on large networks.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct CommandsConfig {
+    /// Names of the commands to turn off, users sending them are told they're disabled.
+    #[serde(default)]
+    pub disabled: Vec<String>,
+    /// Whether operators can still use disabled commands. Defaults to true.
+    #[serde(default = "CommandsConfig::default_oper_override")]
+    pub oper_override: bool,
+    /// How long users have to wait between `WHO` queries, as some clients send one for every
+    /// channel they join. Operators are exempt. Defaults to 0.
+    #[serde(default, with = "serde_humantime")]
+    pub who_interval: Duration,
+}
+
+impl CommandsConfig {
+    /// Commands clients need to stay connected, which can't be disabled.
+    const REQUIRED: &'static [&'static str] = &["PING", "PONG", "QUIT"];
+
+    /// Returns true if `command` is disabled for the user.
+    #[must_use]
+    pub fn is_disabled(&self, command: &str, is_oper: bool) -> bool {
+        !(is_oper && self.oper_override)
+            && self
+                .disabled
+                .iter()
+                .any(|v| v.eq_ignore_ascii_case(command))
+    }
+
+    #[must_use]
+    const fn default_oper_override() -> bool {
+        true
+    }
+}
+
+impl Default for CommandsConfig {
+    fn default() -> Self {
+        Self {
+            disabled: Vec::new(),
+            oper_override: Self::default_oper_override(),
+            who_interval: Duration::ZERO,
+        }
+    }
+}
+
+/// Toggles for behaviour where we're intentionally stricter than other servers, which would
+/// otherwise cause most of an external test suite such as irctest to fail. These are unsafe to
+/// enable on a real network, so they're only accepted when built with the `irctest` feature.
+#[derive(Deserialize, Debug, Copy, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct CompatConfig {
+    /// Whether clients are able to register without SASL, in which case they're logged into
+    /// a passwordless account named after their nick, created if it doesn't already exist.
+    /// Nicks belonging to an account with a password still require SASL. Defaults to false.
+    #[serde(default)]
+    pub implicit_accounts: bool,
+}
+
+/// An account that users can become an operator with.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct OperConfig {
+    pub name: String,
+    /// The argon2 hash of the operator's password, in PHC string format.
+    pub password: String,
+}
+
+/// Records the raw traffic of selected connections for debugging, see `DEBUG TAP`.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct TapConfig {
+    pub path: PathBuf,
+    /// Size in bytes the log can grow to before it's rotated. Defaults to 10MiB.
+    #[serde(default = "TapConfig::default_max_size")]
+    pub max_size: u64,
+    /// Amount of rotated logs to keep. Defaults to 5.
+    #[serde(default = "TapConfig::default_max_files")]
+    pub max_files: usize,
+    /// Host masks of connections to tap as soon as they've registered.
+    #[serde(default)]
+    pub masks: Vec<String>,
+}
+
+impl TapConfig {
+    #[must_use]
+    const fn default_max_size() -> u64 {
+        10 * 1024 * 1024
+    }
+
+    #[must_use]
+    const fn default_max_files() -> usize {
+        5
+    }
+}
+
+/// An HTTP endpoint for sharing files through the server, advertised to clients through the
+/// `FILEHOST` `ISUPPORT` token.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct FilehostConfig {
+    /// Address to serve uploads and downloads on.
+    pub address: SocketAddr,
+    /// The URL the endpoint is publicly reachable at, usually behind a TLS-terminating proxy.
+    pub public_url: String,
+    /// Size in bytes of the largest file that can be uploaded. Defaults to 10MiB.
+    #[serde(default = "FilehostConfig::default_max_size")]
+    pub max_size: usize,
+    /// Where uploaded files are stored.
+    pub storage: FilehostStorage,
+}
+
+impl FilehostConfig {
+    #[must_use]
+    const fn default_max_size() -> usize {
+        10 * 1024 * 1024
+    }
+
+    /// The URL files are uploaded to, as advertised to clients.
+    #[must_use]
+    pub fn upload_url(&self) -> String {
+        format!(
+            "{}{}",
+            self.public_url.trim_end_matches('/'),
+            crate::filehost::UPLOAD_PATH
+        )
+    }
+}
+
+/// An external services package (ie. Atheme or Anope) linking to us over a subset of the TS6
+/// server protocol.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ServicesConfig {
+    /// Address to accept the link from services on, this should only be reachable by services.
+    pub address: SocketAddr,
+    /// The server name services link with.
+    pub name: String,
+    /// The password services send to link, which is also sent back to them.
+    pub password: String,
+    /// Our own TS6 server ID, which services' user IDs are prefixed with. Defaults to `0TI`.
+    #[serde(default = "ServicesConfig::default_sid")]
+    pub sid: String,
+}
+
+impl ServicesConfig {
+    fn default_sid() -> String {
+        "0TI".to_string()
+    }
+
+    /// Checks the server ID is in the format TS6 expects, a digit followed by two letters or
+    /// digits.
+    #[must_use]
+    pub fn is_valid_sid(sid: &str) -> bool {
+        let sid = sid.as_bytes();
+
+        sid.len() == 3
+            && sid[0].is_ascii_digit()
+            && sid[1..]
+                .iter()
+                .all(|c| c.is_ascii_digit() || c.is_ascii_uppercase())
+    }
+}
+
+/// Where the file host stores uploaded files.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum FilehostStorage {
+    /// Files are written to a directory on local disk.
+    Local { path: PathBuf },
+    /// Files are written to an S3 bucket. Credentials are picked up from the standard `AWS_*`
+    /// environment variables.
+    S3 {
+        bucket: String,
+        region: Option<String>,
+        /// Alternate endpoint for S3-compatible services.
+        endpoint: Option<String>,
+    },
+}
+
+/// How to pick a nick for a connecting user whose requested nick is already taken.
+#[derive(Deserialize, Debug, Copy, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum FallbackNick {
+    /// Reject the user's connection.
+    #[default]
+    Disabled,
+    /// Assign the user a random `Guest12345` nick.
+    Guest,
+    /// Append underscores to the user's requested nick (ie. `nick_`).
+    Suffix,
+}
+
+impl FallbackNick {
+    /// Maximum amount of nicks to try before giving up and rejecting the connection.
+    pub const MAX_ATTEMPTS: usize = 10;
+
+    /// Generates a fallback for `requested`, returning `None` once we've run out of attempts or
+    /// if fallback nicks are disabled.
+    #[must_use]
+    pub fn generate(self, requested: &str, attempt: usize) -> Option<String> {
+        if attempt >= Self::MAX_ATTEMPTS {
+            return None;
+        }
+
+        match self {
+            Self::Disabled => None,
+            Self::Guest => Some(format!("Guest{:05}", rand::random::<u32>() % 100_000)),
+            Self::Suffix => Some(format!("{requested}{}", "_".repeat(attempt + 1))),
+        }
+    }
+}
+
+/// Limits on the lifetime of an operator's session, once exceeded the user loses `+o` and must
+/// re-OPER.
+#[derive(Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct OperSessionConfig {
+    /// Drops operator privileges once the operator hasn't sent a command for this long.
+    #[serde(default, deserialize_with = "deserialize_optional_duration")]
+    pub idle_timeout: Option<Duration>,
+    /// Drops operator privileges once this long has passed since the user became an operator,
+    /// regardless of activity.
+    #[serde(default, deserialize_with = "deserialize_optional_duration")]
+    pub max_duration: Option<Duration>,
+    /// How long before privileges are dropped to warn the operator. Defaults to 5 minutes.
+    #[serde(
+        default = "OperSessionConfig::default_warning",
+        with = "serde_humantime"
+    )]
+    pub warning: Duration,
+}
+
+impl OperSessionConfig {
+    #[must_use]
+    const fn default_warning() -> Duration {
+        Duration::from_secs(5 * 60)
+    }
+}
+
+impl Default for OperSessionConfig {
+    fn default() -> Self {
+        Self {
+            idle_timeout: None,
+            max_duration: None,
+            warning: Self::default_warning(),
+        }
+    }
+}
+
+/// How users affected by a network ban are told about it.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct BanConfig {
+    /// Sent to banned users when they're refused a connection or disconnected by a new ban.
+    /// Supports the placeholders `{reason}`, `{mask}`, `{expires}`, `{contact}` and
+    /// `{network}`. Defaults to `G-lined: {reason}`.
+    #[serde(default = "BanConfig::default_message")]
+    pub message: String,
+    /// Where users can appeal their ban, substituted for `{contact}`.
+    pub contact: Option<String>,
+}
+
+impl BanConfig {
+    #[must_use]
+    fn default_message() -> String {
+        "G-lined: {reason}".to_string()
+    }
+}
+
+impl Default for BanConfig {
+    fn default() -> Self {
+        Self {
+            message: Self::default_message(),
+            contact: None,
+        }
+    }
+}
+
+fn deserialize_optional_duration<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Duration>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|v| humantime::parse_duration(&v).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+/// An address for the server to accept connections on.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ListenerConfig {
+    pub address: SocketAddr,
+    /// The name of the connection class to place all clients connecting via this listener
+    /// into, rather than picking one based on the client's address.
+    pub class: Option<String>,
+    /// Whether an IPv6 listener should only accept IPv6 connections, this allows a separate IPv4
+    /// listener to be bound to the same port. Defaults to false.
+    #[serde(default)]
+    pub v6_only: bool,
+    /// Whether clients connecting via this listener must negotiate TLS, requires `tls` to be
+    /// configured. Defaults to false.
+    #[serde(default)]
+    pub tls: bool,
+}
+
+/// Paths to the PEM-encoded certificate chain and private key used for TLS listeners.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct TlsConfig {
+    pub certificate: PathBuf,
+    pub key: PathBuf,
+}
+
+/// A class of connections, defining the limits applied to any client connecting from one of
+/// the class' networks.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ConnectionClass {
+    pub name: String,
+    /// Networks that clients must connect from to be placed into this class, if empty the class
+    /// matches all clients.
+    #[serde(default)]
+    pub cidrs: Vec<Cidr>,
+    /// Host masks that clients must match, against their real host, to be placed into this
+    /// class, in addition to `cidrs`. Clients are only sorted into classes with host masks once
+    /// they've registered. If empty, the class matches on `cidrs` alone.
+    #[serde(default)]
+    pub hostmasks: Vec<String>,
+    /// Whether clients in this class must authenticate using SASL, clients that register
+    /// without it are disconnected.
+    #[serde(default)]
+    pub require_sasl: bool,
+    /// Maximum amount of clients that may be connected within this class at any one time.
+    pub max_clients: Option<usize>,
+    /// Maximum amount of connections a single IP address in this class may have open at once,
+    /// excess connections are turned away before registering.
+    pub max_clients_per_ip: Option<usize>,
+    /// Maximum amount of connections to a single account that may be open at once from this
+    /// class.
+    pub max_clients_per_account: Option<usize>,
+    /// Maximum amount of bytes to buffer for a client before the client stops being read from
+    /// until the buffer is drained.
+    pub sendq: Option<usize>,
+    /// Amount of lines per second a client is allowed to send before being throttled, excess
+    /// lines are queued up and handled as the client falls back within the rate.
+    pub flood_rate: Option<u32>,
+    /// Amount of lines a client may send in a burst before `flood-rate` applies, this is also
+    /// the amount of lines that are queued up before the client is disconnected for flooding.
+    /// Defaults to `flood-rate`.
+    pub flood_burst: Option<u32>,
+    /// How long clients in this class can be quiet for before they're pinged, clients that
+    /// haven't sent anything in four times this duration are disconnected. Defaults to 30
+    /// seconds.
+    #[serde(
+        default = "ConnectionClass::default_ping_frequency",
+        with = "serde_humantime"
+    )]
+    pub ping_frequency: Duration,
+    /// The message of the day to send to clients in this class, rather than the server's.
+    pub motd: Option<String>,
+}
+
+impl ConnectionClass {
+    /// Returns true, if a client connecting from `ip` belongs in this class. `mask` is the
+    /// client's host mask, `None` if they've yet to register.
+    #[must_use]
+    pub fn matches(&self, ip: IpAddr, mask: Option<&HostMask<'_>>) -> bool {
+        let cidr_matches = self.cidrs.is_empty() || self.cidrs.iter().any(|cidr| cidr.contains(ip));
+        let mask_matches = self.hostmasks.is_empty()
+            || mask.is_some_and(|mask| {
+                !self
+                    .hostmasks
+                    .iter()
+                    .filter_map(|v| HostMask::try_from(v.as_str()).ok())
+                    .map(|v| (v, ()))
+                    .collect::<HostMaskMap<_>>()
+                    .get(mask)
+                    .is_empty()
+            });
+
+        cidr_matches && mask_matches
+    }
+
+    /// Returns the reason to disconnect a client that's just registered into this class, if they
+    /// didn't meet its requirements.
+    #[must_use]
+    pub const fn rejects_registration(&self, sasl_authenticated: bool) -> Option<&'static str> {
+        if self.require_sasl && !sasl_authenticated {
+            Some("You must authenticate using SASL to connect from your network")
+        } else {
+            None
+        }
+    }
+
+    /// The amount of time a client can go without sending anything before being disconnected.
+    #[must_use]
+    pub fn ping_timeout(&self) -> Duration {
+        self.ping_frequency * 4
+    }
+
+    #[must_use]
+    const fn default_ping_frequency() -> Duration {
+        Duration::from_secs(30)
+    }
+}
+
+impl Default for ConnectionClass {
+    fn default() -> Self {
+        Self {
+            name: "default".to_string(),
+            cidrs: Vec::new(),
+            hostmasks: Vec::new(),
+            require_sasl: false,
+            max_clients: None,
+            max_clients_per_ip: None,
+            max_clients_per_account: None,
+            sendq: None,
+            flood_rate: None,
+            flood_burst: None,
+            ping_frequency: Self::default_ping_frequency(),
+            motd: None,
+        }
+    }
+}
+
+/// A network address and prefix length, ie. `10.0.0.0/8` or `2001:db8::/32`.
+#[derive(Deserialize, Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[serde(try_from = "String")]
+pub struct Cidr {
+    address: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    /// The network with the given prefix length that `ip` falls within, `prefix_len` must be
+    /// no longer than [`Self::max_prefix_len`] for the address.
+    #[must_use]
+    pub fn of(ip: IpAddr, prefix_len: u8) -> Self {
+        let address = match ip {
+            IpAddr::V4(ip) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(prefix_len))
+                    .unwrap_or(0);
+                IpAddr::V4((u32::from(ip) & mask).into())
+            }
+            IpAddr::V6(ip) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(prefix_len))
+                    .unwrap_or(0);
+                IpAddr::V6((u128::from(ip) & mask).into())
+            }
+        };
+
+        Self {
+            address,
+            prefix_len,
+        }
+    }
+
+    /// The longest prefix length a network containing `ip` can have.
+    #[must_use]
+    pub const fn max_prefix_len(ip: IpAddr) -> u8 {
+        if ip.is_ipv4() {
+            32
+        } else {
+            128
+        }
+    }
+
+    /// Returns true, if the given IP falls within this network.
+    #[must_use]
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+
+        ip.is_ipv4() == self.address.is_ipv4() && Self::of(ip, self.prefix_len) == *self
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, prefix_len) = s.split_once('/').unwrap_or((s, ""));
+        let address = IpAddr::from_str(address).map_err(|e| format!("invalid cidr {s}: {e}"))?;
+
+        let max_prefix_len = Self::max_prefix_len(address);
+        let prefix_len = if prefix_len.is_empty() {
+            max_prefix_len
+        } else {
+            prefix_len
+                .parse()
+                .ok()
+                .filter(|v| *v <= max_prefix_len)
+                .ok_or_else(|| format!("invalid prefix length in cidr {s}"))?
+        };
+
+        // IPv4-mapped networks are stored as plain IPv4 networks, since that's the form client
+        // addresses are compared in
+        match address {
+            IpAddr::V6(v6) if prefix_len >= 96 => {
+                if let Some(v4) = v6.to_ipv4_mapped() {
+                    return Ok(Self::of(IpAddr::V4(v4), prefix_len - 96));
+                }
+            }
+            _ => {}
+        }
+
+        // host bits are cleared, so equal networks compare equal however they were written
+        Ok(Self::of(address, prefix_len))
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Self::from_str(&value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::IpAddr, str::FromStr, sync::Arc, time::Duration};
+
+    use super::{Cidr, Config, ConfigError, ConnectionClass, FallbackNick, NamespaceRestriction};
+    use crate::host_mask::HostMask;
+
+    const MINIMAL: &str = r#"
+        [database]
+        uri = "sqlite::memory:"
+
+        [[listeners]]
+        address = "[::]:6667"
+    "#;
+
+    fn parse(extra: &str) -> Result<Config, ConfigError> {
+        format!("{extra}\n{MINIMAL}").parse()
+    }
+
+    #[test]
+    fn example_config_is_valid() {
+        let config: Config = include_str!("../config.toml").parse().unwrap();
+        assert_eq!(config.listeners.len(), 1);
+        assert_eq!(config.database.max_message_replay_count, 500);
+    }
+
+    #[test]
+    fn temporary_channels() {
+        let config = parse("[channels]\ntemporary-prefix = \"#!temp-\"").unwrap();
+        assert!(config.channels.is_temporary("#!TEMP-event"));
+        assert!(!config.channels.is_temporary("#event"));
+        assert!(!parse("").unwrap().channels.is_temporary("#!temp-event"));
+
+        assert!(matches!(
+            parse("[channels]\ntemporary-prefix = \"temp-\""),
+            Err(ConfigError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn class_motd_overrides_server_motd() {
+        let config = parse(
+            r#"
+            motd = "server"
+
+            [[classes]]
+            name = "local"
+            motd = "local"
+
+            [[classes]]
+            name = "remote"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.motd_for_class("local"), Some("local"));
+        assert_eq!(config.motd_for_class("remote"), Some("server"));
+        assert_eq!(config.motd_for_class("default"), Some("server"));
+    }
+
+    #[test]
+    fn minimal_config_uses_defaults() {
+        let config = parse("").unwrap();
+        assert_eq!(config.network_name, "titanircd");
+        assert_eq!(config.threads.client, 1);
+        assert_eq!(config.nicks.fallback, FallbackNick::Disabled);
+        assert!(config.opers.is_empty());
+    }
+
+    #[test]
+    fn rejects_unknown_fields() {
+        assert!(matches!(
+            parse("listen-address = \"[::]:6667\""),
+            Err(ConfigError::Parse(_))
+        ));
+        assert!(matches!(
+            parse("[nicks]\nsuggest-alternative-nick = true"),
+            Err(ConfigError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_tls_listener_without_certificate() {
+        let config = parse("[[listeners]]\naddress = \"[::]:6697\"\ntls = true");
+        assert!(matches!(config, Err(ConfigError::Invalid(_))), "{config:?}");
+    }
+
+    #[test]
+    fn classes_match_on_cidrs_and_host_masks() {
+        let config = parse(
+            r#"
+            [[classes]]
+            name = "staff"
+            cidrs = ["192.0.2.0/24"]
+            hostmasks = ["*!staff*@*"]
+
+            [[classes]]
+            name = "local"
+            cidrs = ["192.0.2.0/24"]
+            require-sasl = true
+            "#,
+        )
+        .unwrap();
+        let classes: Vec<_> = config.classes.into_iter().map(Arc::new).collect();
+
+        let ip = IpAddr::from_str("192.0.2.1").unwrap();
+        let staff = HostMask::new("nick", "staff-alice", "host.example");
+        let other = HostMask::new("nick", "user", "host.example");
+
+        // host mask classes are only considered once the client has registered
+        assert_eq!(Config::find_class(&classes, ip, None).name, "local");
+        assert_eq!(Config::find_class(&classes, ip, Some(&staff)).name, "staff");
+        assert_eq!(Config::find_class(&classes, ip, Some(&other)).name, "local");
+
+        // both the CIDR and the host mask have to match
+        let ip = IpAddr::from_str("198.51.100.1").unwrap();
+        assert_eq!(
+            Config::find_class(&classes, ip, Some(&staff)).name,
+            "default"
+        );
+
+        let config = parse("[[classes]]\nname = \"bad\"\nhostmasks = [\"*.example!*@*\"]");
+        assert!(matches!(config, Err(ConfigError::Invalid(_))), "{config:?}");
+    }
+
+    #[test]
+    fn classes_can_require_sasl() {
+        let config = parse("[[classes]]\nname = \"sasl\"\nrequire-sasl = true").unwrap();
+        let class = &config.classes[0];
+        assert!(class.rejects_registration(false).is_some());
+        assert!(class.rejects_registration(true).is_none());
+
+        let class = ConnectionClass::default();
+        assert!(class.rejects_registration(false).is_none());
+    }
+
+    #[test]
+    fn rejects_listener_with_unknown_class() {
+        let config = parse("[[listeners]]\naddress = \"[::]:6668\"\nclass = \"local\"");
+        assert!(matches!(config, Err(ConfigError::Invalid(_))), "{config:?}");
+
+        let config = parse(
+            "[[listeners]]\naddress = \"[::]:6668\"\nclass = \"local\"\n\
+             [[classes]]\nname = \"local\"",
+        );
+        assert!(config.is_ok(), "{config:?}");
+    }
+
+    #[test]
+    fn validates_oper_passwords() {
+        let config = parse("[[opers]]\nname = \"admin\"\npassword = \"hunter2\"");
+        assert!(matches!(config, Err(ConfigError::Invalid(_))), "{config:?}");
+
+        let config = parse(
+            "[[opers]]\nname = \"admin\"\npassword = \
+             \"$argon2id$v=19$m=19456,t=2,p=1$c29tZXNhbHQ$RdescudvJCsgt3ub+b+dWRWJTmaaJObG\"",
+        );
+        assert!(config.is_ok(), "{config:?}");
+    }
+
+    #[test]
+    fn disabled_commands() {
+        let config = parse("[commands]\ndisabled = [\"list\", \"WHO\"]").unwrap();
+        assert!(config.commands.is_disabled("LIST", false));
+        assert!(config.commands.is_disabled("who", false));
+        assert!(!config.commands.is_disabled("LIST", true));
+        assert!(!config.commands.is_disabled("PRIVMSG", false));
+
+        let config = parse("[commands]\ndisabled = [\"LIST\"]\noper-override = false").unwrap();
+        assert!(config.commands.is_disabled("LIST", true));
+
+        let config = parse("[commands]\nwho-interval = \"5s\"").unwrap();
+        assert_eq!(config.commands.who_interval, Duration::from_secs(5));
+        assert!(!config.commands.is_disabled("WHO", false));
+
+        let config = parse("[commands]\ndisabled = [\"ping\"]");
+        assert!(matches!(config, Err(ConfigError::Invalid(_))), "{config:?}");
+    }
+
+    #[test]
+    fn services() {
+        let config = parse(
+            "[services]\naddress = \"127.0.0.1:6800\"\nname = \"services.example\"\n\
+             password = \"hunter2\"",
+        )
+        .unwrap();
+
+        let services = config.services.unwrap();
+        assert_eq!(services.name, "services.example");
+        assert_eq!(services.sid, "0TI");
+
+        let config = parse(
+            "[services]\naddress = \"127.0.0.1:6800\"\nname = \"services.example\"\n\
+             password = \"hunter2\"\nsid = \"ABC\"",
+        );
+        assert!(matches!(config, Err(ConfigError::Invalid(_))), "{config:?}");
+    }
+
+    #[test]
+    fn min_account_ages() {
+        let config = parse(
+            "[limits]\nchannel-creation-min-account-age = \"10m\"\n\
+             private-message-min-account-age = \"1h\"",
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.limits.channel_creation_min_account_age,
+            Duration::from_secs(10 * 60)
+        );
+        assert_eq!(
+            config.limits.private_message_min_account_age,
+            Duration::from_secs(60 * 60)
+        );
+        assert!(parse("")
+            .unwrap()
+            .limits
+            .private_message_min_account_age
+            .is_zero());
+    }
+
+    #[test]
+    fn channel_namespaces() {
+        let config = parse(
+            "[[channels.namespaces]]\nprefix = \"#help-\"\noper-only = true\n\
+             [[channels.namespaces]]\nprefix = \"##\"\n\
+             [[channels.namespaces]]\nprefix = \"#\"\nmin-account-age = \"1h\"",
+        )
+        .unwrap();
+        let hour = Duration::from_secs(60 * 60);
+
+        let help = config.channels.namespace_for("#HELP-rust").unwrap();
+        assert_eq!(
+            help.restriction(false, Some(hour)),
+            Some(NamespaceRestriction::OperOnly)
+        );
+        assert_eq!(help.restriction(true, None), None);
+
+        let unofficial = config.channels.namespace_for("##rust").unwrap();
+        assert_eq!(unofficial.restriction(false, None), None);
+
+        let official = config.channels.namespace_for("#rust").unwrap();
+        assert_eq!(
+            official.restriction(false, None),
+            Some(NamespaceRestriction::RegisteredOnly)
+        );
+        assert_eq!(
+            official.restriction(false, Some(Duration::from_secs(60))),
+            Some(NamespaceRestriction::AccountTooNew(hour))
+        );
+        assert_eq!(official.restriction(false, Some(hour)), None);
+
+        assert!(config.channels.namespace_for("&local").is_none());
+
+        let config = parse("[[channels.namespaces]]\nprefix = \"help-\"");
+        assert!(matches!(config, Err(ConfigError::Invalid(_))), "{config:?}");
+    }
+
+    #[test]
+    fn compat_requires_irctest_feature() {
+        let config = parse("[compat]\nimplicit-accounts = true");
+        assert_eq!(config.is_ok(), cfg!(feature = "irctest"), "{config:?}");
+
+        let config = parse("[compat]\nimplicit-accounts = false");
+        assert!(config.is_ok(), "{config:?}");
+    }
+
+    #[test]
+    fn fallback_nick() {
+        assert_eq!(FallbackNick::Disabled.generate("nick", 0), None);
+        assert_eq!(
+            FallbackNick::Suffix.generate("nick", 0).as_deref(),
+            Some("nick_")
+        );
+        assert_eq!(
+            FallbackNick::Suffix.generate("nick", 2).as_deref(),
+            Some("nick___")
+        );
+        assert_eq!(
+            FallbackNick::Suffix.generate("nick", FallbackNick::MAX_ATTEMPTS),
+            None
+        );
+
+        let guest = FallbackNick::Guest.generate("nick", 0).unwrap();
+        assert!(guest.starts_with("Guest"));
+        assert_eq!(guest.len(), "Guest".len() + 5);
+    }
+
+    #[test]
+    fn cidr_contains() {
+        let cidr = Cidr::from_str("10.0.0.0/8").unwrap();
+        assert!(cidr.contains(IpAddr::from_str("10.1.2.3").unwrap()));
+        assert!(cidr.contains(IpAddr::from_str("::ffff:10.1.2.3").unwrap()));
+        assert!(!cidr.contains(IpAddr::from_str("11.1.2.3").unwrap()));
+        assert!(!cidr.contains(IpAddr::from_str("2001:db8::1").unwrap()));
+
+        let cidr = Cidr::from_str("2001:db8::/32").unwrap();
+        assert!(cidr.contains(IpAddr::from_str("2001:db8::1").unwrap()));
+        assert!(!cidr.contains(IpAddr::from_str("2001:db9::1").unwrap()));
+
+        let cidr = Cidr::from_str("::ffff:10.0.0.0/104").unwrap();
+        assert!(cidr.contains(IpAddr::from_str("10.1.2.3").unwrap()));
+        assert!(!cidr.contains(IpAddr::from_str("11.1.2.3").unwrap()));
+
+        let cidr = Cidr::from_str("0.0.0.0/0").unwrap();
+        assert!(cidr.contains(IpAddr::from_str("192.168.0.1").unwrap()));
+    }
+
+    #[test]
+    fn cidr_without_prefix_is_single_address() {
+        let cidr = Cidr::from_str("192.168.0.1").unwrap();
+        assert!(cidr.contains(IpAddr::from_str("192.168.0.1").unwrap()));
+        assert!(!cidr.contains(IpAddr::from_str("192.168.0.2").unwrap()));
+    }
+
+    #[test]
+    fn cidr_of_clears_host_bits() {
+        let ip = IpAddr::from_str("10.1.2.3").unwrap();
+        assert_eq!(Cidr::of(ip, 8), Cidr::from_str("10.0.0.0/8").unwrap());
+        assert_eq!(Cidr::of(ip, 8), Cidr::from_str("10.9.9.9/8").unwrap());
+        assert_eq!(Cidr::of(ip, 32), Cidr::from_str("10.1.2.3").unwrap());
+        assert_ne!(Cidr::of(ip, 16), Cidr::from_str("10.0.0.0/8").unwrap());
+
+        let ip = IpAddr::from_str("2001:db8::1").unwrap();
+        assert_eq!(Cidr::of(ip, 32), Cidr::from_str("2001:db8::/32").unwrap());
+        assert_eq!(
+            Cidr::of(IpAddr::from_str("10.0.0.1").unwrap(), 8),
+            Cidr::from_str("::ffff:10.0.0.0/104").unwrap()
+        );
+    }
 
-    fn from_str(path: &str) -> Result<Self, Self::Err> {
-        let contents = std::fs::read_to_string(path)?;
-        toml::from_str(&contents)
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    #[test]
+    fn cidr_rejects_invalid_prefix() {
+        assert!(Cidr::from_str("10.0.0.0/33").is_err());
+        assert!(Cidr::from_str("10.0.0.0/abc").is_err());
+        assert!(Cidr::from_str("nonsense/8").is_err());
     }
 }
@@ -0,0 +1,475 @@
+//! A scripted client that connects to a running server and checks it behaves as RFC 1459 and
+//! the IRCv3 specs expect, for validating that refactors haven't changed anything on the wire.
+//!
+//! Each check builds on the state left behind by the previous one, so once a check fails the
+//! rest are skipped.
+
+use std::{future::Future, time::Duration};
+
+use base64::{prelude::BASE64_STANDARD, Engine};
+use futures::{SinkExt, TryStreamExt};
+use irc_proto::{CapSubCommand, Command, IrcCodec, Message, Prefix, Response};
+use tokio::net::{
+    tcp::{OwnedReadHalf, OwnedWriteHalf},
+    TcpStream,
+};
+use tokio_util::codec::{FramedRead, FramedWrite};
+
+use crate::{connection::Capability, listener::irc_codec};
+
+/// How long to wait for the server to reply before failing a check.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The channel joined by the channel checks.
+const CHANNEL: &str = "#conformance";
+
+type CheckResult = Result<(), String>;
+
+#[derive(clap::Args, Debug)]
+pub struct ConformanceArgs {
+    /// Address of the server to test
+    #[clap(short, long, default_value = "127.0.0.1:6667")]
+    pub address: String,
+    /// Nick and account to register with, the account is created if it doesn't exist
+    #[clap(short, long, default_value = "conformance")]
+    pub nick: String,
+    /// Password of the account
+    #[clap(short, long, default_value = "conformance")]
+    pub password: String,
+}
+
+/// Runs every check against the server at `args.address`, printing the outcome of each one.
+/// Returns whether all the checks passed.
+pub async fn run(args: ConformanceArgs) -> anyhow::Result<bool> {
+    let mut session = Session::connect(&args.address).await?;
+    let mut checks = Checks::default();
+
+    checks
+        .run(
+            "CAP LS lists the supported capabilities",
+            cap_ls(&mut session),
+        )
+        .await;
+    checks
+        .run(
+            "CAP REQ for an unknown capability is NAKed",
+            cap_req_unknown(&mut session),
+        )
+        .await;
+    checks
+        .run(
+            "SASL PLAIN authenticates the client",
+            sasl_plain(&mut session, &args),
+        )
+        .await;
+
+    let mut burst = Vec::new();
+    checks
+        .run(
+            "Registration completes with RPL_WELCOME",
+            register(&mut session, &args.nick, &mut burst),
+        )
+        .await;
+    checks
+        .run(
+            "Numerics are sent by the server to the client",
+            std::future::ready(numerics_format(&burst, &args.nick)),
+        )
+        .await;
+    checks
+        .run("PING is answered with a matching PONG", ping(&mut session))
+        .await;
+    checks
+        .run(
+            "Unknown commands return ERR_UNKNOWNCOMMAND",
+            unknown_command(&mut session),
+        )
+        .await;
+    checks.run("MOTD ends the MOTD", motd(&mut session)).await;
+    checks
+        .run(
+            "JOIN sends the channel burst",
+            join(&mut session, &args.nick),
+        )
+        .await;
+    checks
+        .run(
+            "MODE on a channel returns RPL_CHANNELMODEIS",
+            channel_mode(&mut session),
+        )
+        .await;
+    checks
+        .run("QUIT is acknowledged with ERROR", quit(&mut session))
+        .await;
+
+    println!(
+        "\n{} passed, {} failed, {} skipped",
+        checks.passed, checks.failed, checks.skipped
+    );
+
+    Ok(checks.failed == 0 && checks.skipped == 0)
+}
+
+/// Tallies the outcome of each check.
+#[derive(Default)]
+struct Checks {
+    passed: usize,
+    failed: usize,
+    skipped: usize,
+}
+
+impl Checks {
+    async fn run(&mut self, name: &str, check: impl Future<Output = CheckResult>) {
+        if self.failed > 0 {
+            self.skipped += 1;
+            println!("SKIP {name}");
+            return;
+        }
+
+        match check.await {
+            Ok(()) => {
+                self.passed += 1;
+                println!("PASS {name}");
+            }
+            Err(reason) => {
+                self.failed += 1;
+                println!("FAIL {name}: {reason}");
+            }
+        }
+    }
+}
+
+/// A single connection to the server under test.
+struct Session {
+    read: FramedRead<OwnedReadHalf, IrcCodec>,
+    write: FramedWrite<OwnedWriteHalf, IrcCodec>,
+}
+
+impl Session {
+    async fn connect(address: &str) -> std::io::Result<Self> {
+        let (read, write) = TcpStream::connect(address).await?.into_split();
+
+        Ok(Self {
+            read: FramedRead::new(read, irc_codec()),
+            write: FramedWrite::new(write, irc_codec()),
+        })
+    }
+
+    async fn send(&mut self, command: Command) -> CheckResult {
+        self.write
+            .send(Message::from(command))
+            .await
+            .map_err(|e| format!("failed to send: {e}"))
+    }
+
+    /// Reads messages until one matches `predicate`, returning every message read including
+    /// the matching one.
+    async fn read_until(
+        &mut self,
+        mut predicate: impl FnMut(&Message) -> bool,
+    ) -> Result<Vec<Message>, String> {
+        let mut read = Vec::new();
+
+        loop {
+            let message = match tokio::time::timeout(REPLY_TIMEOUT, self.read.try_next()).await {
+                Ok(Ok(Some(message))) => message,
+                Ok(Ok(None)) => return Err("server closed the connection".to_string()),
+                Ok(Err(e)) => return Err(format!("failed to read: {e}")),
+                Err(_) => {
+                    return Err(format!(
+                        "timed out waiting for a reply, received: {}",
+                        summarise(&read)
+                    ))
+                }
+            };
+
+            let matched = predicate(&message);
+            read.push(message);
+
+            if matched {
+                return Ok(read);
+            }
+        }
+    }
+}
+
+async fn cap_ls(session: &mut Session) -> CheckResult {
+    session
+        .send(Command::CAP(
+            None,
+            CapSubCommand::LS,
+            Some("302".to_string()),
+            None,
+        ))
+        .await?;
+
+    let messages = session
+        .read_until(|m| matches!(m.command, Command::CAP(_, CapSubCommand::LS, _, _)))
+        .await?;
+    let Some(Command::CAP(_, _, first, second)) = messages.last().map(|m| &m.command) else {
+        unreachable!()
+    };
+
+    let offered: Vec<_> = second
+        .as_ref()
+        .or(first.as_ref())
+        .map_or_else(Vec::new, |v| v.split(' ').collect());
+
+    match Capability::SUPPORTED
+        .iter()
+        .find(|capability| !offered.contains(*capability))
+    {
+        Some(missing) => Err(format!("{missing} isn't offered, got {offered:?}")),
+        None => Ok(()),
+    }
+}
+
+async fn cap_req_unknown(session: &mut Session) -> CheckResult {
+    session
+        .send(Command::CAP(
+            None,
+            CapSubCommand::REQ,
+            Some("conformance-unknown-capability".to_string()),
+            None,
+        ))
+        .await?;
+
+    let messages = session
+        .read_until(|m| matches!(m.command, Command::CAP(..)))
+        .await?;
+
+    match messages.last().map(|m| &m.command) {
+        Some(Command::CAP(_, CapSubCommand::NAK, _, _)) => Ok(()),
+        other => Err(format!("expected a NAK, got {other:?}")),
+    }
+}
+
+async fn sasl_plain(session: &mut Session, args: &ConformanceArgs) -> CheckResult {
+    session
+        .send(Command::CAP(
+            None,
+            CapSubCommand::REQ,
+            Some("sasl".to_string()),
+            None,
+        ))
+        .await?;
+
+    let messages = session
+        .read_until(|m| matches!(m.command, Command::CAP(..)))
+        .await?;
+    if !matches!(
+        messages.last().map(|m| &m.command),
+        Some(Command::CAP(_, CapSubCommand::ACK, _, _))
+    ) {
+        return Err(format!("sasl wasn't ACKed: {}", summarise(&messages)));
+    }
+
+    session
+        .send(Command::AUTHENTICATE("PLAIN".to_string()))
+        .await?;
+    session
+        .read_until(|m| matches!(&m.command, Command::AUTHENTICATE(v) if v == "+"))
+        .await?;
+
+    let payload = BASE64_STANDARD.encode(format!("\0{}\0{}", args.nick, args.password));
+    session.send(Command::AUTHENTICATE(payload)).await?;
+
+    let messages = session
+        .read_until(|m| {
+            matches!(
+                m.command,
+                Command::Response(
+                    Response::RPL_SASLSUCCESS | Response::ERR_SASLFAIL | Response::ERR_SASLABORT,
+                    _
+                )
+            )
+        })
+        .await?;
+
+    match messages.last().map(|m| &m.command) {
+        Some(Command::Response(Response::RPL_SASLSUCCESS, _)) => Ok(()),
+        _ => Err(format!("authentication failed: {}", summarise(&messages))),
+    }
+}
+
+/// Completes registration, storing every message the server sent up to the end of its
+/// `RPL_ISUPPORT` in `burst`.
+async fn register(session: &mut Session, nick: &str, burst: &mut Vec<Message>) -> CheckResult {
+    session.send(Command::NICK(nick.to_string())).await?;
+    session
+        .send(Command::USER(
+            nick.to_string(),
+            "0".to_string(),
+            "Conformance".to_string(),
+        ))
+        .await?;
+    session
+        .send(Command::CAP(None, CapSubCommand::END, None, None))
+        .await?;
+
+    *burst = session
+        .read_until(|m| matches!(m.command, Command::Response(Response::RPL_ISUPPORT, _)))
+        .await?;
+
+    let welcome = burst
+        .iter()
+        .find(|m| matches!(m.command, Command::Response(Response::RPL_WELCOME, _)))
+        .ok_or_else(|| format!("no RPL_WELCOME received: {}", summarise(burst)))?;
+
+    match &welcome.command {
+        Command::Response(_, args) if args.first().is_some_and(|v| v == nick) => Ok(()),
+        command => Err(format!(
+            "RPL_WELCOME isn't addressed to {nick}: {command:?}"
+        )),
+    }
+}
+
+/// Every numeric should come from the server and have the client's nick as its first argument.
+fn numerics_format(burst: &[Message], nick: &str) -> CheckResult {
+    for message in burst {
+        let Command::Response(response, args) = &message.command else {
+            continue;
+        };
+
+        if !matches!(message.prefix, Some(Prefix::ServerName(_))) {
+            return Err(format!("{response:?} wasn't sent by the server: {message}"));
+        }
+
+        if args.first().map(String::as_str) != Some(nick) {
+            return Err(format!("{response:?} isn't addressed to {nick}: {message}"));
+        }
+    }
+
+    Ok(())
+}
+
+async fn ping(session: &mut Session) -> CheckResult {
+    session
+        .send(Command::PING("conformance-token".to_string(), None))
+        .await?;
+
+    let messages = session
+        .read_until(|m| matches!(m.command, Command::PONG(..)))
+        .await?;
+
+    match messages.last().map(|m| &m.command) {
+        Some(Command::PONG(token, None) | Command::PONG(_, Some(token)))
+            if token == "conformance-token" =>
+        {
+            Ok(())
+        }
+        other => Err(format!("PONG didn't echo the token: {other:?}")),
+    }
+}
+
+async fn unknown_command(session: &mut Session) -> CheckResult {
+    session
+        .send(Command::Raw("CONFORMANCEUNKNOWN".to_string(), vec![]))
+        .await?;
+    session
+        .read_until(|m| {
+            matches!(
+                m.command,
+                Command::Response(Response::ERR_UNKNOWNCOMMAND, _)
+            )
+        })
+        .await?;
+
+    Ok(())
+}
+
+async fn motd(session: &mut Session) -> CheckResult {
+    session.send(Command::MOTD(None)).await?;
+    session
+        .read_until(|m| {
+            matches!(
+                m.command,
+                Command::Response(Response::RPL_ENDOFMOTD | Response::ERR_NOMOTD, _)
+            )
+        })
+        .await?;
+
+    Ok(())
+}
+
+async fn join(session: &mut Session, nick: &str) -> CheckResult {
+    session
+        .send(Command::JOIN(CHANNEL.to_string(), None, None))
+        .await?;
+
+    let messages = session
+        .read_until(|m| matches!(m.command, Command::Response(Response::RPL_ENDOFNAMES, _)))
+        .await?;
+
+    let joined = messages.iter().any(|m| {
+        matches!(&m.command, Command::JOIN(channel, _, _) if channel == CHANNEL)
+            && matches!(&m.prefix, Some(Prefix::Nickname(v, _, _)) if v == nick)
+    });
+    if !joined {
+        return Err(format!("JOIN wasn't echoed: {}", summarise(&messages)));
+    }
+
+    let named = messages.iter().any(|m| {
+        matches!(
+            &m.command,
+            Command::Response(Response::RPL_NAMREPLY, args)
+                if args.last().is_some_and(|names| {
+                    names.split(' ').any(|name| name.trim_start_matches(['~', '@', '%', '+']) == nick)
+                })
+        )
+    });
+    if !named {
+        return Err(format!(
+            "RPL_NAMREPLY didn't include {nick}: {}",
+            summarise(&messages)
+        ));
+    }
+
+    Ok(())
+}
+
+async fn channel_mode(session: &mut Session) -> CheckResult {
+    session
+        .send(Command::ChannelMODE(CHANNEL.to_string(), vec![]))
+        .await?;
+
+    let messages = session
+        .read_until(|m| matches!(m.command, Command::Response(Response::RPL_CHANNELMODEIS, _)))
+        .await?;
+
+    match messages.last().map(|m| &m.command) {
+        Some(Command::Response(_, args))
+            if args.get(1).map(String::as_str) == Some(CHANNEL)
+                && args.get(2).is_some_and(|v| v.starts_with('+')) =>
+        {
+            Ok(())
+        }
+        other => Err(format!("malformed RPL_CHANNELMODEIS: {other:?}")),
+    }
+}
+
+async fn quit(session: &mut Session) -> CheckResult {
+    session
+        .send(Command::QUIT(Some(
+            "Conformance checks complete".to_string(),
+        )))
+        .await?;
+    session
+        .read_until(|m| matches!(m.command, Command::ERROR(..)))
+        .await?;
+
+    Ok(())
+}
+
+/// Formats messages received from the server for including in a failure.
+fn summarise(messages: &[Message]) -> String {
+    if messages.is_empty() {
+        return "nothing".to_string();
+    }
+
+    messages
+        .iter()
+        .map(|m| m.to_string().trim_end().to_string())
+        .collect::<Vec<_>>()
+        .join(" | ")
+}
@@ -1,19 +1,22 @@
 #![allow(clippy::iter_without_into_iter)]
 
 mod authenticate;
+pub mod negotiation;
 pub mod sasl;
+pub mod stream;
 
 use std::{
     fmt::{Display, Formatter},
     io::{Error, ErrorKind},
-    net::SocketAddr,
+    net::{IpAddr, SocketAddr},
     str::FromStr,
+    sync::{Arc, RwLock},
     time::Duration,
 };
 
-use actix::{io::FramedWrite, Actor, Addr};
+use actix::{io::FramedWrite, Actor, Addr, MailboxError};
 use bitflags::bitflags;
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use const_format::concatcp;
 use futures::{SinkExt, TryStreamExt};
 use hickory_resolver::TokioAsyncResolver;
@@ -23,23 +26,36 @@ use irc_proto::{
 use sha2::digest::{FixedOutput, Update};
 use tokio::{
     io::{ReadHalf, WriteHalf},
-    net::TcpStream,
+    time::Instant,
 };
 use tokio_util::codec::FramedRead;
-use tracing::{instrument, warn};
+use tracing::instrument;
 
 use crate::{
+    client::traffic::SocketWriter,
+    config::{Config, ConnectionClass, FallbackNick},
     connection::{
-        authenticate::{Authenticate, AuthenticateMessage, AuthenticateResult},
+        authenticate::{
+            handle_implicit_authentication, Authenticate, AuthenticateMessage, AuthenticateResult,
+        },
+        negotiation::{Action, Negotiation, NegotiationState},
         sasl::{AuthStrategy, ConnectionSuccess, SaslSuccess},
+        stream::ClientStream,
     },
     host_mask::HostMask,
     keys::Keys,
-    persistence::{events::ReserveNick, Persistence},
+    messages::CheckNickAvailability,
+    persistence::{
+        events::{FetchAccountByNick, ReserveNick},
+        Persistence,
+    },
+    server::{bans::NetworkBans, response::IntoProtocol, Server},
+    services::{self, NickServResponse},
 };
 
-pub type MessageStream = FramedRead<ReadHalf<TcpStream>, irc_proto::IrcCodec>;
-pub type MessageSink = FramedWrite<Message, WriteHalf<TcpStream>, irc_proto::IrcCodec>;
+pub type MessageStream = FramedRead<ReadHalf<ClientStream>, irc_proto::IrcCodec>;
+pub type MessageSink =
+    FramedWrite<Message, SocketWriter<WriteHalf<ClientStream>>, irc_proto::IrcCodec>;
 
 #[derive(Copy, Clone, Debug, Hash, PartialEq, Eq, sqlx::Type)]
 #[sqlx(transparent)]
@@ -53,21 +69,47 @@ pub struct ConnectionRequest {
     real_name: Option<String>,
     user_id: Option<UserId>,
     capabilities: Capability,
+    class: Arc<ConnectionClass>,
 }
 
-#[derive(Clone, Debug)]
+/// An established connection, once the client is handed off to its actor this is shared via an
+/// `Arc` between the client, the server and each of the user's channels.
+///
+/// The user's identity is fixed for the lifetime of the connection, whereas their presence is
+/// held in a shared cell so every holder sees changes as soon as they're made.
+#[derive(Debug)]
 pub struct InitiatedConnection {
     pub host: SocketAddr,
+    pub family: AddressFamily,
     pub resolved_host: Option<String>,
-    pub cloak: String,
-    pub nick: String,
+    /// Fingerprint of the TLS client certificate the user connected with, if any.
+    pub certificate_fingerprint: Option<String>,
     pub user: String,
-    pub mode: UserMode,
     pub real_name: String,
     pub user_id: UserId,
     pub capabilities: Capability,
-    pub away: Option<String>,
     pub at: chrono::DateTime<Utc>,
+    /// The connection class the client was sorted into upon connecting.
+    pub class: Arc<ConnectionClass>,
+    /// Identifies this connection amongst the account's other sessions, for `NS LOGOUT`.
+    pub session_id: String,
+    /// When the user's account was created, `None` if it was created before creation times were
+    /// recorded.
+    pub account_created: Option<DateTime<Utc>>,
+    presence: RwLock<Presence>,
+}
+
+/// The parts of a connection that can be changed by the user after they've connected.
+#[derive(Clone, Debug)]
+struct Presence {
+    nick: String,
+    /// The host shown to other users in place of the user's address.
+    cloak: String,
+    mode: UserMode,
+    away: Option<String>,
+    /// Whether the user proved they own their account with a password or certificate, rather
+    /// than their account being implicitly created from their nick.
+    registered: bool,
 }
 
 impl InitiatedConnection {
@@ -79,6 +121,7 @@ impl InitiatedConnection {
             real_name: Some(real_name),
             user_id: Some(user_id),
             capabilities,
+            class,
         } = value
         else {
             return Err(value);
@@ -93,133 +136,279 @@ impl InitiatedConnection {
 
         Ok(Self {
             host,
+            family: AddressFamily::from(host.ip()),
             resolved_host: None,
-            cloak: format!("cloaked-{cloak}"),
-            nick,
+            certificate_fingerprint: None,
             user,
-            mode: UserMode::empty(),
             real_name,
             user_id,
             capabilities,
-            away: None,
             at: Utc::now(),
+            class,
+            session_id: format!("{:08x}", rand::random::<u32>()),
+            account_created: None,
+            presence: RwLock::new(Presence {
+                nick,
+                cloak: format!("cloaked-{cloak}"),
+                mode: UserMode::empty(),
+                away: None,
+                registered: false,
+            }),
         })
     }
 
+    #[must_use]
+    pub fn nick(&self) -> String {
+        self.presence.read().unwrap().nick.clone()
+    }
+
+    pub fn set_nick(&self, nick: String) {
+        self.presence.write().unwrap().nick = nick;
+    }
+
+    #[must_use]
+    pub fn cloak(&self) -> String {
+        self.presence.read().unwrap().cloak.clone()
+    }
+
+    pub fn set_cloak(&self, cloak: String) {
+        self.presence.write().unwrap().cloak = cloak;
+    }
+
+    #[must_use]
+    pub fn mode(&self) -> UserMode {
+        self.presence.read().unwrap().mode
+    }
+
+    pub fn set_mode(&self, mode: UserMode) {
+        self.presence.write().unwrap().mode = mode;
+    }
+
+    #[must_use]
+    pub fn away(&self) -> Option<String> {
+        self.presence.read().unwrap().away.clone()
+    }
+
+    #[must_use]
+    pub fn is_away(&self) -> bool {
+        self.presence.read().unwrap().away.is_some()
+    }
+
+    pub fn set_away(&self, message: Option<String>) {
+        self.presence.write().unwrap().away = message;
+    }
+
+    #[must_use]
+    pub fn is_registered(&self) -> bool {
+        self.presence.read().unwrap().registered
+    }
+
+    pub fn set_registered(&self, registered: bool) {
+        self.presence.write().unwrap().registered = registered;
+    }
+
+    /// How long ago the user's account was created, regardless of whether it's been registered.
+    /// Accounts created before creation times were recorded are considered to be as old as they
+    /// can be.
+    #[must_use]
+    pub fn time_since_account_created(&self) -> Duration {
+        self.account_created.map_or(Duration::MAX, |created| {
+            (Utc::now() - created).to_std().unwrap_or_default()
+        })
+    }
+
+    /// How long ago the user's account was created, or `None` if the user hasn't registered
+    /// their account.
+    #[must_use]
+    pub fn account_age(&self) -> Option<Duration> {
+        self.is_registered()
+            .then(|| self.time_since_account_created())
+    }
+
     #[must_use]
     pub fn to_nick(&self) -> Prefix {
-        Prefix::Nickname(
-            self.nick.to_string(),
-            self.user.to_string(),
-            self.cloak.to_string(),
-        )
+        Prefix::Nickname(self.nick(), self.user.to_string(), self.cloak())
     }
 
     #[must_use]
-    pub fn to_host_mask(&self) -> HostMask<'_> {
-        HostMask::new(&self.nick, &self.user, &self.cloak)
+    pub fn to_host_mask(&self) -> HostMask<'static> {
+        HostMask::new(&self.nick(), &self.user, &self.cloak()).into_owned()
     }
 }
 
-/// Currently just awaits client preamble (nick, user), but can be expanded to negotiate
-/// capabilities with the client in the future.
+/// The address family a client connected over, IPv4-mapped IPv6 addresses are considered to be
+/// IPv4.
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+pub enum AddressFamily {
+    V4,
+    V6,
+}
+
+impl From<IpAddr> for AddressFamily {
+    fn from(value: IpAddr) -> Self {
+        match value.to_canonical() {
+            IpAddr::V4(_) => Self::V4,
+            IpAddr::V6(_) => Self::V6,
+        }
+    }
+}
+
+impl Display for AddressFamily {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::V4 => f.write_str("IPv4"),
+            Self::V6 => f.write_str("IPv6"),
+        }
+    }
+}
+
+/// Drives the client through registration (see [`Negotiation`]), authenticating them and
+/// reserving their nick before they're handed off to a `Client` actor. Alongside the
+/// connection, any commands sent before registration that should be replayed by the `Client`
+/// are returned.
+///
+/// Once registered, the client is sorted into the first of `classes` matching their host mask,
+/// `classes` being empty if the client should stay in `class`.
 #[instrument(skip_all)]
+#[allow(clippy::too_many_arguments)]
 pub async fn negotiate_client_connection(
     s: &mut MessageStream,
-    write: &mut tokio_util::codec::FramedWrite<WriteHalf<TcpStream>, IrcCodec>,
+    write: &mut tokio_util::codec::FramedWrite<WriteHalf<ClientStream>, IrcCodec>,
     host: SocketAddr,
     persistence: &Addr<Persistence>,
+    server: &Addr<Server>,
+    bans: &NetworkBans,
     database: sqlx::Pool<sqlx::Any>,
     resolver: &TokioAsyncResolver,
     keys: &Keys,
-) -> Result<Option<InitiatedConnection>, ProtocolError> {
-    let mut request = ConnectionRequest {
-        host: Some(host),
-        ..ConnectionRequest::default()
-    };
+    class: Arc<ConnectionClass>,
+    classes: &[Arc<ConnectionClass>],
+    fallback_nick: FallbackNick,
+    certificate_fingerprint: Option<String>,
+    implicit_accounts: bool,
+) -> Result<Option<(Arc<InitiatedConnection>, Vec<Message>)>, ProtocolError> {
+    let mut negotiation = Negotiation::new(host, class).with_implicit_accounts(implicit_accounts);
+    let mut deferred = Vec::new();
+    let mut registered = false;
+    let mut sasl_authenticated = false;
 
     let authenticate_handle = Authenticate {
         selected_strategy: None,
         database: database.clone(),
+        certificate_fingerprint: certificate_fingerprint.clone(),
     }
     .start();
 
+    // each step of the negotiation has its own deadline, which is reset every time the client
+    // progresses onto the next step
+    let mut state = negotiation.state();
+    let mut deadline = Instant::now() + state.timeout().unwrap_or_default();
+
     // wait for the initiating commands from the user, giving us their NICK & USER and the user
     // requesting the server's capabilities - any clients not requesting capabilities are not
     // supported, as SASL auth is required
     let initiated = loop {
-        let Some(msg) = s.try_next().await? else {
+        let Ok(msg) = tokio::time::timeout_at(deadline, s.try_next()).await else {
+            return Err(ProtocolError::Io(Error::new(
+                ErrorKind::TimedOut,
+                format!("Timed out {state}"),
+            )));
+        };
+
+        let Some(msg) = msg? else {
             break None;
         };
 
-        #[allow(clippy::match_same_arms)]
-        match msg.command {
-            Command::PASS(_) => {}
-            Command::NICK(nick) => request.nick = Some(nick),
-            Command::USER(_user, _mode, real_name) => {
-                // we ignore the user here, as it will be set by the AUTHENTICATE command
-                request.real_name = Some(real_name);
-            }
-            Command::CAP(_, CapSubCommand::LIST | CapSubCommand::LS, _, _) => {
-                write
-                    .send(Message {
-                        tags: None,
-                        prefix: None,
-                        command: Command::CAP(
-                            Some("*".to_string()),
-                            CapSubCommand::LS,
-                            None,
-                            Some(Capability::SUPPORTED.join(" ")),
-                        ),
-                    })
-                    .await
-                    .unwrap();
-            }
-            Command::CAP(_, CapSubCommand::REQ, Some(arguments), None) => {
-                let mut acked = true;
-
-                for argument in arguments.split(' ') {
-                    acked = if argument == "sasl" {
-                        acked
-                    } else if let Ok(capability) = Capability::from_str(argument) {
-                        request.capabilities |= capability;
-                        acked
-                    } else {
-                        false
-                    };
+        for action in negotiation.handle(msg.command) {
+            match action {
+                Action::Reply(message) => write.send(message).await?,
+                Action::Defer(message) => deferred.push(message),
+                Action::Abort(reason) => {
+                    return Err(ProtocolError::Io(Error::new(
+                        ErrorKind::PermissionDenied,
+                        reason,
+                    )));
+                }
+                Action::Authenticate(msg) => {
+                    let result = authenticate_handle
+                        .send(AuthenticateMessage(msg))
+                        .await
+                        .map_err(|e| ProtocolError::Io(Error::new(ErrorKind::Other, e)))??;
+
+                    match result {
+                        AuthenticateResult::Reply(v) => {
+                            write.send(*v).await?;
+                        }
+                        AuthenticateResult::Done(username, user_id) => {
+                            validate_account(bans, &username)?;
+                            negotiation.authenticated(username, user_id);
+                            registered = true;
+                            sasl_authenticated = true;
+                            write.send(SaslSuccess::into_message()).await?;
+                        }
+                    }
                 }
+                Action::Identify(account, password) => {
+                    let response = match services::identify(&database, &account, &password).await {
+                        Some(user_id) => {
+                            validate_account(bans, &account)?;
+                            negotiation.authenticated(account.clone(), user_id);
+                            registered = true;
+                            NickServResponse::Identified(account)
+                        }
+                        None => NickServResponse::InvalidCredentials,
+                    };
 
-                write
-                    .send(AcknowledgedCapabilities(arguments, acked).into_message())
-                    .await?;
-            }
-            Command::AUTHENTICATE(msg) => {
-                match authenticate_handle
-                    .send(AuthenticateMessage(msg))
-                    .await
-                    .unwrap()?
-                {
-                    AuthenticateResult::Reply(v) => {
-                        write.send(*v).await?;
+                    for message in response.into_messages(negotiation.nick()) {
+                        write.send(message).await?;
                     }
-                    AuthenticateResult::Done(username, user_id) => {
-                        request.user = Some(username);
-                        request.user_id = Some(user_id);
-                        write.send(SaslSuccess::into_message()).await?;
+                }
+                Action::AuthenticateImplicitly(nick) => {
+                    match handle_implicit_authentication(&nick, &database).await? {
+                        Some(user_id) => {
+                            validate_account(bans, &nick)?;
+                            negotiation.authenticated(nick, user_id);
+                        }
+                        None => {
+                            write.send(NickNotOwnedByUser(nick).into_message()).await?;
+                            negotiation.reject_nick();
+                        }
                     }
                 }
             }
-            _ => {
-                warn!(?msg, "Client sent unknown command during negotiation");
+        }
+
+        // ensure the nick isn't already being used by another online session before we let the
+        // user register with it, if we're able to assign a fallback nick this is instead done
+        // once the user has registered
+        if negotiation.state() == NegotiationState::Registered
+            && fallback_nick == FallbackNick::Disabled
+        {
+            let availability = server
+                .send(CheckNickAvailability {
+                    nick: negotiation.nick().to_string(),
+                    client: None,
+                })
+                .await
+                .map_err(|e| ProtocolError::Io(Error::new(ErrorKind::Other, e)))?;
+
+            if !availability.is_available() {
+                for message in availability.into_messages("*") {
+                    write.send(message).await?;
+                }
+
+                negotiation.reject_nick();
             }
-        };
+        }
 
-        match InitiatedConnection::new(std::mem::take(&mut request), keys) {
+        negotiation = match negotiation.complete(keys) {
             Ok(v) => break Some(v),
-            Err(v) => {
-                // connection isn't fully initiated yet...
-                request = v;
-            }
+            Err(v) => v,
+        };
+
+        if negotiation.state() != state {
+            state = negotiation.state();
+            deadline = Instant::now() + state.timeout().unwrap_or_default();
         }
     };
 
@@ -229,6 +418,13 @@ pub async fn negotiate_client_connection(
         return Ok(None);
     };
 
+    initiated.certificate_fingerprint = certificate_fingerprint;
+    initiated.set_registered(registered);
+    initiated.account_created =
+        crate::database::fetch_account_created(&database, initiated.user_id)
+            .await
+            .map_err(|e| ProtocolError::Io(Error::new(ErrorKind::Other, e)))?;
+
     if let Ok(Ok(v)) = tokio::time::timeout(
         Duration::from_millis(250),
         resolver.reverse_lookup(host.ip().to_canonical()),
@@ -241,21 +437,31 @@ pub async fn negotiate_client_connection(
             .map(|v| v.to_utf8().trim_end_matches('.').to_string());
     }
 
-    write
-        .send(ConnectionSuccess(initiated.clone()).into_message())
-        .await?;
+    if !classes.is_empty() {
+        // classes match on the user's real host rather than their cloak
+        let real_host = initiated
+            .resolved_host
+            .clone()
+            .unwrap_or_else(|| host.ip().to_canonical().to_string());
+        let mask = HostMask::new(&initiated.nick(), &initiated.user, &real_host);
+        initiated.class = Config::find_class(classes, host.ip(), Some(&mask));
+    }
 
-    let reserved_nick = persistence
-        .send(ReserveNick {
-            user_id: initiated.user_id,
-            nick: initiated.nick.clone(),
-        })
+    if let Some(reason) = initiated.class.rejects_registration(sasl_authenticated) {
+        return Err(ProtocolError::Io(Error::new(
+            ErrorKind::PermissionDenied,
+            reason,
+        )));
+    }
+
+    let requested_nick = initiated.nick();
+    let assigned_nick = assign_nick(&initiated, persistence, server, fallback_nick)
         .await
         .map_err(|e| ProtocolError::Io(Error::new(ErrorKind::InvalidData, e)))?;
 
-    if !reserved_nick {
+    if !assigned_nick {
         write
-            .send(NickNotOwnedByUser(initiated.nick).into_message())
+            .send(NickNotOwnedByUser(initiated.nick()).into_message())
             .await?;
 
         return Err(ProtocolError::Io(Error::new(
@@ -264,7 +470,107 @@ pub async fn negotiate_client_connection(
         )));
     }
 
-    Ok(Some(initiated))
+    if initiated.nick() != requested_nick {
+        // let the user know they've been given a different nick before they're welcomed
+        write
+            .send(Message {
+                tags: None,
+                prefix: Some(Prefix::Nickname(
+                    requested_nick,
+                    initiated.user.to_string(),
+                    initiated.cloak(),
+                )),
+                command: Command::NICK(initiated.nick()),
+            })
+            .await?;
+    }
+
+    let initiated = Arc::new(initiated);
+
+    write
+        .send(ConnectionSuccess(initiated.clone()).into_message())
+        .await?;
+
+    Ok(Some((initiated, deferred)))
+}
+
+/// Rejects banned accounts as soon as we know who the user is, so they can't evade the ban by
+/// connecting from another host.
+fn validate_account(bans: &NetworkBans, username: &str) -> Result<(), ProtocolError> {
+    if let Some(reason) = bans.check_account(username) {
+        return Err(ProtocolError::Io(Error::new(
+            ErrorKind::PermissionDenied,
+            reason,
+        )));
+    }
+
+    Ok(())
+}
+
+/// Reserves the user's requested nick for their account, falling back to a generated nick for
+/// the session if it's already taken and fallback nicks are enabled. Returns false if no nick
+/// could be assigned to the user.
+async fn assign_nick(
+    initiated: &InitiatedConnection,
+    persistence: &Addr<Persistence>,
+    server: &Addr<Server>,
+    fallback_nick: FallbackNick,
+) -> Result<bool, MailboxError> {
+    let requested_nick = initiated.nick();
+    let mut nick = requested_nick.clone();
+    let mut attempt = 0;
+
+    loop {
+        // if fallbacks are disabled, the nick was already checked for online sessions during
+        // negotiation
+        let available = fallback_nick == FallbackNick::Disabled
+            || server
+                .send(CheckNickAvailability {
+                    nick: nick.clone(),
+                    client: None,
+                })
+                .await?
+                .is_available();
+
+        if available && claim_nick(persistence, initiated.user_id, &nick, attempt > 0).await? {
+            initiated.set_nick(nick);
+            return Ok(true);
+        }
+
+        let Some(fallback) = fallback_nick.generate(&requested_nick, attempt) else {
+            return Ok(false);
+        };
+
+        nick = fallback;
+        attempt += 1;
+    }
+}
+
+/// Claims `nick` for the user's session. The requested nick is reserved for the user's account,
+/// whereas generated fallback nicks are only used for the session, as long as no other account
+/// owns them, so the account doesn't end up permanently owning every nick it fell back to.
+async fn claim_nick(
+    persistence: &Addr<Persistence>,
+    user_id: UserId,
+    nick: &str,
+    fallback: bool,
+) -> Result<bool, MailboxError> {
+    if fallback {
+        let owner = persistence
+            .send(FetchAccountByNick {
+                nick: nick.to_string(),
+            })
+            .await?;
+
+        return Ok(!owner.is_some_and(|(owner, _)| owner != user_id));
+    }
+
+    persistence
+        .send(ReserveNick {
+            user_id,
+            nick: nick.to_string(),
+        })
+        .await
 }
 
 pub struct NickNotOwnedByUser(pub String);
@@ -311,6 +617,14 @@ bitflags! {
     pub struct Capability: u32 {
         const USERHOST_IN_NAMES = 0b0000_0000_0000_0000_0000_0000_0000_0001;
         const SERVER_TIME       = 0b0000_0000_0000_0000_0000_0000_0000_0010;
+        const AWAY_NOTIFY       = 0b0000_0000_0000_0000_0000_0000_0000_0100;
+        const MESSAGE_TAGS      = 0b0000_0000_0000_0000_0000_0000_0000_1000;
+        const ECHO_MESSAGE      = 0b0000_0000_0000_0000_0000_0000_0001_0000;
+        const BATCH             = 0b0000_0000_0000_0000_0000_0000_0010_0000;
+        const CHATHISTORY       = 0b0000_0000_0000_0000_0000_0000_0100_0000;
+        const MOTD_CHANGED      = 0b0000_0000_0000_0000_0000_0000_1000_0000;
+        const MESSAGE_REDACTION = 0b0000_0000_0000_0000_0000_0001_0000_0000;
+        const MULTI_PREFIX      = 0b0000_0000_0000_0000_0000_0010_0000_0000;
     }
 
     #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Default)]
@@ -319,6 +633,28 @@ bitflags! {
         const WALLOPS        = 0b0000_0000_0000_0000_0000_0000_0000_0001;
         /// o - operator flag
         const OPER           = 0b0000_0000_0000_0000_0000_0000_0000_0010;
+        /// p - user's channel list is hidden from WHOIS for non-operators
+        const PRIVATE        = 0b0000_0000_0000_0000_0000_0000_0000_0100;
+        /// g - private messages are only accepted from users on the user's accept list
+        const CALLER_ID      = 0b0000_0000_0000_0000_0000_0000_0000_1000;
+        /// i - user is hidden from WHO and NAMES for users who don't share the channel
+        const INVISIBLE      = 0b0000_0000_0000_0000_0000_0000_0001_0000;
+    }
+}
+
+impl UserMode {
+    /// Maps a mode sent by the client to a mode the user is allowed to set on themselves,
+    /// returning `None` if the mode is unknown or can't be set by the user. Operator status
+    /// is only granted through `OPER`, so isn't settable.
+    #[must_use]
+    pub const fn from_user_settable(mode: &irc_proto::UserMode) -> Option<Self> {
+        match mode {
+            irc_proto::UserMode::Invisible => Some(Self::INVISIBLE),
+            irc_proto::UserMode::Wallops => Some(Self::WALLOPS),
+            irc_proto::UserMode::Unknown('p') => Some(Self::PRIVATE),
+            irc_proto::UserMode::Unknown('g') => Some(Self::CALLER_ID),
+            _ => None,
+        }
     }
 }
 
@@ -326,14 +662,26 @@ impl Display for UserMode {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         write!(f, "+")?;
 
+        if self.contains(Self::INVISIBLE) {
+            write!(f, "i")?;
+        }
+
         if self.contains(Self::WALLOPS) {
             write!(f, "w")?;
         }
 
+        if self.contains(Self::CALLER_ID) {
+            write!(f, "g")?;
+        }
+
         if self.contains(Self::OPER) {
             write!(f, "o")?;
         }
 
+        if self.contains(Self::PRIVATE) {
+            write!(f, "p")?;
+        }
+
         Ok(())
     }
 }
@@ -342,8 +690,24 @@ impl Capability {
     pub const SUPPORTED: &'static [&'static str] = &[
         "userhost-in-names",
         "server-time",
+        "away-notify",
+        "message-tags",
+        "echo-message",
+        "batch",
+        "draft/chathistory",
+        "draft/motd-changed",
+        "draft/message-redaction",
+        "multi-prefix",
         concatcp!("sasl=", AuthStrategy::SUPPORTED),
     ];
+
+    /// The names of the capabilities in the set, as they were negotiated by the client.
+    pub fn names(self) -> impl Iterator<Item = &'static str> {
+        Self::SUPPORTED
+            .iter()
+            .copied()
+            .filter(move |name| name.parse::<Self>().is_ok_and(|v| self.contains(v)))
+    }
 }
 
 impl FromStr for Capability {
@@ -353,7 +717,54 @@ impl FromStr for Capability {
         match s {
             "userhost-in-names" => Ok(Self::USERHOST_IN_NAMES),
             "server-time" => Ok(Self::SERVER_TIME),
+            "away-notify" => Ok(Self::AWAY_NOTIFY),
+            "message-tags" => Ok(Self::MESSAGE_TAGS),
+            "echo-message" => Ok(Self::ECHO_MESSAGE),
+            "batch" => Ok(Self::BATCH),
+            "draft/chathistory" => Ok(Self::CHATHISTORY),
+            "draft/motd-changed" => Ok(Self::MOTD_CHANGED),
+            "draft/message-redaction" => Ok(Self::MESSAGE_REDACTION),
+            "multi-prefix" => Ok(Self::MULTI_PREFIX),
             _ => Err(()),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use actix::Actor;
+
+    use super::{claim_nick, UserId};
+    use crate::{
+        database::{create_user_or_fetch_password_hash, in_memory},
+        persistence::Persistence,
+    };
+
+    #[actix_rt::test]
+    async fn fallback_nicks_are_not_reserved() {
+        let database = in_memory().await;
+        let (alice, _) = create_user_or_fetch_password_hash(&database, "alice", b"")
+            .await
+            .unwrap();
+        let (bob, _) = create_user_or_fetch_password_hash(&database, "bob", b"")
+            .await
+            .unwrap();
+
+        let persistence =
+            Persistence::new(database, Duration::from_secs(0), 0, Duration::from_secs(0)).start();
+
+        assert!(claim_nick(&persistence, UserId(alice), "Guest12345", true)
+            .await
+            .unwrap());
+
+        // once alice has left, the nick is free for another account to take
+        assert!(claim_nick(&persistence, UserId(bob), "Guest12345", false)
+            .await
+            .unwrap());
+        assert!(!claim_nick(&persistence, UserId(alice), "Guest12345", true)
+            .await
+            .unwrap());
+    }
+}
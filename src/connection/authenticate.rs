@@ -15,7 +15,7 @@ use std::{
 use actix::{Actor, ActorContext, Context, Handler, Message, ResponseFuture};
 use argon2::PasswordHash;
 use base64::{prelude::BASE64_STANDARD, Engine};
-use futures::TryFutureExt;
+use futures::{FutureExt, TryFutureExt};
 use irc_proto::Command;
 
 use crate::{
@@ -29,6 +29,8 @@ use crate::{
 pub struct Authenticate {
     pub selected_strategy: Option<AuthStrategy>,
     pub database: sqlx::Pool<sqlx::Any>,
+    /// Fingerprint of the TLS client certificate the user connected with, used by `EXTERNAL`.
+    pub certificate_fingerprint: Option<String>,
 }
 
 impl Actor for Authenticate {
@@ -67,16 +69,24 @@ impl Handler<AuthenticateMessage> for Authenticate {
             ))));
         }
 
-        match selected_strategy {
-            AuthStrategy::Plain => Box::pin(
-                handle_plain_authentication(msg.0, self.database.clone()).map_ok(|v| {
-                    v.map_or_else(
-                        || AuthenticateResult::Reply(Box::new(SaslFail::into_message())),
-                        |(username, user_id)| AuthenticateResult::Done(username, user_id),
-                    )
-                }),
-            ),
-        }
+        let result = match selected_strategy {
+            AuthStrategy::Plain => {
+                handle_plain_authentication(msg.0, self.database.clone()).boxed_local()
+            }
+            AuthStrategy::External => handle_external_authentication(
+                msg.0,
+                self.certificate_fingerprint.clone(),
+                self.database.clone(),
+            )
+            .boxed_local(),
+        };
+
+        Box::pin(result.map_ok(|v| {
+            v.map_or_else(
+                || AuthenticateResult::Reply(Box::new(SaslFail::into_message())),
+                |(username, user_id)| AuthenticateResult::Done(username, user_id),
+            )
+        }))
     }
 }
 
@@ -104,8 +114,9 @@ pub async fn handle_plain_authentication(
         return Err(Error::new(ErrorKind::InvalidData, "bad plain message"));
     };
 
-    // we don't want any ambiguity here, so the two identities need to match
-    if authorization_identity != authentication_identity {
+    // we don't want any ambiguity here, so the two identities need to match, though an empty
+    // authorization identity means the client is acting as the identity they authenticated as
+    if !authorization_identity.is_empty() && authorization_identity != authentication_identity {
         return Err(Error::new(ErrorKind::InvalidData, "identity mismatch"));
     }
 
@@ -130,6 +141,70 @@ pub async fn handle_plain_authentication(
     }
 }
 
+/// Attempts to handle an `AUTHENTICATE` command for the `EXTERNAL` authentication method.
+///
+/// The user is identified by the fingerprint of the certificate they presented during the TLS
+/// handshake, which must have been added to their account beforehand. If the client gives an
+/// authorization identity, it must match the account owning the certificate.
+///
+/// This function will return the authenticated user id and username, or None if the client
+/// didn't present a certificate or the certificate isn't known.
+pub async fn handle_external_authentication(
+    arguments: String,
+    certificate_fingerprint: Option<String>,
+    database: sqlx::Pool<sqlx::Any>,
+) -> Result<Option<(String, UserId)>, Error> {
+    let authorization_identity = if arguments == "+" {
+        None
+    } else {
+        let decoded = BASE64_STANDARD
+            .decode(&arguments)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+
+        Some(String::from_utf8(decoded).map_err(|e| Error::new(ErrorKind::InvalidData, e))?)
+    };
+
+    let Some(certificate_fingerprint) = certificate_fingerprint else {
+        return Ok(None);
+    };
+
+    let Some((user_id, username)) =
+        crate::database::fetch_user_by_certificate(&database, &certificate_fingerprint)
+            .await
+            .unwrap()
+    else {
+        return Ok(None);
+    };
+
+    if authorization_identity.is_some_and(|identity| identity != username) {
+        return Ok(None);
+    }
+
+    Ok(Some((username, UserId(user_id))))
+}
+
+/// Logs a client that registered without SASL into the account named after their nick, which is
+/// created without a password if it doesn't already exist. Only used when
+/// `CompatConfig::implicit_accounts` is enabled.
+///
+/// This function will return the user id, or None if the account has a password set.
+pub async fn handle_implicit_authentication(
+    nick: &str,
+    database: &sqlx::Pool<sqlx::Any>,
+) -> Result<Option<UserId>, Error> {
+    let (user_id, password_hash) =
+        crate::database::create_user_or_fetch_password_hash(database, nick, b"")
+            .await
+            .unwrap();
+    let password_hash = PasswordHash::new(&password_hash).unwrap();
+
+    match verify_password(b"", &password_hash) {
+        Ok(()) => Ok(Some(UserId(user_id))),
+        Err(argon2::password_hash::Error::Password) => Ok(None),
+        Err(e) => Err(Error::new(ErrorKind::InvalidData, e.to_string())),
+    }
+}
+
 pub enum AuthenticateResult {
     Reply(Box<irc_proto::Message>),
     Done(String, UserId),
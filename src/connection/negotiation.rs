@@ -0,0 +1,586 @@
+use std::{fmt::Display, net::SocketAddr, str::FromStr, sync::Arc, time::Duration};
+
+use irc_proto::{CapSubCommand, Command, Message, Response};
+use tracing::warn;
+
+use crate::{
+    config::ConnectionClass,
+    connection::{
+        sasl::{SaslAlreadyAuthenticated, SaslFail},
+        AcknowledgedCapabilities, Capability, ConnectionRequest, InitiatedConnection, UserId,
+    },
+    keys::Keys,
+    proto::{LocalCommand, NickServCommand},
+};
+
+/// The stage of registration a connecting client is currently at.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum NegotiationState {
+    /// Waiting for the client to start capability negotiation, which is required for SASL.
+    AwaitingCap,
+    /// Capability negotiation has started, waiting for the client to authenticate using SASL.
+    AwaitingSasl,
+    /// The client has authenticated, waiting for their `NICK`, `USER` and `CAP END`.
+    AwaitingNickUser,
+    /// The client has fully registered and can be handed off to a `Client` actor.
+    Registered,
+}
+
+impl NegotiationState {
+    /// The amount of time a client may spend in this state before they're disconnected.
+    #[must_use]
+    pub const fn timeout(self) -> Option<Duration> {
+        match self {
+            Self::AwaitingCap | Self::AwaitingNickUser => Some(Duration::from_secs(15)),
+            Self::AwaitingSasl => Some(Duration::from_secs(60)),
+            Self::Registered => None,
+        }
+    }
+}
+
+impl Display for NegotiationState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::AwaitingCap => f.write_str("waiting for capability negotiation"),
+            Self::AwaitingSasl => f.write_str("waiting for SASL authentication"),
+            Self::AwaitingNickUser => f.write_str("waiting for NICK, USER and CAP END"),
+            Self::Registered => f.write_str("registered"),
+        }
+    }
+}
+
+/// Something the caller of [`Negotiation::handle`] needs to do on behalf of the state machine.
+#[derive(Debug)]
+pub enum Action {
+    /// Send the message back to the client.
+    Reply(Message),
+    /// Forward the payload of an `AUTHENTICATE` to the SASL authenticator, calling
+    /// [`Negotiation::authenticated`] once the user has successfully authenticated.
+    Authenticate(String),
+    /// Check the credentials given by `NS IDENTIFY` for the account, calling
+    /// [`Negotiation::authenticated`] if they're correct.
+    Identify(String, String),
+    /// The client registered without SASL and should be logged into the implicit account for
+    /// the nick, calling [`Negotiation::authenticated`] if successful or
+    /// [`Negotiation::reject_nick`] if the nick belongs to another account.
+    AuthenticateImplicitly(String),
+    /// The client broke the registration flow and should be disconnected.
+    Abort(&'static str),
+    /// The client sent a command that can't be handled until they've registered, the caller
+    /// should replay it once registration completes.
+    Defer(Message),
+}
+
+/// Drives a client from first connecting through to being fully registered, keeping track of
+/// the details they've given us so far.
+pub struct Negotiation {
+    state: NegotiationState,
+    request: ConnectionRequest,
+    cap_started: bool,
+    sasl_requested: bool,
+    cap_ended: bool,
+    deferred: usize,
+    implicit_accounts: bool,
+    implicit_requested: bool,
+}
+
+impl Negotiation {
+    /// Maximum amount of `JOIN`s sent before registration that we'll replay once the client has
+    /// registered.
+    pub const MAX_DEFERRED_JOINS: usize = 5;
+
+    #[must_use]
+    pub fn new(host: SocketAddr, class: Arc<ConnectionClass>) -> Self {
+        Self {
+            state: NegotiationState::AwaitingCap,
+            request: ConnectionRequest {
+                host: Some(host),
+                class,
+                ..ConnectionRequest::default()
+            },
+            cap_started: false,
+            sasl_requested: false,
+            cap_ended: false,
+            deferred: 0,
+            implicit_accounts: false,
+            implicit_requested: false,
+        }
+    }
+
+    /// Allows the client to register without SASL, see `CompatConfig::implicit_accounts`.
+    #[must_use]
+    pub const fn with_implicit_accounts(mut self, enabled: bool) -> Self {
+        self.implicit_accounts = enabled;
+        self
+    }
+
+    #[must_use]
+    pub const fn state(&self) -> NegotiationState {
+        self.state
+    }
+
+    /// Handles a single command sent by the client, returning anything the caller needs to
+    /// action on our behalf.
+    pub fn handle(&mut self, command: Command) -> Vec<Action> {
+        let mut actions = Vec::new();
+
+        #[allow(clippy::match_same_arms)]
+        match command {
+            Command::PASS(_) | Command::PONG(_, _) => {}
+            Command::PING(v, _) => {
+                // clients may ping us to measure latency or keep the connection alive during
+                // slow authentication flows
+                actions.push(Action::Reply(Message {
+                    tags: None,
+                    prefix: None,
+                    command: Command::PONG(v, None),
+                }));
+            }
+            Command::NICK(nick) => self.request.nick = Some(nick),
+            Command::USER(_user, _mode, real_name) => {
+                // we ignore the user here, as it will be set by the AUTHENTICATE command
+                self.request.real_name = Some(real_name);
+            }
+            Command::CAP(_, CapSubCommand::LIST | CapSubCommand::LS, _, _) => {
+                self.cap_started = true;
+                self.cap_ended = false;
+                actions.push(Action::Reply(Message {
+                    tags: None,
+                    prefix: None,
+                    command: Command::CAP(
+                        Some("*".to_string()),
+                        CapSubCommand::LS,
+                        None,
+                        Some(Capability::SUPPORTED.join(" ")),
+                    ),
+                }));
+            }
+            Command::CAP(_, CapSubCommand::REQ, Some(arguments), None) => {
+                self.cap_started = true;
+                self.cap_ended = false;
+
+                let mut acked = true;
+                let mut capabilities = Capability::empty();
+                let mut sasl_requested = false;
+
+                for argument in arguments.split(' ') {
+                    if argument == "sasl" {
+                        sasl_requested = true;
+                    } else if let Ok(capability) = Capability::from_str(argument) {
+                        capabilities |= capability;
+                    } else {
+                        acked = false;
+                    }
+                }
+
+                // requests are all-or-nothing, so only apply them if we're acking them
+                if acked {
+                    self.request.capabilities |= capabilities;
+                    self.sasl_requested |= sasl_requested;
+                }
+
+                actions.push(Action::Reply(
+                    AcknowledgedCapabilities(arguments, acked).into_message(),
+                ));
+            }
+            Command::CAP(_, CapSubCommand::END, _, _) => {
+                if self.request.user_id.is_none() && !self.implicit_accounts {
+                    actions.push(Action::Abort("You must use SASL to connect to this server"));
+                }
+
+                self.cap_ended = true;
+            }
+            Command::AUTHENTICATE(_) if self.request.user_id.is_some() => {
+                actions.push(Action::Reply(
+                    SaslAlreadyAuthenticated(self.nick().to_string()).into_message(),
+                ));
+            }
+            Command::AUTHENTICATE(_) if !self.sasl_requested => {
+                actions.push(Action::Reply(SaslFail::into_message()));
+            }
+            Command::AUTHENTICATE(msg) => actions.push(Action::Authenticate(msg)),
+            Command::NICKSERV(args) => self.handle_nickserv(args, &mut actions),
+            Command::Raw(command, args) if command == "NS" => {
+                self.handle_nickserv(args, &mut actions);
+            }
+            command @ Command::JOIN(..) if self.deferred < Self::MAX_DEFERRED_JOINS => {
+                self.deferred += 1;
+                actions.push(Action::Defer(Message {
+                    tags: None,
+                    prefix: None,
+                    command,
+                }));
+            }
+            command => {
+                warn!(?command, "Client sent unknown command during negotiation");
+                actions.push(Action::Reply(
+                    NotRegistered(self.nick().to_string()).into_message(),
+                ));
+            }
+        }
+
+        if self.should_authenticate_implicitly() {
+            self.implicit_requested = true;
+            actions.push(Action::AuthenticateImplicitly(self.nick().to_string()));
+        }
+
+        self.advance();
+
+        actions
+    }
+
+    /// Handles an `NS` command sent before registration, of which only `IDENTIFY` is accepted to
+    /// let clients without SASL support log in.
+    fn handle_nickserv(&self, args: Vec<String>, actions: &mut Vec<Action>) {
+        match LocalCommand::try_from(("NS".to_string(), args)) {
+            Ok(LocalCommand::NickServ(NickServCommand::Identify(..)))
+                if self.request.user_id.is_some() =>
+            {
+                actions.push(Action::Reply(
+                    SaslAlreadyAuthenticated(self.nick().to_string()).into_message(),
+                ));
+            }
+            Ok(LocalCommand::NickServ(NickServCommand::Identify(account, password))) => {
+                let account = account.unwrap_or_else(|| self.nick().to_string());
+                actions.push(Action::Identify(account, password));
+            }
+            _ => actions.push(Action::Reply(
+                NotRegistered(self.nick().to_string()).into_message(),
+            )),
+        }
+    }
+
+    /// Whether the client has given us everything we need to register them without SASL, and
+    /// we're yet to try logging them in.
+    fn should_authenticate_implicitly(&self) -> bool {
+        self.implicit_accounts
+            && !self.implicit_requested
+            && self.request.user_id.is_none()
+            && self.request.nick.is_some()
+            && self.request.real_name.is_some()
+            && (!self.cap_started || self.cap_ended)
+    }
+
+    /// Called once the client has successfully authenticated via SASL.
+    pub fn authenticated(&mut self, username: String, user_id: UserId) {
+        self.request.user = Some(username);
+        self.request.user_id = Some(user_id);
+        self.advance();
+    }
+
+    /// Builds the client's connection once they've registered, or returns the negotiation
+    /// back if registration hasn't yet completed.
+    pub fn complete(self, keys: &Keys) -> Result<InitiatedConnection, Self> {
+        if self.state != NegotiationState::Registered {
+            return Err(self);
+        }
+
+        let Self {
+            state,
+            request,
+            cap_started,
+            sasl_requested,
+            cap_ended,
+            deferred,
+            implicit_accounts,
+            implicit_requested,
+        } = self;
+
+        InitiatedConnection::new(request, keys).map_err(|request| Self {
+            state,
+            request,
+            cap_started,
+            sasl_requested,
+            cap_ended,
+            deferred,
+            implicit_accounts,
+            implicit_requested,
+        })
+    }
+
+    /// Called when the nick the client requested can't be used, the client will need to send
+    /// another `NICK` before registration can complete.
+    pub fn reject_nick(&mut self) {
+        self.request.nick = None;
+        self.implicit_requested = false;
+        self.advance();
+    }
+
+    /// The nick the client has requested so far, for use in replies.
+    #[must_use]
+    pub fn nick(&self) -> &str {
+        self.request.nick.as_deref().unwrap_or("*")
+    }
+
+    fn advance(&mut self) {
+        let request = &self.request;
+
+        self.state = if request.user_id.is_none() {
+            if self.cap_started && !self.cap_ended {
+                NegotiationState::AwaitingSasl
+            } else {
+                NegotiationState::AwaitingCap
+            }
+        } else if request.nick.is_none()
+            || request.real_name.is_none()
+            || (self.cap_started && !self.cap_ended)
+        {
+            NegotiationState::AwaitingNickUser
+        } else {
+            NegotiationState::Registered
+        };
+    }
+}
+
+/// Returned to the client when they send a command that requires them to be registered.
+pub struct NotRegistered(pub String);
+
+impl NotRegistered {
+    #[must_use]
+    pub fn into_message(self) -> Message {
+        Message {
+            tags: None,
+            prefix: None,
+            command: Command::Response(
+                Response::ERR_NOTREGISTERED,
+                vec![self.0, "You have not registered".to_string()],
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use irc_proto::{CapSubCommand, Command, Message, Response};
+
+    use super::{Action, Negotiation, NegotiationState};
+    use crate::{connection::UserId, keys::Keys};
+
+    fn negotiation() -> Negotiation {
+        Negotiation::new("127.0.0.1:6667".parse().unwrap(), Arc::default())
+    }
+
+    fn run(negotiation: &mut Negotiation, lines: &[&str]) -> Vec<Action> {
+        lines
+            .iter()
+            .flat_map(|line| negotiation.handle(line.parse::<Message>().unwrap().command))
+            .collect()
+    }
+
+    #[test]
+    fn full_registration() {
+        let mut negotiation = negotiation();
+        assert_eq!(negotiation.state(), NegotiationState::AwaitingCap);
+
+        run(
+            &mut negotiation,
+            &["CAP LS 302", "NICK test", "USER test 0 * :Test"],
+        );
+        assert_eq!(negotiation.state(), NegotiationState::AwaitingSasl);
+
+        let actions = run(&mut negotiation, &["CAP REQ :sasl", "AUTHENTICATE PLAIN"]);
+        assert!(matches!(
+            &actions[..],
+            [Action::Reply(_), Action::Authenticate(v)] if v == "PLAIN"
+        ));
+
+        negotiation.authenticated("test".to_string(), UserId(1));
+        assert_eq!(negotiation.state(), NegotiationState::AwaitingNickUser);
+
+        run(&mut negotiation, &["CAP END"]);
+        assert_eq!(negotiation.state(), NegotiationState::Registered);
+
+        let connection = negotiation
+            .complete(&Keys { ip_salt: [0; 32] })
+            .unwrap_or_else(|_| panic!("registration should have completed"));
+        assert_eq!(connection.nick(), "test");
+        assert_eq!(connection.user, "test");
+        assert_eq!(connection.real_name, "Test");
+    }
+
+    #[test]
+    fn rejected_nick_must_be_replaced() {
+        let mut negotiation = negotiation();
+
+        run(
+            &mut negotiation,
+            &["CAP REQ :sasl", "NICK test", "USER test 0 * :Test"],
+        );
+        negotiation.authenticated("test".to_string(), UserId(1));
+        run(&mut negotiation, &["CAP END"]);
+        assert_eq!(negotiation.state(), NegotiationState::Registered);
+
+        negotiation.reject_nick();
+        assert_eq!(negotiation.state(), NegotiationState::AwaitingNickUser);
+        assert_eq!(negotiation.nick(), "*");
+
+        run(&mut negotiation, &["NICK test_"]);
+        assert_eq!(negotiation.state(), NegotiationState::Registered);
+        assert_eq!(negotiation.nick(), "test_");
+    }
+
+    #[test]
+    fn commands_before_registration() {
+        let mut negotiation = negotiation();
+
+        let actions = run(&mut negotiation, &["PRIVMSG someone :hello"]);
+        assert!(matches!(
+            &actions[..],
+            [Action::Reply(Message {
+                command: Command::Response(Response::ERR_NOTREGISTERED, _),
+                ..
+            })]
+        ));
+
+        let lines = vec!["JOIN #channel"; Negotiation::MAX_DEFERRED_JOINS + 1];
+        let actions = run(&mut negotiation, &lines);
+        assert_eq!(
+            actions
+                .iter()
+                .filter(|v| matches!(v, Action::Defer(_)))
+                .count(),
+            Negotiation::MAX_DEFERRED_JOINS
+        );
+        assert!(matches!(actions.last(), Some(Action::Reply(_))));
+    }
+
+    #[test]
+    fn ping_before_registration() {
+        let mut negotiation = negotiation();
+
+        let actions = run(&mut negotiation, &["PING :123", "PONG :456"]);
+        assert!(matches!(
+            &actions[..],
+            [Action::Reply(Message { command: Command::PONG(v, None), .. })] if v == "123"
+        ));
+        assert_eq!(negotiation.state(), NegotiationState::AwaitingCap);
+    }
+
+    #[test]
+    fn registration_requires_cap_end() {
+        let mut negotiation = negotiation();
+
+        run(
+            &mut negotiation,
+            &["CAP REQ :sasl", "NICK test", "USER test 0 * :Test"],
+        );
+        negotiation.authenticated("test".to_string(), UserId(1));
+        assert_eq!(negotiation.state(), NegotiationState::AwaitingNickUser);
+
+        let negotiation = negotiation
+            .complete(&Keys { ip_salt: [0; 32] })
+            .err()
+            .unwrap();
+        assert_eq!(negotiation.state(), NegotiationState::AwaitingNickUser);
+    }
+
+    #[test]
+    fn cap_end_without_sasl_aborts() {
+        let mut negotiation = negotiation();
+
+        let actions = run(&mut negotiation, &["CAP LS", "NICK test", "CAP END"]);
+        assert!(matches!(&actions[..], [Action::Reply(_), Action::Abort(_)]));
+    }
+
+    #[test]
+    fn implicit_accounts() {
+        let mut negotiation = negotiation().with_implicit_accounts(true);
+
+        let actions = run(&mut negotiation, &["NICK test", "USER test 0 * :Test"]);
+        assert!(matches!(
+            &actions[..],
+            [Action::AuthenticateImplicitly(v)] if v == "test"
+        ));
+
+        negotiation.reject_nick();
+        let actions = run(&mut negotiation, &["NICK test_"]);
+        assert!(matches!(
+            &actions[..],
+            [Action::AuthenticateImplicitly(v)] if v == "test_"
+        ));
+
+        negotiation.authenticated("test_".to_string(), UserId(1));
+        assert_eq!(negotiation.state(), NegotiationState::Registered);
+
+        let mut negotiation = negotiation().with_implicit_accounts(true);
+
+        let actions = run(
+            &mut negotiation,
+            &["CAP LS 302", "NICK test", "USER test 0 * :Test"],
+        );
+        assert!(matches!(&actions[..], [Action::Reply(_)]));
+
+        let actions = run(&mut negotiation, &["CAP END"]);
+        assert!(matches!(&actions[..], [Action::AuthenticateImplicitly(_)]));
+
+        negotiation.authenticated("test".to_string(), UserId(1));
+        assert_eq!(negotiation.state(), NegotiationState::Registered);
+    }
+
+    #[test]
+    fn identify_without_sasl() {
+        let mut negotiation = negotiation();
+
+        let actions = run(
+            &mut negotiation,
+            &["NICK test", "USER test 0 * :Test", "NS IDENTIFY hunter2"],
+        );
+        assert!(matches!(
+            &actions[..],
+            [Action::Identify(account, password)] if account == "test" && password == "hunter2"
+        ));
+
+        let actions = run(&mut negotiation, &["NS REGISTER hunter2"]);
+        assert!(matches!(&actions[..], [Action::Reply(_)]));
+
+        negotiation.authenticated("test".to_string(), UserId(1));
+        assert_eq!(negotiation.state(), NegotiationState::Registered);
+
+        let mut negotiation = negotiation();
+
+        let actions = run(
+            &mut negotiation,
+            &["CAP LS 302", "NICK test", "NS IDENTIFY alice hunter2"],
+        );
+        assert!(matches!(
+            &actions[..],
+            [Action::Reply(_), Action::Identify(account, _)] if account == "alice"
+        ));
+    }
+
+    #[test]
+    fn authenticate_requires_sasl_cap() {
+        let mut negotiation = negotiation();
+
+        let actions = run(&mut negotiation, &["CAP LS", "AUTHENTICATE PLAIN"]);
+        assert!(matches!(
+            &actions[..],
+            [
+                Action::Reply(_),
+                Action::Reply(Message {
+                    command: Command::Response(..),
+                    ..
+                })
+            ]
+        ));
+    }
+
+    #[test]
+    fn nak_is_all_or_nothing() {
+        let mut negotiation = negotiation();
+
+        let actions = run(&mut negotiation, &["CAP REQ :sasl unknown-capability"]);
+        assert!(matches!(
+            &actions[..],
+            [Action::Reply(Message {
+                command: Command::CAP(_, CapSubCommand::NAK, _, _),
+                ..
+            })]
+        ));
+
+        let actions = run(&mut negotiation, &["AUTHENTICATE PLAIN"]);
+        assert!(!matches!(&actions[..], [Action::Authenticate(_)]));
+    }
+}
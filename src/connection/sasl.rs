@@ -1,6 +1,7 @@
 use std::{
     io::{Error, ErrorKind},
     str::FromStr,
+    sync::Arc,
 };
 
 use irc_proto::{Command, Message, Response};
@@ -11,11 +12,14 @@ use crate::connection::InitiatedConnection;
 #[derive(Copy, Clone, Debug)]
 pub enum AuthStrategy {
     Plain,
+    /// Authenticates the user by the fingerprint of the TLS client certificate they connected
+    /// with.
+    External,
 }
 
 impl AuthStrategy {
     /// A list of all supported SASL strategies.
-    pub const SUPPORTED: &'static str = "PLAIN";
+    pub const SUPPORTED: &'static str = "PLAIN,EXTERNAL";
 }
 
 /// Parse a SASL strategy from the wire.
@@ -25,6 +29,7 @@ impl FromStr for AuthStrategy {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
             "PLAIN" => Ok(Self::Plain),
+            "EXTERNAL" => Ok(Self::External),
             _ => Err(Error::new(ErrorKind::InvalidData, "unknown auth strategy")),
         }
     }
@@ -88,7 +93,7 @@ impl SaslSuccess {
 }
 
 /// Returned to the client when the whole connection flow is successful.
-pub struct ConnectionSuccess(pub InitiatedConnection);
+pub struct ConnectionSuccess(pub Arc<InitiatedConnection>);
 
 impl ConnectionSuccess {
     #[must_use]
@@ -99,7 +104,7 @@ impl ConnectionSuccess {
             command: Command::Response(
                 Response::RPL_LOGGEDIN,
                 vec![
-                    self.0.nick.to_string(),
+                    self.0.nick(),
                     self.0.to_nick().to_string(),
                     self.0.user.to_string(),
                     format!("You are now logged in as {}", self.0.user),
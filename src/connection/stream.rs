@@ -0,0 +1,158 @@
+//! The underlying transport for a client's connection, allowing negotiation and the `Client`
+//! actor to work transparently over plaintext and TLS connections.
+
+use std::{
+    io::{BufReader, Error, ErrorKind},
+    path::Path,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::SystemTime,
+};
+
+use sha2::{Digest, Sha256};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+};
+use tokio_rustls::{
+    rustls::{
+        server::{ClientCertVerified, ClientCertVerifier},
+        Certificate, DistinguishedName, PrivateKey, ServerConfig,
+    },
+    server::TlsStream,
+    TlsAcceptor,
+};
+
+use crate::config::TlsConfig;
+
+/// A stream accepted from one of our listeners.
+pub enum ClientStream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl ClientStream {
+    /// The hex-encoded SHA-256 fingerprint of the certificate the client presented during the
+    /// TLS handshake, if any.
+    #[must_use]
+    pub fn certificate_fingerprint(&self) -> Option<String> {
+        let Self::Tls(stream) = self else {
+            return None;
+        };
+
+        let certificate = stream.get_ref().1.peer_certificates()?.first()?;
+        Some(hex::encode(Sha256::digest(&certificate.0)))
+    }
+}
+
+impl AsyncRead for ClientStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            Self::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ClientStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            Self::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            Self::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            Self::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Loads the certificate chain and private key from disk, building an acceptor that can be
+/// shared between all TLS listeners.
+pub fn build_tls_acceptor(config: &TlsConfig) -> std::io::Result<TlsAcceptor> {
+    let certificates = load_certificates(&config.certificate)?;
+    let key = load_private_key(&config.key)?;
+
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_client_cert_verifier(Arc::new(AnyClientCertificate))
+        .with_single_cert(certificates, key)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Requests, but doesn't require, a certificate from the client. Certificates aren't checked
+/// against any CA, since they're only used to identify the client by their fingerprint for SASL
+/// `EXTERNAL`. The client still has to prove it holds the certificate's private key.
+struct AnyClientCertificate;
+
+impl ClientCertVerifier for AnyClientCertificate {
+    fn client_auth_mandatory(&self) -> bool {
+        false
+    }
+
+    fn client_auth_root_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _now: SystemTime,
+    ) -> Result<ClientCertVerified, tokio_rustls::rustls::Error> {
+        Ok(ClientCertVerified::assertion())
+    }
+}
+
+fn load_certificates(path: &Path) -> std::io::Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(std::fs::File::open(path)?);
+    let certificates = rustls_pemfile::certs(&mut reader)?;
+
+    if certificates.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("no certificates found in {}", path.display()),
+        ));
+    }
+
+    Ok(certificates.into_iter().map(Certificate).collect())
+}
+
+fn load_private_key(path: &Path) -> std::io::Result<PrivateKey> {
+    let mut reader = BufReader::new(std::fs::File::open(path)?);
+
+    while let Some(item) = rustls_pemfile::read_one(&mut reader)? {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => {}
+        }
+    }
+
+    Err(Error::new(
+        ErrorKind::InvalidData,
+        format!("no private key found in {}", path.display()),
+    ))
+}
@@ -1,8 +1,78 @@
 use argon2::{password_hash::SaltString, Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use chrono::{DateTime, TimeZone, Utc};
 use rand::rngs::OsRng;
+use sqlx::migrate::{MigrateError, Migrator};
 
 use crate::connection::UserId;
 
+static SQLITE_MIGRATOR: Migrator = sqlx::migrate!("./migrations/sqlite");
+static POSTGRES_MIGRATOR: Migrator = sqlx::migrate!("./migrations/postgres");
+
+/// The databases the server can be backed by, picked by the scheme of the configured URI.
+///
+/// Queries are written to run unchanged against either, so this only decides which set of
+/// migrations the schema is built from.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Backend {
+    Sqlite,
+    Postgres,
+}
+
+impl Backend {
+    #[must_use]
+    pub fn of(pool: &sqlx::Pool<sqlx::Any>) -> Option<Self> {
+        match pool.connect_options().database_url.scheme() {
+            "sqlite" => Some(Self::Sqlite),
+            "postgres" | "postgresql" => Some(Self::Postgres),
+            _ => None,
+        }
+    }
+
+    #[must_use]
+    pub const fn migrator(self) -> &'static Migrator {
+        match self {
+            Self::Sqlite => &SQLITE_MIGRATOR,
+            Self::Postgres => &POSTGRES_MIGRATOR,
+        }
+    }
+}
+
+/// Brings the database's schema up to date using the migrations for its backend.
+pub async fn migrate(pool: &sqlx::Pool<sqlx::Any>) -> Result<(), MigrateError> {
+    let backend = Backend::of(pool).ok_or_else(|| {
+        MigrateError::Source(
+            format!(
+                "unsupported database {}, only sqlite and postgres are supported",
+                pool.connect_options().database_url.scheme()
+            )
+            .into(),
+        )
+    })?;
+
+    backend.migrator().run(pool).await
+}
+
+/// Opens a migrated, in-memory sqlite database for tests to run against.
+#[cfg(test)]
+pub(crate) async fn in_memory() -> sqlx::Pool<sqlx::Any> {
+    use std::str::FromStr;
+
+    use sqlx::any::{AnyConnectOptions, AnyPoolOptions};
+
+    sqlx::any::install_default_drivers();
+
+    // in-memory databases are per-connection, so everything has to go over the one connection
+    let database = AnyPoolOptions::new()
+        .max_connections(1)
+        .connect_with(AnyConnectOptions::from_str("sqlite::memory:").unwrap())
+        .await
+        .unwrap();
+
+    migrate(&database).await.unwrap();
+
+    database
+}
+
 /// Attempts creation of a new user, returning the password of the user.
 ///
 /// The returned password _is not_ guaranteed to be the password that was just set.
@@ -11,19 +81,17 @@ pub async fn create_user_or_fetch_password_hash(
     username: &str,
     password: &[u8],
 ) -> Result<(i64, String), sqlx::Error> {
-    let password_hash = Argon2::default()
-        .hash_password(password, &SaltString::generate(&mut OsRng))
-        .unwrap()
-        .to_string();
+    let password_hash = hash_password(password);
 
     sqlx::query_as(
-        "INSERT INTO users (username, password)
-         VALUES (?, ?)
-         ON CONFLICT(username) DO UPDATE SET username = username
+        "INSERT INTO users (username, password, created_timestamp)
+         VALUES ($1, $2, $3)
+         ON CONFLICT(username) DO UPDATE SET username = users.username
          RETURNING id, password",
     )
     .bind(username)
     .bind(password_hash)
+    .bind(Utc::now().timestamp_nanos_opt().unwrap())
     .fetch_one(conn)
     .await
 }
@@ -34,10 +102,10 @@ pub async fn reserve_nick(
     user_id: UserId,
 ) -> Result<bool, sqlx::Error> {
     let (owning_user,): (i64,) = sqlx::query_as(
-        "INSERT INTO user_nicks (nick, user)
-         VALUES (?, ?)
-         ON CONFLICT(nick) DO UPDATE SET nick = nick
-         RETURNING user",
+        "INSERT INTO user_nicks (nick, user_id)
+         VALUES ($1, $2)
+         ON CONFLICT(nick) DO UPDATE SET nick = user_nicks.nick
+         RETURNING user_id",
     )
     .bind(nick)
     .bind(user_id.0)
@@ -47,6 +115,118 @@ pub async fn reserve_nick(
     Ok(owning_user == user_id.0)
 }
 
+/// Looks up the id and username of the account the given TLS client certificate fingerprint
+/// has been added to.
+pub async fn fetch_user_by_certificate(
+    conn: &sqlx::Pool<sqlx::Any>,
+    fingerprint: &str,
+) -> Result<Option<(i64, String)>, sqlx::Error> {
+    sqlx::query_as(
+        "SELECT users.id, users.username
+         FROM user_certificates
+         INNER JOIN users
+           ON users.id = user_certificates.user_id
+         WHERE user_certificates.fingerprint = $1",
+    )
+    .bind(fingerprint)
+    .fetch_optional(conn)
+    .await
+}
+
+/// Looks up when the account was created, returning `None` for accounts created before creation
+/// times were recorded.
+pub async fn fetch_account_created(
+    conn: &sqlx::Pool<sqlx::Any>,
+    user_id: UserId,
+) -> Result<Option<DateTime<Utc>>, sqlx::Error> {
+    let (created,): (Option<i64>,) =
+        sqlx::query_as("SELECT created_timestamp FROM users WHERE id = $1")
+            .bind(user_id.0)
+            .fetch_one(conn)
+            .await?;
+
+    Ok(created.map(|v| Utc.timestamp_nanos(v)))
+}
+
+/// Looks up the id and password hash of an existing account, without creating it.
+pub async fn fetch_password_hash(
+    conn: &sqlx::Pool<sqlx::Any>,
+    username: &str,
+) -> Result<Option<(i64, String)>, sqlx::Error> {
+    sqlx::query_as("SELECT id, password FROM users WHERE username = $1")
+        .bind(username)
+        .fetch_optional(conn)
+        .await
+}
+
+/// Replaces the password of an existing account.
+pub async fn set_password(
+    conn: &sqlx::Pool<sqlx::Any>,
+    user_id: UserId,
+    password: &[u8],
+) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE users SET password = $1 WHERE id = $2")
+        .bind(hash_password(password))
+        .bind(user_id.0)
+        .execute(conn)
+        .await?;
+
+    Ok(())
+}
+
+/// Deletes an account along with its nicks, certificates, channel memberships and history.
+/// Channels founded by the account are handed over to their successor separately.
+///
+/// Returns false, leaving the account in place, if the account placed any network bans or
+/// shuns that are still in place.
+pub async fn drop_user(conn: &sqlx::Pool<sqlx::Any>, user_id: UserId) -> Result<bool, sqlx::Error> {
+    let mut transaction = conn.begin().await?;
+
+    let (placed_bans,): (i64,) = sqlx::query_as(
+        "SELECT
+           (SELECT COUNT(*) FROM server_bans WHERE requester = $1)
+           + (SELECT COUNT(*) FROM server_shuns WHERE requester = $2)",
+    )
+    .bind(user_id.0)
+    .bind(user_id.0)
+    .fetch_one(&mut *transaction)
+    .await?;
+
+    if placed_bans > 0 {
+        return Ok(false);
+    }
+
+    for query in [
+        "DELETE FROM user_nicks WHERE user_id = $1",
+        "DELETE FROM nick_history WHERE user_id = $1",
+        "DELETE FROM user_certificates WHERE user_id = $1",
+        "DELETE FROM channel_users WHERE user_id = $1",
+        "DELETE FROM channel_invites WHERE user_id = $1",
+        "DELETE FROM channel_message_reactions WHERE user_id = $1",
+        "DELETE FROM login_history WHERE user_id = $1",
+        "DELETE FROM private_messages WHERE receiver = $1",
+        "UPDATE private_messages SET sender_user = NULL WHERE sender_user = $1",
+        "UPDATE channels SET successor = NULL WHERE successor = $1",
+        "DELETE FROM users WHERE id = $1",
+    ] {
+        sqlx::query(query)
+            .bind(user_id.0)
+            .execute(&mut *transaction)
+            .await?;
+    }
+
+    transaction.commit().await?;
+
+    Ok(true)
+}
+
+fn hash_password(password: &[u8]) -> String {
+    Argon2::default()
+        .hash_password(password, &SaltString::generate(&mut OsRng))
+        .unwrap()
+        .to_string()
+}
+
 /// Compares a password to a hash stored in the database.
 pub fn verify_password(
     password: &[u8],
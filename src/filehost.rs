@@ -0,0 +1,258 @@
+//! An HTTP endpoint for sharing files through the server, advertised to clients via the
+//! `FILEHOST` `ISUPPORT` token.
+//!
+//! Users upload a file by `POST`ing it to [`UPLOAD_PATH`] with their account's credentials as
+//! HTTP basic auth, and are given back the URL the file can be downloaded from in the
+//! `Location` header. Only a handful of media and text types are accepted, which keeps us from
+//! serving arbitrary content from the server's domain.
+
+use std::sync::Arc;
+
+use actix_web::{dev::Server, http::header, web, App, HttpRequest, HttpResponse, HttpServer};
+use argon2::PasswordHash;
+use base64::{prelude::BASE64_STANDARD, Engine};
+use object_store::{
+    aws::AmazonS3Builder, local::LocalFileSystem, path::Path, ObjectStore, PutPayload,
+};
+use tracing::{error, info};
+
+use crate::{
+    config::{FilehostConfig, FilehostStorage},
+    database::{fetch_password_hash, verify_password},
+};
+
+/// The path files are uploaded to.
+pub const UPLOAD_PATH: &str = "/upload";
+
+/// The path uploaded files are served from.
+const FILES_PATH: &str = "/files";
+
+/// The content types that can be uploaded, along with the extension files of that type are
+/// stored under. The extension is used to pick the content type again when serving the file.
+const CONTENT_TYPES: &[(&str, &str)] = &[
+    ("image/png", "png"),
+    ("image/jpeg", "jpg"),
+    ("image/gif", "gif"),
+    ("image/webp", "webp"),
+    ("video/mp4", "mp4"),
+    ("video/webm", "webm"),
+    ("audio/mpeg", "mp3"),
+    ("audio/ogg", "ogg"),
+    ("text/plain", "txt"),
+];
+
+struct Filehost {
+    database: sqlx::Pool<sqlx::Any>,
+    store: Arc<dyn ObjectStore>,
+    public_url: String,
+}
+
+impl Filehost {
+    /// Checks the credentials against the user's account, accounts are never created here.
+    async fn authenticate(&self, username: &str, password: &str) -> bool {
+        let Some((_, password_hash)) = fetch_password_hash(&self.database, username).await.unwrap()
+        else {
+            return false;
+        };
+
+        PasswordHash::new(&password_hash)
+            .is_ok_and(|hash| verify_password(password.as_bytes(), &hash).is_ok())
+    }
+}
+
+/// Builds the HTTP server for the file host, bound to the configured address. The returned
+/// server runs until it's dropped or stopped.
+pub fn start(config: &FilehostConfig, database: sqlx::Pool<sqlx::Any>) -> anyhow::Result<Server> {
+    let store: Arc<dyn ObjectStore> = match &config.storage {
+        FilehostStorage::Local { path } => {
+            std::fs::create_dir_all(path)?;
+            Arc::new(LocalFileSystem::new_with_prefix(path)?)
+        }
+        FilehostStorage::S3 {
+            bucket,
+            region,
+            endpoint,
+        } => {
+            let mut builder = AmazonS3Builder::from_env().with_bucket_name(bucket);
+
+            if let Some(region) = region {
+                builder = builder.with_region(region);
+            }
+
+            if let Some(endpoint) = endpoint {
+                builder = builder.with_endpoint(endpoint);
+            }
+
+            Arc::new(builder.build()?)
+        }
+    };
+
+    let filehost = web::Data::new(Filehost {
+        database,
+        store,
+        public_url: config.public_url.trim_end_matches('/').to_string(),
+    });
+    let max_size = config.max_size;
+
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(filehost.clone())
+            .app_data(web::PayloadConfig::new(max_size))
+            .route(UPLOAD_PATH, web::post().to(upload))
+            .route(&format!("{FILES_PATH}/{{key}}"), web::get().to(download))
+    })
+    .bind(config.address)?
+    .run();
+
+    info!(address = %config.address, "File host listening");
+
+    Ok(server)
+}
+
+/// Stores an uploaded file, responding with the URL it can be downloaded from.
+async fn upload(
+    filehost: web::Data<Filehost>,
+    request: HttpRequest,
+    body: web::Bytes,
+) -> HttpResponse {
+    let credentials = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(parse_basic_credentials);
+
+    let Some((username, password)) = credentials else {
+        return unauthorised();
+    };
+
+    if !filehost.authenticate(&username, &password).await {
+        return unauthorised();
+    }
+
+    let extension = request
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(extension_for);
+
+    let Some(extension) = extension else {
+        return HttpResponse::UnsupportedMediaType().finish();
+    };
+
+    if body.is_empty() {
+        return HttpResponse::BadRequest().finish();
+    }
+
+    let key = format!("{:032x}.{extension}", rand::random::<u128>());
+
+    if let Err(error) = filehost
+        .store
+        .put(&Path::from(key.as_str()), PutPayload::from(body))
+        .await
+    {
+        error!(%error, "Failed to store uploaded file");
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    info!(%username, %key, "File uploaded");
+
+    HttpResponse::Created()
+        .insert_header((
+            header::LOCATION,
+            format!("{}{FILES_PATH}/{key}", filehost.public_url),
+        ))
+        .finish()
+}
+
+/// Serves a previously uploaded file.
+async fn download(filehost: web::Data<Filehost>, key: web::Path<String>) -> HttpResponse {
+    let key = key.into_inner();
+
+    let content_type = key
+        .split_once('.')
+        .filter(|(id, _)| id.chars().all(|c| c.is_ascii_hexdigit()))
+        .and_then(|(_, extension)| content_type_for(extension));
+
+    let Some(content_type) = content_type else {
+        return HttpResponse::NotFound().finish();
+    };
+
+    let file = match filehost.store.get(&Path::from(key.as_str())).await {
+        Ok(file) => file.bytes().await,
+        Err(error) => Err(error),
+    };
+
+    match file {
+        Ok(file) => HttpResponse::Ok()
+            .content_type(content_type)
+            .insert_header((header::X_CONTENT_TYPE_OPTIONS, "nosniff"))
+            .body(file),
+        Err(object_store::Error::NotFound { .. }) => HttpResponse::NotFound().finish(),
+        Err(error) => {
+            error!(%error, %key, "Failed to read uploaded file");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+fn unauthorised() -> HttpResponse {
+    HttpResponse::Unauthorized()
+        .insert_header((header::WWW_AUTHENTICATE, "Basic realm=\"titanircd\""))
+        .finish()
+}
+
+/// Parses the username and password out of a basic `Authorization` header.
+fn parse_basic_credentials(header: &str) -> Option<(String, String)> {
+    let (scheme, credentials) = header.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+
+    let credentials = String::from_utf8(BASE64_STANDARD.decode(credentials.trim()).ok()?).ok()?;
+    let (username, password) = credentials.split_once(':')?;
+
+    Some((username.to_string(), password.to_string()))
+}
+
+/// Picks the extension to store a file under from its `Content-Type`, ignoring any parameters
+/// such as the charset.
+fn extension_for(content_type: &str) -> Option<&'static str> {
+    let content_type = content_type.split(';').next()?.trim();
+
+    CONTENT_TYPES
+        .iter()
+        .find(|(v, _)| v.eq_ignore_ascii_case(content_type))
+        .map(|(_, extension)| *extension)
+}
+
+/// Maps a stored file's extension back to the `Content-Type` it's served with.
+fn content_type_for(extension: &str) -> Option<&'static str> {
+    CONTENT_TYPES
+        .iter()
+        .find(|(_, v)| *v == extension)
+        .map(|(content_type, _)| *content_type)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{content_type_for, extension_for, parse_basic_credentials};
+
+    #[test]
+    fn parses_basic_credentials() {
+        assert_eq!(
+            parse_basic_credentials("Basic YWxpY2U6aHVudGVyOjI="),
+            Some(("alice".to_string(), "hunter:2".to_string()))
+        );
+        assert_eq!(parse_basic_credentials("Bearer YWxpY2U6aHVudGVyMg=="), None);
+        assert_eq!(parse_basic_credentials("Basic !!!"), None);
+    }
+
+    #[test]
+    fn maps_content_types() {
+        assert_eq!(extension_for("image/png"), Some("png"));
+        assert_eq!(extension_for("Text/Plain; charset=utf-8"), Some("txt"));
+        assert_eq!(extension_for("text/html"), None);
+        assert_eq!(content_type_for("jpg"), Some("image/jpeg"));
+        assert_eq!(content_type_for("html"), None);
+    }
+}
@@ -4,6 +4,7 @@ use std::{
     fmt::{Display, Formatter},
     io::{Error, ErrorKind},
     iter::once,
+    net::IpAddr,
     str::FromStr,
 };
 
@@ -128,10 +129,18 @@ impl<T> HostMaskMap<T> {
                 return false;
             };
 
-            match node {
+            let (removed, now_empty) = match node {
                 Node::Match(_) => unreachable!("stored hostmask has less parts than a!b@c"),
-                Node::Inner(map) => map.remove(&next_mask),
+                Node::Inner(map) => (map.remove(&next_mask), map.is_empty()),
+            };
+
+            // prune branches left without any masks, so the map is empty once every mask
+            // has been removed
+            if now_empty {
+                self.children.remove(&key);
             }
+
+            removed
         }
     }
 
@@ -274,6 +283,30 @@ impl<'a> HostMask<'a> {
         }
     }
 
+    #[must_use]
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// The same mask with its host replaced.
+    #[must_use]
+    pub fn with_host<'b>(&'b self, host: &'b str) -> HostMask<'b> {
+        HostMask::new(&self.nick, &self.username, host)
+    }
+
+    /// Rewrites a host that is an IP address into its canonical form, so IPv4-mapped IPv6
+    /// addresses and the many textual forms of an IPv6 address all match the same clients.
+    #[must_use]
+    pub fn with_canonical_host(self) -> Self {
+        match IpAddr::from_str(&self.host) {
+            Ok(ip) => Self {
+                host: Cow::Owned(ip.to_canonical().to_string()),
+                ..self
+            },
+            Err(_) => self,
+        }
+    }
+
     #[must_use]
     pub fn into_owned(self) -> HostMask<'static> {
         HostMask {
@@ -361,9 +394,84 @@ impl<'a> TryFrom<&'a str> for HostMask<'a> {
     }
 }
 
+/// The target of a server ban, either a host mask or an account name given in the form
+/// `$a:account`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BanMask {
+    HostMask(HostMask<'static>),
+    Account(String),
+}
+
+impl BanMask {
+    const ACCOUNT_PREFIX: &'static str = "$a:";
+}
+
+impl Display for BanMask {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::HostMask(mask) => mask.fmt(f),
+            Self::Account(account) => write!(f, "{}{account}", Self::ACCOUNT_PREFIX),
+        }
+    }
+}
+
+impl FromStr for BanMask {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix(Self::ACCOUNT_PREFIX) {
+            Some("") => Err(Error::new(ErrorKind::Other, "invalid account")),
+            Some(account) => Ok(Self::Account(account.to_string())),
+            None => HostMask::from_str(s).map(Self::HostMask),
+        }
+    }
+}
+
+impl TryFrom<&str> for BanMask {
+    type Error = Error;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        Self::from_str(value)
+    }
+}
+
+impl<DB> Type<DB> for BanMask
+where
+    String: Type<DB>,
+    DB: Database,
+{
+    fn type_info() -> DB::TypeInfo {
+        String::type_info()
+    }
+
+    fn compatible(ty: &DB::TypeInfo) -> bool {
+        String::compatible(ty)
+    }
+}
+
+impl<'q, DB> Encode<'q, DB> for BanMask
+where
+    String: Encode<'q, DB>,
+    DB: Database,
+{
+    fn encode_by_ref(&self, buf: &mut <DB as HasArguments<'q>>::ArgumentBuffer) -> IsNull {
+        self.to_string().encode(buf)
+    }
+}
+
+impl<'r, DB> Decode<'r, DB> for BanMask
+where
+    &'r str: Decode<'r, DB>,
+    DB: Database,
+{
+    fn decode(value: <DB as HasValueRef<'r>>::ValueRef) -> Result<Self, BoxDynError> {
+        Ok(<&'r str as Decode<'r, DB>>::decode(value)?.parse()?)
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use crate::host_mask::{HostMask, HostMaskMap};
+    use crate::host_mask::{BanMask, HostMask, HostMaskMap};
 
     #[test]
     fn from_iter() {
@@ -528,4 +636,47 @@ mod test {
         assert!(retrieved.contains(&&160));
         assert!(retrieved.contains(&&170));
     }
+
+    #[test]
+    fn remove_prunes_empty_branches() {
+        let mut map = HostMaskMap::new();
+        map.insert(&"aaaa!bbbb@cccc".try_into().unwrap(), 10);
+        map.insert(&"aaaa!*@*".try_into().unwrap(), 20);
+
+        assert!(map.remove(&"aaaa!bbbb@cccc".try_into().unwrap()));
+        assert!(!map.remove(&"aaaa!bbbb@cccc".try_into().unwrap()));
+        assert!(!map.is_empty());
+
+        assert!(map.remove(&"aaaa!*@*".try_into().unwrap()));
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn canonical_host() {
+        let mask: HostMask<'_> = "*!*@::ffff:10.0.0.1".try_into().unwrap();
+        assert_eq!(mask.with_canonical_host().to_string(), "*!*@10.0.0.1");
+
+        let mask: HostMask<'_> = "*!*@2001:0db8:0000::0001".try_into().unwrap();
+        assert_eq!(mask.with_canonical_host().to_string(), "*!*@2001:db8::1");
+
+        let mask: HostMask<'_> = "*!*@cloaked-*".try_into().unwrap();
+        assert_eq!(mask.with_canonical_host().to_string(), "*!*@cloaked-*");
+    }
+
+    #[test]
+    fn ban_mask() {
+        assert_eq!(
+            "$a:someone".parse::<BanMask>().unwrap(),
+            BanMask::Account("someone".to_string())
+        );
+        assert_eq!(
+            "aaa!bbb@ccc".parse::<BanMask>().unwrap(),
+            BanMask::HostMask("aaa!bbb@ccc".try_into().unwrap())
+        );
+        assert_eq!(
+            BanMask::Account("someone".to_string()).to_string(),
+            "$a:someone"
+        );
+        assert!("$a:".parse::<BanMask>().is_err());
+    }
 }
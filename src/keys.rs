@@ -16,8 +16,8 @@ impl Keys {
 async fn fetch_or_create(pool: &Pool<Any>, name: &str) -> Result<Vec<u8>, sqlx::Error> {
     sqlx::query_as(
         "INSERT INTO keys (name, enckey)
-         VALUES (?, ?)
-         ON CONFLICT(name) DO UPDATE SET enckey = enckey
+         VALUES ($1, $2)
+         ON CONFLICT(name) DO UPDATE SET enckey = keys.enckey
          RETURNING enckey",
     )
     .bind(name)
@@ -5,16 +5,22 @@
     clippy::missing_errors_doc
 )]
 
+pub mod casemapping;
 pub mod channel;
 pub mod client;
 pub mod config;
+pub mod conformance;
 pub mod connection;
 pub mod database;
+pub mod filehost;
 pub mod host_mask;
 pub mod keys;
+pub mod listener;
 pub mod messages;
 pub mod persistence;
 pub mod proto;
+pub mod replay;
 pub mod server;
+pub mod services;
 
 pub const SERVER_NAME: &str = "my.cool.server";
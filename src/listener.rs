@@ -0,0 +1,591 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
+};
+
+use actix::{
+    io::FramedWrite, Actor, ActorFutureExt, Addr, AsyncContext, Context, Handler,
+    ResponseActFuture, SpawnHandle, WrapFuture,
+};
+use actix_rt::Arbiter;
+use bytes::BytesMut;
+use futures::{stream, SinkExt, StreamExt};
+use hickory_resolver::TokioAsyncResolver;
+use irc_proto::{Command, IrcCodec, Message};
+use rand::seq::SliceRandom;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{
+    io::WriteHalf,
+    net::{TcpListener, TcpStream},
+    sync::oneshot,
+    time::Instant,
+};
+use tokio_rustls::TlsAcceptor;
+use tokio_util::codec::FramedRead;
+use tracing::{error, info, info_span, warn, Instrument};
+
+use crate::{
+    client::{
+        flood::FloodLimiter,
+        tap::TapLog,
+        traffic::{CountingSink, SocketWriter, Traffic, WriteErrors},
+        Client, OperSession,
+    },
+    config::{
+        CommandsConfig, CompatConfig, Config, ConnectionClass, FallbackNick, OperSessionConfig,
+    },
+    connection::{self, stream::ClientStream},
+    keys::Keys,
+    listener::governor::{ConnectionGovernor, ConnectionPermit},
+    messages::{BindListener, ReloadListeners, UnbindListener, UserConnected, ValidateConnection},
+    persistence::{events::RecordLogin, Persistence},
+    server::{bans::NetworkBans, response::ConnectionValidated, Server},
+};
+
+pub mod governor;
+
+/// Owns each of the sockets the server is listening on, accepting connections from clients
+/// and handing them off to new `Client` actors. Listeners can be bound and unbound at runtime.
+pub struct ListenerManager {
+    pub acceptor: Acceptor,
+    /// Each bound listener, keyed by the address it's bound to.
+    pub listeners: HashMap<SocketAddr, BoundListener>,
+}
+
+/// A socket that's being listened on, along with the task accepting connections from it.
+pub struct BoundListener {
+    /// The options the listener was bound with.
+    pub options: BindListener,
+    /// Shared with the accepting task, so the socket can be carried over if the listener is
+    /// rebound with the same socket options.
+    pub socket: Arc<TcpListener>,
+    pub handle: SpawnHandle,
+    /// Resolves once the accepting task has stopped and dropped its reference to the socket.
+    pub stopped: oneshot::Receiver<()>,
+}
+
+impl Actor for ListenerManager {
+    type Context = Context<Self>;
+}
+
+/// Binds a new listener, replacing any listener already bound to the same address. The
+/// replaced listener's socket is reused, unless the socket options differ in which case the
+/// address is bound again once the old socket has been closed.
+impl Handler<BindListener> for ListenerManager {
+    type Result = std::io::Result<()>;
+
+    fn handle(&mut self, msg: BindListener, ctx: &mut Self::Context) -> Self::Result {
+        let class = msg.class.as_deref().and_then(|name| {
+            let class = find_class(&self.acceptor.classes, name);
+
+            if class.is_none() {
+                warn!(name, address = %msg.address, "Unknown connection class for listener");
+            }
+
+            class
+        });
+
+        let tls = match (msg.tls, &self.acceptor.tls) {
+            (false, _) => None,
+            (true, Some(tls)) => Some(tls.clone()),
+            (true, None) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("TLS listener {} requires a certificate", msg.address),
+                ));
+            }
+        };
+
+        let socket = match self.listeners.remove(&msg.address) {
+            Some(bound) if bound.options.v6_only == msg.v6_only => {
+                ctx.cancel_future(bound.handle);
+                bound.socket
+            }
+            Some(bound) => {
+                // the socket is only closed once the accepting task has been dropped, binding
+                // the address again before then would fail as it's still in use
+                ctx.cancel_future(bound.handle);
+
+                let address = msg.address;
+                ctx.spawn(bound.stopped.into_actor(self).map(move |_, this, ctx| {
+                    if let Err(error) = Handler::<BindListener>::handle(this, msg, ctx) {
+                        error!(%address, %error, "Failed to rebind listener");
+                    }
+                }));
+
+                return Ok(());
+            }
+            None => Arc::new(bind(msg.address, msg.v6_only)?),
+        };
+
+        let (stopped_tx, stopped) = oneshot::channel();
+        let accept = self.acceptor.clone().run(socket.clone(), class, tls);
+        let handle = ctx.spawn(
+            async move {
+                let _stopped = stopped_tx;
+                accept.await;
+            }
+            .into_actor(self),
+        );
+
+        info!(address = %msg.address, v6_only = msg.v6_only, tls = msg.tls, "Server listening");
+
+        self.listeners.insert(
+            msg.address,
+            BoundListener {
+                options: msg,
+                socket,
+                handle,
+                stopped,
+            },
+        );
+
+        Ok(())
+    }
+}
+
+/// Stops accepting connections on a listener, any clients that already connected through
+/// the listener stay connected.
+impl Handler<UnbindListener> for ListenerManager {
+    type Result = bool;
+
+    fn handle(&mut self, msg: UnbindListener, ctx: &mut Self::Context) -> Self::Result {
+        let Some(bound) = self.listeners.remove(&msg.address) else {
+            return false;
+        };
+
+        ctx.cancel_future(bound.handle);
+        info!(address = %msg.address, "Server stopped listening");
+
+        true
+    }
+}
+
+/// Binds listeners that were added to the config and unbinds those that were removed. Listeners
+/// whose options changed, or whose connection class was redefined, are rebound. Clients that
+/// already connected keep the class they were placed into.
+impl Handler<ReloadListeners> for ListenerManager {
+    type Result = ();
+
+    fn handle(&mut self, msg: ReloadListeners, ctx: &mut Self::Context) -> Self::Result {
+        let previous_classes = std::mem::replace(
+            &mut self.acceptor.classes,
+            Arc::new(msg.classes.into_iter().map(Arc::new).collect()),
+        );
+        self.acceptor.oper_session = msg.oper_session;
+        self.acceptor.fallback_nick = msg.fallback_nick;
+        self.acceptor.commands = msg.commands;
+
+        let wanted: HashMap<_, _> = msg
+            .listeners
+            .into_iter()
+            .map(|listener| (listener.address, BindListener::from(listener)))
+            .collect();
+
+        let removed: Vec<_> = self
+            .listeners
+            .keys()
+            .filter(|address| !wanted.contains_key(address))
+            .copied()
+            .collect();
+
+        for address in removed {
+            Handler::<UnbindListener>::handle(self, UnbindListener { address }, ctx);
+        }
+
+        for (address, listener) in wanted {
+            let class_changed = |name: &str| {
+                find_class(&previous_classes, name) != find_class(&self.acceptor.classes, name)
+            };
+
+            let unchanged = self.listeners.get(&address).map_or(false, |bound| {
+                bound.options == listener && !listener.class.as_deref().map_or(false, class_changed)
+            });
+
+            if unchanged {
+                continue;
+            }
+
+            if let Err(error) = Handler::<BindListener>::handle(self, listener, ctx) {
+                error!(%address, %error, "Failed to bind listener after reloading config");
+            }
+        }
+    }
+}
+
+fn find_class(classes: &[Arc<ConnectionClass>], name: &str) -> Option<Arc<ConnectionClass>> {
+    classes.iter().find(|c| c.name == name).cloned()
+}
+
+/// How long a client connecting to a TLS listener has to complete the handshake.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Everything required to accept and negotiate a connection with a client.
+#[derive(Clone)]
+pub struct Acceptor {
+    pub database: sqlx::Pool<sqlx::Any>,
+    pub persistence: Addr<Persistence>,
+    pub server: Addr<Server>,
+    pub client_arbiters: Arc<Vec<Arbiter>>,
+    pub resolver: Arc<TokioAsyncResolver>,
+    pub keys: Arc<Keys>,
+    pub classes: Arc<Vec<Arc<ConnectionClass>>>,
+    pub oper_session: OperSessionConfig,
+    pub fallback_nick: FallbackNick,
+    pub commands: Arc<CommandsConfig>,
+    /// Shared between every TLS listener, `None` if no certificate has been configured.
+    pub tls: Option<TlsAcceptor>,
+    /// The log connections are tapped to, `None` if tapping hasn't been configured.
+    pub tap: Option<Arc<TapLog>>,
+    pub compat: CompatConfig,
+    /// Checked before users are handed over to the server, so banned users are turned away
+    /// without waiting on it.
+    pub bans: NetworkBans,
+    /// Limits the connections open from each IP address and to each account, across every
+    /// listener.
+    pub governor: ConnectionGovernor,
+}
+
+impl Acceptor {
+    /// Start listening for new connections from clients, and create a new client handle for
+    /// them. If `class` is set, all clients connecting via this listener are placed into it,
+    /// otherwise the class is picked based on the client's address. If `tls` is set, clients
+    /// must complete a TLS handshake before negotiation begins.
+    async fn run(
+        self,
+        listener: Arc<TcpListener>,
+        class: Option<Arc<ConnectionClass>>,
+        tls: Option<TlsAcceptor>,
+    ) {
+        while let Ok((stream, addr)) = listener.accept().await {
+            let span = info_span!("connection", %addr);
+            let _entered = span.clone().entered();
+
+            info!("Accepted connection");
+
+            // clients on listeners without a class are sorted into one again once they've
+            // registered, as classes can match on their host mask
+            let reclassify = class.is_none();
+            let class = class
+                .clone()
+                .unwrap_or_else(|| Config::find_class(&self.classes, addr.ip(), None));
+
+            info!(class = %class.name, "Assigned connection class");
+
+            let permit = match self.governor.admit(addr.ip(), class.max_clients_per_ip) {
+                Ok(permit) => permit,
+                Err(reason) => {
+                    warn!(%reason, "Rejecting connection");
+                    actix_rt::spawn(
+                        reject(stream, tls.clone(), reason).instrument(info_span!("rejection")),
+                    );
+                    continue;
+                }
+            };
+
+            actix_rt::spawn(
+                self.clone()
+                    .negotiate(
+                        stream,
+                        tls.clone(),
+                        addr,
+                        class,
+                        reclassify,
+                        permit,
+                        span.clone(),
+                    )
+                    .instrument(info_span!("negotiation")),
+            );
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn negotiate(
+        self,
+        stream: TcpStream,
+        tls: Option<TlsAcceptor>,
+        addr: SocketAddr,
+        class: Arc<ConnectionClass>,
+        reclassify: bool,
+        mut permit: ConnectionPermit,
+        span: tracing::Span,
+    ) {
+        let Self {
+            database,
+            persistence,
+            server,
+            client_arbiters,
+            resolver,
+            keys,
+            classes,
+            oper_session,
+            fallback_nick,
+            commands,
+            tap,
+            compat,
+            bans,
+            ..
+        } = self;
+
+        let Some(stream) = accept_tls(stream, tls).await else {
+            return;
+        };
+
+        let certificate_fingerprint = stream.certificate_fingerprint();
+
+        // split the stream into its read and write halves and setup codecs
+        let (read, writer) = tokio::io::split(stream);
+        let mut read = FramedRead::new(read, irc_codec());
+        let mut write = tokio_util::codec::FramedWrite::new(writer, irc_codec());
+
+        // ensure we have all the details required to actually connect the client to the server
+        // (ie. we have a nick, user, etc)
+        let (connection, deferred) = match connection::negotiate_client_connection(
+            &mut read,
+            &mut write,
+            addr,
+            &persistence,
+            &server,
+            &bans,
+            database.clone(),
+            &resolver,
+            &keys,
+            class,
+            if reclassify { &classes } else { &[] },
+            fallback_nick,
+            certificate_fingerprint,
+            compat.implicit_accounts,
+        )
+        .await
+        {
+            Ok(Some(v)) => v,
+            Ok(None) => {
+                error!("Failed to fully handshake with client, dropping connection");
+
+                let command =
+                    Command::ERROR("You must use SASL to connect to this server".to_string());
+                if let Err(error) = write
+                    .send(Message {
+                        tags: None,
+                        prefix: None,
+                        command,
+                    })
+                    .await
+                {
+                    error!(%error, "Failed to send error message to client, forcefully closing connection.");
+                }
+
+                return;
+            }
+            Err(error) => {
+                error!(%error, "An error occurred whilst handshaking with client");
+
+                let command = Command::ERROR(error.to_string());
+                if let Err(error) = write
+                    .send(Message {
+                        tags: None,
+                        prefix: None,
+                        command,
+                    })
+                    .await
+                {
+                    error!(%error, "Failed to send error message to client, forcefully closing connection.");
+                }
+
+                return;
+            }
+        };
+
+        let validated = if let Err(reason) =
+            permit.admit_account(&connection.user, connection.class.max_clients_per_account)
+        {
+            ConnectionValidated::Reject(reason)
+        } else {
+            match bans.check_connection(&connection) {
+                Some(reason) => ConnectionValidated::Reject(reason),
+                None => server
+                    .send(ValidateConnection(connection.clone()))
+                    .await
+                    .unwrap(),
+            }
+        };
+
+        let shunned = match validated {
+            ConnectionValidated::Allowed => false,
+            ConnectionValidated::Shunned => true,
+            ConnectionValidated::Reject(reason) => {
+                let command = Command::ERROR(reason.to_string());
+                if let Err(error) = write
+                    .send(Message {
+                        tags: None,
+                        prefix: None,
+                        command,
+                    })
+                    .await
+                {
+                    error!(%error, "Failed to send error message to client, forcefully closing connection.");
+                }
+                return;
+            }
+        };
+
+        persistence.do_send(RecordLogin {
+            user_id: connection.user_id,
+            session: connection.session_id.clone(),
+            ip: connection.host.ip().to_canonical().to_string(),
+            host: connection.resolved_host.clone(),
+        });
+
+        // spawn the client's actor
+        let handle = {
+            let server = server.clone();
+            let arbiter = client_arbiters
+                .choose(&mut rand::thread_rng())
+                .map_or_else(Arbiter::current, Arbiter::handle);
+            let span = span.clone();
+            let connection = connection.clone();
+
+            Client::start_in_arbiter(&arbiter, move |ctx| {
+                // setup the writer codec for the user
+                let write_errors = WriteErrors::default();
+                let (stream, codec, buffer) = unpack_writer(write);
+                let stream = SocketWriter::new(stream, write_errors.clone());
+                let mut writer = FramedWrite::from_buffer(stream, codec, buffer, ctx);
+
+                if let Some(sendq) = connection.class.sendq {
+                    writer.set_buffer_capacity(sendq / 4, sendq);
+                }
+
+                let traffic = Arc::new(Traffic::new());
+                let flood = FloodLimiter::for_class(&connection.class);
+                let writer = CountingSink::new(writer, traffic.clone());
+
+                // add the user's incoming tcp stream to the actor, messages over the tcp stream
+                // will be sent to the actor over the `StreamHandler`. any commands the user sent
+                // before registering are replayed first
+                ctx.add_stream(stream::iter(deferred.into_iter().map(Ok)).chain(read));
+
+                let mut client = Client {
+                    writer,
+                    traffic,
+                    tap_log: tap.clone(),
+                    tap: None,
+                    connection,
+                    server,
+                    channels: HashMap::new(),
+                    joining: HashSet::new(),
+                    last_active: Instant::now(),
+                    last_command: Instant::now(),
+                    last_who: None,
+                    oper_session: OperSession::new(oper_session),
+                    commands,
+                    write_errors,
+                    graceful_shutdown: false,
+                    server_shutdown: false,
+                    server_leave_reason: None,
+                    shunned,
+                    flood,
+                    connection_permit: permit,
+                    span,
+                    persistence,
+                    database,
+                };
+
+                // tap the connection from the start if it matches one of the configured masks,
+                // so the replayed commands are captured too
+                if tap.is_some_and(|log| log.matches(&client.connection.to_host_mask())) {
+                    client.set_tap(true);
+                }
+
+                client
+            })
+        };
+
+        // inform the server of the new connection
+        server.do_send(UserConnected {
+            handle,
+            connection,
+            span,
+        });
+    }
+}
+
+/// Completes the TLS handshake with the client if they connected to a TLS listener, returning
+/// `None` if the handshake failed or timed out.
+async fn accept_tls(stream: TcpStream, tls: Option<TlsAcceptor>) -> Option<ClientStream> {
+    let Some(tls) = tls else {
+        return Some(ClientStream::Plain(stream));
+    };
+
+    match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, tls.accept(stream)).await {
+        Ok(Ok(stream)) => Some(ClientStream::Tls(Box::new(stream))),
+        Ok(Err(error)) => {
+            warn!(%error, "TLS handshake failed, dropping connection");
+            None
+        }
+        Err(_) => {
+            warn!("Client didn't complete TLS handshake in time, dropping connection");
+            None
+        }
+    }
+}
+
+/// Turns away a connection before negotiation begins, letting the client know why.
+async fn reject(stream: TcpStream, tls: Option<TlsAcceptor>, reason: String) {
+    let Some(stream) = accept_tls(stream, tls).await else {
+        return;
+    };
+
+    let mut write = tokio_util::codec::FramedWrite::new(stream, irc_codec());
+    if let Err(error) = write
+        .send(Message {
+            tags: None,
+            prefix: None,
+            command: Command::ERROR(reason),
+        })
+        .await
+    {
+        error!(%error, "Failed to send error message to client, forcefully closing connection.");
+    }
+}
+
+/// Binds a new listening socket. IPv6 sockets are dual-stack unless `v6_only` is set, which
+/// allows for a separate IPv4 listener to be bound to the same port.
+fn bind(address: SocketAddr, v6_only: bool) -> std::io::Result<TcpListener> {
+    let socket = Socket::new(
+        Domain::for_address(address),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+
+    if address.is_ipv6() {
+        socket.set_only_v6(v6_only)?;
+    }
+
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(1024)?;
+
+    TcpListener::from_std(socket.into())
+}
+
+/// Unpacks a tokio framed writer, for instantiating an Actix framed writer once connection
+/// instantiation is complete.
+#[must_use]
+pub fn unpack_writer(
+    mut writer: tokio_util::codec::FramedWrite<WriteHalf<ClientStream>, IrcCodec>,
+) -> (WriteHalf<ClientStream>, IrcCodec, BytesMut) {
+    let codec = std::mem::replace(writer.encoder_mut(), irc_codec());
+    let bytes = writer.write_buffer_mut().split();
+    let stream = writer.into_inner();
+
+    (stream, codec, bytes)
+}
+
+#[must_use]
+pub fn irc_codec() -> IrcCodec {
+    IrcCodec::new("utf8").unwrap()
+}
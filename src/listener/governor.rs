@@ -0,0 +1,153 @@
+//! Limits the connections a single IP address or account can hold open at once, as configured
+//! by their connection class, to stop a single host from flooding the server with connections.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
+
+/// Counts the connections currently open from each IP address and to each account. Shared by
+/// every listener, so the limits apply across all of them.
+#[derive(Clone, Default)]
+pub struct ConnectionGovernor(Arc<Mutex<Counts>>);
+
+#[derive(Default)]
+struct Counts {
+    ips: HashMap<IpAddr, usize>,
+    accounts: HashMap<String, usize>,
+}
+
+/// Holds a connection's place in the counts for as long as the connection is open, giving it
+/// up once dropped.
+pub struct ConnectionPermit {
+    governor: ConnectionGovernor,
+    ip: IpAddr,
+    account: Option<String>,
+}
+
+impl ConnectionGovernor {
+    /// Counts a new connection from `ip`, rejecting it with the reason to give the client if
+    /// `limit` connections are already open from the address.
+    pub fn admit(&self, ip: IpAddr, limit: Option<usize>) -> Result<ConnectionPermit, String> {
+        let ip = ip.to_canonical();
+        let mut counts = self.0.lock().unwrap();
+        let count = counts.ips.entry(ip).or_default();
+
+        if limit.is_some_and(|limit| *count >= limit) {
+            return Err("Too many connections from your host".to_string());
+        }
+
+        *count += 1;
+
+        Ok(ConnectionPermit {
+            governor: self.clone(),
+            ip,
+            account: None,
+        })
+    }
+}
+
+impl ConnectionPermit {
+    /// Counts the connection against the account it logged in to, rejecting it with the reason
+    /// to give the client if `limit` connections to the account are already open.
+    pub fn admit_account(&mut self, account: &str, limit: Option<usize>) -> Result<(), String> {
+        if self.account.is_some() {
+            return Ok(());
+        }
+
+        let mut counts = self.governor.0.lock().unwrap();
+        let count = counts.accounts.entry(account.to_string()).or_default();
+
+        if limit.is_some_and(|limit| *count >= limit) {
+            // don't leave behind an entry for an account that has no connections
+            if *count == 0 {
+                counts.accounts.remove(account);
+            }
+
+            return Err("Too many connections to your account".to_string());
+        }
+
+        *count += 1;
+        self.account = Some(account.to_string());
+
+        Ok(())
+    }
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        let mut counts = self.governor.0.lock().unwrap();
+
+        release(&mut counts.ips, &self.ip);
+
+        if let Some(account) = &self.account {
+            release(&mut counts.accounts, account);
+        }
+    }
+}
+
+/// Gives up a place in `counts`, removing the key once it no longer has any connections so the
+/// maps don't grow with every address that's ever connected.
+fn release<K: std::hash::Hash + Eq>(counts: &mut HashMap<K, usize>, key: &K) {
+    if let Some(count) = counts.get_mut(key) {
+        *count -= 1;
+
+        if *count == 0 {
+            counts.remove(key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    use super::ConnectionGovernor;
+
+    const IP: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+
+    #[test]
+    fn limits_connections_per_ip() {
+        let governor = ConnectionGovernor::default();
+
+        let first = governor.admit(IP, Some(2)).unwrap();
+        let _second = governor.admit(IP, Some(2)).unwrap();
+        assert!(governor.admit(IP, Some(2)).is_err());
+
+        // IPv4-mapped addresses count towards the IPv4 address
+        let mapped = IpAddr::V6(Ipv4Addr::new(192, 0, 2, 1).to_ipv6_mapped());
+        assert!(governor.admit(mapped, Some(2)).is_err());
+
+        // other addresses and unlimited classes are unaffected
+        assert!(governor
+            .admit(IpAddr::V6(Ipv6Addr::LOCALHOST), Some(2))
+            .is_ok());
+        assert!(governor.admit(IP, None).is_ok());
+
+        drop(first);
+        assert_eq!(governor.0.lock().unwrap().ips[&IP], 1);
+        assert!(governor.admit(IP, Some(2)).is_ok());
+    }
+
+    #[test]
+    fn limits_connections_per_account() {
+        let governor = ConnectionGovernor::default();
+
+        let mut first = governor.admit(IP, None).unwrap();
+        let mut second = governor.admit(IP, None).unwrap();
+
+        first.admit_account("alice", Some(1)).unwrap();
+        assert!(second.admit_account("alice", Some(1)).is_err());
+        second.admit_account("bob", Some(1)).unwrap();
+
+        drop(first);
+        let mut third = governor.admit(IP, None).unwrap();
+        third.admit_account("alice", Some(1)).unwrap();
+
+        drop((second, third));
+        let counts = governor.0.lock().unwrap();
+        assert!(counts.ips.is_empty());
+        assert!(counts.accounts.is_empty());
+    }
+}
@@ -7,36 +7,31 @@
 
 use std::{collections::HashMap, str::FromStr, sync::Arc};
 
-use actix::{io::FramedWrite, Actor, Addr, AsyncContext, Supervisor};
+use actix::{Actor, Context, Supervisor};
 use actix_rt::{Arbiter, System};
-use bytes::BytesMut;
+use chrono::Utc;
 use clap::Parser;
-use futures::SinkExt;
 use hickory_resolver::AsyncResolver;
-use irc_proto::{Command, IrcCodec, Message};
-use rand::seq::SliceRandom;
-use sqlx::migrate::Migrator;
 use titanircd::{
-    client::Client,
-    config::Args,
-    connection,
+    client::tap::TapLog,
+    config::{Args, Config, Subcommand},
+    conformance,
+    connection::stream::build_tls_acceptor,
+    database, filehost,
     host_mask::HostMaskMap,
     keys::Keys,
-    messages::{UserConnected, ValidateConnection},
-    persistence::Persistence,
-    server::{response::ConnectionValidated, Server},
+    listener::{governor::ConnectionGovernor, Acceptor, ListenerManager},
+    messages::{BindListener, Shutdown},
+    persistence::{self, Persistence},
+    server::{bans::NetworkBans, Server},
+    services::link,
 };
-use tokio::{
-    io::WriteHalf,
-    net::{TcpListener, TcpStream},
-    time::Instant,
-};
-use tokio_util::codec::FramedRead;
-use tracing::{error, info, info_span, Instrument};
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::Span;
 use tracing_subscriber::EnvFilter;
 
-static MIGRATOR: Migrator = sqlx::migrate!();
-
 #[actix_rt::main]
 async fn main() -> anyhow::Result<()> {
     // parse CLI arguments
@@ -53,6 +48,15 @@ async fn main() -> anyhow::Result<()> {
         },
     );
 
+    if let Some(Subcommand::Conformance(args)) = opts.command {
+        let passed = conformance::run(args).await?;
+        std::process::exit(i32::from(!passed));
+    }
+
+    // clap requires the config to be given when no subcommand is
+    let config_path = opts.config.unwrap();
+    let config = Config::load(&config_path)?;
+
     let subscriber = tracing_subscriber::fmt()
         .with_env_filter(EnvFilter::from_default_env())
         .pretty();
@@ -60,181 +64,142 @@ async fn main() -> anyhow::Result<()> {
 
     sqlx::any::install_default_drivers();
     let database = sqlx::Pool::connect_with(sqlx::any::AnyConnectOptions::from_str(
-        &opts.config.database_uri,
+        &config.database.uri,
     )?)
     .await?;
 
-    MIGRATOR.run(&database).await?;
+    database::migrate(&database).await?;
+
+    let (run, _) = persistence::record_startup(&database).await?;
 
     let keys = Arc::new(Keys::new(&database).await?);
 
-    let listen_address = opts.config.listen_address;
-    let client_threads = opts.config.client_threads;
+    let tls = config.tls.as_ref().map(build_tls_acceptor).transpose()?;
+    let tap = config
+        .tap
+        .as_ref()
+        .map(TapLog::open)
+        .transpose()?
+        .map(Arc::new);
+    if let Some(filehost) = &config.filehost {
+        actix_rt::spawn(filehost::start(filehost, database.clone())?);
+    }
+
+    let client_threads = config.threads.client;
+    let classes = config.classes.iter().cloned().map(Arc::new).collect();
+    let listener_configs = config.listeners.clone();
+    let oper_session = config.oper_session;
+    let fallback_nick = config.nicks.fallback;
+    let commands = Arc::new(config.commands.clone());
+    let compat = config.compat;
+    let bans = NetworkBans::new(config.bans.clone(), config.network_name.clone());
+    let services = config
+        .services
+        .clone()
+        .map(|services| (services, config.network_name.clone()));
 
     let server_arbiter = Arbiter::new();
 
     let persistence_addr = {
         let database = database.clone();
-        let config = opts.config.clone();
-
-        Supervisor::start_in_arbiter(&server_arbiter.handle(), move |_ctx| Persistence {
-            database,
-            max_message_replay_since: config.max_message_replay_since,
-            last_seen_clock: 0,
+        let config = config.database.clone();
+
+        Supervisor::start_in_arbiter(&server_arbiter.handle(), move |_ctx| {
+            Persistence::new(
+                database,
+                config.max_message_replay_since,
+                config.max_message_replay_count,
+                config.whowas_retention,
+            )
         })
     };
 
+    // the server needs to be able to reach the listeners to apply reloaded configs, but the
+    // listeners need the server's address to start
+    let listeners_ctx = Context::<ListenerManager>::new();
+    let listeners = listeners_ctx.address();
+
     let persistence = persistence_addr.clone();
+    let server_listeners = listeners.clone();
+    let server_bans = bans.clone();
     let server = Supervisor::start_in_arbiter(&server_arbiter.handle(), move |_ctx| Server {
         channels: HashMap::default(),
         clients: HashMap::default(),
-        channel_arbiters: build_arbiters(opts.config.channel_threads),
-        config: opts.config,
+        nicks: HashMap::default(),
+        memberships: HashMap::default(),
+        channel_arbiters: build_arbiters(config.threads.channel),
+        config,
+        config_path,
+        listeners: server_listeners,
         persistence,
         max_clients: 0,
-        bans: HostMaskMap::new(),
+        started_at: Utc::now(),
+        bans: server_bans,
+        shuns: HostMaskMap::new(),
+        caller_id: HashMap::default(),
+        holds: HashMap::default(),
+        services: None,
     });
 
-    let listener = TcpListener::bind(listen_address).await?;
+    if let Some((services, network_name)) = services {
+        let listener = TcpListener::bind(services.address).await?;
+        actix_rt::spawn(link::run(listener, services, network_name, server.clone()));
+    }
+
+    listeners_ctx.run(ListenerManager {
+        acceptor: Acceptor {
+            database: database.clone(),
+            persistence: persistence_addr,
+            server: server.clone(),
+            client_arbiters: Arc::new(build_arbiters(client_threads)),
+            resolver: Arc::new(AsyncResolver::tokio_from_system_conf()?),
+            keys,
+            classes: Arc::new(classes),
+            oper_session,
+            fallback_nick,
+            commands,
+            tls,
+            tap,
+            compat,
+            bans,
+            governor: ConnectionGovernor::default(),
+        },
+        listeners: HashMap::default(),
+    });
 
-    actix_rt::spawn(start_tcp_acceptor_loop(
-        listener,
-        database,
-        persistence_addr,
-        server,
-        client_threads,
-        keys,
-    ));
+    for listener in listener_configs {
+        listeners.send(BindListener::from(listener)).await??;
+    }
 
-    info!("Server listening on {}", listen_address);
+    shutdown_signal().await?;
+    server
+        .send(Shutdown {
+            span: Span::current(),
+        })
+        .await?;
+    persistence::record_shutdown(&database, run).await?;
 
-    tokio::signal::ctrl_c().await?;
+    // waits on any writes that are still in flight
+    database.close().await;
     System::current().stop();
 
     Ok(())
 }
 
-/// Start listening for new connections from clients, and create a new client handle for
-/// them.
-async fn start_tcp_acceptor_loop(
-    listener: TcpListener,
-    database: sqlx::Pool<sqlx::Any>,
-    persistence: Addr<Persistence>,
-    server: Addr<Server>,
-    client_threads: usize,
-    keys: Arc<Keys>,
-) {
-    let client_arbiters = Arc::new(build_arbiters(client_threads));
-    let resolver = Arc::new(AsyncResolver::tokio_from_system_conf().unwrap());
-
-    while let Ok((stream, addr)) = listener.accept().await {
-        let span = info_span!("connection", %addr);
-        let _entered = span.clone().entered();
-
-        info!("Accepted connection");
+/// Waits for the process to be asked to stop, either by ctrl-c or `SIGTERM`.
+async fn shutdown_signal() -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        let mut terminate = signal(SignalKind::terminate())?;
 
-        let database = database.clone();
-        let server = server.clone();
-        let client_arbiters = client_arbiters.clone();
-        let persistence = persistence.clone();
-        let resolver = resolver.clone();
-        let keys = keys.clone();
-
-        actix_rt::spawn(async move {
-            // split the stream into its read and write halves and setup codecs
-            let (read, writer) = tokio::io::split(stream);
-            let mut read = FramedRead::new(read, irc_codec());
-            let mut write = tokio_util::codec::FramedWrite::new(writer, irc_codec());
-
-            // ensure we have all the details required to actually connect the client to the server
-            // (ie. we have a nick, user, etc)
-            let connection = match connection::negotiate_client_connection(&mut read, &mut write, addr, &persistence, database, &resolver, &keys).await {
-                Ok(Some(v)) => v,
-                Ok(None) => {
-                    error!("Failed to fully handshake with client, dropping connection");
-
-                    let command = Command::ERROR("You must use SASL to connect to this server".to_string());
-                    if let Err(error) = write.send(Message { tags: None, prefix: None, command, }).await {
-                        error!(%error, "Failed to send error message to client, forcefully closing connection.");
-                    }
-
-                    return;
-                }
-                Err(error) => {
-                    error!(%error, "An error occurred whilst handshaking with client");
-
-                    let command = Command::ERROR(error.to_string());
-                    if let Err(error) = write.send(Message { tags: None, prefix: None, command, }).await {
-                        error!(%error, "Failed to send error message to client, forcefully closing connection.");
-                    }
-
-                    return;
-                }
-            };
-
-            match server.send(ValidateConnection(connection.clone())).await.unwrap() {
-                ConnectionValidated::Allowed => {}
-                ConnectionValidated::Reject(reason) => {
-                    let command = Command::ERROR(reason.to_string());
-                    if let Err(error) = write.send(Message { tags: None, prefix: None, command, }).await {
-                        error!(%error, "Failed to send error message to client, forcefully closing connection.");
-                    }
-                    return;
-                }
-            }
-
-            // spawn the client's actor
-            let handle = {
-                let server = server.clone();
-                let arbiter = client_arbiters.choose(&mut rand::thread_rng()).map_or_else(Arbiter::current, Arbiter::handle);
-                let span = span.clone();
-                let connection = connection.clone();
-
-                Client::start_in_arbiter(&arbiter, move |ctx| {
-                    // setup the writer codec for the user
-                    let (stream, codec, buffer) = unpack_writer(write);
-                    let writer = FramedWrite::from_buffer(stream, codec, buffer, ctx);
-
-                    // add the user's incoming tcp stream to the actor, messages over the tcp stream
-                    // will be sent to the actor over the `StreamHandler`
-                    ctx.add_stream(read);
-
-                    Client {
-                        writer,
-                        connection,
-                        server,
-                        channels: HashMap::new(),
-                        last_active: Instant::now(),
-                        graceful_shutdown: false,
-                        server_leave_reason: None,
-                        span,
-                        persistence,
-                    }
-                })
-            };
-
-            // inform the server of the new connection
-            server.do_send(UserConnected { handle, connection, span });
-        }.instrument(info_span!("negotiation")));
+        tokio::select! {
+            res = tokio::signal::ctrl_c() => res,
+            _ = terminate.recv() => Ok(()),
+        }
     }
-}
 
-/// Unpacks a tokio framed writer, for instantiating an Actix framed writer once connection
-/// instantiation is complete.
-#[must_use]
-pub fn unpack_writer(
-    mut writer: tokio_util::codec::FramedWrite<WriteHalf<TcpStream>, IrcCodec>,
-) -> (WriteHalf<TcpStream>, IrcCodec, BytesMut) {
-    let codec = std::mem::replace(writer.encoder_mut(), irc_codec());
-    let bytes = writer.write_buffer_mut().split();
-    let stream = writer.into_inner();
-
-    (stream, codec, bytes)
-}
-
-#[must_use]
-pub fn irc_codec() -> IrcCodec {
-    IrcCodec::new("utf8").unwrap()
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await
 }
 
 #[must_use]
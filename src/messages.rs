@@ -1,24 +1,92 @@
-use std::time::Duration;
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 
 use actix::{Addr, Message};
 use anyhow::Result;
-use irc_proto::{ChannelMode, Mode};
+use irc_proto::{message::Tag, ChannelMode, Command, Mode, Prefix};
 use tracing::Span;
 
 use crate::{
-    channel::Channel,
+    channel::{
+        list::ListQuery,
+        metadata::ChannelMetadataKey,
+        permissions::{Permission, PermissionDetails},
+        Channel,
+    },
     client::Client,
+    config::{CommandsConfig, ConnectionClass, FallbackNick, ListenerConfig, OperSessionConfig},
     connection::{InitiatedConnection, UserId},
-    host_mask::HostMask,
-    server::response::NoSuchNick,
+    host_mask::{BanMask, HostMask},
+    server::response::{NoSuchChannel, NoSuchNick, SpyStatus, TapStatus},
+    services::link::ServicesLink,
 };
 
+/// Sent to the `ListenerManager` to start accepting connections on a new address. If a
+/// class is given, all clients connecting via the listener are placed into it. TLS listeners
+/// require the server to have been configured with a certificate.
+#[derive(Message, Clone, PartialEq, Eq)]
+#[rtype(result = "std::io::Result<()>")]
+pub struct BindListener {
+    pub address: SocketAddr,
+    pub class: Option<String>,
+    pub v6_only: bool,
+    pub tls: bool,
+}
+
+impl From<ListenerConfig> for BindListener {
+    fn from(config: ListenerConfig) -> Self {
+        Self {
+            address: config.address,
+            class: config.class,
+            v6_only: config.v6_only,
+            tls: config.tls,
+        }
+    }
+}
+
+/// Sent to the `ListenerManager` once the config has been reloaded, bringing the bound
+/// listeners in line with the config and applying the new options to future connections.
+#[derive(Message, Clone)]
+#[rtype(result = "()")]
+pub struct ReloadListeners {
+    pub listeners: Vec<ListenerConfig>,
+    pub classes: Vec<ConnectionClass>,
+    pub oper_session: OperSessionConfig,
+    pub fallback_nick: FallbackNick,
+    pub commands: Arc<CommandsConfig>,
+}
+
+/// Sent to every connected client once the config has been reloaded, so changes to the
+/// disabled commands apply straight away.
+#[derive(Message, Clone)]
+#[rtype(result = "()")]
+pub struct UpdateCommandsConfig {
+    pub commands: Arc<CommandsConfig>,
+}
+
+/// Sent to the server when the process is asked to stop, the server stops accepting
+/// connections and forwards this onto every client before waiting on their state to be
+/// persisted. Clients are disconnected without parting their channels, so they're rejoined to
+/// them when they reconnect.
+#[derive(Message, Clone)]
+#[rtype(result = "()")]
+pub struct Shutdown {
+    pub span: Span,
+}
+
+/// Sent to the `ListenerManager` to stop accepting connections on an address, returns
+/// false if no listener was bound to the address.
+#[derive(Message, Clone)]
+#[rtype(result = "bool")]
+pub struct UnbindListener {
+    pub address: SocketAddr,
+}
+
 /// Sent when a user is connecting to the server.
 #[derive(Message, Clone)]
 #[rtype(result = "()")]
 pub struct UserConnected {
     pub handle: Addr<Client>,
-    pub connection: InitiatedConnection,
+    pub connection: Arc<InitiatedConnection>,
     pub span: Span,
 }
 
@@ -41,6 +109,16 @@ pub struct KillUser {
     pub killed: String,
 }
 
+/// Temporarily holds a nick or channel, preventing anyone from using it until `duration` has
+/// passed.
+#[derive(Message, Clone)]
+#[rtype(result = "()")]
+pub struct HoldResource {
+    pub span: Span,
+    pub name: String,
+    pub duration: Duration,
+}
+
 #[derive(Message, Clone)]
 #[rtype(result = "Result<(), NoSuchNick>")]
 pub struct ForceDisconnect {
@@ -49,6 +127,54 @@ pub struct ForceDisconnect {
     pub comment: String,
 }
 
+/// Fetches each of the connections currently logged into an account.
+#[derive(Message, Clone)]
+#[rtype(result = "Vec<Arc<InitiatedConnection>>")]
+pub struct FetchSessions {
+    pub span: Span,
+    pub user_id: UserId,
+}
+
+/// Disconnects one of an account's sessions, identified by its session ID. Returns `false`
+/// if the account has no such session.
+#[derive(Message, Clone)]
+#[rtype(result = "bool")]
+pub struct LogoutSession {
+    pub span: Span,
+    pub user_id: UserId,
+    pub session_id: String,
+}
+
+/// Disconnects every session logged into an account, such as once the account's been dropped.
+#[derive(Message, Clone)]
+#[rtype(result = "()")]
+pub struct DisconnectAccount {
+    pub span: Span,
+    pub user_id: UserId,
+    pub reason: String,
+}
+
+/// Starts or stops tapping a user's raw traffic.
+#[derive(Message, Clone)]
+#[rtype(result = "Result<TapStatus, NoSuchNick>")]
+pub struct TapClient {
+    pub span: Span,
+    pub nick: String,
+    pub enabled: bool,
+}
+
+/// Starts or stops an operator observing a channel's traffic without joining it, sent to the
+/// server by `SPY` and forwarded on to the channel.
+#[derive(Message, Clone)]
+#[rtype(result = "Result<SpyStatus, NoSuchChannel>")]
+pub struct SpyChannel {
+    pub span: Span,
+    pub client: Addr<Client>,
+    pub connection: Arc<InitiatedConnection>,
+    pub channel: String,
+    pub enabled: bool,
+}
+
 /// Internal event to update a user's nick.
 #[derive(Message, Clone)]
 #[rtype(result = "()")]
@@ -58,31 +184,35 @@ pub struct UserNickChangeInternal {
     pub span: Span,
 }
 
-/// Sent when the user changes their nick.
+/// Sent once the user has changed their nick so other users can be notified.
 #[derive(Message, Clone)]
 #[rtype(result = "()")]
 pub struct UserNickChange {
     pub client: Addr<Client>,
-    pub connection: InitiatedConnection,
+    pub old_prefix: Prefix,
     pub new_nick: String,
     pub span: Span,
 }
 
-#[derive(Message, Clone)]
-#[rtype(result = "()")]
-pub struct Wallops {
-    pub message: String,
+/// Checks the credentials given to `OPER` against the configured operator accounts, returning
+/// true if they match one.
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct CheckOperCredentials {
+    pub name: String,
+    pub password: String,
     pub span: Span,
 }
 
-/// List all the channels a user is connected to
 #[derive(Message, Clone)]
-#[rtype(result = "Vec<(crate::channel::permissions::Permission, String)>")]
-pub struct ConnectedChannels {
+#[rtype(result = "()")]
+pub struct Wallops {
+    pub message: String,
     pub span: Span,
 }
 
-/// Marks the client as being away (or not away, if `message` is none)
+/// Informs channels that the client has marked themselves as being away (or not away, if `message`
+/// is none) so members can be notified
 #[derive(Message, Clone)]
 #[rtype(result = "()")]
 pub struct ClientAway {
@@ -91,11 +221,23 @@ pub struct ClientAway {
     pub message: Option<String>,
 }
 
-/// Fetches all the channels visible to the user.
+/// Updates (or lists) the user's caller-id accept list.
+#[derive(Message, Clone)]
+#[rtype(result = "super::server::response::AcceptList")]
+pub struct UpdateAcceptList {
+    pub span: Span,
+    pub client: Addr<Client>,
+    pub changes: Vec<String>,
+}
+
+/// Fetches all the channels visible to the user that match the query.
 #[derive(Message, Clone)]
 #[rtype(result = "super::server::response::ChannelList")]
 pub struct ChannelList {
     pub span: Span,
+    /// The client running the `LIST`, secret and private channels it isn't in are left out
+    pub client: Addr<Client>,
+    pub query: ListQuery,
 }
 
 /// Fetches the WHO list for the given query.
@@ -103,7 +245,11 @@ pub struct ChannelList {
 #[rtype(result = "super::server::response::WhoList")]
 pub struct FetchWhoList {
     pub span: Span,
+    /// The client asking, who invisible users are hidden from unless they share a channel
+    pub client: Addr<Client>,
     pub query: String,
+    /// Whether the requesting client negotiated `multi-prefix`
+    pub multi_prefix: bool,
 }
 
 /// Fetches the WHOIS for the given query.
@@ -111,18 +257,21 @@ pub struct FetchWhoList {
 #[rtype(result = "super::server::response::Whois")]
 pub struct FetchWhois {
     pub span: Span,
+    pub client: Addr<Client>,
     pub query: String,
 }
 
 /// Sent when the user attempts to join a channel.
 #[derive(Message)]
 #[rtype(
-    result = "Result<std::result::Result<Addr<Channel>, super::channel::response::ChannelJoinRejectionReason>>"
+    result = "Result<std::result::Result<super::channel::response::ChannelJoinBurst, super::channel::response::ChannelJoinRejectionReason>>"
 )]
 pub struct ChannelJoin {
     pub channel_name: String,
+    /// The key given by the user for joining a channel with a key (`+k`) set.
+    pub key: Option<String>,
     pub client: Addr<Client>,
-    pub connection: InitiatedConnection,
+    pub connection: Arc<InitiatedConnection>,
     pub span: Span,
 }
 
@@ -135,19 +284,86 @@ pub struct ChannelPart {
     pub span: Span,
 }
 
+/// Sent by a channel to the `Server` whenever a member joins or leaves, so the server knows
+/// which channels each client is in without having to ask the client.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct ChannelMembershipChanged {
+    pub channel: String,
+    pub handle: Addr<Channel>,
+    pub client: Addr<Client>,
+    pub joined: bool,
+    pub span: Span,
+}
+
+/// Sent by a temporary channel to the `Server` once its last member leaves.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct ChannelEmptied {
+    pub name: String,
+    pub handle: Addr<Channel>,
+    pub span: Span,
+}
+
+/// Sent by the `Server` to a temporary channel it's no longer routing users to.
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct CloseChannel {
+    pub span: Span,
+}
+
 /// Retrieves the list of users currently in a channel.
 #[derive(Message)]
 #[rtype(result = "super::channel::response::ChannelNamesList")]
 pub struct ChannelMemberList {
     pub span: Span,
+    /// Invisible members, or every member if the channel is secret or private, are left out
+    /// unless this client is in the channel. Every member is listed if `None`
+    pub requester: Option<Addr<Client>>,
 }
 
-/// Retrieves the list of users currently in a channel.
+/// Retrieves the list of users in a channel the client isn't necessarily in, for `NAMES`.
 #[derive(Message)]
-#[rtype(result = "crate::channel::permissions::Permission")]
+#[rtype(result = "super::channel::response::ChannelNamesList")]
+pub struct FetchChannelMemberList {
+    pub span: Span,
+    pub client: Addr<Client>,
+    pub channel: String,
+}
+
+/// Retrieves the permission a user holds in a channel, for `WHOIS`. Returns `None` if the
+/// channel is secret or private and `requester` isn't in it.
+#[derive(Message)]
+#[rtype(result = "Option<crate::channel::permissions::Permission>")]
 pub struct FetchUserPermission {
     pub span: Span,
     pub host_mask: HostMask<'static>,
+    /// The client running the `WHOIS`, every channel is included if `None`
+    pub requester: Option<Addr<Client>>,
+}
+
+/// Sent to a channel whenever one of its permissions has been persisted, so its cached
+/// permissions stay current regardless of who made the change. `version` is incremented by one
+/// for every change made to the channel's permissions.
+#[derive(Message, Clone)]
+#[rtype(result = "()")]
+pub struct PermissionsChanged {
+    pub mask: HostMask<'static>,
+    pub permissions: Permission,
+    pub details: PermissionDetails,
+    pub version: i64,
+}
+
+/// Sent by persistence when writing messages to the database starts failing, and again once
+/// the messages buffered in the meantime have been written.
+#[derive(Message, Clone, Copy, Debug)]
+#[rtype(result = "()")]
+pub enum DatabaseHealthChanged {
+    /// Messages are being buffered, and will next be retried after `retry_in`.
+    Unavailable { retry_in: Duration },
+    /// Messages are being written again, `dropped` of them were lost because the buffer filled
+    /// up.
+    Restored { dropped: usize },
 }
 
 /// Retrieves the current channel topic.
@@ -163,6 +379,11 @@ pub struct ChannelFetchTopic {
 #[rtype(result = "super::channel::response::ChannelWhoList")]
 pub struct ChannelFetchWhoList {
     pub span: Span,
+    /// Invisible members are left out unless this client is in the channel, every member is
+    /// listed if `None`
+    pub requester: Option<Addr<Client>>,
+    /// Whether the requesting client negotiated `multi-prefix`
+    pub multi_prefix: bool,
 }
 
 /// Sets the given modes on a channel.
@@ -177,8 +398,8 @@ pub struct ChannelSetMode {
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct Gline {
-    pub requester: InitiatedConnection,
-    pub mask: HostMask<'static>,
+    pub requester: Arc<InitiatedConnection>,
+    pub mask: BanMask,
     pub duration: Option<Duration>,
     pub reason: Option<String>,
 }
@@ -186,16 +407,56 @@ pub struct Gline {
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct RemoveGline {
-    pub mask: HostMask<'static>,
+    pub mask: BanMask,
 }
 
 #[derive(Message)]
 #[rtype(result = "Vec<super::server::response::ServerBan>")]
 pub struct ListGline;
 
+/// Re-reads the config file the server was started with, replacing the running config.
+#[derive(Message)]
+#[rtype(result = "super::server::response::Rehash")]
+pub struct ReloadConfig {
+    pub span: Span,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Shun {
+    pub requester: Arc<InitiatedConnection>,
+    pub mask: HostMask<'static>,
+    pub duration: Option<Duration>,
+    pub reason: Option<String>,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct RemoveShun {
+    pub mask: HostMask<'static>,
+}
+
+#[derive(Message)]
+#[rtype(result = "Vec<super::server::response::ServerBan>")]
+pub struct ListShun;
+
+/// Sent to a client whenever they become shunned or unshunned, shunned clients have all of
+/// their commands except for `PING` and `PONG` dropped.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct ClientShunned(pub bool);
+
 #[derive(Message)]
 #[rtype(result = "super::server::response::ConnectionValidated")]
-pub struct ValidateConnection(pub InitiatedConnection);
+pub struct ValidateConnection(pub Arc<InitiatedConnection>);
+
+/// Checks whether a nick is in use by any online session other than `client`'s.
+#[derive(Message)]
+#[rtype(result = "super::server::response::NickAvailability")]
+pub struct CheckNickAvailability {
+    pub nick: String,
+    pub client: Option<Addr<Client>>,
+}
 
 /// Attempts to kick a user from a channel.
 #[derive(Message)]
@@ -205,12 +466,16 @@ pub struct ChannelKickUser {
     pub client: Addr<Client>,
     pub user: String,
     pub reason: Option<String>,
+    /// Bans the user's host from the channel before kicking them.
+    pub ban: bool,
 }
 
 /// Fetch the message of the day from the server.
 #[derive(Message)]
 #[rtype(result = "super::server::response::Motd")]
 pub struct ServerFetchMotd {
+    /// The class of the requesting client, which may override the server's MOTD
+    pub class: Arc<ConnectionClass>,
     pub span: Span,
 }
 
@@ -221,6 +486,14 @@ pub struct ServerListUsers {
     pub span: Span,
 }
 
+/// Returns the result of `STATS`.
+#[derive(Message)]
+#[rtype(result = "super::server::response::Stats")]
+pub struct ServerStats {
+    pub span: Span,
+    pub query: String,
+}
+
 /// Returns the result of `ADMIN`.
 #[derive(Message)]
 #[rtype(result = "super::server::response::AdminInfo")]
@@ -246,6 +519,31 @@ pub struct ChannelUpdateTopic {
     pub span: Span,
 }
 
+/// Sent from a user to a channel to set or clear a piece of its metadata, returns false if the
+/// user isn't allowed to.
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct ChannelSetMetadata {
+    pub key: ChannelMetadataKey,
+    pub value: Option<String>,
+    pub client: Addr<Client>,
+    pub span: Span,
+}
+
+/// Retrieves the time the channel was created.
+#[derive(Message)]
+#[rtype(result = "crate::channel::response::ChannelCreationTime")]
+pub struct ChannelFetchCreationTime {
+    pub span: Span,
+}
+
+/// Retrieves the metadata currently set on the channel.
+#[derive(Message)]
+#[rtype(result = "crate::channel::metadata::ChannelMetadata")]
+pub struct ChannelFetchMetadata {
+    pub span: Span,
+}
+
 /// Sends a raw irc message to a channel/user.
 #[derive(Message, Clone)]
 #[rtype(result = "()")]
@@ -256,18 +554,45 @@ pub struct Broadcast {
 
 /// Fetches the user's current connection info (nick, host, etc)
 #[derive(Message)]
-#[rtype(result = "crate::connection::InitiatedConnection")]
+#[rtype(result = "Arc<crate::connection::InitiatedConnection>")]
 pub struct FetchClientDetails {
     pub span: Span,
 }
 
-#[derive(Copy, Clone, Debug, sqlx::Type)]
+/// Fetches the amount of messages and bytes that have passed between the server and the client.
+#[derive(Message)]
+#[rtype(result = "crate::client::traffic::TrafficSnapshot")]
+pub struct FetchClientTraffic {
+    pub span: Span,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, sqlx::Type)]
 #[repr(i16)]
 pub enum MessageKind {
     /// PRIVMSG from a client
     Normal = 0,
     /// NOTICE from a client
     Notice = 1,
+    /// TAGMSG from a client, these only carry tags and are never persisted
+    Tag = 2,
+}
+
+impl MessageKind {
+    /// Builds the command to deliver a message of this kind to `target`.
+    #[must_use]
+    pub fn into_command(self, target: String, message: String) -> Command {
+        match self {
+            Self::Normal => Command::PRIVMSG(target, message),
+            Self::Notice => Command::NOTICE(target, message),
+            Self::Tag => Command::Raw("TAGMSG".to_string(), vec![target]),
+        }
+    }
+
+    /// Whether messages of this kind are kept for replay to users that haven't seen them.
+    #[must_use]
+    pub const fn is_persisted(self) -> bool {
+        !matches!(self, Self::Tag)
+    }
 }
 
 /// Sends a message to a channel.
@@ -277,6 +602,33 @@ pub struct ChannelMessage {
     pub client: Addr<Client>,
     pub kind: MessageKind,
     pub message: String,
+    /// Client-only tags (ie. `+typing`) to forward to members that negotiated `message-tags`
+    pub tags: Vec<Tag>,
+    /// Only deliver the message to members with at least this permission (ie. `@#channel`)
+    pub status: Option<Permission>,
+    pub span: Span,
+}
+
+/// Redacts a message previously sent to a channel, only the sender of the message or a channel
+/// operator may do this.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct ChannelRedact {
+    pub client: Addr<Client>,
+    pub msgid: String,
+    pub reason: Option<String>,
+    pub span: Span,
+}
+
+/// Sends a message to another member of the channel on behalf of a voiced user or channel
+/// operator, returning whether the sender held the privilege to do so.
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct ChannelDirectMessage {
+    pub client: Addr<Client>,
+    pub nick: String,
+    pub kind: MessageKind,
+    pub message: String,
     pub span: Span,
 }
 
@@ -289,11 +641,15 @@ pub struct ChannelInvite {
     pub span: Span,
 }
 
-/// Fetches a client handle by nick from the server.
+/// Resolves a nick or channel name to whatever it currently refers to, either an online user,
+/// the account owning the nick or a channel.
 #[derive(Message)]
-#[rtype(result = "Option<Addr<Client>>")]
-pub struct FetchClientByNick {
-    pub nick: String,
+#[rtype(result = "super::server::response::Target")]
+pub struct ResolveTarget {
+    pub target: String,
+    /// The server the target was addressed on as `nick@server`, if it wasn't this one.
+    pub server: Option<String>,
+    pub span: Span,
 }
 
 /// Sends a private message between two users.
@@ -301,8 +657,71 @@ pub struct FetchClientByNick {
 #[rtype(result = "()")]
 pub struct PrivateMessage {
     pub destination: UserId,
+    pub destination_nick: String,
     pub message: String,
     pub kind: MessageKind,
+    /// Client-only tags (ie. `+typing`) to forward to sessions that negotiated `message-tags`
+    pub tags: Vec<Tag>,
     pub from: Addr<Client>,
     pub span: Span,
 }
+
+/// Sent by a services link once services have authenticated, returning every connected user so
+/// they can be introduced to services. Returns `None` if services are already linked.
+#[derive(Message)]
+#[rtype(result = "Option<Vec<(Addr<Client>, Arc<InitiatedConnection>)>>")]
+pub struct ServicesLinked {
+    pub link: Addr<ServicesLink>,
+    /// The server name services linked with.
+    pub name: String,
+}
+
+/// Sent by a services link once the link has closed.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct ServicesUnlinked {
+    pub link: Addr<ServicesLink>,
+}
+
+/// Sent by a services link whenever services introduce or remove one of their clients, along
+/// with the nicks of every client services currently have.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct ServicesClientsChanged {
+    pub nicks: Vec<String>,
+}
+
+/// Sent to the services link as users come and go, so services can keep track of them.
+#[derive(Message, Clone)]
+#[rtype(result = "()")]
+pub enum ServicesUserChanged {
+    Connected {
+        client: Addr<Client>,
+        connection: Arc<InitiatedConnection>,
+    },
+    NickChanged {
+        client: Addr<Client>,
+    },
+    Disconnected {
+        client: Addr<Client>,
+        message: Option<String>,
+    },
+}
+
+/// Sends a message from a user to one of services' clients.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct ServicesPrivateMessage {
+    pub from: Addr<Client>,
+    pub target: String,
+    pub message: String,
+    pub kind: MessageKind,
+}
+
+/// Changes the host shown to other users in place of the user's address.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct ChangeHost {
+    pub host: String,
+    pub span: Span,
+}
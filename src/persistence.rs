@@ -1,33 +1,154 @@
+pub mod breaker;
 pub mod events;
 
-use std::time::Duration;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    future::Future,
+    time::{Duration, Instant},
+};
 
-use actix::{AsyncContext, Context, Handler, ResponseFuture, WrapFuture};
-use chrono::{DateTime, TimeZone, Utc};
+use actix::{
+    ActorFutureExt, AsyncContext, Context, Handler, Recipient, ResponseActFuture, ResponseFuture,
+    WrapFuture,
+};
+use chrono::{DateTime, NaiveDate, NaiveTime, TimeZone, Utc};
+use irc_proto::Prefix;
 use itertools::Itertools;
-use tracing::instrument;
+use tracing::{error, info, instrument, warn};
 
 use crate::{
-    channel::permissions::Permission,
+    channel::{
+        metadata::ChannelMetadata,
+        modes::ChannelModeState,
+        permissions::{Permission, PermissionDetails},
+    },
     connection::UserId,
     host_mask::{HostMask, HostMaskMap},
-    messages::MessageKind,
-    persistence::events::{
-        ChannelCreated, ChannelJoined, ChannelMessage, ChannelParted,
-        FetchAllUserChannelPermissions, FetchUnseenChannelMessages, FetchUnseenPrivateMessages,
-        FetchUserChannels, FetchUserIdByNick, PrivateMessage, ReserveNick, ServerBan,
-        ServerListBan, ServerListBanEntry, ServerRemoveBan, SetUserChannelPermissions,
+    messages::{DatabaseHealthChanged, MessageKind, PermissionsChanged},
+    persistence::{
+        breaker::{Backoff, CircuitBreaker},
+        events::{
+            AddUserCertificate, ChannelCreated, ChannelJoined, ChannelMessage,
+            ChannelMessageReplay, ChannelParted, ChannelReaction, ChannelReactionResult,
+            ClientCountChanged, DailyStatsEntry, FetchAccountByNick,
+            FetchAllUserChannelPermissions, FetchChannelAccess, FetchChannelExists,
+            FetchChannelHistory, FetchChannelInvites, FetchChannelMetadata, FetchChannelModes,
+            FetchChannelReactions, FetchDailyStats, FetchLoginHistory, FetchNickHistory,
+            FetchPrivateHistory, FetchSharesChannel, FetchUnseenChannelMessages,
+            FetchUnseenPrivateMessages, FetchUserChannels, FetchWhowas, Flush, ImportChannelAccess,
+            ImportChannelAccessResult, ListUserCertificates, LoginHistoryEntry, NickHistoryEntry,
+            PrivateMessage, PromoteChannelSuccessors, RecordLogin, RecordWhowas,
+            RedactChannelMessage, RedactChannelMessageResult, RegisterChannel,
+            RegisterChannelResult, RemoveUserCertificate, ReserveNick, ServerBan, ServerListBan,
+            ServerListBanEntry, ServerListShun, ServerRemoveBan, ServerRemoveShun, ServerShun,
+            SetChannelAccess, SetChannelAccessResult, SetChannelInvite, SetChannelMetadata,
+            SetChannelModes, SetChannelSuccessor, SetChannelSuccessorResult,
+            SetUserChannelPermissions, StoredMessage, StoredPrivateMessage, StoredReaction,
+            SubscribeChannelPermissions, SubscribeDatabaseHealth, TransferChannel, WhowasEntry,
+        },
     },
 };
 
+/// The most distinct reactions a single channel message can collect.
+const MAX_REACTIONS_PER_MESSAGE: usize = 20;
+
+/// The most reactions a single user can leave on a single channel message.
+const MAX_REACTIONS_PER_USER: usize = 3;
+
+/// The amount of connections remembered for each account's login history.
+const LOGIN_HISTORY_PER_USER: i64 = 20;
+
+/// The most messages buffered while they can't be written to the database, the oldest are
+/// dropped once it's full.
+const MESSAGE_BUFFER_CAPACITY: usize = 10_000;
+
+/// How long the actor has to stay up after a restart before the delay between restarts is
+/// reset.
+const RESTART_BACKOFF_RESET: Duration = Duration::from_secs(300);
+
 /// Takes events destined for other actors and persists them to the database.
 pub struct Persistence {
     pub database: sqlx::Pool<sqlx::Any>,
     pub max_message_replay_since: Duration,
+    pub max_message_replay_count: u32,
+    /// How long nicks are kept for `WHOWAS` after they stop being used.
+    pub whowas_retention: Duration,
     pub last_seen_clock: i64,
+    /// Live channels to notify of changes to their permissions, keyed by channel ID.
+    pub permission_subscribers: HashMap<i64, Recipient<PermissionsChanged>>,
+    /// Today's counters that haven't been written to `stats_daily` yet.
+    pub daily_stats: DailyStats,
+    /// Backs off restarts and message writes while the database is unavailable.
+    pub health: DatabaseHealth,
+}
+
+/// Counters for a single day, kept in memory and periodically added to the day's row in the
+/// database.
+#[derive(Debug, Default)]
+pub struct DailyStats {
+    /// The day being counted, `None` until the first event is counted.
+    pub day: Option<NaiveDate>,
+    pub current_clients: usize,
+    pub peak_clients: usize,
+    /// Messages sent to each channel since the counters were last written, keyed by channel ID.
+    pub channel_messages: HashMap<i64, i64>,
+}
+
+/// Tracks whether the database is usable, so that restarts and message writes are retried at
+/// a steadily slower pace while it isn't rather than failing as fast as they're attempted.
+pub struct DatabaseHealth {
+    /// The delay before each restart of the actor.
+    pub restarts: Backoff,
+    /// The delay to wait out after being restarted before handling any messages.
+    pub restart_delay: Option<Duration>,
+    /// When the actor was last (re)started.
+    pub started_at: Option<Instant>,
+    /// Channel and private messages waiting to be written while the database is unavailable.
+    pub messages: CircuitBreaker<PendingMessage>,
+    /// Notified when messages stop being written and once they're written again, so the
+    /// server can let operators know.
+    pub subscriber: Option<Recipient<DatabaseHealthChanged>>,
+}
+
+impl Default for DatabaseHealth {
+    fn default() -> Self {
+        Self {
+            restarts: Backoff::default(),
+            restart_delay: None,
+            started_at: None,
+            messages: CircuitBreaker::new(MESSAGE_BUFFER_CAPACITY, Backoff::default()),
+            subscriber: None,
+        }
+    }
+}
+
+/// A message waiting to be written, along with the timestamp it was given when it was sent.
+pub enum PendingMessage {
+    Channel(i64, ChannelMessage),
+    Private(i64, PrivateMessage),
 }
 
 impl Persistence {
+    /// Builds the actor around `database` with empty runtime state.
+    #[must_use]
+    pub fn new(
+        database: sqlx::Pool<sqlx::Any>,
+        max_message_replay_since: Duration,
+        max_message_replay_count: u32,
+        whowas_retention: Duration,
+    ) -> Self {
+        Self {
+            database,
+            max_message_replay_since,
+            max_message_replay_count,
+            whowas_retention,
+            last_seen_clock: 0,
+            permission_subscribers: HashMap::default(),
+            daily_stats: DailyStats::default(),
+            health: DatabaseHealth::default(),
+        }
+    }
+
     /// Grabs the current time to use as an ID, preventing against backwards clockskew.
     fn monotonically_increasing_id(&mut self) -> i64 {
         let now = Utc::now().timestamp_nanos_opt().unwrap();
@@ -40,43 +161,250 @@ impl Persistence {
 
         self.last_seen_clock
     }
+
+    /// Returns today's counters, writing out the previous day's counters first if the day has
+    /// rolled over since they were last touched.
+    fn daily_stats(&mut self, ctx: &mut Context<Self>) -> &mut DailyStats {
+        let today = Utc::now().date_naive();
+
+        if self.daily_stats.day != Some(today) {
+            // the clients still connected count towards the new day's peak
+            let current_clients = self.daily_stats.current_clients;
+            let previous = std::mem::replace(
+                &mut self.daily_stats,
+                DailyStats {
+                    day: Some(today),
+                    current_clients,
+                    peak_clients: current_clients,
+                    channel_messages: HashMap::new(),
+                },
+            );
+
+            if let Some(day) = previous.day {
+                ctx.spawn(
+                    record_daily_stats(
+                        self.database.clone(),
+                        day,
+                        previous.peak_clients,
+                        previous.channel_messages,
+                    )
+                    .into_actor(self),
+                );
+            }
+        }
+
+        &mut self.daily_stats
+    }
+
+    /// Lets the channel know about changes made to its permissions, if it's live. `version` is
+    /// the version of the permissions after the last of the changes.
+    fn notify_permission_changes(
+        &self,
+        channel_id: i64,
+        changes: Vec<(HostMask<'static>, Permission)>,
+        version: i64,
+    ) {
+        let Some(subscriber) = self.permission_subscribers.get(&channel_id) else {
+            return;
+        };
+
+        let first_version = version - i64::try_from(changes.len()).unwrap() + 1;
+        let set_at = Utc::now();
+
+        for ((mask, permissions), version) in changes.into_iter().zip(first_version..) {
+            subscriber.do_send(PermissionsChanged {
+                mask,
+                permissions,
+                details: PermissionDetails {
+                    set_by: None,
+                    set_at: Some(set_at),
+                },
+                version,
+            });
+        }
+    }
+
+    /// Writes out today's counters, the peak is kept around as the database only ever raises it.
+    fn flush_daily_stats(&mut self, ctx: &mut Context<Self>) -> impl Future<Output = ()> {
+        let stats = self.daily_stats(ctx);
+        let day = stats.day.unwrap();
+        let peak_clients = stats.peak_clients;
+        let channel_messages = std::mem::take(&mut stats.channel_messages);
+
+        record_daily_stats(self.database.clone(), day, peak_clients, channel_messages)
+    }
+
+    /// Writes the message, unless the database is already known to be unavailable in which case
+    /// it's buffered to be written once it's available again.
+    fn write_message(&mut self, message: PendingMessage) -> ResponseActFuture<Self, ()> {
+        if self.health.messages.is_open() {
+            self.health.messages.buffer(message);
+            return Box::pin(actix::fut::ready(()));
+        }
+
+        let conn = self.database.clone();
+
+        Box::pin(
+            async move {
+                write_message(&conn, &message)
+                    .await
+                    .map_err(|error| (message, error))
+            }
+            .into_actor(self)
+            .map(|res, this, ctx| {
+                let Err((message, error)) = res else {
+                    return;
+                };
+
+                this.health.messages.buffer(message);
+
+                if this.health.messages.open() {
+                    let retry_in = this.schedule_message_retry(ctx);
+                    error!(
+                        %error,
+                        ?retry_in,
+                        "Failed to write message, buffering until the database is available"
+                    );
+                    this.notify_health(DatabaseHealthChanged::Unavailable { retry_in });
+                }
+            }),
+        )
+    }
+
+    /// Retries writing the buffered messages after the next delay, returning the delay.
+    fn schedule_message_retry(&mut self, ctx: &mut Context<Self>) -> Duration {
+        let delay = self.health.messages.retry_delay();
+        ctx.run_later(delay, Self::retry_buffered_messages);
+        delay
+    }
+
+    /// Writes the buffered messages in the order they were sent, closing the breaker once
+    /// they've all been written or scheduling another retry if the database is still
+    /// unavailable.
+    fn retry_buffered_messages(&mut self, ctx: &mut Context<Self>) {
+        let conn = self.database.clone();
+        let mut messages = self.health.messages.take();
+
+        ctx.spawn(
+            async move {
+                while let Some(message) = messages.front() {
+                    if let Err(error) = write_message(&conn, message).await {
+                        return Err((messages, error));
+                    }
+
+                    messages.pop_front();
+                }
+
+                Ok(())
+            }
+            .into_actor(self)
+            .map(|res, this, ctx| match res {
+                // more messages were buffered while the retry was in flight
+                Ok(()) if !this.health.messages.is_empty() => this.retry_buffered_messages(ctx),
+                Ok(()) => {
+                    let dropped = this.health.messages.close();
+                    info!(
+                        dropped,
+                        "Database is available again, resuming message writes"
+                    );
+                    this.notify_health(DatabaseHealthChanged::Restored { dropped });
+                }
+                Err((messages, error)) => {
+                    this.health.messages.requeue(messages);
+                    let retry_in = this.schedule_message_retry(ctx);
+                    warn!(
+                        %error,
+                        ?retry_in,
+                        buffered = this.health.messages.len(),
+                        "Database is still unavailable"
+                    );
+                }
+            }),
+        );
+    }
+
+    fn notify_health(&self, change: DatabaseHealthChanged) {
+        if let Some(subscriber) = &self.health.subscriber {
+            subscriber.do_send(change);
+        }
+    }
 }
 
-impl actix::Supervised for Persistence {}
+/// Backs off restarts of the actor, so a database that's gone away isn't hammered by an actor
+/// restarting as fast as it can crash.
+impl actix::Supervised for Persistence {
+    fn restarting(&mut self, _ctx: &mut Self::Context) {
+        if self
+            .health
+            .started_at
+            .is_some_and(|v| v.elapsed() >= RESTART_BACKOFF_RESET)
+        {
+            self.health.restarts.reset();
+        }
+
+        let delay = self.health.restarts.next_delay();
+        warn!(
+            ?delay,
+            "Persistence restarting, waiting before handling messages"
+        );
+        self.health.restart_delay = Some(delay);
+    }
+}
 
 impl actix::Actor for Persistence {
     type Context = Context<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
+        let restart_delay = self.health.restart_delay.take().unwrap_or_default();
+        self.health.started_at = Some(Instant::now() + restart_delay);
+
+        if !restart_delay.is_zero() {
+            ctx.wait(tokio::time::sleep(restart_delay).into_actor(self));
+        }
+
+        // the retry scheduled before restarting was cancelled along with everything else
+        if self.health.messages.is_open() {
+            self.schedule_message_retry(ctx);
+        }
+
         // truncate the messages table every 5 minutes for messages all users have seen
         ctx.run_interval(Duration::from_secs(300), |this, ctx| {
             let database = this.database.clone();
             let max_message_replay_since = this.max_message_replay_since;
 
             ctx.spawn(truncate_seen_messages(database, max_message_replay_since).into_actor(this));
+
+            let flush = this.flush_daily_stats(ctx);
+            ctx.spawn(flush.into_actor(this));
+
+            ctx.spawn(
+                truncate_whowas(this.database.clone(), this.whowas_retention).into_actor(this),
+            );
         });
     }
 }
 
 /// Create a new channel in the database, if one doesn't already exist.
 impl Handler<ChannelCreated> for Persistence {
-    type Result = ResponseFuture<i64>;
+    type Result = ResponseFuture<(i64, DateTime<Utc>)>;
 
     fn handle(&mut self, msg: ChannelCreated, _ctx: &mut Self::Context) -> Self::Result {
         let conn = self.database.clone();
+        let now = Utc::now().timestamp_nanos_opt().unwrap();
 
         Box::pin(async move {
             sqlx::query_as(
-                "INSERT OR IGNORE INTO channels
-                 (name) VALUES (?)
+                "INSERT INTO channels
+                 (name, created_timestamp) VALUES ($1, $2)
                  ON CONFLICT(name)
-                   DO UPDATE SET name = name
-                 RETURNING id",
+                   DO UPDATE SET created_timestamp = COALESCE(channels.created_timestamp, excluded.created_timestamp)
+                 RETURNING id, created_timestamp",
             )
             .bind(msg.name)
+            .bind(now)
             .fetch_one(&conn)
             .await
-            .map(|(v,)| v)
+            .map(|(id, created_timestamp)| (id, Utc.timestamp_nanos(created_timestamp)))
             .unwrap()
         })
     }
@@ -92,9 +420,9 @@ impl Handler<ChannelJoined> for Persistence {
 
         Box::pin(async move {
             sqlx::query(
-                "INSERT INTO channel_users (channel, user, in_channel)
-                 VALUES (?, ?, ?)
-                 ON CONFLICT(channel, user) DO UPDATE SET in_channel = excluded.in_channel",
+                "INSERT INTO channel_users (channel, user_id, in_channel)
+                 VALUES ($1, $2, $3)
+                 ON CONFLICT(channel, user_id) DO UPDATE SET in_channel = excluded.in_channel",
             )
             .bind(msg.channel_id.0)
             .bind(msg.user_id.0)
@@ -118,8 +446,8 @@ impl Handler<ChannelParted> for Persistence {
             sqlx::query(
                 "UPDATE channel_users
                  SET in_channel = false
-                 WHERE channel = ?
-                   AND user = ?",
+                 WHERE channel = $1
+                   AND user_id = $2",
             )
             .bind(msg.channel_id.0)
             .bind(msg.user_id.0)
@@ -131,7 +459,11 @@ impl Handler<ChannelParted> for Persistence {
 }
 
 impl Handler<FetchAllUserChannelPermissions> for Persistence {
-    type Result = ResponseFuture<HostMaskMap<Permission>>;
+    type Result = ResponseFuture<(
+        HostMaskMap<Permission>,
+        HashMap<String, PermissionDetails>,
+        i64,
+    )>;
 
     fn handle(
         &mut self,
@@ -141,148 +473,197 @@ impl Handler<FetchAllUserChannelPermissions> for Persistence {
         let conn = self.database.clone();
 
         Box::pin(async move {
-            sqlx::query_as::<_, (HostMask, Permission)>(
-                "SELECT mask, permissions
+            // the permissions and their version need to be read together, otherwise a change
+            // could slip in between the two
+            let mut transaction = conn.begin().await.unwrap();
+
+            let rows = sqlx::query_as::<_, (HostMask, Permission, Option<String>, Option<i64>)>(
+                "SELECT mask, permissions, set_by, set_at
                  FROM channel_permissions
-                 WHERE channel = ?",
+                 WHERE channel = $1",
             )
             .bind(msg.channel_id.0)
-            .fetch_all(&conn)
+            .fetch_all(&mut *transaction)
             .await
-            .unwrap()
-            .into_iter()
-            .collect()
+            .unwrap();
+
+            let mut permissions = HostMaskMap::new();
+            let mut details = HashMap::with_capacity(rows.len());
+
+            for (mask, permission, set_by, set_at) in rows {
+                permissions.insert(&mask, permission);
+                details.insert(
+                    mask.to_string(),
+                    PermissionDetails {
+                        set_by,
+                        set_at: set_at.map(|v| Utc.timestamp_nanos(v)),
+                    },
+                );
+            }
+
+            let (version,) = sqlx::query_as::<_, (i64,)>(
+                "SELECT permissions_version
+                 FROM channels
+                 WHERE id = $1",
+            )
+            .bind(msg.channel_id.0)
+            .fetch_one(&mut *transaction)
+            .await
+            .unwrap();
+
+            transaction.commit().await.unwrap();
+
+            (permissions, details, version)
         })
     }
 }
 
+impl Handler<SubscribeDatabaseHealth> for Persistence {
+    type Result = ();
+
+    fn handle(&mut self, msg: SubscribeDatabaseHealth, _ctx: &mut Self::Context) -> Self::Result {
+        self.health.subscriber = Some(msg.subscriber);
+    }
+}
+
+impl Handler<SubscribeChannelPermissions> for Persistence {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        msg: SubscribeChannelPermissions,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        self.permission_subscribers
+            .retain(|_, channel| channel.connected());
+        self.permission_subscribers
+            .insert(msg.channel_id.0, msg.channel);
+    }
+}
+
+/// Persists a change to a channel's permissions, and lets the channel know about it once
+/// it's been written so its cache reflects changes made from anywhere.
 impl Handler<SetUserChannelPermissions> for Persistence {
-    type Result = ResponseFuture<()>;
+    type Result = ResponseActFuture<Self, ()>;
 
     fn handle(&mut self, msg: SetUserChannelPermissions, _ctx: &mut Self::Context) -> Self::Result {
         let conn = self.database.clone();
+        let channel_id = msg.channel_id;
+        let mask = msg.mask.clone();
+        let permissions = msg.permissions;
+        let set_at = Utc::now();
+        let details = PermissionDetails {
+            set_by: msg.set_by.clone(),
+            set_at: Some(set_at),
+        };
+
+        let fut = async move {
+            let mut transaction = conn.begin().await.unwrap();
 
-        Box::pin(async move {
             sqlx::query(
-                "INSERT INTO channel_permissions (channel, mask, permissions)
-                 VALUES (?, ?, ?)
-                 ON CONFLICT(channel, mask) DO UPDATE SET permissions = excluded.permissions",
+                "INSERT INTO channel_permissions (channel, mask, permissions, set_by, set_at)
+                 VALUES ($1, $2, $3, $4, $5)
+                 ON CONFLICT(channel, mask) DO UPDATE SET
+                    permissions = excluded.permissions,
+                    set_by = excluded.set_by,
+                    set_at = excluded.set_at",
             )
             .bind(msg.channel_id.0)
             .bind(msg.mask)
             .bind(msg.permissions)
-            .execute(&conn)
+            .bind(msg.set_by)
+            .bind(set_at.timestamp_nanos_opt().unwrap())
+            .execute(&mut *transaction)
             .await
             .unwrap();
-        })
-    }
-}
-
-impl Handler<FetchUserChannels> for Persistence {
-    type Result = ResponseFuture<Vec<String>>;
-
-    fn handle(&mut self, msg: FetchUserChannels, _ctx: &mut Self::Context) -> Self::Result {
-        let conn = self.database.clone();
 
-        Box::pin(async move {
-            sqlx::query_as(
-                "SELECT channels.name
-                  FROM channel_users
-                  INNER JOIN channels
-                    ON channels.id = channel_users.channel
-                  WHERE user = ?
-                    AND in_channel = true",
+            let (version,) = sqlx::query_as::<_, (i64,)>(
+                "UPDATE channels
+                 SET permissions_version = permissions_version + 1
+                 WHERE id = $1
+                 RETURNING permissions_version",
             )
-            .bind(msg.user_id.0)
-            .fetch_all(&conn)
+            .bind(msg.channel_id.0)
+            .fetch_one(&mut *transaction)
             .await
-            .unwrap()
-            .into_iter()
-            .map(|(v,)| v)
-            .collect()
-        })
-    }
-}
+            .unwrap();
 
-impl Handler<FetchUserIdByNick> for Persistence {
-    type Result = ResponseFuture<Option<UserId>>;
+            transaction.commit().await.unwrap();
 
-    fn handle(&mut self, msg: FetchUserIdByNick, _ctx: &mut Self::Context) -> Self::Result {
-        let conn = self.database.clone();
+            version
+        };
 
-        Box::pin(async move {
-            sqlx::query_as(
-                "SELECT user
-                 FROM user_nicks
-                 WHERE nick = ?",
-            )
-            .bind(msg.nick)
-            .fetch_optional(&conn)
-            .await
-            .unwrap()
-            .map(|(v,)| v)
-        })
+        Box::pin(fut.into_actor(self).map(move |version, this, _ctx| {
+            if let Some(channel) = this.permission_subscribers.get(&channel_id.0) {
+                channel.do_send(PermissionsChanged {
+                    mask,
+                    permissions,
+                    details,
+                    version,
+                });
+            }
+        }))
     }
 }
 
-impl Handler<ChannelMessage> for Persistence {
-    type Result = ResponseFuture<()>;
+impl Handler<FetchChannelModes> for Persistence {
+    type Result = ResponseFuture<ChannelModeState>;
 
-    fn handle(&mut self, msg: ChannelMessage, _ctx: &mut Self::Context) -> Self::Result {
+    fn handle(&mut self, msg: FetchChannelModes, _ctx: &mut Self::Context) -> Self::Result {
         let conn = self.database.clone();
-        let timestamp = self.monotonically_increasing_id();
 
         Box::pin(async move {
-            sqlx::query(
-                "INSERT INTO channel_messages (channel, timestamp, sender, message, kind) VALUES (?, ?, ?, ?, ?)",
-            )
-            .bind(msg.channel_id.0)
-            .bind(timestamp)
-            .bind(msg.sender)
-            .bind(msg.message)
-            .bind(msg.kind)
-            .execute(&conn)
-            .await
-            .unwrap();
-
-            if !msg.receivers.is_empty() {
-                let query = format!(
-                    "UPDATE channel_users
-                     SET last_seen_message_timestamp = ?
-                     WHERE channel = ?
-                       AND user IN ({})",
-                    msg.receivers.iter().map(|_| "?").join(",")
-                );
-
-                let mut query = sqlx::query(&query).bind(timestamp).bind(msg.channel_id.0);
-                for receiver in msg.receivers {
-                    query = query.bind(receiver.0);
-                }
+            let (invite_only, key, limit, moderated, private, secret, topic_lock) =
+                sqlx::query_as::<_, (bool, Option<String>, Option<i64>, bool, bool, bool, bool)>(
+                    "SELECT invite_only, channel_key, client_limit, moderated, private, secret,
+                            topic_lock
+                     FROM channels
+                     WHERE id = $1",
+                )
+                .bind(msg.channel_id.0)
+                .fetch_one(&conn)
+                .await
+                .unwrap();
 
-                query.execute(&conn).await.unwrap();
+            ChannelModeState {
+                invite_only,
+                key,
+                limit: limit.and_then(|v| usize::try_from(v).ok()),
+                moderated,
+                private,
+                secret,
+                topic_lock,
             }
         })
     }
 }
 
-impl Handler<PrivateMessage> for Persistence {
+impl Handler<SetChannelModes> for Persistence {
     type Result = ResponseFuture<()>;
 
-    fn handle(&mut self, msg: PrivateMessage, _ctx: &mut Self::Context) -> Self::Result {
+    fn handle(&mut self, msg: SetChannelModes, _ctx: &mut Self::Context) -> Self::Result {
         let conn = self.database.clone();
-        let timestamp = self.monotonically_increasing_id();
 
         Box::pin(async move {
             sqlx::query(
-                "INSERT INTO private_messages
-                 (timestamp, sender, receiver, message, kind)
-                 VALUES (?, ?, ?, ?, ?)",
+                "UPDATE channels
+                 SET invite_only = $1,
+                     channel_key = $2,
+                     client_limit = $3,
+                     moderated = $4,
+                     private = $5,
+                     secret = $6,
+                     topic_lock = $7
+                 WHERE id = $8",
             )
-            .bind(timestamp)
-            .bind(msg.sender)
-            .bind(msg.receiver)
-            .bind(msg.message)
-            .bind(msg.kind)
+            .bind(msg.modes.invite_only)
+            .bind(msg.modes.key)
+            .bind(msg.modes.limit.and_then(|v| i64::try_from(v).ok()))
+            .bind(msg.modes.moderated)
+            .bind(msg.modes.private)
+            .bind(msg.modes.secret)
+            .bind(msg.modes.topic_lock)
+            .bind(msg.channel_id.0)
             .execute(&conn)
             .await
             .unwrap();
@@ -290,203 +671,3371 @@ impl Handler<PrivateMessage> for Persistence {
     }
 }
 
-impl Handler<FetchUnseenPrivateMessages> for Persistence {
-    type Result = ResponseFuture<Vec<(DateTime<Utc>, String, String, MessageKind)>>;
+impl Handler<FetchChannelExists> for Persistence {
+    type Result = ResponseFuture<bool>;
 
-    fn handle(
-        &mut self,
-        msg: FetchUnseenPrivateMessages,
-        _ctx: &mut Self::Context,
-    ) -> Self::Result {
+    fn handle(&mut self, msg: FetchChannelExists, _ctx: &mut Self::Context) -> Self::Result {
         let conn = self.database.clone();
 
         Box::pin(async move {
-            sqlx::query_as(
-                "DELETE FROM private_messages
-                 WHERE receiver = ?
-                 RETURNING timestamp, sender, message, kind",
-            )
-            .bind(msg.user_id)
-            .fetch_all(&conn)
-            .await
-            .unwrap()
-            .into_iter()
-            .map(|(timestamp, sender, message, kind)| {
-                (Utc.timestamp_nanos(timestamp), sender, message, kind)
-            })
-            .collect()
+            sqlx::query_as::<_, (i64,)>("SELECT id FROM channels WHERE name = $1")
+                .bind(msg.name)
+                .fetch_optional(&conn)
+                .await
+                .unwrap()
+                .is_some()
         })
     }
 }
 
-impl Handler<FetchUnseenChannelMessages> for Persistence {
-    type Result = ResponseFuture<Vec<(DateTime<Utc>, String, String, MessageKind)>>;
+impl Handler<FetchChannelMetadata> for Persistence {
+    type Result = ResponseFuture<ChannelMetadata>;
 
-    #[instrument(parent = &msg.span, skip_all)]
-    fn handle(
-        &mut self,
-        msg: FetchUnseenChannelMessages,
-        _ctx: &mut Self::Context,
-    ) -> Self::Result {
+    fn handle(&mut self, msg: FetchChannelMetadata, _ctx: &mut Self::Context) -> Self::Result {
         let conn = self.database.clone();
-        let max_message_reply_since =
-            Utc::now() - chrono::Duration::from_std(self.max_message_replay_since).unwrap();
 
         Box::pin(async move {
-            // select the last 500 messages, or the last message the user saw - whichever dataset
-            // is smaller.
-            sqlx::query_as(
-                "WITH channel AS (SELECT id FROM channels WHERE name = ?)
-                 SELECT timestamp, sender, message, kind
-                 FROM channel_messages
-                 WHERE channel = (SELECT id FROM channel)
-                    AND timestamp > MAX(
-                      ?,
-                      COALESCE((
-                        SELECT last_seen_message_timestamp
-                        FROM channel_users
-                        WHERE channel = (SELECT id FROM channel)
-                          AND user = ?
-                      ), 0)
-                    )
-                 ORDER BY timestamp ASC",
-            )
-            .bind(&msg.channel_name)
-            .bind(max_message_reply_since.timestamp_nanos_opt().unwrap())
-            .bind(msg.user_id.0)
-            .fetch_all(&conn)
-            .await
-            .unwrap()
-            .into_iter()
-            .map(|(timestamp, sender, message, kind)| {
-                (Utc.timestamp_nanos(timestamp), sender, message, kind)
-            })
-            .collect()
+            let (language, category, website) =
+                sqlx::query_as::<_, (Option<String>, Option<String>, Option<String>)>(
+                    "SELECT language, category, website FROM channels WHERE id = $1",
+                )
+                .bind(msg.channel_id.0)
+                .fetch_one(&conn)
+                .await
+                .unwrap();
+
+            ChannelMetadata {
+                language,
+                category,
+                website,
+            }
         })
     }
 }
 
-impl Handler<ReserveNick> for Persistence {
-    type Result = ResponseFuture<bool>;
+impl Handler<SetChannelMetadata> for Persistence {
+    type Result = ResponseFuture<()>;
 
-    fn handle(&mut self, msg: ReserveNick, _ctx: &mut Self::Context) -> Self::Result {
-        let database = self.database.clone();
+    fn handle(&mut self, msg: SetChannelMetadata, _ctx: &mut Self::Context) -> Self::Result {
+        let conn = self.database.clone();
 
         Box::pin(async move {
-            let (owning_user,): (i64,) = sqlx::query_as(
-                "INSERT INTO user_nicks (nick, user)
-                 VALUES (?, ?)
-                 ON CONFLICT(nick) DO UPDATE SET nick = nick
-                 RETURNING user",
+            sqlx::query(
+                "UPDATE channels
+                 SET language = $1,
+                     category = $2,
+                     website = $3
+                 WHERE id = $4",
             )
-            .bind(msg.nick)
-            .bind(msg.user_id.0)
-            .fetch_one(&database)
+            .bind(msg.metadata.language)
+            .bind(msg.metadata.category)
+            .bind(msg.metadata.website)
+            .bind(msg.channel_id.0)
+            .execute(&conn)
             .await
             .unwrap();
-
-            owning_user == msg.user_id.0
         })
     }
 }
 
-impl Handler<ServerBan> for Persistence {
-    type Result = ResponseFuture<()>;
+impl Handler<FetchChannelInvites> for Persistence {
+    type Result = ResponseFuture<HashSet<UserId>>;
 
-    fn handle(&mut self, msg: ServerBan, _ctx: &mut Self::Context) -> Self::Result {
-        let database = self.database.clone();
+    fn handle(&mut self, msg: FetchChannelInvites, _ctx: &mut Self::Context) -> Self::Result {
+        let conn = self.database.clone();
 
         Box::pin(async move {
-            sqlx::query(
-                "INSERT INTO server_bans
-                 (mask, requester, reason, created_timestamp, expires_timestamp)
-                 VALUES (?, ?, ?, ?, ?)",
-            )
-            .bind(msg.mask)
-            .bind(msg.requester)
-            .bind(msg.reason)
-            .bind(msg.created.timestamp_nanos_opt().unwrap())
-            .bind(msg.expires.map(|v| v.timestamp_nanos_opt().unwrap()))
-            .execute(&database)
-            .await
-            .unwrap();
+            sqlx::query_as::<_, (UserId,)>("SELECT user_id FROM channel_invites WHERE channel = $1")
+                .bind(msg.channel_id.0)
+                .fetch_all(&conn)
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|(user_id,)| user_id)
+                .collect()
         })
     }
 }
 
-impl Handler<ServerRemoveBan> for Persistence {
+impl Handler<SetChannelInvite> for Persistence {
     type Result = ResponseFuture<()>;
 
-    fn handle(&mut self, msg: ServerRemoveBan, _ctx: &mut Self::Context) -> Self::Result {
-        let database = self.database.clone();
+    fn handle(&mut self, msg: SetChannelInvite, _ctx: &mut Self::Context) -> Self::Result {
+        let conn = self.database.clone();
 
         Box::pin(async move {
-            sqlx::query("DELETE FROM server_bans WHERE mask = ?")
-                .bind(msg.mask)
-                .execute(&database)
+            let query = if msg.invited {
+                "INSERT INTO channel_invites (channel, user_id) VALUES ($1, $2)
+                 ON CONFLICT(channel, user_id) DO NOTHING"
+            } else {
+                "DELETE FROM channel_invites WHERE channel = $1 AND user_id = $2"
+            };
+
+            sqlx::query(query)
+                .bind(msg.channel_id.0)
+                .bind(msg.user_id.0)
+                .execute(&conn)
                 .await
                 .unwrap();
         })
     }
 }
 
-impl Handler<ServerListBan> for Persistence {
-    type Result = ResponseFuture<Vec<ServerListBanEntry>>;
+impl Handler<SetChannelSuccessor> for Persistence {
+    type Result = ResponseFuture<SetChannelSuccessorResult>;
 
-    fn handle(&mut self, _msg: ServerListBan, _ctx: &mut Self::Context) -> Self::Result {
-        let database = self.database.clone();
+    fn handle(&mut self, msg: SetChannelSuccessor, _ctx: &mut Self::Context) -> Self::Result {
+        let conn = self.database.clone();
+
+        Box::pin(async move {
+            let mut transaction = conn.begin().await.unwrap();
+
+            let Some((channel_id, _)) =
+                fetch_founded_channel(&mut transaction, &msg.channel, msg.requester).await
+            else {
+                return SetChannelSuccessorResult::NotFounder;
+            };
+
+            let successor = if let Some(account) = msg.successor {
+                let Some((user_id,)) =
+                    sqlx::query_as::<_, (i64,)>("SELECT id FROM users WHERE username = $1")
+                        .bind(account)
+                        .fetch_optional(&mut *transaction)
+                        .await
+                        .unwrap()
+                else {
+                    return SetChannelSuccessorResult::NoSuchAccount;
+                };
+
+                Some(user_id)
+            } else {
+                None
+            };
+
+            sqlx::query("UPDATE channels SET successor = $1 WHERE id = $2")
+                .bind(successor)
+                .bind(channel_id)
+                .execute(&mut *transaction)
+                .await
+                .unwrap();
+
+            transaction.commit().await.unwrap();
+
+            SetChannelSuccessorResult::Updated
+        })
+    }
+}
+
+/// Hands channels over to their successor once the founder's account has been dropped. The
+/// dropped founder's permissions are removed along the way, so registering their old account
+/// name doesn't grant founder status back.
+impl Handler<PromoteChannelSuccessors> for Persistence {
+    type Result = ResponseActFuture<Self, Vec<(String, String)>>;
+
+    fn handle(&mut self, _msg: PromoteChannelSuccessors, _ctx: &mut Self::Context) -> Self::Result {
+        let conn = self.database.clone();
+
+        let fut = async move {
+            let mut transaction = conn.begin().await.unwrap();
+
+            let orphaned = sqlx::query_as::<_, (i64, String, String)>(
+                "SELECT channels.id, channels.name, users.username
+                 FROM channels
+                 INNER JOIN users
+                   ON users.id = channels.successor
+                 WHERE NOT EXISTS (
+                   SELECT 1
+                   FROM channel_permissions
+                   INNER JOIN users founders
+                     ON channel_permissions.mask = '*!' || founders.username || '@*'
+                   WHERE channel_permissions.channel = channels.id
+                     AND channel_permissions.permissions = $1
+                 )",
+            )
+            .bind(Permission::Founder)
+            .fetch_all(&mut *transaction)
+            .await
+            .unwrap();
+
+            let mut promoted = Vec::with_capacity(orphaned.len());
+
+            for (channel_id, channel, successor) in orphaned {
+                let mut changes = sqlx::query_as::<_, (HostMask<'static>,)>(
+                    "DELETE FROM channel_permissions
+                     WHERE channel = $1
+                       AND permissions = $2
+                     RETURNING mask",
+                )
+                .bind(channel_id)
+                .bind(Permission::Founder)
+                .fetch_all(&mut *transaction)
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|(mask,)| (mask, Permission::Normal))
+                .collect::<Vec<_>>();
+
+                let mask = HostMask::new("*", &successor, "*").into_owned();
+
+                sqlx::query(
+                    "INSERT INTO channel_permissions (channel, mask, permissions)
+                     VALUES ($1, $2, $3)
+                     ON CONFLICT(channel, mask) DO UPDATE SET permissions = excluded.permissions",
+                )
+                .bind(channel_id)
+                .bind(&mask)
+                .bind(Permission::Founder)
+                .execute(&mut *transaction)
+                .await
+                .unwrap();
+
+                changes.push((mask, Permission::Founder));
+
+                let (version,) = sqlx::query_as::<_, (i64,)>(
+                    "UPDATE channels
+                     SET successor = NULL,
+                         permissions_version = permissions_version + $1
+                     WHERE id = $2
+                     RETURNING permissions_version",
+                )
+                .bind(i64::try_from(changes.len()).unwrap())
+                .bind(channel_id)
+                .fetch_one(&mut *transaction)
+                .await
+                .unwrap();
+
+                promoted.push((channel_id, channel, successor, changes, version));
+            }
+
+            transaction.commit().await.unwrap();
+
+            promoted
+        };
+
+        Box::pin(fut.into_actor(self).map(|promoted, this, _ctx| {
+            promoted
+                .into_iter()
+                .map(|(channel_id, channel, successor, changes, version)| {
+                    this.notify_permission_changes(channel_id, changes, version);
+                    (channel, successor)
+                })
+                .collect()
+        }))
+    }
+}
+
+impl Handler<RegisterChannel> for Persistence {
+    type Result = ResponseActFuture<Self, RegisterChannelResult>;
+
+    fn handle(&mut self, msg: RegisterChannel, _ctx: &mut Self::Context) -> Self::Result {
+        let conn = self.database.clone();
+
+        let fut = async move {
+            let mut transaction = conn.begin().await.unwrap();
+
+            let Some((channel_id, username)) = sqlx::query_as::<_, (i64, String)>(
+                "SELECT channels.id, users.username
+                 FROM channels
+                 INNER JOIN channel_users
+                   ON channel_users.channel = channels.id
+                 INNER JOIN users
+                   ON users.id = channel_users.user_id
+                 WHERE channels.name = $1
+                   AND channel_users.user_id = $2
+                   AND channel_users.in_channel = true",
+            )
+            .bind(msg.channel)
+            .bind(msg.requester.0)
+            .fetch_optional(&mut *transaction)
+            .await
+            .unwrap() else {
+                return Err(RegisterChannelResult::NotInChannel);
+            };
+
+            // a channel with a successor waiting to be promoted is still spoken for
+            let registered = sqlx::query_as::<_, (i64,)>(
+                "SELECT 1
+                 FROM channels
+                 WHERE id = $1
+                   AND (
+                     successor IS NOT NULL
+                     OR EXISTS (
+                       SELECT 1
+                       FROM channel_permissions
+                       INNER JOIN users
+                         ON channel_permissions.mask = '*!' || users.username || '@*'
+                       WHERE channel_permissions.channel = channels.id
+                         AND channel_permissions.permissions = $2
+                     )
+                   )",
+            )
+            .bind(channel_id)
+            .bind(Permission::Founder)
+            .fetch_optional(&mut *transaction)
+            .await
+            .unwrap()
+            .is_some();
+
+            if registered {
+                return Err(RegisterChannelResult::AlreadyRegistered);
+            }
+
+            // clear out founder entries left behind by dropped accounts
+            let mut changes = sqlx::query_as::<_, (HostMask<'static>,)>(
+                "DELETE FROM channel_permissions
+                 WHERE channel = $1
+                   AND permissions = $2
+                 RETURNING mask",
+            )
+            .bind(channel_id)
+            .bind(Permission::Founder)
+            .fetch_all(&mut *transaction)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(mask,)| (mask, Permission::Normal))
+            .collect::<Vec<_>>();
+
+            let mask = HostMask::new("*", &username, "*").into_owned();
+            upsert_channel_permissions(&mut transaction, channel_id, &mask, Permission::Founder)
+                .await;
+            changes.push((mask, Permission::Founder));
+
+            let version = bump_permissions_version(&mut transaction, channel_id, &changes).await;
+
+            transaction.commit().await.unwrap();
+
+            Ok((channel_id, changes, version))
+        };
+
+        Box::pin(fut.into_actor(self).map(|res, this, _ctx| match res {
+            Ok((channel_id, changes, version)) => {
+                this.notify_permission_changes(channel_id, changes, version);
+                RegisterChannelResult::Registered
+            }
+            Err(result) => result,
+        }))
+    }
+}
+
+impl Handler<TransferChannel> for Persistence {
+    type Result = ResponseActFuture<Self, SetChannelSuccessorResult>;
+
+    fn handle(&mut self, msg: TransferChannel, _ctx: &mut Self::Context) -> Self::Result {
+        let conn = self.database.clone();
+
+        let fut = async move {
+            let mut transaction = conn.begin().await.unwrap();
+
+            let Some((channel_id, founder_mask)) =
+                fetch_founded_channel(&mut transaction, &msg.channel, msg.requester).await
+            else {
+                return Err(SetChannelSuccessorResult::NotFounder);
+            };
+
+            let Some((user_id, username)) = sqlx::query_as::<_, (i64, String)>(
+                "SELECT id, username FROM users WHERE username = $1",
+            )
+            .bind(msg.account)
+            .fetch_optional(&mut *transaction)
+            .await
+            .unwrap() else {
+                return Err(SetChannelSuccessorResult::NoSuchAccount);
+            };
+
+            if user_id == msg.requester.0 {
+                return Err(SetChannelSuccessorResult::Updated);
+            }
+
+            let mask = HostMask::new("*", &username, "*").into_owned();
+            let changes = vec![
+                (founder_mask, Permission::Operator),
+                (mask, Permission::Founder),
+            ];
+
+            for (mask, permissions) in &changes {
+                upsert_channel_permissions(&mut transaction, channel_id, mask, *permissions).await;
+            }
+
+            // the new founder no longer needs to be the channel's successor
+            sqlx::query("UPDATE channels SET successor = NULL WHERE id = $1 AND successor = $2")
+                .bind(channel_id)
+                .bind(user_id)
+                .execute(&mut *transaction)
+                .await
+                .unwrap();
+
+            let version = bump_permissions_version(&mut transaction, channel_id, &changes).await;
+
+            transaction.commit().await.unwrap();
+
+            Ok((channel_id, changes, version))
+        };
+
+        Box::pin(fut.into_actor(self).map(|res, this, _ctx| match res {
+            Ok((channel_id, changes, version)) => {
+                this.notify_permission_changes(channel_id, changes, version);
+                SetChannelSuccessorResult::Updated
+            }
+            Err(result) => result,
+        }))
+    }
+}
+
+impl Handler<SetChannelAccess> for Persistence {
+    type Result = ResponseActFuture<Self, SetChannelAccessResult>;
+
+    fn handle(&mut self, msg: SetChannelAccess, _ctx: &mut Self::Context) -> Self::Result {
+        let conn = self.database.clone();
+
+        let fut = async move {
+            let mut transaction = conn.begin().await.unwrap();
+
+            let Some((channel_id, _)) =
+                fetch_founded_channel(&mut transaction, &msg.channel, msg.requester).await
+            else {
+                return Err(SetChannelAccessResult::NotFounder);
+            };
+
+            let existing = sqlx::query_as::<_, (Permission,)>(
+                "SELECT permissions
+                 FROM channel_permissions
+                 WHERE channel = $1
+                   AND mask = $2",
+            )
+            .bind(channel_id)
+            .bind(&msg.mask)
+            .fetch_optional(&mut *transaction)
+            .await
+            .unwrap()
+            .map(|(permissions,)| permissions);
+
+            if existing == Some(Permission::Founder) || msg.permissions == Some(Permission::Founder)
+            {
+                return Err(SetChannelAccessResult::FounderEntry);
+            }
+
+            let permissions = if let Some(permissions) = msg.permissions {
+                upsert_channel_permissions(&mut transaction, channel_id, &msg.mask, permissions)
+                    .await;
+                permissions
+            } else if existing.is_some() {
+                sqlx::query("DELETE FROM channel_permissions WHERE channel = $1 AND mask = $2")
+                    .bind(channel_id)
+                    .bind(&msg.mask)
+                    .execute(&mut *transaction)
+                    .await
+                    .unwrap();
+                Permission::Normal
+            } else {
+                return Err(SetChannelAccessResult::NoSuchEntry);
+            };
+
+            let changes = vec![(msg.mask, permissions)];
+            let version = bump_permissions_version(&mut transaction, channel_id, &changes).await;
+
+            transaction.commit().await.unwrap();
+
+            Ok((channel_id, changes, version))
+        };
+
+        Box::pin(fut.into_actor(self).map(|res, this, _ctx| match res {
+            Ok((channel_id, changes, version)) => {
+                this.notify_permission_changes(channel_id, changes, version);
+                SetChannelAccessResult::Updated
+            }
+            Err(result) => result,
+        }))
+    }
+}
+
+impl Handler<FetchChannelAccess> for Persistence {
+    type Result = ResponseFuture<Option<Vec<(HostMask<'static>, Permission)>>>;
+
+    fn handle(&mut self, msg: FetchChannelAccess, _ctx: &mut Self::Context) -> Self::Result {
+        let conn = self.database.clone();
+
+        Box::pin(async move {
+            let (channel_id,) =
+                sqlx::query_as::<_, (i64,)>("SELECT id FROM channels WHERE name = $1")
+                    .bind(msg.channel)
+                    .fetch_optional(&conn)
+                    .await
+                    .unwrap()?;
+
+            let access = sqlx::query_as::<_, (HostMask<'static>, Permission)>(
+                "SELECT mask, permissions
+                 FROM channel_permissions
+                 WHERE channel = $1
+                   AND permissions != $2
+                 ORDER BY permissions DESC, mask ASC",
+            )
+            .bind(channel_id)
+            .bind(Permission::Normal)
+            .fetch_all(&conn)
+            .await
+            .unwrap();
+
+            Some(access)
+        })
+    }
+}
+
+impl Handler<ImportChannelAccess> for Persistence {
+    type Result = ResponseActFuture<Self, ImportChannelAccessResult>;
+
+    fn handle(&mut self, msg: ImportChannelAccess, _ctx: &mut Self::Context) -> Self::Result {
+        let conn = self.database.clone();
+
+        let fut = async move {
+            let mut transaction = conn.begin().await.unwrap();
+
+            let Some((channel_id, founder_mask)) =
+                fetch_founded_channel(&mut transaction, &msg.channel, msg.requester).await
+            else {
+                return None;
+            };
+
+            let changes: Vec<_> = msg
+                .entries
+                .into_iter()
+                .filter(|(mask, permissions)| {
+                    *mask != founder_mask && *permissions != Permission::Founder
+                })
+                .collect();
+
+            for (mask, permissions) in &changes {
+                upsert_channel_permissions(&mut transaction, channel_id, mask, *permissions).await;
+            }
+
+            let version = bump_permissions_version(&mut transaction, channel_id, &changes).await;
+
+            transaction.commit().await.unwrap();
+
+            Some((channel_id, changes, version))
+        };
+
+        Box::pin(fut.into_actor(self).map(|res, this, _ctx| match res {
+            Some((channel_id, changes, version)) => {
+                let imported = changes.len();
+                this.notify_permission_changes(channel_id, changes, version);
+                ImportChannelAccessResult::Imported(imported)
+            }
+            None => ImportChannelAccessResult::NotFounder,
+        }))
+    }
+}
+
+impl Handler<Flush> for Persistence {
+    type Result = ResponseFuture<()>;
+
+    fn handle(&mut self, _msg: Flush, ctx: &mut Self::Context) -> Self::Result {
+        Box::pin(self.flush_daily_stats(ctx))
+    }
+}
+
+impl Handler<ClientCountChanged> for Persistence {
+    type Result = ();
+
+    fn handle(&mut self, msg: ClientCountChanged, ctx: &mut Self::Context) -> Self::Result {
+        let stats = self.daily_stats(ctx);
+        stats.current_clients = msg.clients;
+        stats.peak_clients = stats.peak_clients.max(msg.clients);
+    }
+}
+
+/// Writes out today's counters before fetching the statistics, so today's row is up to date.
+impl Handler<FetchDailyStats> for Persistence {
+    type Result = ResponseFuture<Vec<DailyStatsEntry>>;
+
+    fn handle(&mut self, msg: FetchDailyStats, ctx: &mut Self::Context) -> Self::Result {
+        let conn = self.database.clone();
+        let flush = self.flush_daily_stats(ctx);
+
+        Box::pin(async move {
+            flush.await;
+
+            let days = sqlx::query_as::<_, (String, i64, i64)>(
+                "SELECT day, peak_clients, new_accounts
+                 FROM stats_daily
+                 ORDER BY day DESC
+                 LIMIT $1",
+            )
+            .bind(i64::try_from(msg.days).unwrap_or(i64::MAX))
+            .fetch_all(&conn)
+            .await
+            .unwrap();
+
+            let Some((oldest, _, _)) = days.last() else {
+                return Vec::new();
+            };
+
+            let mut channel_messages = sqlx::query_as::<_, (String, String, i64)>(
+                "SELECT stats_daily_channels.day, channels.name, stats_daily_channels.messages
+                 FROM stats_daily_channels
+                 INNER JOIN channels
+                   ON channels.id = stats_daily_channels.channel
+                 WHERE stats_daily_channels.day >= $1
+                 ORDER BY stats_daily_channels.messages DESC",
+            )
+            .bind(oldest)
+            .fetch_all(&conn)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(day, channel, messages)| (day, (channel, messages)))
+            .into_group_map();
+
+            days.into_iter()
+                .map(|(day, peak_clients, new_accounts)| DailyStatsEntry {
+                    channel_messages: channel_messages.remove(&day).unwrap_or_default(),
+                    day,
+                    peak_clients,
+                    new_accounts,
+                })
+                .collect()
+        })
+    }
+}
+
+impl Handler<FetchUserChannels> for Persistence {
+    type Result = ResponseFuture<Vec<String>>;
+
+    fn handle(&mut self, msg: FetchUserChannels, _ctx: &mut Self::Context) -> Self::Result {
+        let conn = self.database.clone();
+
+        Box::pin(async move {
+            sqlx::query_as(
+                "SELECT channels.name
+                  FROM channel_users
+                  INNER JOIN channels
+                    ON channels.id = channel_users.channel
+                  WHERE user_id = $1
+                    AND in_channel = true",
+            )
+            .bind(msg.user_id.0)
+            .fetch_all(&conn)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(v,)| v)
+            .collect()
+        })
+    }
+}
+
+impl Handler<FetchAccountByNick> for Persistence {
+    type Result = ResponseFuture<Option<(UserId, String)>>;
+
+    fn handle(&mut self, msg: FetchAccountByNick, _ctx: &mut Self::Context) -> Self::Result {
+        let conn = self.database.clone();
+
+        Box::pin(async move {
+            // nicks are matched case-insensitively, preferring an exact match if multiple
+            // accounts own a variant of the nick
+            sqlx::query_as(
+                "SELECT user_nicks.user_id, users.username
+                 FROM user_nicks
+                 INNER JOIN users
+                    ON users.id = user_nicks.user_id
+                 WHERE LOWER(user_nicks.nick) = LOWER($1)
+                 ORDER BY user_nicks.nick = $2 DESC
+                 LIMIT 1",
+            )
+            .bind(&msg.nick)
+            .bind(&msg.nick)
+            .fetch_optional(&conn)
+            .await
+            .unwrap()
+        })
+    }
+}
+
+/// Checks whether two users are both members of at least one channel.
+impl Handler<FetchSharesChannel> for Persistence {
+    type Result = ResponseFuture<bool>;
+
+    fn handle(&mut self, msg: FetchSharesChannel, _ctx: &mut Self::Context) -> Self::Result {
+        let conn = self.database.clone();
+
+        Box::pin(async move {
+            sqlx::query_as::<_, (i64,)>(
+                "SELECT a.channel
+                 FROM channel_users a
+                 INNER JOIN channel_users b
+                   ON a.channel = b.channel
+                 WHERE a.user_id = $1
+                   AND b.user_id = $2
+                   AND a.in_channel = true
+                   AND b.in_channel = true
+                 LIMIT 1",
+            )
+            .bind(msg.user_id.0)
+            .bind(msg.other_user_id.0)
+            .fetch_optional(&conn)
+            .await
+            .unwrap()
+            .is_some()
+        })
+    }
+}
+
+impl Handler<ChannelMessage> for Persistence {
+    type Result = ResponseActFuture<Self, ()>;
+
+    fn handle(&mut self, msg: ChannelMessage, ctx: &mut Self::Context) -> Self::Result {
+        let timestamp = self.monotonically_increasing_id();
+
+        *self
+            .daily_stats(ctx)
+            .channel_messages
+            .entry(msg.channel_id.0)
+            .or_default() += 1;
+
+        self.write_message(PendingMessage::Channel(timestamp, msg))
+    }
+}
+
+impl Handler<RedactChannelMessage> for Persistence {
+    type Result = ResponseFuture<RedactChannelMessageResult>;
+
+    fn handle(&mut self, msg: RedactChannelMessage, _ctx: &mut Self::Context) -> Self::Result {
+        let conn = self.database.clone();
+
+        Box::pin(async move {
+            let Some((sender,)) = sqlx::query_as::<_, (String,)>(
+                "SELECT sender
+                 FROM channel_messages
+                 WHERE channel = $1
+                   AND msgid = $2
+                   AND redacted = false",
+            )
+            .bind(msg.channel_id.0)
+            .bind(&msg.msgid)
+            .fetch_optional(&conn)
+            .await
+            .unwrap() else {
+                return RedactChannelMessageResult::UnknownMessage;
+            };
+
+            if let Some(account) = msg.account {
+                let sent_by_account = matches!(
+                    Prefix::new_from_str(&sender),
+                    Prefix::Nickname(_, username, _) if username == account
+                );
+
+                if !sent_by_account {
+                    return RedactChannelMessageResult::Forbidden;
+                }
+            }
+
+            sqlx::query(
+                "UPDATE channel_messages
+                 SET message = '', redacted = true
+                 WHERE channel = $1
+                   AND msgid = $2",
+            )
+            .bind(msg.channel_id.0)
+            .bind(msg.msgid)
+            .execute(&conn)
+            .await
+            .unwrap();
+
+            RedactChannelMessageResult::Redacted
+        })
+    }
+}
+
+impl Handler<ChannelReaction> for Persistence {
+    type Result = ResponseFuture<ChannelReactionResult>;
+
+    fn handle(&mut self, msg: ChannelReaction, _ctx: &mut Self::Context) -> Self::Result {
+        let conn = self.database.clone();
+        let timestamp = self.monotonically_increasing_id();
+
+        Box::pin(async move {
+            let exists = sqlx::query(
+                "SELECT 1
+                 FROM channel_messages
+                 WHERE channel = $1
+                   AND msgid = $2
+                   AND redacted = false",
+            )
+            .bind(msg.channel_id.0)
+            .bind(&msg.msgid)
+            .fetch_optional(&conn)
+            .await
+            .unwrap()
+            .is_some();
+
+            if !exists {
+                return ChannelReactionResult::UnknownMessage;
+            }
+
+            let existing = sqlx::query_as::<_, (i64, String)>(
+                "SELECT user_id, reaction
+                 FROM channel_message_reactions
+                 WHERE channel = $1
+                   AND msgid = $2",
+            )
+            .bind(msg.channel_id.0)
+            .bind(&msg.msgid)
+            .fetch_all(&conn)
+            .await
+            .unwrap();
+
+            if existing
+                .iter()
+                .any(|(user, reaction)| *user == msg.user_id.0 && *reaction == msg.reaction)
+            {
+                return ChannelReactionResult::Stored;
+            }
+
+            let user_reactions = existing
+                .iter()
+                .filter(|(user, _)| *user == msg.user_id.0)
+                .count();
+            let reactions: HashSet<_> = existing.iter().map(|(_, reaction)| reaction).collect();
+
+            if user_reactions >= MAX_REACTIONS_PER_USER
+                || (!reactions.contains(&msg.reaction)
+                    && reactions.len() >= MAX_REACTIONS_PER_MESSAGE)
+            {
+                return ChannelReactionResult::TooManyReactions;
+            }
+
+            sqlx::query(
+                "INSERT INTO channel_message_reactions
+                 (channel, msgid, user_id, sender, reaction, timestamp)
+                 VALUES ($1, $2, $3, $4, $5, $6)
+                 ON CONFLICT DO NOTHING",
+            )
+            .bind(msg.channel_id.0)
+            .bind(msg.msgid)
+            .bind(msg.user_id.0)
+            .bind(msg.sender)
+            .bind(msg.reaction)
+            .bind(timestamp)
+            .execute(&conn)
+            .await
+            .unwrap();
+
+            ChannelReactionResult::Stored
+        })
+    }
+}
+
+impl Handler<FetchChannelReactions> for Persistence {
+    type Result = ResponseFuture<Vec<StoredReaction>>;
+
+    fn handle(&mut self, msg: FetchChannelReactions, _ctx: &mut Self::Context) -> Self::Result {
+        let conn = self.database.clone();
+
+        Box::pin(async move {
+            let Some((channel_id,)) =
+                sqlx::query_as::<_, (i64,)>("SELECT id FROM channels WHERE name = $1")
+                    .bind(msg.channel)
+                    .fetch_optional(&conn)
+                    .await
+                    .unwrap()
+            else {
+                return Vec::new();
+            };
+
+            fetch_reactions(&conn, channel_id, &msg.msgids).await
+        })
+    }
+}
+
+impl Handler<PrivateMessage> for Persistence {
+    type Result = ResponseActFuture<Self, ()>;
+
+    fn handle(&mut self, msg: PrivateMessage, _ctx: &mut Self::Context) -> Self::Result {
+        let timestamp = self.monotonically_increasing_id();
+        self.write_message(PendingMessage::Private(timestamp, msg))
+    }
+}
+
+impl Handler<FetchUnseenPrivateMessages> for Persistence {
+    type Result = ResponseFuture<Vec<StoredMessage>>;
+
+    fn handle(
+        &mut self,
+        msg: FetchUnseenPrivateMessages,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        let conn = self.database.clone();
+
+        Box::pin(async move {
+            sqlx::query_as(
+                "UPDATE private_messages
+                 SET delivered = true
+                 WHERE receiver = $1
+                   AND delivered = false
+                 RETURNING timestamp, sender, message, kind",
+            )
+            .bind(msg.user_id)
+            .fetch_all(&conn)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(timestamp, sender, message, kind)| {
+                (Utc.timestamp_nanos(timestamp), sender, message, kind, None)
+            })
+            .collect()
+        })
+    }
+}
+
+impl Handler<FetchUnseenChannelMessages> for Persistence {
+    type Result = ResponseFuture<ChannelMessageReplay>;
+
+    #[instrument(parent = &msg.span, skip_all)]
+    fn handle(
+        &mut self,
+        msg: FetchUnseenChannelMessages,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        let conn = self.database.clone();
+        let max_message_reply_since =
+            Utc::now() - chrono::Duration::from_std(self.max_message_replay_since).unwrap();
+        let max_message_replay_count = self.max_message_replay_count;
+
+        Box::pin(async move {
+            // select the last `max_message_replay_count` messages, or the last message the user
+            // saw - whichever dataset is smaller, along with the total amount of unseen messages
+            // so we can tell the user how many were omitted
+            let rows: Vec<(i64, String, String, MessageKind, Option<String>, i64)> =
+                sqlx::query_as(
+                    "SELECT timestamp, sender, message, kind, msgid, COUNT(*) OVER ()
+                 FROM channel_messages
+                 WHERE channel = $1
+                    AND redacted = false
+                    AND timestamp > $2
+                    AND timestamp > COALESCE((
+                      SELECT last_seen_message_timestamp
+                      FROM channel_users
+                      WHERE channel = $3
+                        AND user_id = $4
+                    ), 0)
+                 ORDER BY timestamp DESC
+                 LIMIT $5",
+                )
+                .bind(msg.channel_id.0)
+                .bind(max_message_reply_since.timestamp_nanos_opt().unwrap())
+                .bind(msg.channel_id.0)
+                .bind(msg.user_id.0)
+                .bind(i64::from(max_message_replay_count))
+                .fetch_all(&conn)
+                .await
+                .unwrap();
+
+            let total = rows.first().map_or(0, |(.., total)| *total);
+            #[allow(clippy::cast_possible_wrap)]
+            let omitted = total - rows.len() as i64;
+
+            // we fetched the most recent messages first, so flip them back into the order they
+            // were sent
+            let messages: Vec<StoredMessage> = rows
+                .into_iter()
+                .rev()
+                .map(|(timestamp, sender, message, kind, msgid, _)| {
+                    (Utc.timestamp_nanos(timestamp), sender, message, kind, msgid)
+                })
+                .collect();
+
+            let msgids: Vec<_> = messages.iter().filter_map(|v| v.4.clone()).collect();
+            let reactions = fetch_reactions(&conn, msg.channel_id.0, &msgids).await;
+
+            ChannelMessageReplay {
+                messages,
+                reactions,
+                omitted,
+            }
+        })
+    }
+}
+
+impl Handler<FetchChannelHistory> for Persistence {
+    type Result = ResponseFuture<Vec<StoredMessage>>;
+
+    fn handle(&mut self, msg: FetchChannelHistory, _ctx: &mut Self::Context) -> Self::Result {
+        let conn = self.database.clone();
+        let limit = i64::try_from(msg.limit)
+            .unwrap_or(i64::MAX)
+            .min(i64::from(self.max_message_replay_count));
+        let (after, before, latest_first) = msg.range.bounds();
+
+        Box::pin(async move {
+            let query = format!(
+                "SELECT timestamp, sender, message, kind, msgid
+                 FROM channel_messages
+                 WHERE channel = (SELECT id FROM channels WHERE name = $1)
+                   AND redacted = false
+                   AND timestamp > $2
+                   AND timestamp < $3
+                 ORDER BY timestamp {}
+                 LIMIT $4",
+                if latest_first { "DESC" } else { "ASC" }
+            );
+
+            let mut messages: Vec<StoredMessage> =
+                sqlx::query_as::<_, (i64, String, String, MessageKind, Option<String>)>(&query)
+                    .bind(msg.channel)
+                    .bind(after)
+                    .bind(before)
+                    .bind(limit)
+                    .fetch_all(&conn)
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .map(|(timestamp, sender, message, kind, msgid)| {
+                        (Utc.timestamp_nanos(timestamp), sender, message, kind, msgid)
+                    })
+                    .collect();
+
+            if latest_first {
+                messages.reverse();
+            }
+
+            messages
+        })
+    }
+}
+
+impl Handler<FetchPrivateHistory> for Persistence {
+    type Result = ResponseFuture<Vec<StoredPrivateMessage>>;
+
+    fn handle(&mut self, msg: FetchPrivateHistory, _ctx: &mut Self::Context) -> Self::Result {
+        let conn = self.database.clone();
+        let limit = i64::try_from(msg.limit)
+            .unwrap_or(i64::MAX)
+            .min(i64::from(self.max_message_replay_count));
+        let (after, before, latest_first) = msg.range.bounds();
+
+        Box::pin(async move {
+            let query = format!(
+                "SELECT timestamp, sender, message, kind, sender_user
+                 FROM private_messages
+                 WHERE ((sender_user = $1 AND receiver = $2) OR (sender_user = $3 AND receiver = $4))
+                   AND timestamp > $5
+                   AND timestamp < $6
+                 ORDER BY timestamp {}
+                 LIMIT $7",
+                if latest_first { "DESC" } else { "ASC" }
+            );
+
+            let mut messages: Vec<StoredPrivateMessage> =
+                sqlx::query_as::<_, (i64, String, String, MessageKind, i64)>(&query)
+                    .bind(msg.user_id.0)
+                    .bind(msg.other_user_id.0)
+                    .bind(msg.other_user_id.0)
+                    .bind(msg.user_id.0)
+                    .bind(after)
+                    .bind(before)
+                    .bind(limit)
+                    .fetch_all(&conn)
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .map(|(timestamp, sender, message, kind, sender_user)| {
+                        let sent = sender_user == msg.user_id.0;
+                        (
+                            (Utc.timestamp_nanos(timestamp), sender, message, kind, None),
+                            sent,
+                        )
+                    })
+                    .collect();
+
+            if latest_first {
+                messages.reverse();
+            }
+
+            messages
+        })
+    }
+}
+
+impl Handler<ReserveNick> for Persistence {
+    type Result = ResponseFuture<bool>;
+
+    fn handle(&mut self, msg: ReserveNick, _ctx: &mut Self::Context) -> Self::Result {
+        let database = self.database.clone();
+
+        Box::pin(async move {
+            let (owning_user,): (i64,) = sqlx::query_as(
+                "INSERT INTO user_nicks (nick, user_id)
+                 VALUES ($1, $2)
+                 ON CONFLICT(nick) DO UPDATE SET nick = user_nicks.nick
+                 RETURNING user_id",
+            )
+            .bind(&msg.nick)
+            .bind(msg.user_id.0)
+            .fetch_one(&database)
+            .await
+            .unwrap();
+
+            if owning_user != msg.user_id.0 {
+                return false;
+            }
+
+            let now = Utc::now().timestamp_nanos_opt().unwrap();
+
+            // nicks differing only in case share a history entry, which keeps the casing the
+            // nick was first used with
+            let updated = sqlx::query(
+                "UPDATE nick_history
+                 SET last_used_timestamp = $1
+                 WHERE user_id = $2 AND LOWER(nick) = LOWER($3)",
+            )
+            .bind(now)
+            .bind(msg.user_id.0)
+            .bind(&msg.nick)
+            .execute(&database)
+            .await
+            .unwrap()
+            .rows_affected();
+
+            if updated > 0 {
+                return true;
+            }
+
+            sqlx::query(
+                "INSERT INTO nick_history (user_id, nick, first_used_timestamp, last_used_timestamp)
+                 VALUES ($1, $2, $3, $4)
+                 ON CONFLICT(user_id, nick)
+                   DO UPDATE SET last_used_timestamp = excluded.last_used_timestamp",
+            )
+            .bind(msg.user_id.0)
+            .bind(msg.nick)
+            .bind(now)
+            .bind(now)
+            .execute(&database)
+            .await
+            .unwrap();
+
+            true
+        })
+    }
+}
+
+impl Handler<AddUserCertificate> for Persistence {
+    type Result = ResponseFuture<bool>;
+
+    fn handle(&mut self, msg: AddUserCertificate, _ctx: &mut Self::Context) -> Self::Result {
+        let database = self.database.clone();
+
+        Box::pin(async move {
+            sqlx::query(
+                "INSERT INTO user_certificates (fingerprint, user_id)
+                 VALUES ($1, $2)
+                 ON CONFLICT(fingerprint) DO NOTHING",
+            )
+            .bind(msg.fingerprint)
+            .bind(msg.user_id.0)
+            .execute(&database)
+            .await
+            .unwrap()
+            .rows_affected()
+                > 0
+        })
+    }
+}
+
+impl Handler<RemoveUserCertificate> for Persistence {
+    type Result = ResponseFuture<bool>;
+
+    fn handle(&mut self, msg: RemoveUserCertificate, _ctx: &mut Self::Context) -> Self::Result {
+        let database = self.database.clone();
+
+        Box::pin(async move {
+            sqlx::query(
+                "DELETE FROM user_certificates
+                 WHERE fingerprint = $1
+                   AND user_id = $2",
+            )
+            .bind(msg.fingerprint)
+            .bind(msg.user_id.0)
+            .execute(&database)
+            .await
+            .unwrap()
+            .rows_affected()
+                > 0
+        })
+    }
+}
+
+impl Handler<ListUserCertificates> for Persistence {
+    type Result = ResponseFuture<Vec<String>>;
+
+    fn handle(&mut self, msg: ListUserCertificates, _ctx: &mut Self::Context) -> Self::Result {
+        let database = self.database.clone();
+
+        Box::pin(async move {
+            sqlx::query_as::<_, (String,)>(
+                "SELECT fingerprint
+                 FROM user_certificates
+                 WHERE user_id = $1
+                 ORDER BY fingerprint",
+            )
+            .bind(msg.user_id.0)
+            .fetch_all(&database)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(fingerprint,)| fingerprint)
+            .collect()
+        })
+    }
+}
+
+impl Handler<FetchNickHistory> for Persistence {
+    type Result = ResponseFuture<Vec<NickHistoryEntry>>;
+
+    fn handle(&mut self, msg: FetchNickHistory, _ctx: &mut Self::Context) -> Self::Result {
+        let database = self.database.clone();
+
+        Box::pin(async move {
+            sqlx::query_as(
+                "SELECT
+                   nick_history.nick,
+                   users.username,
+                   nick_history.first_used_timestamp,
+                   nick_history.last_used_timestamp
+                 FROM nick_history
+                 INNER JOIN users
+                   ON nick_history.user_id = users.id
+                 WHERE nick_history.user_id = (
+                   SELECT user_id
+                   FROM user_nicks
+                   WHERE LOWER(nick) = LOWER($1)
+                   ORDER BY nick = $2 DESC
+                   LIMIT 1
+                 )
+                 ORDER BY nick_history.last_used_timestamp DESC",
+            )
+            .bind(&msg.nick)
+            .bind(&msg.nick)
+            .fetch_all(&database)
+            .await
+            .unwrap()
+        })
+    }
+}
+
+impl Handler<RecordWhowas> for Persistence {
+    type Result = ResponseFuture<()>;
+
+    fn handle(&mut self, msg: RecordWhowas, _ctx: &mut Self::Context) -> Self::Result {
+        let database = self.database.clone();
+        let seen = self.monotonically_increasing_id();
+
+        Box::pin(async move {
+            sqlx::query(
+                "INSERT INTO whowas (nick, username, host, realname, seen_timestamp)
+                 VALUES ($1, $2, $3, $4, $5)",
+            )
+            .bind(msg.nick)
+            .bind(msg.username)
+            .bind(msg.host)
+            .bind(msg.realname)
+            .bind(seen)
+            .execute(&database)
+            .await
+            .unwrap();
+        })
+    }
+}
+
+impl Handler<FetchWhowas> for Persistence {
+    type Result = ResponseFuture<Vec<WhowasEntry>>;
+
+    fn handle(&mut self, msg: FetchWhowas, _ctx: &mut Self::Context) -> Self::Result {
+        let database = self.database.clone();
+        let seen_since = Utc::now() - chrono::Duration::from_std(self.whowas_retention).unwrap();
+
+        Box::pin(async move {
+            sqlx::query_as(
+                "SELECT nick, username, host, realname, seen_timestamp
+                 FROM whowas
+                 WHERE LOWER(nick) = LOWER($1)
+                   AND seen_timestamp > $2
+                 ORDER BY seen_timestamp DESC
+                 LIMIT $3",
+            )
+            .bind(msg.nick)
+            .bind(seen_since.timestamp_nanos_opt().unwrap())
+            .bind(i64::try_from(msg.count).unwrap_or(i64::MAX))
+            .fetch_all(&database)
+            .await
+            .unwrap()
+        })
+    }
+}
+
+impl Handler<RecordLogin> for Persistence {
+    type Result = ResponseFuture<()>;
+
+    fn handle(&mut self, msg: RecordLogin, _ctx: &mut Self::Context) -> Self::Result {
+        let database = self.database.clone();
+        let timestamp = self.monotonically_increasing_id();
+
+        Box::pin(async move {
+            sqlx::query(
+                "INSERT INTO login_history (user_id, session, ip, host, timestamp)
+                 VALUES ($1, $2, $3, $4, $5)",
+            )
+            .bind(msg.user_id.0)
+            .bind(msg.session)
+            .bind(msg.ip)
+            .bind(msg.host)
+            .bind(timestamp)
+            .execute(&database)
+            .await
+            .unwrap();
+
+            // only the most recent logins are kept around
+            sqlx::query(
+                "DELETE FROM login_history
+                 WHERE user_id = $1
+                   AND timestamp <= (
+                     SELECT timestamp
+                     FROM login_history
+                     WHERE user_id = $2
+                     ORDER BY timestamp DESC
+                     LIMIT 1 OFFSET $3
+                   )",
+            )
+            .bind(msg.user_id.0)
+            .bind(msg.user_id.0)
+            .bind(LOGIN_HISTORY_PER_USER)
+            .execute(&database)
+            .await
+            .unwrap();
+        })
+    }
+}
+
+impl Handler<FetchLoginHistory> for Persistence {
+    type Result = ResponseFuture<Vec<LoginHistoryEntry>>;
+
+    fn handle(&mut self, msg: FetchLoginHistory, _ctx: &mut Self::Context) -> Self::Result {
+        let database = self.database.clone();
+
+        Box::pin(async move {
+            sqlx::query_as(
+                "SELECT session, ip, host, timestamp
+                 FROM login_history
+                 WHERE user_id = $1
+                 ORDER BY timestamp DESC",
+            )
+            .bind(msg.user_id.0)
+            .fetch_all(&database)
+            .await
+            .unwrap()
+        })
+    }
+}
+
+impl Handler<ServerBan> for Persistence {
+    type Result = ResponseFuture<()>;
+
+    fn handle(&mut self, msg: ServerBan, _ctx: &mut Self::Context) -> Self::Result {
+        let database = self.database.clone();
+
+        Box::pin(async move {
+            sqlx::query(
+                "INSERT INTO server_bans
+                 (mask, requester, reason, created_timestamp, expires_timestamp)
+                 VALUES ($1, $2, $3, $4, $5)",
+            )
+            .bind(msg.mask)
+            .bind(msg.requester)
+            .bind(msg.reason)
+            .bind(msg.created.timestamp_nanos_opt().unwrap())
+            .bind(msg.expires.map(|v| v.timestamp_nanos_opt().unwrap()))
+            .execute(&database)
+            .await
+            .unwrap();
+        })
+    }
+}
+
+impl Handler<ServerRemoveBan> for Persistence {
+    type Result = ResponseFuture<()>;
+
+    fn handle(&mut self, msg: ServerRemoveBan, _ctx: &mut Self::Context) -> Self::Result {
+        let database = self.database.clone();
+
+        Box::pin(async move {
+            sqlx::query("DELETE FROM server_bans WHERE mask = $1")
+                .bind(msg.mask)
+                .execute(&database)
+                .await
+                .unwrap();
+        })
+    }
+}
+
+impl Handler<ServerListBan> for Persistence {
+    type Result = ResponseFuture<Vec<ServerListBanEntry>>;
+
+    fn handle(&mut self, _msg: ServerListBan, _ctx: &mut Self::Context) -> Self::Result {
+        let database = self.database.clone();
+
+        Box::pin(async move {
+            sqlx::query_as(
+                "SELECT
+                   users.username AS requester,
+                   server_bans.mask,
+                   server_bans.reason,
+                   server_bans.created_timestamp,
+                   server_bans.expires_timestamp
+                 FROM server_bans
+                 INNER JOIN users
+                   ON server_bans.requester = users.id",
+            )
+            .fetch_all(&database)
+            .await
+            .unwrap()
+        })
+    }
+}
+
+impl Handler<ServerShun> for Persistence {
+    type Result = ResponseFuture<()>;
+
+    fn handle(&mut self, msg: ServerShun, _ctx: &mut Self::Context) -> Self::Result {
+        let database = self.database.clone();
+
+        Box::pin(async move {
+            sqlx::query(
+                "INSERT INTO server_shuns
+                 (mask, requester, reason, created_timestamp, expires_timestamp)
+                 VALUES ($1, $2, $3, $4, $5)",
+            )
+            .bind(msg.mask)
+            .bind(msg.requester)
+            .bind(msg.reason)
+            .bind(msg.created.timestamp_nanos_opt().unwrap())
+            .bind(msg.expires.map(|v| v.timestamp_nanos_opt().unwrap()))
+            .execute(&database)
+            .await
+            .unwrap();
+        })
+    }
+}
+
+impl Handler<ServerRemoveShun> for Persistence {
+    type Result = ResponseFuture<()>;
+
+    fn handle(&mut self, msg: ServerRemoveShun, _ctx: &mut Self::Context) -> Self::Result {
+        let database = self.database.clone();
+
+        Box::pin(async move {
+            sqlx::query("DELETE FROM server_shuns WHERE mask = $1")
+                .bind(msg.mask)
+                .execute(&database)
+                .await
+                .unwrap();
+        })
+    }
+}
+
+impl Handler<ServerListShun> for Persistence {
+    type Result = ResponseFuture<Vec<ServerListBanEntry>>;
+
+    fn handle(&mut self, _msg: ServerListShun, _ctx: &mut Self::Context) -> Self::Result {
+        let database = self.database.clone();
+
+        Box::pin(async move {
+            sqlx::query_as(
+                "SELECT
+                   users.username AS requester,
+                   server_shuns.mask,
+                   server_shuns.reason,
+                   server_shuns.created_timestamp,
+                   server_shuns.expires_timestamp
+                 FROM server_shuns
+                 INNER JOIN users
+                   ON server_shuns.requester = users.id",
+            )
+            .fetch_all(&database)
+            .await
+            .unwrap()
+        })
+    }
+}
+
+/// Remove any messages from the messages table whenever they've been seen by all users
+/// or have passed their retention period, along with any delivered private messages that have
+/// passed their retention period
+/// .
+pub async fn truncate_seen_messages(db: sqlx::Pool<sqlx::Any>, max_replay_since: Duration) {
+    // fetch the minimum last seen message by channel
+    let messages = sqlx::query_as::<_, (i64, i64)>(
+        "SELECT channel, COALESCE(MIN(last_seen_message_timestamp), 0)
+         FROM channel_users
+         GROUP BY channel",
+    )
+    .fetch_all(&db)
+    .await
+    .unwrap();
+
+    let max_replay_since = Utc::now() - chrono::Duration::from_std(max_replay_since).unwrap();
+
+    // delete all messages that have been by all users or have passed their retention period
+    for (channel, min_seen_timestamp) in messages {
+        let remove_before = std::cmp::max(
+            min_seen_timestamp,
+            max_replay_since.timestamp_nanos_opt().unwrap(),
+        );
+
+        sqlx::query(
+            "DELETE FROM channel_messages
+             WHERE channel = $1
+               AND timestamp <= $2",
+        )
+        .bind(channel)
+        .bind(remove_before)
+        .execute(&db)
+        .await
+        .unwrap();
+    }
+
+    // reactions are only replayed alongside the message they were made to
+    sqlx::query(
+        "DELETE FROM channel_message_reactions
+         WHERE NOT EXISTS (
+           SELECT 1
+           FROM channel_messages
+           WHERE channel_messages.channel = channel_message_reactions.channel
+             AND channel_messages.msgid = channel_message_reactions.msgid
+         )",
+    )
+    .execute(&db)
+    .await
+    .unwrap();
+
+    // private messages are only kept for history once they've been delivered, undelivered
+    // messages are kept until the receiver next connects
+    sqlx::query(
+        "DELETE FROM private_messages
+         WHERE delivered = true
+           AND timestamp <= $1",
+    )
+    .bind(max_replay_since.timestamp_nanos_opt().unwrap())
+    .execute(&db)
+    .await
+    .unwrap();
+}
+
+/// Writes a message sent to a channel or user, marking channel messages as seen by the members
+/// they were delivered to.
+async fn write_message(
+    conn: &sqlx::Pool<sqlx::Any>,
+    message: &PendingMessage,
+) -> Result<(), sqlx::Error> {
+    match message {
+        PendingMessage::Channel(timestamp, msg) => {
+            sqlx::query(
+                "INSERT INTO channel_messages (channel, timestamp, msgid, sender, message, kind) VALUES ($1, $2, $3, $4, $5, $6)",
+            )
+            .bind(msg.channel_id.0)
+            .bind(*timestamp)
+            .bind(msg.msgid.as_str())
+            .bind(msg.sender.as_str())
+            .bind(msg.message.as_str())
+            .bind(msg.kind)
+            .execute(conn)
+            .await?;
+
+            if !msg.receivers.is_empty() {
+                let query = format!(
+                    "UPDATE channel_users
+                     SET last_seen_message_timestamp = $1
+                     WHERE channel = $2
+                       AND user_id IN ({})",
+                    (3..3 + msg.receivers.len())
+                        .map(|i| format!("${i}"))
+                        .join(",")
+                );
+
+                let mut query = sqlx::query(&query).bind(*timestamp).bind(msg.channel_id.0);
+                for receiver in &msg.receivers {
+                    query = query.bind(receiver.0);
+                }
+
+                query.execute(conn).await?;
+            }
+        }
+        PendingMessage::Private(timestamp, msg) => {
+            sqlx::query(
+                "INSERT INTO private_messages
+                 (timestamp, sender, sender_user, receiver, message, kind, delivered)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7)",
+            )
+            .bind(*timestamp)
+            .bind(msg.sender.as_str())
+            .bind(msg.sender_user.0)
+            .bind(msg.receiver)
+            .bind(msg.message.as_str())
+            .bind(msg.kind)
+            .bind(msg.delivered)
+            .execute(conn)
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Fetches the ID of the channel along with the mask its founder was granted, if `user_id` is
+/// the channel's founder. Founders are always granted their permissions against their account's
+/// mask.
+async fn fetch_founded_channel(
+    transaction: &mut sqlx::Transaction<'_, sqlx::Any>,
+    channel: &str,
+    user_id: UserId,
+) -> Option<(i64, HostMask<'static>)> {
+    sqlx::query_as::<_, (i64, HostMask<'static>)>(
+        "SELECT channels.id, channel_permissions.mask
+         FROM channels
+         INNER JOIN channel_permissions
+           ON channel_permissions.channel = channels.id
+         INNER JOIN users
+           ON channel_permissions.mask = '*!' || users.username || '@*'
+         WHERE channels.name = $1
+           AND users.id = $2
+           AND channel_permissions.permissions = $3",
+    )
+    .bind(channel)
+    .bind(user_id.0)
+    .bind(Permission::Founder)
+    .fetch_optional(&mut **transaction)
+    .await
+    .unwrap()
+}
+
+async fn upsert_channel_permissions(
+    transaction: &mut sqlx::Transaction<'_, sqlx::Any>,
+    channel_id: i64,
+    mask: &HostMask<'static>,
+    permissions: Permission,
+) {
+    // changes made outside of the channel don't have a setter to show
+    sqlx::query(
+        "INSERT INTO channel_permissions (channel, mask, permissions, set_by, set_at)
+         VALUES ($1, $2, $3, NULL, $4)
+         ON CONFLICT(channel, mask) DO UPDATE SET
+            permissions = excluded.permissions,
+            set_by = NULL,
+            set_at = excluded.set_at",
+    )
+    .bind(channel_id)
+    .bind(mask)
+    .bind(permissions)
+    .bind(Utc::now().timestamp_nanos_opt().unwrap())
+    .execute(&mut **transaction)
+    .await
+    .unwrap();
+}
+
+/// Bumps the version of the channel's permissions once for each of `changes`, returning the
+/// version after the last change.
+async fn bump_permissions_version(
+    transaction: &mut sqlx::Transaction<'_, sqlx::Any>,
+    channel_id: i64,
+    changes: &[(HostMask<'static>, Permission)],
+) -> i64 {
+    let (version,) = sqlx::query_as::<_, (i64,)>(
+        "UPDATE channels
+         SET permissions_version = permissions_version + $1
+         WHERE id = $2
+         RETURNING permissions_version",
+    )
+    .bind(i64::try_from(changes.len()).unwrap())
+    .bind(channel_id)
+    .fetch_one(&mut **transaction)
+    .await
+    .unwrap();
+
+    version
+}
+
+/// Fetches the reactions to any of `msgids` in a channel, in the order they were made.
+async fn fetch_reactions(
+    conn: &sqlx::Pool<sqlx::Any>,
+    channel_id: i64,
+    msgids: &[String],
+) -> Vec<StoredReaction> {
+    if msgids.is_empty() {
+        return Vec::new();
+    }
+
+    let query = format!(
+        "SELECT timestamp, sender, msgid, reaction
+         FROM channel_message_reactions
+         WHERE channel = $1
+           AND msgid IN ({})
+         ORDER BY timestamp ASC",
+        (2..2 + msgids.len()).map(|i| format!("${i}")).join(",")
+    );
+
+    let mut query = sqlx::query_as::<_, (i64, String, String, String)>(&query).bind(channel_id);
+    for msgid in msgids {
+        query = query.bind(msgid);
+    }
+
+    query
+        .fetch_all(conn)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|(timestamp, sender, msgid, reaction)| {
+            (Utc.timestamp_nanos(timestamp), sender, msgid, reaction)
+        })
+        .collect()
+}
+
+/// Removes nicks that were last used before the `WHOWAS` retention window.
+pub async fn truncate_whowas(db: sqlx::Pool<sqlx::Any>, retention: Duration) {
+    let seen_before = Utc::now() - chrono::Duration::from_std(retention).unwrap();
+
+    sqlx::query("DELETE FROM whowas WHERE seen_timestamp <= $1")
+        .bind(seen_before.timestamp_nanos_opt().unwrap())
+        .execute(&db)
+        .await
+        .unwrap();
+}
+
+/// Adds a day's counters to its row in the database, along with the amount of accounts that
+/// were registered on that day.
+pub async fn record_daily_stats(
+    db: sqlx::Pool<sqlx::Any>,
+    day: NaiveDate,
+    peak_clients: usize,
+    channel_messages: HashMap<i64, i64>,
+) {
+    let start = day.and_time(NaiveTime::MIN).and_utc();
+    let end = start + chrono::Duration::days(1);
+
+    let mut transaction = db.begin().await.unwrap();
+
+    sqlx::query(
+        "INSERT INTO stats_daily (day, peak_clients, new_accounts)
+         VALUES (
+           $1,
+           $2,
+           (SELECT COUNT(*) FROM users WHERE created_timestamp >= $3 AND created_timestamp < $4)
+         )
+         ON CONFLICT(day) DO UPDATE SET
+           peak_clients = CASE
+             WHEN excluded.peak_clients > stats_daily.peak_clients THEN excluded.peak_clients
+             ELSE stats_daily.peak_clients
+           END,
+           new_accounts = excluded.new_accounts",
+    )
+    .bind(day.to_string())
+    .bind(i64::try_from(peak_clients).unwrap_or(i64::MAX))
+    .bind(start.timestamp_nanos_opt().unwrap())
+    .bind(end.timestamp_nanos_opt().unwrap())
+    .execute(&mut *transaction)
+    .await
+    .unwrap();
+
+    for (channel, messages) in channel_messages {
+        sqlx::query(
+            "INSERT INTO stats_daily_channels (day, channel, messages)
+             VALUES ($1, $2, $3)
+             ON CONFLICT(day, channel) DO UPDATE SET
+               messages = stats_daily_channels.messages + excluded.messages",
+        )
+        .bind(day.to_string())
+        .bind(channel)
+        .bind(messages)
+        .execute(&mut *transaction)
+        .await
+        .unwrap();
+    }
+
+    transaction.commit().await.unwrap();
+}
+
+/// Records the server starting up, reconciling any state left behind if the server didn't
+/// shut down cleanly on its previous run. Returns the id of the new run, to be passed to
+/// [`record_shutdown`], along with whether the previous run shut down cleanly.
+pub async fn record_startup(db: &sqlx::Pool<sqlx::Any>) -> Result<(i64, bool), sqlx::Error> {
+    let previous_run_stopped = sqlx::query_as::<_, (Option<i64>,)>(
+        "SELECT stopped_timestamp
+         FROM server_runs
+         ORDER BY id DESC
+         LIMIT 1",
+    )
+    .fetch_optional(db)
+    .await?;
+
+    // the first ever run is always considered clean
+    let clean = previous_run_stopped.map_or(true, |(stopped,)| stopped.is_some());
+
+    if !clean {
+        reconcile_channel_users(db).await?;
+    }
+
+    let (id,) = sqlx::query_as(
+        "INSERT INTO server_runs (started_timestamp)
+         VALUES ($1)
+         RETURNING id",
+    )
+    .bind(Utc::now().timestamp_nanos_opt().unwrap())
+    .fetch_one(db)
+    .await?;
+
+    Ok((id, clean))
+}
+
+/// Records the server cleanly shutting down.
+pub async fn record_shutdown(db: &sqlx::Pool<sqlx::Any>, run: i64) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE server_runs
+         SET stopped_timestamp = $1
+         WHERE id = $2",
+    )
+    .bind(Utc::now().timestamp_nanos_opt().unwrap())
+    .bind(run)
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+/// Clears `in_channel` flags referring to users or channels that no longer exist, which can be
+/// left behind if the server dies part way through removing them. `in_channel` tracks
+/// persistent membership rather than presence, so memberships of users that were simply
+/// connected when the server died are left alone and rejoined on reconnect.
+async fn reconcile_channel_users(db: &sqlx::Pool<sqlx::Any>) -> Result<(), sqlx::Error> {
+    let cleared = sqlx::query(
+        "UPDATE channel_users
+         SET in_channel = false
+         WHERE in_channel = true
+           AND (
+             user_id NOT IN (SELECT id FROM users)
+             OR channel NOT IN (SELECT id FROM channels)
+           )",
+    )
+    .execute(db)
+    .await?
+    .rows_affected();
+
+    warn!(
+        cleared,
+        "Server did not shut down cleanly, cleared stale channel memberships"
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{str::FromStr, time::Duration};
+
+    use actix::{Actor, Addr, Context, Handler};
+    use chrono::{NaiveDate, TimeZone, Utc};
+    use sqlx::{
+        any::{AnyConnectOptions, AnyPoolOptions},
+        AnyConnection, Connection, Executor,
+    };
+    use tokio::sync::mpsc;
+    use tracing::{error, Span};
+
+    use super::{
+        events::{
+            ChannelCreated, ChannelJoined, ChannelMessage, ChannelReaction, ChannelReactionResult,
+            DailyStatsEntry, FetchAccountByNick, FetchAllUserChannelPermissions,
+            FetchChannelAccess, FetchChannelHistory, FetchChannelInvites, FetchChannelReactions,
+            FetchDailyStats, FetchLoginHistory, FetchNickHistory, FetchPrivateHistory,
+            FetchUnseenChannelMessages, FetchWhowas, HistoryRange, ImportChannelAccess,
+            ImportChannelAccessResult, PrivateMessage, PromoteChannelSuccessors, RecordLogin,
+            RecordWhowas, RedactChannelMessage, RedactChannelMessageResult, RegisterChannel,
+            RegisterChannelResult, ReserveNick, ServerBan, ServerListBan, ServerListShun,
+            ServerShun, SetChannelAccess, SetChannelAccessResult, SetChannelInvite,
+            SetChannelSuccessor, SetChannelSuccessorResult, SetUserChannelPermissions,
+            SubscribeChannelPermissions, SubscribeDatabaseHealth, TransferChannel, WhowasEntry,
+        },
+        record_daily_stats, record_shutdown, record_startup, truncate_seen_messages, Persistence,
+        StoredMessage,
+    };
+    use crate::{
+        channel::{permissions::Permission, ChannelId},
+        connection::UserId,
+        database::{
+            create_user_or_fetch_password_hash, drop_user, in_memory, reserve_nick, Backend,
+        },
+        host_mask::{BanMask, HostMask},
+        keys::Keys,
+        messages::{DatabaseHealthChanged, MessageKind, PermissionsChanged},
+    };
+
+    /// Starts the persistence actor over `database` with message replay and whowas disabled.
+    fn persistence(database: &sqlx::Pool<sqlx::Any>) -> Addr<Persistence> {
+        persistence(&database)
+    }
+
+    /// Registers an account for `name`, for tests that need a user to exist.
+    async fn user(database: &sqlx::Pool<sqlx::Any>, id: i64, name: &str) {
+        sqlx::query("INSERT INTO users (id, username, password) VALUES ($1, $2, '')")
+            .bind(id)
+            .bind(name)
+            .execute(database)
+            .await
+            .unwrap();
+    }
+
+    async fn in_channel(database: &sqlx::Pool<sqlx::Any>, channel: i64, user: i64) -> bool {
+        sqlx::query_as::<_, (bool,)>(
+            "SELECT in_channel FROM channel_users WHERE channel = $1 AND user_id = $2",
+        )
+        .bind(channel)
+        .bind(user)
+        .fetch_one(database)
+        .await
+        .unwrap()
+        .0
+    }
+
+    #[tokio::test]
+    async fn recovers_from_unclean_shutdown() {
+        let database = in_memory().await;
+
+        user(&database, 1, "user").await;
+
+        // foreign keys are disabled so we're able to insert a membership for a user that
+        // doesn't exist, as would be left behind by a crash part way through removing a user
+        sqlx::query(
+            "PRAGMA foreign_keys = OFF;
+             INSERT INTO channels (id, name) VALUES (1, '#channel');
+             INSERT INTO channel_users (channel, user_id, in_channel) VALUES (1, 1, true);
+             INSERT INTO channel_users (channel, user_id, in_channel) VALUES (1, 2, true);",
+        )
+        .execute(&database)
+        .await
+        .unwrap();
+
+        // first run is always clean, and nothing should be touched
+        let (run, clean) = record_startup(&database).await.unwrap();
+        assert!(clean);
+        assert!(in_channel(&database, 1, 2).await);
+
+        // a clean shutdown shouldn't trigger reconciliation
+        record_shutdown(&database, run).await.unwrap();
+        let (_run, clean) = record_startup(&database).await.unwrap();
+        assert!(clean);
+        assert!(in_channel(&database, 1, 2).await);
+
+        // simulate a crash by never recording the shutdown
+        let (_run, clean) = record_startup(&database).await.unwrap();
+        assert!(!clean);
+        assert!(in_channel(&database, 1, 1).await);
+        assert!(!in_channel(&database, 1, 2).await);
+    }
+
+    #[actix_rt::test]
+    async fn fetches_account_by_nick_case_insensitively() {
+        let database = in_memory().await;
+
+        user(&database, 1, "bob").await;
+        user(&database, 2, "other").await;
+
+        sqlx::query("INSERT INTO user_nicks (nick, user_id) VALUES ('Bob', 1);")
+            .execute(&database)
+            .await
+            .unwrap();
+
+        let persistence = persistence(&database);
+
+        let fetch = |nick: &str| {
+            persistence.send(FetchAccountByNick {
+                nick: nick.to_string(),
+            })
+        };
+
+        let bob = Some((UserId(1), "bob".to_string()));
+        let other = Some((UserId(2), "other".to_string()));
+
+        assert_eq!(fetch("Bob").await.unwrap(), bob);
+        assert_eq!(fetch("bob").await.unwrap(), bob);
+        assert_eq!(fetch("BOB").await.unwrap(), bob);
+        assert_eq!(fetch("bobby").await.unwrap(), None);
+
+        // an exact match is preferred if another account owns a different case of the nick
+        sqlx::query("INSERT INTO user_nicks (nick, user_id) VALUES ('bob', 2)")
+            .execute(&database)
+            .await
+            .unwrap();
+
+        assert_eq!(fetch("bob").await.unwrap(), other);
+        assert_eq!(fetch("Bob").await.unwrap(), bob);
+    }
+
+    #[actix_rt::test]
+    async fn nick_history_is_case_insensitive() {
+        let database = in_memory().await;
+
+        user(&database, 1, "bob").await;
+
+        let persistence = persistence(&database);
+
+        for nick in ["Bob", "bob", "Robert"] {
+            assert!(persistence
+                .send(ReserveNick {
+                    user_id: UserId(1),
+                    nick: nick.to_string(),
+                })
+                .await
+                .unwrap());
+        }
+
+        let history = persistence
+            .send(FetchNickHistory {
+                nick: "BOB".to_string(),
+            })
+            .await
+            .unwrap();
+
+        let nicks: Vec<_> = history.iter().map(|entry| entry.nick.as_str()).collect();
+        assert_eq!(nicks, ["Robert", "Bob"]);
+    }
+
+    /// Stands in for a channel, forwarding any permission changes it's sent.
+    struct PermissionsRecorder(mpsc::UnboundedSender<PermissionsChanged>);
+
+    impl Actor for PermissionsRecorder {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<PermissionsChanged> for PermissionsRecorder {
+        type Result = ();
+
+        fn handle(&mut self, msg: PermissionsChanged, _ctx: &mut Self::Context) -> Self::Result {
+            self.0.send(msg).unwrap();
+        }
+    }
+
+    #[actix_rt::test]
+    async fn notifies_subscribed_channel_of_permission_changes() {
+        let database = in_memory().await;
+
+        sqlx::query("INSERT INTO channels (id, name) VALUES (1, '#channel')")
+            .execute(&database)
+            .await
+            .unwrap();
+
+        let persistence = persistence(&database);
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        persistence
+            .send(SubscribeChannelPermissions {
+                channel_id: ChannelId(1),
+                channel: PermissionsRecorder(tx).start().recipient(),
+            })
+            .await
+            .unwrap();
+
+        let mask = HostMask::new("*", "bob", "*").into_owned();
+
+        for permissions in [Permission::Voice, Permission::Operator] {
+            persistence
+                .send(SetUserChannelPermissions {
+                    channel_id: ChannelId(1),
+                    mask: mask.clone(),
+                    permissions,
+                    set_by: Some("alice!alice@example.com".to_string()),
+                })
+                .await
+                .unwrap();
+        }
+
+        let first = rx.recv().await.unwrap();
+        assert_eq!(first.version, 1);
+        assert_eq!(first.permissions, Permission::Voice);
+
+        let second = rx.recv().await.unwrap();
+        assert_eq!(second.version, 2);
+        assert_eq!(second.permissions, Permission::Operator);
+        assert_eq!(second.mask, mask);
+        assert_eq!(
+            second.details.set_by.as_deref(),
+            Some("alice!alice@example.com")
+        );
+
+        let (permissions, details, version) = persistence
+            .send(FetchAllUserChannelPermissions {
+                channel_id: ChannelId(1),
+            })
+            .await
+            .unwrap();
+        assert_eq!(version, 2);
+        assert_eq!(permissions.get(&mask), vec![&Permission::Operator]);
+        assert_eq!(details[&mask.to_string()], second.details);
+    }
+
+    #[actix_rt::test]
+    async fn persists_channel_invites() {
+        let database = in_memory().await;
+
+        user(&database, 1, "alice").await;
+        user(&database, 2, "bob").await;
+
+        sqlx::query("INSERT INTO channels (id, name) VALUES (1, '#channel');")
+            .execute(&database)
+            .await
+            .unwrap();
+
+        let persistence = persistence(&database);
+
+        let set = |user_id, invited| {
+            persistence.send(SetChannelInvite {
+                channel_id: ChannelId(1),
+                user_id: UserId(user_id),
+                invited,
+            })
+        };
+        let fetch = || {
+            persistence.send(FetchChannelInvites {
+                channel_id: ChannelId(1),
+            })
+        };
+
+        set(1, true).await.unwrap();
+        set(1, true).await.unwrap();
+        set(2, true).await.unwrap();
+        assert_eq!(
+            fetch().await.unwrap(),
+            [UserId(1), UserId(2)].into_iter().collect()
+        );
+
+        set(1, false).await.unwrap();
+        assert_eq!(fetch().await.unwrap(), [UserId(2)].into_iter().collect());
+    }
+
+    #[actix_rt::test]
+    async fn promotes_successor_once_founder_is_dropped() {
+        let database = in_memory().await;
+
+        user(&database, 1, "alice").await;
+        user(&database, 2, "bob").await;
+
+        sqlx::query(
+            "INSERT INTO channels (id, name) VALUES (1, '#channel');
+             INSERT INTO channel_permissions (channel, mask, permissions)
+               VALUES (1, '*!alice@*', 32767);",
+        )
+        .execute(&database)
+        .await
+        .unwrap();
+
+        let persistence = persistence(&database);
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        persistence
+            .send(SubscribeChannelPermissions {
+                channel_id: ChannelId(1),
+                channel: PermissionsRecorder(tx).start().recipient(),
+            })
+            .await
+            .unwrap();
+
+        let set = |requester, successor: &str| {
+            persistence.send(SetChannelSuccessor {
+                channel: "#channel".to_string(),
+                requester: UserId(requester),
+                successor: Some(successor.to_string()),
+            })
+        };
+
+        assert_eq!(
+            set(2, "bob").await.unwrap(),
+            SetChannelSuccessorResult::NotFounder
+        );
+        assert_eq!(
+            set(1, "carol").await.unwrap(),
+            SetChannelSuccessorResult::NoSuchAccount
+        );
+        assert_eq!(
+            set(1, "bob").await.unwrap(),
+            SetChannelSuccessorResult::Updated
+        );
+
+        // nothing happens whilst the founder is still around
+        assert!(persistence
+            .send(PromoteChannelSuccessors)
+            .await
+            .unwrap()
+            .is_empty());
+
+        sqlx::query("DELETE FROM users WHERE id = 1")
+            .execute(&database)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            persistence.send(PromoteChannelSuccessors).await.unwrap(),
+            vec![("#channel".to_string(), "bob".to_string())]
+        );
+
+        let demoted = rx.recv().await.unwrap();
+        assert_eq!(demoted.mask, HostMask::new("*", "alice", "*"));
+        assert_eq!(demoted.permissions, Permission::Normal);
+        assert_eq!(demoted.version, 1);
+
+        let promoted = rx.recv().await.unwrap();
+        assert_eq!(promoted.mask, HostMask::new("*", "bob", "*"));
+        assert_eq!(promoted.permissions, Permission::Founder);
+        assert_eq!(promoted.version, 2);
+
+        let (permissions, _, version) = persistence
+            .send(FetchAllUserChannelPermissions {
+                channel_id: ChannelId(1),
+            })
+            .await
+            .unwrap();
+        assert_eq!(version, 2);
+        assert!(permissions
+            .get(&HostMask::new("*", "alice", "*"))
+            .is_empty());
+
+        // the successor is only used once
+        assert!(persistence
+            .send(PromoteChannelSuccessors)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn manages_channel_access() {
+        let database = in_memory().await;
+
+        user(&database, 1, "alice").await;
+        user(&database, 2, "bob").await;
+        user(&database, 3, "carol").await;
+
+        sqlx::query(
+            "INSERT INTO channels (id, name) VALUES (1, '#channel');
+             INSERT INTO channel_users (channel, user_id, in_channel) VALUES (1, 2, true);
+             INSERT INTO channel_permissions (channel, mask, permissions)
+               VALUES (1, '*!dropped@*', 32767);",
+        )
+        .execute(&database)
+        .await
+        .unwrap();
+
+        let persistence = persistence(&database);
+
+        let register = |requester| {
+            persistence.send(RegisterChannel {
+                channel: "#channel".to_string(),
+                requester: UserId(requester),
+            })
+        };
+
+        // only members can claim the channel, and only whilst nobody else holds it
+        assert_eq!(
+            register(1).await.unwrap(),
+            RegisterChannelResult::NotInChannel
+        );
+        assert_eq!(
+            register(2).await.unwrap(),
+            RegisterChannelResult::Registered
+        );
+        assert_eq!(
+            register(2).await.unwrap(),
+            RegisterChannelResult::AlreadyRegistered
+        );
+
+        let access = |requester, mask: &str, permissions| {
+            persistence.send(SetChannelAccess {
+                channel: "#channel".to_string(),
+                requester: UserId(requester),
+                mask: mask.parse().unwrap(),
+                permissions,
+            })
+        };
+
+        assert_eq!(
+            access(1, "*!carol@*", Some(Permission::Operator))
+                .await
+                .unwrap(),
+            SetChannelAccessResult::NotFounder
+        );
+        assert_eq!(
+            access(2, "*!carol@*", Some(Permission::Operator))
+                .await
+                .unwrap(),
+            SetChannelAccessResult::Updated
+        );
+        assert_eq!(
+            access(2, "*!*@spam.example", Some(Permission::Ban))
+                .await
+                .unwrap(),
+            SetChannelAccessResult::Updated
+        );
+        assert_eq!(
+            access(2, "*!bob@*", None).await.unwrap(),
+            SetChannelAccessResult::FounderEntry
+        );
+        assert_eq!(
+            access(2, "*!dave@*", None).await.unwrap(),
+            SetChannelAccessResult::NoSuchEntry
+        );
+
+        let transfer = |requester, account: &str| {
+            persistence.send(TransferChannel {
+                channel: "#channel".to_string(),
+                requester: UserId(requester),
+                account: account.to_string(),
+            })
+        };
+
+        assert_eq!(
+            transfer(3, "carol").await.unwrap(),
+            SetChannelSuccessorResult::NotFounder
+        );
+        assert_eq!(
+            transfer(2, "dave").await.unwrap(),
+            SetChannelSuccessorResult::NoSuchAccount
+        );
+        assert_eq!(
+            transfer(2, "carol").await.unwrap(),
+            SetChannelSuccessorResult::Updated
+        );
+
+        assert_eq!(
+            access(3, "*!*@spam.example", None).await.unwrap(),
+            SetChannelAccessResult::Updated
+        );
+
+        let access = persistence
+            .send(FetchChannelAccess {
+                channel: "#channel".to_string(),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            access,
+            vec![
+                (HostMask::new("*", "carol", "*"), Permission::Founder),
+                (HostMask::new("*", "bob", "*"), Permission::Operator),
+            ]
+        );
+
+        assert!(persistence
+            .send(FetchChannelAccess {
+                channel: "#missing".to_string(),
+            })
+            .await
+            .unwrap()
+            .is_none());
+
+        // every change was counted towards the permissions version
+        let (_, _, version) = persistence
+            .send(FetchAllUserChannelPermissions {
+                channel_id: ChannelId(1),
+            })
+            .await
+            .unwrap();
+        assert_eq!(version, 7);
+    }
+
+    #[actix_rt::test]
+    async fn imports_channel_access() {
+        let database = in_memory().await;
+
+        user(&database, 1, "alice").await;
+        user(&database, 2, "bob").await;
+
+        sqlx::query(
+            "INSERT INTO channels (id, name) VALUES (1, '#channel');
+             INSERT INTO channel_permissions (channel, mask, permissions)
+               VALUES (1, '*!alice@*', 32767), (1, '*!carol@*', 1);",
+        )
+        .execute(&database)
+        .await
+        .unwrap();
+
+        let persistence = persistence(&database);
+
+        let import = |requester| {
+            persistence.send(ImportChannelAccess {
+                channel: "#channel".to_string(),
+                requester: UserId(requester),
+                entries: vec![
+                    (
+                        HostMask::new("*", "alice", "*").into_owned(),
+                        Permission::Ban,
+                    ),
+                    (
+                        HostMask::new("*", "bob", "*").into_owned(),
+                        Permission::Operator,
+                    ),
+                    (
+                        HostMask::new("*", "carol", "*").into_owned(),
+                        Permission::HalfOperator,
+                    ),
+                ],
+            })
+        };
+
+        assert_eq!(
+            import(2).await.unwrap(),
+            ImportChannelAccessResult::NotFounder
+        );
+
+        // the founder's entry is skipped, everything else is merged into the existing list
+        assert_eq!(
+            import(1).await.unwrap(),
+            ImportChannelAccessResult::Imported(2)
+        );
+
+        let access = persistence
+            .send(FetchChannelAccess {
+                channel: "#channel".to_string(),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            access,
+            vec![
+                (HostMask::new("*", "alice", "*"), Permission::Founder),
+                (HostMask::new("*", "bob", "*"), Permission::Operator),
+                (HostMask::new("*", "carol", "*"), Permission::HalfOperator),
+            ]
+        );
+
+        let (_, _, version) = persistence
+            .send(FetchAllUserChannelPermissions {
+                channel_id: ChannelId(1),
+            })
+            .await
+            .unwrap();
+        assert_eq!(version, 2);
+    }
+
+    #[actix_rt::test]
+    async fn records_daily_stats() {
+        let database = in_memory().await;
+        let day = NaiveDate::from_ymd_opt(2024, 1, 1).unwrap();
+        let registered = Utc.with_ymd_and_hms(2024, 1, 1, 12, 0, 0).unwrap();
+        let registered_after = Utc.with_ymd_and_hms(2024, 1, 2, 0, 0, 0).unwrap();
+
+        sqlx::query(
+            "INSERT INTO users (id, username, password, created_timestamp)
+             VALUES (1, 'alice', '', $1), (2, 'bob', '', $2), (3, 'carol', '', NULL)",
+        )
+        .bind(registered.timestamp_nanos_opt().unwrap())
+        .bind(registered_after.timestamp_nanos_opt().unwrap())
+        .execute(&database)
+        .await
+        .unwrap();
+
+        sqlx::query("INSERT INTO channels (id, name) VALUES (1, '#one'), (2, '#two')")
+            .execute(&database)
+            .await
+            .unwrap();
+
+        record_daily_stats(database.clone(), day, 5, [(1, 3)].into_iter().collect()).await;
+        record_daily_stats(
+            database.clone(),
+            day,
+            3,
+            [(1, 2), (2, 6)].into_iter().collect(),
+        )
+        .await;
+
+        let persistence = persistence(&database);
+
+        // today's row is written out before fetching
+        let stats = persistence.send(FetchDailyStats { days: 2 }).await.unwrap();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[0].day, Utc::now().date_naive().to_string());
+        assert_eq!(
+            stats[1],
+            DailyStatsEntry {
+                day: "2024-01-01".to_string(),
+                peak_clients: 5,
+                new_accounts: 1,
+                channel_messages: vec![("#two".to_string(), 6), ("#one".to_string(), 5)],
+            }
+        );
+    }
+
+    #[actix_rt::test]
+    async fn redacts_channel_messages() {
+        let database = in_memory().await;
+
+        sqlx::query(
+            "INSERT INTO channels (id, name) VALUES (1, '#channel');
+             INSERT INTO channel_messages (channel, timestamp, msgid, sender, message, kind) VALUES
+               (1, 1000000, 'a', 'alice!alice@host', 'one', 0),
+               (1, 2000000, 'b', 'bob!bob@host', 'two', 0),
+               (1, 3000000, 'c', 'alice!alice@host', 'three', 0);",
+        )
+        .execute(&database)
+        .await
+        .unwrap();
 
-        Box::pin(async move {
-            sqlx::query_as(
-                "SELECT
-                   users.username AS requester,
-                   server_bans.mask,
-                   server_bans.reason,
-                   server_bans.created_timestamp,
-                   server_bans.expires_timestamp
-                 FROM server_bans
-                 INNER JOIN users
-                   ON server_bans.requester = users.id",
-            )
-            .fetch_all(&database)
+        let persistence =
+            Persistence::new(database, Duration::from_secs(0), 50, Duration::from_secs(0)).start();
+
+        let redact = |msgid: &str, account: Option<&str>| {
+            persistence.send(RedactChannelMessage {
+                channel_id: ChannelId(1),
+                msgid: msgid.to_string(),
+                account: account.map(ToString::to_string),
+            })
+        };
+
+        assert_eq!(
+            redact("b", Some("alice")).await.unwrap(),
+            RedactChannelMessageResult::Forbidden
+        );
+        assert_eq!(
+            redact("a", Some("alice")).await.unwrap(),
+            RedactChannelMessageResult::Redacted
+        );
+        assert_eq!(
+            redact("a", Some("alice")).await.unwrap(),
+            RedactChannelMessageResult::UnknownMessage
+        );
+        assert_eq!(
+            redact("b", None).await.unwrap(),
+            RedactChannelMessageResult::Redacted
+        );
+
+        let history = persistence
+            .send(FetchChannelHistory {
+                channel: "#channel".to_string(),
+                range: HistoryRange::Latest(None),
+                limit: 50,
+            })
+            .await
+            .unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].2, "three");
+        assert_eq!(history[0].4.as_deref(), Some("c"));
+    }
+
+    #[actix_rt::test]
+    async fn caps_channel_reactions() {
+        let database = in_memory().await;
+
+        user(&database, 1, "alice").await;
+        user(&database, 2, "bob").await;
+
+        sqlx::query(
+            "INSERT INTO channels (id, name) VALUES (1, '#channel');
+             INSERT INTO channel_messages (channel, timestamp, msgid, sender, message, kind) VALUES
+               (1, 1000000, 'a', 'alice!alice@host', 'one', 0),
+               (1, 2000000, 'b', 'bob!bob@host', 'two', 0);",
+        )
+        .execute(&database)
+        .await
+        .unwrap();
+
+        let persistence =
+            Persistence::new(database, Duration::from_secs(0), 50, Duration::from_secs(0)).start();
+
+        let react = |msgid: &str, user_id: i64, reaction: &str| {
+            persistence.send(ChannelReaction {
+                channel_id: ChannelId(1),
+                msgid: msgid.to_string(),
+                user_id: UserId(user_id),
+                sender: format!("user{user_id}!user@host"),
+                reaction: reaction.to_string(),
+            })
+        };
+
+        assert_eq!(
+            react("missing", 1, "+1").await.unwrap(),
+            ChannelReactionResult::UnknownMessage
+        );
+
+        for reaction in ["+1", "+2", "+3"] {
+            assert_eq!(
+                react("a", 2, reaction).await.unwrap(),
+                ChannelReactionResult::Stored
+            );
+        }
+
+        // reacting again with a reaction the user already left is a no-op
+        assert_eq!(
+            react("a", 2, "+1").await.unwrap(),
+            ChannelReactionResult::Stored
+        );
+        assert_eq!(
+            react("a", 2, "+4").await.unwrap(),
+            ChannelReactionResult::TooManyReactions
+        );
+        assert_eq!(
+            react("a", 1, "+1").await.unwrap(),
+            ChannelReactionResult::Stored
+        );
+
+        let reactions = persistence
+            .send(FetchChannelReactions {
+                channel: "#channel".to_string(),
+                msgids: vec!["a".to_string(), "b".to_string()],
+            })
             .await
             .unwrap()
-        })
+            .into_iter()
+            .map(|(_, sender, msgid, reaction)| (sender, msgid, reaction))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            reactions,
+            vec![
+                (
+                    "user2!user@host".to_string(),
+                    "a".to_string(),
+                    "+1".to_string()
+                ),
+                (
+                    "user2!user@host".to_string(),
+                    "a".to_string(),
+                    "+2".to_string()
+                ),
+                (
+                    "user2!user@host".to_string(),
+                    "a".to_string(),
+                    "+3".to_string()
+                ),
+                (
+                    "user1!user@host".to_string(),
+                    "a".to_string(),
+                    "+1".to_string()
+                ),
+            ]
+        );
     }
-}
 
-/// Remove any messages from the messages table whenever they've been seen by all users
-/// or have passed their retention period
-/// .
-pub async fn truncate_seen_messages(db: sqlx::Pool<sqlx::Any>, max_replay_since: Duration) {
-    // fetch the minimum last seen message by channel
-    let messages = sqlx::query_as::<_, (i64, i64)>(
-        "SELECT channel, COALESCE(MIN(last_seen_message_timestamp), 0)
-         FROM channel_users
-         GROUP BY channel",
-    )
-    .fetch_all(&db)
-    .await
-    .unwrap();
+    #[actix_rt::test]
+    async fn fetches_whowas() {
+        let database = in_memory().await;
 
-    let max_replay_since = Utc::now() - chrono::Duration::from_std(max_replay_since).unwrap();
+        // seen before the retention window
+        sqlx::query(
+            "INSERT INTO whowas (nick, username, host, realname, seen_timestamp)
+             VALUES ('bob', 'old', 'host', 'Old Bob', 1000)",
+        )
+        .execute(&database)
+        .await
+        .unwrap();
 
-    // delete all messages that have been by all users or have passed their retention period
-    for (channel, min_seen_timestamp) in messages {
-        let remove_before = std::cmp::max(
-            min_seen_timestamp,
-            max_replay_since.timestamp_nanos_opt().unwrap(),
+        let persistence = Persistence::new(
+            database,
+            Duration::from_secs(0),
+            0,
+            Duration::from_secs(3600),
+        )
+        .start();
+
+        for (nick, username) in [("bob", "first"), ("Bob", "second"), ("alice", "alice")] {
+            persistence
+                .send(RecordWhowas {
+                    nick: nick.to_string(),
+                    username: username.to_string(),
+                    host: "host".to_string(),
+                    realname: "Bob".to_string(),
+                })
+                .await
+                .unwrap();
+        }
+
+        let fetch = |count| {
+            persistence.send(FetchWhowas {
+                nick: "BOB".to_string(),
+                count,
+            })
+        };
+        let usernames =
+            |entries: Vec<WhowasEntry>| entries.into_iter().map(|v| v.username).collect::<Vec<_>>();
+
+        assert_eq!(usernames(fetch(10).await.unwrap()), ["second", "first"]);
+        assert_eq!(usernames(fetch(1).await.unwrap()), ["second"]);
+    }
+
+    #[actix_rt::test]
+    async fn caps_login_history() {
+        let database = in_memory().await;
+
+        user(&database, 1, "alice").await;
+        user(&database, 2, "bob").await;
+
+        let persistence = persistence(&database);
+
+        let login = |user_id, session: String| {
+            persistence.send(RecordLogin {
+                user_id: UserId(user_id),
+                session,
+                ip: "127.0.0.1".to_string(),
+                host: None,
+            })
+        };
+
+        login(2, "bob".to_string()).await.unwrap();
+        for i in 0..=super::LOGIN_HISTORY_PER_USER {
+            login(1, i.to_string()).await.unwrap();
+        }
+
+        let persistence = &persistence;
+        let sessions = |user_id| async move {
+            persistence
+                .send(FetchLoginHistory {
+                    user_id: UserId(user_id),
+                })
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|v| v.session)
+                .collect::<Vec<_>>()
+        };
+
+        let alice = sessions(1).await;
+        assert_eq!(
+            alice.len(),
+            usize::try_from(super::LOGIN_HISTORY_PER_USER).unwrap()
         );
+        assert_eq!(alice.first().map(String::as_str), Some("20"));
+        assert_eq!(alice.last().map(String::as_str), Some("1"));
+
+        assert_eq!(sessions(2).await, ["bob"]);
+    }
+
+    #[actix_rt::test]
+    async fn fetches_history() {
+        let database = in_memory().await;
 
+        user(&database, 1, "alice").await;
+        user(&database, 2, "bob").await;
+
+        // messages 1ms apart, with the second sent part way through its millisecond
         sqlx::query(
-            "DELETE FROM channel_messages
-             WHERE channel = ?
-               AND timestamp <= ?",
+            "INSERT INTO channels (id, name) VALUES (1, '#channel');
+             INSERT INTO channel_messages (channel, timestamp, sender, message, kind) VALUES
+               (1, 1000000, 'alice', 'one', 0),
+               (1, 2000500, 'bob', 'two', 0),
+               (1, 3000000, 'alice', 'three', 0);
+             INSERT INTO private_messages
+               (timestamp, sender, sender_user, receiver, message, kind, delivered) VALUES
+               (1000000, 'alice', 1, 2, 'hi bob', 0, true),
+               (2000000, 'bob', 2, 1, 'hi alice', 0, true);",
+        )
+        .execute(&database)
+        .await
+        .unwrap();
+
+        let persistence =
+            Persistence::new(database, Duration::from_secs(0), 2, Duration::from_secs(0)).start();
+
+        let ms = |v: i64| Utc.timestamp_nanos(v * 1_000_000);
+        let channel = |range, limit| {
+            persistence.send(FetchChannelHistory {
+                channel: "#channel".to_string(),
+                range,
+                limit,
+            })
+        };
+        let messages = |v: Vec<StoredMessage>| v.into_iter().map(|v| v.2).collect::<Vec<_>>();
+
+        // limited by the configured replay count, returning the most recent in order
+        assert_eq!(
+            messages(channel(HistoryRange::Latest(None), 50).await.unwrap()),
+            vec!["two", "three"]
+        );
+        assert_eq!(
+            messages(channel(HistoryRange::Before(ms(3)), 1).await.unwrap()),
+            vec!["two"]
+        );
+        // the selector covers the whole millisecond, so "two" isn't returned again
+        assert_eq!(
+            messages(channel(HistoryRange::After(ms(2)), 50).await.unwrap()),
+            vec!["three"]
+        );
+        assert_eq!(
+            messages(
+                channel(HistoryRange::Between(ms(3), ms(0)), 1)
+                    .await
+                    .unwrap()
+            ),
+            vec!["two"]
+        );
+
+        let history = persistence
+            .send(FetchPrivateHistory {
+                user_id: UserId(1),
+                other_user_id: UserId(2),
+                range: HistoryRange::Latest(None),
+                limit: 50,
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            history
+                .into_iter()
+                .map(|(message, sent)| (message.2, sent))
+                .collect::<Vec<_>>(),
+            vec![
+                ("hi bob".to_string(), true),
+                ("hi alice".to_string(), false)
+            ]
+        );
+    }
+
+    /// Stands in for the server, forwarding any changes to the database's health it's sent.
+    struct HealthRecorder(mpsc::UnboundedSender<DatabaseHealthChanged>);
+
+    impl Actor for HealthRecorder {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<DatabaseHealthChanged> for HealthRecorder {
+        type Result = ();
+
+        fn handle(&mut self, msg: DatabaseHealthChanged, _ctx: &mut Self::Context) -> Self::Result {
+            self.0.send(msg).unwrap();
+        }
+    }
+
+    #[actix_rt::test]
+    async fn buffers_messages_while_database_unavailable() {
+        let database = in_memory().await;
+
+        user(&database, 1, "alice").await;
+        user(&database, 2, "bob").await;
+
+        let persistence = persistence(&database);
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        persistence
+            .send(SubscribeDatabaseHealth {
+                subscriber: HealthRecorder(tx).start().recipient(),
+            })
+            .await
+            .unwrap();
+
+        let message = |message: &str| PrivateMessage {
+            sender: "alice!alice@host".to_string(),
+            sender_user: UserId(1),
+            receiver: UserId(2),
+            message: message.to_string(),
+            kind: MessageKind::Normal,
+            delivered: true,
+        };
+
+        // make writes fail by moving the table out from under the actor
+        sqlx::query("ALTER TABLE private_messages RENAME TO private_messages_moved")
+            .execute(&database)
+            .await
+            .unwrap();
+
+        persistence.send(message("first")).await.unwrap();
+        assert!(matches!(
+            rx.recv().await.unwrap(),
+            DatabaseHealthChanged::Unavailable { retry_in } if retry_in == Duration::from_secs(1)
+        ));
+
+        // the breaker is open, so this is buffered without being attempted
+        persistence.send(message("second")).await.unwrap();
+
+        sqlx::query("ALTER TABLE private_messages_moved RENAME TO private_messages")
+            .execute(&database)
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            rx.recv().await.unwrap(),
+            DatabaseHealthChanged::Restored { dropped: 0 }
+        ));
+
+        let messages: Vec<(String,)> =
+            sqlx::query_as("SELECT message FROM private_messages ORDER BY timestamp")
+                .fetch_all(&database)
+                .await
+                .unwrap();
+
+        assert_eq!(
+            messages,
+            vec![("first".to_string(),), ("second".to_string(),)]
+        );
+    }
+
+    /// A connection to a schema created by [`postgres`], which is dropped along with everything
+    /// in it once the test is done with it, even if the test panics.
+    struct PostgresSchema {
+        database: sqlx::Pool<sqlx::Any>,
+        uri: String,
+        schema: String,
+    }
+
+    impl Drop for PostgresSchema {
+        fn drop(&mut self) {
+            let uri = std::mem::take(&mut self.uri);
+            let query = format!("DROP SCHEMA IF EXISTS {} CASCADE", self.schema);
+
+            // the test's runtime can't be blocked on from within one of its tasks, so the schema
+            // is dropped from a runtime of its own
+            let dropped = std::thread::spawn(move || {
+                tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()
+                    .unwrap()
+                    .block_on(async move {
+                        let mut conn = AnyConnection::connect(&uri).await.unwrap();
+                        conn.execute(query.as_str()).await.unwrap();
+                        conn.close().await.unwrap();
+                    });
+            })
+            .join();
+
+            if dropped.is_err() {
+                error!(self.schema, "Failed to drop test schema");
+            }
+        }
+    }
+
+    /// Connects to the Postgres database given by `TITANIRCD_TEST_POSTGRES_URI`, if set, within
+    /// a freshly created schema so runs don't see each other's data.
+    async fn postgres() -> Option<PostgresSchema> {
+        let uri = std::env::var("TITANIRCD_TEST_POSTGRES_URI").ok()?;
+        sqlx::any::install_default_drivers();
+
+        let schema = format!("titanircd_test_{:016x}", rand::random::<u64>());
+        let search_path = schema.clone();
+        let database = AnyPoolOptions::new()
+            .max_connections(1)
+            .after_connect(move |conn, _meta| {
+                let schema = search_path.clone();
+                Box::pin(async move {
+                    conn.execute(
+                        format!(
+                            "CREATE SCHEMA IF NOT EXISTS {schema}; SET search_path TO {schema}"
+                        )
+                        .as_str(),
+                    )
+                    .await?;
+                    Ok(())
+                })
+            })
+            .connect_with(AnyConnectOptions::from_str(&uri).unwrap())
+            .await
+            .unwrap();
+
+        crate::database::migrate(&database).await.unwrap();
+
+        Some(PostgresSchema {
+            database,
+            uri,
+            schema,
+        })
+    }
+
+    /// Goes through the queries made over the lifetime of an account and a channel, to catch
+    /// SQL that only runs against one of the databases.
+    async fn exercise_backend(database: sqlx::Pool<sqlx::Any>) {
+        let (run, _) = record_startup(&database).await.unwrap();
+        Keys::new(&database).await.unwrap();
+
+        let (alice, _) = create_user_or_fetch_password_hash(&database, "alice", b"password")
+            .await
+            .unwrap();
+        let (bob, _) = create_user_or_fetch_password_hash(&database, "bob", b"password")
+            .await
+            .unwrap();
+        assert_eq!(
+            create_user_or_fetch_password_hash(&database, "alice", b"other")
+                .await
+                .unwrap()
+                .0,
+            alice
+        );
+        assert!(reserve_nick(&database, "Alice", UserId(alice))
+            .await
+            .unwrap());
+        assert!(!reserve_nick(&database, "Alice", UserId(bob)).await.unwrap());
+
+        let persistence = Persistence::new(
+            database.clone(),
+            Duration::from_secs(3600),
+            50,
+            Duration::from_secs(3600),
+        )
+        .start();
+
+        assert_eq!(
+            persistence
+                .send(FetchAccountByNick {
+                    nick: "ALICE".to_string(),
+                })
+                .await
+                .unwrap(),
+            Some((UserId(alice), "alice".to_string()))
+        );
+
+        let (channel, _) = persistence
+            .send(ChannelCreated {
+                name: "#channel".to_string(),
+            })
+            .await
+            .unwrap();
+        let (recreated, _) = persistence
+            .send(ChannelCreated {
+                name: "#channel".to_string(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(channel, recreated);
+
+        for user in [alice, bob] {
+            persistence
+                .send(ChannelJoined {
+                    channel_id: ChannelId(channel),
+                    user_id: UserId(user),
+                    span: Span::current(),
+                })
+                .await
+                .unwrap();
+        }
+
+        let mask = HostMask::new("*", "alice", "*").into_owned();
+        persistence
+            .send(SetUserChannelPermissions {
+                channel_id: ChannelId(channel),
+                mask: mask.clone(),
+                permissions: Permission::Founder,
+                set_by: None,
+            })
+            .await
+            .unwrap();
+        let (permissions, details, version) = persistence
+            .send(FetchAllUserChannelPermissions {
+                channel_id: ChannelId(channel),
+            })
+            .await
+            .unwrap();
+        assert_eq!(permissions.get(&mask), vec![&Permission::Founder]);
+        assert!(details[&mask.to_string()].set_at.is_some());
+        assert_eq!(version, 1);
+
+        persistence
+            .send(ChannelMessage {
+                channel_id: ChannelId(channel),
+                msgid: "a".to_string(),
+                sender: "alice!alice@host".to_string(),
+                message: "hello".to_string(),
+                receivers: vec![UserId(alice)],
+                kind: MessageKind::Normal,
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            persistence
+                .send(ChannelReaction {
+                    channel_id: ChannelId(channel),
+                    msgid: "a".to_string(),
+                    user_id: UserId(bob),
+                    sender: "bob!bob@host".to_string(),
+                    reaction: "+1".to_string(),
+                })
+                .await
+                .unwrap(),
+            ChannelReactionResult::Stored
+        );
+
+        // only bob hasn't seen the message yet
+        let replay = |user| {
+            persistence.send(FetchUnseenChannelMessages {
+                channel_id: ChannelId(channel),
+                user_id: UserId(user),
+                span: Span::current(),
+            })
+        };
+        assert!(replay(alice).await.unwrap().messages.is_empty());
+        let unseen = replay(bob).await.unwrap();
+        assert_eq!(unseen.messages.len(), 1);
+        assert_eq!(unseen.reactions.len(), 1);
+
+        let history = persistence
+            .send(FetchChannelHistory {
+                channel: "#channel".to_string(),
+                range: HistoryRange::Latest(None),
+                limit: 50,
+            })
+            .await
+            .unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].2, "hello");
+
+        persistence
+            .send(RecordWhowas {
+                nick: "Alice".to_string(),
+                username: "alice".to_string(),
+                host: "host".to_string(),
+                realname: "Alice".to_string(),
+            })
+            .await
+            .unwrap();
+        let whowas = persistence
+            .send(FetchWhowas {
+                nick: "alice".to_string(),
+                count: 10,
+            })
+            .await
+            .unwrap();
+        assert_eq!(whowas.len(), 1);
+
+        let day = Utc::now().date_naive();
+        record_daily_stats(
+            database.clone(),
+            day,
+            2,
+            [(channel, 1)].into_iter().collect(),
+        )
+        .await;
+        record_daily_stats(
+            database.clone(),
+            day,
+            1,
+            [(channel, 1)].into_iter().collect(),
+        )
+        .await;
+        truncate_seen_messages(database.clone(), Duration::from_secs(3600)).await;
+
+        assert!(drop_user(&database, UserId(bob)).await.unwrap());
+        record_shutdown(&database, run).await.unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn runs_against_sqlite() {
+        exercise_backend(in_memory().await).await;
+    }
+
+    #[actix_rt::test]
+    #[ignore = "needs a Postgres database given by TITANIRCD_TEST_POSTGRES_URI"]
+    async fn runs_against_postgres() {
+        let postgres = postgres()
+            .await
+            .expect("TITANIRCD_TEST_POSTGRES_URI should be set");
+
+        exercise_backend(postgres.database.clone()).await;
+    }
+
+    /// Stores and reads back each of the types with their own encoding, to catch any that
+    /// don't survive the column types of one of the databases.
+    async fn round_trip_types(database: sqlx::Pool<sqlx::Any>) {
+        let backend = Backend::of(&database).unwrap();
+
+        let (user, _) = create_user_or_fetch_password_hash(&database, "alice", b"password")
+            .await
+            .unwrap();
+        let (other_user, _) = create_user_or_fetch_password_hash(&database, "bob", b"password")
+            .await
+            .unwrap();
+
+        let persistence = Persistence::new(
+            database.clone(),
+            Duration::from_secs(3600),
+            50,
+            Duration::from_secs(3600),
+        )
+        .start();
+
+        let masks = [
+            "nick!user@host",
+            "*!*@*",
+            "ni*!*@192.0.2.*",
+            "*!~user@2001:db8::1",
+            "nïck!üser@höst.example",
+            "*!*@example.*",
+        ]
+        .map(|mask| HostMask::try_from(mask).unwrap().into_owned());
+
+        // host and account masks through server bans
+        let bans: Vec<BanMask> = masks
+            .iter()
+            .cloned()
+            .map(BanMask::HostMask)
+            .chain([BanMask::Account("alice".to_string())])
+            .collect();
+        for mask in &bans {
+            persistence
+                .send(ServerBan {
+                    mask: mask.clone(),
+                    requester: UserId(user),
+                    reason: "reason".to_string(),
+                    created: Utc::now(),
+                    expires: None,
+                })
+                .await
+                .unwrap();
+        }
+        let mut stored: Vec<_> = persistence
+            .send(ServerListBan)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.mask)
+            .collect();
+        stored.sort_by_key(ToString::to_string);
+        let mut expected = bans.clone();
+        expected.sort_by_key(ToString::to_string);
+        assert_eq!(stored, expected, "server bans on {backend:?}");
+
+        // host masks through server shuns
+        for mask in &masks {
+            persistence
+                .send(ServerShun {
+                    mask: mask.clone(),
+                    requester: UserId(user),
+                    reason: "reason".to_string(),
+                    created: Utc::now(),
+                    expires: None,
+                })
+                .await
+                .unwrap();
+        }
+        let shuns = persistence.send(ServerListShun).await.unwrap();
+        assert_eq!(shuns.len(), masks.len(), "server shuns on {backend:?}");
+        for mask in &masks {
+            assert!(
+                shuns
+                    .iter()
+                    .any(|entry| entry.mask == BanMask::HostMask(mask.clone())),
+                "server shun {mask} on {backend:?}"
+            );
+        }
+
+        // every permission, including the ends of the range, against a mask of its own
+        let (channel, _) = persistence
+            .send(ChannelCreated {
+                name: "#channel".to_string(),
+            })
+            .await
+            .unwrap();
+        let permissions = [
+            Permission::Ban,
+            Permission::Normal,
+            Permission::Voice,
+            Permission::HalfOperator,
+            Permission::Operator,
+            Permission::Founder,
+        ];
+        for (mask, permission) in masks.iter().zip(permissions) {
+            persistence
+                .send(SetUserChannelPermissions {
+                    channel_id: ChannelId(channel),
+                    mask: mask.clone(),
+                    permissions: permission,
+                    set_by: Some("nick!user@host".to_string()),
+                })
+                .await
+                .unwrap();
+        }
+        let (stored, details, _) = persistence
+            .send(FetchAllUserChannelPermissions {
+                channel_id: ChannelId(channel),
+            })
+            .await
+            .unwrap();
+        for (mask, permission) in masks.iter().zip(permissions) {
+            // wildcard masks also match the more specific masks, so there can be more than one
+            assert!(
+                stored.get(mask).contains(&&permission),
+                "permissions for {mask} on {backend:?}"
+            );
+            assert_eq!(
+                details[&mask.to_string()].set_by.as_deref(),
+                Some("nick!user@host")
+            );
+        }
+
+        // and decoded straight from the column
+        let mut decoded: Vec<_> = sqlx::query_as::<_, (HostMask<'static>, Permission)>(
+            "SELECT mask, permissions FROM channel_permissions WHERE channel = $1",
         )
         .bind(channel)
-        .bind(remove_before)
-        .execute(&db)
+        .fetch_all(&database)
         .await
         .unwrap();
+        decoded.sort_by_key(|(mask, _)| mask.to_string());
+        let mut expected: Vec<_> = masks.iter().cloned().zip(permissions).collect();
+        expected.sort_by_key(|(mask, _)| mask.to_string());
+        assert_eq!(decoded, expected, "decoded permissions on {backend:?}");
+
+        // message kinds that are persisted, to channels and between users
+        let kinds = [MessageKind::Normal, MessageKind::Notice];
+        for (i, kind) in kinds.into_iter().enumerate() {
+            persistence
+                .send(ChannelMessage {
+                    channel_id: ChannelId(channel),
+                    msgid: i.to_string(),
+                    sender: "alice!alice@host".to_string(),
+                    message: format!("message {i}"),
+                    receivers: vec![],
+                    kind,
+                })
+                .await
+                .unwrap();
+            persistence
+                .send(PrivateMessage {
+                    sender: "alice!alice@host".to_string(),
+                    sender_user: UserId(user),
+                    receiver: UserId(other_user),
+                    message: format!("message {i}"),
+                    kind,
+                    delivered: true,
+                })
+                .await
+                .unwrap();
+        }
+
+        let channel_kinds: Vec<_> = persistence
+            .send(FetchChannelHistory {
+                channel: "#channel".to_string(),
+                range: HistoryRange::Latest(None),
+                limit: 50,
+            })
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(_, _, _, kind, _)| kind)
+            .collect();
+        assert_eq!(channel_kinds, kinds, "channel message kinds on {backend:?}");
+
+        let private_kinds: Vec<_> = persistence
+            .send(FetchPrivateHistory {
+                user_id: UserId(other_user),
+                other_user_id: UserId(user),
+                range: HistoryRange::Latest(None),
+                limit: 50,
+            })
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|((_, _, _, kind, _), _)| kind)
+            .collect();
+        assert_eq!(private_kinds, kinds, "private message kinds on {backend:?}");
+    }
+
+    #[actix_rt::test]
+    async fn round_trips_types_on_every_backend() {
+        round_trip_types(in_memory().await).await;
+
+        // Postgres is only included if `TITANIRCD_TEST_POSTGRES_URI` is set
+        if let Some(postgres) = postgres().await {
+            round_trip_types(postgres.database.clone()).await;
+        }
     }
 }
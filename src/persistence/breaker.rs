@@ -0,0 +1,202 @@
+//! Keeps the server usable while the database is unavailable: restarts of the persistence actor
+//! are backed off rather than retried as fast as they fail, and message writes are buffered and
+//! retried rather than crashing the actor each time one fails.
+
+use std::{collections::VecDeque, time::Duration};
+
+/// A delay that doubles every time it's used, up to a limit.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    current: Option<Duration>,
+}
+
+impl Backoff {
+    #[must_use]
+    pub const fn new(initial: Duration, max: Duration) -> Self {
+        Self {
+            initial,
+            max,
+            current: None,
+        }
+    }
+
+    /// Returns how long to wait before the next attempt, doubling the delay for the attempt
+    /// after.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.current.map_or(self.initial, |current| {
+            current.saturating_mul(2).min(self.max)
+        });
+        self.current = Some(delay);
+        delay
+    }
+
+    /// Starts the delay from the beginning again, after an attempt has succeeded.
+    pub fn reset(&mut self) {
+        self.current = None;
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(1), Duration::from_secs(60))
+    }
+}
+
+/// Stops writes from being attempted once one has failed, buffering them until a retry
+/// succeeds. Only the most recent `capacity` writes are kept, older ones are dropped to bound
+/// memory use during a long outage.
+#[derive(Debug)]
+pub struct CircuitBreaker<T> {
+    buffer: VecDeque<T>,
+    capacity: usize,
+    /// Writes dropped since the breaker opened because the buffer was full
+    dropped: usize,
+    open: bool,
+    /// The delay between retries
+    backoff: Backoff,
+}
+
+impl<T> CircuitBreaker<T> {
+    #[must_use]
+    pub const fn new(capacity: usize, backoff: Backoff) -> Self {
+        Self {
+            buffer: VecDeque::new(),
+            capacity,
+            dropped: 0,
+            open: false,
+            backoff,
+        }
+    }
+
+    /// Whether writes should be buffered rather than attempted.
+    #[must_use]
+    pub const fn is_open(&self) -> bool {
+        self.open
+    }
+
+    /// Opens the breaker after a write failed, returning `true` if it was previously closed.
+    pub fn open(&mut self) -> bool {
+        !std::mem::replace(&mut self.open, true)
+    }
+
+    /// Closes the breaker once the buffer has been written, returning the amount of writes that
+    /// were dropped while it was open.
+    pub fn close(&mut self) -> usize {
+        self.open = false;
+        self.backoff.reset();
+        std::mem::take(&mut self.dropped)
+    }
+
+    /// Buffers a write to be retried, dropping the oldest buffered write if the buffer's full.
+    pub fn buffer(&mut self, item: T) {
+        self.buffer.push_back(item);
+        self.truncate();
+    }
+
+    /// Takes every buffered write to be retried.
+    pub fn take(&mut self) -> VecDeque<T> {
+        std::mem::take(&mut self.buffer)
+    }
+
+    /// Puts writes that failed to be retried back in front of anything buffered since they were
+    /// taken.
+    pub fn requeue(&mut self, mut items: VecDeque<T>) {
+        items.append(&mut self.buffer);
+        self.buffer = items;
+        self.truncate();
+    }
+
+    /// Returns how long to wait before retrying the buffered writes.
+    pub fn retry_delay(&mut self) -> Duration {
+        self.backoff.next_delay()
+    }
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    fn truncate(&mut self) {
+        while self.buffer.len() > self.capacity {
+            self.buffer.pop_front();
+            self.dropped += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::VecDeque, time::Duration};
+
+    use super::{Backoff, CircuitBreaker};
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(5));
+
+        assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+        assert_eq!(backoff.next_delay(), Duration::from_secs(2));
+        assert_eq!(backoff.next_delay(), Duration::from_secs(4));
+        assert_eq!(backoff.next_delay(), Duration::from_secs(5));
+        assert_eq!(backoff.next_delay(), Duration::from_secs(5));
+
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn breaker_buffers_and_drops_oldest() {
+        let mut breaker = CircuitBreaker::new(3, Backoff::default());
+
+        assert!(!breaker.is_open());
+        assert!(breaker.open());
+        assert!(!breaker.open());
+
+        for i in 0..5 {
+            breaker.buffer(i);
+        }
+
+        assert_eq!(breaker.take(), VecDeque::from([2, 3, 4]));
+        assert!(breaker.is_empty());
+        assert_eq!(breaker.close(), 2);
+        assert!(!breaker.is_open());
+        assert_eq!(breaker.close(), 0);
+    }
+
+    #[test]
+    fn breaker_requeues_in_front() {
+        let mut breaker = CircuitBreaker::new(3, Backoff::default());
+        breaker.open();
+
+        breaker.buffer(1);
+        breaker.buffer(2);
+        let retrying = breaker.take();
+        breaker.buffer(3);
+        breaker.buffer(4);
+        breaker.requeue(retrying);
+
+        assert_eq!(breaker.len(), 3);
+        assert_eq!(breaker.take(), VecDeque::from([2, 3, 4]));
+        assert_eq!(breaker.close(), 1);
+    }
+
+    #[test]
+    fn breaker_retry_delay_resets_on_close() {
+        let mut breaker = CircuitBreaker::<()>::new(1, Backoff::default());
+        breaker.open();
+
+        assert_eq!(breaker.retry_delay(), Duration::from_secs(1));
+        assert_eq!(breaker.retry_delay(), Duration::from_secs(2));
+
+        breaker.close();
+        breaker.open();
+        assert_eq!(breaker.retry_delay(), Duration::from_secs(1));
+    }
+}
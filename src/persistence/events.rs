@@ -1,17 +1,24 @@
-use actix::Message;
+use std::collections::{HashMap, HashSet};
+
+use actix::{Message, Recipient};
 use chrono::{DateTime, Utc};
 use sqlx::FromRow;
 use tracing::Span;
 
 use crate::{
-    channel::{permissions::Permission, ChannelId},
+    channel::{
+        metadata::ChannelMetadata,
+        modes::ChannelModeState,
+        permissions::{Permission, PermissionDetails},
+        ChannelId,
+    },
     connection::UserId,
-    host_mask::{HostMask, HostMaskMap},
-    messages::MessageKind,
+    host_mask::{BanMask, HostMask, HostMaskMap},
+    messages::{DatabaseHealthChanged, MessageKind, PermissionsChanged},
 };
 
 #[derive(Message)]
-#[rtype(result = "i64")]
+#[rtype(result = "(i64, DateTime<Utc>)")]
 pub struct ChannelCreated {
     pub name: String,
 }
@@ -39,30 +46,262 @@ pub struct FetchUserChannels {
     pub span: Span,
 }
 
+/// Fetches every permission set on the channel along with who set each of them, keyed by mask,
+/// and the version of the permissions.
 #[derive(Message)]
-#[rtype(result = "HostMaskMap<Permission>")]
+#[rtype(result = "(HostMaskMap<Permission>, HashMap<String, PermissionDetails>, i64)")]
 pub struct FetchAllUserChannelPermissions {
     pub channel_id: ChannelId,
 }
 
+/// Registers a live channel to be sent a [`PermissionsChanged`] for every change made to its
+/// permissions, replacing any previous registration for the channel.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SubscribeChannelPermissions {
+    pub channel_id: ChannelId,
+    pub channel: Recipient<PermissionsChanged>,
+}
+
+/// Registers the server to be sent a [`DatabaseHealthChanged`] whenever messages stop being
+/// written to the database, and again once they're being written again.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SubscribeDatabaseHealth {
+    pub subscriber: Recipient<DatabaseHealthChanged>,
+}
+
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct SetUserChannelPermissions {
     pub channel_id: ChannelId,
     pub mask: HostMask<'static>,
     pub permissions: Permission,
+    /// The hostmask of the user making the change
+    pub set_by: Option<String>,
+}
+
+#[derive(Message)]
+#[rtype(result = "ChannelModeState")]
+pub struct FetchChannelModes {
+    pub channel_id: ChannelId,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SetChannelModes {
+    pub channel_id: ChannelId,
+    pub modes: ChannelModeState,
+}
+
+/// Checks whether a channel has ever been created, regardless of whether it's currently loaded.
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct FetchChannelExists {
+    pub name: String,
+}
+
+/// Fetches the descriptive metadata set on the channel through `CS SET`.
+#[derive(Message)]
+#[rtype(result = "ChannelMetadata")]
+pub struct FetchChannelMetadata {
+    pub channel_id: ChannelId,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SetChannelMetadata {
+    pub channel_id: ChannelId,
+    pub metadata: ChannelMetadata,
+}
+
+/// Fetches the users with an outstanding invite to the channel.
+#[derive(Message)]
+#[rtype(result = "HashSet<UserId>")]
+pub struct FetchChannelInvites {
+    pub channel_id: ChannelId,
+}
+
+/// Records a new invite to the channel, or removes one once it's been used.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SetChannelInvite {
+    pub channel_id: ChannelId,
+    pub user_id: UserId,
+    pub invited: bool,
+}
+
+/// Designates the account that takes over as founder of the channel once the founder's
+/// account is dropped, or clears it if `successor` is `None`. Only the channel's founder may
+/// change its successor.
+#[derive(Message)]
+#[rtype(result = "SetChannelSuccessorResult")]
+pub struct SetChannelSuccessor {
+    pub channel: String,
+    pub requester: UserId,
+    pub successor: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetChannelSuccessorResult {
+    Updated,
+    NotFounder,
+    NoSuchAccount,
+}
+
+/// Makes the requester the founder of a channel that doesn't currently have one, such as a
+/// channel whose founder dropped their account without designating a successor. The requester
+/// has to be in the channel.
+#[derive(Message)]
+#[rtype(result = "RegisterChannelResult")]
+pub struct RegisterChannel {
+    pub channel: String,
+    pub requester: UserId,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterChannelResult {
+    Registered,
+    AlreadyRegistered,
+    NotInChannel,
+}
+
+/// Hands the channel over to another account, the previous founder is kept on as an operator.
+/// Only the channel's founder may transfer it.
+#[derive(Message)]
+#[rtype(result = "SetChannelSuccessorResult")]
+pub struct TransferChannel {
+    pub channel: String,
+    pub requester: UserId,
+    pub account: String,
+}
+
+/// Grants `mask` the given permission on the channel, or removes its entry from the channel's
+/// access list if `permissions` is `None`. Only the channel's founder may change its access
+/// list, and the founder's own entry can only be changed by transferring the channel.
+#[derive(Message)]
+#[rtype(result = "SetChannelAccessResult")]
+pub struct SetChannelAccess {
+    pub channel: String,
+    pub requester: UserId,
+    pub mask: HostMask<'static>,
+    pub permissions: Option<Permission>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetChannelAccessResult {
+    Updated,
+    NotFounder,
+    FounderEntry,
+    NoSuchEntry,
+}
+
+/// Fetches the channel's access list, highest permission first, or `None` if the channel
+/// doesn't exist.
+#[derive(Message)]
+#[rtype(result = "Option<Vec<(HostMask<'static>, Permission)>>")]
+pub struct FetchChannelAccess {
+    pub channel: String,
+}
+
+/// Merges `entries` into the channel's access list, replacing the permission of any mask that's
+/// already on it. Only the channel's founder may import entries, and founder status can't be
+/// imported, so the founder's own entry is left as it is.
+#[derive(Message)]
+#[rtype(result = "ImportChannelAccessResult")]
+pub struct ImportChannelAccess {
+    pub channel: String,
+    pub requester: UserId,
+    pub entries: Vec<(HostMask<'static>, Permission)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportChannelAccessResult {
+    /// The amount of entries that were added to or updated on the access list
+    Imported(usize),
+    NotFounder,
+}
+
+/// Promotes the successor of every channel whose founder's account has been dropped, returning
+/// the name of each channel along with the account of its new founder.
+#[derive(Message)]
+#[rtype(result = "Vec<(String, String)>")]
+pub struct PromoteChannelSuccessors;
+
+/// Writes out the counters held in memory, sent when the server is shutting down. Events are
+/// handled in the order they're sent, so once this is answered every event sent before it has
+/// been handled too.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Flush;
+
+/// Sent by the server whenever a client connects or disconnects, so the daily peak can be
+/// recorded.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct ClientCountChanged {
+    pub clients: usize,
+}
+
+/// Fetches the daily statistics for the given amount of days, most recent first.
+#[derive(Message)]
+#[rtype(result = "Vec<DailyStatsEntry>")]
+pub struct FetchDailyStats {
+    pub days: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DailyStatsEntry {
+    pub day: String,
+    pub peak_clients: i64,
+    pub new_accounts: i64,
+    /// Messages sent to each channel, busiest channel first.
+    pub channel_messages: Vec<(String, i64)>,
+}
+
+/// Adds a TLS client certificate fingerprint to an account, returning false if the certificate
+/// has already been added to an account.
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct AddUserCertificate {
+    pub user_id: UserId,
+    pub fingerprint: String,
+}
+
+/// Removes a TLS client certificate fingerprint from an account, returning false if the account
+/// didn't have the certificate.
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct RemoveUserCertificate {
+    pub user_id: UserId,
+    pub fingerprint: String,
 }
 
 #[derive(Message)]
-#[rtype(result = "Option<UserId>")]
-pub struct FetchUserIdByNick {
+#[rtype(result = "Vec<String>")]
+pub struct ListUserCertificates {
+    pub user_id: UserId,
+}
+
+/// Looks up the id and username of the account owning the given nick.
+#[derive(Message)]
+#[rtype(result = "Option<(UserId, String)>")]
+pub struct FetchAccountByNick {
     pub nick: String,
 }
 
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct FetchSharesChannel {
+    pub user_id: UserId,
+    pub other_user_id: UserId,
+}
+
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct ChannelMessage {
     pub channel_id: ChannelId,
+    pub msgid: String,
     pub sender: String,
     pub message: String,
     pub receivers: Vec<UserId>,
@@ -73,26 +312,163 @@ pub struct ChannelMessage {
 #[rtype(result = "()")]
 pub struct PrivateMessage {
     pub sender: String,
+    pub sender_user: UserId,
     pub receiver: UserId,
     pub message: String,
     pub kind: MessageKind,
+    /// Whether the receiver had a session online to deliver the message to, undelivered
+    /// messages are replayed when the receiver next connects.
+    pub delivered: bool,
+}
+
+/// A message as it was stored, as `(sent, sender, message, kind, msgid)`. Only channel messages
+/// sent since messages were given ids have a `msgid`.
+pub type StoredMessage = (DateTime<Utc>, String, String, MessageKind, Option<String>);
+
+/// Tombstones a message sent to a channel so it's no longer replayed. If `account` is given,
+/// the message is only redacted if it was sent by that account.
+#[derive(Message)]
+#[rtype(result = "RedactChannelMessageResult")]
+pub struct RedactChannelMessage {
+    pub channel_id: ChannelId,
+    pub msgid: String,
+    pub account: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RedactChannelMessageResult {
+    Redacted,
+    /// The message was sent by someone other than `account`.
+    Forbidden,
+    /// The message doesn't exist, has already been redacted or was never persisted.
+    UnknownMessage,
+}
+
+/// Stores a `+draft/react` reaction to a message previously sent to a channel, so it can be
+/// replayed alongside the message.
+#[derive(Message)]
+#[rtype(result = "ChannelReactionResult")]
+pub struct ChannelReaction {
+    pub channel_id: ChannelId,
+    /// The msgid of the message being reacted to
+    pub msgid: String,
+    pub user_id: UserId,
+    pub sender: String,
+    pub reaction: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelReactionResult {
+    /// The reaction was stored, or the user had already reacted to the message with it.
+    Stored,
+    /// The message doesn't exist, has been redacted or was never persisted.
+    UnknownMessage,
+    /// The message or the user has already reached the cap on reactions to the message.
+    TooManyReactions,
 }
 
+/// A reaction as it was stored, as `(sent, sender, msgid, reaction)` where `msgid` is the
+/// message being reacted to.
+pub type StoredReaction = (DateTime<Utc>, String, String, String);
+
+/// Fetches the reactions to the given messages sent to a channel, in the order they were made.
 #[derive(Message)]
-#[rtype(result = "Vec<(DateTime<Utc>, String, String, MessageKind)>")]
+#[rtype(result = "Vec<StoredReaction>")]
+pub struct FetchChannelReactions {
+    pub channel: String,
+    pub msgids: Vec<String>,
+}
+
+/// A private message fetched for `CHATHISTORY`, along with whether it was sent by the user
+/// requesting the history (rather than to them).
+pub type StoredPrivateMessage = (StoredMessage, bool);
+
+/// The messages to fetch for `CHATHISTORY`, as selected by the client.
+///
+/// Timestamps are only given to clients to the millisecond, so a timestamp given by a client
+/// is taken to cover the entire millisecond. Otherwise, asking for the messages after the
+/// last message the client has seen would return that same message again.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HistoryRange {
+    /// The most recent messages sent before the timestamp
+    Before(DateTime<Utc>),
+    /// The earliest messages sent after the timestamp
+    After(DateTime<Utc>),
+    /// The most recent messages, only including those sent after the timestamp if one is given
+    Latest(Option<DateTime<Utc>>),
+    /// The messages sent between the two timestamps, starting from the first timestamp, which
+    /// may be later than the second
+    Between(DateTime<Utc>, DateTime<Utc>),
+}
+
+impl HistoryRange {
+    /// Returns the exclusive lower and upper bounds of the range as nanosecond timestamps, and
+    /// whether the messages closest to the upper bound should be picked first.
+    #[must_use]
+    pub fn bounds(self) -> (i64, i64, bool) {
+        let start = |v: DateTime<Utc>| nanos(v).saturating_add(999_999);
+        let end = nanos;
+
+        match self {
+            Self::Before(v) => (i64::MIN, end(v), true),
+            Self::After(v) => (start(v), i64::MAX, false),
+            Self::Latest(v) => (v.map_or(i64::MIN, start), i64::MAX, true),
+            Self::Between(from, to) if from <= to => (start(from), end(to), false),
+            Self::Between(from, to) => (start(to), end(from), true),
+        }
+    }
+}
+
+fn nanos(v: DateTime<Utc>) -> i64 {
+    v.timestamp_nanos_opt().unwrap_or(i64::MAX)
+}
+
+/// Fetches up to `limit` messages sent to a channel for `CHATHISTORY`, in the order they were
+/// sent.
+#[derive(Message)]
+#[rtype(result = "Vec<StoredMessage>")]
+pub struct FetchChannelHistory {
+    pub channel: String,
+    pub range: HistoryRange,
+    pub limit: usize,
+}
+
+/// Fetches up to `limit` private messages exchanged between two users for `CHATHISTORY`, in
+/// the order they were sent.
+#[derive(Message)]
+#[rtype(result = "Vec<StoredPrivateMessage>")]
+pub struct FetchPrivateHistory {
+    pub user_id: UserId,
+    pub other_user_id: UserId,
+    pub range: HistoryRange,
+    pub limit: usize,
+}
+
+#[derive(Message)]
+#[rtype(result = "Vec<StoredMessage>")]
 pub struct FetchUnseenPrivateMessages {
     pub user_id: UserId,
     pub span: Span,
 }
 
 #[derive(Message)]
-#[rtype(result = "Vec<(DateTime<Utc>, String, String, MessageKind)>")]
+#[rtype(result = "ChannelMessageReplay")]
 pub struct FetchUnseenChannelMessages {
-    pub channel_name: String,
+    pub channel_id: ChannelId,
     pub user_id: UserId,
     pub span: Span,
 }
 
+/// Messages to replay to a user upon joining a channel.
+pub struct ChannelMessageReplay {
+    /// The most recent unseen messages, in the order they were sent
+    pub messages: Vec<StoredMessage>,
+    /// Reactions to the replayed messages, in the order they were made
+    pub reactions: Vec<StoredReaction>,
+    /// Amount of older unseen messages that were omitted due to the replay cap
+    pub omitted: i64,
+}
+
 #[derive(Message)]
 #[rtype(result = "bool")]
 pub struct ReserveNick {
@@ -100,10 +476,81 @@ pub struct ReserveNick {
     pub nick: String,
 }
 
+/// Fetches every nick used by the account owning the given nick, most recently used first.
+#[derive(Message)]
+#[rtype(result = "Vec<NickHistoryEntry>")]
+pub struct FetchNickHistory {
+    pub nick: String,
+}
+
+#[derive(FromRow)]
+pub struct NickHistoryEntry {
+    pub nick: String,
+    pub username: String,
+    // timestamps in nanos. todo: sqlx datetime<utc>
+    pub first_used_timestamp: i64,
+    pub last_used_timestamp: i64,
+}
+
+/// Records a nick a user has stopped using, whether by changing nick or disconnecting.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct RecordWhowas {
+    pub nick: String,
+    pub username: String,
+    pub host: String,
+    pub realname: String,
+}
+
+/// Fetches up to `count` users that previously used the given nick within the retention
+/// window, most recent first.
+#[derive(Message)]
+#[rtype(result = "Vec<WhowasEntry>")]
+pub struct FetchWhowas {
+    pub nick: String,
+    pub count: usize,
+}
+
+#[derive(FromRow)]
+pub struct WhowasEntry {
+    pub nick: String,
+    pub username: String,
+    pub host: String,
+    pub realname: String,
+    // timestamp in nanos. todo: sqlx datetime<utc>
+    pub seen_timestamp: i64,
+}
+
+/// Records a connection made to an account, for the account's login history.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct RecordLogin {
+    pub user_id: UserId,
+    pub session: String,
+    pub ip: String,
+    pub host: Option<String>,
+}
+
+/// Fetches the most recent connections made to an account, most recent first.
+#[derive(Message)]
+#[rtype(result = "Vec<LoginHistoryEntry>")]
+pub struct FetchLoginHistory {
+    pub user_id: UserId,
+}
+
+#[derive(FromRow)]
+pub struct LoginHistoryEntry {
+    pub session: String,
+    pub ip: String,
+    pub host: Option<String>,
+    // timestamp in nanos. todo: sqlx datetime<utc>
+    pub timestamp: i64,
+}
+
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct ServerBan {
-    pub mask: HostMask<'static>,
+    pub mask: BanMask,
     pub requester: UserId,
     pub reason: String,
     pub created: DateTime<Utc>,
@@ -113,17 +560,37 @@ pub struct ServerBan {
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct ServerRemoveBan {
-    pub mask: HostMask<'static>,
+    pub mask: BanMask,
 }
 
 #[derive(Message)]
 #[rtype(result = "Vec<ServerListBanEntry>")]
 pub struct ServerListBan;
 
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct ServerShun {
+    pub mask: HostMask<'static>,
+    pub requester: UserId,
+    pub reason: String,
+    pub created: DateTime<Utc>,
+    pub expires: Option<DateTime<Utc>>,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct ServerRemoveShun {
+    pub mask: HostMask<'static>,
+}
+
+#[derive(Message)]
+#[rtype(result = "Vec<ServerListBanEntry>")]
+pub struct ServerListShun;
+
 #[derive(Message, FromRow)]
 #[rtype(result = "()")]
 pub struct ServerListBanEntry {
-    pub mask: HostMask<'static>,
+    pub mask: BanMask,
     pub requester: String,
     pub reason: String,
     // timestamp in nanos. todo: sqlx datetime<utc>
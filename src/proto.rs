@@ -1,17 +1,127 @@
+pub mod builder;
+
 use std::{convert::identity, str::FromStr, time::Duration};
 
-use irc_proto::{Command, Message, Prefix, Response};
+use chrono::{DateTime, Utc};
+use irc_proto::{ChannelExt, Message, Response};
 use thiserror::Error;
 
-use crate::{host_mask::HostMask, server::response::IntoProtocol, SERVER_NAME};
+use crate::{
+    channel::{metadata::ChannelMetadataKey, permissions::Permission},
+    host_mask::{BanMask, HostMask},
+    messages::MessageKind,
+    persistence::events::HistoryRange,
+    proto::builder::MessageBuilder,
+    server::response::IntoProtocol,
+    services::AccessEntry,
+    SERVER_NAME,
+};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum LocalCommand {
     ListGline,
-    /// Unbans a hostmask
-    RemoveGline(HostMask<'static>),
-    /// Bans a hostmask from the network for the given duration with the given message
-    Gline(HostMask<'static>, Option<Duration>, Option<String>),
+    /// Unbans a hostmask or account
+    RemoveGline(BanMask),
+    /// Bans a hostmask or account (given as `$a:account`) from the network for the given
+    /// duration with the given message
+    Gline(BanMask, Option<Duration>, Option<String>),
+    ListShun,
+    /// Lifts a shun from a hostmask
+    RemoveShun(HostMask<'static>),
+    /// Shuns a hostmask for the given duration, shunned users stay connected but have all of
+    /// their commands ignored
+    Shun(HostMask<'static>, Option<Duration>, Option<String>),
+    /// Sends a message to a user via a channel the sender has operator privileges in, bypassing
+    /// the usual target limits (`CPRIVMSG`/`CNOTICE`)
+    ChannelDirectMessage(MessageKind, String, String, String),
+    /// Adds (or, if prefixed with `-`, removes) nicks to the user's caller-id accept list, or
+    /// lists the current entries if given `*`
+    Accept(Vec<String>),
+    /// Lists every nick used by the account owning the given nick
+    NickHistory(String),
+    /// Bans a user's host from a channel and kicks them in one step (`KICKBAN`/`REMOVE`)
+    KickBan(String, String, Option<String>),
+    /// Sends a message consisting only of tags to a user or channel (`TAGMSG`)
+    TagMsg(String),
+    /// Starts (or, if given `OFF`, stops) recording the raw traffic of the given user
+    /// (`DEBUG TAP <nick> [ON|OFF]`)
+    DebugTap(String, bool),
+    /// Starts (or, if given `OFF`, stops) observing a channel's traffic without joining it
+    /// (`SPY <channel> [ON|OFF]`)
+    Spy(String, bool),
+    /// Manages the TLS client certificates that can be used to authenticate as the user's
+    /// account via SASL `EXTERNAL`
+    Cert(CertCommand),
+    /// Fetches up to the given amount of messages previously sent to a channel, or exchanged
+    /// with a user (`CHATHISTORY`)
+    ChatHistory(String, HistoryRange, usize),
+    /// Lists the daily statistics recorded for the given amount of days (`DAILYSTATS [days]`)
+    DailyStats(Option<usize>),
+    /// Deletes a message previously sent to a channel, referring to it by its `msgid`
+    /// (`REDACT <target> <msgid> [reason]`)
+    Redact(String, String, Option<String>),
+    /// Manages the registration and persistent access list of a channel (`CS`/`CHANSERV`)
+    ChanServ(ChanServCommand),
+    /// Manages the user's account and the sessions logged into it (`NS`/`NICKSERV`)
+    NickServ(NickServCommand),
+}
+
+/// The `NS` subcommands.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NickServCommand {
+    /// Sets a password on an account that was created without one
+    Register(String),
+    /// Logs into an account, which is the user's nick if no account is given. Only accepted
+    /// before registration, as an alternative to SASL
+    Identify(Option<String>, String),
+    /// Deletes the account, once the user has confirmed its password
+    Drop(String),
+    /// Changes the account's password
+    SetPassword(String),
+    /// Lists the connections currently logged into the account, along with its recent logins
+    Sessions,
+    /// Disconnects one of the account's sessions, identified by the ID given by `SESSIONS`
+    Logout(String),
+}
+
+/// The `CS` subcommands, each of them operating on the given channel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChanServCommand {
+    /// Claims founder status on a channel that doesn't currently have a founder
+    Register(String),
+    /// Hands the channel over to another account (`CS TRANSFER <channel> <account>`)
+    Transfer(String, String),
+    /// Designates the account that takes over the channel once the founder's account is
+    /// dropped, or clears it if no account is given (`CS SET SUCCESSOR <channel> [account]`)
+    SetSuccessor(String, Option<String>),
+    /// Sets a piece of the channel's metadata, or clears it if no value is given
+    /// (`CS SET LANGUAGE|CATEGORY|WEBSITE <channel> [value]`)
+    SetMetadata(String, ChannelMetadataKey, Option<String>),
+    /// Grants a mask a permission on the channel (`CS ACCESS <channel> ADD <mask> <level>`), a
+    /// bare account name is taken to mean the account's mask
+    AccessAdd(String, HostMask<'static>, Permission),
+    /// Removes a mask from the channel's access list (`CS ACCESS <channel> DEL <mask>`)
+    AccessDel(String, HostMask<'static>),
+    /// Lists the permissions granted on the channel (`CS ACCESS <channel> LIST`)
+    AccessList(String),
+    /// Writes out the channel's access list as a JSON document, for backing it up or copying it
+    /// to another channel (`CS ACCESS <channel> EXPORT`)
+    AccessExport(String),
+    /// Merges the entries of a document written by `EXPORT` into the channel's access list
+    /// (`CS ACCESS <channel> IMPORT <document>`)
+    AccessImport(String, Vec<(HostMask<'static>, Permission)>),
+}
+
+/// The `CERT` subcommands, fingerprints are hex-encoded SHA-256 hashes of the certificate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CertCommand {
+    /// Adds the given fingerprint to the account, or the fingerprint of the certificate the
+    /// user is currently connected with if none is given
+    Add(Option<String>),
+    /// Removes a fingerprint from the account
+    Del(String),
+    /// Lists the fingerprints added to the account
+    List,
 }
 
 impl TryFrom<(String, Vec<String>)> for LocalCommand {
@@ -23,15 +133,228 @@ impl TryFrom<(String, Vec<String>)> for LocalCommand {
             "GLINE" if args.len() == 1 && args[0].starts_with('-') => parse1(
                 Self::RemoveGline,
                 args,
-                required(truncate_first_character(parse_host_mask)),
+                required(truncate_first_character(parse_ban_mask)),
             ),
             "GLINE" => parse3(
                 Self::Gline,
                 args,
+                required(parse_ban_mask),
+                opt(parse_duration),
+                opt(wrap_ok(identity)),
+            ),
+            "SHUN" if args.is_empty() => Ok(Self::ListShun),
+            "SHUN" if args.len() == 1 && args[0].starts_with('-') => parse1(
+                Self::RemoveShun,
+                args,
+                required(truncate_first_character(parse_host_mask)),
+            ),
+            "SHUN" => parse3(
+                Self::Shun,
+                args,
                 required(parse_host_mask),
                 opt(parse_duration),
                 opt(wrap_ok(identity)),
             ),
+            "ACCEPT" if args.is_empty() => Ok(Self::Accept(vec!["*".to_string()])),
+            "ACCEPT" => parse1(Self::Accept, args, required(wrap_ok(parse_list))),
+            "NICKHISTORY" => parse1(Self::NickHistory, args, required(wrap_ok(identity))),
+            "REDACT" => parse3(
+                Self::Redact,
+                args,
+                required(wrap_ok(identity)),
+                required(wrap_ok(identity)),
+                opt(wrap_ok(identity)),
+            ),
+            "DAILYSTATS" => parse1(Self::DailyStats, args, opt(parse_positive_count)),
+            "KICKBAN" | "REMOVE" => parse3(
+                Self::KickBan,
+                args,
+                required(wrap_ok(identity)),
+                required(wrap_ok(identity)),
+                opt(wrap_ok(identity)),
+            ),
+            "DEBUG" if is_subcommand(&args, "TAP") => parse2(
+                Self::DebugTap,
+                args.into_iter().skip(1).collect(),
+                required(wrap_ok(identity)),
+                parse_toggle,
+            ),
+            "SPY" => parse2(Self::Spy, args, required(wrap_ok(identity)), parse_toggle),
+            "CERT" if is_subcommand(&args, "ADD") => parse1(
+                |v| Self::Cert(CertCommand::Add(v)),
+                args.into_iter().skip(1).collect(),
+                opt(parse_fingerprint),
+            ),
+            "CERT" if is_subcommand(&args, "DEL") => parse1(
+                |v| Self::Cert(CertCommand::Del(v)),
+                args.into_iter().skip(1).collect(),
+                required(parse_fingerprint),
+            ),
+            "CERT" if is_subcommand(&args, "LIST") && args.len() == 1 => {
+                Ok(Self::Cert(CertCommand::List))
+            }
+            "CS" if is_subcommand(&args, "REGISTER") => parse1(
+                |v| Self::ChanServ(ChanServCommand::Register(v)),
+                args.into_iter().skip(1).collect(),
+                required(parse_channel_name),
+            ),
+            "CS" if is_subcommand(&args, "TRANSFER") => parse2(
+                |channel, v| Self::ChanServ(ChanServCommand::Transfer(channel, v)),
+                args.into_iter().skip(1).collect(),
+                required(parse_channel_name),
+                required(wrap_ok(identity)),
+            ),
+            "CS" if is_subcommand(&args, "SET")
+                && args
+                    .get(1)
+                    .is_some_and(|v| v.eq_ignore_ascii_case("SUCCESSOR")) =>
+            {
+                parse2(
+                    |channel, v| Self::ChanServ(ChanServCommand::SetSuccessor(channel, v)),
+                    args.into_iter().skip(2).collect(),
+                    required(parse_channel_name),
+                    opt(wrap_ok(identity)),
+                )
+            }
+            "CS" if is_subcommand(&args, "SET")
+                && args
+                    .get(1)
+                    .is_some_and(|v| v.parse::<ChannelMetadataKey>().is_ok()) =>
+            {
+                parse3(
+                    |key, channel, v| Self::ChanServ(ChanServCommand::SetMetadata(channel, key, v)),
+                    args.into_iter().skip(1).collect(),
+                    required(parse_metadata_key),
+                    required(parse_channel_name),
+                    opt(wrap_ok(identity)),
+                )
+                .and_then(validate_metadata)
+            }
+            "CS" if is_access_subcommand(&args, "ADD") => parse3(
+                |channel, mask, v| Self::ChanServ(ChanServCommand::AccessAdd(channel, mask, v)),
+                access_arguments(args),
+                required(parse_channel_name),
+                required(parse_access_mask),
+                required(parse_access_level),
+            ),
+            "CS" if is_access_subcommand(&args, "DEL") => parse2(
+                |channel, v| Self::ChanServ(ChanServCommand::AccessDel(channel, v)),
+                access_arguments(args),
+                required(parse_channel_name),
+                required(parse_access_mask),
+            ),
+            "CS" if is_access_subcommand(&args, "LIST") => parse1(
+                |v| Self::ChanServ(ChanServCommand::AccessList(v)),
+                access_arguments(args),
+                required(parse_channel_name),
+            ),
+            "CS" if is_access_subcommand(&args, "EXPORT") => parse1(
+                |v| Self::ChanServ(ChanServCommand::AccessExport(v)),
+                access_arguments(args),
+                required(parse_channel_name),
+            ),
+            "CS" if is_access_subcommand(&args, "IMPORT") => parse2(
+                |channel, v| Self::ChanServ(ChanServCommand::AccessImport(channel, v)),
+                access_arguments(args),
+                required(parse_channel_name),
+                required(parse_access_document),
+            ),
+            "NS" if is_subcommand(&args, "REGISTER") => parse1(
+                |v| Self::NickServ(NickServCommand::Register(v)),
+                args.into_iter().skip(1).collect(),
+                required(wrap_ok(identity)),
+            ),
+            "NS" if is_subcommand(&args, "IDENTIFY") && args.len() == 2 => parse1(
+                |v| Self::NickServ(NickServCommand::Identify(None, v)),
+                args.into_iter().skip(1).collect(),
+                required(wrap_ok(identity)),
+            ),
+            "NS" if is_subcommand(&args, "IDENTIFY") => parse2(
+                |account, v| Self::NickServ(NickServCommand::Identify(Some(account), v)),
+                args.into_iter().skip(1).collect(),
+                required(wrap_ok(identity)),
+                required(wrap_ok(identity)),
+            ),
+            "NS" if is_subcommand(&args, "DROP") => parse1(
+                |v| Self::NickServ(NickServCommand::Drop(v)),
+                args.into_iter().skip(1).collect(),
+                required(wrap_ok(identity)),
+            ),
+            "NS" if is_subcommand(&args, "SET")
+                && args
+                    .get(1)
+                    .is_some_and(|v| v.eq_ignore_ascii_case("PASSWORD")) =>
+            {
+                parse1(
+                    |v| Self::NickServ(NickServCommand::SetPassword(v)),
+                    args.into_iter().skip(2).collect(),
+                    required(wrap_ok(identity)),
+                )
+            }
+            "NS" if is_subcommand(&args, "SESSIONS") && args.len() == 1 => {
+                Ok(Self::NickServ(NickServCommand::Sessions))
+            }
+            "NS" if is_subcommand(&args, "LOGOUT") => parse1(
+                |v| Self::NickServ(NickServCommand::Logout(v)),
+                args.into_iter().skip(1).collect(),
+                required(wrap_ok(identity)),
+            ),
+            "CHATHISTORY" if is_subcommand(&args, "BEFORE") => parse3(
+                |target, v, limit| Self::ChatHistory(target, HistoryRange::Before(v), limit),
+                args.into_iter().skip(1).collect(),
+                required(wrap_ok(identity)),
+                required(parse_timestamp_selector),
+                required(parse_positive_count),
+            ),
+            "CHATHISTORY" if is_subcommand(&args, "AFTER") => parse3(
+                |target, v, limit| Self::ChatHistory(target, HistoryRange::After(v), limit),
+                args.into_iter().skip(1).collect(),
+                required(wrap_ok(identity)),
+                required(parse_timestamp_selector),
+                required(parse_positive_count),
+            ),
+            "CHATHISTORY" if is_subcommand(&args, "LATEST") => parse3(
+                |target, v, limit| Self::ChatHistory(target, HistoryRange::Latest(v), limit),
+                args.into_iter().skip(1).collect(),
+                required(wrap_ok(identity)),
+                required(|v| {
+                    if v == "*" {
+                        Ok(None)
+                    } else {
+                        parse_timestamp_selector(v).map(Some)
+                    }
+                }),
+                required(parse_positive_count),
+            ),
+            "CHATHISTORY" if is_subcommand(&args, "BETWEEN") => parse4(
+                |target, from, to, limit| {
+                    Self::ChatHistory(target, HistoryRange::Between(from, to), limit)
+                },
+                args.into_iter().skip(1).collect(),
+                required(wrap_ok(identity)),
+                required(parse_timestamp_selector),
+                required(parse_timestamp_selector),
+                required(parse_positive_count),
+            ),
+            "TAGMSG" => parse1(Self::TagMsg, args, required(wrap_ok(identity))),
+            "CPRIVMSG" => parse3(
+                |nick, channel, message| {
+                    Self::ChannelDirectMessage(MessageKind::Normal, nick, channel, message)
+                },
+                args,
+                required(wrap_ok(identity)),
+                required(wrap_ok(identity)),
+                required(wrap_ok(identity)),
+            ),
+            "CNOTICE" => parse3(
+                |nick, channel, message| {
+                    Self::ChannelDirectMessage(MessageKind::Notice, nick, channel, message)
+                },
+                args,
+                required(wrap_ok(identity)),
+                required(wrap_ok(identity)),
+                required(wrap_ok(identity)),
+            ),
             _ => Err(Error::UnknownCommand),
         }
     }
@@ -49,22 +372,85 @@ pub enum Error {
     InvalidHostMask(std::io::Error),
     #[error("too many arguments")]
     TooManyArguments,
+    #[error("invalid argument: {0}")]
+    InvalidArgument(String),
 }
 
 impl IntoProtocol for Error {
     fn into_messages(self, for_user: &str) -> Vec<Message> {
-        vec![Message {
-            tags: None,
-            prefix: Some(Prefix::ServerName(SERVER_NAME.to_string())),
-            command: Command::Response(
-                Response::ERR_UNKNOWNCOMMAND,
-                vec![
-                    for_user.to_string(),
-                    "command".to_string(), // TODO
-                    "Unknown command".to_string(),
-                ],
-            ),
-        }]
+        vec![MessageBuilder::server().response(
+            Response::ERR_UNKNOWNCOMMAND,
+            vec![
+                for_user.to_string(),
+                "command".to_string(), // TODO
+                "Unknown command".to_string(),
+            ],
+        )]
+    }
+}
+
+/// The recipient of a `PRIVMSG` or `NOTICE`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MessageTarget {
+    /// A user, which may have been addressed as `nick@server`.
+    User(String),
+    /// A user addressed as `nick@server` on another server, only the clients of linked services
+    /// can be reached this way.
+    Remote { nick: String, server: String },
+    /// A channel, if addressed with a status prefix (ie. `@#channel`) the message is only
+    /// delivered to members with at least that permission.
+    Channel {
+        name: String,
+        status: Option<Permission>,
+    },
+}
+
+impl MessageTarget {
+    /// Parses a message target as sent by a client.
+    #[must_use]
+    pub fn parse(target: &str) -> Self {
+        let mut chars = target.chars();
+        if let Some(status) = chars.next().and_then(Permission::from_prefix) {
+            let name = chars.as_str();
+
+            if name.is_channel_name() {
+                return Self::Channel {
+                    name: name.to_string(),
+                    status: Some(status),
+                };
+            }
+        }
+
+        if target.is_channel_name() {
+            return Self::Channel {
+                name: target.to_string(),
+                status: None,
+            };
+        }
+
+        match target.split_once('@') {
+            Some((nick, server)) if server.eq_ignore_ascii_case(SERVER_NAME) => {
+                Self::User(nick.to_string())
+            }
+            Some((nick, server)) => Self::Remote {
+                nick: nick.to_string(),
+                server: server.to_string(),
+            },
+            None => Self::User(target.to_string()),
+        }
+    }
+}
+
+/// Returned to the user when they attempt to address a message to a server that doesn't exist.
+#[derive(Debug)]
+pub struct NoSuchServer(pub String);
+
+impl IntoProtocol for NoSuchServer {
+    fn into_messages(self, for_user: &str) -> Vec<Message> {
+        vec![MessageBuilder::server().response(
+            Response::ERR_NOSUCHSERVER,
+            vec![for_user.to_string(), self.0, "No such server".to_string()],
+        )]
     }
 }
 
@@ -96,12 +482,150 @@ fn parse_host_mask(v: String) -> Result<HostMask<'static>, Error> {
     HostMask::from_str(&v).map_err(Error::InvalidHostMask)
 }
 
+/// Parses a host mask or account ban argument
+#[allow(clippy::needless_pass_by_value)]
+fn parse_ban_mask(v: String) -> Result<BanMask, Error> {
+    BanMask::from_str(&v).map_err(Error::InvalidHostMask)
+}
+
 /// Parses a humantime duration
 #[allow(clippy::needless_pass_by_value)]
 fn parse_duration(v: String) -> Result<Duration, Error> {
     humantime::parse_duration(&v).map_err(Error::InvalidDuration)
 }
 
+/// Parses a comma-separated list argument
+#[allow(clippy::needless_pass_by_value)]
+fn parse_list(v: String) -> Vec<String> {
+    v.split(',')
+        .filter(|v| !v.is_empty())
+        .map(ToString::to_string)
+        .collect()
+}
+
+/// Whether the first argument is the given subcommand, for commands like `DEBUG TAP`.
+fn is_subcommand(args: &[String], name: &str) -> bool {
+    args.first().is_some_and(|v| v.eq_ignore_ascii_case(name))
+}
+
+/// Checks for `CS ACCESS <channel> <name>`, where the subcommand comes after the channel.
+fn is_access_subcommand(args: &[String], name: &str) -> bool {
+    is_subcommand(args, "ACCESS") && args.get(2).is_some_and(|v| v.eq_ignore_ascii_case(name))
+}
+
+/// Drops `ACCESS` and its subcommand, leaving the channel followed by the subcommand's
+/// arguments.
+fn access_arguments(args: Vec<String>) -> Vec<String> {
+    args.into_iter()
+        .enumerate()
+        .filter(|(i, _)| *i != 0 && *i != 2)
+        .map(|(_, v)| v)
+        .collect()
+}
+
+/// Parses a host mask, or an account name which is taken to mean the account's mask
+#[allow(clippy::needless_pass_by_value)]
+fn parse_access_mask(v: String) -> Result<HostMask<'static>, Error> {
+    if v.contains(['!', '@']) {
+        parse_host_mask(v)
+    } else {
+        Ok(HostMask::new("*", &v, "*").into_owned())
+    }
+}
+
+/// Parses the level granted to an entry on a channel's access list, founder status can only be
+/// handed over using `CS TRANSFER`.
+fn parse_access_level(v: String) -> Result<Permission, Error> {
+    match v.to_ascii_uppercase().as_str() {
+        "VOICE" => Ok(Permission::Voice),
+        "HALFOP" => Ok(Permission::HalfOperator),
+        "OP" => Ok(Permission::Operator),
+        "BAN" => Ok(Permission::Ban),
+        _ => Err(Error::InvalidArgument(v)),
+    }
+}
+
+/// Parses the JSON document given to `CS ACCESS IMPORT`, as written by `CS ACCESS EXPORT`.
+#[allow(clippy::needless_pass_by_value)]
+fn parse_access_document(v: String) -> Result<Vec<(HostMask<'static>, Permission)>, Error> {
+    let entries: Vec<AccessEntry> =
+        serde_json::from_str(&v).map_err(|e| Error::InvalidArgument(e.to_string()))?;
+
+    entries
+        .into_iter()
+        .map(|entry| {
+            Ok((
+                parse_access_mask(entry.mask)?,
+                parse_access_level(entry.level)?,
+            ))
+        })
+        .collect()
+}
+
+fn parse_metadata_key(v: String) -> Result<ChannelMetadataKey, Error> {
+    v.parse().map_err(|()| Error::InvalidArgument(v))
+}
+
+/// Checks the value given to `CS SET` is valid for the metadata being set.
+fn validate_metadata(command: LocalCommand) -> Result<LocalCommand, Error> {
+    if let LocalCommand::ChanServ(ChanServCommand::SetMetadata(_, key, Some(v))) = &command {
+        if !key.is_valid(v) {
+            return Err(Error::InvalidArgument(v.to_string()));
+        }
+    }
+
+    Ok(command)
+}
+
+fn parse_channel_name(v: String) -> Result<String, Error> {
+    if v.is_channel_name() {
+        Ok(v)
+    } else {
+        Err(Error::InvalidArgument(v))
+    }
+}
+
+/// Parses a SHA-256 certificate fingerprint, accepting the colon-separated form most tools
+/// print fingerprints in.
+fn parse_fingerprint(v: String) -> Result<String, Error> {
+    let fingerprint = v.replace(':', "").to_ascii_lowercase();
+
+    if fingerprint.len() == 64 && fingerprint.bytes().all(|c| c.is_ascii_hexdigit()) {
+        Ok(fingerprint)
+    } else {
+        Err(Error::InvalidArgument(v))
+    }
+}
+
+/// Parses a `CHATHISTORY` message selector, only `timestamp=` selectors are supported since
+/// messages aren't given IDs.
+fn parse_timestamp_selector(v: String) -> Result<DateTime<Utc>, Error> {
+    v.strip_prefix("timestamp=")
+        .and_then(|v| DateTime::parse_from_rfc3339(v).ok())
+        .map(|v| v.with_timezone(&Utc))
+        .ok_or(Error::InvalidArgument(v))
+}
+
+/// Parses a count that has to be at least one, such as the maximum amount of messages to return
+/// for `CHATHISTORY`
+fn parse_positive_count(v: String) -> Result<usize, Error> {
+    match v.parse() {
+        Ok(limit) if limit > 0 => Ok(limit),
+        _ => Err(Error::InvalidArgument(v)),
+    }
+}
+
+/// Parses an optional `ON`/`OFF` argument, defaulting to on
+#[allow(clippy::needless_pass_by_value)]
+fn parse_toggle(v: Option<String>) -> Result<bool, Error> {
+    match v {
+        None => Ok(true),
+        Some(v) if v.eq_ignore_ascii_case("ON") => Ok(true),
+        Some(v) if v.eq_ignore_ascii_case("OFF") => Ok(false),
+        Some(v) => Err(Error::InvalidArgument(v)),
+    }
+}
+
 /// Takes a string argument as-is
 fn wrap_ok<T>(transform: fn(String) -> T) -> impl Fn(String) -> Result<T, Error> {
     move |v| Ok((transform)(v))
@@ -122,6 +646,22 @@ fn parse1<T1>(
     Ok((out)(t1(i.next())?))
 }
 
+/// Parses two arguments from `args`, transforming them using `t1` and `t2`
+/// and returns a `LocalCommand`.
+fn parse2<T1, T2>(
+    out: fn(T1, T2) -> LocalCommand,
+    args: Vec<String>,
+    t1: impl FnOnce(Option<String>) -> Result<T1, Error>,
+    t2: impl FnOnce(Option<String>) -> Result<T2, Error>,
+) -> Result<LocalCommand, Error> {
+    if args.len() > 2 {
+        return Err(Error::TooManyArguments);
+    }
+
+    let mut i = args.into_iter();
+    Ok((out)(t1(i.next())?, t2(i.next())?))
+}
+
 /// Parses three arguments from `args`, transforming them using `t1`, `t2` and `t3`
 /// and returns a `LocalCommand`.
 fn parse3<T1, T2, T3>(
@@ -139,11 +679,90 @@ fn parse3<T1, T2, T3>(
     Ok((out)(t1(i.next())?, t2(i.next())?, t3(i.next())?))
 }
 
+/// Parses four arguments from `args`, transforming them using `t1`, `t2`, `t3` and `t4`
+/// and returns a `LocalCommand`.
+fn parse4<T1, T2, T3, T4>(
+    out: fn(T1, T2, T3, T4) -> LocalCommand,
+    args: Vec<String>,
+    t1: impl FnOnce(Option<String>) -> Result<T1, Error>,
+    t2: impl FnOnce(Option<String>) -> Result<T2, Error>,
+    t3: impl FnOnce(Option<String>) -> Result<T3, Error>,
+    t4: impl FnOnce(Option<String>) -> Result<T4, Error>,
+) -> Result<LocalCommand, Error> {
+    if args.len() > 4 {
+        return Err(Error::TooManyArguments);
+    }
+
+    let mut i = args.into_iter();
+    Ok((out)(
+        t1(i.next())?,
+        t2(i.next())?,
+        t3(i.next())?,
+        t4(i.next())?,
+    ))
+}
+
 #[cfg(test)]
 mod test {
     use std::time::Duration;
 
-    use crate::proto::{Error, LocalCommand};
+    use chrono::{TimeZone, Utc};
+
+    use crate::{
+        channel::{metadata::ChannelMetadataKey, permissions::Permission},
+        host_mask::{BanMask, HostMask},
+        messages::MessageKind,
+        persistence::events::HistoryRange,
+        proto::{
+            CertCommand, ChanServCommand, Error, LocalCommand, MessageTarget, NickServCommand,
+        },
+        services::export_access,
+        SERVER_NAME,
+    };
+
+    #[test]
+    fn message_target() {
+        assert_eq!(
+            MessageTarget::parse("nick"),
+            MessageTarget::User("nick".to_string())
+        );
+        assert_eq!(
+            MessageTarget::parse(&format!("nick@{SERVER_NAME}")),
+            MessageTarget::User("nick".to_string())
+        );
+        assert_eq!(
+            MessageTarget::parse("nickserv@services."),
+            MessageTarget::Remote {
+                nick: "nickserv".to_string(),
+                server: "services.".to_string()
+            }
+        );
+        assert_eq!(
+            MessageTarget::parse("#channel"),
+            MessageTarget::Channel {
+                name: "#channel".to_string(),
+                status: None
+            }
+        );
+        assert_eq!(
+            MessageTarget::parse("@#channel"),
+            MessageTarget::Channel {
+                name: "#channel".to_string(),
+                status: Some(Permission::Operator)
+            }
+        );
+        assert_eq!(
+            MessageTarget::parse("+#channel"),
+            MessageTarget::Channel {
+                name: "#channel".to_string(),
+                status: Some(Permission::Voice)
+            }
+        );
+        assert_eq!(
+            MessageTarget::parse("+nick"),
+            MessageTarget::User("+nick".to_string())
+        );
+    }
 
     #[test]
     fn remove_gline() {
@@ -177,6 +796,523 @@ mod test {
         );
     }
 
+    #[test]
+    fn account_gline() {
+        let command = LocalCommand::try_from((
+            "GLINE".to_string(),
+            vec!["$a:someone".to_string(), "1h".to_string()],
+        ))
+        .unwrap();
+        assert_eq!(
+            command,
+            LocalCommand::Gline(
+                BanMask::Account("someone".to_string()),
+                Some(Duration::from_secs(3600)),
+                None
+            )
+        );
+
+        let command =
+            LocalCommand::try_from(("GLINE".to_string(), vec!["-$a:someone".to_string()])).unwrap();
+        assert_eq!(
+            command,
+            LocalCommand::RemoveGline(BanMask::Account("someone".to_string()))
+        );
+    }
+
+    #[test]
+    fn shun() {
+        let command = LocalCommand::try_from((
+            "SHUN".to_string(),
+            vec!["aaa!bbb@ccc".to_string(), "1d".to_string()],
+        ))
+        .unwrap();
+        assert_eq!(
+            command,
+            LocalCommand::Shun(
+                "aaa!bbb@ccc".try_into().unwrap(),
+                Some(Duration::from_secs(86_400)),
+                None
+            )
+        );
+
+        let command =
+            LocalCommand::try_from(("SHUN".to_string(), vec!["-aaa!bbb@ccc".to_string()])).unwrap();
+        assert_eq!(
+            command,
+            LocalCommand::RemoveShun("aaa!bbb@ccc".try_into().unwrap())
+        );
+    }
+
+    #[test]
+    fn accept() {
+        let command =
+            LocalCommand::try_from(("ACCEPT".to_string(), vec!["aaa,-bbb,".to_string()])).unwrap();
+        assert_eq!(
+            command,
+            LocalCommand::Accept(vec!["aaa".to_string(), "-bbb".to_string()])
+        );
+    }
+
+    #[test]
+    fn nick_history() {
+        let command =
+            LocalCommand::try_from(("NICKHISTORY".to_string(), vec!["aaa".to_string()])).unwrap();
+        assert_eq!(command, LocalCommand::NickHistory("aaa".to_string()));
+
+        assert!(LocalCommand::try_from(("NICKHISTORY".to_string(), vec![])).is_err());
+    }
+
+    #[test]
+    fn redact() {
+        let command = LocalCommand::try_from((
+            "REDACT".to_string(),
+            vec![
+                "#channel".to_string(),
+                "abc".to_string(),
+                "oops".to_string(),
+            ],
+        ))
+        .unwrap();
+        assert_eq!(
+            command,
+            LocalCommand::Redact(
+                "#channel".to_string(),
+                "abc".to_string(),
+                Some("oops".to_string())
+            )
+        );
+
+        assert!(
+            LocalCommand::try_from(("REDACT".to_string(), vec!["#channel".to_string()])).is_err()
+        );
+    }
+
+    #[test]
+    fn kickban() {
+        let command = LocalCommand::try_from((
+            "KICKBAN".to_string(),
+            vec!["#channel".to_string(), "nick".to_string()],
+        ))
+        .unwrap();
+        assert_eq!(
+            command,
+            LocalCommand::KickBan("#channel".to_string(), "nick".to_string(), None)
+        );
+
+        let command = LocalCommand::try_from((
+            "REMOVE".to_string(),
+            vec![
+                "#channel".to_string(),
+                "nick".to_string(),
+                "go away".to_string(),
+            ],
+        ))
+        .unwrap();
+        assert_eq!(
+            command,
+            LocalCommand::KickBan(
+                "#channel".to_string(),
+                "nick".to_string(),
+                Some("go away".to_string())
+            )
+        );
+    }
+
+    #[test]
+    fn cprivmsg() {
+        let command = LocalCommand::try_from((
+            "CPRIVMSG".to_string(),
+            vec![
+                "nick".to_string(),
+                "#channel".to_string(),
+                "hello world".to_string(),
+            ],
+        ))
+        .unwrap();
+        assert_eq!(
+            command,
+            LocalCommand::ChannelDirectMessage(
+                MessageKind::Normal,
+                "nick".to_string(),
+                "#channel".to_string(),
+                "hello world".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn cnotice_missing_argument() {
+        let command = LocalCommand::try_from((
+            "CNOTICE".to_string(),
+            vec!["nick".to_string(), "#channel".to_string()],
+        ));
+        assert!(
+            matches!(command, Err(Error::MissingArgument)),
+            "{command:?}"
+        );
+    }
+
+    #[test]
+    fn debug_tap() {
+        let command = LocalCommand::try_from((
+            "DEBUG".to_string(),
+            vec!["TAP".to_string(), "nick".to_string()],
+        ))
+        .unwrap();
+        assert_eq!(command, LocalCommand::DebugTap("nick".to_string(), true));
+
+        let command = LocalCommand::try_from((
+            "DEBUG".to_string(),
+            vec!["tap".to_string(), "nick".to_string(), "off".to_string()],
+        ))
+        .unwrap();
+        assert_eq!(command, LocalCommand::DebugTap("nick".to_string(), false));
+
+        let command = LocalCommand::try_from((
+            "DEBUG".to_string(),
+            vec!["TAP".to_string(), "nick".to_string(), "maybe".to_string()],
+        ));
+        assert!(
+            matches!(command, Err(Error::InvalidArgument(_))),
+            "{command:?}"
+        );
+
+        let command = LocalCommand::try_from(("DEBUG".to_string(), vec!["OTHER".to_string()]));
+        assert!(matches!(command, Err(Error::UnknownCommand)), "{command:?}");
+    }
+
+    #[test]
+    fn spy() {
+        let command =
+            LocalCommand::try_from(("SPY".to_string(), vec!["#channel".to_string()])).unwrap();
+        assert_eq!(command, LocalCommand::Spy("#channel".to_string(), true));
+
+        let command = LocalCommand::try_from((
+            "SPY".to_string(),
+            vec!["#channel".to_string(), "OFF".to_string()],
+        ))
+        .unwrap();
+        assert_eq!(command, LocalCommand::Spy("#channel".to_string(), false));
+
+        let command = LocalCommand::try_from(("SPY".to_string(), vec![]));
+        assert!(
+            matches!(command, Err(Error::MissingArgument)),
+            "{command:?}"
+        );
+    }
+
+    #[test]
+    fn cert() {
+        let fingerprint = "AB:".repeat(31) + "AB";
+
+        let command =
+            LocalCommand::try_from(("CERT".to_string(), vec!["add".to_string(), fingerprint]))
+                .unwrap();
+        assert_eq!(
+            command,
+            LocalCommand::Cert(CertCommand::Add(Some("ab".repeat(32))))
+        );
+
+        let command =
+            LocalCommand::try_from(("CERT".to_string(), vec!["ADD".to_string()])).unwrap();
+        assert_eq!(command, LocalCommand::Cert(CertCommand::Add(None)));
+
+        let command =
+            LocalCommand::try_from(("CERT".to_string(), vec!["LIST".to_string()])).unwrap();
+        assert_eq!(command, LocalCommand::Cert(CertCommand::List));
+
+        let command = LocalCommand::try_from((
+            "CERT".to_string(),
+            vec!["DEL".to_string(), "not-a-fingerprint".to_string()],
+        ));
+        assert!(
+            matches!(command, Err(Error::InvalidArgument(_))),
+            "{command:?}"
+        );
+    }
+
+    #[test]
+    fn nickserv_sessions() {
+        let command =
+            LocalCommand::try_from(("NS".to_string(), vec!["sessions".to_string()])).unwrap();
+        assert_eq!(command, LocalCommand::NickServ(NickServCommand::Sessions));
+
+        let command = LocalCommand::try_from((
+            "NS".to_string(),
+            vec!["LOGOUT".to_string(), "0a1b2c3d".to_string()],
+        ))
+        .unwrap();
+        assert_eq!(
+            command,
+            LocalCommand::NickServ(NickServCommand::Logout("0a1b2c3d".to_string()))
+        );
+
+        let command = LocalCommand::try_from(("NS".to_string(), vec!["LOGOUT".to_string()]));
+        assert!(
+            matches!(command, Err(Error::MissingArgument)),
+            "{command:?}"
+        );
+    }
+
+    #[test]
+    fn nickserv_accounts() {
+        let parse = |args: &[&str]| {
+            LocalCommand::try_from((
+                "NS".to_string(),
+                args.iter().map(ToString::to_string).collect(),
+            ))
+        };
+
+        assert_eq!(
+            parse(&["IDENTIFY", "hunter2"]).unwrap(),
+            LocalCommand::NickServ(NickServCommand::Identify(None, "hunter2".to_string()))
+        );
+        assert_eq!(
+            parse(&["identify", "alice", "hunter2"]).unwrap(),
+            LocalCommand::NickServ(NickServCommand::Identify(
+                Some("alice".to_string()),
+                "hunter2".to_string()
+            ))
+        );
+        assert_eq!(
+            parse(&["SET", "PASSWORD", "hunter3"]).unwrap(),
+            LocalCommand::NickServ(NickServCommand::SetPassword("hunter3".to_string()))
+        );
+        assert!(matches!(parse(&["REGISTER"]), Err(Error::MissingArgument)));
+        assert!(matches!(
+            parse(&["DROP", "hunter2", "extra"]),
+            Err(Error::TooManyArguments)
+        ));
+    }
+
+    #[test]
+    fn channel_successor() {
+        let parse = |args: &[&str]| {
+            LocalCommand::try_from((
+                "CS".to_string(),
+                args.iter().map(ToString::to_string).collect(),
+            ))
+        };
+
+        assert_eq!(
+            parse(&["SET", "successor", "#channel", "bob"]).unwrap(),
+            LocalCommand::ChanServ(ChanServCommand::SetSuccessor(
+                "#channel".to_string(),
+                Some("bob".to_string())
+            ))
+        );
+        assert_eq!(
+            parse(&["set", "SUCCESSOR", "#channel"]).unwrap(),
+            LocalCommand::ChanServ(ChanServCommand::SetSuccessor("#channel".to_string(), None))
+        );
+        assert!(matches!(
+            parse(&["SET", "SUCCESSOR", "bob"]),
+            Err(Error::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            parse(&["SET", "TOPIC", "#channel"]),
+            Err(Error::UnknownCommand)
+        ));
+    }
+
+    #[test]
+    fn channel_metadata() {
+        let parse = |args: &[&str]| {
+            LocalCommand::try_from((
+                "CS".to_string(),
+                args.iter().map(ToString::to_string).collect(),
+            ))
+        };
+
+        assert_eq!(
+            parse(&["SET", "language", "#channel", "en"]).unwrap(),
+            LocalCommand::ChanServ(ChanServCommand::SetMetadata(
+                "#channel".to_string(),
+                ChannelMetadataKey::Language,
+                Some("en".to_string())
+            ))
+        );
+        assert_eq!(
+            parse(&["SET", "WEBSITE", "#channel"]).unwrap(),
+            LocalCommand::ChanServ(ChanServCommand::SetMetadata(
+                "#channel".to_string(),
+                ChannelMetadataKey::Website,
+                None
+            ))
+        );
+        assert!(matches!(
+            parse(&["SET", "WEBSITE", "#channel", "ftp://example.com"]),
+            Err(Error::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            parse(&["SET", "CATEGORY", "#channel", "a", "b"]),
+            Err(Error::TooManyArguments)
+        ));
+    }
+
+    #[test]
+    fn chanserv() {
+        let parse = |args: &[&str]| {
+            LocalCommand::try_from((
+                "CS".to_string(),
+                args.iter().map(ToString::to_string).collect(),
+            ))
+        };
+
+        assert_eq!(
+            parse(&["register", "#channel"]).unwrap(),
+            LocalCommand::ChanServ(ChanServCommand::Register("#channel".to_string()))
+        );
+        assert_eq!(
+            parse(&["TRANSFER", "#channel", "bob"]).unwrap(),
+            LocalCommand::ChanServ(ChanServCommand::Transfer(
+                "#channel".to_string(),
+                "bob".to_string()
+            ))
+        );
+        assert_eq!(
+            parse(&["ACCESS", "#channel", "add", "bob", "op"]).unwrap(),
+            LocalCommand::ChanServ(ChanServCommand::AccessAdd(
+                "#channel".to_string(),
+                HostMask::new("*", "bob", "*").into_owned(),
+                Permission::Operator
+            ))
+        );
+        assert_eq!(
+            parse(&["ACCESS", "#channel", "DEL", "*!*@spam.example"]).unwrap(),
+            LocalCommand::ChanServ(ChanServCommand::AccessDel(
+                "#channel".to_string(),
+                HostMask::new("*", "*", "spam.example").into_owned()
+            ))
+        );
+        assert_eq!(
+            parse(&["ACCESS", "#channel", "LIST"]).unwrap(),
+            LocalCommand::ChanServ(ChanServCommand::AccessList("#channel".to_string()))
+        );
+        assert!(matches!(
+            parse(&["ACCESS", "#channel", "ADD", "bob", "founder"]),
+            Err(Error::InvalidArgument(_))
+        ));
+        assert_eq!(
+            parse(&["ACCESS", "#channel", "EXPORT"]).unwrap(),
+            LocalCommand::ChanServ(ChanServCommand::AccessExport("#channel".to_string()))
+        );
+
+        // the founder is left out of exports, everyone else can be imported as they were
+        let access = vec![
+            (
+                HostMask::new("*", "alice", "*").into_owned(),
+                Permission::Founder,
+            ),
+            (
+                HostMask::new("*", "bob", "*").into_owned(),
+                Permission::Operator,
+            ),
+            (
+                HostMask::new("*", "*", "spam.example").into_owned(),
+                Permission::Ban,
+            ),
+        ];
+        let document = export_access(&access);
+        assert_eq!(
+            parse(&["ACCESS", "#other", "IMPORT", &document]).unwrap(),
+            LocalCommand::ChanServ(ChanServCommand::AccessImport(
+                "#other".to_string(),
+                access[1..].to_vec()
+            ))
+        );
+        assert!(matches!(
+            parse(&[
+                "ACCESS",
+                "#channel",
+                "IMPORT",
+                r#"[{"mask":"*!alice@*","level":"FOUNDER"}]"#
+            ]),
+            Err(Error::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            parse(&["ACCESS", "#channel", "IMPORT", "{"]),
+            Err(Error::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            parse(&["ACCESS", "#channel", "ADD", "bob"]),
+            Err(Error::MissingArgument)
+        ));
+    }
+
+    #[test]
+    fn chathistory() {
+        let parse = |args: &[&str]| {
+            LocalCommand::try_from((
+                "CHATHISTORY".to_string(),
+                args.iter().map(ToString::to_string).collect(),
+            ))
+        };
+
+        let time = Utc.with_ymd_and_hms(2024, 1, 1, 12, 0, 0).unwrap();
+
+        assert_eq!(
+            parse(&[
+                "BEFORE",
+                "#chan",
+                "timestamp=2024-01-01T12:00:00.000Z",
+                "50"
+            ])
+            .unwrap(),
+            LocalCommand::ChatHistory("#chan".to_string(), HistoryRange::Before(time), 50)
+        );
+        assert_eq!(
+            parse(&["latest", "nick", "*", "10"]).unwrap(),
+            LocalCommand::ChatHistory("nick".to_string(), HistoryRange::Latest(None), 10)
+        );
+        assert_eq!(
+            parse(&[
+                "BETWEEN",
+                "#chan",
+                "timestamp=2024-01-01T12:00:00.000Z",
+                "timestamp=2024-01-01T11:00:00.000Z",
+                "5"
+            ])
+            .unwrap(),
+            LocalCommand::ChatHistory(
+                "#chan".to_string(),
+                HistoryRange::Between(time, time - chrono::Duration::hours(1)),
+                5
+            )
+        );
+
+        let command = parse(&["AFTER", "#chan", "msgid=abc", "50"]);
+        assert!(
+            matches!(command, Err(Error::InvalidArgument(_))),
+            "{command:?}"
+        );
+
+        let command = parse(&["LATEST", "#chan", "*", "0"]);
+        assert!(
+            matches!(command, Err(Error::InvalidArgument(_))),
+            "{command:?}"
+        );
+
+        assert!(matches!(
+            parse(&["TARGETS", "*", "*", "50"]),
+            Err(Error::UnknownCommand)
+        ));
+    }
+
+    #[test]
+    fn tagmsg() {
+        let command =
+            LocalCommand::try_from(("TAGMSG".to_string(), vec!["#channel".to_string()])).unwrap();
+        assert_eq!(command, LocalCommand::TagMsg("#channel".to_string()));
+
+        let command = LocalCommand::try_from(("TAGMSG".to_string(), vec![]));
+        assert!(
+            matches!(command, Err(Error::MissingArgument)),
+            "{command:?}"
+        );
+    }
+
     #[test]
     fn too_many_arguments() {
         let command = LocalCommand::try_from((
@@ -0,0 +1,111 @@
+use irc_proto::{message::Tag, Command, Message, Prefix, Response};
+
+use crate::SERVER_NAME;
+
+/// Builds a [`Message`], filling in the prefix and tags so they don't have to be spelled out by
+/// hand at every call site.
+#[derive(Debug, Default, Clone)]
+#[must_use]
+pub struct MessageBuilder {
+    tags: Option<Vec<Tag>>,
+    prefix: Option<Prefix>,
+}
+
+impl MessageBuilder {
+    /// A message originating from this server, used for all numeric responses.
+    pub fn server() -> Self {
+        Self {
+            tags: None,
+            prefix: Some(Prefix::ServerName(SERVER_NAME.to_string())),
+        }
+    }
+
+    /// A message originating from the given user.
+    pub fn user(prefix: Prefix) -> Self {
+        Self {
+            tags: None,
+            prefix: Some(prefix),
+        }
+    }
+
+    /// A message without a prefix, which the client will assume originated from the server
+    /// it's connected to.
+    pub fn bare() -> Self {
+        Self::default()
+    }
+
+    /// Attaches the given tags to the message.
+    pub fn tags(mut self, tags: impl Into<Option<Vec<Tag>>>) -> Self {
+        self.tags = tags.into();
+        self
+    }
+
+    #[must_use]
+    pub fn command(self, command: Command) -> Message {
+        Message {
+            tags: self.tags,
+            prefix: self.prefix,
+            command,
+        }
+    }
+
+    /// Builds a numeric response, `args` should start with the nick of the user the response is
+    /// being sent to.
+    #[must_use]
+    pub fn response(self, response: Response, args: Vec<String>) -> Message {
+        self.command(Command::Response(response, args))
+    }
+
+    /// Builds a numeric response for a numeric that isn't known to `irc_proto`.
+    #[must_use]
+    pub fn numeric(self, numeric: u16, args: Vec<String>) -> Message {
+        self.command(Command::Raw(format!("{numeric:03}"), args))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use irc_proto::{message::Tag, Command, Prefix, Response};
+
+    use super::MessageBuilder;
+    use crate::SERVER_NAME;
+
+    #[test]
+    fn server_response() {
+        let message = MessageBuilder::server().response(
+            Response::RPL_ENDOFINFO,
+            vec!["nick".to_string(), "End of INFO list".to_string()],
+        );
+
+        assert_eq!(
+            message.prefix,
+            Some(Prefix::ServerName(SERVER_NAME.to_string()))
+        );
+        assert_eq!(message.tags, None);
+        assert_eq!(
+            message.to_string(),
+            format!(":{SERVER_NAME} 374 nick :End of INFO list\r\n")
+        );
+    }
+
+    #[test]
+    fn numeric_is_zero_padded() {
+        let message = MessageBuilder::server().numeric(7, vec!["nick".to_string()]);
+
+        assert_eq!(
+            message.command,
+            Command::Raw("007".to_string(), vec!["nick".to_string()])
+        );
+    }
+
+    #[test]
+    fn user_message_with_tags() {
+        let prefix = Prefix::new_from_str("nick!user@host");
+        let message = MessageBuilder::user(prefix.clone())
+            .tags(vec![Tag("time".to_string(), None)])
+            .command(Command::AWAY(None));
+
+        assert_eq!(message.prefix, Some(prefix));
+        assert_eq!(message.tags, Some(vec![Tag("time".to_string(), None)]));
+    }
+}
@@ -0,0 +1,407 @@
+//! Formats stored history for replay to a client, tagging each message according to the
+//! capabilities the client negotiated.
+
+use std::collections::HashMap;
+
+use irc_proto::{message::Tag, Command, Message, Prefix};
+use itertools::Itertools;
+
+use crate::{
+    client::{msgid_tag, server_time_tag, TagBuilder},
+    connection::Capability,
+    messages::MessageKind,
+    persistence::events::{StoredMessage, StoredReaction},
+    proto::builder::MessageBuilder,
+};
+
+/// Builds the messages replayed to a single client, independent of where the history came from.
+#[derive(Copy, Clone, Debug)]
+pub struct Replayer {
+    capabilities: Capability,
+}
+
+impl Replayer {
+    #[must_use]
+    pub const fn new(capabilities: Capability) -> Self {
+        Self { capabilities }
+    }
+
+    /// Builds the messages to replay `messages` sent to `target`, which is the channel name for
+    /// channel history, or the recipient's own nick for private messages. Reactions to the
+    /// messages follow the message they were made to. If any messages were omitted from the
+    /// replay, the client is told how many first. If the client negotiated `batch`, the messages
+    /// are wrapped in a `chathistory` batch identified by `batch`.
+    #[must_use]
+    pub fn replay(
+        &self,
+        batch: &str,
+        target: &str,
+        messages: Vec<StoredMessage>,
+        reactions: Vec<StoredReaction>,
+        omitted: i64,
+    ) -> Vec<Message> {
+        let mut out = Vec::with_capacity(messages.len() + 3);
+        let mut reactions = self.group_reactions(reactions);
+
+        if omitted > 0 {
+            out.push(MessageBuilder::server().command(Command::NOTICE(
+                target.to_string(),
+                format!("{omitted} older messages were omitted from the replay"),
+            )));
+        }
+
+        // there's no point opening a batch the client would immediately see closed
+        let batch = self.batch(batch).filter(|_| !messages.is_empty());
+
+        if let Some(batch) = batch {
+            out.push(start_batch(batch, target));
+        }
+
+        for message in messages {
+            self.push_message(&mut out, &mut reactions, target, message, batch);
+        }
+
+        if let Some(batch) = batch {
+            out.push(end_batch(batch));
+        }
+
+        out
+    }
+
+    /// Builds the response to a `CHATHISTORY` request for `target`, each message being paired
+    /// with the target it was originally addressed to, followed by any reactions to it. If the
+    /// client negotiated `batch`, the messages are wrapped in a `chathistory` batch identified
+    /// by `batch`.
+    #[must_use]
+    pub fn history(
+        &self,
+        batch: &str,
+        target: &str,
+        messages: Vec<(String, StoredMessage)>,
+        reactions: Vec<StoredReaction>,
+    ) -> Vec<Message> {
+        let mut reactions = self.group_reactions(reactions);
+        let batch = self.batch(batch);
+        let mut out = Vec::with_capacity(messages.len() + 2);
+
+        if let Some(batch) = batch {
+            out.push(start_batch(batch, target));
+        }
+
+        for (target, message) in messages {
+            self.push_message(&mut out, &mut reactions, &target, message, batch);
+        }
+
+        if let Some(batch) = batch {
+            out.push(end_batch(batch));
+        }
+
+        out
+    }
+
+    /// The batch to tag replayed messages with, if the client negotiated `batch`.
+    fn batch<'a>(&self, batch: &'a str) -> Option<&'a str> {
+        self.capabilities
+            .contains(Capability::BATCH)
+            .then_some(batch)
+    }
+
+    /// Groups reactions by the msgid they were made to, dropping them entirely if the client
+    /// can't receive client tags.
+    fn group_reactions(
+        &self,
+        reactions: Vec<StoredReaction>,
+    ) -> HashMap<String, Vec<StoredReaction>> {
+        if !self.capabilities.contains(Capability::MESSAGE_TAGS) {
+            return HashMap::new();
+        }
+
+        reactions
+            .into_iter()
+            .map(|reaction| (reaction.2.clone(), reaction))
+            .into_group_map()
+    }
+
+    fn push_message(
+        &self,
+        out: &mut Vec<Message>,
+        reactions: &mut HashMap<String, Vec<StoredReaction>>,
+        target: &str,
+        message: StoredMessage,
+        batch: Option<&str>,
+    ) {
+        let message_reactions = message
+            .4
+            .as_ref()
+            .and_then(|msgid| reactions.remove(msgid))
+            .unwrap_or_default();
+
+        out.push(self.build_message(target, message, batch));
+        out.extend(
+            message_reactions
+                .into_iter()
+                .map(|reaction| self.build_reaction(target, reaction, batch)),
+        );
+    }
+
+    fn build_reaction(
+        &self,
+        target: &str,
+        (sent, source, msgid, reaction): StoredReaction,
+        batch: Option<&str>,
+    ) -> Message {
+        let time = self
+            .capabilities
+            .contains(Capability::SERVER_TIME)
+            .then(|| server_time_tag(sent));
+        let batch = batch.map(|v| Tag("batch".to_string(), Some(v.to_string())));
+
+        MessageBuilder::user(Prefix::new_from_str(&source))
+            .tags(
+                TagBuilder::default()
+                    .insert(time)
+                    .insert(batch)
+                    .insert(Tag("+draft/react".to_string(), Some(reaction)))
+                    .insert(Tag("+draft/reply".to_string(), Some(msgid))),
+            )
+            .command(MessageKind::Tag.into_command(target.to_string(), String::new()))
+    }
+
+    fn build_message(
+        &self,
+        target: &str,
+        (sent, source, message, kind, msgid): StoredMessage,
+        batch: Option<&str>,
+    ) -> Message {
+        let time = self
+            .capabilities
+            .contains(Capability::SERVER_TIME)
+            .then(|| server_time_tag(sent));
+        let batch = batch.map(|v| Tag("batch".to_string(), Some(v.to_string())));
+        let msgid = msgid
+            .filter(|_| self.capabilities.contains(Capability::MESSAGE_TAGS))
+            .map(msgid_tag);
+
+        MessageBuilder::user(Prefix::new_from_str(&source))
+            .tags(
+                TagBuilder::default()
+                    .insert(time)
+                    .insert(batch)
+                    .insert(msgid),
+            )
+            .command(kind.into_command(target.to_string(), message))
+    }
+}
+
+/// Generates a reference tag for a new batch, unique enough to not clash with any other batch
+/// the client has open.
+#[must_use]
+pub fn new_batch_id() -> String {
+    format!("{:016x}", rand::random::<u64>())
+}
+
+fn start_batch(batch: &str, target: &str) -> Message {
+    MessageBuilder::server().command(Command::Raw(
+        "BATCH".to_string(),
+        vec![
+            format!("+{batch}"),
+            "chathistory".to_string(),
+            target.to_string(),
+        ],
+    ))
+}
+
+fn end_batch(batch: &str) -> Message {
+    MessageBuilder::server().command(Command::Raw("BATCH".to_string(), vec![format!("-{batch}")]))
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::{TimeZone, Utc};
+
+    use super::Replayer;
+    use crate::{
+        connection::Capability, messages::MessageKind, persistence::events::StoredMessage,
+        SERVER_NAME,
+    };
+
+    fn history() -> Vec<StoredMessage> {
+        vec![
+            (
+                Utc.with_ymd_and_hms(2023, 1, 1, 12, 0, 0).unwrap(),
+                "alice!alice@host".to_string(),
+                "hello".to_string(),
+                MessageKind::Normal,
+                Some("abc".to_string()),
+            ),
+            (
+                Utc.with_ymd_and_hms(2023, 1, 1, 12, 0, 5).unwrap(),
+                "bob!bob@host".to_string(),
+                "hi there".to_string(),
+                MessageKind::Notice,
+                None,
+            ),
+        ]
+    }
+
+    fn transcript(messages: &[irc_proto::Message]) -> String {
+        messages.iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn channel_replay_with_server_time() {
+        let messages =
+            Replayer::new(Capability::SERVER_TIME).replay("abc", "#chan", history(), Vec::new(), 0);
+
+        assert_eq!(
+            transcript(&messages),
+            "@time=2023-01-01T12:00:00.000Z :alice!alice@host PRIVMSG #chan :hello\r\n\
+             @time=2023-01-01T12:00:05.000Z :bob!bob@host NOTICE #chan :hi there\r\n"
+        );
+    }
+
+    #[test]
+    fn channel_replay_with_msgid() {
+        let messages = Replayer::new(Capability::MESSAGE_TAGS).replay(
+            "abc",
+            "#chan",
+            history(),
+            Vec::new(),
+            0,
+        );
+
+        assert_eq!(
+            transcript(&messages),
+            "@msgid=abc :alice!alice@host PRIVMSG #chan :hello\r\n\
+             :bob!bob@host NOTICE #chan :hi there\r\n"
+        );
+    }
+
+    #[test]
+    fn channel_replay_with_reactions() {
+        let reactions = vec![(
+            Utc.with_ymd_and_hms(2023, 1, 1, 12, 0, 10).unwrap(),
+            "bob!bob@host".to_string(),
+            "abc".to_string(),
+            "👍".to_string(),
+        )];
+
+        let messages = Replayer::new(Capability::MESSAGE_TAGS).replay(
+            "abc",
+            "#chan",
+            history(),
+            reactions.clone(),
+            0,
+        );
+        assert_eq!(
+            transcript(&messages),
+            "@msgid=abc :alice!alice@host PRIVMSG #chan :hello\r\n\
+             @+draft/react=👍;+draft/reply=abc :bob!bob@host TAGMSG #chan\r\n\
+             :bob!bob@host NOTICE #chan :hi there\r\n"
+        );
+
+        let messages =
+            Replayer::new(Capability::empty()).replay("abc", "#chan", history(), reactions, 0);
+        assert_eq!(
+            transcript(&messages),
+            ":alice!alice@host PRIVMSG #chan :hello\r\n\
+             :bob!bob@host NOTICE #chan :hi there\r\n"
+        );
+    }
+
+    #[test]
+    fn private_replay_without_server_time() {
+        let messages =
+            Replayer::new(Capability::empty()).replay("abc", "carol", history(), Vec::new(), 0);
+
+        assert_eq!(
+            transcript(&messages),
+            ":alice!alice@host PRIVMSG carol :hello\r\n\
+             :bob!bob@host NOTICE carol :hi there\r\n"
+        );
+    }
+
+    #[test]
+    fn omitted_messages_are_announced_first() {
+        let messages =
+            Replayer::new(Capability::empty()).replay("abc", "#chan", history(), Vec::new(), 3);
+
+        assert_eq!(
+            transcript(&messages),
+            format!(
+                ":{SERVER_NAME} NOTICE #chan :3 older messages were omitted from the replay\r\n\
+                 :alice!alice@host PRIVMSG #chan :hello\r\n\
+                 :bob!bob@host NOTICE #chan :hi there\r\n"
+            )
+        );
+    }
+
+    #[test]
+    fn replay_in_batch() {
+        let messages =
+            Replayer::new(Capability::BATCH).replay("abc", "#chan", history(), Vec::new(), 3);
+
+        assert_eq!(
+            transcript(&messages),
+            format!(
+                ":{SERVER_NAME} NOTICE #chan :3 older messages were omitted from the replay\r\n\
+                 :{SERVER_NAME} BATCH +abc chathistory #chan\r\n\
+                 @batch=abc :alice!alice@host PRIVMSG #chan :hello\r\n\
+                 @batch=abc :bob!bob@host NOTICE #chan :hi there\r\n\
+                 :{SERVER_NAME} BATCH -abc\r\n"
+            )
+        );
+
+        assert!(Replayer::new(Capability::BATCH)
+            .replay("abc", "#chan", Vec::new(), Vec::new(), 0)
+            .is_empty());
+    }
+
+    #[test]
+    fn history_in_batch() {
+        let messages = history()
+            .into_iter()
+            .map(|message| ("#chan".to_string(), message))
+            .collect();
+        let messages = Replayer::new(Capability::SERVER_TIME | Capability::BATCH).history(
+            "abc",
+            "#chan",
+            messages,
+            Vec::new(),
+        );
+
+        assert_eq!(
+            transcript(&messages),
+            format!(
+                ":{SERVER_NAME} BATCH +abc chathistory #chan\r\n\
+                 @time=2023-01-01T12:00:00.000Z;batch=abc :alice!alice@host PRIVMSG #chan :hello\r\n\
+                 @time=2023-01-01T12:00:05.000Z;batch=abc :bob!bob@host NOTICE #chan :hi there\r\n\
+                 :{SERVER_NAME} BATCH -abc\r\n"
+            )
+        );
+    }
+
+    #[test]
+    fn history_without_batch() {
+        let mut history = history();
+        let messages = vec![
+            ("carol".to_string(), history.remove(0)),
+            ("alice".to_string(), history.remove(0)),
+        ];
+        let messages =
+            Replayer::new(Capability::empty()).history("abc", "alice", messages, Vec::new());
+
+        assert_eq!(
+            transcript(&messages),
+            ":alice!alice@host PRIVMSG carol :hello\r\n\
+             :bob!bob@host NOTICE alice :hi there\r\n"
+        );
+    }
+
+    #[test]
+    fn empty_replay() {
+        assert!(Replayer::new(Capability::SERVER_TIME)
+            .replay("abc", "#chan", Vec::new(), Vec::new(), 0)
+            .is_empty());
+    }
+}
@@ -1,44 +1,83 @@
+pub mod bans;
 pub mod response;
 
-use std::{borrow::Cow, collections::HashMap, time::Duration};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    future::Future,
+    path::PathBuf,
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, Instant},
+};
 
 use actix::{
     Actor, ActorContext, ActorFuture, ActorFutureExt, Addr, AsyncContext, Context, Handler,
-    MessageResult, ResponseFuture, Supervised, Supervisor, WrapFuture,
+    MessageResult, ResponseActFuture, ResponseFuture, Supervised, Supervisor, WrapFuture,
 };
 use actix_rt::Arbiter;
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use clap::crate_version;
 use futures::{
     future,
     stream::{FuturesOrdered, FuturesUnordered},
     TryFutureExt,
 };
-use irc_proto::{Command, Message, Prefix, Response};
+use irc_proto::{ChannelExt, Command, Message, Prefix, Response};
 use rand::seq::SliceRandom;
 use tokio_stream::StreamExt;
 use tracing::{debug, error, info, instrument, warn, Span};
 
 use crate::{
-    channel::{permissions::Permission, Channel, ChannelId},
-    client::Client,
-    config::Config,
-    connection::{InitiatedConnection, UserMode},
-    host_mask::{HostMask, HostMaskMap},
+    casemapping::{self, CASEMAPPING},
+    channel::{
+        list,
+        metadata::ChannelMetadata,
+        modes::ChannelModeState,
+        permissions::Permission,
+        response::{ChannelJoinRejectionReason, ChannelNamesList, CreationRestricted},
+        Channel, ChannelId,
+    },
+    client::{
+        server_time_tag, server_time_tags, traffic::TOTAL_TRAFFIC, usage::COMMAND_USAGE, Client,
+        TagBuilder, WRITE_ERRORS,
+    },
+    config::{Config, NamespaceRestriction},
+    connection::{AddressFamily, Capability, InitiatedConnection, UserId, UserMode},
+    database::verify_password,
+    host_mask::{BanMask, HostMaskMap},
+    listener::ListenerManager,
     messages::{
-        Broadcast, ChannelFetchTopic, ChannelFetchWhoList, ChannelJoin, ChannelList,
-        ChannelMemberList, ClientAway, ConnectedChannels, FetchClientByNick, FetchWhoList,
-        FetchWhois, ForceDisconnect, Gline, KillUser, ListGline, MessageKind, PrivateMessage,
-        RemoveGline, ServerAdminInfo, ServerDisconnect, ServerFetchMotd, ServerListUsers,
-        UserConnected, UserNickChange, UserNickChangeInternal, ValidateConnection, Wallops,
+        Broadcast, ChannelEmptied, ChannelFetchCreationTime, ChannelFetchMetadata,
+        ChannelFetchTopic, ChannelFetchWhoList, ChannelJoin, ChannelList, ChannelMemberList,
+        ChannelMembershipChanged, CheckNickAvailability, CheckOperCredentials, ClientShunned,
+        CloseChannel, DatabaseHealthChanged, DisconnectAccount, FetchChannelMemberList,
+        FetchClientTraffic, FetchSessions, FetchUserPermission, FetchWhoList, FetchWhois,
+        ForceDisconnect, Gline, HoldResource, KillUser, ListGline, ListShun, LogoutSession,
+        PrivateMessage, ReloadConfig, ReloadListeners, RemoveGline, RemoveShun, ResolveTarget,
+        ServerAdminInfo, ServerDisconnect, ServerFetchMotd, ServerListUsers, ServerStats,
+        ServicesClientsChanged, ServicesLinked, ServicesUnlinked, ServicesUserChanged, Shun,
+        Shutdown, SpyChannel, TapClient, UnbindListener, UpdateCommandsConfig, UserConnected,
+        UserNickChange, UserNickChangeInternal, ValidateConnection, Wallops,
     },
     persistence::{
-        events::{ServerBan, ServerRemoveBan},
+        events::{
+            ClientCountChanged, FetchAccountByNick, FetchChannelExists, FetchSharesChannel, Flush,
+            PromoteChannelSuccessors, ServerBan, ServerListShun, ServerRemoveBan, ServerRemoveShun,
+            ServerShun, SubscribeDatabaseHealth,
+        },
         Persistence,
     },
-    server::response::{
-        AdminInfo, ConnectionValidated, IntoProtocol, ListUsers, Motd, NoSuchNick, WhoList, Whois,
+    proto::builder::MessageBuilder,
+    server::{
+        bans::NetworkBans,
+        response::{
+            AcceptList, AcceptListError, AccountTooNew, AdminInfo, CallerIdNotify,
+            CallerIdRejected, ChannelSuccessor, ConnectionValidated, IntoProtocol, ListUsers, Motd,
+            NickAvailability, NoSharedChannel, NoSuchChannel, NoSuchNick, Rehash,
+            ResourceUnavailable, Stats, StatsReport, Target, WhoList, Whois,
+        },
     },
+    services::link::ServicesLink,
     SERVER_NAME,
 };
 
@@ -46,11 +85,81 @@ use crate::{
 pub struct Server {
     pub channel_arbiters: Vec<Arbiter>,
     pub channels: HashMap<String, Addr<Channel>>,
-    pub clients: HashMap<Addr<Client>, InitiatedConnection>,
+    pub clients: HashMap<Addr<Client>, Arc<InitiatedConnection>>,
+    /// Online clients keyed by their folded nick, for looking up users without scanning
+    /// `clients`.
+    pub nicks: HashMap<String, Addr<Client>>,
+    /// The channels each client is in, as reported by the channels themselves.
+    pub memberships: HashMap<Addr<Client>, HashMap<String, Addr<Channel>>>,
     pub max_clients: usize,
+    pub started_at: DateTime<Utc>,
     pub config: Config,
+    /// The file `config` was loaded from, re-read upon `REHASH`.
+    pub config_path: PathBuf,
+    pub listeners: Addr<ListenerManager>,
     pub persistence: Addr<Persistence>,
-    pub bans: HostMaskMap<response::ServerBan>,
+    /// The network bans, shared with the listeners so connecting users can be checked against
+    /// them without going through the server.
+    pub bans: NetworkBans,
+    pub shuns: HostMaskMap<response::ServerBan>,
+    pub caller_id: HashMap<UserId, CallerIdState>,
+    /// Nicks and channels that are temporarily unavailable for use, keyed by their folded name
+    /// along with the time the hold expires.
+    pub holds: HashMap<String, Instant>,
+    /// The external services package currently linked to the server, if any.
+    pub services: Option<LinkedServices>,
+}
+
+/// An external services package linked to the server over [`ServicesLink`].
+pub struct LinkedServices {
+    pub link: Addr<ServicesLink>,
+    /// The server name services linked with, which their clients can be addressed on.
+    pub name: String,
+    /// The folded nicks of services' own clients, which can't be used by users.
+    pub nicks: HashSet<String>,
+}
+
+/// A user's caller-id (`+g`) state, shared between all of their sessions.
+#[derive(Default)]
+pub struct CallerIdState {
+    /// Nicks that are allowed to send private messages to the user, keyed by their folded form.
+    pub accepted: HashMap<String, String>,
+    /// The last time the user was informed of a message from a nick not on their accept list,
+    /// only holds nicks notified within the last [`Self::NOTIFY_INTERVAL`].
+    pub last_notified: HashMap<String, Instant>,
+}
+
+impl CallerIdState {
+    /// The maximum amount of nicks a user can have on their accept list.
+    pub const MAX_ACCEPTED: usize = 64;
+
+    /// How often a user is informed of a single nick attempting to message them.
+    pub const NOTIFY_INTERVAL: Duration = Duration::from_secs(60);
+
+    /// Returns true if `nick` is on the user's accept list.
+    #[must_use]
+    pub fn is_accepted(&self, nick: &str) -> bool {
+        self.accepted.contains_key(&casemapping::fold(nick))
+    }
+
+    /// Returns true, and marks the nick as notified, if the user hasn't been informed of a
+    /// message from `nick` within the last [`Self::NOTIFY_INTERVAL`]. `nick` is expected to
+    /// already be folded so differently cased nicks share a single notification.
+    pub fn should_notify(&mut self, nick: &str) -> bool {
+        let now = Instant::now();
+
+        // forget about any nicks that can be notified about again, so the map doesn't grow
+        // with every nick that's ever attempted to message the user
+        self.last_notified
+            .retain(|_, last| now.duration_since(*last) < Self::NOTIFY_INTERVAL);
+
+        if self.last_notified.contains_key(nick) {
+            return false;
+        }
+
+        self.last_notified.insert(nick.to_string(), now);
+        true
+    }
 }
 
 impl Supervised for Server {}
@@ -60,8 +169,7 @@ impl Handler<UserNickChangeInternal> for Server {
     type Result = ();
 
     fn handle(&mut self, msg: UserNickChangeInternal, _ctx: &mut Self::Context) -> Self::Result {
-        let client = self.clients.iter().find(|(_k, v)| v.nick == msg.old_nick);
-        let Some((client, _)) = client else {
+        let Some((client, _)) = self.find_client(&msg.old_nick) else {
             warn!(%msg.old_nick, %msg.new_nick, "User attempted to update nick for unknown user");
             return;
         };
@@ -72,17 +180,25 @@ impl Handler<UserNickChangeInternal> for Server {
     }
 }
 
+/// Checks a newly registered connection against the limits of its connection class and the
+/// shuns in place. Bans have already been checked by the listener.
 impl Handler<ValidateConnection> for Server {
     type Result = MessageResult<ValidateConnection>;
 
-    #[allow(clippy::option_if_let_else)]
     fn handle(&mut self, msg: ValidateConnection, _ctx: &mut Self::Context) -> Self::Result {
+        let class = &msg.0.class;
+
         MessageResult(
-            if let Some(ban) = self.bans.get(&msg.0.to_host_mask()).into_iter().next() {
-                ConnectionValidated::Reject(format!(
-                    "G-lined: {}",
-                    ban.reason.as_deref().unwrap_or("no reason given")
-                ))
+            if class.max_clients.is_some_and(|max| {
+                self.clients
+                    .values()
+                    .filter(|c| c.class.name == class.name)
+                    .count()
+                    >= max
+            }) {
+                ConnectionValidated::Reject(format!("Too many connections in class {}", class.name))
+            } else if self.is_shunned(&msg.0) {
+                ConnectionValidated::Shunned
             } else {
                 ConnectionValidated::Allowed
             },
@@ -90,6 +206,42 @@ impl Handler<ValidateConnection> for Server {
     }
 }
 
+impl Handler<CheckNickAvailability> for Server {
+    type Result = MessageResult<CheckNickAvailability>;
+
+    fn handle(&mut self, msg: CheckNickAvailability, _ctx: &mut Self::Context) -> Self::Result {
+        let in_use = |nick: &str| {
+            self.is_services_nick(nick)
+                || self
+                    .find_client(nick)
+                    .map_or(false, |(handle, _)| Some(handle) != msg.client.as_ref())
+        };
+
+        if self.is_held(&msg.nick) {
+            return MessageResult(NickAvailability::Unavailable(ResourceUnavailable(msg.nick)));
+        }
+
+        if !in_use(&msg.nick) {
+            return MessageResult(NickAvailability::Available);
+        }
+
+        let suggestion = if self.config.nicks.suggest_alternative {
+            let mut suggestion = format!("{}_", msg.nick);
+            while in_use(&suggestion) {
+                suggestion.push('_');
+            }
+            Some(suggestion)
+        } else {
+            None
+        };
+
+        MessageResult(NickAvailability::InUse {
+            nick: msg.nick,
+            suggestion,
+        })
+    }
+}
+
 /// Received when a user connects to the server, and sends them the server preamble
 impl Handler<UserConnected> for Server {
     type Result = ();
@@ -98,6 +250,29 @@ impl Handler<UserConnected> for Server {
     fn handle(&mut self, msg: UserConnected, _ctx: &mut Self::Context) -> Self::Result {
         let nick = msg.connection.to_nick();
 
+        let mut isupport: Vec<Cow<'_, str>> = vec![
+            format!("PREFIX={}", Permission::SUPPORTED_PREFIXES).into(),
+            format!("STATUSMSG={}", Permission::STATUSMSG_PREFIXES).into(),
+            format!("CHANMODES={}", ChannelModeState::SUPPORTED_MODES).into(),
+            format!("ELIST={}", list::SUPPORTED_EXTENSIONS).into(),
+            format!("CASEMAPPING={CASEMAPPING}").into(),
+            "CALLERID=g".into(),
+            format!(
+                "CHATHISTORY={}",
+                self.config.database.max_message_replay_count
+            )
+            .into(),
+            "MSGREFTYPES=timestamp".into(),
+            "CPRIVMSG".into(),
+            "CNOTICE".into(),
+        ];
+
+        if let Some(filehost) = &self.config.filehost {
+            isupport.push(format!("FILEHOST={}", filehost.upload_url()).into());
+        }
+
+        isupport.push("are supported by this server".into());
+
         // send a welcome to the user
         let responses = [
             (
@@ -114,7 +289,11 @@ impl Handler<UserConnected> for Server {
             ),
             (
                 Response::RPL_CREATED,
-                vec!["This server was created at some point".into()],
+                vec![format!(
+                    "This server was created {}",
+                    self.started_at.format("%a %b %d %Y at %T UTC")
+                )
+                .into()],
             ),
             (
                 Response::RPL_MYINFO,
@@ -126,39 +305,70 @@ impl Handler<UserConnected> for Server {
                     "bkloveqjfI".into(),
                 ],
             ),
-            (
-                Response::RPL_ISUPPORT,
-                vec![
-                    format!("PREFIX={}", Permission::SUPPORTED_PREFIXES).into(),
-                    "are supported by this server".into(),
-                ],
-            ),
+            (Response::RPL_ISUPPORT, isupport),
         ];
 
         for (response, arguments) in responses {
-            let arguments = std::iter::once(msg.connection.nick.clone())
+            let arguments = std::iter::once(msg.connection.nick())
                 .chain(arguments.into_iter().map(Cow::into_owned))
                 .collect();
 
             msg.handle.do_send(Broadcast {
                 span: Span::current(),
-                message: Message {
-                    tags: None,
-                    prefix: Some(Prefix::ServerName(SERVER_NAME.to_string())),
-                    command: Command::Response(response, arguments),
-                },
+                message: MessageBuilder::server().response(response, arguments),
             });
         }
 
-        for message in Motd::new(self).into_messages(&msg.connection.nick) {
+        self.clients
+            .insert(msg.handle.clone(), msg.connection.clone());
+        self.nicks.insert(
+            casemapping::fold(&msg.connection.nick()),
+            msg.handle.clone(),
+        );
+        self.max_clients = self.clients.len().max(self.max_clients);
+        self.persistence.do_send(ClientCountChanged {
+            clients: self.clients.len(),
+        });
+        self.notify_services(ServicesUserChanged::Connected {
+            client: msg.handle.clone(),
+            connection: msg.connection.clone(),
+        });
+
+        for message in Motd::new(self, &msg.connection.class).into_messages(&msg.connection.nick())
+        {
             msg.handle.do_send(Broadcast {
                 span: Span::current(),
                 message,
             });
         }
+    }
+}
 
-        self.clients.insert(msg.handle, msg.connection);
-        self.max_clients = self.clients.len().max(self.max_clients);
+/// Verifies an operator's password off of the server's thread, as argon2 is intentionally slow.
+impl Handler<CheckOperCredentials> for Server {
+    type Result = ResponseFuture<bool>;
+
+    #[instrument(parent = &msg.span, skip_all)]
+    fn handle(&mut self, msg: CheckOperCredentials, _ctx: &mut Self::Context) -> Self::Result {
+        let Some(hash) = self
+            .config
+            .opers
+            .iter()
+            .find(|oper| oper.name == msg.name)
+            .map(|oper| oper.password.clone())
+        else {
+            return Box::pin(future::ready(false));
+        };
+
+        Box::pin(async move {
+            tokio::task::spawn_blocking(move || {
+                // hashes are checked when the config is loaded
+                let hash = argon2::PasswordHash::new(&hash).unwrap();
+                verify_password(msg.password.as_bytes(), &hash).is_ok()
+            })
+            .await
+            .unwrap()
+        })
     }
 }
 
@@ -168,29 +378,204 @@ impl Handler<Wallops> for Server {
     #[instrument(parent = &msg.span, skip_all)]
     fn handle(&mut self, msg: Wallops, _ctx: &mut Self::Context) -> Self::Result {
         for (handle, conn) in &self.clients {
-            if !conn.mode.contains(UserMode::WALLOPS) {
+            if !conn.mode().contains(UserMode::WALLOPS) {
                 continue;
             }
 
             handle.do_send(Broadcast {
-                message: Message {
-                    tags: None,
-                    prefix: Some(Prefix::ServerName(SERVER_NAME.to_string())),
-                    command: Command::WALLOPS(msg.message.clone()),
-                },
+                message: MessageBuilder::server()
+                    .tags(server_time_tags())
+                    .command(Command::WALLOPS(msg.message.clone())),
                 span: msg.span.clone(),
             });
         }
     }
 }
 
+/// Accepts a link from services, unless services are already linked, returning every connected
+/// user to be introduced to them.
+impl Handler<ServicesLinked> for Server {
+    type Result = MessageResult<ServicesLinked>;
+
+    fn handle(&mut self, msg: ServicesLinked, _ctx: &mut Self::Context) -> Self::Result {
+        if self
+            .services
+            .as_ref()
+            .is_some_and(|services| services.link.connected())
+        {
+            return MessageResult(None);
+        }
+
+        info!("Services linked");
+
+        self.services = Some(LinkedServices {
+            link: msg.link,
+            name: msg.name,
+            nicks: HashSet::new(),
+        });
+
+        MessageResult(Some(
+            self.clients
+                .iter()
+                .map(|(handle, connection)| (handle.clone(), connection.clone()))
+                .collect(),
+        ))
+    }
+}
+
+/// Forgets about services once their link closes, freeing up their clients' nicks.
+impl Handler<ServicesUnlinked> for Server {
+    type Result = ();
+
+    fn handle(&mut self, msg: ServicesUnlinked, _ctx: &mut Self::Context) -> Self::Result {
+        if self
+            .services
+            .as_ref()
+            .is_some_and(|services| services.link == msg.link)
+        {
+            info!("Services unlinked");
+            self.services = None;
+        }
+    }
+}
+
+/// Keeps track of the nicks used by services' own clients.
+impl Handler<ServicesClientsChanged> for Server {
+    type Result = ();
+
+    fn handle(&mut self, msg: ServicesClientsChanged, _ctx: &mut Self::Context) -> Self::Result {
+        if let Some(services) = &mut self.services {
+            services.nicks = msg.nicks.iter().map(|v| casemapping::fold(v)).collect();
+        }
+    }
+}
+
+/// Lets operators know when messages can't be written to the database, and once they're being
+/// written again.
+impl Handler<DatabaseHealthChanged> for Server {
+    type Result = ();
+
+    fn handle(&mut self, msg: DatabaseHealthChanged, _ctx: &mut Self::Context) -> Self::Result {
+        let notice = match msg {
+            DatabaseHealthChanged::Unavailable { retry_in } => format!(
+                "Unable to write messages to the database, buffering them and retrying in {}",
+                humantime::format_duration(retry_in)
+            ),
+            DatabaseHealthChanged::Restored { dropped: 0 } => {
+                "Messages are being written to the database again".to_string()
+            }
+            DatabaseHealthChanged::Restored { dropped } => {
+                format!("Messages are being written to the database again, {dropped} were dropped")
+            }
+        };
+
+        for (handle, conn) in &self.clients {
+            if !conn.mode().contains(UserMode::OPER) {
+                continue;
+            }
+
+            handle.do_send(Broadcast {
+                message: MessageBuilder::server()
+                    .tags(server_time_tags())
+                    .command(Command::NOTICE(conn.nick(), notice.clone())),
+                span: Span::current(),
+            });
+        }
+    }
+}
+
 /// Returns the MOTD when requested.
 impl Handler<ServerFetchMotd> for Server {
     type Result = MessageResult<ServerFetchMotd>;
 
     #[instrument(parent = &msg.span, skip_all)]
     fn handle(&mut self, msg: ServerFetchMotd, _ctx: &mut Self::Context) -> Self::Result {
-        MessageResult(Motd::new(self))
+        MessageResult(Motd::new(self, &msg.class))
+    }
+}
+
+/// Reloads the config from disk, keeping the current config if the new one is invalid. The
+/// database, thread, TLS, tap, file host and services settings only take effect after a
+/// restart.
+impl Handler<ReloadConfig> for Server {
+    type Result = MessageResult<ReloadConfig>;
+
+    #[instrument(parent = &msg.span, skip_all)]
+    fn handle(&mut self, msg: ReloadConfig, _ctx: &mut Self::Context) -> Self::Result {
+        let path = self.config_path.display().to_string();
+
+        let config = match Config::load(&self.config_path) {
+            Ok(config) => config,
+            Err(error) => {
+                warn!(%path, %error, "Failed to reload config");
+                return MessageResult(Rehash::Failed(error.to_string()));
+            }
+        };
+
+        info!(%path, "Reloaded config");
+
+        let previous = std::mem::replace(&mut self.config, config);
+        let commands = Arc::new(self.config.commands.clone());
+
+        self.bans
+            .set_config(self.config.bans.clone(), self.config.network_name.clone());
+
+        self.listeners.do_send(ReloadListeners {
+            listeners: self.config.listeners.clone(),
+            classes: self.config.classes.clone(),
+            oper_session: self.config.oper_session,
+            fallback_nick: self.config.nicks.fallback,
+            commands: commands.clone(),
+        });
+
+        for handle in self.clients.keys() {
+            handle.do_send(UpdateCommandsConfig {
+                commands: commands.clone(),
+            });
+        }
+
+        self.notify_motd_changed(&previous);
+
+        MessageResult(Rehash::Reloaded(path))
+    }
+}
+
+/// Stops accepting connections and disconnects every client, answering once everything they
+/// left behind has been persisted.
+impl Handler<Shutdown> for Server {
+    type Result = ResponseFuture<()>;
+
+    #[instrument(parent = &msg.span, skip_all)]
+    fn handle(&mut self, msg: Shutdown, _ctx: &mut Self::Context) -> Self::Result {
+        info!(clients = self.clients.len(), "Shutting down");
+
+        let unbind = self
+            .config
+            .listeners
+            .iter()
+            .map(|listener| {
+                self.listeners.send(UnbindListener {
+                    address: listener.address,
+                })
+            })
+            .collect::<Vec<_>>();
+        let clients = self
+            .clients
+            .keys()
+            .map(|handle| handle.send(msg.clone()))
+            .collect::<Vec<_>>();
+        let persistence = self.persistence.clone();
+
+        Box::pin(async move {
+            future::join_all(unbind).await;
+
+            // clients that already went away have nothing left to persist
+            future::join_all(clients).await;
+
+            if let Err(error) = persistence.send(Flush).await {
+                error!(%error, "Failed to flush persistence");
+            }
+        })
     }
 }
 
@@ -200,48 +585,118 @@ impl Handler<ServerDisconnect> for Server {
 
     #[instrument(parent = &msg.span, skip_all)]
     fn handle(&mut self, msg: ServerDisconnect, _ctx: &mut Self::Context) -> Self::Result {
-        self.clients.remove(&msg.client);
+        self.memberships.remove(&msg.client);
+
+        if let Some(connection) = self.clients.remove(&msg.client) {
+            self.unindex_nick(&connection.nick(), &msg.client);
+            self.persistence.do_send(ClientCountChanged {
+                clients: self.clients.len(),
+            });
+            self.notify_services(ServicesUserChanged::Disconnected {
+                client: msg.client,
+                message: msg.message,
+            });
+        }
     }
 }
 
 /// Received when a client is attempting to join a channel, and forwards it onto the requested
 /// channel for it to handle -- creating it if it doesn't already exist.
 impl Handler<ChannelJoin> for Server {
-    type Result = ResponseFuture<<ChannelJoin as actix::Message>::Result>;
+    type Result = ResponseActFuture<Self, <ChannelJoin as actix::Message>::Result>;
 
     #[instrument(parent = &msg.span, skip_all)]
     fn handle(&mut self, msg: ChannelJoin, ctx: &mut Self::Context) -> Self::Result {
-        let channel = self
-            .channels
-            .entry(msg.channel_name.clone())
-            .or_insert_with(|| {
-                let arbiter = self
-                    .channel_arbiters
-                    .choose(&mut rand::thread_rng())
-                    .map_or_else(Arbiter::current, Arbiter::handle);
+        if self.is_held(&msg.channel_name) {
+            return Box::pin(
+                future::ready(Ok(Err(ChannelJoinRejectionReason::Unavailable(
+                    ResourceUnavailable(msg.channel_name),
+                ))))
+                .into_actor(self),
+            );
+        }
 
-                let channel_name = msg.channel_name.clone();
-                let server = ctx.address();
-                let persistence = self.persistence.clone();
+        let Some(restricted) = self.creation_restriction(&msg) else {
+            let channel = self.channel_handle(&msg.channel_name, ctx);
+            return Box::pin(join_channel(&channel, msg).into_actor(self));
+        };
 
-                Supervisor::start_in_arbiter(&arbiter, move |_ctx| Channel {
-                    name: channel_name,
-                    permissions: HostMaskMap::new(),
-                    clients: HashMap::new(),
-                    topic: None,
-                    server,
-                    persistence,
-                    channel_id: ChannelId(0),
-                })
-            })
-            .clone();
+        // the channel might already exist without being loaded, such as when its members are
+        // rejoining after a restart, in which case it isn't being created
+        let exists = self.persistence.send(FetchChannelExists {
+            name: msg.channel_name.clone(),
+        });
 
-        Box::pin(
-            channel
-                .send(msg)
-                .map_err(anyhow::Error::new)
-                .and_then(futures::future::ready),
-        )
+        Box::pin(exists.into_actor(self).then(move |res, this, ctx| {
+            if matches!(res, Ok(true)) {
+                let channel = this.channel_handle(&msg.channel_name, ctx);
+                future::Either::Left(join_channel(&channel, msg)).into_actor(this)
+            } else {
+                future::Either::Right(future::ready(Ok(Err(
+                    ChannelJoinRejectionReason::Restricted(restricted),
+                ))))
+                .into_actor(this)
+            }
+        }))
+    }
+}
+
+/// Forwards a join onto the channel, flattening any errors from delivering it.
+fn join_channel(
+    channel: &Addr<Channel>,
+    msg: ChannelJoin,
+) -> impl Future<Output = <ChannelJoin as actix::Message>::Result> {
+    channel
+        .send(msg)
+        .map_err(anyhow::Error::new)
+        .and_then(future::ready)
+}
+
+/// Keeps track of the channels each client is in, for answering `WHOIS`.
+impl Handler<ChannelMembershipChanged> for Server {
+    type Result = ();
+
+    #[instrument(parent = &msg.span, skip_all)]
+    fn handle(&mut self, msg: ChannelMembershipChanged, _ctx: &mut Self::Context) -> Self::Result {
+        if msg.joined {
+            // the client may have disconnected before the channel told us about the join
+            if self.clients.contains_key(&msg.client) {
+                self.memberships
+                    .entry(msg.client)
+                    .or_default()
+                    .insert(msg.channel, msg.handle);
+            }
+        } else if let Some(channels) = self.memberships.get_mut(&msg.client) {
+            channels.remove(&msg.channel);
+        }
+    }
+}
+
+/// Received when the last member leaves a temporary channel. The server waits on the channel
+/// to confirm it's still empty before forgetting about it, so no joins can be routed to the
+/// channel after it's destroyed.
+impl Handler<ChannelEmptied> for Server {
+    type Result = ();
+
+    #[instrument(parent = &msg.span, skip_all)]
+    fn handle(&mut self, msg: ChannelEmptied, ctx: &mut Self::Context) -> Self::Result {
+        if self.channels.get(&msg.name) != Some(&msg.handle) {
+            return;
+        }
+
+        ctx.wait(
+            msg.handle
+                .send(CloseChannel {
+                    span: Span::current(),
+                })
+                .into_actor(self)
+                .map(move |closed, this, _ctx| {
+                    // the channel is already gone if it couldn't receive the message
+                    if closed.unwrap_or(true) {
+                        this.channels.remove(&msg.name);
+                    }
+                }),
+        );
     }
 }
 
@@ -252,15 +707,28 @@ impl Handler<UserNickChange> for Server {
 
     #[instrument(parent = &msg.span, skip_all)]
     fn handle(&mut self, msg: UserNickChange, _ctx: &mut Self::Context) -> Self::Result {
+        if let Prefix::Nickname(old_nick, _, _) = &msg.old_prefix {
+            self.unindex_nick(old_nick, &msg.client);
+        }
+
+        self.nicks
+            .insert(casemapping::fold(&msg.new_nick), msg.client.clone());
+
+        // shuns can match on nick, so changing it can both lift a shun and place the user
+        // under one
+        if let Some(connection) = self.clients.get(&msg.client) {
+            msg.client
+                .do_send(ClientShunned(self.is_shunned(connection)));
+        }
+
+        self.notify_services(ServicesUserChanged::NickChanged {
+            client: msg.client.clone(),
+        });
+
         // inform all clients of the nick change
         for client in self.clients.keys() {
             client.do_send(msg.clone());
         }
-
-        if let Some(client) = self.clients.get_mut(&msg.client) {
-            *client = msg.connection;
-            client.nick = msg.new_nick;
-        }
     }
 }
 
@@ -269,37 +737,85 @@ impl Handler<KillUser> for Server {
     type Result = ();
 
     #[instrument(parent = &msg.span, skip_all)]
-    fn handle(&mut self, msg: KillUser, _ctx: &mut Self::Context) -> Self::Result {
-        for (handle, user) in &self.clients {
-            if user.nick == msg.killed {
-                handle.do_send(msg.clone());
-            }
+    fn handle(&mut self, msg: KillUser, ctx: &mut Self::Context) -> Self::Result {
+        let Some((handle, _)) = self.find_client(&msg.killed) else {
+            return;
+        };
+
+        handle.do_send(msg.clone());
+
+        // stop anyone from immediately taking over the killed user's nick
+        if !self.config.nicks.kill_hold.is_zero() {
+            self.hold(&msg.killed, self.config.nicks.kill_hold, ctx);
         }
     }
 }
 
-impl Handler<ClientAway> for Server {
+/// Holds a nick or channel, responding to anyone trying to use it with `ERR_UNAVAILRESOURCE`
+/// until the hold expires.
+impl Handler<HoldResource> for Server {
     type Result = ();
 
-    fn handle(&mut self, msg: ClientAway, _ctx: &mut Self::Context) -> Self::Result {
-        if let Some(c) = self.clients.get_mut(&msg.handle) {
-            c.away = msg.message;
-        }
+    #[instrument(parent = &msg.span, skip_all)]
+    fn handle(&mut self, msg: HoldResource, ctx: &mut Self::Context) -> Self::Result {
+        self.hold(&msg.name, msg.duration, ctx);
     }
 }
 
-/// Fetches a client's handle by their nick
-impl Handler<FetchClientByNick> for Server {
-    type Result = MessageResult<FetchClientByNick>;
+/// Resolves a nick or channel name, falling back to the database for nicks owned by users that
+/// aren't currently online.
+impl Handler<ResolveTarget> for Server {
+    type Result = ResponseFuture<Target>;
 
-    fn handle(&mut self, msg: FetchClientByNick, _ctx: &mut Self::Context) -> Self::Result {
-        MessageResult(
-            // TODO: need O(1) lookup here
-            self.clients
-                .iter()
-                .find(|(_handle, connection)| connection.nick == msg.nick)
-                .map(|v| v.0.clone()),
-        )
+    #[instrument(parent = &msg.span, skip_all)]
+    fn handle(&mut self, msg: ResolveTarget, _ctx: &mut Self::Context) -> Self::Result {
+        // the only other server users can be addressed on is the one services linked with
+        if let Some(server) = &msg.server {
+            let target = self
+                .services
+                .as_ref()
+                .filter(|services| {
+                    services.name.eq_ignore_ascii_case(server)
+                        && services.nicks.contains(&casemapping::fold(&msg.target))
+                })
+                .map_or(Target::Unknown, |services| {
+                    Target::Services(services.link.clone())
+                });
+            return Box::pin(future::ready(target));
+        }
+
+        if msg.target.is_channel_name() {
+            let target = self
+                .channels
+                .get(&msg.target)
+                .cloned()
+                .map_or(Target::Unknown, Target::Channel);
+            return Box::pin(future::ready(target));
+        }
+
+        if let Some((handle, connection)) = self.find_client(&msg.target) {
+            return Box::pin(future::ready(Target::OnlineUser {
+                handle: handle.clone(),
+                connection: connection.clone(),
+            }));
+        }
+
+        if let Some(services) = &self.services {
+            if services.nicks.contains(&casemapping::fold(&msg.target)) {
+                return Box::pin(future::ready(Target::Services(services.link.clone())));
+            }
+        }
+
+        let account = self
+            .persistence
+            .send(FetchAccountByNick { nick: msg.target });
+
+        Box::pin(async move {
+            match account.await.unwrap() {
+                Some((user_id, account)) => Target::OfflineAccount { user_id, account },
+                None => Target::Unknown,
+            }
+        })
     }
 }
 
@@ -308,8 +824,7 @@ impl Handler<FetchWhois> for Server {
 
     #[instrument(parent = &msg.span, skip_all)]
     fn handle(&mut self, msg: FetchWhois, _ctx: &mut Self::Context) -> Self::Result {
-        let Some((handle, conn)) = self.clients.iter().find(|(_, conn)| conn.nick == msg.query)
-        else {
+        let Some((handle, conn)) = self.find_client(&msg.query) else {
             return Box::pin(future::ready(Whois {
                 query: msg.query,
                 conn: None,
@@ -317,16 +832,49 @@ impl Handler<FetchWhois> for Server {
             }));
         };
 
+        // users marked as private only have their channels exposed to themselves and operators
+        let requester_is_oper = self
+            .clients
+            .get(&msg.client)
+            .map_or(false, |v| v.mode().contains(UserMode::OPER));
+        let hide_channels =
+            conn.mode().contains(UserMode::PRIVATE) && !requester_is_oper && *handle != msg.client;
+        // secret and private channels are only shown to their members and operators
+        let requester = self.hidden_from(&msg.client);
+
+        // permissions are fetched from the channels themselves, so a client with a backed up
+        // mailbox doesn't hold up anyone looking them up
+        let host_mask = conn.to_host_mask();
+        let channels = self
+            .memberships
+            .get(handle)
+            .filter(|_| !hide_channels)
+            .into_iter()
+            .flatten()
+            .map(|(channel_name, channel)| {
+                let channel_name = channel_name.clone();
+                let permission = channel.send(FetchUserPermission {
+                    span: Span::current(),
+                    host_mask: host_mask.clone(),
+                    requester: requester.clone(),
+                });
+
+                async move { Some((permission.await.ok()??, channel_name)) }
+            })
+            .collect::<Vec<_>>();
         let conn = conn.clone();
-        let channels = handle.send(ConnectedChannels {
-            span: Span::current(),
-        });
 
         Box::pin(async move {
+            let channels = future::join_all(channels)
+                .await
+                .into_iter()
+                .flatten()
+                .collect();
+
             Whois {
                 query: msg.query,
                 conn: Some(conn),
-                channels: channels.await.unwrap(),
+                channels,
             }
         })
     }
@@ -337,7 +885,7 @@ impl Handler<ForceDisconnect> for Server {
 
     #[instrument(parent = &msg.span, skip_all)]
     fn handle(&mut self, msg: ForceDisconnect, _ctx: &mut Self::Context) -> Self::Result {
-        if let Some((handle, _)) = self.clients.iter().find(|(_, v)| v.nick == msg.user) {
+        if let Some((handle, _)) = self.find_client(&msg.user) {
             handle.do_send(msg);
             MessageResult(Ok(()))
         } else {
@@ -346,39 +894,147 @@ impl Handler<ForceDisconnect> for Server {
     }
 }
 
-impl Handler<FetchWhoList> for Server {
-    type Result = ResponseFuture<<FetchWhoList as actix::Message>::Result>;
+/// Lists the connections logged into an account, oldest first.
+impl Handler<FetchSessions> for Server {
+    type Result = MessageResult<FetchSessions>;
 
     #[instrument(parent = &msg.span, skip_all)]
-    fn handle(&mut self, msg: FetchWhoList, _ctx: &mut Self::Context) -> Self::Result {
-        if let Some(channel) = self.channels.get(&msg.query).cloned() {
-            Box::pin(async move {
-                WhoList {
-                    list: vec![channel
-                        .send(ChannelFetchWhoList { span: msg.span })
-                        .await
-                        .unwrap()],
-                    query: msg.query,
-                }
-            })
-        } else {
-            let futures = self
-                .clients
-                .iter()
-                .filter(|(_, conn)| conn.nick == msg.query)
-                .map(|(client, _)| {
-                    client.send(FetchWhoList {
-                        span: msg.span.clone(),
-                        query: String::new(),
-                    })
-                })
-                .collect::<FuturesUnordered<_>>();
+    fn handle(&mut self, msg: FetchSessions, _ctx: &mut Self::Context) -> Self::Result {
+        let mut sessions: Vec<_> = self
+            .clients
+            .values()
+            .filter(|conn| conn.user_id == msg.user_id)
+            .cloned()
+            .collect();
+        sessions.sort_by_key(|conn| conn.at);
 
-            let init = WhoList {
-                query: msg.query,
-                list: Vec::new(),
-            };
-            Box::pin(futures.fold(init, |mut acc, item| {
+        MessageResult(sessions)
+    }
+}
+
+impl Handler<LogoutSession> for Server {
+    type Result = bool;
+
+    #[instrument(parent = &msg.span, skip_all)]
+    fn handle(&mut self, msg: LogoutSession, _ctx: &mut Self::Context) -> Self::Result {
+        let Some((handle, conn)) = self.clients.iter().find(|(_, conn)| {
+            conn.user_id == msg.user_id && conn.session_id.eq_ignore_ascii_case(&msg.session_id)
+        }) else {
+            return false;
+        };
+
+        handle.do_send(ForceDisconnect {
+            span: msg.span,
+            user: conn.nick(),
+            comment: "Logged out from another session".to_string(),
+        });
+
+        true
+    }
+}
+
+impl Handler<DisconnectAccount> for Server {
+    type Result = ();
+
+    #[instrument(parent = &msg.span, skip_all)]
+    fn handle(&mut self, msg: DisconnectAccount, _ctx: &mut Self::Context) -> Self::Result {
+        for (handle, conn) in &self.clients {
+            if conn.user_id != msg.user_id {
+                continue;
+            }
+
+            handle.do_send(ForceDisconnect {
+                span: Span::current(),
+                user: conn.nick(),
+                comment: msg.reason.clone(),
+            });
+        }
+    }
+}
+
+/// Forwards a tap request onto the client being tapped.
+impl Handler<TapClient> for Server {
+    type Result = ResponseFuture<<TapClient as actix::Message>::Result>;
+
+    #[instrument(parent = &msg.span, skip_all)]
+    fn handle(&mut self, msg: TapClient, _ctx: &mut Self::Context) -> Self::Result {
+        let Some((handle, _)) = self.find_client(&msg.nick) else {
+            return Box::pin(future::ready(Err(NoSuchNick { nick: msg.nick })));
+        };
+
+        let handle = handle.clone();
+        Box::pin(async move { handle.send(msg).await.unwrap() })
+    }
+}
+
+/// Forwards an operator's `SPY` on to the channel, recording every use to the audit log.
+impl Handler<SpyChannel> for Server {
+    type Result = ResponseFuture<<SpyChannel as actix::Message>::Result>;
+
+    #[instrument(parent = &msg.span, skip_all)]
+    fn handle(&mut self, msg: SpyChannel, _ctx: &mut Self::Context) -> Self::Result {
+        info!(
+            target: "audit",
+            oper = %msg.connection.to_nick(),
+            channel = %msg.channel,
+            enabled = msg.enabled,
+            "Operator used SPY"
+        );
+
+        let Some(channel) = self.channels.get(&msg.channel).cloned() else {
+            return Box::pin(future::ready(Err(NoSuchChannel {
+                channel: msg.channel,
+            })));
+        };
+
+        let name = msg.channel.clone();
+        Box::pin(async move {
+            // the channel may have closed since we last heard from it
+            channel
+                .send(msg)
+                .await
+                .unwrap_or(Err(NoSuchChannel { channel: name }))
+        })
+    }
+}
+
+impl Handler<FetchWhoList> for Server {
+    type Result = ResponseFuture<<FetchWhoList as actix::Message>::Result>;
+
+    #[instrument(parent = &msg.span, skip_all)]
+    fn handle(&mut self, msg: FetchWhoList, _ctx: &mut Self::Context) -> Self::Result {
+        if let Some(channel) = self.channels.get(&msg.query).cloned() {
+            let fetch = channel.send(ChannelFetchWhoList {
+                span: msg.span,
+                requester: self.hidden_from(&msg.client),
+                multi_prefix: msg.multi_prefix,
+            });
+
+            Box::pin(async move {
+                WhoList {
+                    list: vec![fetch.await.unwrap()],
+                    query: msg.query,
+                }
+            })
+        } else {
+            let futures = self
+                .find_client(&msg.query)
+                .into_iter()
+                .map(|(client, _)| {
+                    client.send(FetchWhoList {
+                        span: msg.span.clone(),
+                        client: msg.client.clone(),
+                        query: String::new(),
+                        multi_prefix: msg.multi_prefix,
+                    })
+                })
+                .collect::<FuturesUnordered<_>>();
+
+            let init = WhoList {
+                query: msg.query,
+                list: Vec::new(),
+            };
+            Box::pin(futures.fold(init, |mut acc, item| {
                 acc.list.extend(item.unwrap().list);
                 acc
             }))
@@ -386,15 +1042,50 @@ impl Handler<FetchWhoList> for Server {
     }
 }
 
+/// Fetches the members of a channel for `NAMES`, for channels the client isn't in. Unknown
+/// channels are listed without any members.
+impl Handler<FetchChannelMemberList> for Server {
+    type Result = ResponseFuture<<FetchChannelMemberList as actix::Message>::Result>;
+
+    #[instrument(parent = &msg.span, skip_all)]
+    fn handle(&mut self, msg: FetchChannelMemberList, _ctx: &mut Self::Context) -> Self::Result {
+        let Some(channel) = self.channels.get(&msg.channel) else {
+            return Box::pin(future::ready(ChannelNamesList::empty(msg.channel)));
+        };
+
+        let fetch = channel.send(ChannelMemberList {
+            span: Span::current(),
+            requester: self.hidden_from(&msg.client),
+        });
+
+        Box::pin(async move { fetch.await.unwrap() })
+    }
+}
+
 impl Handler<ChannelList> for Server {
     type Result = ResponseFuture<<ChannelList as actix::Message>::Result>;
 
     #[instrument(parent = &msg.span, skip_all)]
     fn handle(&mut self, msg: ChannelList, _ctx: &mut Self::Context) -> Self::Result {
+        let list_metadata = self.config.channels.list_metadata;
+        let query = msg.query;
+
+        // secret and private channels are only listed to their members and operators, `None` if
+        // every channel can be listed
+        let member_of = self.hidden_from(&msg.client).map(|client| {
+            self.memberships
+                .get(&client)
+                .map(|channels| channels.keys().cloned().collect::<HashSet<_>>())
+                .unwrap_or_default()
+        });
+
+        // filter by name up front, so only the channels that could match are asked for the
+        // rest of their state
         let fut = self
             .channels
-            .values()
-            .map(|channel| {
+            .iter()
+            .filter(|(name, _)| query.matches_name(name))
+            .map(|(_, channel)| {
                 let fetch_topic = channel.send(ChannelFetchTopic {
                     span: Span::current(),
                     skip_on_none: true,
@@ -402,22 +1093,54 @@ impl Handler<ChannelList> for Server {
 
                 let fetch_members = channel.send(ChannelMemberList {
                     span: Span::current(),
+                    requester: None,
+                });
+
+                let fetch_metadata = channel.send(ChannelFetchMetadata {
+                    span: Span::current(),
+                });
+
+                let fetch_created_at = channel.send(ChannelFetchCreationTime {
+                    span: Span::current(),
                 });
 
-                futures::future::try_join(fetch_topic, fetch_members)
+                futures::future::try_join4(
+                    fetch_topic,
+                    fetch_members,
+                    fetch_metadata,
+                    fetch_created_at,
+                )
             })
             .collect::<FuturesOrdered<_>>()
-            .map(|res| {
-                let (topic, members) = res.unwrap();
+            .map(move |res| {
+                let (topic, members, metadata, created_at) = res.unwrap();
+                let client_count = members.nick_list.len();
+
+                if members.visibility.is_hidden()
+                    && member_of
+                        .as_ref()
+                        .is_some_and(|v| !v.contains(&topic.channel_name))
+                {
+                    return None;
+                }
+
+                if !query.matches_channel(
+                    client_count,
+                    created_at.created_at,
+                    topic.topic.as_ref().map(|v| v.set_time),
+                ) {
+                    return None;
+                }
 
-                response::ChannelListItem {
+                Some(response::ChannelListItem {
                     channel_name: topic.channel_name,
-                    client_count: members.nick_list.len(),
+                    client_count,
                     topic: topic.topic.map(|v| v.topic),
-                }
+                    metadata: metadata.list_summary().filter(|_| list_metadata),
+                })
             })
             .fold(response::ChannelList::default(), |mut acc, v| {
-                acc.members.push(v);
+                acc.members.extend(v);
                 acc
             });
 
@@ -432,12 +1155,85 @@ impl Handler<ServerListUsers> for Server {
         MessageResult(ListUsers {
             current_clients: self.clients.len(),
             max_clients: self.max_clients,
-            operators_online: 0,
+            operators_online: self
+                .clients
+                .values()
+                .filter(|conn| conn.mode().contains(UserMode::OPER))
+                .count(),
             channels_formed: self.channels.len(),
         })
     }
 }
 
+impl Handler<ServerStats> for Server {
+    type Result = ResponseFuture<<ServerStats as actix::Message>::Result>;
+
+    #[instrument(parent = &msg.span, skip_all)]
+    fn handle(&mut self, msg: ServerStats, _ctx: &mut Self::Context) -> Self::Result {
+        let report = match msg.query.as_str() {
+            "u" => StatsReport::Uptime {
+                uptime: (Utc::now() - self.started_at).to_std().unwrap_or_default(),
+                current_clients: self.clients.len(),
+                max_clients: self.max_clients,
+                write_errors: WRITE_ERRORS.load(Ordering::Relaxed),
+                traffic: TOTAL_TRAFFIC.snapshot(),
+            },
+            "f" => StatsReport::AddressFamilies {
+                v4: self
+                    .clients
+                    .values()
+                    .filter(|c| c.family == AddressFamily::V4)
+                    .count(),
+                v6: self
+                    .clients
+                    .values()
+                    .filter(|c| c.family == AddressFamily::V6)
+                    .count(),
+            },
+            "m" => StatsReport::Commands(COMMAND_USAGE.snapshot()),
+            "o" => StatsReport::Operators(
+                self.config
+                    .opers
+                    .iter()
+                    .map(|oper| oper.name.to_string())
+                    .collect(),
+            ),
+            "k" | "g" => StatsReport::Bans(self.bans.list()),
+            "l" => {
+                // the counters are owned by each client, so fetch them from every client in turn
+                let futures = self
+                    .clients
+                    .iter()
+                    .map(|(handle, conn)| {
+                        let conn = conn.clone();
+
+                        handle
+                            .send(FetchClientTraffic {
+                                span: Span::current(),
+                            })
+                            .map_ok(move |traffic| (conn, traffic))
+                    })
+                    .collect::<FuturesUnordered<_>>();
+
+                return Box::pin(async move {
+                    let links = futures.filter_map(Result::ok).collect::<Vec<_>>().await;
+
+                    Stats {
+                        query: msg.query,
+                        report: StatsReport::Links(links),
+                    }
+                });
+            }
+            _ => StatsReport::Unsupported,
+        };
+
+        Box::pin(future::ready(Stats {
+            query: msg.query,
+            report,
+        }))
+    }
+}
+
 impl Handler<ServerAdminInfo> for Server {
     type Result = MessageResult<ServerAdminInfo>;
 
@@ -454,46 +1250,110 @@ impl Handler<PrivateMessage> for Server {
     type Result = ();
 
     #[instrument(parent = &msg.span, skip_all)]
-    fn handle(&mut self, msg: PrivateMessage, _ctx: &mut Self::Context) -> Self::Result {
+    fn handle(&mut self, msg: PrivateMessage, ctx: &mut Self::Context) -> Self::Result {
         let Some(source) = self.clients.get(&msg.from) else {
             // user is not yet registered with the server
             return;
         };
 
-        let mut seen_by_user = false;
-
-        // TODO: O(1) lookup of users by id
-        for (target, target_conn) in self.clients.iter().filter(|(handle, connection)| {
-            connection.user_id == msg.destination && msg.from != **handle
-        }) {
-            target.do_send(Broadcast {
-                message: Message {
-                    tags: None,
-                    prefix: Some(source.to_nick()),
-                    command: match msg.kind {
-                        MessageKind::Normal => {
-                            Command::PRIVMSG(target_conn.nick.clone(), msg.message.clone())
-                        }
-                        MessageKind::Notice => {
-                            Command::NOTICE(target_conn.nick.clone(), msg.message.clone())
-                        }
-                    },
-                },
-                span: msg.span.clone(),
-            });
+        // brand-new accounts have to wait a while before they're able to message other users,
+        // which keeps spammers from messaging users as soon as they connect
+        let min_account_age = self.config.limits.private_message_min_account_age;
+        if source.user_id != msg.destination
+            && !source.mode().contains(UserMode::OPER)
+            && source.time_since_account_created() < min_account_age
+        {
+            for message in
+                AccountTooNew(msg.destination_nick, min_account_age).into_messages(&source.nick())
+            {
+                msg.from.do_send(Broadcast {
+                    message,
+                    span: msg.span.clone(),
+                });
+            }
 
-            seen_by_user = true;
+            return;
         }
 
-        if !seen_by_user {
-            self.persistence
-                .do_send(crate::persistence::events::PrivateMessage {
-                    sender: source.to_nick().to_string(),
-                    receiver: msg.destination,
-                    message: msg.message,
-                    kind: msg.kind,
+        // if configured, users need to share a channel with the target before they're able
+        // to message them
+        if self
+            .config
+            .limits
+            .require_shared_channel_for_private_messages
+            && source.user_id != msg.destination
+            && !source.mode().contains(UserMode::OPER)
+        {
+            let fut = self
+                .persistence
+                .send(FetchSharesChannel {
+                    user_id: source.user_id,
+                    other_user_id: msg.destination,
+                })
+                .into_actor(self)
+                .map(move |res, this, _ctx| {
+                    if res.unwrap() {
+                        this.route_private_message(msg);
+                        return;
+                    }
+
+                    let Some(source) = this.clients.get(&msg.from) else {
+                        return;
+                    };
+
+                    for message in
+                        NoSharedChannel(msg.destination_nick).into_messages(&source.nick())
+                    {
+                        msg.from.do_send(Broadcast {
+                            message,
+                            span: msg.span.clone(),
+                        });
+                    }
                 });
+
+            ctx.spawn(fut);
+            return;
         }
+
+        self.route_private_message(msg);
+    }
+}
+
+/// Updates (or lists) the user's caller-id accept list.
+impl Handler<UpdateAcceptList> for Server {
+    type Result = MessageResult<UpdateAcceptList>;
+
+    #[instrument(parent = &msg.span, skip_all)]
+    fn handle(&mut self, msg: UpdateAcceptList, _ctx: &mut Self::Context) -> Self::Result {
+        let Some(conn) = self.clients.get(&msg.client) else {
+            return MessageResult(AcceptList::default());
+        };
+
+        let state = self.caller_id.entry(conn.user_id).or_default();
+        let mut out = AcceptList::default();
+        let mut list_requested = false;
+
+        for change in msg.changes {
+            if change == "*" {
+                list_requested = true;
+            } else if let Some(nick) = change.strip_prefix('-') {
+                if state.accepted.remove(&casemapping::fold(nick)).is_none() {
+                    out.errors.push(AcceptListError::NotFound(nick.to_string()));
+                }
+            } else if state.is_accepted(&change) {
+                out.errors.push(AcceptListError::AlreadyExists(change));
+            } else if state.accepted.len() >= CallerIdState::MAX_ACCEPTED {
+                out.errors.push(AcceptListError::Full(change));
+            } else {
+                state.accepted.insert(casemapping::fold(&change), change);
+            }
+        }
+
+        if list_requested {
+            out.list = Some(state.accepted.values().cloned().collect());
+        }
+
+        MessageResult(out)
     }
 }
 
@@ -503,18 +1363,21 @@ impl Handler<Gline> for Server {
     fn handle(&mut self, msg: Gline, _ctx: &mut Self::Context) -> Self::Result {
         let created = Utc::now();
         let expires = msg.duration.map(|v| created + v);
+        let mask = match msg.mask {
+            BanMask::HostMask(mask) => BanMask::HostMask(mask.with_canonical_host()),
+            v @ BanMask::Account(_) => v,
+        };
+
+        let ban = response::ServerBan {
+            mask: mask.clone(),
+            requester: msg.requester.user.to_string(),
+            reason: msg.reason.clone(),
+            created,
+            expires,
+        };
 
         // TODO: return ack msg
-        self.bans.insert(
-            &msg.mask,
-            response::ServerBan {
-                mask: msg.mask.clone(),
-                requester: msg.requester.user.to_string(),
-                reason: msg.reason.clone(),
-                created,
-                expires,
-            },
-        );
+        self.bans.insert(ban);
 
         // TODO: stop looping over all users
         let comment = format!(
@@ -522,18 +1385,24 @@ impl Handler<Gline> for Server {
             msg.reason.as_deref().unwrap_or("no reason given")
         );
         for (handle, user) in &self.clients {
-            if !self.bans.get(&user.to_host_mask()).is_empty() {
+            if let Some(reason) = self.bans.check_connection(user) {
+                // tell the user why they're being disconnected, the kill comment is seen by
+                // everyone so it's kept brief
+                handle.do_send(Broadcast {
+                    message: MessageBuilder::bare().command(Command::ERROR(reason)),
+                    span: Span::current(),
+                });
                 handle.do_send(KillUser {
                     span: Span::current(),
-                    killer: msg.requester.nick.to_string(),
+                    killer: msg.requester.nick(),
                     comment: comment.to_string(),
-                    killed: user.nick.to_string(),
+                    killed: user.nick(),
                 });
             }
         }
 
         self.persistence.do_send(ServerBan {
-            mask: msg.mask,
+            mask,
             requester: msg.requester.user_id,
             reason: msg.reason.unwrap_or_default(),
             created,
@@ -548,7 +1417,6 @@ impl Handler<RemoveGline> for Server {
     fn handle(&mut self, msg: RemoveGline, _ctx: &mut Self::Context) -> Self::Result {
         // TODO: return ack msg
         self.bans.remove(&msg.mask);
-
         self.persistence.do_send(ServerRemoveBan { mask: msg.mask });
     }
 }
@@ -557,7 +1425,59 @@ impl Handler<ListGline> for Server {
     type Result = MessageResult<ListGline>;
 
     fn handle(&mut self, _msg: ListGline, _ctx: &mut Self::Context) -> Self::Result {
-        MessageResult(self.bans.iter().map(|(_, v)| v.clone()).collect())
+        MessageResult(self.bans.list())
+    }
+}
+
+impl Handler<Shun> for Server {
+    type Result = ();
+
+    fn handle(&mut self, msg: Shun, _ctx: &mut Self::Context) -> Self::Result {
+        let created = Utc::now();
+        let expires = msg.duration.map(|v| created + v);
+
+        // TODO: return ack msg
+        self.shuns.insert(
+            &msg.mask,
+            response::ServerBan {
+                mask: BanMask::HostMask(msg.mask.clone()),
+                requester: msg.requester.user.to_string(),
+                reason: msg.reason.clone(),
+                created,
+                expires,
+            },
+        );
+
+        self.update_shunned_clients();
+
+        self.persistence.do_send(ServerShun {
+            mask: msg.mask,
+            requester: msg.requester.user_id,
+            reason: msg.reason.unwrap_or_default(),
+            created,
+            expires,
+        });
+    }
+}
+
+impl Handler<RemoveShun> for Server {
+    type Result = ();
+
+    fn handle(&mut self, msg: RemoveShun, _ctx: &mut Self::Context) -> Self::Result {
+        // TODO: return ack msg
+        self.shuns.remove(&msg.mask);
+        self.update_shunned_clients();
+
+        self.persistence
+            .do_send(ServerRemoveShun { mask: msg.mask });
+    }
+}
+
+impl Handler<ListShun> for Server {
+    type Result = MessageResult<ListShun>;
+
+    fn handle(&mut self, _msg: ListShun, _ctx: &mut Self::Context) -> Self::Result {
+        MessageResult(self.shuns.iter().map(|(_, v)| v.clone()).collect())
     }
 }
 
@@ -565,22 +1485,319 @@ impl Actor for Server {
     type Context = Context<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
+        self.persistence.do_send(SubscribeDatabaseHealth {
+            subscriber: ctx.address().recipient(),
+        });
         ctx.wait(self.load_server_ban_list());
+        ctx.wait(self.load_server_shun_list());
         ctx.run_interval(Duration::from_secs(30), Self::remove_expired_bans);
+        ctx.run_interval(Duration::from_secs(300), Self::promote_channel_successors);
     }
 }
 
 impl Server {
+    /// Looks up an online client by their nick.
+    fn find_client(&self, nick: &str) -> Option<(&Addr<Client>, &Arc<InitiatedConnection>)> {
+        let handle = self.nicks.get(&casemapping::fold(nick))?;
+        self.clients.get_key_value(handle)
+    }
+
+    /// Returns the client invisible users, and secret and private channels, should be hidden from.
+    /// Operators can see everything, so `None` is returned for them.
+    fn hidden_from(&self, client: &Addr<Client>) -> Option<Addr<Client>> {
+        let is_oper = self
+            .clients
+            .get(client)
+            .is_some_and(|v| v.mode().contains(UserMode::OPER));
+
+        (!is_oper).then(|| client.clone())
+    }
+
+    /// Returns true if the nick is used by one of services' own clients.
+    fn is_services_nick(&self, nick: &str) -> bool {
+        self.services
+            .as_ref()
+            .is_some_and(|services| services.nicks.contains(&casemapping::fold(nick)))
+    }
+
+    /// Keeps services, if linked, up to date with the users connected to the server.
+    fn notify_services(&self, change: ServicesUserChanged) {
+        if let Some(services) = &self.services {
+            services.link.do_send(change);
+        }
+    }
+
+    /// Removes a nick from the nick index, if it still refers to the given client.
+    fn unindex_nick(&mut self, nick: &str, client: &Addr<Client>) {
+        let key = casemapping::fold(nick);
+
+        if self.nicks.get(&key) == Some(client) {
+            self.nicks.remove(&key);
+        }
+    }
+
+    /// Returns the handle to the channel, starting it up if it isn't already running.
+    fn channel_handle(&mut self, channel_name: &str, ctx: &mut Context<Self>) -> Addr<Channel> {
+        self.channels
+            .entry(channel_name.to_string())
+            .or_insert_with(|| {
+                let arbiter = self
+                    .channel_arbiters
+                    .choose(&mut rand::thread_rng())
+                    .map_or_else(Arbiter::current, Arbiter::handle);
+
+                let channel_name = channel_name.to_string();
+                let server = ctx.address();
+                let persistence = self.persistence.clone();
+                let temporary = self.config.channels.is_temporary(&channel_name);
+
+                let channel = move |_ctx: &mut Context<Channel>| Channel {
+                    name: channel_name,
+                    permissions: HostMaskMap::new(),
+                    permissions_version: 0,
+                    permission_details: HashMap::new(),
+                    clients: HashMap::new(),
+                    spies: HashMap::new(),
+                    topic: None,
+                    modes: ChannelModeState::default(),
+                    invites: HashSet::new(),
+                    metadata: ChannelMetadata::default(),
+                    who_cache: None,
+                    server,
+                    persistence,
+                    channel_id: ChannelId(0),
+                    created_at: Utc::now(),
+                    temporary,
+                };
+
+                // temporary channels stop themselves once empty, so mustn't be restarted
+                if temporary {
+                    Channel::start_in_arbiter(&arbiter, channel)
+                } else {
+                    Supervisor::start_in_arbiter(&arbiter, channel)
+                }
+            })
+            .clone()
+    }
+
+    /// Returns the restriction preventing the user from creating the channel they're joining,
+    /// `None` if the channel is already running or the user is allowed to create it. The
+    /// channel's namespace is checked before the network-wide minimum account age.
+    fn creation_restriction(&self, msg: &ChannelJoin) -> Option<CreationRestricted> {
+        if self.channels.contains_key(&msg.channel_name) {
+            return None;
+        }
+
+        let is_oper = msg.connection.mode().contains(UserMode::OPER);
+
+        if let Some(namespace) = self.config.channels.namespace_for(&msg.channel_name) {
+            if let Some(restriction) = namespace.restriction(is_oper, msg.connection.account_age())
+            {
+                return Some(CreationRestricted {
+                    channel: msg.channel_name.to_string(),
+                    prefix: Some(namespace.prefix.to_string()),
+                    restriction,
+                });
+            }
+        }
+
+        let min_account_age = self.config.limits.channel_creation_min_account_age;
+
+        (!is_oper && msg.connection.time_since_account_created() < min_account_age).then(|| {
+            CreationRestricted {
+                channel: msg.channel_name.to_string(),
+                prefix: None,
+                restriction: NamespaceRestriction::AccountTooNew(min_account_age),
+            }
+        })
+    }
+
+    /// Returns true if the given nick or channel is currently being held.
+    fn is_held(&self, name: &str) -> bool {
+        self.holds
+            .get(&casemapping::fold(name))
+            .map_or(false, |expires| *expires > Instant::now())
+    }
+
+    /// Holds a nick or channel for `duration`, extending any existing hold on it.
+    fn hold(&mut self, name: &str, duration: Duration, ctx: &mut Context<Self>) {
+        let key = casemapping::fold(name);
+        let expires = Instant::now() + duration;
+
+        info!(%name, ?duration, "Holding resource");
+
+        let current = self.holds.entry(key.clone()).or_insert(expires);
+        *current = (*current).max(expires);
+
+        // only remove the hold if it hasn't since been extended
+        ctx.run_later(duration, move |this, _ctx| {
+            if this
+                .holds
+                .get(&key)
+                .map_or(false, |expires| *expires <= Instant::now())
+            {
+                this.holds.remove(&key);
+            }
+        });
+    }
+
+    /// Called once the config has been swapped out for a new one, informs the clients whose
+    /// MOTD differs from the one they'd have been sent under `previous` if the server is
+    /// configured to do so.
+    pub fn notify_motd_changed(&self, previous: &Config) {
+        if !self.config.notify_motd_change {
+            return;
+        }
+
+        for (handle, connection) in &self.clients {
+            let class = &connection.class.name;
+            if self.config.motd_for_class(class) == previous.motd_for_class(class) {
+                continue;
+            }
+
+            let nick = connection.nick();
+            let messages = if connection.capabilities.contains(Capability::MOTD_CHANGED) {
+                Motd::new(self, &connection.class).into_messages(&nick)
+            } else {
+                vec![MessageBuilder::server().command(Command::NOTICE(
+                    nick.clone(),
+                    "The message of the day has changed, use MOTD to read it".to_string(),
+                ))]
+            };
+
+            for message in messages {
+                handle.do_send(Broadcast {
+                    message,
+                    span: Span::current(),
+                });
+            }
+        }
+    }
+
+    fn is_shunned(&self, connection: &InitiatedConnection) -> bool {
+        !self.shuns.get(&connection.to_host_mask()).is_empty()
+    }
+
+    /// Informs every client of whether they're currently shunned.
+    fn update_shunned_clients(&self) {
+        // TODO: stop looping over all users
+        for (handle, connection) in &self.clients {
+            handle.do_send(ClientShunned(self.is_shunned(connection)));
+        }
+    }
+
+    /// Delivers a private message to all of the target's sessions, or persists it for later if
+    /// the target isn't currently connected.
+    fn route_private_message(&mut self, msg: PrivateMessage) {
+        let Some(source) = self.clients.get(&msg.from) else {
+            return;
+        };
+
+        // if the target is in caller-id mode, only users on their accept list (and opers) are
+        // able to message them
+        let target_has_caller_id = self.clients.values().any(|conn| {
+            conn.user_id == msg.destination && conn.mode().contains(UserMode::CALLER_ID)
+        });
+
+        if target_has_caller_id
+            && source.user_id != msg.destination
+            && !source.mode().contains(UserMode::OPER)
+        {
+            let state = self.caller_id.entry(msg.destination).or_default();
+
+            let source_nick = source.nick();
+
+            if !state.is_accepted(&source_nick) {
+                let notified = state.should_notify(&casemapping::fold(&source_nick));
+
+                if notified {
+                    for (target, target_conn) in self
+                        .clients
+                        .iter()
+                        .filter(|(_, conn)| conn.user_id == msg.destination)
+                    {
+                        for message in
+                            CallerIdNotify(source.clone()).into_messages(&target_conn.nick())
+                        {
+                            target.do_send(Broadcast {
+                                message,
+                                span: msg.span.clone(),
+                            });
+                        }
+                    }
+                }
+
+                let rejection = CallerIdRejected {
+                    target: msg.destination_nick,
+                    notified,
+                };
+
+                for message in rejection.into_messages(&source_nick) {
+                    msg.from.do_send(Broadcast {
+                        message,
+                        span: msg.span.clone(),
+                    });
+                }
+
+                return;
+            }
+        }
+
+        let mut seen_by_user = false;
+        let sent_at = Utc::now();
+
+        let build_message = |target: String| {
+            MessageBuilder::user(source.to_nick())
+                .tags(
+                    TagBuilder::default()
+                        .insert(server_time_tag(sent_at))
+                        .extend(msg.tags.iter().cloned()),
+                )
+                .command(msg.kind.into_command(target, msg.message.clone()))
+        };
+
+        // TODO: O(1) lookup of users by id
+        for (target, target_conn) in self.clients.iter().filter(|(handle, connection)| {
+            connection.user_id == msg.destination && msg.from != **handle
+        }) {
+            target.do_send(Broadcast {
+                message: build_message(target_conn.nick()),
+                span: msg.span.clone(),
+            });
+
+            seen_by_user = true;
+        }
+
+        if source.capabilities.contains(Capability::ECHO_MESSAGE) {
+            msg.from.do_send(Broadcast {
+                message: build_message(msg.destination_nick.clone()),
+                span: msg.span.clone(),
+            });
+        }
+
+        // messages are kept after being delivered, so they can be fetched with CHATHISTORY
+        if msg.kind.is_persisted() {
+            self.persistence
+                .do_send(crate::persistence::events::PrivateMessage {
+                    sender: source.to_nick().to_string(),
+                    sender_user: source.user_id,
+                    receiver: msg.destination,
+                    message: msg.message,
+                    kind: msg.kind,
+                    delivered: seen_by_user,
+                });
+        }
+    }
+
     fn load_server_ban_list(&mut self) -> impl ActorFuture<Self, Output = ()> + 'static {
         self.persistence
             .send(crate::persistence::events::ServerListBan)
             .into_actor(self)
             .map(|res, this, ctx| match res {
                 Ok(bans) => {
-                    this.bans = bans
-                        .into_iter()
-                        .map(|v| (v.mask.clone(), v.into()))
-                        .collect();
+                    for ban in bans {
+                        this.bans.insert(ban.into());
+                    }
                 }
                 Err(error) => {
                     error!(%error, "Failed to fetch bans");
@@ -589,32 +1806,85 @@ impl Server {
             })
     }
 
+    fn load_server_shun_list(&mut self) -> impl ActorFuture<Self, Output = ()> + 'static {
+        self.persistence
+            .send(ServerListShun)
+            .into_actor(self)
+            .map(|res, this, ctx| match res {
+                Ok(shuns) => {
+                    for shun in shuns {
+                        if let BanMask::HostMask(mask) = shun.mask.clone() {
+                            this.shuns.insert(&mask, shun.into());
+                        }
+                    }
+                }
+                Err(error) => {
+                    error!(%error, "Failed to fetch shuns");
+                    ctx.terminate();
+                }
+            })
+    }
+
+    /// Hands over channels whose founder has dropped their account to the successor the founder
+    /// designated, letting the new founder know if they're online.
+    fn promote_channel_successors(&mut self, ctx: &mut Context<Self>) {
+        let fut = self.persistence.send(PromoteChannelSuccessors);
+
+        ctx.spawn(fut.into_actor(self).map(|promoted, this, _ctx| {
+            for (channel, account) in promoted.unwrap() {
+                info!(%channel, %account, "Promoted successor to channel founder");
+
+                for (handle, connection) in &this.clients {
+                    if connection.user != account {
+                        continue;
+                    }
+
+                    for message in ChannelSuccessor::Promoted(channel.clone())
+                        .into_messages(&connection.nick())
+                    {
+                        handle.do_send(Broadcast {
+                            message,
+                            span: Span::current(),
+                        });
+                    }
+                }
+            }
+        }));
+    }
+
     fn remove_expired_bans(&mut self, _ctx: &mut Context<Self>) {
-        let mut expired = Vec::new();
+        let now = Utc::now();
+        let is_expired = |ban: &response::ServerBan| ban.expires.is_some_and(|v| v <= now);
 
-        for (mask, ban) in self.bans.iter() {
-            let Some(expires_at) = ban.expires else {
-                continue;
-            };
+        for mask in self.bans.expired(now) {
+            info!("Removing expired ban on {mask}");
 
-            if expires_at > Utc::now() {
-                continue;
-            }
+            self.bans.remove(&mask);
+            self.persistence.do_send(ServerRemoveBan { mask });
+        }
 
-            let Ok(mask) = HostMask::try_from(mask.as_str()) else {
-                continue;
-            };
+        let expired_shuns: Vec<_> = self
+            .shuns
+            .iter()
+            .map(|(_, shun)| shun)
+            .filter(|shun| is_expired(shun))
+            .filter_map(|shun| match &shun.mask {
+                BanMask::HostMask(mask) => Some(mask.clone()),
+                BanMask::Account(_) => None,
+            })
+            .collect();
 
-            expired.push(mask.into_owned());
+        if expired_shuns.is_empty() {
+            return;
         }
 
-        for mask in expired {
-            info!("Removing expired ban on {mask}");
+        for mask in expired_shuns {
+            info!("Removing expired shun on {mask}");
 
-            self.bans.remove(&mask);
-            self.persistence.do_send(ServerRemoveBan {
-                mask: mask.into_owned(),
-            });
+            self.shuns.remove(&mask);
+            self.persistence.do_send(ServerRemoveShun { mask });
         }
+
+        self.update_shunned_clients();
     }
 }
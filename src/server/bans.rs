@@ -0,0 +1,200 @@
+//! The network bans currently in place, shared between the `Server`, which manages them, and
+//! the listeners, which check connecting users against them without going through the
+//! `Server`'s mailbox.
+
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{Arc, RwLock},
+};
+
+use chrono::{DateTime, Utc};
+
+use crate::{
+    config::{BanConfig, Cidr},
+    connection::InitiatedConnection,
+    host_mask::{BanMask, HostMask, HostMaskMap},
+    server::response::ServerBan,
+};
+
+/// A handle to the network bans, cheap to clone. Lookups only take a read lock, so any number of
+/// connections can be checked at once.
+#[derive(Clone)]
+pub struct NetworkBans(Arc<RwLock<Bans>>);
+
+struct Bans {
+    hosts: HostMaskMap<ServerBan>,
+    /// Bans with a CIDR range as their host, keyed by the range so an IP only has to be looked
+    /// up once for each prefix length. The bans' nick and username are matched by a map of
+    /// their masks with the host replaced by `*`.
+    cidrs: HashMap<Cidr, HostMaskMap<ServerBan>>,
+    accounts: HashMap<String, ServerBan>,
+    /// How affected users are told about their ban, kept up to date as the config is reloaded
+    config: BanConfig,
+    network_name: String,
+}
+
+impl NetworkBans {
+    #[must_use]
+    pub fn new(config: BanConfig, network_name: String) -> Self {
+        Self(Arc::new(RwLock::new(Bans {
+            hosts: HostMaskMap::new(),
+            cidrs: HashMap::new(),
+            accounts: HashMap::new(),
+            config,
+            network_name,
+        })))
+    }
+
+    /// Replaces the settings used to render the message shown to banned users.
+    pub fn set_config(&self, config: BanConfig, network_name: String) {
+        let mut bans = self.0.write().unwrap();
+        bans.config = config;
+        bans.network_name = network_name;
+    }
+
+    /// Adds a ban, replacing any existing ban on the same mask.
+    pub fn insert(&self, ban: ServerBan) {
+        let mut bans = self.0.write().unwrap();
+
+        match ban.mask.clone() {
+            BanMask::HostMask(mask) => match cidr(&mask) {
+                Some(cidr) => bans
+                    .cidrs
+                    .entry(cidr)
+                    .or_default()
+                    .insert(&mask.with_host("*"), ban),
+                None => bans.hosts.insert(&mask, ban),
+            },
+            BanMask::Account(account) => {
+                bans.accounts.insert(account, ban);
+            }
+        }
+    }
+
+    pub fn remove(&self, mask: &BanMask) {
+        let mut bans = self.0.write().unwrap();
+
+        match mask {
+            BanMask::HostMask(mask) => match cidr(mask) {
+                Some(cidr) => {
+                    if let Some(masks) = bans.cidrs.get_mut(&cidr) {
+                        masks.remove(&mask.with_host("*"));
+
+                        if masks.is_empty() {
+                            bans.cidrs.remove(&cidr);
+                        }
+                    }
+                }
+                None => {
+                    bans.hosts.remove(mask);
+                }
+            },
+            BanMask::Account(account) => {
+                bans.accounts.remove(account);
+            }
+        }
+    }
+
+    /// Returns every ban, host bans first.
+    #[must_use]
+    pub fn list(&self) -> Vec<ServerBan> {
+        let bans = self.0.read().unwrap();
+
+        bans.iter().cloned().collect()
+    }
+
+    /// Returns the masks of every ban that expired by `now`, which the caller is responsible
+    /// for removing.
+    #[must_use]
+    pub fn expired(&self, now: DateTime<Utc>) -> Vec<BanMask> {
+        let bans = self.0.read().unwrap();
+
+        bans.iter()
+            .filter(|ban| ban.expires.is_some_and(|v| v <= now))
+            .map(|ban| ban.mask.clone())
+            .collect()
+    }
+
+    /// Checks the connection against the bans, returning the message to reject it with if it's
+    /// banned.
+    #[must_use]
+    pub fn check_connection(&self, connection: &InitiatedConnection) -> Option<String> {
+        let bans = self.0.read().unwrap();
+        let ban = bans.find(connection)?;
+
+        Some(ban.render(&bans.config, &bans.network_name))
+    }
+
+    /// Checks the account against the bans, returning the message to reject the user with if
+    /// it's banned.
+    #[must_use]
+    pub fn check_account(&self, account: &str) -> Option<String> {
+        let bans = self.0.read().unwrap();
+        let ban = bans.accounts.get(account)?;
+
+        Some(ban.render(&bans.config, &bans.network_name))
+    }
+}
+
+impl Bans {
+    /// Every ban, host bans first.
+    fn iter(&self) -> impl Iterator<Item = &ServerBan> {
+        self.hosts
+            .iter()
+            .chain(self.cidrs.values().flat_map(HostMaskMap::iter))
+            .map(|(_, v)| v)
+            .chain(self.accounts.values())
+    }
+
+    /// Finds a ban matching the given connection, matching against both the user's cloaked
+    /// host and their IP address. Bans with a CIDR range as their host match any IP within the
+    /// range.
+    fn find(&self, connection: &InitiatedConnection) -> Option<&ServerBan> {
+        if let Some(ban) = self.accounts.get(&connection.user) {
+            return Some(ban);
+        }
+
+        if let Some(ban) = self
+            .hosts
+            .get(&connection.to_host_mask())
+            .into_iter()
+            .next()
+        {
+            return Some(ban);
+        }
+
+        let ip = connection.host.ip().to_canonical();
+        let ip_host = ip.to_string();
+        let nick = connection.nick();
+        let ip_mask = HostMask::new(&nick, &connection.user, &ip_host);
+
+        if let Some(ban) = self.hosts.get(&ip_mask).into_iter().next() {
+            return Some(ban);
+        }
+
+        if self.cidrs.is_empty() {
+            return None;
+        }
+
+        // the narrowest range containing the IP takes precedence
+        (0..=Cidr::max_prefix_len(ip)).rev().find_map(|prefix_len| {
+            self.cidrs
+                .get(&Cidr::of(ip, prefix_len))?
+                .get(&ip_mask)
+                .into_iter()
+                .next()
+        })
+    }
+}
+
+/// The range a ban applies to, if the host of its mask is a CIDR range rather than a host.
+fn cidr(mask: &HostMask<'_>) -> Option<Cidr> {
+    let host = mask.host();
+
+    if !host.contains('/') {
+        return None;
+    }
+
+    Cidr::from_str(host).ok()
+}
@@ -1,15 +1,29 @@
-use chrono::{DateTime, TimeZone, Utc};
-use irc_proto::{Command, Message, Prefix, Response};
+use std::{sync::Arc, time::Duration};
+
+use actix::Addr;
+use chrono::{DateTime, SecondsFormat, TimeZone, Utc};
+use clap::crate_version;
+use irc_proto::{Command, Message, Response};
 use itertools::Itertools;
 
 use crate::{
-    channel::permissions::Permission, connection::InitiatedConnection, host_mask::HostMask,
-    persistence::events::ServerListBanEntry, server::Server, SERVER_NAME,
+    channel::{permissions::Permission, Channel},
+    client::{traffic::TrafficSnapshot, Client},
+    config::{BanConfig, ConnectionClass},
+    connection::{InitiatedConnection, UserId},
+    host_mask::BanMask,
+    persistence::events::{
+        DailyStatsEntry, LoginHistoryEntry, NickHistoryEntry, ServerListBanEntry, WhowasEntry,
+    },
+    proto::builder::MessageBuilder,
+    server::Server,
+    services::link::ServicesLink,
+    SERVER_NAME,
 };
 
 pub struct Whois {
     pub query: String,
-    pub conn: Option<InitiatedConnection>,
+    pub conn: Option<Arc<InitiatedConnection>>,
     pub channels: Vec<(Permission, String)>,
 }
 
@@ -17,25 +31,16 @@ impl IntoProtocol for Whois {
     fn into_messages(self, for_user: &str) -> Vec<Message> {
         macro_rules! msg {
             ($response:ident, $($payload:expr),*) => {
-
-                Message {
-                    tags: None,
-                    prefix: Some(Prefix::ServerName(SERVER_NAME.to_string())),
-                    command: Command::Response(
-                        Response::$response,
-                        vec![for_user.to_string(), $($payload),*],
-                    ),
-                }
+                MessageBuilder::server().response(
+                    Response::$response,
+                    vec![for_user.to_string(), $($payload),*],
+                )
             };
             ($response:literal, $($payload:expr),*) => {
-                Message {
-                    tags: None,
-                    prefix: Some(Prefix::ServerName(SERVER_NAME.to_string())),
-                    command: Command::Raw(
-                        format!("{:03}", $response),
-                        vec![for_user.to_string(), $($payload),*],
-                    ),
-                }
+                MessageBuilder::server().numeric(
+                    $response,
+                    vec![for_user.to_string(), $($payload),*],
+                )
             };
         }
 
@@ -43,6 +48,9 @@ impl IntoProtocol for Whois {
             return vec![msg!(ERR_NOSUCHNICK, self.query, "No such nick".to_string())];
         };
 
+        let nick = conn.nick();
+        let mode = conn.mode();
+
         let channels = self
             .channels
             .into_iter()
@@ -56,65 +64,74 @@ impl IntoProtocol for Whois {
         let mut out = vec![
             msg!(
                 307,
-                conn.nick.to_string(),
+                nick.to_string(),
                 "has identified for this nick".to_string()
             ), // RPL_WHOISREGNICK
             msg!(
                 RPL_WHOISUSER,
-                conn.nick.to_string(),
+                nick.to_string(),
                 conn.user.to_string(),
-                conn.cloak,
+                conn.cloak(),
                 "*".to_string(),
-                conn.real_name
+                conn.real_name.to_string()
             ),
             msg!(
                 RPL_WHOISSERVER,
-                conn.nick.to_string(),
+                nick.to_string(),
                 SERVER_NAME.to_string(),
                 SERVER_NAME.to_string()
             ),
             msg!(
                 RPL_WHOISIDLE,
-                conn.nick.to_string(),
+                nick.to_string(),
                 "0".to_string(),
                 conn.at.timestamp().to_string(),
                 "seconds idle, signon time".to_string()
             ), // TODO
-            msg!(RPL_WHOISCHANNELS, conn.nick.to_string(), channels),
+        ];
+
+        if !channels.is_empty() {
+            out.push(msg!(RPL_WHOISCHANNELS, nick.to_string(), channels));
+        }
+
+        out.extend([
             msg!(
                 330,
-                conn.nick.to_string(),
+                nick.to_string(),
                 conn.user.to_string(),
                 "is logged in as".to_string()
             ), // RPL_WHOISACCOUNT
             msg!(
                 378,
-                conn.nick.to_string(),
+                nick.to_string(),
                 format!(
                     "is connecting from {}@{} {}",
                     conn.user,
-                    conn.resolved_host
-                        .unwrap_or_else(|| conn.host.ip().to_canonical().to_string()),
+                    conn.resolved_host.clone().unwrap_or_else(|| conn
+                        .host
+                        .ip()
+                        .to_canonical()
+                        .to_string()),
                     conn.host.ip().to_canonical()
                 )
             ), // RPL_WHOISHOST
-        ];
+        ]);
 
-        if !conn.mode.is_empty() {
+        if !mode.is_empty() {
             out.push(msg!(
                 379,
-                conn.nick.to_string(),
-                format!("is using modes {}", conn.mode)
+                nick.to_string(),
+                format!("is using modes {mode}")
             )); // RPL_WHOISMODES
         }
 
-        if let Some(msg) = conn.away {
-            out.push(msg!(RPL_AWAY, conn.nick.to_string(), msg));
+        if let Some(msg) = conn.away() {
+            out.push(msg!(RPL_AWAY, nick.to_string(), msg));
         }
 
         out.push(msg!(
             RPL_ENDOFWHOIS,
-            conn.nick.to_string(),
+            nick.to_string(),
             "End of /WHOIS list".to_string()
         ));
 
@@ -128,14 +145,397 @@ pub struct NoSuchNick {
 
 impl IntoProtocol for NoSuchNick {
     fn into_messages(self, for_user: &str) -> Vec<Message> {
-        vec![Message {
-            tags: None,
-            prefix: Some(Prefix::ServerName(SERVER_NAME.to_string())),
-            command: Command::Response(
-                Response::ERR_NOSUCHNICK,
-                vec![for_user.to_string(), self.nick, "No such nick".to_string()],
+        vec![MessageBuilder::server().response(
+            Response::ERR_NOSUCHNICK,
+            vec![for_user.to_string(), self.nick, "No such nick".to_string()],
+        )]
+    }
+}
+
+pub struct NoSuchChannel {
+    pub channel: String,
+}
+
+impl IntoProtocol for NoSuchChannel {
+    fn into_messages(self, for_user: &str) -> Vec<Message> {
+        vec![MessageBuilder::server().response(
+            Response::ERR_NOSUCHCHANNEL,
+            vec![
+                for_user.to_string(),
+                self.channel,
+                "No such channel".to_string(),
+            ],
+        )]
+    }
+}
+
+/// The outcome of a `CERT` command, sent to the user as notices.
+pub enum CertificateResponse {
+    Added(String),
+    /// The certificate has already been added to an account, possibly the user's own.
+    AlreadyInUse(String),
+    Removed(String),
+    NotFound(String),
+    /// `CERT ADD` was used without a fingerprint, and the user didn't connect with a
+    /// certificate.
+    NoCertificate,
+    List(Vec<String>),
+}
+
+impl IntoProtocol for CertificateResponse {
+    fn into_messages(self, for_user: &str) -> Vec<Message> {
+        let lines = match self {
+            Self::Added(fingerprint) => vec![format!(
+                "Added certificate {fingerprint} to your account, you can now authenticate with it using SASL EXTERNAL"
+            )],
+            Self::AlreadyInUse(fingerprint) => {
+                vec![format!("Certificate {fingerprint} has already been added to an account")]
+            }
+            Self::Removed(fingerprint) => {
+                vec![format!("Removed certificate {fingerprint} from your account")]
+            }
+            Self::NotFound(fingerprint) => {
+                vec![format!("Certificate {fingerprint} isn't on your account")]
+            }
+            Self::NoCertificate => vec![
+                "You aren't connected with a client certificate, give the fingerprint of the certificate to add"
+                    .to_string(),
+            ],
+            Self::List(fingerprints) if fingerprints.is_empty() => {
+                vec!["There are no certificates on your account".to_string()]
+            }
+            Self::List(fingerprints) => std::iter::once("Certificates on your account:".to_string())
+                .chain(fingerprints)
+                .chain(std::iter::once("End of certificate list".to_string()))
+                .collect(),
+        };
+
+        lines
+            .into_iter()
+            .map(|line| {
+                MessageBuilder::server().command(Command::NOTICE(for_user.to_string(), line))
+            })
+            .collect()
+    }
+}
+
+/// The outcome of an `NS SESSIONS` or `NS LOGOUT` command, sent to the user as notices.
+pub enum SessionsResponse {
+    List {
+        /// The session ID of the connection the command was sent from.
+        current: String,
+        sessions: Vec<Arc<InitiatedConnection>>,
+        history: Vec<LoginHistoryEntry>,
+    },
+    LoggedOut(String),
+    NotFound(String),
+}
+
+impl IntoProtocol for SessionsResponse {
+    fn into_messages(self, for_user: &str) -> Vec<Message> {
+        let lines = match self {
+            Self::List {
+                current,
+                sessions,
+                history,
+            } => {
+                let sessions = sessions.into_iter().map(|conn| {
+                    format!(
+                        "{}{} {} ({}) as {}, connected {}, capabilities: {}",
+                        conn.session_id,
+                        if conn.session_id == current { "*" } else { "" },
+                        conn.host.ip().to_canonical(),
+                        conn.resolved_host.as_deref().unwrap_or("unresolved"),
+                        conn.nick(),
+                        conn.at.to_rfc3339_opts(SecondsFormat::Secs, true),
+                        conn.capabilities.names().join(" "),
+                    )
+                });
+
+                let history = history.into_iter().map(|entry| {
+                    format!(
+                        "{} {} ({}) at {}",
+                        entry.session,
+                        entry.ip,
+                        entry.host.as_deref().unwrap_or("unresolved"),
+                        Utc.timestamp_nanos(entry.timestamp)
+                            .to_rfc3339_opts(SecondsFormat::Secs, true),
+                    )
+                });
+
+                std::iter::once(
+                    "Sessions logged into your account (* is this session):".to_string(),
+                )
+                .chain(sessions)
+                .chain(std::iter::once("Recent logins:".to_string()))
+                .chain(history)
+                .chain(std::iter::once(
+                    "End of sessions, use NS LOGOUT <id> to disconnect a session".to_string(),
+                ))
+                .collect()
+            }
+            Self::LoggedOut(session) => vec![format!("Session {session} has been logged out")],
+            Self::NotFound(session) => {
+                vec![format!(
+                    "There's no session {session} logged into your account"
+                )]
+            }
+        };
+
+        lines
+            .into_iter()
+            .map(|line| {
+                MessageBuilder::server().command(Command::NOTICE(for_user.to_string(), line))
+            })
+            .collect()
+    }
+}
+
+/// The outcome of a founder changing their channel's successor, or the notice sent to the
+/// successor once they've taken over the channel.
+pub enum ChannelSuccessor {
+    Set(String, String),
+    Cleared(String),
+    NotFounder(String),
+    NoSuchAccount(String),
+    Promoted(String),
+}
+
+impl IntoProtocol for ChannelSuccessor {
+    fn into_messages(self, for_user: &str) -> Vec<Message> {
+        let text = match self {
+            Self::Set(channel, account) => {
+                format!("{account} will take over {channel} if your account is dropped")
+            }
+            Self::Cleared(channel) => format!("Removed the successor of {channel}"),
+            Self::NotFounder(channel) => format!("You aren't the founder of {channel}"),
+            Self::NoSuchAccount(account) => format!("Account {account} doesn't exist"),
+            Self::Promoted(channel) => format!(
+                "The founder of {channel} dropped their account, you're now the channel's founder"
             ),
-        }]
+        };
+
+        vec![MessageBuilder::server().command(Command::NOTICE(for_user.to_string(), text))]
+    }
+}
+
+/// The outcome of an operator reloading the config with `REHASH`.
+pub enum Rehash {
+    /// The config was reloaded from the given file.
+    Reloaded(String),
+    /// The config couldn't be loaded, the server carries on with its current config.
+    Failed(String),
+}
+
+impl IntoProtocol for Rehash {
+    fn into_messages(self, for_user: &str) -> Vec<Message> {
+        match self {
+            Self::Reloaded(path) => vec![MessageBuilder::server().response(
+                Response::RPL_REHASHING,
+                vec![for_user.to_string(), path, "Rehashing".to_string()],
+            )],
+            Self::Failed(error) => vec![MessageBuilder::server().command(Command::NOTICE(
+                for_user.to_string(),
+                format!("Failed to reload config: {error}"),
+            ))],
+        }
+    }
+}
+
+/// The outcome of an operator starting or stopping a tap on a user.
+pub enum TapStatus {
+    Enabled(String),
+    Disabled(String),
+    NotConfigured,
+}
+
+impl IntoProtocol for TapStatus {
+    fn into_messages(self, for_user: &str) -> Vec<Message> {
+        let text = match self {
+            Self::Enabled(nick) => format!("Now tapping the traffic of {nick}"),
+            Self::Disabled(nick) => format!("No longer tapping the traffic of {nick}"),
+            Self::NotConfigured => "Traffic tapping isn't configured on this server".to_string(),
+        };
+
+        vec![MessageBuilder::server().command(Command::NOTICE(for_user.to_string(), text))]
+    }
+}
+
+/// The outcome of `SPY`.
+pub enum SpyStatus {
+    Enabled(String),
+    Disabled(String),
+}
+
+impl IntoProtocol for SpyStatus {
+    fn into_messages(self, for_user: &str) -> Vec<Message> {
+        let text = match self {
+            Self::Enabled(channel) => {
+                format!("Now observing {channel}, its members can't see you")
+            }
+            Self::Disabled(channel) => format!("No longer observing {channel}"),
+        };
+
+        vec![MessageBuilder::server().command(Command::NOTICE(for_user.to_string(), text))]
+    }
+}
+
+/// The daily statistics requested by an oper, most recent day first. Only the busiest few
+/// channels of each day are named.
+pub struct DailyStats(pub Vec<DailyStatsEntry>);
+
+impl DailyStats {
+    const BUSIEST_CHANNELS: usize = 3;
+}
+
+impl IntoProtocol for DailyStats {
+    fn into_messages(self, for_user: &str) -> Vec<Message> {
+        let lines = self.0.into_iter().map(|entry| {
+            let messages: i64 = entry.channel_messages.iter().map(|(_, v)| v).sum();
+            let busiest = entry
+                .channel_messages
+                .iter()
+                .take(Self::BUSIEST_CHANNELS)
+                .map(|(channel, messages)| format!("{channel}: {messages}"))
+                .join(", ");
+
+            let line = format!(
+                "{}: {} peak clients, {} new accounts, {messages} channel messages",
+                entry.day, entry.peak_clients, entry.new_accounts
+            );
+
+            if busiest.is_empty() {
+                line
+            } else {
+                format!("{line} ({busiest})")
+            }
+        });
+
+        std::iter::once("Daily statistics:".to_string())
+            .chain(lines)
+            .chain(std::iter::once("End of daily statistics".to_string()))
+            .map(|line| {
+                MessageBuilder::server().command(Command::NOTICE(for_user.to_string(), line))
+            })
+            .collect()
+    }
+}
+
+/// The users that previously used the nick `query`, most recent first.
+pub struct Whowas {
+    pub query: String,
+    pub entries: Vec<WhowasEntry>,
+}
+
+impl IntoProtocol for Whowas {
+    fn into_messages(self, for_user: &str) -> Vec<Message> {
+        let mut out: Vec<_> = self
+            .entries
+            .into_iter()
+            .flat_map(|entry| {
+                let seen = Utc
+                    .timestamp_nanos(entry.seen_timestamp)
+                    .to_rfc3339_opts(SecondsFormat::Secs, true);
+
+                [
+                    MessageBuilder::server().response(
+                        Response::RPL_WHOWASUSER,
+                        vec![
+                            for_user.to_string(),
+                            entry.nick.clone(),
+                            entry.username,
+                            entry.host,
+                            "*".to_string(),
+                            entry.realname,
+                        ],
+                    ),
+                    MessageBuilder::server().response(
+                        Response::RPL_WHOISSERVER,
+                        vec![
+                            for_user.to_string(),
+                            entry.nick,
+                            SERVER_NAME.to_string(),
+                            format!("Last seen {seen}"),
+                        ],
+                    ),
+                ]
+            })
+            .collect();
+
+        if out.is_empty() {
+            out.push(MessageBuilder::server().response(
+                Response::ERR_WASNOSUCHNICK,
+                vec![
+                    for_user.to_string(),
+                    self.query.clone(),
+                    "There was no such nickname".to_string(),
+                ],
+            ));
+        }
+
+        out.push(MessageBuilder::server().response(
+            Response::RPL_ENDOFWHOWAS,
+            vec![
+                for_user.to_string(),
+                self.query,
+                "End of WHOWAS".to_string(),
+            ],
+        ));
+
+        out
+    }
+}
+
+/// Every nick used by the account owning `query`, shown to opers in the style of `WHOWAS`.
+pub struct NickHistory {
+    pub query: String,
+    pub entries: Vec<NickHistoryEntry>,
+}
+
+impl IntoProtocol for NickHistory {
+    fn into_messages(self, for_user: &str) -> Vec<Message> {
+        let mut out: Vec<_> = self
+            .entries
+            .into_iter()
+            .map(|entry| {
+                let first_used = Utc.timestamp_nanos(entry.first_used_timestamp);
+                let last_used = Utc.timestamp_nanos(entry.last_used_timestamp);
+
+                MessageBuilder::server().response(
+                    Response::RPL_WHOWASUSER,
+                    vec![
+                        for_user.to_string(),
+                        entry.nick,
+                        entry.username,
+                        "*".to_string(),
+                        "*".to_string(),
+                        format!("First used {first_used}, last used {last_used}"),
+                    ],
+                )
+            })
+            .collect();
+
+        if out.is_empty() {
+            out.push(MessageBuilder::server().response(
+                Response::ERR_WASNOSUCHNICK,
+                vec![
+                    for_user.to_string(),
+                    self.query.clone(),
+                    "There was no such nickname".to_string(),
+                ],
+            ));
+        }
+
+        out.push(MessageBuilder::server().response(
+            Response::RPL_ENDOFWHOWAS,
+            vec![
+                for_user.to_string(),
+                self.query,
+                "End of nick history".to_string(),
+            ],
+        ));
+
+        out
     }
 }
 
@@ -153,18 +553,14 @@ impl IntoProtocol for WhoList {
             .flat_map(|v| v.into_messages(for_user))
             .collect();
 
-        out.push(Message {
-            tags: None,
-            prefix: Some(Prefix::ServerName(SERVER_NAME.to_string())),
-            command: Command::Response(
-                Response::RPL_ENDOFWHO,
-                vec![
-                    for_user.to_string(),
-                    self.query,
-                    "End of WHO list".to_string(),
-                ],
-            ),
-        });
+        out.push(MessageBuilder::server().response(
+            Response::RPL_ENDOFWHO,
+            vec![
+                for_user.to_string(),
+                self.query,
+                "End of WHO list".to_string(),
+            ],
+        ));
 
         out
     }
@@ -180,15 +576,10 @@ impl IntoProtocol for AdminInfo {
     fn into_messages(self, for_user: &str) -> Vec<Message> {
         macro_rules! msg {
             ($response:ident, $($payload:expr),*) => {
-
-                Message {
-                    tags: None,
-                    prefix: Some(Prefix::ServerName(SERVER_NAME.to_string())),
-                    command: Command::Response(
-                        Response::$response,
-                        vec![for_user.to_string(), $($payload),*],
-                    ),
-                }
+                MessageBuilder::server().response(
+                    Response::$response,
+                    vec![for_user.to_string(), $($payload),*],
+                )
             };
         }
 
@@ -217,15 +608,10 @@ impl IntoProtocol for ListUsers {
     fn into_messages(self, for_user: &str) -> Vec<Message> {
         macro_rules! msg {
             ($response:ident, $($payload:expr),*) => {
-
-                Message {
-                    tags: None,
-                    prefix: Some(Prefix::ServerName(SERVER_NAME.to_string())),
-                    command: Command::Response(
-                        Response::$response,
-                        vec![for_user.to_string(), $($payload),*],
-                    ),
-                }
+                MessageBuilder::server().response(
+                    Response::$response,
+                    vec![for_user.to_string(), $($payload),*],
+                )
             };
         }
 
@@ -276,18 +662,383 @@ impl IntoProtocol for ListUsers {
     }
 }
 
+#[derive(Default)]
+pub struct AcceptList {
+    pub list: Option<Vec<String>>,
+    pub errors: Vec<AcceptListError>,
+}
+
+pub enum AcceptListError {
+    Full(String),
+    AlreadyExists(String),
+    NotFound(String),
+}
+
+impl IntoProtocol for AcceptList {
+    fn into_messages(self, for_user: &str) -> Vec<Message> {
+        macro_rules! msg {
+            ($response:literal, $($payload:expr),*) => {
+                MessageBuilder::server().numeric(
+                    $response,
+                    vec![for_user.to_string(), $($payload),*],
+                )
+            };
+        }
+
+        let mut out: Vec<_> = self
+            .errors
+            .into_iter()
+            .map(|error| match error {
+                AcceptListError::Full(nick) => {
+                    msg!(456, nick, "Accept list is full".to_string()) // ERR_ACCEPTFULL
+                }
+                AcceptListError::AlreadyExists(nick) => {
+                    msg!(457, nick, "is already on your accept list".to_string())
+                    // ERR_ACCEPTEXIST
+                }
+                AcceptListError::NotFound(nick) => {
+                    msg!(458, nick, "is not on your accept list".to_string()) // ERR_ACCEPTNOT
+                }
+            })
+            .collect();
+
+        if let Some(list) = self.list {
+            if !list.is_empty() {
+                out.push(msg!(281, list.join(" "))); // RPL_ACCEPTLIST
+            }
+
+            out.push(msg!(282, "End of /ACCEPT list".to_string())); // RPL_ENDOFACCEPT
+        }
+
+        out
+    }
+}
+
+/// Sent to a user attempting to message a user in caller-id (`+g`) mode that hasn't
+/// accepted them.
+pub struct CallerIdRejected {
+    pub target: String,
+    pub notified: bool,
+}
+
+impl IntoProtocol for CallerIdRejected {
+    fn into_messages(self, for_user: &str) -> Vec<Message> {
+        let mut out = vec![MessageBuilder::server().command(Command::Raw(
+            "716".to_string(),
+            vec![
+                for_user.to_string(),
+                self.target.to_string(),
+                "is in +g mode (server-side ignore.)".to_string(),
+            ],
+        ))]; // RPL_TARGUMODEG
+
+        if self.notified {
+            out.push(MessageBuilder::server().command(Command::Raw(
+                "717".to_string(),
+                vec![
+                    for_user.to_string(),
+                    self.target,
+                    "has been informed that you messaged them.".to_string(),
+                ],
+            ))); // RPL_TARGNOTIFY
+        }
+
+        out
+    }
+}
+
+/// Sent to a user in caller-id (`+g`) mode when a user that isn't on their accept list
+/// attempts to message them.
+pub struct CallerIdNotify(pub Arc<InitiatedConnection>);
+
+impl IntoProtocol for CallerIdNotify {
+    fn into_messages(self, for_user: &str) -> Vec<Message> {
+        vec![MessageBuilder::server().command(Command::Raw(
+            "718".to_string(),
+            vec![
+                for_user.to_string(),
+                self.0.nick(),
+                format!("{}@{}", self.0.user, self.0.cloak()),
+                "is messaging you, and you have umode +g.".to_string(),
+            ],
+        ))] // RPL_UMODEGMSG
+    }
+}
+
+/// Sent to a user attempting to use a command that's been disabled on this server.
+pub struct CommandDisabled(pub String);
+
+impl IntoProtocol for CommandDisabled {
+    fn into_messages(self, for_user: &str) -> Vec<Message> {
+        vec![MessageBuilder::server().numeric(
+            517,
+            vec![
+                for_user.to_string(),
+                self.0,
+                "Command disabled on this server".to_string(),
+            ],
+        )] // ERR_DISABLED
+    }
+}
+
+/// Sent to a user attempting to message a user they don't share a channel with, when the server
+/// requires it.
+pub struct NoSharedChannel(pub String);
+
+impl IntoProtocol for NoSharedChannel {
+    fn into_messages(self, for_user: &str) -> Vec<Message> {
+        vec![MessageBuilder::server().command(Command::Raw(
+            "531".to_string(),
+            vec![
+                for_user.to_string(),
+                self.0,
+                "You must share a channel with this user to message them".to_string(),
+            ],
+        ))] // ERR_CANTSENDTOUSER
+    }
+}
+
+/// Sent to a user attempting to message another user before their account is old enough to.
+pub struct AccountTooNew(pub String, pub Duration);
+
+impl IntoProtocol for AccountTooNew {
+    fn into_messages(self, for_user: &str) -> Vec<Message> {
+        vec![MessageBuilder::server().numeric(
+            531,
+            vec![
+                for_user.to_string(),
+                self.0,
+                format!(
+                    "Your account needs to be at least {} old to message other users",
+                    humantime::format_duration(self.1)
+                ),
+            ],
+        )] // ERR_CANTSENDTOUSER
+    }
+}
+
+pub struct Stats {
+    pub query: String,
+    pub report: StatsReport,
+}
+
+pub enum StatsReport {
+    /// `STATS u`, the server's uptime, connection high-water mark, amount of failed writes
+    /// to clients and the total traffic to and from clients.
+    Uptime {
+        uptime: Duration,
+        current_clients: usize,
+        max_clients: usize,
+        write_errors: u64,
+        traffic: TrafficSnapshot,
+    },
+    /// `STATS l`, the traffic sent to and received from each connected client.
+    Links(Vec<(Arc<InitiatedConnection>, TrafficSnapshot)>),
+    /// `STATS f`, the amount of clients currently connected over each address family.
+    AddressFamilies {
+        v4: usize,
+        v6: usize,
+    },
+    /// `STATS m`, the amount of times each command has been used since the server started.
+    Commands(Vec<(String, u64)>),
+    /// `STATS o`, the names of the configured operators.
+    Operators(Vec<String>),
+    /// `STATS k` and `STATS g`, the network bans currently in place. Every ban applies across
+    /// the whole network, so both list the same bans.
+    Bans(Vec<ServerBan>),
+    Unsupported,
+}
+
+impl IntoProtocol for Stats {
+    fn into_messages(self, for_user: &str) -> Vec<Message> {
+        macro_rules! msg {
+            ($response:ident, $($payload:expr),*) => {
+                MessageBuilder::server().response(
+                    Response::$response,
+                    vec![for_user.to_string(), $($payload),*],
+                )
+            };
+            ($response:literal, $($payload:expr),*) => {
+                MessageBuilder::server().numeric(
+                    $response,
+                    vec![for_user.to_string(), $($payload),*],
+                )
+            };
+        }
+
+        let mut out = match self.report {
+            StatsReport::Uptime {
+                uptime,
+                current_clients,
+                max_clients,
+                write_errors,
+                traffic,
+            } => {
+                let secs = uptime.as_secs();
+
+                vec![
+                    msg!(
+                        RPL_STATSUPTIME,
+                        format!(
+                            "Server Up {} days {}:{:02}:{:02}",
+                            secs / 86_400,
+                            (secs % 86_400) / 3600,
+                            (secs % 3600) / 60,
+                            secs % 60
+                        )
+                    ),
+                    msg!(
+                        250,
+                        format!(
+                            "Highest connection count: {max_clients} ({current_clients} \
+                             currently connected)"
+                        )
+                    ), // RPL_STATSCONN
+                    msg!(249, format!("Client write errors: {write_errors}")), // RPL_STATSDEBUG
+                    msg!(
+                        249,
+                        format!(
+                            "Sent {} messages ({} KiB), received {} messages ({} KiB)",
+                            traffic.messages_sent,
+                            traffic.bytes_sent / 1024,
+                            traffic.messages_received,
+                            traffic.bytes_received / 1024
+                        )
+                    ), // RPL_STATSDEBUG
+                ]
+            }
+            StatsReport::Links(links) => links
+                .into_iter()
+                .map(|(conn, traffic)| {
+                    let open_for = (Utc::now() - conn.at).num_seconds();
+
+                    msg!(
+                        RPL_STATSLINKINFO,
+                        format!("{}[{}@{}]", conn.nick(), conn.user, conn.cloak()),
+                        "0".to_string(),
+                        traffic.messages_sent.to_string(),
+                        (traffic.bytes_sent / 1024).to_string(),
+                        traffic.messages_received.to_string(),
+                        (traffic.bytes_received / 1024).to_string(),
+                        open_for.to_string()
+                    )
+                })
+                .collect(),
+            StatsReport::AddressFamilies { v4, v6 } => vec![
+                msg!(249, format!("IPv4 clients: {v4}")), // RPL_STATSDEBUG
+                msg!(249, format!("IPv6 clients: {v6}")), // RPL_STATSDEBUG
+            ],
+            StatsReport::Commands(commands) => commands
+                .into_iter()
+                .map(|(command, count)| msg!(RPL_STATSCOMMANDS, command, count.to_string()))
+                .collect(),
+            StatsReport::Operators(operators) => operators
+                .into_iter()
+                .map(|name| {
+                    msg!(
+                        RPL_STATSOLINE,
+                        "O".to_string(),
+                        "*".to_string(),
+                        "*".to_string(),
+                        name
+                    )
+                })
+                .collect(),
+            StatsReport::Bans(bans) => bans
+                .into_iter()
+                .map(|ban| {
+                    msg!(
+                        223,
+                        "G".to_string(),
+                        ban.mask.to_string(),
+                        ban.expires
+                            .map_or(0, |expires| expires.timestamp())
+                            .to_string(),
+                        ban.created.timestamp().to_string(),
+                        ban.requester,
+                        ban.reason.unwrap_or_default()
+                    ) // RPL_STATSGLINE
+                })
+                .collect(),
+            StatsReport::Unsupported => vec![],
+        };
+
+        out.push(msg!(
+            RPL_ENDOFSTATS,
+            self.query,
+            "End of /STATS report".to_string()
+        ));
+
+        out
+    }
+}
+
 #[derive(Default)]
 pub struct Motd {
     pub motd: Option<String>,
+    pub network: String,
+    pub clients: usize,
+    pub uptime: Duration,
 }
 
 impl Motd {
     #[must_use]
-    pub fn new(server: &Server) -> Self {
+    pub fn new(server: &Server, class: &ConnectionClass) -> Self {
         Self {
-            motd: server.config.motd.clone(),
+            // looked up by name so changes to the config apply to already connected clients
+            motd: server
+                .config
+                .motd_for_class(&class.name)
+                .map(ToString::to_string),
+            network: server.config.network_name.clone(),
+            clients: server.clients.len(),
+            uptime: (Utc::now() - server.started_at)
+                .to_std()
+                .unwrap_or_default(),
         }
     }
+
+    /// Expands any of the supported `{placeholder}`s in the given MOTD line, leaving
+    /// unknown placeholders untouched.
+    #[must_use]
+    pub fn expand_variables(&self, line: &str) -> String {
+        expand_placeholders(line, |name| match name {
+            "server_name" => Some(SERVER_NAME.to_string()),
+            "network" => Some(self.network.clone()),
+            "clients" => Some(self.clients.to_string()),
+            "uptime" => Some(
+                humantime::format_duration(Duration::from_secs(self.uptime.as_secs())).to_string(),
+            ),
+            "version" => Some(crate_version!().to_string()),
+            _ => None,
+        })
+    }
+}
+
+/// Replaces each `{placeholder}` in `line` with the value `lookup` returns for it, leaving
+/// placeholders it doesn't know of untouched.
+fn expand_placeholders(line: &str, lookup: impl Fn(&str) -> Option<String>) -> String {
+    let mut out = String::with_capacity(line.len());
+    let mut rest = line;
+
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let Some(end) = rest.find('}') else {
+            break;
+        };
+
+        match lookup(&rest[1..end]) {
+            Some(value) => out.push_str(&value),
+            None => out.push_str(&rest[..=end]),
+        }
+
+        rest = &rest[end + 1..];
+    }
+
+    out.push_str(rest);
+    out
 }
 
 impl IntoProtocol for Motd {
@@ -295,45 +1046,31 @@ impl IntoProtocol for Motd {
     fn into_messages(self, for_user: &str) -> Vec<Message> {
         let mut out = Vec::new();
 
-        if let Some(motd) = self.motd {
-            out.push(Message {
-                tags: None,
-                prefix: Some(Prefix::ServerName(SERVER_NAME.to_string())),
-                command: Command::Response(
-                    Response::RPL_MOTDSTART,
-                    vec![
-                        for_user.to_string(),
-                        format!("- {SERVER_NAME} Message of the day -"),
-                    ],
-                ),
-            });
+        if let Some(motd) = &self.motd {
+            out.push(MessageBuilder::server().response(
+                Response::RPL_MOTDSTART,
+                vec![
+                    for_user.to_string(),
+                    format!("- {SERVER_NAME} Message of the day -"),
+                ],
+            ));
 
-            out.extend(motd.trim().split('\n').map(|v| Message {
-                tags: None,
-                prefix: Some(Prefix::ServerName(SERVER_NAME.to_string())),
-                command: Command::Response(
+            out.extend(motd.trim().split('\n').map(|v| {
+                MessageBuilder::server().response(
                     Response::RPL_MOTD,
-                    vec![for_user.to_string(), v.to_string()],
-                ),
+                    vec![for_user.to_string(), self.expand_variables(v)],
+                )
             }));
 
-            out.push(Message {
-                tags: None,
-                prefix: Some(Prefix::ServerName(SERVER_NAME.to_string())),
-                command: Command::Response(
-                    Response::RPL_ENDOFMOTD,
-                    vec![for_user.to_string(), "End of /MOTD command.".to_string()],
-                ),
-            });
+            out.push(MessageBuilder::server().response(
+                Response::RPL_ENDOFMOTD,
+                vec![for_user.to_string(), "End of /MOTD command.".to_string()],
+            ));
         } else {
-            out.push(Message {
-                tags: None,
-                prefix: Some(Prefix::ServerName(SERVER_NAME.to_string())),
-                command: Command::Response(
-                    Response::ERR_NOMOTD,
-                    vec![for_user.to_string(), "MOTD File is missing".to_string()],
-                ),
-            });
+            out.push(MessageBuilder::server().response(
+                Response::ERR_NOMOTD,
+                vec![for_user.to_string(), "MOTD File is missing".to_string()],
+            ));
         }
 
         out
@@ -350,43 +1087,33 @@ impl IntoProtocol for ChannelList {
     fn into_messages(self, for_user: &str) -> Vec<Message> {
         let mut messages = Vec::with_capacity(self.members.len() + 2);
 
-        messages.push(Message {
-            tags: None,
-            prefix: Some(Prefix::ServerName(SERVER_NAME.to_string())),
-            command: Command::Response(
-                Response::RPL_LISTSTART,
+        messages.push(MessageBuilder::server().response(
+            Response::RPL_LISTSTART,
+            vec![
+                for_user.to_string(),
+                "Channel".to_string(),
+                "Users  Name".to_string(),
+            ],
+        ));
+
+        for item in self.members {
+            let topic = item.list_topic();
+
+            messages.push(MessageBuilder::server().response(
+                Response::RPL_LIST,
                 vec![
                     for_user.to_string(),
-                    "Channel".to_string(),
-                    "Users  Name".to_string(),
+                    item.channel_name,
+                    item.client_count.to_string(),
+                    topic,
                 ],
-            ),
-        });
-
-        for item in self.members {
-            messages.push(Message {
-                tags: None,
-                prefix: Some(Prefix::ServerName(SERVER_NAME.to_string())),
-                command: Command::Response(
-                    Response::RPL_LIST,
-                    vec![
-                        for_user.to_string(),
-                        item.channel_name,
-                        item.client_count.to_string(),
-                        item.topic.unwrap_or_default(),
-                    ],
-                ),
-            });
+            ));
         }
 
-        messages.push(Message {
-            tags: None,
-            prefix: Some(Prefix::ServerName(SERVER_NAME.to_string())),
-            command: Command::Response(
-                Response::RPL_LISTEND,
-                vec![for_user.to_string(), "End of /LIST".to_string()],
-            ),
-        });
+        messages.push(MessageBuilder::server().response(
+            Response::RPL_LISTEND,
+            vec![for_user.to_string(), "End of /LIST".to_string()],
+        ));
 
         messages
     }
@@ -396,17 +1123,52 @@ pub struct ChannelListItem {
     pub channel_name: String,
     pub client_count: usize,
     pub topic: Option<String>,
+    /// A summary of the channel's metadata to prefix the topic with, if configured to.
+    pub metadata: Option<String>,
+}
+
+impl ChannelListItem {
+    fn list_topic(&self) -> String {
+        match (&self.metadata, &self.topic) {
+            (Some(metadata), Some(topic)) => format!("{metadata} {topic}"),
+            (Some(metadata), None) => metadata.clone(),
+            (None, topic) => topic.clone().unwrap_or_default(),
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct ServerBan {
-    pub mask: HostMask<'static>,
+    pub mask: BanMask,
     pub requester: String,
     pub reason: Option<String>,
     pub created: DateTime<Utc>,
     pub expires: Option<DateTime<Utc>>,
 }
 
+impl ServerBan {
+    /// Builds the message shown to a user affected by this ban from the configured template,
+    /// expanding `{reason}`, `{mask}`, `{expires}`, `{contact}` and `{network}`.
+    #[must_use]
+    pub fn render(&self, config: &BanConfig, network: &str) -> String {
+        expand_placeholders(&config.message, |name| match name {
+            "reason" => Some(
+                self.reason
+                    .clone()
+                    .unwrap_or_else(|| "no reason given".to_string()),
+            ),
+            "mask" => Some(self.mask.to_string()),
+            "expires" => Some(self.expires.map_or_else(
+                || "never".to_string(),
+                |v| v.to_rfc3339_opts(SecondsFormat::Secs, true),
+            )),
+            "contact" => Some(config.contact.clone().unwrap_or_default()),
+            "network" => Some(network.to_string()),
+            _ => None,
+        })
+    }
+}
+
 impl From<ServerListBanEntry> for ServerBan {
     fn from(value: ServerListBanEntry) -> Self {
         Self {
@@ -421,32 +1183,107 @@ impl From<ServerListBanEntry> for ServerBan {
 
 impl IntoProtocol for ServerBan {
     fn into_messages(self, for_user: &str) -> Vec<Message> {
-        vec![Message {
-            tags: None,
-            prefix: Some(Prefix::ServerName(SERVER_NAME.to_string())),
-            command: Command::Raw(
-                "216".to_string(),
-                vec![
-                    for_user.to_string(),
-                    format!(
-                        "{} by {} ({}), created {}, expires {}",
-                        self.mask,
-                        self.requester,
-                        self.reason.as_deref().unwrap_or("no reason given"),
-                        self.created,
-                        self.expires
-                            .map(|v| v.to_string())
-                            .as_deref()
-                            .unwrap_or("never")
-                    ),
-                ],
-            ),
-        }]
+        vec![MessageBuilder::server().command(Command::Raw(
+            "216".to_string(),
+            vec![
+                for_user.to_string(),
+                format!(
+                    "{} by {} ({}), created {}, expires {}",
+                    self.mask,
+                    self.requester,
+                    self.reason.as_deref().unwrap_or("no reason given"),
+                    self.created,
+                    self.expires
+                        .map(|v| v.to_string())
+                        .as_deref()
+                        .unwrap_or("never")
+                ),
+            ],
+        ))]
+    }
+}
+
+/// What a nick or channel name refers to, as returned by [`ResolveTarget`].
+///
+/// [`ResolveTarget`]: crate::messages::ResolveTarget
+pub enum Target {
+    /// A user that's currently connected to the server.
+    OnlineUser {
+        handle: Addr<Client>,
+        connection: Arc<InitiatedConnection>,
+    },
+    /// A nick owned by an account that isn't currently connected.
+    OfflineAccount {
+        user_id: UserId,
+        account: String,
+    },
+    Channel(Addr<Channel>),
+    /// One of the clients of the linked services package.
+    Services(Addr<ServicesLink>),
+    Unknown,
+}
+
+/// Whether a nick is free for a user to use.
+pub enum NickAvailability {
+    Available,
+    /// The nick is in use by another online session, `suggestion` is an alternative nick that
+    /// the user could use instead, if suggestions are enabled.
+    InUse {
+        nick: String,
+        suggestion: Option<String>,
+    },
+    /// The nick is being held and can't be used by anyone until the hold expires.
+    Unavailable(ResourceUnavailable),
+}
+
+impl NickAvailability {
+    #[must_use]
+    pub const fn is_available(&self) -> bool {
+        matches!(self, Self::Available)
+    }
+}
+
+impl IntoProtocol for NickAvailability {
+    fn into_messages(self, for_user: &str) -> Vec<Message> {
+        let (nick, suggestion) = match self {
+            Self::Available => return vec![],
+            Self::Unavailable(unavailable) => return unavailable.into_messages(for_user),
+            Self::InUse { nick, suggestion } => (nick, suggestion),
+        };
+
+        let message = match suggestion {
+            Some(suggestion) => format!("Nickname is already in use, try {suggestion}"),
+            None => "Nickname is already in use".to_string(),
+        };
+
+        vec![MessageBuilder::server().response(
+            Response::ERR_NICKNAMEINUSE,
+            vec![for_user.to_string(), nick, message],
+        )]
+    }
+}
+
+/// Returned when a nick or channel is temporarily being held by the server.
+#[derive(Clone, Debug)]
+pub struct ResourceUnavailable(pub String);
+
+impl IntoProtocol for ResourceUnavailable {
+    fn into_messages(self, for_user: &str) -> Vec<Message> {
+        vec![MessageBuilder::server().response(
+            Response::ERR_UNAVAILRESOURCE,
+            vec![
+                for_user.to_string(),
+                self.0,
+                "Nick/channel is temporarily unavailable".to_string(),
+            ],
+        )]
     }
 }
 
 pub enum ConnectionValidated {
     Allowed,
+    /// The connection is allowed, but the user is shunned and will have their commands ignored.
+    Shunned,
     Reject(String),
 }
 
@@ -487,3 +1324,73 @@ impl<T: IntoProtocol> IntoProtocol for Vec<T> {
             .collect()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use chrono::{TimeZone, Utc};
+
+    use crate::{
+        config::BanConfig,
+        host_mask::BanMask,
+        server::response::{Motd, ServerBan},
+        SERVER_NAME,
+    };
+
+    #[test]
+    fn motd_expands_variables() {
+        let motd = Motd {
+            motd: None,
+            network: "testnet".to_string(),
+            clients: 42,
+            uptime: Duration::from_secs(90),
+        };
+
+        assert_eq!(
+            motd.expand_variables("{server_name} on {network}: {clients} users, up {uptime}"),
+            format!("{SERVER_NAME} on testnet: 42 users, up 1m 30s")
+        );
+    }
+
+    #[test]
+    fn motd_leaves_unknown_variables() {
+        let motd = Motd::default();
+
+        assert_eq!(motd.expand_variables("{unknown} {"), "{unknown} {");
+    }
+
+    #[test]
+    fn ban_renders_template() {
+        let mut ban = ServerBan {
+            mask: "*!*@127.0.0.1".parse::<BanMask>().unwrap(),
+            requester: "admin".to_string(),
+            reason: Some("spam".to_string()),
+            created: Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap(),
+            expires: Some(Utc.with_ymd_and_hms(2024, 1, 2, 0, 0, 0).unwrap()),
+        };
+
+        assert_eq!(
+            ban.render(&BanConfig::default(), "testnet"),
+            "G-lined: spam"
+        );
+
+        let config = BanConfig {
+            message:
+                "Banned from {network} ({mask}) until {expires}: {reason}, appeal at {contact}"
+                    .to_string(),
+            contact: Some("abuse@example.com".to_string()),
+        };
+        assert_eq!(
+            ban.render(&config, "testnet"),
+            "Banned from testnet (*!*@127.0.0.1) until 2024-01-02T00:00:00Z: spam, appeal at abuse@example.com"
+        );
+
+        ban.reason = None;
+        ban.expires = None;
+        assert_eq!(
+            ban.render(&config, "testnet"),
+            "Banned from testnet (*!*@127.0.0.1) until never: no reason given, appeal at abuse@example.com"
+        );
+    }
+}
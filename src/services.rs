@@ -0,0 +1,311 @@
+//! NickServ-style account management, letting users register, log into, drop and change the
+//! password of their account in-band rather than only through SASL when connecting, along with
+//! the responses to the ChanServ-style channel registration and settings commands. An external
+//! services package can be linked in their place, see [`link`].
+
+pub mod link;
+
+use argon2::PasswordHash;
+use irc_proto::{Command, Message};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    channel::{metadata::ChannelMetadataKey, permissions::Permission},
+    connection::UserId,
+    database::{self, verify_password},
+    host_mask::HostMask,
+    proto::builder::MessageBuilder,
+    server::response::IntoProtocol,
+};
+
+/// The outcome of an `NS` account command, sent to the user as notices.
+#[derive(Debug, PartialEq, Eq)]
+pub enum NickServResponse {
+    Registered(String),
+    /// The account already has a password, `NS SET PASSWORD` should be used to change it.
+    AlreadyRegistered(String),
+    Identified(String),
+    /// `NS IDENTIFY` was sent after registering, every registered user is already logged in.
+    AlreadyIdentified(String),
+    InvalidCredentials,
+    PasswordChanged,
+    Dropped(String),
+    /// The account placed network bans or shuns that are still in place, so can't be dropped.
+    DropBlocked(String),
+}
+
+impl IntoProtocol for NickServResponse {
+    fn into_messages(self, for_user: &str) -> Vec<Message> {
+        let line = match self {
+            Self::Registered(account) => format!(
+                "Your account {account} is now registered, use your password to log in via SASL or NS IDENTIFY from now on"
+            ),
+            Self::AlreadyRegistered(account) => format!(
+                "Your account {account} is already registered, use NS SET PASSWORD to change its password"
+            ),
+            Self::Identified(account) => format!("You are now logged in as {account}"),
+            Self::AlreadyIdentified(account) => format!("You are already logged in as {account}"),
+            Self::InvalidCredentials => "Invalid account name or password".to_string(),
+            Self::PasswordChanged => "Your password has been changed".to_string(),
+            Self::Dropped(account) => format!("Your account {account} has been dropped"),
+            Self::DropBlocked(account) => format!(
+                "Your account {account} can't be dropped whilst network bans or shuns it placed are still in place"
+            ),
+        };
+
+        vec![MessageBuilder::server().command(Command::NOTICE(for_user.to_string(), line))]
+    }
+}
+
+/// The outcome of a `CS` command, sent to the user as notices.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ChanServResponse {
+    Registered(String),
+    /// The channel already has a founder, or a successor waiting to take it over.
+    AlreadyRegistered(String),
+    NotInChannel(String),
+    Transferred(String, String),
+    NotFounder(String),
+    NoSuchAccount(String),
+    AccessUpdated(String, HostMask<'static>, Permission),
+    AccessRemoved(String, HostMask<'static>),
+    /// The founder's own entry was targeted, which can only be changed with `CS TRANSFER`.
+    FounderEntry(String),
+    NoSuchEntry(String, HostMask<'static>),
+    AccessList(String, Vec<(HostMask<'static>, Permission)>),
+    /// The channel's access list as a JSON document, produced by [`export_access`].
+    AccessExport(String, String),
+    AccessImported(String, usize),
+    NoSuchChannel(String),
+    MetadataSet(String, ChannelMetadataKey, String),
+    MetadataCleared(String, ChannelMetadataKey),
+    /// Only channel operators can change the channel's metadata.
+    NotOperator(String),
+}
+
+impl IntoProtocol for ChanServResponse {
+    fn into_messages(self, for_user: &str) -> Vec<Message> {
+        let lines =
+            match self {
+                Self::Registered(channel) => vec![format!("You are now the founder of {channel}")],
+                Self::AlreadyRegistered(channel) => {
+                    vec![format!("{channel} is already registered")]
+                }
+                Self::NotInChannel(channel) => {
+                    vec![format!("You need to be in {channel} to register it")]
+                }
+                Self::Transferred(channel, account) => {
+                    vec![format!("{account} is now the founder of {channel}")]
+                }
+                Self::NotFounder(channel) => vec![format!("You aren't the founder of {channel}")],
+                Self::NoSuchAccount(account) => vec![format!("Account {account} doesn't exist")],
+                Self::AccessUpdated(channel, mask, permissions) => vec![format!(
+                    "{mask} now has {} access on {channel}",
+                    access_level(permissions)
+                )],
+                Self::AccessRemoved(channel, mask) => {
+                    vec![format!("Removed {mask} from the access list of {channel}")]
+                }
+                Self::FounderEntry(channel) => vec![format!(
+                    "The founder of {channel} can only be changed with CS TRANSFER"
+                )],
+                Self::NoSuchEntry(channel, mask) => {
+                    vec![format!("{mask} isn't on the access list of {channel}")]
+                }
+                Self::AccessList(channel, entries) => {
+                    let mut lines = vec![format!("Access list for {channel}:")];
+                    lines.extend(entries.into_iter().map(|(mask, permissions)| {
+                        format!("{mask} {}", access_level(permissions))
+                    }));
+                    lines.push("End of access list".to_string());
+                    lines
+                }
+                Self::AccessExport(channel, document) => vec![
+                    format!(
+                        "Access list for {channel}, copy it to another channel with \
+                         CS ACCESS <channel> IMPORT <document>:"
+                    ),
+                    document,
+                ],
+                Self::AccessImported(channel, imported) => vec![format!(
+                    "Imported {imported} entries into the access list of {channel}"
+                )],
+                Self::NoSuchChannel(channel) => vec![format!("{channel} isn't registered")],
+                Self::MetadataSet(channel, key, value) => {
+                    vec![format!("{} of {channel} is now {value}", key.name())]
+                }
+                Self::MetadataCleared(channel, key) => {
+                    vec![format!("{} of {channel} has been cleared", key.name())]
+                }
+                Self::NotOperator(channel) => {
+                    vec![format!(
+                        "You need to be an operator of {channel} to change its settings"
+                    )]
+                }
+            };
+
+        lines
+            .into_iter()
+            .map(|line| {
+                MessageBuilder::server().command(Command::NOTICE(for_user.to_string(), line))
+            })
+            .collect()
+    }
+}
+
+/// The name of a permission as it's given to `CS ACCESS ADD`.
+const fn access_level(permissions: Permission) -> &'static str {
+    match permissions {
+        Permission::Ban => "BAN",
+        Permission::Normal => "NORMAL",
+        Permission::Voice => "VOICE",
+        Permission::HalfOperator => "HALFOP",
+        Permission::Operator => "OP",
+        Permission::Founder => "FOUNDER",
+    }
+}
+
+/// An entry of the JSON document produced by `CS ACCESS EXPORT` and read back by
+/// `CS ACCESS IMPORT`, with the level named as it's given to `CS ACCESS ADD`.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccessEntry {
+    pub mask: String,
+    pub level: String,
+}
+
+/// Serialises a channel's access list for `CS ACCESS EXPORT`. The founder's entry is left out
+/// since founder status can only be handed over with `CS TRANSFER`.
+#[must_use]
+pub fn export_access(access: &[(HostMask<'_>, Permission)]) -> String {
+    let entries: Vec<_> = access
+        .iter()
+        .filter(|(_, permissions)| *permissions != Permission::Founder)
+        .map(|(mask, permissions)| AccessEntry {
+            mask: mask.to_string(),
+            level: access_level(*permissions).to_string(),
+        })
+        .collect();
+
+    serde_json::to_string(&entries).unwrap()
+}
+
+/// Sets a password on an account that was created without one, such as one created when a user
+/// registered without SASL.
+pub async fn register(
+    database: &sqlx::Pool<sqlx::Any>,
+    account: &str,
+    user_id: UserId,
+    password: &str,
+) -> NickServResponse {
+    let passwordless = database::fetch_password_hash(database, account)
+        .await
+        .unwrap()
+        .is_some_and(|(_, hash)| password_matches(&hash, b""));
+
+    if !passwordless {
+        return NickServResponse::AlreadyRegistered(account.to_string());
+    }
+
+    database::set_password(database, user_id, password.as_bytes())
+        .await
+        .unwrap();
+
+    NickServResponse::Registered(account.to_string())
+}
+
+/// Checks the credentials of an existing account, returning the account's ID if they're
+/// correct. Unlike SASL `PLAIN`, accounts are never created here.
+pub async fn identify(
+    database: &sqlx::Pool<sqlx::Any>,
+    account: &str,
+    password: &str,
+) -> Option<UserId> {
+    let (user_id, hash) = database::fetch_password_hash(database, account)
+        .await
+        .unwrap()?;
+
+    password_matches(&hash, password.as_bytes()).then_some(UserId(user_id))
+}
+
+/// Changes the password of the user's account.
+pub async fn set_password(
+    database: &sqlx::Pool<sqlx::Any>,
+    user_id: UserId,
+    password: &str,
+) -> NickServResponse {
+    database::set_password(database, user_id, password.as_bytes())
+        .await
+        .unwrap();
+
+    NickServResponse::PasswordChanged
+}
+
+/// Deletes the user's account once they've confirmed its password. The caller is responsible
+/// for disconnecting the account's sessions once it's been dropped.
+pub async fn drop_account(
+    database: &sqlx::Pool<sqlx::Any>,
+    account: &str,
+    user_id: UserId,
+    password: &str,
+) -> NickServResponse {
+    if identify(database, account, password).await != Some(user_id) {
+        return NickServResponse::InvalidCredentials;
+    }
+
+    if database::drop_user(database, user_id).await.unwrap() {
+        NickServResponse::Dropped(account.to_string())
+    } else {
+        NickServResponse::DropBlocked(account.to_string())
+    }
+}
+
+fn password_matches(hash: &str, password: &[u8]) -> bool {
+    PasswordHash::new(hash).is_ok_and(|hash| verify_password(password, &hash).is_ok())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{drop_account, identify, register, set_password, NickServResponse};
+    use crate::{
+        connection::UserId,
+        database::{create_user_or_fetch_password_hash, in_memory},
+    };
+
+    #[tokio::test]
+    async fn account_lifecycle() {
+        let database = in_memory().await;
+
+        // accounts created by implicit authentication have an empty password
+        let (user_id, _) = create_user_or_fetch_password_hash(&database, "alice", b"")
+            .await
+            .unwrap();
+        let user_id = UserId(user_id);
+
+        assert_eq!(
+            register(&database, "alice", user_id, "hunter2").await,
+            NickServResponse::Registered("alice".to_string())
+        );
+        assert_eq!(
+            register(&database, "alice", user_id, "hunter3").await,
+            NickServResponse::AlreadyRegistered("alice".to_string())
+        );
+
+        assert_eq!(identify(&database, "alice", "hunter2").await, Some(user_id));
+        assert_eq!(identify(&database, "alice", "").await, None);
+        assert_eq!(identify(&database, "bob", "hunter2").await, None);
+
+        set_password(&database, user_id, "hunter3").await;
+        assert_eq!(identify(&database, "alice", "hunter2").await, None);
+
+        assert_eq!(
+            drop_account(&database, "alice", user_id, "hunter2").await,
+            NickServResponse::InvalidCredentials
+        );
+        assert_eq!(
+            drop_account(&database, "alice", user_id, "hunter3").await,
+            NickServResponse::Dropped("alice".to_string())
+        );
+        assert_eq!(identify(&database, "alice", "hunter3").await, None);
+    }
+}
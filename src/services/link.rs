@@ -0,0 +1,612 @@
+//! Links an external services package, such as Atheme or Anope, to the server over the subset of
+//! the TS6 server protocol services need to track users and act on them.
+//!
+//! Services connect to the configured address and authenticate with `PASS` and `SERVER`, after
+//! which every connected user is introduced to them with `UID` and kept up to date as they
+//! change their nick or disconnect. Services' own clients (`NickServ`, `ChanServ`, etc.) can
+//! message users, and services can force a nick change with `SVSNICK`, set a user's modes with
+//! `SVSMODE`, change their displayed host with `CHGHOST` or disconnect them with `KILL`.
+//!
+//! Channel state isn't shared with services, channels are still managed by the server itself.
+
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use actix::{
+    io::{FramedWrite, WriteHandler},
+    Actor, ActorContext, ActorFutureExt, Addr, AsyncContext, Context, Handler, StreamHandler,
+    WrapFuture,
+};
+use chrono::Utc;
+use irc_proto::{Mode, Prefix};
+use tokio::{
+    io::WriteHalf,
+    net::{TcpListener, TcpStream},
+};
+use tokio_util::codec::{FramedRead, LinesCodec, LinesCodecError};
+use tracing::{debug, error, info, info_span, warn, Span};
+
+use crate::{
+    casemapping::nick_eq,
+    client::{Client, SetUserModes},
+    config::ServicesConfig,
+    connection::{InitiatedConnection, UserMode},
+    messages::{
+        Broadcast, ChangeHost, KillUser, MessageKind, ServicesClientsChanged, ServicesLinked,
+        ServicesPrivateMessage, ServicesUnlinked, ServicesUserChanged, UserNickChangeInternal,
+    },
+    proto::builder::MessageBuilder,
+    server::Server,
+    SERVER_NAME,
+};
+
+/// How long services have to authenticate before the link is dropped.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The longest line accepted from services, TS6 lines are limited to 512 bytes but services may
+/// send a few extra for tags.
+const MAX_LINE_LENGTH: usize = 4096;
+
+/// Accepts links from services until the listener closes. Only one link can be established at a
+/// time, any further links are refused once they've authenticated.
+pub async fn run(
+    listener: TcpListener,
+    config: ServicesConfig,
+    network_name: String,
+    server: Addr<Server>,
+) {
+    while let Ok((stream, address)) = listener.accept().await {
+        info!(%address, "Accepted services connection");
+
+        let (read, write) = tokio::io::split(stream);
+        let config = config.clone();
+        let network_name = network_name.clone();
+        let server = server.clone();
+
+        ServicesLink::create(move |ctx| {
+            ctx.add_stream(FramedRead::new(
+                read,
+                LinesCodec::new_with_max_length(MAX_LINE_LENGTH),
+            ));
+
+            ServicesLink {
+                writer: FramedWrite::new(write, LinesCodec::new(), ctx),
+                config,
+                network_name,
+                server,
+                remote_sid: None,
+                linked: false,
+                users: HashMap::new(),
+                uids: HashMap::new(),
+                next_uid: 0,
+                clients: HashMap::new(),
+                span: info_span!("services", %address),
+            }
+        });
+    }
+}
+
+/// A single link to services.
+pub struct ServicesLink {
+    writer: FramedWrite<String, WriteHalf<TcpStream>, LinesCodec>,
+    config: ServicesConfig,
+    network_name: String,
+    server: Addr<Server>,
+    /// The server ID services sent in `PASS`
+    remote_sid: Option<String>,
+    /// Whether services have authenticated and been accepted by the server
+    linked: bool,
+    /// Every user introduced to services, keyed by the ID they were introduced with
+    users: HashMap<String, (Addr<Client>, Arc<InitiatedConnection>)>,
+    uids: HashMap<Addr<Client>, String>,
+    /// Used to generate the ID of the next user introduced to services
+    next_uid: usize,
+    /// Services' own clients, keyed by their ID
+    clients: HashMap<String, ServicesClient>,
+    span: Span,
+}
+
+/// One of services' own clients, such as `NickServ`.
+struct ServicesClient {
+    nick: String,
+    user: String,
+    host: String,
+}
+
+impl ServicesClient {
+    fn to_prefix(&self) -> Prefix {
+        Prefix::Nickname(self.nick.clone(), self.user.clone(), self.host.clone())
+    }
+}
+
+impl Actor for ServicesLink {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_later(HANDSHAKE_TIMEOUT, |this, ctx| {
+            if !this.linked {
+                warn!(parent: &this.span, "Services failed to authenticate in time");
+                this.close("Connection timed out", ctx);
+            }
+        });
+    }
+
+    fn stopped(&mut self, ctx: &mut Self::Context) {
+        info!(parent: &self.span, "Services link closed");
+
+        self.server.do_send(ServicesUnlinked {
+            link: ctx.address(),
+        });
+    }
+}
+
+impl ServicesLink {
+    fn send(&mut self, line: String) {
+        debug!(parent: &self.span, %line, "Sending to services");
+        self.writer.write(line);
+    }
+
+    /// Tells services why the link is being dropped and closes it.
+    fn close(&mut self, reason: &str, ctx: &mut Context<Self>) {
+        self.send(format!("ERROR :Closing Link: {reason}"));
+        ctx.stop();
+    }
+
+    /// Introduces a user to services, allocating them an ID.
+    fn introduce(&mut self, client: Addr<Client>, connection: Arc<InitiatedConnection>) {
+        let uid = uid(&self.config.sid, self.next_uid);
+        self.next_uid += 1;
+
+        let ip = connection.host.ip().to_canonical().to_string();
+        // parameters can't start with a colon, so IPv6 addresses like `::1` are prefixed
+        let ip = if ip.starts_with(':') {
+            format!("0{ip}")
+        } else {
+            ip
+        };
+
+        self.send(format!(
+            ":{sid} UID {nick} 1 {ts} {modes} {user} {host} {ip} {uid} :{real_name}",
+            sid = self.config.sid,
+            nick = connection.nick(),
+            ts = connection.at.timestamp(),
+            modes = connection.mode(),
+            user = connection.user,
+            host = connection.cloak(),
+            real_name = connection.real_name,
+        ));
+
+        if connection.is_registered() {
+            self.send(format!(":{uid} ENCAP * LOGIN {}", connection.user));
+        }
+
+        self.uids.insert(client.clone(), uid.clone());
+        self.users.insert(uid, (client, connection));
+    }
+
+    /// Finds a user by the ID they were introduced to services with, or by their nick.
+    fn find_user(&self, target: &str) -> Option<&(Addr<Client>, Arc<InitiatedConnection>)> {
+        self.users.get(target).or_else(|| {
+            self.users
+                .values()
+                .find(|(_, connection)| nick_eq(&connection.nick(), target))
+        })
+    }
+
+    /// Handles `PASS` and `SERVER`, linking services to the server once both have been sent.
+    fn authenticate(&mut self, line: &Line<'_>, ctx: &mut Context<Self>) {
+        match (line.command, line.params.as_slice()) {
+            ("PASS", [password, "TS", "6", sid, ..]) => {
+                if *password != self.config.password {
+                    warn!(parent: &self.span, "Services sent an invalid password");
+                    self.close("Invalid password", ctx);
+                    return;
+                }
+
+                self.remote_sid = Some((*sid).to_string());
+            }
+            ("SERVER", [name, ..]) => {
+                if self.remote_sid.is_none() {
+                    self.close("Expected PASS", ctx);
+                    return;
+                } else if *name != self.config.name {
+                    warn!(parent: &self.span, name, "Services sent an unexpected server name");
+                    self.close("Invalid server name", ctx);
+                    return;
+                }
+
+                self.link(ctx);
+            }
+            ("CAPAB" | "SVINFO", _) => {}
+            ("ERROR", _) => ctx.stop(),
+            (command, _) => {
+                warn!(parent: &self.span, command, "Services sent a command before linking");
+                self.close("Expected PASS and SERVER", ctx);
+            }
+        }
+    }
+
+    /// Asks the server to accept the link, then sends our side of the handshake and introduces
+    /// every connected user.
+    fn link(&mut self, ctx: &mut Context<Self>) {
+        let fut = self.server.send(ServicesLinked {
+            link: ctx.address(),
+            name: self.config.name.clone(),
+        });
+
+        ctx.wait(fut.into_actor(self).map(|res, this, ctx| {
+            let Ok(Some(users)) = res else {
+                warn!(parent: &this.span, "Refusing services link, services are already linked");
+                this.close("Services are already linked", ctx);
+                return;
+            };
+
+            this.send(format!(
+                "PASS {} TS 6 :{}",
+                this.config.password, this.config.sid
+            ));
+            this.send("CAPAB :QS EX IE KLN UNKLN ENCAP SERVICES EUID".to_string());
+            this.send(format!("SERVER {SERVER_NAME} 1 :{}", this.network_name));
+            this.send(format!("SVINFO 6 6 0 :{}", Utc::now().timestamp()));
+
+            for (client, connection) in users {
+                this.introduce(client, connection);
+            }
+
+            this.send(format!(":{} PING :{}", this.config.sid, this.config.sid));
+            this.linked = true;
+
+            info!(parent: &this.span, users = this.users.len(), "Services linked");
+        }));
+    }
+
+    /// Handles a line from services once they've linked.
+    fn handle_command(&mut self, line: Line<'_>, ctx: &mut Context<Self>) {
+        let source = line.source.unwrap_or_default();
+
+        match (line.command, line.params.as_slice()) {
+            ("ENCAP", [_target, command, params @ ..]) => {
+                self.handle_command(
+                    Line {
+                        source: line.source,
+                        command: *command,
+                        params: params.to_vec(),
+                    },
+                    ctx,
+                );
+            }
+            ("PING", [.., token]) => {
+                self.send(format!(":{} PONG {SERVER_NAME} :{token}", self.config.sid));
+            }
+            ("UID" | "EUID", [nick, _hops, _ts, _modes, user, host, _ip, uid, ..]) => {
+                self.clients.insert(
+                    (*uid).to_string(),
+                    ServicesClient {
+                        nick: (*nick).to_string(),
+                        user: (*user).to_string(),
+                        host: (*host).to_string(),
+                    },
+                );
+                self.clients_changed();
+            }
+            ("NICK", [nick, ..]) => {
+                if let Some(client) = self.clients.get_mut(source) {
+                    client.nick = (*nick).to_string();
+                    self.clients_changed();
+                }
+            }
+            ("QUIT", _) => {
+                if self.clients.remove(source).is_some() {
+                    self.clients_changed();
+                }
+            }
+            ("PRIVMSG" | "NOTICE", [target, message]) => {
+                let Some(from) = self.clients.get(source) else {
+                    return;
+                };
+                let Some((client, connection)) = self.find_user(target) else {
+                    debug!(parent: &self.span, target, "Ignoring services message to non-user");
+                    return;
+                };
+
+                let kind = if line.command == "NOTICE" {
+                    MessageKind::Notice
+                } else {
+                    MessageKind::Normal
+                };
+
+                client.do_send(Broadcast {
+                    message: MessageBuilder::user(from.to_prefix())
+                        .command(kind.into_command(connection.nick(), (*message).to_string())),
+                    span: self.span.clone(),
+                });
+            }
+            ("SVSNICK", [target, nick, ..]) => {
+                let Some((_, connection)) = self.find_user(target) else {
+                    return;
+                };
+
+                self.server.do_send(UserNickChangeInternal {
+                    old_nick: connection.nick(),
+                    new_nick: (*nick).to_string(),
+                    span: self.span.clone(),
+                });
+            }
+            ("SVSMODE", [target, params @ ..]) => {
+                let Some((client, connection)) = self.find_user(target) else {
+                    return;
+                };
+
+                // the user's timestamp may be given before the modes
+                let pieces = params
+                    .iter()
+                    .skip_while(|v| v.bytes().all(|c| c.is_ascii_digit()))
+                    .copied()
+                    .collect::<Vec<_>>();
+
+                let modes = match Mode::as_user_modes(&pieces) {
+                    Ok(modes) => modes,
+                    Err(error) => {
+                        warn!(parent: &self.span, %error, "Services sent invalid SVSMODE");
+                        return;
+                    }
+                };
+
+                // only pass on modes the user could have set themselves, or the removal of their
+                // operator status
+                let modes = modes
+                    .into_iter()
+                    .filter(|mode| match mode {
+                        Mode::Plus(mode, _) => UserMode::from_user_settable(mode).is_some(),
+                        Mode::Minus(mode, _) => {
+                            UserMode::from_user_settable(mode).is_some()
+                                || matches!(mode, irc_proto::UserMode::Oper)
+                        }
+                    })
+                    .collect::<Vec<_>>();
+
+                if !modes.is_empty() {
+                    client.do_send(SetUserModes {
+                        nick: connection.nick(),
+                        modes,
+                        span: self.span.clone(),
+                    });
+                }
+            }
+            ("CHGHOST", [target, host]) => {
+                let Some((client, _)) = self.find_user(target) else {
+                    return;
+                };
+
+                client.do_send(ChangeHost {
+                    host: (*host).to_string(),
+                    span: self.span.clone(),
+                });
+            }
+            ("KILL", [target, reason @ ..]) => {
+                let Some((_, connection)) = self.find_user(target) else {
+                    return;
+                };
+
+                let killer = self
+                    .clients
+                    .get(source)
+                    .map_or_else(|| self.config.name.clone(), |client| client.nick.clone());
+
+                self.server.do_send(KillUser {
+                    span: self.span.clone(),
+                    killer,
+                    comment: reason.join(" "),
+                    killed: connection.nick(),
+                });
+            }
+            ("SQUIT" | "ERROR", params) => {
+                info!(parent: &self.span, reason = %params.join(" "), "Services delinked");
+                ctx.stop();
+            }
+            (command, _) => {
+                debug!(parent: &self.span, command, "Ignoring unsupported command from services");
+            }
+        }
+    }
+
+    /// Tells the server the nicks services' clients are using, so users can't take them.
+    fn clients_changed(&self) {
+        self.server.do_send(ServicesClientsChanged {
+            nicks: self.clients.values().map(|v| v.nick.clone()).collect(),
+        });
+    }
+}
+
+impl StreamHandler<Result<String, LinesCodecError>> for ServicesLink {
+    fn handle(&mut self, item: Result<String, LinesCodecError>, ctx: &mut Self::Context) {
+        let item = match item {
+            Ok(item) => item,
+            Err(error) => {
+                error!(parent: &self.span, %error, "Failed to read from services");
+                ctx.stop();
+                return;
+            }
+        };
+
+        debug!(parent: &self.span, line = %item, "Received from services");
+
+        let Some(line) = Line::parse(&item) else {
+            return;
+        };
+
+        if self.linked {
+            self.handle_command(line, ctx);
+        } else {
+            self.authenticate(&line, ctx);
+        }
+    }
+}
+
+impl WriteHandler<LinesCodecError> for ServicesLink {}
+
+/// Keeps services up to date with the users connected to the server.
+impl Handler<ServicesUserChanged> for ServicesLink {
+    type Result = ();
+
+    fn handle(&mut self, msg: ServicesUserChanged, _ctx: &mut Self::Context) -> Self::Result {
+        if !self.linked {
+            return;
+        }
+
+        match msg {
+            ServicesUserChanged::Connected { client, connection } => {
+                self.introduce(client, connection);
+            }
+            ServicesUserChanged::NickChanged { client } => {
+                let Some(uid) = self.uids.get(&client) else {
+                    return;
+                };
+                let Some((_, connection)) = self.users.get(uid) else {
+                    return;
+                };
+
+                let line = format!(
+                    ":{uid} NICK {} :{}",
+                    connection.nick(),
+                    Utc::now().timestamp()
+                );
+                self.send(line);
+            }
+            ServicesUserChanged::Disconnected { client, message } => {
+                let Some(uid) = self.uids.remove(&client) else {
+                    return;
+                };
+
+                self.users.remove(&uid);
+                self.send(format!(":{uid} QUIT :{}", message.unwrap_or_default()));
+            }
+        }
+    }
+}
+
+/// Sends a user's message on to one of services' clients.
+impl Handler<ServicesPrivateMessage> for ServicesLink {
+    type Result = ();
+
+    fn handle(&mut self, msg: ServicesPrivateMessage, _ctx: &mut Self::Context) -> Self::Result {
+        let Some(from) = self.uids.get(&msg.from) else {
+            return;
+        };
+        let Some(target) = self
+            .clients
+            .iter()
+            .find_map(|(uid, client)| nick_eq(&client.nick, &msg.target).then_some(uid))
+        else {
+            return;
+        };
+
+        let command = match msg.kind {
+            MessageKind::Normal => "PRIVMSG",
+            MessageKind::Notice => "NOTICE",
+            MessageKind::Tag => return,
+        };
+
+        let line = format!(":{from} {command} {target} :{}", msg.message);
+        self.send(line);
+    }
+}
+
+/// A line received from services, `[:source] COMMAND [params...] [:trailing]`.
+#[derive(Debug, PartialEq, Eq)]
+struct Line<'a> {
+    source: Option<&'a str>,
+    command: &'a str,
+    params: Vec<&'a str>,
+}
+
+impl<'a> Line<'a> {
+    fn parse(line: &'a str) -> Option<Self> {
+        let line = line.trim_end_matches('\r');
+
+        let (source, rest) = match line.strip_prefix(':') {
+            Some(rest) => {
+                let (source, rest) = rest.split_once(' ')?;
+                (Some(source), rest)
+            }
+            None => (None, line),
+        };
+
+        let (rest, trailing) = match rest.split_once(" :") {
+            Some((rest, trailing)) => (rest, Some(trailing)),
+            None => (rest, None),
+        };
+
+        let mut params = rest.split(' ').filter(|v| !v.is_empty());
+        let command = params.next()?;
+
+        Some(Self {
+            source,
+            command,
+            params: params.chain(trailing).collect(),
+        })
+    }
+}
+
+/// Builds the ID of the `n`th user introduced to services, our server ID followed by a letter and
+/// five letters or digits.
+fn uid(sid: &str, n: usize) -> String {
+    const ALPHABET: &[u8; 36] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789";
+
+    let mut id = [0; 6];
+    let mut n = n;
+
+    for c in id[1..].iter_mut().rev() {
+        *c = ALPHABET[n % 36];
+        n /= 36;
+    }
+
+    id[0] = ALPHABET[n % 26];
+
+    format!("{sid}{}", std::str::from_utf8(&id).unwrap())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{uid, Line};
+
+    #[test]
+    fn parse_line() {
+        assert_eq!(
+            Line::parse(":00AAAAAAB PRIVMSG 0TIAAAAAA :hello there\r"),
+            Some(Line {
+                source: Some("00AAAAAAB"),
+                command: "PRIVMSG",
+                params: vec!["0TIAAAAAA", "hello there"],
+            })
+        );
+
+        assert_eq!(
+            Line::parse("PASS secret TS 6 :00A"),
+            Some(Line {
+                source: None,
+                command: "PASS",
+                params: vec!["secret", "TS", "6", "00A"],
+            })
+        );
+
+        assert_eq!(
+            Line::parse(":00A ENCAP * CHGHOST 0TIAAAAAA new.host"),
+            Some(Line {
+                source: Some("00A"),
+                command: "ENCAP",
+                params: vec!["*", "CHGHOST", "0TIAAAAAA", "new.host"],
+            })
+        );
+
+        assert_eq!(Line::parse(""), None);
+        assert_eq!(Line::parse(":00A"), None);
+    }
+
+    #[test]
+    fn uids() {
+        assert_eq!(uid("0TI", 0), "0TIAAAAAA");
+        assert_eq!(uid("0TI", 1), "0TIAAAAAB");
+        assert_eq!(uid("0TI", 36), "0TIAAAABA");
+        assert_eq!(uid("0TI", 36usize.pow(5)), "0TIBAAAAA");
+    }
+}